@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS update_script_logs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    attr_path TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    status TEXT NOT NULL,
+    stdout TEXT NOT NULL,
+    stderr TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_update_script_logs_attr_path ON update_script_logs(attr_path);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS passthru_test_results (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    attr_path TEXT NOT NULL,
+    version TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    test_name TEXT NOT NULL,
+    status TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_passthru_test_results_attr_path ON passthru_test_results(attr_path);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS vulnerabilities (
+    attr_path TEXT PRIMARY KEY,
+    checked_at TEXT NOT NULL,
+    current_version TEXT NOT NULL,
+    latest_version TEXT NOT NULL,
+    fixed_ids TEXT NOT NULL,
+    remaining_ids TEXT NOT NULL
+);
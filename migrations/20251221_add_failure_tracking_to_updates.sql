@@ -0,0 +1,2 @@
+ALTER TABLE updates ADD COLUMN consecutive_failures INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE updates ADD COLUMN failure_issue_number INTEGER;
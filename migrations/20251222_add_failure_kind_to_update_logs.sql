@@ -0,0 +1 @@
+ALTER TABLE update_logs ADD COLUMN failure_kind TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE updates ADD COLUMN retry_strategy TEXT;
+ALTER TABLE update_logs ADD COLUMN retry_strategy TEXT;
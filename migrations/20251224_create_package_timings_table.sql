@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS package_timings (
+    attr_path TEXT PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    metadata_eval_ms INTEGER NOT NULL,
+    upstream_fetch_ms INTEGER NOT NULL,
+    rewrite_ms INTEGER NOT NULL,
+    build_ms INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_package_timings_timestamp ON package_timings(timestamp);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS releases (
+    source TEXT PRIMARY KEY,
+    data TEXT NOT NULL,
+    fetched_at TEXT NOT NULL
+);
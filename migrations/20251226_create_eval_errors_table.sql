@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS eval_errors (
+    attr_path TEXT PRIMARY KEY,
+    error TEXT NOT NULL,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_eval_errors_last_seen ON eval_errors(last_seen);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS update_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    attr_path TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    old_version TEXT,
+    new_version TEXT,
+    pr_url TEXT,
+    pr_number INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS idx_update_events_attr_path ON update_events(attr_path);
@@ -0,0 +1,251 @@
+//! Vendored `Cargo.lock` files of Rust packages
+//!
+//! Packages built with `cargoLock.lockFile = ./Cargo.lock` vendor a lockfile, usually the one of
+//! upstream, and list the hashes of their git dependencies in `cargoLock.outputHashes`. Updates
+//! replace the vendored lockfile with the one of the new source, at the root of the workspace or
+//! in `cargoRoot`. Upstreams which don't commit their lockfile get one generated by
+//! `cargo generate-lockfile` in a scratch copy of the new source, with its own `CARGO_HOME`.
+//!
+//! Git dependencies are hashed once per revision, so `outputHashes` keeps a single entry for the
+//! crates of the same revision, and only revisions which changed need a new hash.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::{debug, info};
+
+use crate::patches::unpack_source;
+
+/// Packages of a `Cargo.lock`
+#[derive(Debug, Deserialize)]
+struct CargoLock {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    /// e.g. `registry+https://github.com/rust-lang/crates.io-index` or
+    /// `git+https://github.com/owner/repo?branch=main#<rev>`
+    source: Option<String>,
+}
+
+/// Git dependencies of a `Cargo.lock`, their source by `<name>-<version>` as `outputHashes` keys
+/// them
+pub fn git_dependencies(lock: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    let lock: CargoLock = toml::from_str(lock).context("Invalid Cargo.lock")?;
+    Ok(lock
+        .package
+        .into_iter()
+        .filter_map(|package| {
+            let source = package.source.filter(|s| s.starts_with("git+"))?;
+            Some((format!("{}-{}", package.name, package.version), source))
+        })
+        .collect())
+}
+
+/// Packages of the workspace of a `Cargo.lock`, which have no source
+pub fn root_packages(lock: &str) -> anyhow::Result<Vec<String>> {
+    let lock: CargoLock = toml::from_str(lock).context("Invalid Cargo.lock")?;
+    Ok(lock
+        .package
+        .into_iter()
+        .filter(|package| package.source.is_none())
+        .map(|package| package.name)
+        .collect())
+}
+
+/// Revision a git dependency is locked to, e.g. `<rev>` of `git+https://...#<rev>`
+fn git_revision(source: &str) -> &str {
+    source.rsplit_once('#').map_or(source, |(_, rev)| rev)
+}
+
+/// `outputHashes` entries of the git dependencies of a new lockfile
+///
+/// Hashes of `current` are kept for the revisions they were computed for in the old lockfile,
+/// the other revisions get `placeholder`. Each revision has a single entry, preferably under the
+/// key it already had. Entries of dependencies which are gone are dropped, as `importCargoLock`
+/// rejects them.
+pub fn plan_output_hashes(
+    current: &[(String, String)],
+    old_dependencies: &BTreeMap<String, String>,
+    new_dependencies: &BTreeMap<String, String>,
+    placeholder: &str,
+) -> Vec<(String, String)> {
+    let known: HashMap<&str, &str> = current
+        .iter()
+        .filter_map(|(key, hash)| {
+            let source = old_dependencies.get(key)?;
+            Some((git_revision(source), hash.as_str()))
+        })
+        .collect();
+
+    // Keys already listed first, so they keep standing for their revision
+    let (listed, unlisted): (Vec<_>, Vec<_>) = new_dependencies
+        .iter()
+        .partition(|(key, _)| current.iter().any(|(listed, _)| listed == *key));
+
+    let mut by_revision: HashMap<&str, (String, String)> = HashMap::new();
+    for (key, source) in listed.into_iter().chain(unlisted) {
+        let revision = git_revision(source);
+        by_revision.entry(revision).or_insert_with(|| {
+            let hash = known.get(revision).copied().unwrap_or(placeholder);
+            (key.clone(), hash.to_string())
+        });
+    }
+
+    let mut hashes: Vec<(String, String)> = by_revision.into_values().collect();
+    hashes.sort();
+    hashes
+}
+
+/// `Cargo.lock` of a new source, generated if upstream doesn't commit one
+///
+/// `cargo_root` is the directory of the workspace relative to the root of the source, if it
+/// isn't the root.
+pub async fn lock_file_of(src: &Path, cargo_root: Option<&str>) -> anyhow::Result<String> {
+    let workspace = src.join(cargo_root.unwrap_or("."));
+    let lock_file = workspace.join("Cargo.lock");
+    if lock_file.is_file() {
+        debug!("Using the lockfile of the source {}", lock_file.display());
+        return tokio::fs::read_to_string(&lock_file)
+            .await
+            .with_context(|| format!("Failed to read {}", lock_file.display()));
+    }
+
+    // Packages are updated concurrently, each lockfile gets its own directory
+    static LOCKFILES: AtomicUsize = AtomicUsize::new(0);
+    let scratch = std::env::temp_dir().join(format!(
+        "ekapkgs-update-{}-cargo-lock-{}",
+        std::process::id(),
+        LOCKFILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    if scratch.exists() {
+        tokio::fs::remove_dir_all(&scratch).await?;
+    }
+    tokio::fs::create_dir_all(&scratch).await?;
+
+    let lock = match unpack_source(src, &scratch).await {
+        Ok(root) => generate_lockfile(&root.join(cargo_root.unwrap_or(".")), &scratch).await,
+        Err(e) => Err(e),
+    };
+    tokio::fs::remove_dir_all(&scratch).await.ok();
+    lock
+}
+
+/// Run `cargo generate-lockfile` in `workspace`, keeping the registry index in `scratch`
+async fn generate_lockfile(workspace: &Path, scratch: &Path) -> anyhow::Result<String> {
+    if !workspace.join("Cargo.toml").is_file() {
+        anyhow::bail!("No Cargo.toml in {}", workspace.display());
+    }
+
+    info!("Source has no Cargo.lock, generating one");
+    let output = Command::new("cargo")
+        .arg("generate-lockfile")
+        .env("CARGO_HOME", scratch.join("cargo-home"))
+        .current_dir(workspace)
+        .process_group(0)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("Failed to run cargo generate-lockfile")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "cargo generate-lockfile failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    tokio::fs::read_to_string(workspace.join("Cargo.lock"))
+        .await
+        .context("cargo generate-lockfile wrote no Cargo.lock")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLACEHOLDER: &str = "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    #[test]
+    fn test_git_dependencies() {
+        let lock = r#"
+version = 3
+
+[[package]]
+name = "foo"
+version = "1.2.0"
+
+[[package]]
+name = "serde"
+version = "1.0.200"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "ddc6f9cc94d67c0e21aaf7eda3a010fd3af78ebf6e096aa6e2e13c79749cce4f"
+
+[[package]]
+name = "tree-sitter-foo"
+version = "0.1.0"
+source = "git+https://github.com/owner/tree-sitter-foo?branch=main#0123abc"
+"#;
+        assert_eq!(root_packages(lock).unwrap(), ["foo"]);
+        let dependencies = git_dependencies(lock).unwrap();
+        assert_eq!(
+            dependencies.into_iter().collect::<Vec<_>>(),
+            vec![(
+                "tree-sitter-foo-0.1.0".to_string(),
+                "git+https://github.com/owner/tree-sitter-foo?branch=main#0123abc".to_string()
+            )]
+        );
+        assert!(git_dependencies("[[package]]\nname = 1").is_err());
+    }
+
+    #[test]
+    fn test_plan_output_hashes() {
+        let deps = |entries: &[(&str, &str)]| -> BTreeMap<String, String> {
+            entries
+                .iter()
+                .map(|(key, rev)| {
+                    (
+                        key.to_string(),
+                        format!("git+https://example.org/r#{}", rev),
+                    )
+                })
+                .collect()
+        };
+        let current = vec![
+            ("a-0.1.0".to_string(), "sha256-a".to_string()),
+            ("gone-1.0.0".to_string(), "sha256-gone".to_string()),
+            ("moved-1.0.0".to_string(), "sha256-moved".to_string()),
+        ];
+        let old = deps(&[
+            ("a-0.1.0", "r1"),
+            ("gone-1.0.0", "r2"),
+            ("moved-1.0.0", "r3"),
+        ]);
+        let new = deps(&[
+            // Another crate of the same revision needs no entry
+            ("a-0.1.0", "r1"),
+            ("a-macros-0.1.0", "r1"),
+            ("moved-1.0.0", "r4"),
+            ("new-2.0.0", "r5"),
+        ]);
+
+        assert_eq!(
+            plan_output_hashes(&current, &old, &new, PLACEHOLDER),
+            vec![
+                ("a-0.1.0".to_string(), "sha256-a".to_string()),
+                ("moved-1.0.0".to_string(), PLACEHOLDER.to_string()),
+                ("new-2.0.0".to_string(), PLACEHOLDER.to_string()),
+            ]
+        );
+        assert!(plan_output_hashes(&current, &old, &BTreeMap::new(), PLACEHOLDER).is_empty());
+    }
+}
@@ -0,0 +1,64 @@
+use tracing::{info, warn};
+
+use crate::commands::run::{get_file_location, worktree_path_for};
+use crate::commands::update::{ProposedUpdate, UpdateOptions, find_update, rewrite_version};
+use crate::config::Config;
+use crate::git::{cleanup_worktree, create_worktree, worktree_diff};
+use crate::timings::PhaseTimings;
+
+/// Print the rewrite an update of a package would make, without building or committing it
+///
+/// The new version is written to a temporary worktree checked out at HEAD. The source hash is
+/// replaced by the same placeholder the update starts with, since the real hash and dependency
+/// hashes are only known once the new source is built.
+pub async fn diff(
+    file: String,
+    attr_path: String,
+    semver_strategy: String,
+    config: Config,
+) -> anyhow::Result<()> {
+    let options = UpdateOptions {
+        strategy: semver_strategy.parse()?,
+        config,
+        ..Default::default()
+    };
+
+    let ProposedUpdate {
+        metadata,
+        new_version,
+        ..
+    } = find_update(&file, &attr_path, &options, &mut PhaseTimings::default()).await?;
+    if new_version == metadata.version {
+        println!("{} is up to date at {}", attr_path, metadata.version);
+        return Ok(());
+    }
+
+    let file_location = get_file_location(&file, &attr_path).await?;
+    let worktree_path = create_worktree(&format!("diff-{}", attr_path)).await?;
+    let diff = async {
+        let worktree_file = worktree_path_for(&worktree_path, &file_location);
+        let worktree_entry_point = worktree_path_for(&worktree_path, &file);
+        rewrite_version(
+            &worktree_entry_point.to_string_lossy(),
+            &attr_path,
+            &worktree_file.to_string_lossy(),
+            &metadata,
+            &new_version,
+        )
+        .await?;
+        worktree_diff(&worktree_path).await
+    }
+    .await;
+
+    if let Err(e) = cleanup_worktree(&worktree_path).await {
+        warn!("{}: Failed to clean up worktree: {}", attr_path, e);
+    }
+
+    info!(
+        "{}: {} -> {}, hashes are refreshed when building the update",
+        attr_path, metadata.version, new_version
+    );
+    print!("{}", diff?);
+
+    Ok(())
+}
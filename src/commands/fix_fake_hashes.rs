@@ -0,0 +1,319 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use regex::Regex;
+use tokio::fs;
+use tracing::{error, info, warn};
+use walkdir::WalkDir;
+
+use super::run::get_file_location;
+use crate::nix::{BuildOptions, build_nix_expr, entry_point_dir};
+
+/// Letters distinguishing the placeholders of a file while they are repaired
+const PLACEHOLDER_LETTERS: &str = "BCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Placeholder hashes set by updates, e.g. `sha256-AAAA...=` (`lib.fakeHash`), and the numbered
+/// placeholders of an interrupted repair
+fn fake_hash_regex() -> &'static Regex {
+    static FAKE_HASH: OnceLock<Regex> = OnceLock::new();
+    FAKE_HASH.get_or_init(|| Regex::new(r"sha256-[A-Z]A{42}=").unwrap())
+}
+
+/// Count the placeholder hashes in the content of a file
+pub fn count_fake_hashes(content: &str) -> usize {
+    fake_hash_regex().find_iter(content).count()
+}
+
+/// Hash specified for a fixed-output derivation and the hash Nix actually got
+fn parse_hash_mismatch(stderr: &str) -> Option<(String, String)> {
+    static MISMATCH: OnceLock<Regex> = OnceLock::new();
+    let mismatch = MISMATCH.get_or_init(|| {
+        Regex::new(r"specified:\s+(sha256-[A-Za-z0-9+/=]+)\s+got:\s+(sha256-[A-Za-z0-9+/=]+)")
+            .unwrap()
+    });
+    let caps = mismatch.captures(stderr)?;
+    Some((caps[1].to_string(), caps[2].to_string()))
+}
+
+/// Give every placeholder of a file its own value, so a hash mismatch tells which one it is about
+fn number_placeholders(content: &str) -> Option<String> {
+    let mut letters = PLACEHOLDER_LETTERS.chars();
+    let mut exhausted = false;
+    let numbered = fake_hash_regex().replace_all(content, |_: &regex::Captures| {
+        let letter = letters.next().unwrap_or_else(|| {
+            exhausted = true;
+            'A'
+        });
+        format!("sha256-{}{}=", letter, "A".repeat(42))
+    });
+    (!exhausted).then(|| numbered.into_owned())
+}
+
+/// Find the .nix files and sidecar files (`.json`, `.toml`) containing placeholder hashes
+pub async fn find_fake_hashes(directory: &Path) -> anyhow::Result<Vec<(PathBuf, usize)>> {
+    let mut found = Vec::new();
+    for entry in WalkDir::new(directory)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        let extension = path.extension().and_then(|s| s.to_str());
+        if !path.is_file() || !matches!(extension, Some("nix" | "json" | "toml")) {
+            continue;
+        }
+        let Ok(content) = fs::read_to_string(path).await else {
+            continue;
+        };
+        let count = count_fake_hashes(&content);
+        if count > 0 {
+            found.push((path.to_path_buf(), count));
+        }
+    }
+    Ok(found)
+}
+
+/// Attribute path a package file most likely defines, from the conventions of the tree
+///
+/// `pkgs/by-name/he/hello/package.nix`, `pkgs/hello/default.nix` and `pkgs/hello/sources.json`
+/// yield `hello`, other .nix files their own name.
+fn candidate_attr_path(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    let is_nix = path.extension().and_then(|s| s.to_str()) == Some("nix");
+    if is_nix && !matches!(stem, "default" | "package") {
+        return Some(stem.to_string());
+    }
+    Some(path.parent()?.file_name()?.to_str()?.to_string())
+}
+
+/// Find the package a file with placeholder hashes belongs to
+///
+/// The candidate attribute path must be defined in that file, or next to it for sidecar files.
+async fn locate_package(eval_entry_point: &str, path: &Path) -> Option<String> {
+    let attr_path = candidate_attr_path(path)?;
+    let location = get_file_location(eval_entry_point, &attr_path).await.ok()?;
+    let location = fs::canonicalize(&location).await.ok()?;
+    let path = fs::canonicalize(path).await.ok()?;
+    let defined_here = if path.extension().and_then(|s| s.to_str()) == Some("nix") {
+        location == path
+    } else {
+        location.parent() == path.parent()
+    };
+    defined_here.then_some(attr_path)
+}
+
+/// Replace the placeholder hashes of a file with the hashes reported by building the package
+///
+/// Returns the number of repaired hashes. Placeholders the build doesn't use are left alone.
+pub async fn repair_fake_hashes(
+    eval_entry_point: &str,
+    attr_path: &str,
+    path: &Path,
+    build_options: &BuildOptions,
+) -> anyhow::Result<usize> {
+    let content = fs::read_to_string(path).await?;
+    let count = count_fake_hashes(&content);
+    if count == 0 {
+        return Ok(0);
+    }
+    let numbered = number_placeholders(&content).ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} has more than {} placeholder hashes",
+            path.display(),
+            PLACEHOLDER_LETTERS.len()
+        )
+    })?;
+    fs::write(path, numbered).await?;
+
+    let mut repaired = 0;
+    for _ in 0..count {
+        let (success, _stdout, stderr) =
+            build_nix_expr(eval_entry_point, attr_path, None, build_options).await?;
+        if success {
+            break;
+        }
+
+        let Some((specified, got)) = parse_hash_mismatch(&stderr) else {
+            anyhow::bail!(
+                "Build of {} failed without a hash mismatch:\n{}",
+                attr_path,
+                stderr
+            );
+        };
+        let content = fs::read_to_string(path).await?;
+        if !content.contains(&specified) {
+            anyhow::bail!(
+                "Build of {} failed on hash {}, which isn't a placeholder of {}",
+                attr_path,
+                specified,
+                path.display()
+            );
+        }
+        fs::write(path, content.replacen(&specified, &got, 1)).await?;
+        info!("{}: replaced placeholder hash with {}", path.display(), got);
+        repaired += 1;
+    }
+    Ok(repaired)
+}
+
+/// Repair the placeholder hashes of every file in the tree
+///
+/// Returns the number of files repaired and the number of files which couldn't be.
+async fn repair_tree(
+    eval_entry_point: &str,
+    files: &[(PathBuf, usize)],
+    build_options: &BuildOptions,
+) -> (usize, usize) {
+    let (mut repaired, mut failed) = (0, 0);
+    for (path, _count) in files {
+        let Some(attr_path) = locate_package(eval_entry_point, path).await else {
+            error!(
+                "{}: can't tell which package it belongs to, pass its attribute path",
+                path.display()
+            );
+            failed += 1;
+            continue;
+        };
+        match repair_fake_hashes(eval_entry_point, &attr_path, path, build_options).await {
+            Ok(_) => repaired += 1,
+            Err(e) => {
+                error!(
+                    "{}: failed to repair placeholder hashes: {}",
+                    path.display(),
+                    e
+                );
+                failed += 1;
+            },
+        }
+    }
+    (repaired, failed)
+}
+
+/// Find placeholder hashes left by failed or interrupted updates and replace them with the
+/// correct hashes
+///
+/// Without attribute paths, the files of the tree are scanned and each is attributed to the
+/// package it defines by name. Otherwise only the files next to the given packages are repaired.
+///
+/// # Arguments
+/// * `check` - If true, only report the files with placeholder hashes and fail if there are any
+pub async fn fix_fake_hashes(
+    file: String,
+    attr_paths: Vec<String>,
+    build_options: BuildOptions,
+    check: bool,
+) -> anyhow::Result<()> {
+    let directory = entry_point_dir(&file);
+    info!("Looking for placeholder hashes in {}", directory.display());
+
+    let files = find_fake_hashes(directory).await?;
+    for (path, count) in &files {
+        warn!("{}: {} placeholder hashes", path.display(), count);
+    }
+    if files.is_empty() {
+        info!("No placeholder hashes found");
+        return Ok(());
+    }
+    if check {
+        anyhow::bail!(
+            "Check failed: {} files contain placeholder hashes",
+            files.len()
+        );
+    }
+
+    let (repaired, failed) = if attr_paths.is_empty() {
+        repair_tree(&file, &files, &build_options).await
+    } else {
+        let (mut repaired, mut failed) = (0, 0);
+        for attr_path in &attr_paths {
+            let location = PathBuf::from(get_file_location(&file, attr_path).await?);
+            let package_dir = fs::canonicalize(location.parent().unwrap_or(Path::new("."))).await?;
+            for (path, _count) in &files {
+                if fs::canonicalize(path).await?.parent() != Some(package_dir.as_path()) {
+                    continue;
+                }
+                match repair_fake_hashes(&file, attr_path, path, &build_options).await {
+                    Ok(_) => repaired += 1,
+                    Err(e) => {
+                        error!(
+                            "{}: failed to repair placeholder hashes: {}",
+                            path.display(),
+                            e
+                        );
+                        failed += 1;
+                    },
+                }
+            }
+        }
+        (repaired, failed)
+    };
+
+    info!("Completed: {} files repaired, {} errors", repaired, failed);
+    if failed > 0 {
+        anyhow::bail!(
+            "Failed to repair the placeholder hashes of {} files",
+            failed
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_placeholders() {
+        let fake = "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let content = format!(
+            "{{\n  src.hash = \"{}\";\n  cargoHash = \"{}\";\n  other = \
+             \"sha256-2lWOG1FI6b6jBoXJ6xYm5DBN3hj8hdWyHTSpd9z5nxg=\";\n}}",
+            fake, fake
+        );
+        assert_eq!(count_fake_hashes(&content), 2);
+
+        let numbered = number_placeholders(&content).unwrap();
+        assert!(numbered.contains(&format!("sha256-B{}=", "A".repeat(42))));
+        assert!(numbered.contains(&format!("sha256-C{}=", "A".repeat(42))));
+        assert!(!numbered.contains(fake));
+        // Numbered placeholders are still detected
+        assert_eq!(count_fake_hashes(&numbered), 2);
+    }
+
+    #[test]
+    fn test_parse_hash_mismatch() {
+        let stderr = r#"error: hash mismatch in fixed-output derivation '/nix/store/abc-source.drv':
+         specified: sha256-BAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-2lWOG1FI6b6jBoXJ6xYm5DBN3hj8hdWyHTSpd9z5nxg="#;
+        assert_eq!(
+            parse_hash_mismatch(stderr),
+            Some((
+                "sha256-BAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string(),
+                "sha256-2lWOG1FI6b6jBoXJ6xYm5DBN3hj8hdWyHTSpd9z5nxg=".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_candidate_attr_path() {
+        let candidate = |path: &str| candidate_attr_path(Path::new(path));
+        assert_eq!(
+            candidate("pkgs/by-name/he/hello/package.nix").as_deref(),
+            Some("hello")
+        );
+        assert_eq!(
+            candidate("pkgs/hello/default.nix").as_deref(),
+            Some("hello")
+        );
+        assert_eq!(
+            candidate("pkgs/hello/sources.json").as_deref(),
+            Some("hello")
+        );
+        assert_eq!(
+            candidate("pkgs/tools/ripgrep.nix").as_deref(),
+            Some("ripgrep")
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::database::{Database, UpdateEvent, UpdateLog, UpdateScriptLog};
+use crate::github::parse_github_url;
+use crate::nix::nix_eval_jobs::eval_error_summary;
+
+/// Output format of the history of a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum HistoryFormat {
+    /// Timeline for humans
+    Text,
+    /// JSON document for scripts
+    Json,
+}
+
+/// Entry of the timeline of a package
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct HistoryEntry {
+    /// In RFC 3339
+    timestamp: String,
+    /// `updated`, `version_changed`, `pr_created`, `failed` or `update_script`
+    kind: String,
+    old_version: Option<String>,
+    new_version: Option<String>,
+    pr_url: Option<String>,
+    pr_number: Option<i64>,
+    /// `open`, `merged` or `closed`, if it could be looked up
+    pr_state: Option<String>,
+    /// What a failed update failed on, or the outcome of an update script run
+    detail: Option<String>,
+}
+
+impl HistoryEntry {
+    fn new(timestamp: &str, kind: &str) -> Self {
+        Self {
+            timestamp: timestamp.to_string(),
+            kind: kind.to_string(),
+            old_version: None,
+            new_version: None,
+            pr_url: None,
+            pr_number: None,
+            pr_state: None,
+            detail: None,
+        }
+    }
+
+    /// Description of the entry for humans
+    fn describe(&self) -> String {
+        let versions = match (&self.old_version, &self.new_version) {
+            (Some(old), Some(new)) => format!(" {} → {}", old, new),
+            (Some(version), None) | (None, Some(version)) => format!(" {}", version),
+            (None, None) => String::new(),
+        };
+        let detail = self
+            .detail
+            .as_ref()
+            .map(|detail| format!(" ({})", detail))
+            .unwrap_or_default();
+        match self.kind.as_str() {
+            "updated" => format!("Updated{}", versions),
+            "version_changed" => format!("Changed{} outside of updates", versions),
+            "pr_created" => format!(
+                "Opened PR{}{} for{}: {}",
+                self.pr_number
+                    .map(|number| format!(" #{}", number))
+                    .unwrap_or_default(),
+                self.pr_state
+                    .as_ref()
+                    .map(|state| format!(" ({})", state))
+                    .unwrap_or_default(),
+                versions,
+                self.pr_url.as_deref().unwrap_or_default()
+            ),
+            "failed" => format!("Update{} failed{}", versions, detail),
+            "update_script" => format!("Ran update script{}", detail),
+            kind => format!("{}{}{}", kind, versions, detail),
+        }
+    }
+}
+
+/// Everything the database knows about a package
+#[derive(Debug, Clone, Serialize)]
+struct History {
+    attr_path: String,
+    current_version: Option<String>,
+    proposed_version: Option<String>,
+    /// Earliest time of the next update attempt, in RFC 3339
+    next_attempt: Option<String>,
+    /// Error of the package, if it fails to evaluate
+    eval_error: Option<String>,
+    /// Oldest first
+    timeline: Vec<HistoryEntry>,
+}
+
+/// Merge the records of a package into a single timeline, oldest first
+fn timeline(
+    events: Vec<UpdateEvent>,
+    failures: Vec<UpdateLog>,
+    script_logs: Vec<UpdateScriptLog>,
+) -> Vec<HistoryEntry> {
+    let mut entries: Vec<HistoryEntry> = events
+        .into_iter()
+        .map(|event| HistoryEntry {
+            old_version: event.old_version,
+            new_version: event.new_version,
+            pr_url: event.pr_url,
+            pr_number: event.pr_number,
+            ..HistoryEntry::new(&event.timestamp, &event.kind)
+        })
+        .collect();
+
+    entries.extend(failures.into_iter().map(|log| {
+        let detail = match (log.failure_kind, log.retry_strategy) {
+            (Some(kind), Some(strategy)) => Some(format!("{}, retry strategy {}", kind, strategy)),
+            (Some(kind), None) => Some(kind),
+            (None, Some(strategy)) => Some(format!("retry strategy {}", strategy)),
+            (None, None) => None,
+        };
+        HistoryEntry {
+            old_version: log.old_version,
+            new_version: log.new_version,
+            detail,
+            ..HistoryEntry::new(&log.timestamp, "failed")
+        }
+    }));
+
+    entries.extend(script_logs.into_iter().map(|log| HistoryEntry {
+        detail: Some(log.status),
+        ..HistoryEntry::new(&log.timestamp, "update_script")
+    }));
+
+    // Timestamps are all in UTC, so they sort chronologically as strings
+    entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    entries
+}
+
+/// Look up whether the PRs of the timeline are open, merged or closed
+async fn lookup_pr_states(entries: &mut [HistoryEntry], token: &str) {
+    for entry in entries {
+        let (Some(url), Some(number)) = (&entry.pr_url, entry.pr_number) else {
+            continue;
+        };
+        let Some(repo) = parse_github_url(url) else {
+            continue;
+        };
+        match crate::github::get_pull_request(&repo.owner, &repo.repo, number, token).await {
+            Ok(pr) => entry.pr_state = Some(pr.outcome().to_string()),
+            Err(e) => debug!("Failed to look up the state of {}: {}", url, e),
+        }
+    }
+}
+
+/// Show the update timeline of a package: version changes, failed updates and PRs
+///
+/// The state of PRs is looked up on GitHub if GITHUB_TOKEN is set.
+pub async fn show_history(
+    database_path: String,
+    attr_path: String,
+    format: HistoryFormat,
+) -> anyhow::Result<()> {
+    let expanded_db_path = shellexpand::tilde(&database_path).to_string();
+    let db = Database::new(&expanded_db_path).await?;
+
+    let record = db.get_update_record(&attr_path).await?;
+    let mut entries = timeline(
+        db.get_update_events(&attr_path).await?,
+        db.get_all_failed_logs_by_attr(&attr_path).await?,
+        db.get_update_script_logs(&attr_path).await?,
+    );
+    if let Ok(token) = std::env::var("GITHUB_TOKEN") {
+        lookup_pr_states(&mut entries, &token).await;
+    }
+
+    let history = History {
+        current_version: record.as_ref().and_then(|r| r.current_version.clone()),
+        proposed_version: record.as_ref().and_then(|r| r.proposed_version.clone()),
+        next_attempt: record
+            .as_ref()
+            .and_then(|r| r.next_attempt)
+            .map(|time| time.to_rfc3339()),
+        eval_error: db
+            .get_eval_error(&attr_path)
+            .await?
+            .map(|error| eval_error_summary(&error.error).to_string()),
+        timeline: entries,
+        attr_path,
+    };
+
+    match format {
+        HistoryFormat::Json => println!("{}", serde_json::to_string_pretty(&history)?),
+        HistoryFormat::Text => print_history(&history),
+    }
+    Ok(())
+}
+
+fn print_history(history: &History) {
+    if history.current_version.is_none() && history.timeline.is_empty() {
+        info!("No history found for {}", history.attr_path);
+        return;
+    }
+
+    info!("History of {}", history.attr_path);
+    if let Some(version) = &history.current_version {
+        info!("Current version:  {}", version);
+    }
+    if let Some(version) = &history.proposed_version {
+        info!("Proposed version: {}", version);
+    }
+    if let Some(next_attempt) = &history.next_attempt {
+        info!("Next attempt:     {}", next_attempt);
+    }
+    if let Some(error) = &history.eval_error {
+        info!("Fails to evaluate: {}", error);
+    }
+    info!("");
+
+    for entry in &history.timeline {
+        let timestamp = chrono::DateTime::parse_from_rfc3339(&entry.timestamp)
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|_| entry.timestamp.clone());
+        info!("  {}  {}", timestamp, entry.describe());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline() {
+        let entries = timeline(
+            vec![
+                UpdateEvent {
+                    timestamp: "2024-05-01T02:00:00+00:00".to_string(),
+                    kind: "updated".to_string(),
+                    old_version: Some("1.6".to_string()),
+                    new_version: Some("1.7".to_string()),
+                    pr_url: None,
+                    pr_number: None,
+                },
+                UpdateEvent {
+                    timestamp: "2024-05-01T02:05:00+00:00".to_string(),
+                    kind: "pr_created".to_string(),
+                    old_version: None,
+                    new_version: Some("1.7".to_string()),
+                    pr_url: Some("https://github.com/o/r/pull/12".to_string()),
+                    pr_number: Some(12),
+                },
+            ],
+            vec![UpdateLog {
+                drv_path: "/nix/store/abc-jq-1.6.drv".to_string(),
+                attr_path: "jq".to_string(),
+                timestamp: "2024-04-20T02:00:00+00:00".to_string(),
+                status: "failed".to_string(),
+                error_log: "error: builder failed".to_string(),
+                old_version: Some("1.6".to_string()),
+                new_version: Some("1.7".to_string()),
+                failure_kind: Some("compile-error".to_string()),
+                retry_strategy: None,
+            }],
+            Vec::new(),
+        );
+
+        let kinds: Vec<&str> = entries.iter().map(|e| e.kind.as_str()).collect();
+        assert_eq!(kinds, vec!["failed", "updated", "pr_created"]);
+        assert_eq!(
+            entries[0].describe(),
+            "Update 1.6 → 1.7 failed (compile-error)"
+        );
+        assert_eq!(entries[1].describe(), "Updated 1.6 → 1.7");
+
+        let mut pr = entries[2].clone();
+        pr.pr_state = Some("merged".to_string());
+        assert_eq!(
+            pr.describe(),
+            "Opened PR #12 (merged) for 1.7: https://github.com/o/r/pull/12"
+        );
+    }
+}
@@ -2,6 +2,7 @@ use anyhow::Context;
 use tracing::info;
 
 use crate::database::Database;
+use crate::nix::nix_eval_jobs::eval_error_summary;
 
 pub async fn show_log(database_path: String, identifier: String) -> anyhow::Result<()> {
     // Expand tilde in database path
@@ -34,9 +35,57 @@ async fn show_log_by_drv(db: &Database, drv_identifier: &str) -> anyhow::Result<
     Ok(())
 }
 
+/// List the packages failing to evaluate, as of the latest run
+pub async fn show_eval_errors(database_path: String) -> anyhow::Result<()> {
+    let expanded_db_path = shellexpand::tilde(&database_path).to_string();
+    let db = Database::new(&expanded_db_path).await?;
+
+    let errors = db.get_eval_errors().await?;
+    if errors.is_empty() {
+        info!("No packages fail to evaluate");
+        return Ok(());
+    }
+
+    info!("{} packages fail to evaluate:", errors.len());
+    for error in &errors {
+        info!(
+            "  {} (since {}): {}",
+            error.attr_path,
+            error.first_seen_as_datetime().format("%Y-%m-%d %H:%M:%S"),
+            eval_error_summary(&error.error)
+        );
+    }
+    info!("");
+    info!("Use 'ekapkgs-update log <attr-path>' to view the full error of a package");
+    Ok(())
+}
+
 async fn show_logs_by_attr(db: &Database, attr_path: &str) -> anyhow::Result<()> {
     let logs = db.get_all_failed_logs_by_attr(attr_path).await?;
 
+    if let Some(eval_error) = db.get_eval_error(attr_path).await? {
+        print_eval_error(&eval_error);
+        info!("");
+    }
+
+    if let Some(script_log) = db.get_latest_update_script_log(attr_path).await? {
+        print_update_script_log(&script_log);
+        info!("");
+    }
+
+    let test_results = db.get_latest_passthru_test_results(attr_path).await?;
+    if let Some(first) = test_results.first() {
+        info!(
+            "passthru.tests of {} ({}):",
+            first.version,
+            first.timestamp_as_datetime().format("%Y-%m-%d %H:%M:%S")
+        );
+        for result in &test_results {
+            info!("  {}: {}", result.test_name, result.status);
+        }
+        info!("");
+    }
+
     if logs.is_empty() {
         info!("No failed update logs found for {}", attr_path);
         return Ok(());
@@ -53,10 +102,14 @@ async fn show_logs_by_attr(db: &Database, attr_path: &str) -> anyhow::Result<()>
         info!("Previous failed attempts:");
         for (i, log) in logs.iter().skip(1).enumerate() {
             info!(
-                "  {}. {} ({})",
+                "  {}. {} ({}{})",
                 i + 2,
                 extract_drv_name(&log.drv_path),
-                log.timestamp_as_datetime().format("%Y-%m-%d %H:%M:%S")
+                log.timestamp_as_datetime().format("%Y-%m-%d %H:%M:%S"),
+                log.failure_kind
+                    .as_ref()
+                    .map(|kind| format!(", {}", kind))
+                    .unwrap_or_default()
             );
         }
         info!("");
@@ -85,6 +138,12 @@ fn print_log_entry(log: &crate::database::UpdateLog, show_full_log: bool) {
     }
 
     info!("Status:         {}", log.status);
+    if let Some(kind) = &log.failure_kind {
+        info!("Failure Kind:   {}", kind);
+    }
+    if let Some(strategy) = &log.retry_strategy {
+        info!("Retry Strategy: {}", strategy);
+    }
     info!("");
     info!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     info!("Error Log");
@@ -116,6 +175,52 @@ fn print_log_entry(log: &crate::database::UpdateLog, show_full_log: bool) {
     info!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
 }
 
+fn print_eval_error(error: &crate::database::EvalErrorRecord) {
+    info!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    info!("Evaluation Error");
+    info!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    info!("");
+    info!("Attribute Path: {}", error.attr_path);
+    info!(
+        "First Seen:     {}",
+        error
+            .first_seen_as_datetime()
+            .format("%Y-%m-%d %H:%M:%S %Z")
+    );
+    info!(
+        "Last Seen:      {}",
+        error.last_seen_as_datetime().format("%Y-%m-%d %H:%M:%S %Z")
+    );
+    info!("");
+    for line in error.error.lines() {
+        info!("{}", line);
+    }
+}
+
+fn print_update_script_log(log: &crate::database::UpdateScriptLog) {
+    info!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    info!("Latest Update Script Run");
+    info!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    info!("");
+    info!("Attribute Path: {}", log.attr_path);
+    info!(
+        "Timestamp:      {}",
+        log.timestamp_as_datetime().format("%Y-%m-%d %H:%M:%S %Z")
+    );
+    info!("Status:         {}", log.status);
+
+    for (name, output) in [("stdout", &log.stdout), ("stderr", &log.stderr)] {
+        if output.is_empty() {
+            continue;
+        }
+        info!("");
+        info!("{}:", name);
+        for line in output.lines() {
+            info!("{}", line);
+        }
+    }
+}
+
 fn extract_drv_name(drv_path: &str) -> &str {
     // Extract just the drv name from full path
     // E.g., "/nix/store/abc123-python-setuptools-1.2.3.drv" -> "abc123-python-setuptools-1.2.3.drv"
@@ -1,4 +1,12 @@
+pub mod diff;
+pub mod fix_fake_hashes;
+pub mod history;
 pub mod log;
+pub mod normalize_hashes;
+pub mod outdated;
 pub mod prune_maintainers;
+pub mod rebase_prs;
+pub mod rewrite_attr;
 pub mod run;
+pub mod sync_from_nixpkgs;
 pub mod update;
@@ -0,0 +1,97 @@
+use std::path::Path;
+
+use tokio::fs;
+use tracing::{debug, error, info, warn};
+use walkdir::WalkDir;
+
+use super::rewrite_attr::unified_diff;
+use crate::rewrite::normalize_sha256_hashes;
+
+/// Convert the legacy `sha256` arguments of fetchers to SRI `hash` arguments in all .nix files in
+/// a directory
+///
+/// Each file is checked to parse before and after the conversion. Files which read a `sha256`
+/// attribute, e.g. `src.sha256`, are left alone. The changes are printed as a unified diff.
+///
+/// # Arguments
+/// * `check` - If true, only print the changes and fail if there are any, without modifying files
+pub async fn normalize_hashes(directory: String, check: bool) -> anyhow::Result<()> {
+    let dir_path = Path::new(&directory);
+
+    if !dir_path.is_dir() {
+        anyhow::bail!("Not a directory: {}", directory);
+    }
+
+    info!("Normalizing sha256 hashes in .nix files in: {}", directory);
+
+    let mut processed_count = 0;
+    let mut modified_count = 0;
+    let mut converted_count = 0;
+    let mut error_count = 0;
+
+    for entry in WalkDir::new(dir_path)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        if !path.is_file() || path.extension().and_then(|s| s.to_str()) != Some("nix") {
+            continue;
+        }
+
+        processed_count += 1;
+        let content = fs::read_to_string(path).await?;
+        if !content.contains("sha256") {
+            continue;
+        }
+        let (updated, converted) = match normalize_sha256_hashes(&content) {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Error processing {}: {}", path.display(), e);
+                error_count += 1;
+                continue;
+            },
+        };
+        if converted == 0 {
+            debug!("No changes: {}", path.display());
+            continue;
+        }
+
+        print!(
+            "{}",
+            unified_diff(&path.to_string_lossy(), &content, &updated)
+        );
+        if !check {
+            fs::write(path, updated).await?;
+        }
+        modified_count += 1;
+        converted_count += converted;
+    }
+
+    info!(
+        "Completed: {} files processed, {} {} ({} hashes), {} errors",
+        processed_count,
+        modified_count,
+        if check {
+            "would be modified"
+        } else {
+            "modified"
+        },
+        converted_count,
+        error_count
+    );
+
+    if check && modified_count > 0 {
+        error!(
+            "Check failed: {} files have sha256 hashes to normalize",
+            modified_count
+        );
+        anyhow::bail!(
+            "Check failed: {} files have sha256 hashes to normalize",
+            modified_count
+        );
+    }
+
+    Ok(())
+}
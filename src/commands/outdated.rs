@@ -0,0 +1,212 @@
+use futures::StreamExt;
+use tracing::{debug, info};
+
+use crate::commands::update::{ProposedUpdate, find_release};
+use crate::config::Config;
+use crate::nix::nix_eval_jobs::NixEvalItem;
+use crate::nix::run_eval::{EvalJobsOptions, run_nix_eval_jobs};
+use crate::nixpkgs;
+use crate::package::PackageMetadata;
+use crate::timings::PhaseTimings;
+use crate::vcs_sources::SemverStrategy;
+
+/// A package whose upstream published a newer release
+#[derive(Debug, Clone, PartialEq)]
+struct OutdatedPackage {
+    attr_path: String,
+    current_version: String,
+    latest_version: String,
+    source: String,
+    /// Comparison with nixpkgs, if a nixpkgs checkout is configured
+    nixpkgs: Option<String>,
+}
+
+/// Print the packages with newer upstream releases, failing if there are any
+///
+/// Only package metadata is evaluated and upstream queried, nothing is built or rewritten, so
+/// it's quick enough for a CI gate or a scheduled freshness report.
+pub async fn outdated(
+    file: String,
+    semver_strategy: String,
+    concurrency: Option<usize>,
+    skip_unstable: bool,
+    eval_jobs_options: EvalJobsOptions,
+    config: Config,
+) -> anyhow::Result<()> {
+    let strategy: SemverStrategy = semver_strategy.parse()?;
+    let concurrency = concurrency.unwrap_or_else(num_cpus::get).max(1);
+
+    info!("Running nix-eval-jobs on: {}", file);
+    let items: Vec<anyhow::Result<NixEvalItem>> =
+        run_nix_eval_jobs(file.clone(), eval_jobs_options)
+            .collect()
+            .await;
+    let mut attr_paths = Vec::new();
+    for item in items {
+        match item? {
+            NixEvalItem::Drv(drv) => attr_paths.push(drv.attr),
+            NixEvalItem::Error(e) => debug!("Evaluation error: {:?}", e),
+        }
+    }
+    info!("Checking {} packages for newer releases", attr_paths.len());
+
+    let mut outdated: Vec<OutdatedPackage> = futures::stream::iter(&attr_paths)
+        .map(|attr_path| check_package(&file, attr_path, strategy, skip_unstable, &config))
+        .buffer_unordered(concurrency)
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+    outdated.sort_by(|a, b| a.attr_path.cmp(&b.attr_path));
+
+    if outdated.is_empty() {
+        info!("All {} packages are up to date", attr_paths.len());
+        return Ok(());
+    }
+
+    println!("{}", format_outdated_table(&outdated));
+    anyhow::bail!(
+        "{} of {} packages are outdated",
+        outdated.len(),
+        attr_paths.len()
+    );
+}
+
+/// Look up the latest release of a package, None if it's up to date or can't be checked
+async fn check_package(
+    eval_entry_point: &str,
+    attr_path: &str,
+    strategy: SemverStrategy,
+    skip_unstable: bool,
+    config: &Config,
+) -> Option<OutdatedPackage> {
+    let metadata = match PackageMetadata::from_attr_path(eval_entry_point, attr_path).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            debug!("{}: Failed to extract metadata: {}", attr_path, e);
+            return None;
+        },
+    };
+    if (skip_unstable && metadata.version.contains("unstable"))
+        || metadata.directives.skip.is_some()
+    {
+        return None;
+    }
+    let ProposedUpdate {
+        metadata,
+        upstream_source,
+        new_version: latest_version,
+        ..
+    } = match find_release(
+        eval_entry_point,
+        attr_path,
+        metadata,
+        strategy,
+        config,
+        &mut PhaseTimings::default(),
+    )
+    .await
+    {
+        Ok(proposed) => proposed,
+        Err(e) => {
+            debug!("{}: Failed to fetch upstream release: {:#}", attr_path, e);
+            return None;
+        },
+    };
+    if latest_version == metadata.version {
+        return None;
+    }
+    let nixpkgs = match &config.nixpkgs {
+        Some(nixpkgs) => Some(nixpkgs::compare(nixpkgs, attr_path, &latest_version).await),
+        None => None,
+    };
+    Some(OutdatedPackage {
+        attr_path: attr_path.to_string(),
+        current_version: metadata.version,
+        latest_version,
+        source: upstream_source.description(),
+        nixpkgs,
+    })
+}
+
+/// Render outdated packages as an aligned plain text table
+///
+/// The nixpkgs column is only shown if packages were compared with nixpkgs.
+fn format_outdated_table(packages: &[OutdatedPackage]) -> String {
+    let with_nixpkgs = packages.iter().any(|p| p.nixpkgs.is_some());
+    let mut header = vec!["Package", "Current", "Latest", "Source"];
+    if with_nixpkgs {
+        header.push("nixpkgs");
+    }
+    let rows: Vec<Vec<&str>> = packages
+        .iter()
+        .map(|p| {
+            let mut row = vec![
+                p.attr_path.as_str(),
+                p.current_version.as_str(),
+                p.latest_version.as_str(),
+                p.source.as_str(),
+            ];
+            if with_nixpkgs {
+                row.push(p.nixpkgs.as_deref().unwrap_or(""));
+            }
+            row
+        })
+        .collect();
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    std::iter::once(header)
+        .chain(rows)
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_outdated_table() {
+        let table = format_outdated_table(&[OutdatedPackage {
+            attr_path: "hello".to_string(),
+            current_version: "2.12".to_string(),
+            latest_version: "2.12.1".to_string(),
+            source: "GitHub repo: gnu/hello".to_string(),
+            nixpkgs: None,
+        }]);
+        assert_eq!(
+            table,
+            "Package  Current  Latest  Source\nhello    2.12     2.12.1  GitHub repo: gnu/hello"
+        );
+    }
+
+    #[test]
+    fn test_format_outdated_table_with_nixpkgs() {
+        let table = format_outdated_table(&[OutdatedPackage {
+            attr_path: "hello".to_string(),
+            current_version: "2.12".to_string(),
+            latest_version: "2.12.1".to_string(),
+            source: "gnu mirror: hello/hello-2.12.tar.gz".to_string(),
+            nixpkgs: Some("nixpkgs has 2.12.1 (same)".to_string()),
+        }]);
+        assert_eq!(
+            table,
+            "Package  Current  Latest  Source                               nixpkgs\nhello    \
+             2.12     2.12.1  gnu mirror: hello/hello-2.12.tar.gz  nixpkgs has 2.12.1 (same)"
+        );
+    }
+}
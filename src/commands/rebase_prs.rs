@@ -0,0 +1,87 @@
+use tracing::{info, warn};
+
+use crate::git::{
+    PrConfig, RebaseOutcome, get_remote_github_repo, list_remote_branches, rebase_branch,
+};
+use crate::github::list_pull_requests_from_branch;
+
+/// Rebase the branches of open update PRs onto the latest base branch
+///
+/// Long-lived PRs otherwise conflict with later changes to the package set. Branches whose
+/// rebase conflicts are left untouched and reported.
+pub async fn rebase_prs(
+    upstream: Option<String>,
+    fork: String,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let pr_config = match upstream {
+        Some(remote_name) => crate::git::get_pr_config_from_remote(&remote_name).await?,
+        None => crate::git::get_pr_config_from_git().await?,
+    };
+    let github_token = std::env::var("GITHUB_TOKEN")
+        .map_err(|_| anyhow::anyhow!("GITHUB_TOKEN environment variable not set"))?;
+
+    let branches = open_pr_branches(&pr_config, &fork, &github_token).await?;
+    info!(
+        "Found {} open update PRs against {}/{}:{}",
+        branches.len(),
+        pr_config.owner,
+        pr_config.repo,
+        pr_config.base_branch
+    );
+
+    let mut rebased = 0;
+    let mut conflicts = Vec::new();
+    for (branch, pr_number) in &branches {
+        if dry_run {
+            info!("Would rebase {} (PR #{})", branch, pr_number);
+            continue;
+        }
+
+        match rebase_branch(&pr_config, &fork, branch).await {
+            Ok(RebaseOutcome::Rebased) => {
+                info!("✓ Rebased {} (PR #{})", branch, pr_number);
+                rebased += 1;
+            },
+            Ok(RebaseOutcome::UpToDate) => info!("{} is up to date", branch),
+            Ok(RebaseOutcome::Conflict(reason)) => {
+                warn!("✗ {} (PR #{}) conflicts: {}", branch, pr_number, reason);
+                conflicts.push(format!("{} (PR #{})", branch, pr_number));
+            },
+            Err(e) => warn!("✗ Failed to rebase {}: {:#}", branch, e),
+        }
+    }
+
+    if !dry_run {
+        info!("Rebased {} of {} open update PRs", rebased, branches.len());
+        if !conflicts.is_empty() {
+            warn!(
+                "{} PRs need a manual rebase: {}",
+                conflicts.len(),
+                conflicts.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Update branches of the fork with an open PR, with the PR number
+async fn open_pr_branches(
+    pr_config: &PrConfig,
+    fork: &str,
+    token: &str,
+) -> anyhow::Result<Vec<(String, i64)>> {
+    let fork_repo = get_remote_github_repo(fork).await?;
+
+    let mut branches = Vec::new();
+    for branch in list_remote_branches(fork, "update/").await? {
+        let head = format!("{}:{}", fork_repo.owner, branch);
+        let pull_requests =
+            list_pull_requests_from_branch(&pr_config.owner, &pr_config.repo, &head, token).await?;
+        if let Some(pr) = pull_requests.iter().find(|pr| pr.state == "open") {
+            branches.push((branch, pr.number));
+        }
+    }
+    Ok(branches)
+}
@@ -0,0 +1,153 @@
+use std::path::Path;
+
+use tokio::fs;
+use tracing::{debug, error, info, warn};
+use walkdir::WalkDir;
+
+use crate::rewrite::find_and_update_attr;
+
+/// Set an attribute to a new value in all .nix files in a directory
+///
+/// Every binding of `attr_name` holding a string is rewritten, or only those holding
+/// `old_value` if given, e.g. to move a shared `homepage` to a new domain or bump a shared
+/// toolchain version. The changes are printed as a unified diff.
+///
+/// # Arguments
+/// * `check` - If true, only print the changes and fail if there are any, without modifying files
+pub async fn rewrite_attr(
+    directory: String,
+    attr_name: String,
+    new_value: String,
+    old_value: Option<String>,
+    check: bool,
+) -> anyhow::Result<()> {
+    let dir_path = Path::new(&directory);
+
+    if !dir_path.is_dir() {
+        anyhow::bail!("Not a directory: {}", directory);
+    }
+
+    info!(
+        "Rewriting {} to \"{}\" in .nix files in: {}",
+        attr_name, new_value, directory
+    );
+
+    let mut processed_count = 0;
+    let mut modified_count = 0;
+    let mut error_count = 0;
+
+    for entry in WalkDir::new(dir_path)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        if !path.is_file() || path.extension().and_then(|s| s.to_str()) != Some("nix") {
+            continue;
+        }
+
+        processed_count += 1;
+        let content = fs::read_to_string(path).await?;
+        let updated =
+            match find_and_update_attr(&content, &attr_name, &new_value, old_value.as_deref()) {
+                Ok(updated) => updated,
+                Err(e) if e.to_string().contains("not found") => continue,
+                Err(e) => {
+                    warn!("Error processing {}: {}", path.display(), e);
+                    error_count += 1;
+                    continue;
+                },
+            };
+        if updated == content {
+            debug!("No changes: {}", path.display());
+            continue;
+        }
+
+        print!(
+            "{}",
+            unified_diff(&path.to_string_lossy(), &content, &updated)
+        );
+        if !check {
+            fs::write(path, updated).await?;
+        }
+        modified_count += 1;
+    }
+
+    info!(
+        "Completed: {} files processed, {} {}, {} errors",
+        processed_count,
+        modified_count,
+        if check {
+            "would be modified"
+        } else {
+            "modified"
+        },
+        error_count
+    );
+
+    if check && modified_count > 0 {
+        error!(
+            "Check failed: {} files would be modified by rewrite-attr",
+            modified_count
+        );
+        anyhow::bail!(
+            "Check failed: {} files would be modified by rewrite-attr",
+            modified_count
+        );
+    }
+
+    Ok(())
+}
+
+/// Unified diff of a rewrite, with a hunk for each changed line
+///
+/// Rewrites only replace string contents, so lines keep their position. Should the line count
+/// change anyway, e.g. for a multi-line value, the whole file is diffed as a single hunk.
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let mut diff = format!("--- {}\n+++ {}\n", path, path);
+
+    if old_lines.len() == new_lines.len() {
+        for (index, (old_line, new_line)) in old_lines.iter().zip(&new_lines).enumerate() {
+            if old_line != new_line {
+                diff.push_str(&format!(
+                    "@@ -{line},1 +{line},1 @@\n-{}\n+{}\n",
+                    old_line,
+                    new_line,
+                    line = index + 1
+                ));
+            }
+        }
+    } else {
+        diff.push_str(&format!(
+            "@@ -1,{} +1,{} @@\n",
+            old_lines.len(),
+            new_lines.len()
+        ));
+        for line in &old_lines {
+            diff.push_str(&format!("-{}\n", line));
+        }
+        for line in &new_lines {
+            diff.push_str(&format!("+{}\n", line));
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "{\n  version = \"1.0\";\n  src = ./.;\n  rust = \"1.80\";\n}\n";
+        let new = "{\n  version = \"1.0\";\n  src = ./.;\n  rust = \"1.81\";\n}\n";
+        assert_eq!(
+            unified_diff("pkgs/foo/default.nix", old, new),
+            "--- pkgs/foo/default.nix\n+++ pkgs/foo/default.nix\n@@ -4,1 +4,1 @@\n-  rust = \
+             \"1.80\";\n+  rust = \"1.81\";\n"
+        );
+    }
+}
@@ -1,27 +1,577 @@
-use futures::{StreamExt, pin_mut};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::{Stream, StreamExt};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, Semaphore};
 use tokio::task::JoinSet;
 use tracing::{debug, info, warn};
 
+use crate::commands::fix_fake_hashes::find_fake_hashes;
+use crate::commands::update::{UpdateOptions, parse_dependency_hash_attrs};
+use crate::config::{Config, PackageConfig};
 use crate::database::Database;
-use crate::git::{PrConfig, cleanup_worktree, create_worktree};
-use crate::nix;
-use crate::nix::nix_eval_jobs::NixEvalItem;
-use crate::nix::{eval_nix_expr, normalize_entry_point};
-use crate::package::PackageMetadata;
-use crate::vcs_sources::{SemverStrategy, UpstreamSource};
-
-pub async fn run(
-    file: String,
-    database_path: String,
-    upstream: Option<String>,
+use crate::git::{
+    CommitStep, PrConfig, cleanup_stale_worktrees, cleanup_worktree, create_worktree_at,
+    delete_closed_update_branches, fetch_base_branch, file_at_revision, update_trailers,
+    worktree_path,
+};
+use crate::groups::{PackageGroup, PackageGroups, derive_upstream_groups};
+use crate::hooks::{HookContext, HookStage, run_hook};
+use crate::load::adapt_concurrency;
+use crate::nix::build_failure::UpdateFailureKind;
+use crate::nix::nix_eval_jobs::{
+    NixEvalError, NixEvalItem, ReverseDependencyIndex, eval_error_summary,
+};
+use crate::nix::run_eval::EvalJobsOptions;
+use crate::nix::{
+    BuildOptions, build_nix_expr, dry_run_build_nix_expr, eval_nix_expr, import_entry_point,
+    normalize_entry_point,
+};
+use crate::osv::SecurityStatus;
+use crate::package::{PackageMetadata, PackageQuery};
+use crate::plan::{Plan, PlannedUpdate};
+use crate::plugin::{PluginInput, SourcePlugin};
+use crate::progress::RunProgress;
+use crate::pypi::PythonRequirements;
+use crate::retry::RetryStrategy;
+use crate::rewrite::replace_version;
+use crate::summary::{PackageStatus, PackageSummary, RunSummary, excerpt};
+use crate::timings::{PhaseTimings, UpdatePhase, format_duration};
+use crate::update_script::{UpdateScript, run_update_script};
+use crate::vcs_sources::{
+    NoCompatibleRelease, SemverStrategy, UpstreamSource, is_version_acceptable, set_release_cache,
+    with_releases_fetched_after,
+};
+use crate::webhook::{self, WebhookTargets};
+use crate::withdrawn::query_withdrawn_versions;
+use crate::{nix, nixpkgs};
+
+/// Maximum number of reverse dependencies verified per update
+const MAX_REVERSE_DEPS: usize = 20;
+
+/// How reverse dependencies of an updated package are verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReverseDepsMode {
+    /// Build each reverse dependency
+    Build,
+    /// Only instantiate each reverse dependency with `nix-build --dry-run`
+    DryRun,
+}
+
+/// Settings shared by every package update of a run
+#[derive(Clone)]
+struct RunOptions {
     fork: String,
-    run_passthru_tests: bool,
     dry_run: bool,
-    concurrent_updates: Option<usize>,
     skip_unstable: bool,
-) -> anyhow::Result<()> {
+    ignore_update_script: bool,
+    update_options: UpdateOptions,
+    verify_reverse_deps: Option<ReverseDepsMode>,
+    reverse_deps: Arc<ReverseDependencyIndex>,
+    check_advisories: bool,
+    security_only: bool,
+    /// Only update the packages of this plan, to its versions
+    plan: Option<Plan>,
+    /// Consecutive failures of a package after which an issue is opened
+    failure_issue_threshold: Option<i64>,
+    /// Packages being rewritten and built, limited separately from packages being checked
+    build_slots: Arc<Semaphore>,
+    interrupts: Arc<Interrupts>,
+    budget: Arc<UpdateBudget>,
+    /// Check release feeds before querying the API of GitHub packages
+    release_feeds: bool,
+    /// Remote-tracking branch of the upstream base branch, None if it couldn't be fetched
+    upstream_base: Option<String>,
+    file_locks: Arc<FileLocks>,
+}
+
+/// Interrupts (Ctrl+C) and termination requests (SIGTERM) during a run
+///
+/// The first one stops new updates from starting and lets the ones in progress finish, the
+/// second one cancels them. Children run in their own process group, so a Ctrl+C in the terminal
+/// only reaches the run.
+#[derive(Default)]
+struct Interrupts {
+    count: AtomicUsize,
+    notify: Notify,
+}
+
+impl Interrupts {
+    /// Count interrupts and termination requests until the run ends
+    fn listen(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(e) => {
+                    warn!("Failed to listen for SIGTERM: {}", e);
+                    return;
+                },
+            };
+            loop {
+                tokio::select! {
+                    result = tokio::signal::ctrl_c() => if result.is_err() {
+                        break;
+                    },
+                    received = terminate.recv() => if received.is_none() {
+                        break;
+                    },
+                }
+                if self.count.fetch_add(1, Ordering::SeqCst) == 0 {
+                    warn!(
+                        "Interrupted, finishing the updates in progress (interrupt again to \
+                         cancel them)"
+                    );
+                } else {
+                    warn!("Cancelling the updates in progress");
+                }
+                self.notify.notify_waiters();
+            }
+        })
+    }
+
+    /// Whether no more updates should be started
+    fn stopping(&self) -> bool {
+        self.count.load(Ordering::SeqCst) > 0
+    }
+
+    /// Wait until the run was interrupted `count` times
+    async fn reached(&self, count: usize) {
+        loop {
+            // Registered before checking, so a press in between isn't missed
+            let notified = self.notify.notified();
+            if self.count.load(Ordering::SeqCst) >= count {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Run an update task until it finishes or is cancelled, removing the worktree it may have
+    /// left behind in the latter case
+    async fn cancellable(
+        &self,
+        worktree_name: &str,
+        update: impl std::future::Future<Output = anyhow::Result<UpdateResult>>,
+    ) -> anyhow::Result<UpdateResult> {
+        tokio::select! {
+            result = update => result,
+            _ = self.reached(2) => {
+                if let Ok(path) = worktree_path(worktree_name) {
+                    if let Err(e) = cleanup_worktree(&path).await {
+                        warn!("{}: Failed to clean up worktree: {}", worktree_name, e);
+                    }
+                }
+                Ok(UpdateResult::Skipped("Cancelled".to_string()))
+            },
+        }
+    }
+}
+
+/// Reason of the updates not carried out once the budget of the run is spent
+const BUDGET_SPENT: &str = "Update budget of the run spent";
+
+/// Limits of the updates carried out by a run, keeping the PRs of scheduled runs predictable
+///
+/// Packages not updated once the budget is spent aren't recorded in the database, so the next
+/// run picks them up again.
+struct UpdateBudget {
+    max_updates: Option<usize>,
+    deadline: Option<Instant>,
+    /// Updates carried out or in progress
+    updates: AtomicUsize,
+}
+
+impl UpdateBudget {
+    fn new(max_updates: Option<usize>, max_duration: Option<Duration>) -> Self {
+        Self {
+            max_updates,
+            deadline: max_duration.map(|duration| Instant::now() + duration),
+            updates: AtomicUsize::new(0),
+        }
+    }
+
+    fn out_of_time(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Whether no more updates may be started
+    fn spent(&self) -> bool {
+        self.out_of_time()
+            || self
+                .max_updates
+                .is_some_and(|max| self.updates.load(Ordering::SeqCst) >= max)
+    }
+
+    /// Reserve an update, None if the budget is spent
+    fn claim(&self) -> Option<BudgetClaim<'_>> {
+        if self.out_of_time() {
+            return None;
+        }
+        self.updates
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |updates| {
+                match self.max_updates {
+                    Some(max) if updates >= max => None,
+                    _ => Some(updates + 1),
+                }
+            })
+            .ok()?;
+        Some(BudgetClaim {
+            budget: self,
+            kept: false,
+        })
+    }
+}
+
+/// Update reserved in the budget of a run, given back unless it's kept
+struct BudgetClaim<'a> {
+    budget: &'a UpdateBudget,
+    kept: bool,
+}
+
+impl BudgetClaim<'_> {
+    /// Count the update against the budget, once it was carried out
+    fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for BudgetClaim<'_> {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.budget.updates.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Update proposed in a run, which later updates of the files it changed build upon
+#[derive(Debug, Clone)]
+struct ChainedUpdate {
+    /// Package or group which was updated
+    label: String,
+    /// Commit of the update, in the branch of its PR
+    commit: String,
+    proposed_at: std::time::Instant,
+}
+
+/// Attr path checked for each derivation of a run, aliases of it are skipped
+///
+/// Packages requested by webhooks are always checked: later releases request the same
+/// package again, and a requested alias was asked for explicitly.
+struct SeenDrvs {
+    listening: bool,
+    canonical: HashMap<String, String>,
+}
+
+impl SeenDrvs {
+    fn new(listening: bool) -> Self {
+        Self {
+            listening,
+            canonical: HashMap::new(),
+        }
+    }
+
+    /// Attr path checked for the derivation of `drv` if it's an alias, recording it otherwise
+    fn alias_of(&mut self, drv: &crate::nix::nix_eval_jobs::NixEvalDrv) -> Option<String> {
+        if self.listening {
+            return None;
+        }
+        if let Some(canonical) = self.canonical.get(&drv.drv_path) {
+            return Some(canonical.clone());
+        }
+        self.canonical
+            .insert(drv.drv_path.clone(), drv.attr.clone());
+        None
+    }
+}
+
+/// Files being rewritten by the updates of a run
+///
+/// Packages defined in the same file, e.g. variants, are updated one after the other. Each
+/// update starts from the commit of the last update proposed for its files, so the PRs of a
+/// file form a chain instead of conflicting with each other.
+#[derive(Default)]
+struct FileLocks {
+    files: std::sync::Mutex<HashMap<PathBuf, Arc<Mutex<Option<ChainedUpdate>>>>>,
+}
+
+/// Files locked for an update, see [`FileLocks::lock`]
+struct FileClaim {
+    guards: Vec<OwnedMutexGuard<Option<ChainedUpdate>>>,
+}
+
+impl FileClaim {
+    /// Last update proposed for the files, which the update must start from
+    fn chained_to(&self) -> Option<&ChainedUpdate> {
+        self.guards
+            .iter()
+            .filter_map(|guard| guard.as_ref())
+            .max_by_key(|update| update.proposed_at)
+    }
+
+    /// Commit the worktree of the update is created at
+    fn start_point(&self) -> &str {
+        self.chained_to()
+            .map_or("HEAD", |update| update.commit.as_str())
+    }
+
+    /// PR body section pointing at the update this one builds upon
+    fn report(&self) -> Option<String> {
+        self.chained_to().map(|update| {
+            format!(
+                "## Based on\n\nThis update builds upon the update of `{}`, which changes the \
+                 same files. Merge that one first.",
+                update.label
+            )
+        })
+    }
+
+    /// Record the update proposed in `worktree_path`, which later updates of the files start
+    /// from
+    async fn chain(&mut self, label: &str, worktree_path: &Path) {
+        let commit = match crate::git::head_commit(worktree_path).await {
+            Ok(commit) => commit,
+            Err(e) => {
+                warn!(
+                    "{}: Failed to resolve the commit of the update: {:#}",
+                    label, e
+                );
+                return;
+            },
+        };
+        let update = ChainedUpdate {
+            label: label.to_string(),
+            commit,
+            proposed_at: std::time::Instant::now(),
+        };
+        for guard in &mut self.guards {
+            **guard = Some(update.clone());
+        }
+    }
+}
+
+impl FileLocks {
+    /// Lock the given files for the update of `label`, waiting for other updates holding them
+    ///
+    /// Files are locked in a consistent order, so updates of several files don't deadlock.
+    async fn lock(&self, label: &str, files: &[String]) -> FileClaim {
+        let mut paths: Vec<PathBuf> = files
+            .iter()
+            .map(|file| std::fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file)))
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        let mut guards = Vec::new();
+        for path in paths {
+            let lock = self
+                .files
+                .lock()
+                .unwrap()
+                .entry(path.clone())
+                .or_default()
+                .clone();
+            let guard = match lock.clone().try_lock_owned() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    info!(
+                        "{}: Waiting for the update of another package defined in {}",
+                        label,
+                        path.display()
+                    );
+                    lock.lock_owned().await
+                },
+            };
+            guards.push(guard);
+        }
+        FileClaim { guards }
+    }
+}
+
+/// Options of a run, as given on the command line
+#[derive(Debug)]
+pub struct RunArgs {
+    /// Nix file to evaluate
+    pub file: String,
+    /// Path of the database of update attempts
+    pub database_path: String,
+    /// Upstream git remote PRs are opened against, inferred if unset
+    pub upstream: Option<String>,
+    /// Remote to push update branches to
+    pub fork: String,
+    /// Build passthru.tests after the package build
+    pub run_passthru_tests: bool,
+    /// Seconds each passthru test may take to build
+    pub passthru_test_timeout: u64,
+    /// Timeout and resource limits of package builds
+    pub build_options: BuildOptions,
+    /// Additional systems to build updates for, if the packages support them
+    pub verify_systems: Vec<String>,
+    /// Platforms to cross-compile updates for
+    pub verify_cross_systems: Vec<String>,
+    /// Check for updates without modifying files
+    pub dry_run: bool,
+    /// Number of updates built concurrently, the number of CPUs by default
+    pub concurrent_updates: Option<usize>,
+    /// Number of packages checked for updates concurrently
+    pub concurrent_checks: Option<usize>,
+    /// Scale the concurrent updates with the load of the machine
+    pub adaptive_concurrency: bool,
+    /// Check release feeds before querying the API of GitHub packages
+    pub release_feeds: bool,
+    /// Seconds the releases of an upstream are cached
+    pub release_cache_ttl: u64,
+    /// Skip packages with unstable versions
+    pub skip_unstable: bool,
+    /// Update packages with an updateScript like the others
+    pub ignore_update_script: bool,
+    /// Dependency FOD hashes to refresh after the source hash
+    pub dependency_hash_attrs: Vec<String>,
+    /// Formatter command run on rewritten Nix files
+    pub formatter: Option<String>,
+    /// Build the reverse dependencies of updated packages
+    pub verify_reverse_deps: Option<ReverseDepsMode>,
+    /// Look up the known vulnerabilities of packages
+    pub check_advisories: bool,
+    /// Only update packages with vulnerabilities fixed by the new version
+    pub security_only: bool,
+    /// Update packages marked insecure before the others
+    pub prioritize_insecure: bool,
+    /// GitHub handles of maintainers who don't want to be pinged in PRs
+    pub maintainer_opt_out: Vec<String>,
+    /// File defining groups of packages updated together
+    pub groups_file: Option<String>,
+    /// Group packages sharing an upstream source
+    pub auto_group: bool,
+    /// Keep the branches of closed update PRs
+    pub keep_closed_branches: bool,
+    /// Commit each logical change of an update separately
+    pub split_commits: bool,
+    /// Write the updates found to a plan instead of carrying them out
+    pub plan_out: Option<String>,
+    /// Plan whose updates are carried out
+    pub apply: Option<String>,
+    /// Consecutive failures of a package after which a tracking issue is opened
+    pub failure_issue_threshold: Option<i64>,
+    /// Show the progress of the run
+    pub show_progress: bool,
+    /// Address to listen on for webhooks triggering updates
+    pub listen: Option<String>,
+    /// Number of updates after which the run stops
+    pub max_updates: Option<usize>,
+    /// Time after which the run stops starting updates
+    pub max_duration: Option<Duration>,
+    /// Files the summary of the run is written to
+    pub summary_out: Vec<String>,
+    /// Options of the evaluation of the tree
+    pub eval_jobs_options: EvalJobsOptions,
+    /// Per-package settings
+    pub config: Config,
+}
+
+/// Check the packages of a tree for updates and carry them out
+pub async fn run(args: RunArgs) -> anyhow::Result<()> {
+    let RunArgs {
+        file,
+        database_path,
+        upstream,
+        fork,
+        run_passthru_tests,
+        passthru_test_timeout,
+        build_options,
+        verify_systems,
+        verify_cross_systems,
+        dry_run,
+        concurrent_updates,
+        concurrent_checks,
+        adaptive_concurrency,
+        release_feeds,
+        release_cache_ttl,
+        skip_unstable,
+        ignore_update_script,
+        dependency_hash_attrs,
+        formatter,
+        verify_reverse_deps,
+        check_advisories,
+        security_only,
+        prioritize_insecure,
+        maintainer_opt_out,
+        groups_file,
+        auto_group,
+        keep_closed_branches,
+        split_commits,
+        plan_out,
+        apply,
+        failure_issue_threshold,
+        show_progress,
+        listen,
+        max_updates,
+        max_duration,
+        summary_out,
+        mut eval_jobs_options,
+        mut config,
+    } = args;
+
+    // The time budget includes evaluating the tree
+    let budget = Arc::new(UpdateBudget::new(max_updates, max_duration));
+
+    let mut groups = match groups_file {
+        Some(path) => {
+            let expanded_path = shellexpand::tilde(&path).to_string();
+            let groups = PackageGroups::load(Path::new(&expanded_path)).await?;
+            if groups.is_empty() {
+                warn!("{} defines no package groups", path);
+            } else {
+                info!("Loaded {} package groups from {}", groups.len(), path);
+            }
+            groups
+        },
+        None => PackageGroups::default(),
+    };
+
+    // Applied plans pin every planned package to its target version
+    let plan = match apply {
+        Some(path) => {
+            let expanded_path = shellexpand::tilde(&path).to_string();
+            let plan = Plan::load(Path::new(&expanded_path)).await?;
+            info!(
+                "Applying {} planned updates from {}",
+                plan.updates.len(),
+                path
+            );
+            for update in &plan.updates {
+                config.pin_version(&update.attr_path, &update.target_version);
+            }
+            Some(plan)
+        },
+        None => None,
+    };
+
     info!("Running nix-eval-jobs on: {}", file);
 
+    // Commits and PRs are handled separately by create_pr_for_update
+    let update_options = UpdateOptions {
+        strategy: SemverStrategy::Latest,
+        run_passthru_tests,
+        passthru_test_timeout: Duration::from_secs(passthru_test_timeout),
+        build_options,
+        verify_systems,
+        verify_cross_systems,
+        maintainer_opt_out,
+        fail_on_test_failure: run_passthru_tests, // Fail on test errors in run mode
+        dependency_hash_attrs: parse_dependency_hash_attrs(&dependency_hash_attrs)?,
+        formatter,
+        split_commits,
+        config,
+        ..Default::default()
+    };
+
     // Expand tilde in database path
     let expanded_db_path = shellexpand::tilde(&database_path).to_string();
 
@@ -29,12 +579,26 @@ pub async fn run(
     let db = Database::new(&expanded_db_path).await?;
     info!("Database initialized at: {}", expanded_db_path);
 
-    // Calculate concurrency: use provided value or default to CPU cores / 4 (minimum 1)
-    let concurrency = concurrent_updates.unwrap_or_else(|| {
-        let cpus = num_cpus::get();
-        std::cmp::max(1, cpus / 4)
-    });
-    info!("Running with concurrency level: {}", concurrency);
+    // Packages sharing an upstream, and consecutive runs, reuse the fetched releases
+    if release_cache_ttl > 0 {
+        set_release_cache(
+            db.clone(),
+            chrono::Duration::seconds(release_cache_ttl as i64),
+        );
+    }
+
+    // Checking for updates mostly waits on evaluation and upstream APIs, so it runs with more
+    // parallelism than the builds: CPU cores and CPU cores / 4 by default (minimum 1)
+    let build_concurrency = concurrent_updates
+        .unwrap_or_else(|| num_cpus::get() / 4)
+        .max(1);
+    let concurrency = concurrent_checks
+        .unwrap_or_else(num_cpus::get)
+        .max(build_concurrency);
+    info!(
+        "Checking up to {} packages and updating up to {} packages concurrently",
+        concurrency, build_concurrency
+    );
 
     // Determine PR configuration: use CLI override or auto-detect from git
     let pr_config = if let Some(remote_name) = upstream {
@@ -45,56 +609,310 @@ pub async fn run(
         crate::git::get_pr_config_from_git().await.ok()
     };
 
-    let stream = nix::run_eval::run_nix_eval_jobs(file.clone());
-    pin_mut!(stream);
+    // Update branches of merged or closed PRs would otherwise accumulate in the fork
+    let token = std::env::var("GITHUB_TOKEN").ok();
+    if let (Some(config), Some(token)) = (&pr_config, &token) {
+        if !dry_run && !keep_closed_branches {
+            match delete_closed_update_branches(config, &fork, token).await {
+                Ok(0) => {},
+                Ok(deleted) => info!("Deleted {} branches of closed PRs from {}", deleted, fork),
+                Err(e) => warn!("Failed to delete branches of closed PRs: {:#}", e),
+            }
+        }
+    }
+
+    // Updates made upstream since the local tree was checked out are detected on the base branch
+    let upstream_base = match &pr_config {
+        Some(config) => match fetch_base_branch(config).await {
+            Ok(tracking) => Some(tracking),
+            Err(e) => {
+                warn!(
+                    "Failed to fetch the base branch, updates already made upstream won't be \
+                     detected: {:#}",
+                    e
+                );
+                None
+            },
+        },
+        None => None,
+    };
+
+    // Worktrees of crashed runs would otherwise accumulate
+    match cleanup_stale_worktrees().await {
+        Ok(0) => {},
+        Ok(removed) => info!("Removed {} stale worktrees of previous runs", removed),
+        Err(e) => warn!("Failed to clean up stale worktrees: {:#}", e),
+    }
+
+    // Placeholder hashes of failed or interrupted updates are left to `fix-fake-hashes`, the
+    // checkout isn't modified by runs
+    match find_fake_hashes(nix::entry_point_dir(&file)).await {
+        Ok(files) if files.is_empty() => {},
+        Ok(files) => {
+            for (path, count) in &files {
+                warn!("{}: {} placeholder hashes", path.display(), count);
+            }
+            warn!(
+                "{} files contain placeholder hashes, run `ekapkgs-update fix-fake-hashes` to \
+                 repair them",
+                files.len()
+            );
+        },
+        Err(e) => warn!("Failed to look for placeholder hashes: {:#}", e),
+    }
+
+    // The markings of packages are part of their meta
+    eval_jobs_options.meta |= prioritize_insecure;
+    let mut stream: Pin<Box<dyn Stream<Item = anyhow::Result<NixEvalItem>> + Send>> =
+        Box::pin(canonical_first(nix::run_eval::run_nix_eval_jobs(
+            file.clone(),
+            eval_jobs_options,
+        )));
+
+    // Reverse dependencies and shared upstreams are only known once the whole package set has
+    // been evaluated, as are the packages webhooks can trigger updates of
+    let mut reverse_deps = ReverseDependencyIndex::default();
+    if verify_reverse_deps.is_some() || auto_group || listen.is_some() || prioritize_insecure {
+        info!("Evaluating all packages before updating");
+        let mut items: Vec<anyhow::Result<NixEvalItem>> = stream.collect().await;
+        // A failed evaluation leaves packages out, they'd be grouped and indexed without them
+        if let Some(failure) = items.iter().position(Result::is_err) {
+            items.remove(failure)?;
+        }
+        let eval_drvs: Vec<_> = items
+            .iter()
+            .filter_map(|item| match item {
+                Ok(NixEvalItem::Drv(drv)) => Some(drv.clone()),
+                _ => None,
+            })
+            .collect();
+        if verify_reverse_deps.is_some() {
+            reverse_deps = ReverseDependencyIndex::new(&eval_drvs);
+        }
+        if auto_group {
+            info!("Grouping packages fetched from the same repository");
+            let derived = derive_upstream_groups(&file, &eval_drvs, &groups, concurrency).await;
+            info!("Derived {} groups from shared upstreams", derived.len());
+            groups.extend(derived);
+        }
+        stream = match &listen {
+            Some(addr) => {
+                // Only the requested packages are streamed, evaluation errors are recorded here
+                for item in &items {
+                    if let Ok(NixEvalItem::Error(e)) = item {
+                        record_eval_error(&db, e).await;
+                    }
+                }
+                info!("Indexing packages by upstream repository");
+                let targets = WebhookTargets::new(&file, eval_drvs, concurrency).await;
+                let secret = std::env::var("WEBHOOK_SECRET")
+                    .ok()
+                    .filter(|s| !s.is_empty());
+                Box::pin(webhook::listen(addr, secret, targets).await?)
+            },
+            None => {
+                // Canonical attr paths come first, so aliases of them are the ones skipped.
+                // Packages marked insecure come before the others, with their aliases
+                fn key(
+                    item: &anyhow::Result<NixEvalItem>,
+                    prioritize_insecure: bool,
+                ) -> Option<(bool, (usize, usize, &str))> {
+                    match item {
+                        Ok(NixEvalItem::Drv(drv)) => Some((
+                            !(prioritize_insecure && drv.is_marked_insecure()),
+                            drv.canonical_key(),
+                        )),
+                        _ => None,
+                    }
+                }
+                items.sort_by(|a, b| key(a, prioritize_insecure).cmp(&key(b, prioritize_insecure)));
+                if prioritize_insecure {
+                    let insecure: HashSet<&str> = eval_drvs
+                        .iter()
+                        .filter(|drv| drv.is_marked_insecure())
+                        .map(|drv| drv.drv_path.as_str())
+                        .collect();
+                    info!("Updating {} packages marked insecure first", insecure.len());
+                }
+                Box::pin(futures::stream::iter(items))
+            },
+        };
+    }
+
+    let interrupts = Arc::new(Interrupts::default());
+    let interrupt_listener = interrupts.clone().listen();
+
+    let build_slots = Arc::new(Semaphore::new(build_concurrency));
+    let load_monitor = adaptive_concurrency
+        .then(|| tokio::spawn(adapt_concurrency(build_slots.clone(), build_concurrency)));
+
+    let run_options = Arc::new(RunOptions {
+        fork,
+        dry_run,
+        skip_unstable,
+        ignore_update_script,
+        update_options,
+        verify_reverse_deps,
+        reverse_deps: Arc::new(reverse_deps),
+        // Security-only runs rely on the advisories to pick updates
+        check_advisories: check_advisories || security_only,
+        security_only,
+        plan,
+        failure_issue_threshold,
+        build_slots,
+        interrupts: interrupts.clone(),
+        budget,
+        release_feeds,
+        upstream_base,
+        file_locks: Arc::new(FileLocks::default()),
+    });
 
     let mut drvs = Vec::new();
+    let mut group_members: BTreeMap<
+        String,
+        (PackageGroup, Vec<crate::nix::nix_eval_jobs::NixEvalDrv>),
+    > = BTreeMap::new();
     let mut error_count = 0;
     let mut skipped_count = 0;
     let mut checked_count = 0;
     let mut updated_count = 0;
     let mut failed_count = 0;
+    let mut partial_group_count = 0;
+    let mut alias_count = 0;
+    let mut deferred_count = 0;
+    // Packages which evaluated in earlier runs
+    let mut newly_failing: Vec<String> = Vec::new();
+    // Whether every package was evaluated, so errors not seen again are resolved
+    let mut evaluated = false;
+    // Failure of the evaluation, returned once the updates in progress are done
+    let mut eval_failure = None;
+    let mut seen_drvs = SeenDrvs::new(listen.is_some());
+    let mut planned_updates = Vec::new();
+    let mut package_summaries = Vec::new();
+    let progress = RunProgress::new(show_progress);
+    let run_started = chrono::Utc::now();
 
     // JoinSet for managing concurrent update tasks
     let mut join_set: JoinSet<(anyhow::Result<UpdateResult>, String)> = JoinSet::new();
 
     // Helper function to process a completed task result
     let mut process_result = |result: anyhow::Result<UpdateResult>, attr_path: &str| {
-        match result {
-            Ok(UpdateResult::Updated { .. }) | Ok(UpdateResult::DryRun { .. }) => {
-                updated_count += 1
+        match &result {
+            Ok(update_result) => {
+                planned_updates.extend(update_result.planned_updates(attr_path));
+                package_summaries.extend(update_result.summaries(attr_path));
             },
-            Err(_) => failed_count += 1,
-            _ => {},
+            Err(e) => package_summaries.push(PackageSummary {
+                detail: Some(excerpt(&format!("{:#}", e))),
+                ..PackageSummary::new(attr_path, PackageStatus::Failed)
+            }),
+        }
+        let updated = matches!(
+            result,
+            Ok(UpdateResult::Updated { .. })
+                | Ok(UpdateResult::DryRun { .. })
+                | Ok(UpdateResult::GroupUpdated { .. })
+                | Ok(UpdateResult::GroupDryRun(_))
+        );
+        let failed = matches!(result, Err(_) | Ok(UpdateResult::Failed { .. }));
+        if updated {
+            updated_count += 1;
+        }
+        if failed {
+            failed_count += 1;
+        }
+        if matches!(result, Ok(UpdateResult::GroupPartiallyReleased { .. })) {
+            partial_group_count += 1;
+        }
+        if matches!(&result, Ok(UpdateResult::Skipped(reason)) if reason == BUDGET_SPENT) {
+            deferred_count += 1;
         }
+        progress.finished(updated, failed);
         handle_result(result, attr_path);
     };
 
     // Consume the stream, processing each item as it arrives
-    while let Some(result) = stream.next().await {
+    loop {
+        // Updates in progress may fail, giving their share of the budget back
+        if run_options.budget.spent() {
+            while let Some(task_result) = join_set.join_next().await {
+                match task_result {
+                    Ok((result, task_attr_path)) => {
+                        process_result(result, &task_attr_path);
+                    },
+                    Err(e) => {
+                        warn!("Task panicked: {}", e);
+                    },
+                }
+            }
+            if run_options.budget.spent() {
+                info!("Update budget spent, the remaining packages are left to the next run");
+                break;
+            }
+        }
+
+        let result = tokio::select! {
+            biased;
+            _ = interrupts.reached(1) => break,
+            result = stream.next() => match result {
+                Some(result) => result,
+                None => {
+                    evaluated = true;
+                    break;
+                },
+            },
+        };
+        if result.is_ok() {
+            progress.evaluated();
+        }
         match result {
             Ok(NixEvalItem::Drv(drv)) => {
                 drvs.push(drv.clone());
 
+                if let Some(canonical) = seen_drvs.alias_of(&drv) {
+                    debug!("{}: Skipping alias of {}", drv.attr, canonical);
+                    alias_count += 1;
+                    continue;
+                }
+
+                // Group members are updated together once every member has been evaluated
+                if let Some(group) = groups.group_of(&drv.attr) {
+                    group_members
+                        .entry(group.name.clone())
+                        .or_insert_with(|| (group.clone(), Vec::new()))
+                        .1
+                        .push(drv);
+                    continue;
+                }
+
                 // Check if we should attempt an update for this package
                 let attr_path = &drv.attr;
 
-                match db.should_check_update(attr_path).await {
-                    Ok(false) => {
-                        debug!("{}: Skipping (in backoff period)", attr_path);
-                        skipped_count += 1;
+                // Planned and requested updates are carried out regardless of the backoff period
+                if let Some(plan) = &run_options.plan {
+                    if !plan.contains(attr_path) {
                         continue;
-                    },
-                    Ok(true) => {
-                        debug!("{}: Checking for updates", attr_path);
-                    },
-                    Err(e) => {
-                        warn!(
-                            "{}: Database error checking update status: {}",
-                            attr_path, e
-                        );
-                        // Continue checking anyway
-                    },
+                    }
+                } else if listen.is_some() {
+                    debug!("{}: Checking for updates as requested", attr_path);
+                } else {
+                    match db.should_check_update(attr_path).await {
+                        Ok(false) => {
+                            debug!("{}: Skipping (in backoff period)", attr_path);
+                            skipped_count += 1;
+                            continue;
+                        },
+                        Ok(true) => {
+                            debug!("{}: Checking for updates", attr_path);
+                        },
+                        Err(e) => {
+                            warn!(
+                                "{}: Database error checking update status: {}",
+                                attr_path, e
+                            );
+                            // Continue checking anyway
+                        },
+                    }
                 }
 
                 checked_count += 1;
@@ -118,35 +936,118 @@ pub async fn run(
                 let file_clone = file.clone();
                 let drv_clone = drv.clone();
                 let pr_config_clone = pr_config.clone();
-                let fork_clone = fork.clone();
                 let attr_path_clone = attr_path.clone();
+                let run_options_clone = run_options.clone();
+                let task_progress = progress.start(attr_path);
+                // Requested updates look for the release which was just published
+                let requested_at = listen.is_some().then(chrono::Utc::now);
 
                 // Spawn the update task
-                join_set.spawn(async move {
-                    let result = check_and_update_package(
-                        &db_clone,
-                        &file_clone,
-                        &drv_clone,
-                        pr_config_clone.as_ref(),
-                        &fork_clone,
-                        run_passthru_tests,
-                        dry_run,
-                        skip_unstable,
-                    )
-                    .await;
+                join_set.spawn(with_releases_fetched_after(requested_at, async move {
+                    let mut timings = PhaseTimings::default();
+                    let result = run_options_clone
+                        .interrupts
+                        .cancellable(
+                            &attr_path_clone,
+                            check_and_update_package(
+                                &db_clone,
+                                &file_clone,
+                                &drv_clone,
+                                pr_config_clone.as_ref(),
+                                &run_options_clone,
+                                &mut timings,
+                            ),
+                        )
+                        .await;
+                    record_timings(&db_clone, &attr_path_clone, &mut timings).await;
+                    drop(task_progress);
                     (result, attr_path_clone)
-                });
+                }));
             },
             Ok(NixEvalItem::Error(e)) => {
                 debug!("Evaluation error: {:?}", e);
                 error_count += 1;
+                if record_eval_error(&db, &e).await {
+                    newly_failing.push(e.attr);
+                }
             },
+            // nix-eval-jobs is killed once the run stops reading it
+            Err(_) if interrupts.stopping() => break,
             Err(e) => {
-                return Err(e);
+                eval_failure = Some(e);
+                break;
             },
         }
     }
 
+    // Update each group as a whole, checking it if any member is out of its backoff period
+    for (group_name, (group, members)) in group_members {
+        if interrupts.stopping() || run_options.budget.spent() || eval_failure.is_some() {
+            break;
+        }
+        let mut due = false;
+        if let Some(plan) = &run_options.plan {
+            // Planned groups are updated regardless of the backoff period
+            if !members.iter().any(|member| plan.contains(&member.attr)) {
+                continue;
+            }
+            due = true;
+        } else {
+            for member in &members {
+                // Database errors don't prevent checking, as for single packages
+                if db.should_check_update(&member.attr).await.unwrap_or(true) {
+                    due = true;
+                    break;
+                }
+            }
+        }
+        if !due {
+            debug!("{}: Skipping group (in backoff period)", group_name);
+            skipped_count += members.len();
+            continue;
+        }
+
+        checked_count += members.len();
+
+        while join_set.len() >= concurrency {
+            if let Some(task_result) = join_set.join_next().await {
+                match task_result {
+                    Ok((result, task_label)) => {
+                        process_result(result, &task_label);
+                    },
+                    Err(e) => {
+                        warn!("Task panicked: {}", e);
+                    },
+                }
+            }
+        }
+
+        let db_clone = db.clone();
+        let file_clone = file.clone();
+        let pr_config_clone = pr_config.clone();
+        let run_options_clone = run_options.clone();
+        let task_progress = progress.start(&group_name);
+
+        join_set.spawn(async move {
+            let result = run_options_clone
+                .interrupts
+                .cancellable(
+                    &format!("group-{}", group_name),
+                    check_and_update_group(
+                        &db_clone,
+                        &file_clone,
+                        &group,
+                        &members,
+                        pr_config_clone.as_ref(),
+                        &run_options_clone,
+                    ),
+                )
+                .await;
+            drop(task_progress);
+            (result, group_name)
+        });
+    }
+
     // Wait for all remaining tasks to complete
     while let Some(task_result) = join_set.join_next().await {
         match task_result {
@@ -159,12 +1060,67 @@ pub async fn run(
         }
     }
 
+    if let Some(load_monitor) = load_monitor {
+        load_monitor.abort();
+    }
+    interrupt_listener.abort();
+    if let Some(e) = eval_failure {
+        progress.finish();
+        return Err(e);
+    }
+
+    if let Some(path) = plan_out {
+        let expanded_path = shellexpand::tilde(&path).to_string();
+        Plan {
+            updates: planned_updates,
+        }
+        .save(Path::new(&expanded_path))
+        .await?;
+        info!("Wrote plan to {}", path);
+    }
+
+    if !summary_out.is_empty() {
+        package_summaries.sort_by(|a: &PackageSummary, b| a.attr_path.cmp(&b.attr_path));
+        let summary = RunSummary {
+            started_at: run_started,
+            finished_at: chrono::Utc::now(),
+            dry_run,
+            interrupted: interrupts.stopping(),
+            evaluation_errors: error_count,
+            backoff: skipped_count,
+            packages: package_summaries,
+        };
+        for path in &summary_out {
+            let expanded_path = shellexpand::tilde(path).to_string();
+            // The updates were made, failing to report them doesn't fail the run
+            match summary.save(Path::new(&expanded_path)).await {
+                Ok(()) => info!("Wrote summary to {}", path),
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+    }
+
     // Display summary
+    progress.finish();
     info!("Evaluation complete!");
     info!("Total derivations: {}", drvs.len());
     if error_count > 0 {
         info!("Evaluation errors: {}", error_count);
     }
+    if !newly_failing.is_empty() {
+        warn!(
+            "{} packages evaluated in earlier runs fail to evaluate: {}",
+            newly_failing.len(),
+            newly_failing.join(", ")
+        );
+    }
+    if evaluated && listen.is_none() {
+        match db.clear_eval_errors_before(run_started).await {
+            Ok(0) => {},
+            Ok(resolved) => info!("Packages evaluating again: {}", resolved),
+            Err(e) => warn!("Failed to clear resolved evaluation errors: {}", e),
+        }
+    }
     if dry_run {
         info!("Update summary (dry-run scan - no changes made):");
     } else {
@@ -172,8 +1128,63 @@ pub async fn run(
     }
     info!("  Checked: {}", checked_count);
     info!("  Skipped (backoff): {}", skipped_count);
+    if alias_count > 0 {
+        info!("  Skipped (aliases): {}", alias_count);
+    }
+    if deferred_count > 0 {
+        info!("  Deferred (budget spent): {}", deferred_count);
+    }
     info!("  Updated: {}", updated_count);
     info!("  Failed: {}", failed_count);
+    if partial_group_count > 0 {
+        info!(
+            "  Partially released groups (waiting): {}",
+            partial_group_count
+        );
+    }
+
+    // Point out where the time went, to target performance work
+    match db
+        .get_slowest_packages(run_started, SLOWEST_PACKAGES_SHOWN)
+        .await
+    {
+        Ok(slowest) if !slowest.is_empty() => {
+            info!("Slowest packages:");
+            for record in slowest {
+                let ms = |ms: i64| format_duration(Duration::from_millis(ms.max(0) as u64));
+                info!(
+                    "  {}: {} (eval {}, upstream {}, rewrite {}, build {})",
+                    record.attr_path,
+                    ms(record.total_ms()),
+                    ms(record.metadata_eval_ms),
+                    ms(record.upstream_fetch_ms),
+                    ms(record.rewrite_ms),
+                    ms(record.build_ms)
+                );
+            }
+        },
+        Ok(_) => {},
+        Err(e) => warn!("Failed to query package timings: {}", e),
+    }
+
+    // What updates fail on across runs, to spot the most common causes
+    match db.get_statistics().await {
+        Ok(stats) => {
+            info!(
+                "Tracked packages: {} ({} with a proposed update, {} in backoff)",
+                stats.total_packages,
+                stats.packages_with_proposed_updates,
+                stats.packages_in_backoff
+            );
+            if !stats.failures_by_kind.is_empty() {
+                info!("Failed update attempts by kind:");
+                for (kind, count) in &stats.failures_by_kind {
+                    info!("  {}: {}", kind, count);
+                }
+            }
+        },
+        Err(e) => warn!("Failed to query database statistics: {}", e),
+    }
 
     // Count by system
     let mut systems = std::collections::HashMap::new();
@@ -186,16 +1197,68 @@ pub async fn run(
         info!("  {}: {}", system, count);
     }
 
+    db.close().await;
+    if interrupts.stopping() {
+        anyhow::bail!("Run interrupted");
+    }
+
     Ok(())
 }
 
-/// Do additional processing depending on the result of the update
-fn handle_result(result: anyhow::Result<UpdateResult>, attr_path: &str) {
-    match result {
-        Ok(UpdateResult::Updated {
-            old_version,
-            new_version,
-        }) => {
+/// Evaluated packages with the members of package sets held back until the evaluation ends
+///
+/// Aliases often are other package sets evaluating to the same derivations, e.g.
+/// `python312Packages.foo` of `python3Packages.foo`. Held back members come canonical attr paths
+/// first, so the aliases are the ones skipped when updates stream along with the evaluation.
+/// Top-level packages are yielded as they're evaluated.
+fn canonical_first(
+    items: impl Stream<Item = anyhow::Result<NixEvalItem>> + Send,
+) -> impl Stream<Item = anyhow::Result<NixEvalItem>> + Send {
+    async_stream::stream! {
+        let mut members = Vec::new();
+        for await item in items {
+            match item {
+                Ok(NixEvalItem::Drv(drv)) if drv.attr_path.len() > 1 => members.push(drv),
+                item => yield item,
+            }
+        }
+        members.sort_by(|a, b| a.canonical_key().cmp(&b.canonical_key()));
+        for drv in members {
+            yield Ok(NixEvalItem::Drv(drv));
+        }
+    }
+}
+
+/// Record the evaluation error of a package, returning whether it evaluated in earlier runs
+async fn record_eval_error(db: &Database, error: &NixEvalError) -> bool {
+    match db.record_eval_error(&error.attr, &error.error).await {
+        Ok(true) => {
+            warn!(
+                "{}: Newly fails to evaluate: {}",
+                error.attr,
+                eval_error_summary(&error.error)
+            );
+            true
+        },
+        Ok(false) => false,
+        Err(e) => {
+            warn!("{}: Failed to record evaluation error: {}", error.attr, e);
+            false
+        },
+    }
+}
+
+/// Number of packages listed in the slowest packages of the run summary
+const SLOWEST_PACKAGES_SHOWN: i64 = 10;
+
+/// Do additional processing depending on the result of the update
+fn handle_result(result: anyhow::Result<UpdateResult>, attr_path: &str) {
+    match result {
+        Ok(UpdateResult::Updated {
+            old_version,
+            new_version,
+            ..
+        }) => {
             info!(
                 "{}: Updated from {} to {}",
                 attr_path, old_version, new_version
@@ -216,12 +1279,38 @@ fn handle_result(result: anyhow::Result<UpdateResult>, attr_path: &str) {
         Ok(UpdateResult::DryRun {
             current_version,
             new_version,
-        }) => {
-            info!(
+            nixpkgs,
+            ..
+        }) => match nixpkgs {
+            Some(nixpkgs) => info!(
+                "{}: Would update {} -> {} ({})",
+                attr_path, current_version, new_version, nixpkgs
+            ),
+            None => info!(
                 "{}: Would update {} -> {}",
                 attr_path, current_version, new_version
+            ),
+        },
+        Ok(UpdateResult::Failed { error, .. }) => {
+            // Failures are reported as they happen
+            debug!("{}: Update failed - {}", attr_path, error);
+        },
+        Ok(UpdateResult::GroupUpdated { changes, .. }) => {
+            let changes: Vec<String> = changes.iter().map(ToString::to_string).collect();
+            info!("{}: Updated group: {}", attr_path, changes.join(", "));
+        },
+        Ok(UpdateResult::GroupPartiallyReleased { target, missing }) => {
+            info!(
+                "{}: Partially released at {}, waiting for {}",
+                attr_path,
+                target,
+                missing.join(", ")
             );
         },
+        Ok(UpdateResult::GroupDryRun(changes)) => {
+            let changes: Vec<String> = changes.iter().map(ToString::to_string).collect();
+            info!("{}: Would update group: {}", attr_path, changes.join(", "));
+        },
         Err(e) => {
             warn!("{}: Failed to check for updates: {}", attr_path, e);
         },
@@ -233,6 +1322,15 @@ enum UpdateResult {
     Updated {
         old_version: String,
         new_version: String,
+        /// URL of the PR of the update, if one was created
+        pr_url: Option<String>,
+    },
+    /// An update was found but carrying it out failed
+    Failed {
+        old_version: String,
+        /// Version the update was attempted to, unknown for update scripts
+        new_version: Option<String>,
+        error: String,
     },
     NoUpdateNeeded {
         current_version: String,
@@ -242,7 +1340,110 @@ enum UpdateResult {
     DryRun {
         current_version: String,
         new_version: String,
+        /// Description of the upstream source the new version was found at
+        source: String,
+        /// Comparison with nixpkgs, if a nixpkgs checkout is configured
+        nixpkgs: Option<String>,
+    },
+    /// Members of a group updated together
+    GroupUpdated {
+        changes: Vec<GroupChange>,
+        pr_url: Option<String>,
     },
+    GroupDryRun(Vec<GroupChange>),
+    /// A lockstep group whose newest version isn't released for every member yet
+    GroupPartiallyReleased {
+        target: String,
+        missing: Vec<String>,
+    },
+}
+
+impl UpdateResult {
+    /// Updates found by a dry run, to record in a plan
+    fn planned_updates(&self, attr_path: &str) -> Vec<PlannedUpdate> {
+        match self {
+            UpdateResult::DryRun {
+                current_version,
+                new_version,
+                source,
+                ..
+            } => vec![PlannedUpdate {
+                attr_path: attr_path.to_string(),
+                current_version: current_version.clone(),
+                target_version: new_version.clone(),
+                source: source.clone(),
+            }],
+            UpdateResult::GroupDryRun(changes) => changes
+                .iter()
+                .map(|change| PlannedUpdate {
+                    attr_path: change.attr.clone(),
+                    current_version: change.old_version.clone(),
+                    target_version: change.new_version.clone(),
+                    source: change.source.clone(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Outcome of each package of the result, for the summary of the run
+    fn summaries(&self, attr_path: &str) -> Vec<PackageSummary> {
+        let summary = |status| PackageSummary::new(attr_path, status);
+        let changes = |changes: &[GroupChange], status, pr_url: Option<&String>| {
+            changes
+                .iter()
+                .map(|change| PackageSummary {
+                    pr_url: pr_url.cloned(),
+                    ..PackageSummary::new(&change.attr, status)
+                        .versions(&change.old_version, Some(&change.new_version))
+                })
+                .collect()
+        };
+        match self {
+            UpdateResult::Updated {
+                old_version,
+                new_version,
+                pr_url,
+            } => vec![PackageSummary {
+                pr_url: pr_url.clone(),
+                ..summary(PackageStatus::Updated).versions(old_version, Some(new_version))
+            }],
+            UpdateResult::Failed {
+                old_version,
+                new_version,
+                error,
+            } => vec![PackageSummary {
+                detail: Some(excerpt(error)),
+                ..summary(PackageStatus::Failed).versions(old_version, new_version.as_deref())
+            }],
+            UpdateResult::NoUpdateNeeded {
+                current_version, ..
+            } => vec![summary(PackageStatus::UpToDate).versions(current_version, None)],
+            UpdateResult::Skipped(reason) => vec![PackageSummary {
+                detail: Some(reason.clone()),
+                ..summary(PackageStatus::Skipped)
+            }],
+            UpdateResult::DryRun {
+                current_version,
+                new_version,
+                ..
+            } => vec![
+                summary(PackageStatus::WouldUpdate).versions(current_version, Some(new_version)),
+            ],
+            UpdateResult::GroupUpdated { changes: c, pr_url } => {
+                changes(c, PackageStatus::Updated, pr_url.as_ref())
+            },
+            UpdateResult::GroupDryRun(c) => changes(c, PackageStatus::WouldUpdate, None),
+            UpdateResult::GroupPartiallyReleased { target, missing } => vec![PackageSummary {
+                detail: Some(format!(
+                    "Released at {}, waiting for {}",
+                    target,
+                    missing.join(", ")
+                )),
+                ..summary(PackageStatus::Waiting)
+            }],
+        }
+    }
 }
 
 /// Check if a package needs updating and attempt to update it
@@ -251,14 +1452,31 @@ async fn check_and_update_package(
     eval_entry_point: &str,
     drv: &crate::nix::nix_eval_jobs::NixEvalDrv,
     pr_config: Option<&PrConfig>,
-    fork: &str,
-    run_passthru_tests: bool,
-    dry_run: bool,
-    skip_unstable: bool,
+    run_options: &RunOptions,
+    timings: &mut PhaseTimings,
 ) -> anyhow::Result<UpdateResult> {
+    let RunOptions {
+        ref fork,
+        dry_run,
+        skip_unstable,
+        ignore_update_script,
+        ref update_options,
+        check_advisories,
+        security_only,
+        release_feeds,
+        ref upstream_base,
+        ..
+    } = *run_options;
     let attr_path = &drv.attr;
+    let package_config = update_options.config.package(attr_path);
+
+    // Packages vendored from other repositories have their PRs opened there
+    let package_pr_config = pr_config.map(|config| package_config.pr_config(config));
+    let pr_config = package_pr_config.as_ref();
+    let fork = package_config.fork(fork);
 
     // Extract package metadata to get current version
+    timings.enter(UpdatePhase::MetadataEval);
     let metadata = match PackageMetadata::from_attr_path(eval_entry_point, attr_path).await {
         Ok(m) => m,
         Err(e) => {
@@ -272,6 +1490,11 @@ async fn check_and_update_package(
     let current_version = &metadata.version;
     debug!("{}: Current version: {}", attr_path, current_version);
 
+    if let Some(ref reason) = metadata.directives.skip {
+        debug!("{}: Skipping due to a skip directive", attr_path);
+        return Ok(UpdateResult::Skipped(skip_directive_reason(reason)));
+    }
+
     // Skip packages with 'unstable' in version if flag is set
     if skip_unstable && current_version.contains("unstable") {
         debug!(
@@ -283,30 +1506,112 @@ async fn check_and_update_package(
         ));
     }
 
-    // Determine upstream source
-    let upstream_source = if let Some(ref src_url) = metadata.src_url {
-        match UpstreamSource::from_url(src_url) {
-            Some(source) => source,
-            None => {
-                debug!("{}: Could not parse upstream source from URL", attr_path);
-                return Ok(UpdateResult::Skipped("Unsupported source".to_string()));
-            },
+    // Packages with an update script are updated by running it
+    if !ignore_update_script
+        && matches!(
+            UpdateScript::eval(eval_entry_point, attr_path).await,
+            Ok(Some(_))
+        )
+    {
+        if dry_run {
+            return Ok(UpdateResult::Skipped(
+                "Update script not run in dry-run mode".to_string(),
+            ));
         }
-    } else if let Some(ref pname) = metadata.pname {
-        UpstreamSource::PyPI {
-            pname: pname.clone(),
+        // The version an update script would pick isn't known before running it
+        if security_only {
+            return Ok(UpdateResult::Skipped(
+                "Update script not run in security-only mode".to_string(),
+            ));
         }
-    } else {
-        debug!("{}: No source URL or pname found", attr_path);
-        return Ok(UpdateResult::Skipped("No source info".to_string()));
+        return update_with_script(
+            db,
+            eval_entry_point,
+            drv,
+            pr_config,
+            run_options,
+            current_version,
+        )
+        .await;
+    }
+
+    // Determine upstream source
+    let upstream_source = match upstream_source_for(attr_path, &metadata, &package_config) {
+        Ok(source) => source,
+        Err(reason) => return Ok(UpdateResult::Skipped(reason)),
+    };
+
+    // Failed updates are retried with escalating strategies
+    let record = db.get_update_record(attr_path).await?;
+    let retry_strategy = record
+        .as_ref()
+        .and_then(|rec| rec.retry_strategy.as_deref())
+        .and_then(RetryStrategy::parse)
+        .unwrap_or_default();
+
+    // Fetch latest compatible release (using the retry strategy's semver strategy)
+    timings.enter(UpdatePhase::UpstreamFetch);
+    let tag_filter = match package_config.tag_filter() {
+        Ok(filter) => filter,
+        Err(e) => return Ok(UpdateResult::Skipped(format!("{:#}", e))),
     };
 
-    // Fetch latest compatible release (using Latest strategy)
+    // The release feed tells whether the API needs to be queried at all
+    if release_feeds
+        && !upstream_source
+            .may_have_newer_release(
+                current_version,
+                &tag_filter,
+                package_config.version_scheme,
+                package_config.release_listing,
+            )
+            .await
+    {
+        debug!("{}: Release feed lists no newer release", attr_path);
+        if retry_strategy != RetryStrategy::Default {
+            return Ok(skip_retry_strategy(db, attr_path, retry_strategy).await);
+        }
+        if let Err(e) = db
+            .record_no_update(attr_path, current_version, current_version)
+            .await
+        {
+            warn!(
+                "{}: Failed to record no update in database: {}",
+                attr_path, e
+            );
+        }
+        return Ok(UpdateResult::NoUpdateNeeded {
+            current_version: current_version.to_string(),
+            latest_version: current_version.to_string(),
+        });
+    }
+
+    let withdrawn =
+        query_withdrawn_versions(eval_entry_point, attr_path, &upstream_source, &metadata).await;
     let best_release = match upstream_source
-        .get_compatible_release(current_version, SemverStrategy::Latest)
+        .get_compatible_release(
+            current_version,
+            directive_strategy(&metadata, retry_strategy.semver_strategy()),
+            &tag_filter,
+            package_config.version_scheme,
+            package_config.release_listing,
+            &PythonRequirements::from_metadata(&metadata),
+            &withdrawn,
+            package_config.min_release_age(),
+        )
         .await
     {
         Ok(release) => release,
+        Err(e) if retry_strategy != RetryStrategy::Default => {
+            if e.is::<NoCompatibleRelease>() {
+                return Ok(skip_retry_strategy(db, attr_path, retry_strategy).await);
+            }
+            // The strategy is tried again once the upstream can be looked up
+            return Err(e.context(format!(
+                "Failed to fetch upstream releases for retry strategy {}",
+                retry_strategy
+            )));
+        },
         Err(e) => {
             debug!("{}: Failed to fetch upstream release: {}", attr_path, e);
             // Record no update available
@@ -322,10 +1627,14 @@ async fn check_and_update_package(
         },
     };
 
-    let latest_version = UpstreamSource::get_version(&best_release);
+    timings.finish();
+    let latest_version = tag_filter.release_version(&best_release);
     debug!("{}: Latest version: {}", attr_path, latest_version);
 
     // Check if update is needed
+    if current_version == &latest_version && retry_strategy != RetryStrategy::Default {
+        return Ok(skip_retry_strategy(db, attr_path, retry_strategy).await);
+    }
     if current_version == &latest_version {
         // No update needed - record in database
         if let Err(e) = db
@@ -344,7 +1653,6 @@ async fn check_and_update_package(
     }
 
     // Check if there's a proposed version that differs from latest
-    let record = db.get_update_record(attr_path).await?;
     if let Some(ref rec) = record {
         if let Some(ref proposed) = rec.proposed_version {
             if proposed == &latest_version {
@@ -370,30 +1678,73 @@ async fn check_and_update_package(
         }
     }
 
+    // Someone may have updated the package upstream since the local tree was checked out
+    if let Some(base) = upstream_base {
+        if updated_upstream(eval_entry_point, attr_path, base, &latest_version).await {
+            info!(
+                "{}: Already updated to {} upstream",
+                attr_path, latest_version
+            );
+            if let Err(e) = db
+                .record_no_update(attr_path, &latest_version, &latest_version)
+                .await
+            {
+                warn!("{}: Failed to update database: {}", attr_path, e);
+            }
+            return Ok(UpdateResult::Skipped(
+                "Already updated upstream".to_string(),
+            ));
+        }
+    }
+
     // Update is needed - attempt the update
     info!(
         "{}: Update available: {} -> {}",
         attr_path, current_version, latest_version
     );
 
+    // Look up known vulnerabilities fixed by the update
+    let security_status = if check_advisories {
+        query_security_status(
+            db,
+            attr_path,
+            &upstream_source,
+            current_version,
+            &latest_version,
+        )
+        .await
+    } else {
+        None
+    };
+
+    if security_only && security_status.as_ref().is_none_or(|s| s.fixed.is_empty()) {
+        debug!(
+            "{}: Skipping update fixing no known vulnerabilities",
+            attr_path
+        );
+        return Ok(UpdateResult::Skipped(
+            "No known vulnerabilities fixed".to_string(),
+        ));
+    }
+
     // If dry-run mode, report the update without performing it
     if dry_run {
+        let nixpkgs = match &update_options.config.nixpkgs {
+            Some(nixpkgs) => Some(nixpkgs::compare(nixpkgs, attr_path, &latest_version).await),
+            None => None,
+        };
         return Ok(UpdateResult::DryRun {
             current_version: current_version.to_string(),
             new_version: latest_version.to_string(),
+            source: upstream_source.description(),
+            nixpkgs,
         });
     }
 
-    // Create a worktree for this update
-    let worktree_path = match create_worktree(attr_path).await {
-        Ok(path) => path,
-        Err(e) => {
-            warn!("{}: Failed to create worktree: {}", attr_path, e);
-            return Ok(UpdateResult::Skipped(format!(
-                "Worktree creation failed: {}",
-                e
-            )));
-        },
+    // Left to the next run, without recording the check
+    let Some(budget_claim) = run_options.budget.claim() else {
+        debug!("{}: Not updating, the update budget is spent", attr_path);
+        return Ok(UpdateResult::Skipped(BUDGET_SPENT.to_string()));
     };
 
     // Get file location from meta.position (in the main repository)
@@ -401,36 +1752,72 @@ async fn check_and_update_package(
         Ok(loc) => loc,
         Err(e) => {
             warn!("{}: Failed to get file location: {}", attr_path, e);
-            cleanup_worktree(&worktree_path).await.ok();
             return Ok(UpdateResult::Skipped("Could not locate file".to_string()));
         },
     };
 
     debug!("{}: File location: {}", attr_path, file_location);
 
+    // Packages sharing the file are updated one after the other, before taking a build slot
+    let mut file_claim = run_options
+        .file_locks
+        .lock(attr_path, std::slice::from_ref(&file_location))
+        .await;
+
+    // Wait for a build slot, as the update rewrites and builds the package
+    let _build_slot = run_options.build_slots.acquire().await?;
+
+    // Create a worktree for this update
+    let worktree_path = match create_worktree_at(attr_path, file_claim.start_point()).await {
+        Ok(path) => path,
+        Err(e) => {
+            warn!("{}: Failed to create worktree: {}", attr_path, e);
+            return Ok(UpdateResult::Skipped(format!(
+                "Worktree creation failed: {}",
+                e
+            )));
+        },
+    };
+
     // Convert the file path to be relative to the worktree
-    let worktree_file_path = worktree_path.join(&file_location);
-    let worktree_file_str = worktree_file_path.to_string_lossy().to_string();
+    let worktree_file_str = worktree_path_for(&worktree_path, &file_location)
+        .to_string_lossy()
+        .to_string();
+    let worktree_entry_point = worktree_path_for(&worktree_path, eval_entry_point)
+        .to_string_lossy()
+        .to_string();
+
+    // Carry out a retry with the options of its strategy
+    let retry_options;
+    let update_options = if retry_strategy == RetryStrategy::Default {
+        update_options
+    } else {
+        info!("{}: Retrying with strategy {}", attr_path, retry_strategy);
+        retry_options = UpdateOptions {
+            strategy: retry_strategy.semver_strategy(),
+            run_passthru_tests: update_options.run_passthru_tests && retry_strategy.runs_tests(),
+            ..update_options.clone()
+        };
+        &retry_options
+    };
 
     // Attempt the update in the worktree
     let update_result = crate::commands::update::update_from_file_path(
-        eval_entry_point.to_string(),
+        worktree_entry_point.clone(),
         attr_path.to_string(),
         worktree_file_str,
-        SemverStrategy::Latest,
-        false,                // Don't auto-commit in run mode
-        false,                // Don't create PR here (handled separately by create_pr_for_update)
-        None,                 // upstream - not needed in run mode, PR handled separately
-        "origin".to_string(), // fork - not used since create_pr is false
-        run_passthru_tests,
-        run_passthru_tests, // Fail on test errors in run mode
+        None,
+        update_options,
+        timings,
     )
     .await;
+    timings.finish();
 
     match update_result {
-        Ok(()) => {
+        Ok(outcome) => {
             // Update succeeded
             info!("{}: Successfully updated to {}", attr_path, latest_version);
+            budget_claim.keep();
 
             // Record successful update first
             if let Err(e) = db
@@ -439,8 +1826,30 @@ async fn check_and_update_package(
             {
                 warn!("{}: Failed to record successful update: {}", attr_path, e);
             }
+            if !outcome.test_results.is_empty() {
+                if let Err(e) = db
+                    .record_passthru_test_results(attr_path, &latest_version, &outcome.test_results)
+                    .await
+                {
+                    warn!(
+                        "{}: Failed to record passthru test results: {}",
+                        attr_path, e
+                    );
+                }
+            }
+
+            let mut report_sections: Vec<String> = security_status
+                .as_ref()
+                .and_then(SecurityStatus::report)
+                .into_iter()
+                .collect();
+            report_sections.extend(outcome.report_sections());
+            report_sections
+                .extend(verify_reverse_dependencies(run_options, drv, &worktree_entry_point).await);
+            report_sections.extend(file_claim.report());
 
             // Create PR if configured
+            let mut created_pr = None;
             if let Some(config) = pr_config {
                 match create_pr_for_update(
                     db,
@@ -448,13 +1857,28 @@ async fn check_and_update_package(
                     attr_path,
                     current_version,
                     &latest_version,
+                    &outcome.commit_steps,
                     config,
                     fork,
+                    &report_sections,
+                    &update_options.maintainer_opt_out,
                 )
                 .await
                 {
                     Ok((pr_url, pr_number)) => {
                         info!("{}: Created PR #{}: {}", attr_path, pr_number, pr_url);
+                        created_pr = Some(pr_url.clone());
+                        file_claim.chain(attr_path, &worktree_path).await;
+                        run_post_pr_hook(
+                            run_options,
+                            &worktree_path,
+                            HookContext {
+                                out_paths: outcome.out_paths.clone(),
+                                pr_url: Some(pr_url),
+                                ..HookContext::new(attr_path, current_version, &latest_version)
+                            },
+                        )
+                        .await;
                     },
                     Err(e) => {
                         warn!("{}: Failed to create PR: {}", attr_path, e);
@@ -471,6 +1895,7 @@ async fn check_and_update_package(
             Ok(UpdateResult::Updated {
                 old_version: current_version.to_string(),
                 new_version: latest_version.to_string(),
+                pr_url: created_pr,
             })
         },
         Err(e) => {
@@ -486,123 +1911,1775 @@ async fn check_and_update_package(
                 );
             }
 
-            if let Err(db_err) = db
-                .record_failed_update(
-                    &drv.drv_path,
-                    attr_path,
-                    &error_message,
-                    Some(current_version),
-                    Some(&latest_version),
-                )
-                .await
-            {
-                warn!("{}: Failed to record update failure: {}", attr_path, db_err);
-            }
+            record_failure(
+                db,
+                pr_config,
+                run_options,
+                &drv.drv_path,
+                attr_path,
+                &error_message,
+                Some(current_version),
+                Some(&latest_version),
+                Some(retry_strategy),
+            )
+            .await;
 
-            // Return as skipped so it doesn't count as a successful update
-            Ok(UpdateResult::Skipped(format!("Update failed: {}", e)))
+            Ok(UpdateResult::Failed {
+                old_version: current_version.to_string(),
+                new_version: Some(latest_version),
+                error: error_message,
+            })
         },
     }
 }
 
-/// Get the file location for a package from meta.position
-async fn get_file_location(eval_entry_point: &str, attr_path: &str) -> anyhow::Result<String> {
-    let normalized_entry = normalize_entry_point(eval_entry_point);
-    let position_expr = format!(
-        "with import {} {{ }}; {}.meta.position",
-        normalized_entry, attr_path
-    );
-
-    let position = eval_nix_expr(&position_expr).await?;
-
-    if position.is_empty() {
-        anyhow::bail!("Empty position returned from meta.position");
+/// Run the `post_pr` hook of a package once the pull request of its update was created
+async fn run_post_pr_hook(run_options: &RunOptions, worktree_path: &Path, context: HookContext) {
+    let hooks = run_options
+        .update_options
+        .config
+        .package(&context.attr_path)
+        .hooks;
+    if let Err(e) = run_hook(&hooks, HookStage::PostPr, &context, worktree_path).await {
+        warn!("{}: {:#}", context.attr_path, e);
     }
+}
 
-    // Parse position string (format: "file:line")
-    let (file_path, _line_str) = position
-        .rsplit_once(':')
-        .ok_or_else(|| anyhow::anyhow!("Unexpected position format: {}", position))?;
-
-    Ok(file_path.to_string())
+/// Record the time spent in each phase of updating a package, finishing the current phase
+async fn record_timings(db: &Database, attr_path: &str, timings: &mut PhaseTimings) {
+    timings.finish();
+    debug!("{}: Took {}", attr_path, format_duration(timings.total()));
+    if let Err(e) = db.record_package_timings(attr_path, timings).await {
+        warn!("{}: Failed to record timings: {}", attr_path, e);
+    }
 }
 
-/// Create a pull request for a successful update
-async fn create_pr_for_update(
+/// Move on to the next retry strategy of a package, as the current one offers no newer release
+async fn skip_retry_strategy(
     db: &Database,
-    worktree_path: &std::path::Path,
     attr_path: &str,
-    old_version: &str,
-    new_version: &str,
-    config: &PrConfig,
-    fork: &str,
-) -> anyhow::Result<(String, i64)> {
-    // Get GitHub token from environment
-    let github_token = std::env::var("GITHUB_TOKEN")
-        .map_err(|_| anyhow::anyhow!("GITHUB_TOKEN environment variable not set"))?;
+    retry_strategy: RetryStrategy,
+) -> UpdateResult {
+    debug!(
+        "{}: No newer release for retry strategy {}",
+        attr_path, retry_strategy
+    );
+    let next = retry_strategy.next(None);
+    if let Err(e) = db
+        .schedule_retry(attr_path, next.as_ref().map(RetryStrategy::as_str))
+        .await
+    {
+        warn!("{}: Failed to schedule retry: {}", attr_path, e);
+    }
+    UpdateResult::Skipped(format!(
+        "No newer release for retry strategy {}",
+        retry_strategy
+    ))
+}
 
-    // Create and push branch
-    let branch_name = crate::git::create_and_push_branch(
-        worktree_path,
-        attr_path,
-        old_version,
-        new_version,
-        fork,
-    )
-    .await?;
+/// Maximum number of lines of the error log included in failure issues
+const MAX_ISSUE_LOG_LINES: usize = 50;
 
-    // Fetch package metadata for PR body
-    let eval_entry_point = normalize_entry_point("<nixpkgs>");
-    let metadata = PackageMetadata::from_attr_path(&eval_entry_point, attr_path)
-        .await
-        .ok();
+/// Record a failed update, and report it in a tracking issue once the package failed repeatedly
+///
+/// Issues are only opened with `--failure-issue-threshold`, on the repository PRs are opened
+/// against. Later failures are commented on the issue while it's open.
+#[allow(clippy::too_many_arguments)]
+async fn record_failure(
+    db: &Database,
+    pr_config: Option<&PrConfig>,
+    run_options: &RunOptions,
+    drv_path: &str,
+    attr_path: &str,
+    error_message: &str,
+    old_version: Option<&str>,
+    new_version: Option<&str>,
+    retry_strategy: Option<RetryStrategy>,
+) {
+    // Builds may be killed along with the run, e.g. by a SIGTERM to the whole service, which isn't
+    // a failure of the update
+    if run_options.interrupts.stopping() {
+        debug!("{}: Not recording failure of interrupted update", attr_path);
+        return;
+    }
 
-    // Create PR title and body
-    let title = format!(
-        "Update {} from {} to {}",
-        attr_path, old_version, new_version
-    );
-    let mut body = format!(
-        "## Summary\n\nThis PR updates `{}` from version {} to {}.\n\n## Changes\n\n- Updated \
-         package version\n- Updated source hash",
-        attr_path, old_version, new_version
-    );
+    let failure_kind = UpdateFailureKind::classify(error_message);
+    let failures = match db
+        .record_failed_update(
+            drv_path,
+            attr_path,
+            error_message,
+            failure_kind.as_ref().map(UpdateFailureKind::as_str),
+            retry_strategy.as_ref().map(RetryStrategy::as_str),
+            old_version,
+            new_version,
+        )
+        .await
+    {
+        Ok(failures) => failures,
+        Err(e) => {
+            warn!("{}: Failed to record update failure: {}", attr_path, e);
+            return;
+        },
+    };
 
-    // Add optional metadata fields if available
-    if let Some(meta) = metadata.as_ref() {
-        if let Some(description) = meta.description.as_ref() {
-            body.push_str(&format!(
-                "\n\n## Package Information\n\n**Description:** {}",
-                description
-            ));
-        } else {
-            body.push_str("\n\n## Package Information");
-        }
-        if let Some(homepage) = meta.homepage.as_ref() {
-            body.push_str(&format!("\n\n**Homepage:** {}", homepage));
+    if let Some(strategy) = retry_strategy {
+        let next = strategy.next(failure_kind);
+        match next {
+            Some(next) => info!("{}: Will retry with strategy {}", attr_path, next),
+            None => info!(
+                "{}: Every retry strategy failed, giving up for now",
+                attr_path
+            ),
         }
-        if let Some(changelog) = meta.changelog.as_ref() {
-            body.push_str(&format!("\n\n**Changelog:** {}", changelog));
+        if let Err(e) = db
+            .schedule_retry(attr_path, next.as_ref().map(RetryStrategy::as_str))
+            .await
+        {
+            warn!("{}: Failed to schedule retry: {}", attr_path, e);
         }
     }
 
-    body.push_str("\n\n🤖 Generated with ekapkgs-update");
+    if run_options
+        .failure_issue_threshold
+        .is_none_or(|threshold| failures < threshold)
+    {
+        return;
+    }
+    let (Some(config), Ok(token)) = (pr_config, std::env::var("GITHUB_TOKEN")) else {
+        return;
+    };
 
-    // Create PR via GitHub API
-    let pr = crate::github::create_pull_request(
-        &config.owner,
-        &config.repo,
-        &title,
-        &body,
-        &branch_name,
-        &config.base_branch,
-        &github_token,
-    )
-    .await?;
+    let body = failure_issue_body(attr_path, failures, error_message, old_version, new_version);
+    let result = async {
+        if let Some(number) = db.get_failure_issue(attr_path).await? {
+            let issue =
+                crate::github::get_issue(&config.owner, &config.repo, number, &token).await?;
+            if issue.state == "open" {
+                crate::github::comment_on_issue(&config.owner, &config.repo, number, &body, &token)
+                    .await?;
+                info!("{}: Reported failure on {}", attr_path, issue.html_url);
+                return anyhow::Ok(());
+            }
+        }
 
-    // Record PR info in database
-    db.record_pr_info(attr_path, &pr.html_url, pr.number)
-        .await?;
+        let title = format!("Updating {} fails repeatedly", attr_path);
+        let issue =
+            crate::github::create_issue(&config.owner, &config.repo, &title, &body, &token).await?;
+        db.record_failure_issue(attr_path, issue.number).await?;
+        info!("{}: Opened issue {}", attr_path, issue.html_url);
+        Ok(())
+    }
+    .await;
 
-    Ok((pr.html_url, pr.number))
+    if let Err(e) = result {
+        warn!(
+            "{}: Failed to report failure in an issue: {:#}",
+            attr_path, e
+        );
+    }
+}
+
+/// Markdown body of a failure issue or comment, with the end of the error log
+fn failure_issue_body(
+    attr_path: &str,
+    failures: i64,
+    error_message: &str,
+    old_version: Option<&str>,
+    new_version: Option<&str>,
+) -> String {
+    let versions = match (old_version, new_version) {
+        (Some(old), Some(new)) => format!("from {} to {}", old, new),
+        (Some(old), None) => format!("from {}", old),
+        _ => String::new(),
+    };
+    let lines: Vec<&str> = error_message.lines().collect();
+    let log = lines[lines.len().saturating_sub(MAX_ISSUE_LOG_LINES)..].join("\n");
+
+    format!(
+        "Updating `{}` {} failed {} times in a row.\n\n<details>\n<summary>Error log (last {} \
+         lines)</summary>\n\n```\n{}\n```\n\n</details>\n\n🤖 Generated with ekapkgs-update",
+        attr_path, versions, failures, MAX_ISSUE_LOG_LINES, log
+    )
+}
+
+/// Source command of a package, from its configuration or `passthru.updateInfo.command`
+pub fn plugin_source_for(
+    attr_path: &str,
+    metadata: &PackageMetadata,
+    package_config: &PackageConfig,
+) -> Option<UpstreamSource> {
+    let plugin = match package_config.source_command {
+        Some(ref command) => SourcePlugin {
+            command: command.clone(),
+            derivations: None,
+        },
+        None => metadata.source_plugin.clone()?,
+    };
+    Some(UpstreamSource::Plugin {
+        plugin,
+        input: PluginInput {
+            attr_path: attr_path.to_string(),
+            version: metadata.version.clone(),
+            pname: metadata.pname.clone(),
+            src_url: metadata.src_url.clone(),
+            homepage: metadata.homepage.clone(),
+        },
+    })
+}
+
+/// Upstream source of a package, from its source command, its `src` URL or, failing that, its
+/// PyPI `pname`
+///
+/// Returns the reason to skip the package if the source isn't supported.
+pub fn upstream_source_for(
+    attr_path: &str,
+    metadata: &PackageMetadata,
+    package_config: &PackageConfig,
+) -> Result<UpstreamSource, String> {
+    if let Some(source) = plugin_source_for(attr_path, metadata, package_config) {
+        Ok(source)
+    } else if let Some(ref source) = metadata.directives.source {
+        UpstreamSource::from_url(source).ok_or_else(|| {
+            debug!("{}: Unsupported source directive {}", attr_path, source);
+            "Unsupported source directive".to_string()
+        })
+    } else if let Some(ref src_url) = metadata.src_url {
+        UpstreamSource::from_url(src_url).ok_or_else(|| {
+            debug!("{}: Could not parse upstream source from URL", attr_path);
+            "Unsupported source".to_string()
+        })
+    } else if let Some(ref pname) = metadata.pname {
+        Ok(UpstreamSource::PyPI {
+            pname: pname.clone(),
+        })
+    } else {
+        debug!("{}: No source URL or pname found", attr_path);
+        Err("No source info".to_string())
+    }
+}
+
+/// Semver strategy of an update, restricted by the `semver` directive of the package
+pub fn directive_strategy(metadata: &PackageMetadata, strategy: SemverStrategy) -> SemverStrategy {
+    match metadata.directives.semver {
+        Some(directive) => strategy.stricter(directive),
+        None => strategy,
+    }
+}
+
+/// Reason reported for a package skipped by a `skip` directive
+pub fn skip_directive_reason(reason: &str) -> String {
+    if reason.is_empty() {
+        "Skipped by directive".to_string()
+    } else {
+        format!("Skipped by directive: {}", reason)
+    }
+}
+
+/// Query the known vulnerabilities fixed by an update and record them in the database
+///
+/// Returns None if the advisories couldn't be queried.
+async fn query_security_status(
+    db: &Database,
+    attr_path: &str,
+    upstream_source: &UpstreamSource,
+    current_version: &str,
+    latest_version: &str,
+) -> Option<SecurityStatus> {
+    match SecurityStatus::query(upstream_source, current_version, latest_version).await {
+        Ok(status) => {
+            if !status.fixed.is_empty() {
+                let ids: Vec<&str> = status.fixed.iter().map(|v| v.display_id()).collect();
+                info!("{}: Update fixes {}", attr_path, ids.join(", "));
+            }
+            if let Err(e) = db
+                .record_vulnerability_status(attr_path, current_version, latest_version, &status)
+                .await
+            {
+                warn!(
+                    "{}: Failed to record vulnerability status: {}",
+                    attr_path, e
+                );
+            }
+            Some(status)
+        },
+        Err(e) => {
+            warn!("{}: Failed to query security advisories: {}", attr_path, e);
+            None
+        },
+    }
+}
+
+/// Update a package by running its update script inside a worktree
+///
+/// The script's output is recorded in the database and its changes go through the same PR
+/// pipeline as generic updates.
+async fn update_with_script(
+    db: &Database,
+    eval_entry_point: &str,
+    drv: &crate::nix::nix_eval_jobs::NixEvalDrv,
+    pr_config: Option<&PrConfig>,
+    run_options: &RunOptions,
+    current_version: &str,
+) -> anyhow::Result<UpdateResult> {
+    let attr_path = &drv.attr;
+
+    let Some(budget_claim) = run_options.budget.claim() else {
+        debug!("{}: Not updating, the update budget is spent", attr_path);
+        return Ok(UpdateResult::Skipped(BUDGET_SPENT.to_string()));
+    };
+
+    // Update scripts usually rewrite the file of the package, shared by its variants
+    let file_location = get_file_location(eval_entry_point, attr_path).await.ok();
+    let mut file_claim = run_options
+        .file_locks
+        .lock(attr_path, file_location.as_slice())
+        .await;
+
+    // Update scripts build the package too
+    let _build_slot = run_options.build_slots.acquire().await?;
+
+    let worktree_path = match create_worktree_at(attr_path, file_claim.start_point()).await {
+        Ok(path) => path,
+        Err(e) => {
+            warn!("{}: Failed to create worktree: {}", attr_path, e);
+            return Ok(UpdateResult::Skipped(format!(
+                "Worktree creation failed: {}",
+                e
+            )));
+        },
+    };
+    let worktree_entry_point = worktree_path_for(&worktree_path, eval_entry_point)
+        .to_string_lossy()
+        .to_string();
+
+    let script_outcome = run_script_in_worktree(
+        db,
+        run_options,
+        &worktree_entry_point,
+        &worktree_path,
+        attr_path,
+        current_version,
+    )
+    .await;
+
+    let result = match script_outcome {
+        Ok(new_version) if new_version == current_version => {
+            debug!("{}: Update script made no version change", attr_path);
+            if let Err(e) = db
+                .record_no_update(attr_path, current_version, current_version)
+                .await
+            {
+                warn!("{}: Failed to record no update: {}", attr_path, e);
+            }
+            UpdateResult::NoUpdateNeeded {
+                current_version: current_version.to_string(),
+                latest_version: new_version,
+            }
+        },
+        Ok(new_version) => {
+            info!("{}: Update script updated to {}", attr_path, new_version);
+            budget_claim.keep();
+            if let Err(e) = db
+                .record_successful_update(attr_path, current_version, &new_version)
+                .await
+            {
+                warn!("{}: Failed to record successful update: {}", attr_path, e);
+            }
+
+            let report_sections: Vec<String> =
+                verify_reverse_dependencies(run_options, drv, &worktree_entry_point)
+                    .await
+                    .into_iter()
+                    .chain(file_claim.report())
+                    .collect();
+
+            let mut created_pr = None;
+            if let Some(config) = pr_config {
+                match create_pr_for_update(
+                    db,
+                    &worktree_path,
+                    attr_path,
+                    current_version,
+                    &new_version,
+                    &[],
+                    config,
+                    run_options
+                        .update_options
+                        .config
+                        .package(attr_path)
+                        .fork(&run_options.fork),
+                    &report_sections,
+                    &run_options.update_options.maintainer_opt_out,
+                )
+                .await
+                {
+                    Ok((pr_url, pr_number)) => {
+                        info!("{}: Created PR #{}: {}", attr_path, pr_number, pr_url);
+                        created_pr = Some(pr_url.clone());
+                        file_claim.chain(attr_path, &worktree_path).await;
+                        run_post_pr_hook(
+                            run_options,
+                            &worktree_path,
+                            HookContext {
+                                pr_url: Some(pr_url),
+                                ..HookContext::new(attr_path, current_version, &new_version)
+                            },
+                        )
+                        .await;
+                    },
+                    Err(e) => {
+                        warn!("{}: Failed to create PR: {}", attr_path, e);
+                    },
+                }
+            }
+
+            UpdateResult::Updated {
+                old_version: current_version.to_string(),
+                new_version,
+                pr_url: created_pr,
+            }
+        },
+        Err(e) => {
+            let error_message = format!("{:#}", e);
+            warn!("{}: Update script failed: {}", attr_path, error_message);
+            record_failure(
+                db,
+                pr_config,
+                run_options,
+                &drv.drv_path,
+                attr_path,
+                &error_message,
+                Some(current_version),
+                None,
+                None,
+            )
+            .await;
+            UpdateResult::Failed {
+                old_version: current_version.to_string(),
+                new_version: None,
+                error: error_message,
+            }
+        },
+    };
+
+    if let Err(e) = cleanup_worktree(&worktree_path).await {
+        warn!("{}: Failed to clean up worktree: {}", attr_path, e);
+    }
+
+    Ok(result)
+}
+
+/// Run the update script of a package in a worktree, returning the version it updated to
+///
+/// The script's output is recorded in the database. The `pre_rewrite` hook runs before the
+/// script, not knowing the new version yet, and the `post_build` hook after it.
+async fn run_script_in_worktree(
+    db: &Database,
+    run_options: &RunOptions,
+    worktree_entry_point: &str,
+    worktree_path: &Path,
+    attr_path: &str,
+    current_version: &str,
+) -> anyhow::Result<String> {
+    let hooks = run_options.update_options.config.package(attr_path).hooks;
+    let tree = nix::entry_point_dir(worktree_entry_point);
+    let mut hook_context = HookContext::new(attr_path, current_version, "");
+    run_hook(&hooks, HookStage::PreRewrite, &hook_context, tree).await?;
+
+    info!("{}: Running update script in worktree", attr_path);
+    let Some(script_result) = run_update_script(
+        worktree_entry_point,
+        attr_path,
+        Some(worktree_path),
+        run_options.update_options.build_timeout(attr_path),
+    )
+    .await?
+    else {
+        anyhow::bail!("No update script found in worktree");
+    };
+    if let Err(e) = db
+        .record_update_script_log(
+            attr_path,
+            script_result.success(),
+            &script_result.stdout,
+            &script_result.stderr,
+        )
+        .await
+    {
+        warn!(
+            "{}: Failed to record update script output: {}",
+            attr_path, e
+        );
+    }
+    script_result.check()?;
+    let new_version = PackageMetadata::from_attr_path(worktree_entry_point, attr_path)
+        .await?
+        .version;
+
+    if new_version != current_version {
+        hook_context.new_version = new_version.clone();
+        run_hook(&hooks, HookStage::PostBuild, &hook_context, tree).await?;
+    }
+    Ok(new_version)
+}
+
+/// Version change of one member of a group update
+#[derive(Debug, Clone, PartialEq)]
+struct GroupChange {
+    attr: String,
+    old_version: String,
+    new_version: String,
+    /// Description of the upstream source the new version was found at
+    source: String,
+}
+
+impl fmt::Display for GroupChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {}",
+            self.attr, self.old_version, self.new_version
+        )
+    }
+}
+
+/// Current and latest upstream version of a group member
+struct GroupMember {
+    drv: crate::nix::nix_eval_jobs::NixEvalDrv,
+    upstream_source: UpstreamSource,
+    current_version: String,
+    latest_version: String,
+    /// Whether the member is updated by running its update script
+    update_script: bool,
+}
+
+/// A group member with an update available
+struct GroupMemberUpdate {
+    drv: crate::nix::nix_eval_jobs::NixEvalDrv,
+    change: GroupChange,
+    security_status: Option<SecurityStatus>,
+    update_script: bool,
+}
+
+/// Look up the latest upstream version of a group member, None if the member is skipped
+async fn check_group_member(
+    db: &Database,
+    eval_entry_point: &str,
+    drv: &crate::nix::nix_eval_jobs::NixEvalDrv,
+    run_options: &RunOptions,
+) -> anyhow::Result<Option<GroupMember>> {
+    let attr_path = &drv.attr;
+    let metadata = PackageMetadata::from_attr_path(eval_entry_point, attr_path).await?;
+    let current_version = metadata.version.clone();
+
+    if run_options.skip_unstable && current_version.contains("unstable") {
+        debug!("{}: Skipping due to --skip-unstable flag", attr_path);
+        return Ok(None);
+    }
+    if metadata.directives.skip.is_some() {
+        debug!("{}: Skipping due to a skip directive", attr_path);
+        return Ok(None);
+    }
+
+    let package_config = run_options.update_options.config.package(attr_path);
+    let upstream_source =
+        upstream_source_for(attr_path, &metadata, &package_config).map_err(anyhow::Error::msg)?;
+    let tag_filter = package_config.tag_filter()?;
+    let withdrawn =
+        query_withdrawn_versions(eval_entry_point, attr_path, &upstream_source, &metadata).await;
+    let best_release = upstream_source
+        .get_compatible_release(
+            &current_version,
+            directive_strategy(&metadata, SemverStrategy::Latest),
+            &tag_filter,
+            package_config.version_scheme,
+            package_config.release_listing,
+            &PythonRequirements::from_metadata(&metadata),
+            &withdrawn,
+            package_config.min_release_age(),
+        )
+        .await?;
+    let latest_version = tag_filter.release_version(&best_release);
+
+    if current_version == latest_version {
+        if let Err(e) = db
+            .record_no_update(attr_path, &current_version, &latest_version)
+            .await
+        {
+            warn!(
+                "{}: Failed to record no update in database: {}",
+                attr_path, e
+            );
+        }
+    }
+
+    let update_script = !run_options.ignore_update_script
+        && matches!(
+            UpdateScript::eval(eval_entry_point, attr_path).await,
+            Ok(Some(_))
+        );
+
+    Ok(Some(GroupMember {
+        drv: drv.clone(),
+        upstream_source,
+        current_version,
+        latest_version,
+        update_script,
+    }))
+}
+
+/// Whether every member of a lockstep group has been released at the same version
+#[derive(Debug, Clone, PartialEq)]
+enum LockstepStatus {
+    /// Every member is released at this version
+    Released(String),
+    /// The newest version is missing for some members, by attribute path
+    Partial {
+        target: String,
+        missing: Vec<String>,
+    },
+}
+
+/// Compare the latest versions of the members of a lockstep group
+///
+/// The target is the newest of their latest versions. Members whose latest version is unknown
+/// count as missing. Returns None if no latest version is known.
+fn lockstep_status(latest_versions: &[(String, Option<String>)]) -> Option<LockstepStatus> {
+    let target = latest_versions
+        .iter()
+        .filter_map(|(_, latest)| latest.as_deref())
+        .reduce(|newest, version| {
+            if is_version_acceptable(newest, version, SemverStrategy::Latest).unwrap_or(false) {
+                version
+            } else {
+                newest
+            }
+        })?
+        .to_string();
+
+    let missing: Vec<String> = latest_versions
+        .iter()
+        .filter(|(_, latest)| latest.as_deref() != Some(target.as_str()))
+        .map(|(attr, _)| attr.clone())
+        .collect();
+
+    if missing.is_empty() {
+        Some(LockstepStatus::Released(target))
+    } else {
+        Some(LockstepStatus::Partial { target, missing })
+    }
+}
+
+/// Update every member of a group with an update available in a single worktree and PR
+///
+/// Members are updated in the order they were evaluated, so later members are built against the
+/// updated earlier ones. Members with an update script are updated by running it, the others
+/// from their upstream releases. If any member fails, the whole group is abandoned.
+///
+/// Members of a lockstep group are only updated once upstream has released the same version
+/// of every member. Until then the group is reported as partially released.
+async fn check_and_update_group(
+    db: &Database,
+    eval_entry_point: &str,
+    group: &PackageGroup,
+    members: &[crate::nix::nix_eval_jobs::NixEvalDrv],
+    pr_config: Option<&PrConfig>,
+    run_options: &RunOptions,
+) -> anyhow::Result<UpdateResult> {
+    let group_name = group.name.as_str();
+
+    let mut checked_members = Vec::new();
+    let mut latest_versions = Vec::new();
+    for drv in members {
+        match check_group_member(db, eval_entry_point, drv, run_options).await {
+            Ok(Some(member)) => {
+                latest_versions.push((drv.attr.clone(), Some(member.latest_version.clone())));
+                checked_members.push(member);
+            },
+            Ok(None) => {},
+            Err(e) => {
+                debug!("{}: Could not check for updates: {}", drv.attr, e);
+                latest_versions.push((drv.attr.clone(), None));
+            },
+        }
+    }
+
+    if group.lockstep {
+        match lockstep_status(&latest_versions) {
+            Some(LockstepStatus::Released(version)) => {
+                debug!("{}: Every member is released at {}", group_name, version);
+            },
+            Some(LockstepStatus::Partial { target, missing }) => {
+                return Ok(UpdateResult::GroupPartiallyReleased { target, missing });
+            },
+            None => {
+                return Ok(UpdateResult::Skipped(
+                    "Could not determine the latest version of any member".to_string(),
+                ));
+            },
+        }
+    }
+
+    let mut updates = Vec::new();
+    for member in checked_members {
+        if member.current_version == member.latest_version {
+            continue;
+        }
+
+        let attr_path = &member.drv.attr;
+        info!(
+            "{}: Update available: {} -> {}",
+            attr_path, member.current_version, member.latest_version
+        );
+
+        let security_status = if run_options.check_advisories {
+            query_security_status(
+                db,
+                attr_path,
+                &member.upstream_source,
+                &member.current_version,
+                &member.latest_version,
+            )
+            .await
+        } else {
+            None
+        };
+
+        updates.push(GroupMemberUpdate {
+            change: GroupChange {
+                attr: attr_path.clone(),
+                old_version: member.current_version,
+                new_version: member.latest_version,
+                source: member.upstream_source.description(),
+            },
+            drv: member.drv,
+            security_status,
+            update_script: member.update_script,
+        });
+    }
+
+    if updates.is_empty() {
+        return Ok(UpdateResult::Skipped("No member has an update".to_string()));
+    }
+
+    // Skip the group while every update is still waiting for the previously proposed PR
+    let mut already_proposed = true;
+    for update in &updates {
+        let record = db.get_update_record(&update.change.attr).await?;
+        let proposed = record.and_then(|r| r.proposed_version);
+        if proposed.as_deref() != Some(update.change.new_version.as_str()) {
+            already_proposed = false;
+            break;
+        }
+    }
+    if already_proposed {
+        return Ok(UpdateResult::Skipped("Update already proposed".to_string()));
+    }
+
+    if run_options.security_only
+        && updates.iter().all(|update| {
+            update
+                .security_status
+                .as_ref()
+                .is_none_or(|s| s.fixed.is_empty())
+        })
+    {
+        return Ok(UpdateResult::Skipped(
+            "No known vulnerabilities fixed".to_string(),
+        ));
+    }
+
+    let changes: Vec<GroupChange> = updates.iter().map(|u| u.change.clone()).collect();
+    if run_options.dry_run {
+        return Ok(UpdateResult::GroupDryRun(changes));
+    }
+
+    let Some(budget_claim) = run_options.budget.claim() else {
+        debug!("{}: Not updating, the update budget is spent", group_name);
+        return Ok(UpdateResult::Skipped(BUDGET_SPENT.to_string()));
+    };
+
+    // Members may share files with each other and with packages outside of the group
+    let mut file_locations = Vec::new();
+    for update in &updates {
+        if let Ok(location) = get_file_location(eval_entry_point, &update.change.attr).await {
+            file_locations.push(location);
+        }
+    }
+    let mut file_claim = run_options
+        .file_locks
+        .lock(group_name, &file_locations)
+        .await;
+
+    let _build_slot = run_options.build_slots.acquire().await?;
+    let worktree_path = match create_worktree_at(
+        &format!("group-{}", group_name),
+        file_claim.start_point(),
+    )
+    .await
+    {
+        Ok(path) => path,
+        Err(e) => {
+            warn!("{}: Failed to create worktree: {}", group_name, e);
+            return Ok(UpdateResult::Skipped(format!(
+                "Worktree creation failed: {}",
+                e
+            )));
+        },
+    };
+    let worktree_entry_point = worktree_path_for(&worktree_path, eval_entry_point)
+        .to_string_lossy()
+        .to_string();
+
+    let mut report_sections = Vec::new();
+    let mut commit_steps = Vec::new();
+    for update in &mut updates {
+        // Update scripts pick the version themselves
+        if update.update_script {
+            let attr = update.change.attr.clone();
+            let script_outcome = run_script_in_worktree(
+                db,
+                run_options,
+                &worktree_entry_point,
+                &worktree_path,
+                &attr,
+                &update.change.old_version,
+            )
+            .await;
+            match script_outcome {
+                Ok(new_version) => {
+                    info!(
+                        "{}: Update script updated {} to {}",
+                        group_name, attr, new_version
+                    );
+                    update.change.new_version = new_version;
+                    continue;
+                },
+                Err(e) => {
+                    let error_message = format!("{:#}", e);
+                    warn!(
+                        "{}: Update script of group member {} failed: {}",
+                        group_name, attr, error_message
+                    );
+                    record_failure(
+                        db,
+                        pr_config,
+                        run_options,
+                        &update.drv.drv_path,
+                        &attr,
+                        &error_message,
+                        Some(&update.change.old_version),
+                        None,
+                        None,
+                    )
+                    .await;
+                    if let Err(cleanup_err) = cleanup_worktree(&worktree_path).await {
+                        warn!(
+                            "{}: Failed to clean up worktree: {}",
+                            group_name, cleanup_err
+                        );
+                    }
+                    return Ok(UpdateResult::Failed {
+                        old_version: update.change.old_version.clone(),
+                        new_version: None,
+                        error: format!("Update script of {} failed: {}", attr, error_message),
+                    });
+                },
+            }
+        }
+
+        let GroupChange {
+            attr,
+            old_version,
+            new_version,
+            ..
+        } = &update.change;
+
+        let mut timings = PhaseTimings::default();
+        let result = async {
+            let file_location = get_file_location(eval_entry_point, attr).await?;
+            let worktree_file = worktree_path_for(&worktree_path, &file_location)
+                .to_string_lossy()
+                .to_string();
+            crate::commands::update::update_from_file_path(
+                worktree_entry_point.clone(),
+                attr.clone(),
+                worktree_file,
+                None,
+                &run_options.update_options,
+                &mut timings,
+            )
+            .await
+        }
+        .await;
+        record_timings(db, attr, &mut timings).await;
+
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                let error_message = format!("{:#}", e);
+                warn!(
+                    "{}: Update of group member {} failed: {}",
+                    group_name, attr, error_message
+                );
+                record_failure(
+                    db,
+                    pr_config,
+                    run_options,
+                    &update.drv.drv_path,
+                    attr,
+                    &error_message,
+                    Some(old_version),
+                    Some(new_version),
+                    None,
+                )
+                .await;
+                if let Err(cleanup_err) = cleanup_worktree(&worktree_path).await {
+                    warn!(
+                        "{}: Failed to clean up worktree: {}",
+                        group_name, cleanup_err
+                    );
+                }
+                return Ok(UpdateResult::Failed {
+                    old_version: old_version.clone(),
+                    new_version: Some(new_version.clone()),
+                    error: format!("Update of {} failed: {}", attr, error_message),
+                });
+            },
+        };
+
+        info!("{}: Updated {}", group_name, update.change);
+        commit_steps.extend(outcome.commit_steps.iter().cloned());
+        if !outcome.test_results.is_empty() {
+            if let Err(e) = db
+                .record_passthru_test_results(attr, new_version, &outcome.test_results)
+                .await
+            {
+                warn!("{}: Failed to record passthru test results: {}", attr, e);
+            }
+        }
+
+        let mut sections: Vec<String> = update
+            .security_status
+            .as_ref()
+            .and_then(SecurityStatus::report)
+            .into_iter()
+            .collect();
+        sections.extend(outcome.report_sections());
+        sections.extend(
+            verify_reverse_dependencies(run_options, &update.drv, &worktree_entry_point).await,
+        );
+        if !sections.is_empty() {
+            report_sections.push(format!("# `{}`", attr));
+            report_sections.extend(sections);
+        }
+    }
+
+    // Update scripts may have picked other versions than upstream's latest, or none
+    let changes: Vec<GroupChange> = updates
+        .iter()
+        .map(|u| u.change.clone())
+        .filter(|change| change.new_version != change.old_version)
+        .collect();
+    if changes.is_empty() {
+        if let Err(e) = cleanup_worktree(&worktree_path).await {
+            warn!("{}: Failed to clean up worktree: {}", group_name, e);
+        }
+        return Ok(UpdateResult::Skipped(
+            "Update scripts made no version change".to_string(),
+        ));
+    }
+
+    budget_claim.keep();
+    report_sections.extend(file_claim.report());
+
+    let mut created_pr = None;
+    if let Some(config) = pr_config {
+        match create_pr_for_group(
+            db,
+            &worktree_path,
+            eval_entry_point,
+            group_name,
+            &changes,
+            &commit_steps,
+            config,
+            &run_options.fork,
+            &report_sections,
+            &run_options.update_options.maintainer_opt_out,
+        )
+        .await
+        {
+            Ok((pr_url, pr_number)) => {
+                info!("{}: Created PR #{}: {}", group_name, pr_number, pr_url);
+                created_pr = Some(pr_url.clone());
+                file_claim.chain(group_name, &worktree_path).await;
+                for change in &changes {
+                    run_post_pr_hook(
+                        run_options,
+                        &worktree_path,
+                        HookContext {
+                            pr_url: Some(pr_url.clone()),
+                            ..HookContext::new(
+                                &change.attr,
+                                &change.old_version,
+                                &change.new_version,
+                            )
+                        },
+                    )
+                    .await;
+                }
+            },
+            Err(e) => {
+                // The members are retried like failed updates, none of them was proposed
+                let error_message = format!("Failed to create PR: {:#}", e);
+                warn!("{}: {}", group_name, error_message);
+                for update in &updates {
+                    record_failure(
+                        db,
+                        pr_config,
+                        run_options,
+                        &update.drv.drv_path,
+                        &update.change.attr,
+                        &error_message,
+                        Some(&update.change.old_version),
+                        Some(&update.change.new_version),
+                        None,
+                    )
+                    .await;
+                }
+                if let Err(e) = cleanup_worktree(&worktree_path).await {
+                    warn!("{}: Failed to clean up worktree: {}", group_name, e);
+                }
+                return Ok(UpdateResult::Failed {
+                    old_version: changes[0].old_version.clone(),
+                    new_version: Some(group_version_label(&changes)),
+                    error: error_message,
+                });
+            },
+        }
+    }
+
+    // Only recorded once proposed, so a group whose PR fails isn't taken as updated
+    for change in &changes {
+        if let Err(e) = db
+            .record_successful_update(&change.attr, &change.old_version, &change.new_version)
+            .await
+        {
+            warn!("{}: Failed to record successful update: {}", change.attr, e);
+        }
+    }
+
+    if let Err(e) = cleanup_worktree(&worktree_path).await {
+        warn!("{}: Failed to clean up worktree: {}", group_name, e);
+    }
+
+    Ok(UpdateResult::GroupUpdated {
+        changes,
+        pr_url: created_pr,
+    })
+}
+
+/// Version used to name a group update: the common new version, or every new version
+fn group_version_label(changes: &[GroupChange]) -> String {
+    let mut versions: Vec<&str> = changes.iter().map(|c| c.new_version.as_str()).collect();
+    versions.sort_unstable();
+    versions.dedup();
+    versions.join("-")
+}
+
+/// Markdown summary of a group update for its PR body
+fn format_group_summary(group_name: &str, changes: &[GroupChange]) -> String {
+    let mut summary = format!(
+        "## Summary\n\nThis PR updates the `{}` group together.\n\n| Package | From | To |\n| --- \
+         | --- | --- |",
+        group_name
+    );
+    for change in changes {
+        summary.push_str(&format!(
+            "\n| `{}` | {} | {} |",
+            change.attr, change.old_version, change.new_version
+        ));
+    }
+    summary
+}
+
+/// Create a single pull request for a group update
+#[allow(clippy::too_many_arguments)]
+async fn create_pr_for_group(
+    db: &Database,
+    worktree_path: &Path,
+    eval_entry_point: &str,
+    group_name: &str,
+    changes: &[GroupChange],
+    commit_steps: &[CommitStep],
+    config: &PrConfig,
+    fork: &str,
+    report_sections: &[String],
+    maintainer_opt_out: &[String],
+) -> anyhow::Result<(String, i64)> {
+    let github_token = std::env::var("GITHUB_TOKEN")
+        .map_err(|_| anyhow::anyhow!("GITHUB_TOKEN environment variable not set"))?;
+
+    let version_label = group_version_label(changes);
+    let branch_name = format!(
+        "update/group-{}/{}",
+        crate::git::ref_component(&group_name.replace('.', "-")),
+        crate::git::ref_component(&version_label)
+    );
+    let change_list: Vec<String> = changes.iter().map(|c| format!("- {}", c)).collect();
+    let trailers: Vec<(&str, &str, &str)> = changes
+        .iter()
+        .map(|c| {
+            (
+                c.attr.as_str(),
+                c.old_version.as_str(),
+                c.new_version.as_str(),
+            )
+        })
+        .collect();
+    let commit_message = format!(
+        "Update {} group\n\n{}\n\n🤖 Generated with ekapkgs-update\n\nCo-Authored-By: \
+         ekapkgs-update <noreply@ekapkgs.org>\n{}",
+        group_name,
+        change_list.join("\n"),
+        update_trailers(&trailers)
+    );
+    crate::git::commit_and_push_branch(
+        worktree_path,
+        group_name,
+        &branch_name,
+        &commit_message,
+        commit_steps,
+        fork,
+    )
+    .await?;
+
+    let title = format!("Update {} group to {}", group_name, version_label);
+    let mut body = format_group_summary(group_name, changes);
+    for section in report_sections {
+        body.push_str(&format!("\n\n{}", section));
+    }
+
+    // Ping the maintainers of every member
+    let mut maintainers: Vec<String> = Vec::new();
+    for change in changes {
+        for handle in PackageQuery::new(eval_entry_point, &change.attr)
+            .get_maintainer_handles()
+            .await
+        {
+            if !maintainers.contains(&handle) {
+                maintainers.push(handle);
+            }
+        }
+    }
+    if let Some(mentions) = crate::github::mention_maintainers(&maintainers, maintainer_opt_out) {
+        body.push_str(&format!("\n\n{}", mentions));
+    }
+
+    body.push_str("\n\n🤖 Generated with ekapkgs-update");
+
+    let pr = crate::github::create_pull_request(
+        &config.owner,
+        &config.repo,
+        &title,
+        &body,
+        &crate::git::pr_head(fork, &branch_name, config).await,
+        &config.base_branch,
+        &github_token,
+    )
+    .await?;
+
+    for change in changes {
+        db.record_pr_info(&change.attr, &pr.html_url, pr.number)
+            .await?;
+    }
+
+    Ok((pr.html_url, pr.number))
+}
+
+/// Outcome of verifying a reverse dependency against an updated package
+#[derive(Debug, Clone, PartialEq)]
+struct ReverseDepResult {
+    attr: String,
+    passed: bool,
+}
+
+/// Build (or dry-run build) the direct reverse dependencies of an updated package
+///
+/// Returns a markdown report for the PR body, or None if verification is disabled or the
+/// package has no known reverse dependencies.
+async fn verify_reverse_dependencies(
+    run_options: &RunOptions,
+    drv: &crate::nix::nix_eval_jobs::NixEvalDrv,
+    eval_entry_point: &str,
+) -> Option<String> {
+    let mode = run_options.verify_reverse_deps?;
+    let dependents = run_options.reverse_deps.dependents_of(&drv.drv_path);
+    if dependents.is_empty() {
+        return None;
+    }
+
+    info!(
+        "{}: Verifying {} reverse dependencies",
+        drv.attr,
+        dependents.len().min(MAX_REVERSE_DEPS)
+    );
+
+    let mut results = Vec::new();
+    for attr in dependents.iter().take(MAX_REVERSE_DEPS) {
+        let outcome = match mode {
+            ReverseDepsMode::Build => build_nix_expr(
+                eval_entry_point,
+                attr,
+                None,
+                &run_options.update_options.build_options,
+            )
+            .await
+            .map(|(success, _stdout, stderr)| (success, stderr)),
+            ReverseDepsMode::DryRun => dry_run_build_nix_expr(eval_entry_point, attr).await,
+        };
+        let passed = match outcome {
+            Ok((success, stderr)) => {
+                if !success {
+                    warn!(
+                        "{}: Reverse dependency {} failed:\n{}",
+                        drv.attr, attr, stderr
+                    );
+                }
+                success
+            },
+            Err(e) => {
+                warn!("{}: Could not verify {}: {}", drv.attr, attr, e);
+                false
+            },
+        };
+        results.push(ReverseDepResult {
+            attr: attr.clone(),
+            passed,
+        });
+    }
+
+    Some(format_reverse_deps_report(&results, dependents.len(), mode))
+}
+
+/// Render reverse dependency results as a markdown table
+fn format_reverse_deps_report(
+    results: &[ReverseDepResult],
+    total: usize,
+    mode: ReverseDepsMode,
+) -> String {
+    let action = match mode {
+        ReverseDepsMode::Build => "Built",
+        ReverseDepsMode::DryRun => "Instantiated",
+    };
+    let failed = results.iter().filter(|r| !r.passed).count();
+
+    let mut report = format!(
+        "## Reverse Dependencies\n\n{} {} of {} direct reverse dependencies, {} failed.\n\n| \
+         Package | Result |\n| --- | --- |",
+        action,
+        results.len(),
+        total,
+        failed
+    );
+    for result in results {
+        let outcome = if result.passed {
+            "✅ passed"
+        } else {
+            "❌ failed"
+        };
+        report.push_str(&format!("\n| `{}` | {} |", result.attr, outcome));
+    }
+    report
+}
+
+/// Map a path in the main repository to the same path inside a worktree
+pub fn worktree_path_for(worktree_path: &Path, path: &str) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(repo_root) => rebase_path(&repo_root, worktree_path, path),
+        Err(_) => worktree_path.join(path),
+    }
+}
+
+/// Rebase `path`, relative to or inside `repo_root`, onto `worktree_path`
+///
+/// Absolute paths outside of the repository are returned unchanged.
+fn rebase_path(repo_root: &Path, worktree_path: &Path, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let relative = path.strip_prefix(repo_root).unwrap_or(path);
+    worktree_path.join(relative)
+}
+
+/// Whether the upstream base branch already updated a package to `version`
+///
+/// Failures to locate or read the file defining the package are only logged, the update is then
+/// attempted as usual.
+async fn updated_upstream(
+    eval_entry_point: &str,
+    attr_path: &str,
+    base: &str,
+    version: &str,
+) -> bool {
+    let file_location = match get_file_location(eval_entry_point, attr_path).await {
+        Ok(location) => location,
+        Err(e) => {
+            debug!("{}: Failed to get file location: {}", attr_path, e);
+            return false;
+        },
+    };
+    let upstream = match file_at_revision(base, Path::new(&file_location)).await {
+        Ok(Some(content)) => content,
+        Ok(None) => return false,
+        Err(e) => {
+            debug!("{}: {:#}", attr_path, e);
+            return false;
+        },
+    };
+    let local = tokio::fs::read_to_string(&file_location)
+        .await
+        .unwrap_or_default();
+    is_updated_in(&local, &upstream, version)
+}
+
+/// Whether the upstream version of a Nix file sets `version` while the local one doesn't
+fn is_updated_in(local: &str, upstream: &str, version: &str) -> bool {
+    let quoted = format!("\"{}\"", version);
+    upstream.contains(&quoted) && !local.contains(&quoted)
+}
+
+/// Get the file location for a package from meta.position
+///
+/// Packages without `meta.position`, e.g. generated or aliased ones, are located by searching
+/// the tree of the entry point for the file defining their `pname` and `version`.
+pub async fn get_file_location(eval_entry_point: &str, attr_path: &str) -> anyhow::Result<String> {
+    let package_set = import_entry_point(eval_entry_point);
+    let position_expr = format!("with {}; {}.meta.position", package_set, attr_path);
+
+    let position = match eval_nix_expr(&position_expr).await {
+        Ok(position) if !position.is_empty() => position,
+        Ok(_) => {
+            debug!("{}: Empty meta.position, searching the tree", attr_path);
+            return find_file_location_by_grep(eval_entry_point, attr_path).await;
+        },
+        Err(e) => {
+            debug!(
+                "{}: Failed to evaluate meta.position ({}), searching the tree",
+                attr_path, e
+            );
+            return find_file_location_by_grep(eval_entry_point, attr_path).await;
+        },
+    };
+
+    // Parse position string (format: "file:line")
+    let (file_path, _line_str) = position
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Unexpected position format: {}", position))?;
+
+    Ok(file_path.to_string())
+}
+
+/// Locate the file defining a package by searching for its `pname` and `version` bindings
+///
+/// Only succeeds if exactly one .nix file under the entry point's directory binds both, so an
+/// ambiguous match never results in rewriting the wrong package.
+async fn find_file_location_by_grep(
+    eval_entry_point: &str,
+    attr_path: &str,
+) -> anyhow::Result<String> {
+    let package_set = import_entry_point(eval_entry_point);
+    let pname = eval_nix_expr(&format!(
+        "with {}; {}.pname or \"\"",
+        package_set, attr_path
+    ))
+    .await?;
+    let version = eval_nix_expr(&format!(
+        "with {}; {}.version or \"\"",
+        package_set, attr_path
+    ))
+    .await?;
+    if pname.is_empty() || version.is_empty() {
+        anyhow::bail!("No meta.position, and no pname and version to search for");
+    }
+
+    let mut candidates = Vec::new();
+    for entry in walkdir::WalkDir::new(nix::entry_point_dir(eval_entry_point))
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        if !path.is_file() || path.extension().and_then(|s| s.to_str()) != Some("nix") {
+            continue;
+        }
+        let Ok(content) = tokio::fs::read_to_string(path).await else {
+            continue;
+        };
+        if defines_package(&content, &pname, &version) {
+            candidates.push(path.to_string_lossy().to_string());
+        }
+    }
+
+    match candidates.len() {
+        1 => {
+            info!(
+                "{}: No meta.position, found definition of {} {} in {}",
+                attr_path, pname, version, candidates[0]
+            );
+            Ok(candidates.remove(0))
+        },
+        0 => anyhow::bail!(
+            "No meta.position, and no file binds pname \"{}\" and version \"{}\"",
+            pname,
+            version
+        ),
+        n => anyhow::bail!(
+            "No meta.position, and {} files bind pname \"{}\" and version \"{}\": {}",
+            n,
+            pname,
+            version,
+            candidates.join(", ")
+        ),
+    }
+}
+
+/// Check whether a Nix file binds both `pname` and `version` to the given string literals
+fn defines_package(content: &str, pname: &str, version: &str) -> bool {
+    let binds = |attr: &str, value: &str| {
+        regex::Regex::new(&format!(
+            r#"(?m)(^|[\s{{;]){}\s*=\s*"{}"\s*;"#,
+            attr,
+            regex::escape(value)
+        ))
+        .is_ok_and(|re| re.is_match(content))
+    };
+    binds("pname", pname) && binds("version", version)
+}
+
+/// Create a pull request for a successful update
+#[allow(clippy::too_many_arguments)]
+async fn create_pr_for_update(
+    db: &Database,
+    worktree_path: &std::path::Path,
+    attr_path: &str,
+    old_version: &str,
+    new_version: &str,
+    commit_steps: &[CommitStep],
+    config: &PrConfig,
+    fork: &str,
+    report_sections: &[String],
+    maintainer_opt_out: &[String],
+) -> anyhow::Result<(String, i64)> {
+    // Get GitHub token from environment
+    let github_token = std::env::var("GITHUB_TOKEN")
+        .map_err(|_| anyhow::anyhow!("GITHUB_TOKEN environment variable not set"))?;
+
+    // Create and push branch
+    let branch_name = crate::git::create_and_push_branch(
+        worktree_path,
+        attr_path,
+        old_version,
+        new_version,
+        commit_steps,
+        fork,
+    )
+    .await?;
+
+    // Fetch package metadata for PR body
+    let eval_entry_point = normalize_entry_point("<nixpkgs>");
+    let metadata = PackageMetadata::from_attr_path(&eval_entry_point, attr_path)
+        .await
+        .ok();
+
+    // Create PR title and body
+    let title = format!(
+        "Update {} from {} to {}",
+        attr_path, old_version, new_version
+    );
+    let mut body = format!(
+        "## Summary\n\nThis PR updates `{}` from version {} to {}.\n\n## Changes\n\n- Updated \
+         package version\n- Updated source hash",
+        attr_path, old_version, new_version
+    );
+
+    // Add optional metadata fields if available
+    if let Some(meta) = metadata.as_ref() {
+        if let Some(description) = meta.description.as_ref() {
+            body.push_str(&format!(
+                "\n\n## Package Information\n\n**Description:** {}",
+                description
+            ));
+        } else {
+            body.push_str("\n\n## Package Information");
+        }
+        if let Some(homepage) = meta.homepage.as_ref() {
+            body.push_str(&format!("\n\n**Homepage:** {}", homepage));
+        }
+        if let Some(changelog) = meta.changelog.as_ref() {
+            body.push_str(&format!(
+                "\n\n**Changelog:** {}",
+                replace_version(changelog, old_version, new_version)
+            ));
+        }
+    }
+
+    for section in report_sections {
+        body.push_str(&format!("\n\n{}", section));
+    }
+
+    if let Some(mentions) = metadata
+        .as_ref()
+        .and_then(|meta| crate::github::mention_maintainers(&meta.maintainers, maintainer_opt_out))
+    {
+        body.push_str(&format!("\n\n{}", mentions));
+    }
+
+    body.push_str("\n\n🤖 Generated with ekapkgs-update");
+
+    // Create PR via GitHub API
+    let pr = crate::github::create_pull_request(
+        &config.owner,
+        &config.repo,
+        &title,
+        &body,
+        &crate::git::pr_head(fork, &branch_name, config).await,
+        &config.base_branch,
+        &github_token,
+    )
+    .await?;
+
+    // Record PR info in database
+    db.record_pr_info(attr_path, &pr.html_url, pr.number)
+        .await?;
+
+    Ok((pr.html_url, pr.number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen_drvs() {
+        let drv = |attr: &str| crate::nix::nix_eval_jobs::NixEvalDrv {
+            attr: attr.to_string(),
+            attr_path: vec![attr.to_string()],
+            drv_path: "/nix/store/hello.drv".to_string(),
+            input_drvs: None,
+            name: "hello".to_string(),
+            outputs: HashMap::new(),
+            system: "x86_64-linux".to_string(),
+            meta: None,
+        };
+        let dispatched = |listening: bool| -> Vec<String> {
+            let mut seen_drvs = SeenDrvs::new(listening);
+            [drv("hello"), drv("hello"), drv("hello-alias")]
+                .into_iter()
+                .filter(|drv| seen_drvs.alias_of(drv).is_none())
+                .map(|drv| drv.attr)
+                .collect()
+        };
+
+        assert_eq!(dispatched(false), ["hello"]);
+        // Every webhook request is dispatched, however often the package was requested
+        assert_eq!(dispatched(true), ["hello", "hello", "hello-alias"]);
+    }
+
+    #[test]
+    fn test_file_claim() {
+        let now = std::time::Instant::now();
+        let chained = |label: &str, commit: &str, at| ChainedUpdate {
+            label: label.to_string(),
+            commit: commit.to_string(),
+            proposed_at: at,
+        };
+        let claim = |updates: Vec<Option<ChainedUpdate>>| FileClaim {
+            guards: updates
+                .into_iter()
+                .map(|update| Arc::new(Mutex::new(update)).try_lock_owned().unwrap())
+                .collect(),
+        };
+
+        let unchained = claim(vec![None, None]);
+        assert_eq!(unchained.start_point(), "HEAD");
+        assert_eq!(unchained.report(), None);
+
+        let later = now + std::time::Duration::from_secs(1);
+        let claim = claim(vec![
+            Some(chained("foo", "abc", now)),
+            None,
+            Some(chained("bar", "def", later)),
+        ]);
+        assert_eq!(claim.start_point(), "def");
+        assert!(claim.report().unwrap().contains("update of `bar`"));
+    }
+
+    #[test]
+    fn test_rebase_path() {
+        let repo_root = Path::new("/home/user/ekapkgs");
+        let worktree = Path::new("/home/user/.cache/ekapkgs-update/worktrees/update-hello");
+
+        assert_eq!(
+            rebase_path(
+                repo_root,
+                worktree,
+                "/home/user/ekapkgs/pkgs/hello/default.nix"
+            ),
+            worktree.join("pkgs/hello/default.nix")
+        );
+        assert_eq!(
+            rebase_path(repo_root, worktree, "default.nix"),
+            worktree.join("default.nix")
+        );
+        assert_eq!(
+            rebase_path(repo_root, worktree, "/nix/store/abc-source/default.nix"),
+            PathBuf::from("/nix/store/abc-source/default.nix")
+        );
+    }
+
+    #[test]
+    fn test_format_reverse_deps_report() {
+        let results = vec![
+            ReverseDepResult {
+                attr: "cmake".to_string(),
+                passed: true,
+            },
+            ReverseDepResult {
+                attr: "curl".to_string(),
+                passed: false,
+            },
+        ];
+
+        let report = format_reverse_deps_report(&results, 30, ReverseDepsMode::Build);
+        assert!(report.starts_with("## Reverse Dependencies"));
+        assert!(report.contains("Built 2 of 30 direct reverse dependencies, 1 failed."));
+        assert!(report.contains("| `cmake` | ✅ passed |"));
+        assert!(report.contains("| `curl` | ❌ failed |"));
+    }
+
+    #[test]
+    fn test_group_summary() {
+        let changes = vec![
+            GroupChange {
+                attr: "python3Packages.sphinx".to_string(),
+                old_version: "7.1.0".to_string(),
+                new_version: "7.2.0".to_string(),
+                source: "PyPI package: sphinx".to_string(),
+            },
+            GroupChange {
+                attr: "python3Packages.sphinxcontrib-foo".to_string(),
+                old_version: "1.0".to_string(),
+                new_version: "1.1".to_string(),
+                source: "PyPI package: sphinxcontrib-foo".to_string(),
+            },
+        ];
+
+        assert_eq!(group_version_label(&changes), "1.1-7.2.0");
+        assert_eq!(group_version_label(&changes[..1]), "7.2.0");
+        assert_eq!(
+            changes[0].to_string(),
+            "python3Packages.sphinx 7.1.0 -> 7.2.0"
+        );
+
+        let summary = format_group_summary("sphinx", &changes);
+        assert!(summary.contains("updates the `sphinx` group together"));
+        assert!(summary.contains("| `python3Packages.sphinxcontrib-foo` | 1.0 | 1.1 |"));
+    }
+
+    #[test]
+    fn test_update_budget() {
+        let budget = UpdateBudget::new(Some(2), None);
+        let first = budget.claim().unwrap();
+        let second = budget.claim().unwrap();
+        assert!(budget.spent());
+        assert!(budget.claim().is_none());
+
+        // Failed updates give their share back
+        drop(second);
+        assert!(!budget.spent());
+        first.keep();
+        budget.claim().unwrap().keep();
+        assert!(budget.spent());
+
+        assert!(!UpdateBudget::new(None, None).spent());
+        assert!(
+            UpdateBudget::new(None, Some(Duration::ZERO))
+                .claim()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_lockstep_status() {
+        let released = vec![
+            ("qt6.qtbase".to_string(), Some("6.8.1".to_string())),
+            ("qt6.qtsvg".to_string(), Some("6.8.1".to_string())),
+        ];
+        assert_eq!(
+            lockstep_status(&released),
+            Some(LockstepStatus::Released("6.8.1".to_string()))
+        );
+
+        let partial = vec![
+            ("qt6.qtbase".to_string(), Some("6.8.1".to_string())),
+            ("qt6.qtsvg".to_string(), Some("6.8.0".to_string())),
+            ("qt6.qtwayland".to_string(), None),
+        ];
+        assert_eq!(
+            lockstep_status(&partial),
+            Some(LockstepStatus::Partial {
+                target: "6.8.1".to_string(),
+                missing: vec!["qt6.qtsvg".to_string(), "qt6.qtwayland".to_string()],
+            })
+        );
+
+        assert_eq!(lockstep_status(&[("foo".to_string(), None)]), None);
+    }
+
+    #[test]
+    fn test_failure_issue_body() {
+        let log: Vec<String> = (0..80).map(|i| format!("build line {}", i)).collect();
+        let body = failure_issue_body("hello", 3, &log.join("\n"), Some("2.12"), Some("2.13"));
+
+        assert!(body.starts_with("Updating `hello` from 2.12 to 2.13 failed 3 times in a row."));
+        assert!(body.contains("build line 79"));
+        assert!(body.contains("build line 30\n"));
+        assert!(!body.contains("build line 29\n"));
+    }
+
+    #[test]
+    fn test_defines_package() {
+        let content = "{ stdenv }:\nstdenv.mkDerivation {\n  pname = \"hello\";\n  version = \
+                       \"2.12.1\";\n}\n";
+        assert!(defines_package(content, "hello", "2.12.1"));
+        assert!(!defines_package(content, "hello", "2.12"));
+        assert!(!defines_package(content, "hello-unwrapped", "2.12.1"));
+        assert!(!defines_package(
+            "{ mypname = \"hello\"; version = \"2.12.1\"; }",
+            "hello",
+            "2.12.1"
+        ));
+        assert!(defines_package(
+            "{ pname = \"hello\"; version = \"2.12.1\"; }",
+            "hello",
+            "2.12.1"
+        ));
+    }
+
+    #[test]
+    fn test_is_updated_in() {
+        let local = "{\n  pname = \"hello\";\n  version = \"2.12.1\";\n}\n";
+        let upstream = local.replace("2.12.1", "2.12.2");
+        assert!(is_updated_in(local, &upstream, "2.12.2"));
+        assert!(!is_updated_in(local, local, "2.12.2"));
+        // Already set locally, e.g. a hash-only change upstream
+        assert!(!is_updated_in(&upstream, &upstream, "2.12.2"));
+        // Prefix of another version
+        assert!(!is_updated_in(
+            local,
+            &local.replace("2.12.1", "2.12.20"),
+            "2.12.2"
+        ));
+    }
 }
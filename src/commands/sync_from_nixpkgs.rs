@@ -0,0 +1,299 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use regex::Regex;
+use tracing::{info, warn};
+
+use crate::commands::run::get_file_location;
+use crate::commands::update::{UpdateOptions, UpdateOutcome, create_git_commit, create_update_pr};
+use crate::config::Config;
+use crate::git::{uncommitted_changes, update_trailers};
+use crate::nixpkgs;
+use crate::package::PackageMetadata;
+
+/// Copy the expression of a package, and the files it refers to, from nixpkgs into the tree
+///
+/// The file defining the package in nixpkgs replaces the local one, and patches and other files
+/// referred to by relative paths within its directory are copied next to it. References to files
+/// outside of its directory can't be adapted and are reported for manual review. The files are
+/// restored if the synced package fails to evaluate.
+#[allow(clippy::too_many_arguments)]
+pub async fn sync_from_nixpkgs(
+    file: String,
+    attr_path: String,
+    nixpkgs: Option<String>,
+    commit: bool,
+    create_pr: bool,
+    upstream: Option<String>,
+    fork: String,
+    allow_dirty: bool,
+    config: Config,
+) -> anyhow::Result<()> {
+    let nixpkgs = nixpkgs
+        .or_else(|| config.nixpkgs.clone())
+        .context("No nixpkgs to sync from, pass --nixpkgs or set `nixpkgs` in the config file")?;
+
+    let metadata = PackageMetadata::from_attr_path(&file, &attr_path).await?;
+    let nixpkgs_version = nixpkgs::nixpkgs_version(&nixpkgs, &attr_path)
+        .await
+        .with_context(|| format!("{} fails to evaluate in nixpkgs", attr_path))?
+        .with_context(|| format!("{} has no version in nixpkgs", attr_path))?;
+    if nixpkgs_version == metadata.version {
+        info!(
+            "{} is already at the nixpkgs version {}",
+            attr_path, metadata.version
+        );
+        return Ok(());
+    }
+
+    let source_file = nixpkgs::package_file(&nixpkgs, &attr_path).await?;
+    let target_file = PathBuf::from(get_file_location(&file, &attr_path).await?);
+    let source_dir = source_file.parent().unwrap_or(Path::new("."));
+    let target_dir = target_file.parent().unwrap_or(Path::new("."));
+
+    // Don't mix the synced expression into uncommitted work on the package
+    if !allow_dirty {
+        let dirty = uncommitted_changes(target_dir).await?;
+        if !dirty.is_empty() {
+            anyhow::bail!(
+                "{} has uncommitted changes ({}), commit or stash them or pass --allow-dirty",
+                target_dir.display(),
+                dirty.join(", ")
+            );
+        }
+    }
+
+    info!(
+        "Syncing {} {} -> {} from {}",
+        attr_path,
+        metadata.version,
+        nixpkgs_version,
+        source_file.display()
+    );
+
+    let content = tokio::fs::read_to_string(&source_file)
+        .await
+        .with_context(|| format!("Failed to read {}", source_file.display()))?;
+
+    // Every file written, with its previous content, to restore them if the sync fails
+    let mut staged = StagedFiles::default();
+    let mut copied = Vec::new();
+    for reference in relative_references(&content) {
+        let Some(relative) = resolve_reference(reference) else {
+            warn!(
+                "{} refers to {} outside of its directory, adapt it manually",
+                attr_path, reference
+            );
+            continue;
+        };
+        let source = source_dir.join(&relative);
+        if !source.is_file() {
+            if source.exists() {
+                warn!(
+                    "{} refers to the directory {}, sync it manually",
+                    attr_path, reference
+                );
+            }
+            continue;
+        }
+        let target = target_dir.join(&relative);
+        let copy = async {
+            let source_content = tokio::fs::read(&source)
+                .await
+                .with_context(|| format!("Failed to copy {}", source.display()))?;
+            staged.write(&target, &source_content).await
+        };
+        if let Err(e) = copy.await {
+            staged.restore().await;
+            return Err(e);
+        }
+        copied.push(relative.display().to_string());
+    }
+
+    if let Err(e) = staged.write(&target_file, content.as_bytes()).await {
+        staged.restore().await;
+        return Err(e);
+    }
+    info!(
+        "Wrote {}, copied {} referenced files",
+        target_file.display(),
+        copied.len()
+    );
+
+    // The synced expression may depend on attributes the tree doesn't have
+    let synced = match PackageMetadata::from_attr_path(&file, &attr_path).await {
+        Ok(synced) => synced,
+        Err(e) => {
+            staged.restore().await;
+            return Err(e.context(
+                "The synced package fails to evaluate, restored the previous files, adapt it \
+                 manually",
+            ));
+        },
+    };
+    if synced.version != nixpkgs_version {
+        warn!(
+            "{} evaluates to version {} after syncing, expected {}",
+            attr_path, synced.version, nixpkgs_version
+        );
+    }
+
+    let trailers = update_trailers(&[(&attr_path, &metadata.version, &synced.version)]);
+    let commit_message = format!(
+        "Update {} from {} to {}\n\nSynced from nixpkgs.",
+        attr_path, metadata.version, synced.version
+    );
+    if create_pr {
+        let outcome = UpdateOutcome {
+            nixpkgs_sync: Some(sync_report(&copied)),
+            ..Default::default()
+        };
+        let options = UpdateOptions {
+            create_pr,
+            upstream,
+            fork,
+            config,
+            ..Default::default()
+        };
+        create_update_pr(
+            &attr_path,
+            &metadata.version,
+            &synced.version,
+            Some(&commit_message),
+            &trailers,
+            &outcome,
+            &synced,
+            &options,
+        )
+        .await?;
+    } else if commit {
+        create_git_commit(&format!("{}\n\n{}", commit_message, trailers)).await?;
+    }
+
+    Ok(())
+}
+
+/// Files written by a sync, with their content before it, None for new files
+#[derive(Default)]
+struct StagedFiles {
+    files: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
+
+impl StagedFiles {
+    /// Write `content` to `path`, keeping its previous content
+    async fn write(&mut self, path: &Path, content: &[u8]) -> anyhow::Result<()> {
+        if !self.files.iter().any(|(staged, _)| staged == path) {
+            let previous = tokio::fs::read(path).await.ok();
+            self.files.push((path.to_path_buf(), previous));
+        }
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, content)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Put back the previous content of the written files, removing the new ones
+    async fn restore(self) {
+        for (path, previous) in self.files.into_iter().rev() {
+            let restored = match previous {
+                Some(content) => tokio::fs::write(&path, content).await,
+                None => tokio::fs::remove_file(&path).await,
+            };
+            if let Err(e) = restored {
+                warn!("Failed to restore {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Paths relative to the file in a Nix expression, e.g. `./fix-build.patch`
+fn relative_references(content: &str) -> Vec<&str> {
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    let reference =
+        REFERENCE.get_or_init(|| Regex::new(r"(?:^|[\s\[(=:])(\.\.?/[A-Za-z0-9._+\-/]*)").unwrap());
+
+    let mut references: Vec<&str> = Vec::new();
+    for captures in reference.captures_iter(content) {
+        let path = captures.get(1).map_or("", |m| m.as_str());
+        let path = path.trim_end_matches(['/', '.']);
+        if !path.is_empty() && !references.contains(&path) {
+            references.push(path);
+        }
+    }
+    references
+}
+
+/// Path of a reference relative to the directory of the file, None if it leaves the directory
+fn resolve_reference(reference: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(reference).components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !path.pop() {
+                    return None;
+                }
+            },
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// Markdown section of the PR body listing the files synced along the expression
+fn sync_report(copied: &[String]) -> String {
+    let mut report = String::from("## nixpkgs\n\nSynced from nixpkgs.");
+    if !copied.is_empty() {
+        report.push_str("\n\nCopied files:\n");
+        for file in copied {
+            report.push_str(&format!("\n- `{}`", file));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_references() {
+        let content = r#"{ stdenv, fetchurl }:
+stdenv.mkDerivation {
+  patches = [ ./fix-build.patch ./patches/musl.patch ];
+  setupHook = ./setup-hook.sh;
+  src = ./.;
+  common = import ../common.nix;
+  passthru.tests = callPackage ./tests/ { };
+  homepage = "https://example.org/./not-a-path";
+}"#;
+        assert_eq!(
+            relative_references(content),
+            vec![
+                "./fix-build.patch",
+                "./patches/musl.patch",
+                "./setup-hook.sh",
+                "../common.nix",
+                "./tests"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_reference() {
+        assert_eq!(
+            resolve_reference("./patches/musl.patch"),
+            Some(PathBuf::from("patches/musl.patch"))
+        );
+        assert_eq!(
+            resolve_reference("./patches/../fix.patch"),
+            Some(PathBuf::from("fix.patch"))
+        );
+        assert_eq!(resolve_reference("../common.nix"), None);
+        assert_eq!(resolve_reference("./."), None);
+    }
+}
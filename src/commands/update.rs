@@ -1,76 +1,82 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::time::Duration;
 
 use anyhow::Context;
 use regex::Regex;
 use tokio::process::Command;
 use tracing::{debug, info, warn};
 
-use crate::git::get_pr_config_from_git;
-use crate::github;
+use crate::commands::fix_fake_hashes::{count_fake_hashes, repair_fake_hashes};
+use crate::commands::run::{directive_strategy, plugin_source_for};
+use crate::config::Config;
+use crate::git::{
+    ChangesSnapshot, CommitStep, checkout_branch, commit_steps, get_current_branch,
+    get_pr_config_from_git, git_commit_command, uncommitted_changes, update_trailers,
+};
+use crate::hooks::{HookContext, HookStage, run_hook};
+use crate::http::{self, Throttled};
+use crate::nix::passthru_tests::{self, PassthruTestResult};
+use crate::nix::systems::{SystemBuildResult, build_for_systems, format_system_report};
 use crate::nix::{
-    eval_nix_expr, has_passthru_tests, is_many_variants_package, normalize_entry_point,
+    BuildOptions, build_nix_expr, entry_point_dir, eval_nix_expr, import_entry_point,
+    is_many_variants_package, normalize_entry_point,
+};
+use crate::package::{DependencyHashAttr, PackageMetadata, PackageQuery, PlatformSource};
+use crate::patches::{
+    self, PatchRebase, detect_failed_patch, detect_hash_mismatch, format_rebase_report,
+    local_patch_path,
+};
+use crate::pypi::{
+    DependencyDelta, PythonRequirements, fetch_pypi_releases, fetch_requires_dist,
+    sha256_hex_to_sri,
 };
-use crate::package::PackageMetadata;
 use crate::rewrite::{
-    find_and_update_attr, is_patches_array_empty, remove_patch_from_array, remove_patches_attribute,
+    SidecarFormat, cargo_output_hashes, fetched_patch_hash, find_and_update_attr,
+    find_and_update_version, find_cargo_lock_file, find_sidecar_files, find_version_files,
+    is_patches_array_empty, remove_patch_from_array, remove_patches_attribute, replace_version,
+    set_cargo_output_hashes, update_call_package_arg, update_changelog_version,
+    update_sidecar_attr, update_version_file,
+};
+use crate::timings::{PhaseTimings, UpdatePhase};
+use crate::update_script::{ScriptCommit, UpdateScriptResult, run_update_script};
+use crate::vcs_sources::{Release, SemverStrategy, UpstreamSource};
+use crate::verification::{
+    CheckResult, ProvenanceCheck, ProvenanceOrigin, VerificationCheck, format_provenance_report,
+    format_verification_report, verify_provenance, verify_source,
 };
-use crate::vcs_sources::{SemverStrategy, UpstreamSource};
+use crate::withdrawn::query_withdrawn_versions;
+use crate::{cargo, github, nixpkgs};
 
-/// Check for and run update script if it exists
-///
-/// Returns Ok(true) if update script was found and executed successfully,
-/// Ok(false) if no update script exists, or Err if execution failed.
-async fn run_update_script(file: &str, attr_path: &str) -> anyhow::Result<bool> {
-    info!("Checking for update script for {}", attr_path);
-
-    // Check if an update script is defined for this package
-    let normalized_entry = normalize_entry_point(file);
-    let nix_expr = format!(
-        "with import {} {{ }}; toString {}.updateScript",
-        normalized_entry, attr_path
-    );
+/// Placeholder hash used to provoke a hash mismatch from Nix
+const FAKE_HASH: &str = "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
 
-    let script_path_result = eval_nix_expr(&nix_expr).await;
+/// Attribute names which may hold the hash of a source
+const SRC_HASH_ATTRS: &[&str] = &["hash", "sha256", "outputHash", "src-hash"];
 
-    // If update script exists, use it
-    match script_path_result {
-        Ok(script_path) if !script_path.is_empty() => {
-            info!("Found update script: {}", script_path);
+/// Files of a package directory the old version may be replaced in, more is likely a mistake
+const MAX_VERSION_OCCURRENCE_FILES: usize = 20;
 
-            // Execute the update script
-            debug!("Executing update script...");
-            let status = Command::new(&script_path)
-                .stdin(std::process::Stdio::inherit())
-                .stdout(std::process::Stdio::inherit())
-                .stderr(std::process::Stdio::inherit())
-                .status()
-                .await?;
+/// Size of the largest file searched for occurrences of the old version
+const MAX_VERSION_OCCURRENCE_FILE_SIZE: u64 = 1024 * 1024;
 
-            if !status.success() {
-                anyhow::bail!(
-                    "Update script failed with exit code: {}",
-                    status.code().unwrap_or(-1)
-                );
-            }
+/// Files defining the package of their directory
+const PACKAGE_FILE_NAMES: &[&str] = &["default.nix", "package.nix"];
 
-            info!("Update script completed successfully for {}", attr_path);
-            Ok(true)
-        },
-        Ok(_) => {
-            debug!("Update script path is empty");
-            Ok(false)
-        },
-        Err(e) => {
-            debug!("No update script found for {}", attr_path);
-            debug!("nix-instantiate stderr: {}", e);
-            Ok(false)
-        },
-    }
-}
+/// Lockfiles not ending in `.lock`, which are generated rather than edited
+const LOCK_FILE_NAMES: &[&str] = &[
+    "package-lock.json",
+    "npm-shrinkwrap.json",
+    "pnpm-lock.yaml",
+    "go.sum",
+];
 
+#[allow(clippy::too_many_arguments)]
 pub async fn update(
     file: String,
-    attr_path: String,
+    mut attr_paths: Vec<String>,
+    attrs_file: Option<String>,
     semver_strategy: String,
     ignore_update_script: bool,
     commit: bool,
@@ -78,15 +84,291 @@ pub async fn update(
     upstream: Option<String>,
     fork: String,
     run_passthru_tests: bool,
+    passthru_test_timeout: u64,
+    build_options: BuildOptions,
+    verify_systems: Vec<String>,
+    verify_cross_systems: Vec<String>,
+    dependency_hash_attrs: Vec<String>,
+    formatter: Option<String>,
+    maintainer_opt_out: Vec<String>,
+    allow_dirty: bool,
+    split_commits: bool,
+    single_commit: bool,
+    config: Config,
 ) -> anyhow::Result<()> {
+    if let Some(ref attrs_file) = attrs_file {
+        let content = tokio::fs::read_to_string(attrs_file)
+            .await
+            .with_context(|| format!("Failed to read {}", attrs_file))?;
+        attr_paths.extend(parse_attrs_file(&content));
+    }
+    let mut seen = HashSet::new();
+    attr_paths.retain(|attr_path| seen.insert(attr_path.clone()));
+    if attr_paths.is_empty() {
+        anyhow::bail!("No package to update");
+    }
+
     // Parse semver strategy
-    let strategy = SemverStrategy::from_str(&semver_strategy)?;
+    let strategy: SemverStrategy = semver_strategy.parse()?;
     info!("Using semver strategy: {:?}", strategy);
 
+    // The updates of several packages are committed together once they're all done
+    let single_commit = single_commit && commit && attr_paths.len() > 1;
+    let options = UpdateOptions {
+        strategy,
+        commit: commit && !single_commit,
+        create_pr,
+        upstream,
+        fork,
+        run_passthru_tests,
+        passthru_test_timeout: Duration::from_secs(passthru_test_timeout),
+        build_options,
+        verify_systems,
+        verify_cross_systems,
+        fail_on_test_failure: false, // Don't fail on test errors for update command
+        dependency_hash_attrs: parse_dependency_hash_attrs(&dependency_hash_attrs)?,
+        formatter,
+        maintainer_opt_out,
+        split_commits,
+        config,
+    };
+
+    // Evaluate the packages together and find their files via meta.position, packages which fail
+    // to evaluate are evaluated again one by one
+    debug!("Attempting to locate package definitions...");
+    let mut metadata =
+        PackageMetadata::from_attr_paths(&file, &attr_paths, &options.dependency_hash_attrs).await;
+    let before: HashMap<String, String> = metadata
+        .iter()
+        .map(|(attr_path, metadata)| (attr_path.clone(), metadata.version.clone()))
+        .collect();
+    let mut file_locations = HashMap::new();
+    for attr_path in &attr_paths {
+        let file_location = match metadata.get(attr_path).and_then(PackageMetadata::file) {
+            Some(file_location) => Ok(file_location.to_string()),
+            None => crate::commands::run::get_file_location(&file, attr_path).await,
+        };
+        file_locations.insert(attr_path.as_str(), file_location);
+    }
+
+    // Don't mix the rewrites into uncommitted work on the packages. Only the file of a package
+    // sharing its directory with others is rewritten, the other files are none of its business.
+    if !allow_dirty {
+        for file_location in file_locations.values() {
+            let Some(package_file) = file_location.as_ref().ok().map(Path::new) else {
+                continue;
+            };
+            let target = match package_file.parent() {
+                Some(package_dir) if owns_directory(package_file) => package_dir,
+                _ => package_file,
+            };
+            let dirty = uncommitted_changes(target).await?;
+            if !dirty.is_empty() {
+                anyhow::bail!(
+                    "{} has uncommitted changes ({}), commit or stash them or pass --allow-dirty",
+                    target.display(),
+                    dirty.join(", ")
+                );
+            }
+        }
+    }
+
+    if let [attr_path] = attr_paths.as_slice() {
+        let file_location = file_locations.remove(attr_path.as_str()).unwrap();
+        update_package(
+            &file,
+            attr_path,
+            file_location,
+            metadata.remove(attr_path.as_str()),
+            ignore_update_script,
+            &options,
+        )
+        .await?;
+        return Ok(());
+    }
+
+    // Pull requests are branched off the current branch, which has to be checked out again for
+    // the next package
+    let base_branch = if create_pr {
+        Some(get_current_branch().await?)
+    } else {
+        None
+    };
+
+    // Update every package even if one fails, putting the files back as they were before a
+    // failed update so its rewrites don't end up in the commits of the next ones
+    let mut updated = Vec::new();
+    let mut failed = Vec::new();
+    let mut updated_files = HashSet::new();
+    for (i, attr_path) in attr_paths.iter().enumerate() {
+        info!("[{}/{}] Updating {}", i + 1, attr_paths.len(), attr_path);
+        let snapshot = ChangesSnapshot::take().await?;
+        let file_location = file_locations.remove(attr_path.as_str()).unwrap();
+        // Packages defined in the same file as one updated before are evaluated again
+        let defined_in = file_location.as_ref().ok().cloned();
+        let evaluated = metadata.remove(attr_path).filter(|_| {
+            defined_in
+                .as_ref()
+                .is_some_and(|f| !updated_files.contains(f))
+        });
+        match update_package(
+            &file,
+            attr_path,
+            file_location,
+            evaluated,
+            ignore_update_script,
+            &options,
+        )
+        .await
+        {
+            Ok(()) => {
+                updated.push(attr_path.clone());
+                updated_files.extend(defined_in);
+            },
+            Err(e) => {
+                warn!("{}: Update failed: {:#}", attr_path, e);
+                if let Err(e) = snapshot.restore().await {
+                    warn!("{}: Failed to discard the rewrites: {}", attr_path, e);
+                }
+                failed.push(attr_path.clone());
+            },
+        }
+        if let Some(ref base_branch) = base_branch {
+            checkout_branch(base_branch).await?;
+        }
+    }
+
+    if single_commit && !updated.is_empty() {
+        commit_updates(&file, &updated, &before).await?;
+    }
+
+    info!("Updated {} of {} packages", updated.len(), attr_paths.len());
+    if !failed.is_empty() {
+        anyhow::bail!("Failed to update {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Parse a list of attribute paths, one per line, ignoring blank lines and `#` comments
+pub fn parse_attrs_file(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Nix expression evaluating the version of several packages to JSON
+fn package_versions_expr(eval_entry_point: &str, attr_paths: &[String]) -> String {
+    let packages: Vec<String> = attr_paths
+        .iter()
+        .map(|attr_path| {
+            format!(
+                "{:?} = get (pkgs.{attr}.version or (builtins.parseDrvName (pkgs.{attr}.name or \
+                 \"\")).version);",
+                attr_path,
+                attr = attr_path
+            )
+        })
+        .collect();
+    format!(
+        "let pkgs = {}; get = value: let result = builtins.tryEval value; in if result.success && \
+         result.value != \"\" then result.value else null; in builtins.toJSON {{ {} }}",
+        import_entry_point(eval_entry_point),
+        packages.join(" ")
+    )
+}
+
+/// Evaluate the version of several packages at once
+///
+/// Packages are missing from the result if their version failed to evaluate.
+async fn eval_package_versions(
+    eval_entry_point: &str,
+    attr_paths: &[String],
+) -> HashMap<String, String> {
+    let expr = package_versions_expr(eval_entry_point, attr_paths);
+    match eval_nix_expr(&expr).await.and_then(|json| {
+        Ok(serde_json::from_str::<HashMap<String, Option<String>>>(
+            &json,
+        )?)
+    }) {
+        Ok(versions) => versions
+            .into_iter()
+            .filter_map(|(attr_path, version)| Some((attr_path, version?)))
+            .collect(),
+        Err(e) => {
+            debug!("Failed to evaluate the packages together: {}", e);
+            HashMap::new()
+        },
+    }
+}
+
+/// Commit the updates of several packages in a single commit
+///
+/// `before` holds the versions of the packages before the update, their new versions are
+/// evaluated again.
+async fn commit_updates(
+    eval_entry_point: &str,
+    attr_paths: &[String],
+    before: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let after = eval_package_versions(eval_entry_point, attr_paths).await;
+    let changes: Vec<(&str, &str, &str)> = attr_paths
+        .iter()
+        .filter_map(|attr_path| {
+            let old_version = before.get(attr_path)?.as_str();
+            let new_version = after.get(attr_path)?.as_str();
+            Some((attr_path.as_str(), old_version, new_version))
+        })
+        .collect();
+
+    let commit_message = match changes.as_slice() {
+        [(attr_path, old_version, new_version)] => {
+            update_commit_message(attr_path, old_version, new_version, &[])
+        },
+        _ => format!(
+            "Update {} packages\n\n{}\n\n{}",
+            attr_paths.len(),
+            changes
+                .iter()
+                .map(|(attr_path, old_version, new_version)| format!(
+                    "- {}: {} -> {}",
+                    attr_path, old_version, new_version
+                ))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            update_trailers(&changes)
+        ),
+    };
+    create_git_commit(&commit_message).await
+}
+
+/// Update a single package, with its update script unless `ignore_update_script` is set
+async fn update_package(
+    file: &str,
+    attr_path: &str,
+    expr_file_path: anyhow::Result<String>,
+    metadata: Option<PackageMetadata>,
+    ignore_update_script: bool,
+    options: &UpdateOptions,
+) -> anyhow::Result<()> {
     // Try to run update script if not ignored
     if !ignore_update_script {
-        let script_executed = run_update_script(&file, &attr_path).await?;
-        if script_executed {
+        if let Some(script_result) =
+            run_update_script(file, attr_path, None, options.build_timeout(attr_path)).await?
+        {
+            for line in script_result
+                .stdout
+                .lines()
+                .chain(script_result.stderr.lines())
+            {
+                info!("[updateScript] {}", line);
+            }
+            script_result.check()?;
+            if options.commit || options.create_pr {
+                commit_script_update(file, attr_path, script_result, options).await?;
+            }
             return Ok(());
         }
     } else {
@@ -94,36 +376,13 @@ pub async fn update(
     }
 
     // No update script or ignoring it - use generic update method
-    // Try to find the package file location via meta.position
-    debug!("Attempting to locate package definition...");
-    let normalized_entry = normalize_entry_point(&file);
-    let position_expr = format!(
-        "with import {} {{ }}; {}.meta.position",
-        normalized_entry, attr_path
-    );
-
-    let expr_file_path = eval_nix_expr(&position_expr).await.and_then(|position| {
-        if position.is_empty() {
-            anyhow::bail!("Empty position returned from meta.position");
-        }
-        // Parse position string (format: "file:line")
-        let (file_path, _line_str) = position
-            .rsplit_once(':')
-            .ok_or_else(|| anyhow::anyhow!("Unexpected position format: {}", position))?;
-        Ok(file_path.to_string())
-    })?;
-
     update_from_file_path(
-        file,
-        attr_path,
-        expr_file_path,
-        strategy,
-        commit,
-        create_pr,
-        upstream,
-        fork,
-        run_passthru_tests,
-        false, // Don't fail on test errors for update command
+        file.to_string(),
+        attr_path.to_string(),
+        expr_file_path?,
+        metadata,
+        options,
+        &mut PhaseTimings::default(),
     )
     .await?;
 
@@ -200,9 +459,288 @@ async fn find_version_in_siblings(
     Ok(None)
 }
 
+/// Update an attribute in the content of a Nix file or of a JSON/TOML sidecar file
+fn update_attr_in_content(
+    file_path: &str,
+    content: &str,
+    attr_name: &str,
+    new_value: &str,
+    old_value: &str,
+) -> anyhow::Result<String> {
+    match SidecarFormat::from_path(file_path) {
+        Some(format) => update_sidecar_attr(content, format, attr_name, new_value, Some(old_value)),
+        None => find_and_update_attr(content, attr_name, new_value, Some(old_value)),
+    }
+}
+
+/// Find the sidecar file (e.g. `sources.json`) read by a Nix file which pins `old_version`
+///
+/// Returns the sidecar path and its content with the version updated.
+async fn update_version_in_sidecar(
+    file_path: &str,
+    content: &str,
+    old_version: &str,
+    new_version: &str,
+) -> anyhow::Result<Option<(String, String)>> {
+    let dir = std::path::Path::new(file_path)
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new("."));
+
+    for sidecar in find_sidecar_files(content) {
+        let sidecar_path = dir.join(&sidecar).to_string_lossy().to_string();
+        let Some(format) = SidecarFormat::from_path(&sidecar_path) else {
+            continue;
+        };
+        let Ok(sidecar_content) = tokio::fs::read_to_string(&sidecar_path).await else {
+            debug!("Could not read sidecar file {}", sidecar_path);
+            continue;
+        };
+
+        if let Ok(updated) = update_sidecar_attr(
+            &sidecar_content,
+            format,
+            "version",
+            new_version,
+            Some(old_version),
+        ) {
+            return Ok(Some((sidecar_path, updated)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether a version is unlikely to appear in a file by coincidence, e.g. `2.12` but not `24`
+fn is_distinctive_version(version: &str) -> bool {
+    version.len() >= 4 && version.contains(|c: char| !c.is_ascii_digit())
+}
+
+/// Whether the package defined in `file` has its directory to itself, which is the case for
+/// packages defined in a `default.nix` or `package.nix`, possibly with a sidecar file next to it
+fn owns_directory(file: &Path) -> bool {
+    let Some(name) = file.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    if PACKAGE_FILE_NAMES.contains(&name) {
+        return true;
+    }
+    SidecarFormat::from_path(name).is_some()
+        && file
+            .parent()
+            .is_some_and(|dir| PACKAGE_FILE_NAMES.iter().any(|n| dir.join(n).is_file()))
+}
+
+/// Whether the version in a file must be left alone, patches apply to the old source and
+/// lockfiles are generated
+fn keeps_old_version(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    LOCK_FILE_NAMES.contains(&name.as_ref())
+        || path
+            .extension()
+            .is_some_and(|ext| ext == "patch" || ext == "diff" || ext == "lock")
+}
+
+/// Replace the old version in the other text files of the directory of a package
+///
+/// `rewritten` lists the files already updated, the first one defining the package. Returns the
+/// files rewritten. Nothing is rewritten if the package shares its directory, e.g. with the
+/// other packages of `pkgs/tools/misc`, the version could appear by coincidence or too many
+/// files contain it. Patches and lockfiles are skipped.
+async fn update_version_occurrences(
+    eval_entry_point: &str,
+    attr_path: &str,
+    rewritten: &[&str],
+    old_version: &str,
+    new_version: &str,
+) -> anyhow::Result<Vec<String>> {
+    let Some(package_file) = rewritten.first().map(Path::new) else {
+        return Ok(Vec::new());
+    };
+    if !owns_directory(package_file) {
+        warn!(
+            "{}: Not replacing version occurrences, {} shares its directory with other packages",
+            attr_path,
+            package_file.display()
+        );
+        return Ok(Vec::new());
+    }
+    let Some(package_dir) = package_file
+        .parent()
+        .and_then(|dir| std::fs::canonicalize(dir).ok())
+    else {
+        return Ok(Vec::new());
+    };
+    if std::fs::canonicalize(entry_point_dir(eval_entry_point))
+        .is_ok_and(|root| root == package_dir)
+    {
+        warn!(
+            "{}: Not replacing version occurrences in the root of the tree",
+            attr_path
+        );
+        return Ok(Vec::new());
+    }
+    if !is_distinctive_version(old_version) {
+        warn!(
+            "{}: Not replacing occurrences of version {}, it may appear by coincidence",
+            attr_path, old_version
+        );
+        return Ok(Vec::new());
+    }
+    let rewritten: Vec<PathBuf> = rewritten
+        .iter()
+        .filter_map(|file| std::fs::canonicalize(file).ok())
+        .collect();
+
+    let mut updates = Vec::new();
+    for entry in walkdir::WalkDir::new(&package_dir)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        if !entry.file_type().is_file()
+            || keeps_old_version(path)
+            || rewritten.iter().any(|file| file == path)
+            || entry
+                .metadata()
+                .is_ok_and(|m| m.len() > MAX_VERSION_OCCURRENCE_FILE_SIZE)
+        {
+            continue;
+        }
+        // Binary files aren't valid UTF-8
+        let Ok(content) = tokio::fs::read_to_string(path).await else {
+            continue;
+        };
+        let updated = replace_version(&content, old_version, new_version);
+        if updated != content {
+            updates.push((path.to_path_buf(), updated));
+        }
+    }
+
+    if updates.len() > MAX_VERSION_OCCURRENCE_FILES {
+        warn!(
+            "{}: Version {} appears in {} files of {}, not replacing it",
+            attr_path,
+            old_version,
+            updates.len(),
+            package_dir.display()
+        );
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for (path, updated) in updates {
+        tokio::fs::write(&path, updated).await?;
+        info!("{}: Replaced version in {}", attr_path, path.display());
+        files.push(path.to_string_lossy().to_string());
+    }
+    Ok(files)
+}
+
+/// Find the plain text file, e.g. `./version`, a Nix file reads `old_version` from
+///
+/// Returns the path of the version file and its content with the version updated.
+async fn update_version_in_version_file(
+    file_path: &str,
+    content: &str,
+    old_version: &str,
+    new_version: &str,
+) -> Option<(String, String)> {
+    let dir = Path::new(file_path).parent().unwrap_or(Path::new("."));
+
+    for version_file in find_version_files(content) {
+        let version_path = dir.join(&version_file).to_string_lossy().to_string();
+        let Ok(version_content) = tokio::fs::read_to_string(&version_path).await else {
+            debug!("Could not read version file {}", version_path);
+            continue;
+        };
+        if let Some(updated) = update_version_file(&version_content, old_version, new_version) {
+            return Some((version_path, updated));
+        }
+    }
+
+    None
+}
+
+/// Find the file passing `old_version` to the `callPackage` call of a package's file
+///
+/// Only applies if the package's function takes `version` as an argument. The `.nix` files
+/// under the entry point are searched for a single `callPackage` call of `file_path` (or its
+/// directory, for a `default.nix`) with `version = old_version`. Returns the caller path and its
+/// content with the version updated.
+async fn update_version_in_caller(
+    eval_entry_point: &str,
+    file_path: &str,
+    old_version: &str,
+    new_version: &str,
+) -> anyhow::Result<Option<(String, String)>> {
+    let takes_version = eval_nix_expr(&format!(
+        "if (builtins.functionArgs (import {})) ? version then \"1\" else \"\"",
+        normalize_entry_point(file_path)
+    ))
+    .await
+    .unwrap_or_default();
+    if takes_version.is_empty() {
+        return Ok(None);
+    }
+
+    let Ok(target) = std::fs::canonicalize(file_path) else {
+        return Ok(None);
+    };
+    let target_dir = target
+        .parent()
+        .filter(|_| target.file_name().is_some_and(|name| name == "default.nix"));
+
+    let mut callers = Vec::new();
+    for entry in walkdir::WalkDir::new(entry_point_dir(eval_entry_point))
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        if !path.is_file() || path.extension().and_then(|s| s.to_str()) != Some("nix") {
+            continue;
+        }
+        let Ok(content) = tokio::fs::read_to_string(path).await else {
+            continue;
+        };
+        if !content.contains("callPackage") || !content.contains(old_version) {
+            continue;
+        }
+
+        let caller_dir = path.parent().unwrap_or(Path::new("."));
+        let is_target = |called: &str| {
+            std::fs::canonicalize(caller_dir.join(called))
+                .is_ok_and(|called| called == target || Some(called.as_path()) == target_dir)
+        };
+        if let Ok(updated) =
+            update_call_package_arg(&content, is_target, "version", new_version, old_version)
+        {
+            callers.push((path.to_string_lossy().to_string(), updated));
+        }
+    }
+
+    match callers.len() {
+        0 | 1 => Ok(callers.pop()),
+        n => anyhow::bail!(
+            "Version {} of {} is passed by {} callPackage calls",
+            old_version,
+            file_path,
+            n
+        ),
+    }
+}
+
 /// Update version and hash attributes in Nix file using AST manipulation
 ///
-/// Returns the actual file path that was updated (may differ from input due to mkManyVariants)
+/// Returns the actual file path that was updated (may differ from input due to mkManyVariants
+/// or versions pinned in a JSON/TOML sidecar file). A version passed through `callPackage`
+/// arguments is updated in the calling file, while the hash is still updated in `file_path`.
 async fn update_nix_file(
     eval_entry_point: &str,
     attr_path: &str,
@@ -216,17 +754,45 @@ async fn update_nix_file(
     let content = tokio::fs::read_to_string(file_path).await?;
 
     // Try to update the version attribute
-    let (updated_content, actual_file_path) =
-        match find_and_update_attr(&content, "version", new_version, Some(old_version)) {
-            Ok(content) => {
-                debug!(
-                    "Updated version attribute: {} -> {}",
-                    old_version, new_version
+    let version_result = match SidecarFormat::from_path(file_path) {
+        Some(format) => {
+            update_sidecar_attr(&content, format, "version", new_version, Some(old_version))
+        },
+        None => find_and_update_version(&content, new_version, old_version),
+    };
+    let (updated_content, actual_file_path, is_many_variants) = match version_result {
+        Ok(content) => {
+            debug!(
+                "Updated version attribute: {} -> {}",
+                old_version, new_version
+            );
+            (content, file_path.to_string(), false)
+        },
+        Err(e) if e.to_string().contains("not found") => {
+            // Version not found - check for a JSON/TOML sidecar file holding the pins
+            if let Some((sidecar_path, updated)) =
+                update_version_in_sidecar(file_path, &content, old_version, new_version).await?
+            {
+                info!("Using version pinned in sidecar file: {}", sidecar_path);
+                (updated, sidecar_path, false)
+            } else if let Some((version_file, updated)) =
+                update_version_in_version_file(file_path, &content, old_version, new_version).await
+            {
+                info!("Using version read from: {}", version_file);
+                tokio::fs::write(&version_file, updated).await?;
+                (content, file_path.to_string(), false)
+            } else if let Some((caller_path, updated)) =
+                update_version_in_caller(eval_entry_point, file_path, old_version, new_version)
+                    .await?
+            {
+                info!(
+                    "Using version passed through callPackage in: {}",
+                    caller_path
                 );
-                (content, file_path.to_string())
-            },
-            Err(e) if e.to_string().contains("not found") => {
-                // Version not found - check if this is a mkManyVariants package
+                tokio::fs::write(&caller_path, updated).await?;
+                (content, file_path.to_string(), false)
+            } else {
+                // Check if this is a mkManyVariants package
                 debug!(
                     "Version not found in {}, checking if mkManyVariants",
                     file_path
@@ -241,7 +807,7 @@ async fn update_nix_file(
 
                             // Try simple string replacement for mkManyVariants files
                             let updated = sibling_content.replace(old_version, new_version);
-                            (updated, sibling_path)
+                            (updated, sibling_path, true)
                         },
                         None => {
                             // No sibling found, return original error
@@ -252,15 +818,16 @@ async fn update_nix_file(
                     // Not a mkManyVariants package, return original error
                     return Err(e);
                 }
-            },
-            Err(e) => return Err(e),
-        };
+            }
+        },
+        Err(e) => return Err(e),
+    };
 
     // Update hash if provided
     let final_content = if let (Some(old_h), Some(new_h)) = (old_hash, new_hash) {
         // For mkManyVariants, use simple string replacement
-        // For normal files, use AST-based replacement
-        if actual_file_path != file_path {
+        // For normal and sidecar files, replace the value of a known hash attribute
+        if is_many_variants {
             // mkManyVariants file - use string replacement
             let result = updated_content.replace(old_h, new_h);
             debug!(
@@ -270,12 +837,11 @@ async fn update_nix_file(
             result
         } else {
             // Normal file - try AST-based replacement
-            let hash_attrs = vec!["hash", "sha256", "outputHash", "src-hash"];
             let mut result = updated_content.clone();
             let mut hash_updated = false;
 
-            for attr_name in hash_attrs {
-                match find_and_update_attr(&result, attr_name, new_h, Some(old_h)) {
+            for attr_name in SRC_HASH_ATTRS {
+                match update_attr_in_content(&actual_file_path, &result, attr_name, new_h, old_h) {
                     Ok(new_content) => {
                         debug!("Updated {} attribute: {} -> {}", attr_name, old_h, new_h);
                         result = new_content;
@@ -287,7 +853,10 @@ async fn update_nix_file(
             }
 
             if !hash_updated {
-                warn!("Could not find hash attribute to update in Nix file");
+                warn!(
+                    "Could not find hash attribute to update in {}",
+                    actual_file_path
+                );
             }
 
             result
@@ -301,56 +870,436 @@ async fn update_nix_file(
     Ok(actual_file_path)
 }
 
-/// Update cargoHash attribute in Nix file
-async fn update_cargo_hash(file_path: &str, old_hash: &str, new_hash: &str) -> anyhow::Result<()> {
-    debug!("Updating cargoHash in {} using AST manipulation", file_path);
-    let content = tokio::fs::read_to_string(file_path).await?;
-
-    let updated_content = find_and_update_attr(&content, "cargoHash", new_hash, Some(old_hash))?;
-    debug!("Updated cargoHash attribute: {} -> {}", old_hash, new_hash);
-
-    tokio::fs::write(file_path, updated_content).await?;
-    Ok(())
-}
-
-/// Update vendorHash attribute in Nix file
-async fn update_vendor_hash(file_path: &str, old_hash: &str, new_hash: &str) -> anyhow::Result<()> {
+/// Update a dependency hash attribute in Nix file
+///
+/// Tries each attribute name in turn, only replacing an attribute whose current value is
+/// `old_hash`.
+async fn update_dependency_hash(
+    file_path: &str,
+    attr_names: &[&str],
+    old_hash: &str,
+    new_hash: &str,
+) -> anyhow::Result<()> {
     debug!(
-        "Updating vendorHash in {} using AST manipulation",
+        "Updating dependency hash in {} using AST manipulation",
         file_path
     );
     let content = tokio::fs::read_to_string(file_path).await?;
 
-    let updated_content = find_and_update_attr(&content, "vendorHash", new_hash, Some(old_hash))?;
-    debug!("Updated vendorHash attribute: {} -> {}", old_hash, new_hash);
-
-    tokio::fs::write(file_path, updated_content).await?;
-    Ok(())
-}
+    for attr_name in attr_names {
+        if let Ok(updated_content) =
+            update_attr_in_content(file_path, &content, attr_name, new_hash, old_hash)
+        {
+            debug!(
+                "Updated {} attribute: {} -> {}",
+                attr_name, old_hash, new_hash
+            );
+            tokio::fs::write(file_path, updated_content).await?;
+            return Ok(());
+        }
+    }
 
-/// Extract hash from Nix build error output
-fn extract_hash_from_error(stderr: &str) -> Option<String> {
-    // Nix error format: "got: sha256-<hash>"
-    let hash_regex = Regex::new(r"got:\s+(sha256-[A-Za-z0-9+/=]+)").ok()?;
-    let caps = hash_regex.captures(stderr)?;
-    Some(caps.get(1)?.as_str().to_string())
+    anyhow::bail!(
+        "Attribute '{}' with hash {} not found in Nix file",
+        attr_names.join("' or '"),
+        old_hash
+    )
 }
 
-/// Detect reversed patch errors and extract the patch filename
-///
-/// Looks for "Reversed (or previously applied) patch detected!" in the last 20 lines
-/// and extracts the patch name from the preceding "applying patch" line.
+/// Refresh a dependency FOD hash (cargoHash, vendorHash, pnpmDeps, ...)
 ///
-/// Returns the patch filename to be removed from the patches array.
-fn detect_reversed_patch(stderr: &str) -> Option<String> {
-    // Get last 20 lines of stderr
-    let lines: Vec<&str> = stderr.lines().collect();
-    let start = lines.len().saturating_sub(20);
-    let last_lines = &lines[start..];
-    let patch_regex = Regex::new(r"applying patch /nix/store/[^-]+-(.+)").ok()?;
-
-    // Look for the reversed patch error message
-    for (i, line) in last_lines.iter().enumerate() {
+/// Sets the hash to a placeholder, builds the full package to provoke a hash mismatch and
+/// writes back the hash reported by Nix, which is returned.
+async fn refresh_dependency_hash(
+    eval_entry_point: &str,
+    attr_path: &str,
+    file_path: &str,
+    label: &str,
+    attr_names: &[&str],
+    old_hash: &str,
+    build_options: &BuildOptions,
+) -> anyhow::Result<String> {
+    // Set invalid hash
+    update_dependency_hash(file_path, attr_names, old_hash, FAKE_HASH).await?;
+
+    info!("Set invalid {} in {}", label, file_path);
+
+    // Build full package to get correct hash
+    let (success, _stdout, stderr) =
+        build_nix_expr(eval_entry_point, attr_path, None, build_options).await?;
+
+    if success {
+        warn!(
+            "Build succeeded with invalid {} - this shouldn't happen",
+            label
+        );
+        anyhow::bail!("Expected {} mismatch error but build succeeded", label);
+    }
+
+    let correct_hash = extract_hash_from_error(&stderr).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Could not extract correct {} from build error:\n{}",
+            label,
+            stderr
+        )
+    })?;
+
+    info!("Extracted correct {}: {}", label, correct_hash);
+
+    // Update hash with correct value
+    update_dependency_hash(file_path, attr_names, FAKE_HASH, &correct_hash).await?;
+
+    info!("Updated {} in {}", label, file_path);
+    Ok(correct_hash)
+}
+
+/// Replace the vendored `Cargo.lock` of a Rust package with the lockfile of the new source
+///
+/// The `cargoLock.outputHashes` of git dependencies whose revision changed are refreshed by
+/// building the package. Returns the files which changed.
+async fn update_cargo_lock(
+    eval_entry_point: &str,
+    attr_path: &str,
+    nix_file_location: &str,
+    lock_path: &str,
+    src_out_path: Option<&str>,
+    build_options: &BuildOptions,
+) -> anyhow::Result<Vec<String>> {
+    let nix_file = Path::new(nix_file_location);
+    let lock_file = nix_file.parent().unwrap_or(Path::new(".")).join(lock_path);
+    let src = src_out_path.ok_or_else(|| anyhow::anyhow!("source path is unknown"))?;
+    let cargo_root = PackageQuery::new(eval_entry_point, attr_path)
+        .get_attr("cargoRoot")
+        .await
+        .filter(|root| !root.is_empty());
+
+    let old_lock = tokio::fs::read_to_string(&lock_file)
+        .await
+        .with_context(|| format!("Failed to read {}", lock_file.display()))?;
+    let new_lock = cargo::lock_file_of(Path::new(src), cargo_root.as_deref()).await?;
+    if new_lock == old_lock {
+        debug!("{} is up to date", lock_file.display());
+        return Ok(Vec::new());
+    }
+    tokio::fs::write(&lock_file, &new_lock).await?;
+    info!("Updated {}", lock_file.display());
+    let mut changed_files = vec![lock_file.display().to_string()];
+
+    let content = tokio::fs::read_to_string(nix_file).await?;
+    let current = cargo_output_hashes(&content);
+    let hashes = cargo::plan_output_hashes(
+        &current,
+        &cargo::git_dependencies(&old_lock)?,
+        &cargo::git_dependencies(&new_lock)?,
+        FAKE_HASH,
+    );
+    if hashes != current {
+        tokio::fs::write(nix_file, set_cargo_output_hashes(&content, &hashes)?).await?;
+        let repaired =
+            repair_fake_hashes(eval_entry_point, attr_path, nix_file, build_options).await?;
+        info!(
+            "Updated cargoLock.outputHashes, {} git dependencies rehashed",
+            repaired
+        );
+        changed_files.push(nix_file_location.to_string());
+    }
+    Ok(changed_files)
+}
+
+/// Update the hash of a fetched patch whose content changed upstream
+///
+/// Returns the name of the patch if the build failed on the hash of one of the `fetchpatch`
+/// entries of the Nix file. Fails for the patches of `refreshed`, whose hash was already
+/// refreshed: a patch changing on every fetch would be refreshed forever.
+async fn refresh_changed_patch_hash(
+    nix_file_location: &str,
+    stderr: &str,
+    refreshed: &[String],
+) -> anyhow::Result<Option<String>> {
+    let Some((patch_name, new_hash)) = detect_hash_mismatch(stderr) else {
+        return Ok(None);
+    };
+    if refreshed.contains(&patch_name) {
+        anyhow::bail!(
+            "Package build failed after update. The hash of patch {} changed again after \
+             refreshing it\n{}",
+            patch_name,
+            stderr
+        );
+    }
+    let content = tokio::fs::read_to_string(nix_file_location).await?;
+    let Some(old_hash) = fetched_patch_hash(&content, &patch_name) else {
+        return Ok(None);
+    };
+
+    update_dependency_hash(nix_file_location, &["hash", "sha256"], &old_hash, &new_hash)
+        .await
+        .with_context(|| format!("Failed to update the hash of patch {}", patch_name))?;
+    info!(
+        "Patch {} changed upstream, updated its hash to {}",
+        patch_name, new_hash
+    );
+    Ok(Some(patch_name))
+}
+
+/// Rebase a patch which fails to apply to the new source
+///
+/// Fetched patches get their hash refreshed, in case upstream updated them, and local patch files
+/// are regenerated against the new source `src_out_path`. Returns the description of the change,
+/// the file it touched and how the patch was rebased.
+async fn rebase_failed_patch(
+    eval_entry_point: &str,
+    attr_path: &str,
+    nix_file_location: &str,
+    patch_name: &str,
+    src_out_path: Option<&str>,
+    build_options: &BuildOptions,
+) -> anyhow::Result<(String, String, PatchRebase)> {
+    let content = tokio::fs::read_to_string(nix_file_location).await?;
+
+    if let Some(old_hash) = fetched_patch_hash(&content, patch_name) {
+        let new_hash = refresh_dependency_hash(
+            eval_entry_point,
+            attr_path,
+            nix_file_location,
+            &format!("hash of {}", patch_name),
+            &["hash", "sha256"],
+            &old_hash,
+            build_options,
+        )
+        .await?;
+        if new_hash == old_hash {
+            anyhow::bail!("upstream still serves the same patch");
+        }
+        return Ok((
+            format!("refresh hash of patch {}", patch_name),
+            nix_file_location.to_string(),
+            PatchRebase::RefreshedHash,
+        ));
+    }
+
+    let patch_path = local_patch_path(Path::new(nix_file_location), &content, patch_name)
+        .ok_or_else(|| anyhow::anyhow!("patch is neither fetched nor a local file"))?;
+    let src = src_out_path.ok_or_else(|| anyhow::anyhow!("source path is unknown"))?;
+    let rebase = patches::rebase_patch(Path::new(src), &patch_path).await?;
+    Ok((
+        format!("rebase patch {}", patch_name),
+        patch_path.display().to_string(),
+        rebase,
+    ))
+}
+
+/// Replace the placeholder source hash with the hash reported by building `src`
+async fn refresh_src_hash(
+    eval_entry_point: &str,
+    attr_path: &str,
+    file_path: &str,
+    new_version: &str,
+    build_options: &BuildOptions,
+) -> anyhow::Result<()> {
+    // Step 6: Build source to get correct hash
+    let (success, _stdout, stderr) =
+        build_nix_expr(eval_entry_point, attr_path, Some("src"), build_options).await?;
+
+    if success {
+        warn!("Build succeeded with invalid hash - this shouldn't happen");
+        anyhow::bail!("Expected hash mismatch error but build succeeded");
+    }
+
+    let correct_hash = extract_hash_from_error(&stderr).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Could not extract correct hash from build error:\n{}",
+            stderr
+        )
+    })?;
+
+    info!("Extracted correct hash: {}", correct_hash);
+
+    // Step 7: Update hash with correct value (use actual file location from step 5)
+    let _ = update_nix_file(
+        eval_entry_point,
+        attr_path,
+        file_path,
+        new_version, // version stays the same
+        new_version,
+        Some(FAKE_HASH),
+        Some(&correct_hash),
+    )
+    .await?;
+
+    info!("Updated hash in {}", file_path);
+    Ok(())
+}
+
+/// Refresh the source hash of every platform of a multi-platform package
+///
+/// Each platform source is set to the placeholder hash and built on its own to obtain the new
+/// hash. Platforms sharing a hash (e.g. universal darwin binaries) are refreshed together.
+async fn refresh_platform_source_hashes(
+    eval_entry_point: &str,
+    attr_path: &str,
+    file_path: &str,
+    platform_sources: &[PlatformSource],
+    build_options: &BuildOptions,
+) -> anyhow::Result<()> {
+    let mut refreshed_hashes: Vec<&str> = Vec::new();
+
+    for source in platform_sources {
+        if refreshed_hashes.contains(&source.hash.as_str()) {
+            debug!("{}: Hash already refreshed", source.system);
+            continue;
+        }
+
+        info!("Refreshing source hash for {}", source.system);
+        update_dependency_hash(file_path, SRC_HASH_ATTRS, &source.hash, FAKE_HASH).await?;
+
+        let (success, _stdout, stderr) = build_nix_expr(
+            eval_entry_point,
+            attr_path,
+            Some(&source.attr),
+            build_options,
+        )
+        .await?;
+
+        if success {
+            anyhow::bail!(
+                "Expected hash mismatch error for {} source but build succeeded",
+                source.system
+            );
+        }
+
+        let correct_hash = extract_hash_from_error(&stderr).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Could not extract correct hash for {} source from build error:\n{}",
+                source.system,
+                stderr
+            )
+        })?;
+
+        info!(
+            "Extracted correct hash for {}: {}",
+            source.system, correct_hash
+        );
+        update_dependency_hash(file_path, SRC_HASH_ATTRS, FAKE_HASH, &correct_hash).await?;
+        refreshed_hashes.push(&source.hash);
+    }
+
+    Ok(())
+}
+
+/// SHA-256 digest in hex which PyPI publishes for the new source artifact
+///
+/// None if PyPI doesn't list the artifact or the source isn't hashed as a flat file, e.g. an
+/// unpacked `fetchzip`, in which case the digest can't be used as the Nix hash.
+async fn pypi_artifact_sha256(
+    eval_entry_point: &str,
+    attr_path: &str,
+    pname: &str,
+    version: &str,
+    src_url: Option<&str>,
+) -> Option<String> {
+    let file_name = src_url?.split_whitespace().next()?.rsplit('/').next()?;
+
+    let hash_mode = PackageQuery::new(eval_entry_point, attr_path)
+        .get_attr("src.outputHashMode or \"flat\"")
+        .await?;
+    if hash_mode != "flat" {
+        debug!("Source is hashed recursively, PyPI digest not applicable");
+        return None;
+    }
+
+    let response = match fetch_pypi_releases(pname).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Could not fetch PyPI digests for {}: {}", pname, e);
+            return None;
+        },
+    };
+    let sha256 = response.artifact_sha256(version, file_name);
+    if sha256.is_none() {
+        debug!("PyPI lists no digest for {} {}", file_name, version);
+    }
+    sha256.map(str::to_string)
+}
+
+/// Select the rendered source URL to validate after a version bump
+///
+/// Only HTTP(S) URLs which changed and contain the new version are worth checking, as those are
+/// built by interpolating the version into a path. For `src.urls` the first mirror is used.
+fn interpolated_src_url<'a>(
+    old_url: Option<&str>,
+    new_url: Option<&'a str>,
+    new_version: &str,
+) -> Option<&'a str> {
+    let url = new_url?.split_whitespace().next()?;
+    let old_url = old_url.and_then(|u| u.split_whitespace().next());
+
+    let is_http = url.starts_with("https://") || url.starts_with("http://");
+    if !is_http || old_url == Some(url) || !url.contains(new_version) {
+        return None;
+    }
+    Some(url)
+}
+
+/// Check that a source URL exists with a HEAD request
+///
+/// Fails on 404/410 responses. Other errors are only logged since some servers reject HEAD
+/// requests; the source build will surface any real problem.
+async fn check_src_url_exists(url: &str) -> anyhow::Result<()> {
+    debug!("Checking that {} exists", url);
+    let client = http::client();
+    let response = match client
+        .head(url)
+        .header("User-Agent", "ekapkgs-update")
+        .send_throttled()
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Could not check source URL {}: {}", url, e);
+            return Ok(());
+        },
+    };
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::GONE {
+        anyhow::bail!(
+            "Release asset missing: {} returned {}. The release may not publish this asset or may \
+             use a different URL layout",
+            url,
+            status
+        );
+    }
+
+    if !status.is_success() {
+        debug!("HEAD {} returned {}, continuing", url, status);
+    }
+    Ok(())
+}
+
+/// Extract hash from Nix build error output
+fn extract_hash_from_error(stderr: &str) -> Option<String> {
+    // Nix error format: "got: sha256-<hash>"
+    let hash_regex = Regex::new(r"got:\s+(sha256-[A-Za-z0-9+/=]+)").ok()?;
+    let caps = hash_regex.captures(stderr)?;
+    Some(caps.get(1)?.as_str().to_string())
+}
+
+/// Detect reversed patch errors and extract the patch filename
+///
+/// Looks for "Reversed (or previously applied) patch detected!" in the last 20 lines
+/// and extracts the patch name from the preceding "applying patch" line.
+///
+/// Returns the patch filename to be removed from the patches array.
+fn detect_reversed_patch(stderr: &str) -> Option<String> {
+    // Get last 20 lines of stderr
+    let lines: Vec<&str> = stderr.lines().collect();
+    let start = lines.len().saturating_sub(20);
+    let last_lines = &lines[start..];
+    let patch_regex = Regex::new(r"applying patch /nix/store/[^-]+-(.+)").ok()?;
+
+    // Look for the reversed patch error message
+    for (i, line) in last_lines.iter().enumerate() {
         if line.contains("Reversed (or previously applied) patch detected!") {
             // Look backward for the "applying patch" line
             for j in (0..i).rev() {
@@ -366,40 +1315,95 @@ fn detect_reversed_patch(stderr: &str) -> Option<String> {
     None
 }
 
-/// Build Nix expression and return stdout/stderr
-async fn build_nix_expr(
-    eval_entry_point: &str,
-    attr_path: &str,
-    attr_suffix: Option<&str>,
-) -> anyhow::Result<(bool, String, String)> {
-    let full_attr = if let Some(suffix) = attr_suffix {
-        format!("{}.{}", attr_path, suffix)
-    } else {
-        attr_path.to_string()
-    };
-
-    debug!("Building {}", full_attr);
-
-    let output = Command::new("nix-build")
-        .arg(eval_entry_point)
-        .arg("-A")
-        .arg(&full_attr)
+/// Run a formatter command (e.g. `nixfmt`, `alejandra`, `treefmt`) on the given files
+///
+/// The command is split on whitespace and the files are appended as arguments. It runs from the
+/// directory of the first file so that tools like treefmt find the project configuration.
+async fn format_files(formatter: &str, files: &[&str]) -> anyhow::Result<()> {
+    let mut parts = formatter.split_whitespace();
+    let program = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Formatter command is empty"))?;
+
+    let files: Vec<std::path::PathBuf> = files
+        .iter()
+        .map(std::path::absolute)
+        .collect::<Result<_, _>>()?;
+    let working_dir = files
+        .first()
+        .and_then(|f| f.parent())
+        .map(|p| p.to_path_buf())
+        .unwrap_or_else(|| std::path::PathBuf::from("."));
+
+    debug!("Running formatter '{}' on {:?}", formatter, files);
+    let output = Command::new(program)
+        .args(parts)
+        .args(&files)
+        .current_dir(working_dir)
+        .process_group(0)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .output()
-        .await?;
+        .await
+        .with_context(|| format!("Failed to run formatter '{}'", program))?;
 
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Formatter '{}' failed: {}", formatter, stderr);
+    }
 
-    Ok((output.status.success(), stdout, stderr))
+    Ok(())
 }
 
-/// Create a git commit for the update
-async fn create_git_commit(
+/// Commit message for an update, ending with the tests and update trailers
+fn update_commit_message(
     attr_path: &str,
     old_version: &str,
     new_version: &str,
-    tests_passed: bool,
+    test_results: &[PassthruTestResult],
+) -> String {
+    let trailers = update_trailers(&[(attr_path, old_version, new_version)]);
+    match passthru_tests::tests_trailer(test_results) {
+        Some(tests_trailer) => format!(
+            "{}: {} -> {}\n\n{}\n{}",
+            attr_path, old_version, new_version, tests_trailer, trailers
+        ),
+        None => format!(
+            "{}: {} -> {}\n\n{}",
+            attr_path, old_version, new_version, trailers
+        ),
+    }
+}
+
+/// Record the current content of `files` as a logical change of the update
+///
+/// Does nothing unless commits are split, see [`UpdateOptions::split_commits`].
+async fn record_commit_step(
+    steps: &mut Vec<CommitStep>,
+    split_commits: bool,
+    message: String,
+    files: &[&str],
 ) -> anyhow::Result<()> {
+    if !split_commits {
+        return Ok(());
+    }
+    let mut step = CommitStep {
+        message,
+        files: Vec::new(),
+    };
+    for file in files {
+        let path = PathBuf::from(file);
+        if step.files.iter().all(|(p, _)| *p != path) {
+            let content = tokio::fs::read_to_string(&path).await?;
+            step.files.push((path, content));
+        }
+    }
+    steps.push(step);
+    Ok(())
+}
+
+/// Create a git commit of all modified files
+pub async fn create_git_commit(commit_message: &str) -> anyhow::Result<()> {
     info!("Creating git commit for update");
 
     // Check if we're in a git repository
@@ -460,17 +1464,8 @@ async fn create_git_commit(
         anyhow::bail!("git add failed: {}", stderr);
     }
 
-    // Create commit with formatted message
-    let commit_message = if tests_passed {
-        format!(
-            "{}: {} -> {}\n\nTests: passthru.tests passed",
-            attr_path, old_version, new_version
-        )
-    } else {
-        format!("{}: {} -> {}", attr_path, old_version, new_version)
-    };
-    let commit_output = Command::new("git")
-        .args(["commit", "-m", &commit_message])
+    let commit_output = git_commit_command()
+        .args(["-m", commit_message])
         .output()
         .await
         .context("Failed to run git commit")?;
@@ -485,208 +1480,721 @@ async fn create_git_commit(
     Ok(())
 }
 
-/// Update the nix expr generically
-pub async fn update_from_file_path(
-    eval_entry_point: String,
-    attr_path: String,
-    file_location: String,
-    strategy: SemverStrategy,
-    commit: bool,
-    create_pr: bool,
-    upstream: Option<String>,
-    fork: String,
-    run_passthru_tests: bool,
-    fail_on_test_failure: bool,
-) -> anyhow::Result<()> {
-    info!(
-        "Starting generic update for {} at {}",
-        attr_path, file_location
-    );
+/// Options controlling how a generic update is carried out
+#[derive(Debug, Clone)]
+pub struct UpdateOptions {
+    /// Version selection strategy
+    pub strategy: SemverStrategy,
+    /// Create a git commit after a successful update
+    pub commit: bool,
+    /// Create a pull request after a successful update (implies commit)
+    pub create_pr: bool,
+    /// Upstream git remote used for PR creation, inferred if unset
+    pub upstream: Option<String>,
+    /// Remote to push update branches to
+    pub fork: String,
+    /// Build passthru.tests after the package build
+    pub run_passthru_tests: bool,
+    /// Time each passthru test may take to build
+    pub passthru_test_timeout: Duration,
+    /// Timeout and resource limits of package builds
+    pub build_options: BuildOptions,
+    /// Additional systems to build the update for, if the package supports them
+    pub verify_systems: Vec<String>,
+    /// Platforms to cross-compile the update for
+    pub verify_cross_systems: Vec<String>,
+    /// Treat failing passthru.tests as a failed update
+    pub fail_on_test_failure: bool,
+    /// Dependency FOD hashes to refresh after the source hash
+    pub dependency_hash_attrs: Vec<DependencyHashAttr>,
+    /// Formatter command run on rewritten Nix files, e.g. `nixfmt` or `treefmt`
+    pub formatter: Option<String>,
+    /// GitHub handles of maintainers who don't want to be pinged in PRs
+    pub maintainer_opt_out: Vec<String>,
+    /// Commit each logical change of an update separately instead of all changes at once
+    pub split_commits: bool,
+    /// Per-package settings, e.g. tag filters
+    pub config: Config,
+}
 
-    // Step 1: Extract package metadata
-    let metadata = PackageMetadata::from_attr_path(&eval_entry_point, &attr_path).await?;
-    info!("Current version: {}", metadata.version);
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            strategy: SemverStrategy::Latest,
+            commit: false,
+            create_pr: false,
+            upstream: None,
+            fork: "origin".to_string(),
+            run_passthru_tests: false,
+            passthru_test_timeout: passthru_tests::DEFAULT_TEST_TIMEOUT,
+            build_options: BuildOptions::default(),
+            verify_systems: Vec::new(),
+            verify_cross_systems: Vec::new(),
+            fail_on_test_failure: false,
+            dependency_hash_attrs: DependencyHashAttr::defaults(),
+            formatter: None,
+            maintainer_opt_out: Vec::new(),
+            split_commits: false,
+            config: Config::default(),
+        }
+    }
+}
 
-    // Step 2: Determine upstream source
-    let upstream_source = if let Some(ref src_url) = metadata.src_url {
-        // Try to parse URL as GitHub/GitLab/PyPI
-        UpstreamSource::from_url(src_url)
-            .context("Source is not from a supported VCS platform (GitHub, GitLab, PyPI)")?
-    } else if let Some(ref pname) = metadata.pname {
-        // If no src_url but pname exists, create PyPI source directly
-        UpstreamSource::PyPI {
-            pname: pname.clone(),
+impl UpdateOptions {
+    /// Time a single build, or the update script, of a package may take
+    pub fn build_timeout(&self, attr_path: &str) -> Option<Duration> {
+        self.config
+            .package(attr_path)
+            .build_timeout()
+            .or(self.build_options.timeout)
+    }
+}
+
+/// Parse `--dependency-hash-attr` values, extending the default dependency hash attributes
+pub fn parse_dependency_hash_attrs(specs: &[String]) -> anyhow::Result<Vec<DependencyHashAttr>> {
+    let mut attrs = DependencyHashAttr::defaults();
+    for spec in specs {
+        let attr = DependencyHashAttr::parse(spec)?;
+        if !attrs.contains(&attr) {
+            attrs.push(attr);
         }
-    } else {
-        anyhow::bail!(
-            "No source URL or pname found for package - cannot determine upstream source"
-        );
+    }
+    Ok(attrs)
+}
+
+/// Compare the requirements of a new PyPI release against the Nix expression's dependencies
+async fn python_dependency_delta(
+    eval_entry_point: &str,
+    attr_path: &str,
+    pname: &str,
+    new_version: &str,
+) -> Option<DependencyDelta> {
+    let nix_dependencies = match PackageQuery::new(eval_entry_point, attr_path)
+        .get_python_dependencies()
+        .await
+    {
+        Ok(dependencies) => dependencies,
+        Err(e) => {
+            debug!(
+                "{}: Failed to evaluate Python dependencies: {}",
+                attr_path, e
+            );
+            return None;
+        },
+    };
+    let requires_dist = match fetch_requires_dist(pname, new_version).await {
+        Ok(requires_dist) => requires_dist,
+        Err(e) => {
+            warn!(
+                "{}: Failed to fetch requirements of {} {}: {}",
+                attr_path, pname, new_version, e
+            );
+            return None;
+        },
     };
 
-    info!("{}", upstream_source.description());
+    let delta = DependencyDelta::compute(new_version, &requires_dist, &nix_dependencies);
+    if !delta.added.is_empty() || !delta.dropped.is_empty() {
+        info!(
+            "{}: Python dependencies changed: {} added, {} dropped",
+            attr_path,
+            delta.added.len(),
+            delta.dropped.len()
+        );
+    }
+    Some(delta)
+}
 
-    // Step 3: Fetch best compatible release based on strategy
-    let best_release = upstream_source
-        .get_compatible_release(&metadata.version, strategy)
-        .await?;
+/// Results of a successful update which are reported alongside it
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOutcome {
+    /// Results of building each passthru test, empty if tests weren't run
+    pub test_results: Vec<PassthruTestResult>,
+    /// Results of building the package for additional systems
+    pub system_results: Vec<SystemBuildResult>,
+    /// Release notes, compare link and size of the comparison of the new version
+    pub release_notes: Option<String>,
+    /// Checksums and signatures the fetched source was checked against
+    pub source_verification: Vec<VerificationCheck>,
+    /// Provenance attestation the fetched source was checked against, if upstream publishes one
+    pub provenance: Option<ProvenanceCheck>,
+    /// Logical changes made by the update, empty unless commits are split
+    pub commit_steps: Vec<CommitStep>,
+    /// Python dependencies of the new PyPI release which differ from the Nix expression
+    pub python_dependencies: Option<DependencyDelta>,
+    /// Comparison of the new version with nixpkgs, if a nixpkgs checkout is configured
+    pub nixpkgs_comparison: Option<String>,
+    /// Files synced from nixpkgs, for packages updated by copying their nixpkgs expression
+    pub nixpkgs_sync: Option<String>,
+    /// Output paths of the updated package
+    pub out_paths: Vec<String>,
+    /// Comparison of the vulnerabilities the old and new version are marked with, if either is
+    pub insecure_markings: Option<String>,
+    /// Patches which no longer applied and were rebased, and how
+    pub rebased_patches: Vec<(String, PatchRebase)>,
+}
 
-    let new_version = UpstreamSource::get_version(&best_release);
-    info!(
-        "Found compatible version ({:?}): {} -> {}",
-        strategy, metadata.version, new_version
-    );
+impl UpdateOutcome {
+    /// Markdown sections describing the outcome, for PR bodies
+    pub fn report_sections(&self) -> Vec<String> {
+        let mut sections: Vec<String> = self.release_notes.iter().cloned().collect();
+        if !self.test_results.is_empty() {
+            sections.push(passthru_tests::format_test_report(&self.test_results));
+        }
+        if !self.system_results.is_empty() {
+            sections.push(format_system_report(&self.system_results));
+        }
+        sections.extend(format_verification_report(&self.source_verification));
+        sections.extend(self.provenance.as_ref().map(format_provenance_report));
+        sections.extend(
+            self.python_dependencies
+                .as_ref()
+                .and_then(DependencyDelta::report),
+        );
+        sections.extend(self.nixpkgs_comparison.clone());
+        sections.extend(self.nixpkgs_sync.clone());
+        sections.extend(self.insecure_markings.clone());
+        sections.extend(format_rebase_report(&self.rebased_patches));
+        sections
+    }
+}
 
-    // Step 5: Update version in file with invalid hash
-    let invalid_hash = "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
-    let actual_file_location = update_nix_file(
-        &eval_entry_point,
-        &attr_path,
-        &file_location,
-        &metadata.version,
-        &new_version,
-        metadata.output_hash.as_deref(),
-        Some(invalid_hash),
+/// Package state and the upstream release it would be updated to
+pub struct ProposedUpdate {
+    pub metadata: PackageMetadata,
+    pub upstream_source: UpstreamSource,
+    pub best_release: Release,
+    pub new_version: String,
+}
+
+/// Determine the upstream source of a package and the release it should be updated to
+pub async fn find_update(
+    eval_entry_point: &str,
+    attr_path: &str,
+    options: &UpdateOptions,
+    timings: &mut PhaseTimings,
+) -> anyhow::Result<ProposedUpdate> {
+    let UpdateOptions {
+        ref dependency_hash_attrs,
+        ..
+    } = *options;
+
+    // Step 1: Extract package metadata
+    timings.enter(UpdatePhase::MetadataEval);
+    let metadata = PackageMetadata::from_attr_path_with_hashes(
+        eval_entry_point,
+        attr_path,
+        dependency_hash_attrs,
     )
     .await?;
+    find_update_with_metadata(eval_entry_point, attr_path, metadata, options, timings).await
+}
+
+/// [`find_update`] of a package whose metadata is already evaluated
+pub async fn find_update_with_metadata(
+    eval_entry_point: &str,
+    attr_path: &str,
+    metadata: PackageMetadata,
+    options: &UpdateOptions,
+    timings: &mut PhaseTimings,
+) -> anyhow::Result<ProposedUpdate> {
+    let UpdateOptions {
+        strategy,
+        ref config,
+        ..
+    } = *options;
+    info!("Current version: {}", metadata.version);
 
+    let proposed = find_release(
+        eval_entry_point,
+        attr_path,
+        metadata,
+        strategy,
+        config,
+        timings,
+    )
+    .await?;
+    info!("{}", proposed.upstream_source.description());
     info!(
-        "Updated version and set invalid hash in {}",
-        actual_file_location
+        "Found compatible version ({:?}): {} -> {}",
+        directive_strategy(&proposed.metadata, strategy),
+        proposed.metadata.version,
+        proposed.new_version
     );
+    Ok(proposed)
+}
 
-    // Step 6: Build source to get correct hash
-    let (success, _stdout, stderr) =
-        build_nix_expr(&eval_entry_point, &attr_path, Some("src")).await?;
-
-    if success {
-        warn!("Build succeeded with invalid hash - this shouldn't happen");
-        anyhow::bail!("Expected hash mismatch error but build succeeded");
-    }
+/// Determine the upstream source of an evaluated package and the release it should be updated to
+pub async fn find_release(
+    eval_entry_point: &str,
+    attr_path: &str,
+    metadata: PackageMetadata,
+    strategy: SemverStrategy,
+    config: &Config,
+    timings: &mut PhaseTimings,
+) -> anyhow::Result<ProposedUpdate> {
+    // Step 2: Determine upstream source
+    let package_config = config.package(attr_path);
+    let upstream_source =
+        if let Some(source) = plugin_source_for(attr_path, &metadata, &package_config) {
+            source
+        } else if let Some(ref source) = metadata.directives.source {
+            UpstreamSource::from_url(source)
+                .with_context(|| format!("Unsupported source directive {}", source))?
+        } else if let Some(ref src_url) = metadata.src_url {
+            // Try to parse URL as GitHub/GitLab/PyPI
+            UpstreamSource::from_url(src_url).context(
+                "Source is not from a supported platform (GitHub, GitLab, PyPI, mirrors, or \
+                 libraries.io), a source_command can look it up instead",
+            )?
+        } else if let Some(ref pname) = metadata.pname {
+            // If no src_url but pname exists, create PyPI source directly
+            UpstreamSource::PyPI {
+                pname: pname.clone(),
+            }
+        } else {
+            anyhow::bail!(
+                "No source URL or pname found for package - cannot determine upstream source"
+            );
+        };
 
-    let correct_hash = extract_hash_from_error(&stderr).ok_or_else(|| {
-        anyhow::anyhow!(
-            "Could not extract correct hash from build error:\n{}",
-            stderr
+    // Step 3: Fetch best compatible release based on strategy
+    timings.enter(UpdatePhase::UpstreamFetch);
+    let tag_filter = package_config.tag_filter()?;
+    let withdrawn =
+        query_withdrawn_versions(eval_entry_point, attr_path, &upstream_source, &metadata).await;
+    let strategy = directive_strategy(&metadata, strategy);
+    let best_release = upstream_source
+        .get_compatible_release(
+            &metadata.version,
+            strategy,
+            &tag_filter,
+            package_config.version_scheme,
+            package_config.release_listing,
+            &PythonRequirements::from_metadata(&metadata),
+            &withdrawn,
+            package_config.min_release_age(),
         )
-    })?;
+        .await?;
 
-    info!("Extracted correct hash: {}", correct_hash);
+    let new_version = tag_filter.release_version(&best_release);
+    Ok(ProposedUpdate {
+        metadata,
+        upstream_source,
+        best_release,
+        new_version,
+    })
+}
 
-    // Step 7: Update hash with correct value (use actual file location from step 5)
-    let _ = update_nix_file(
-        &eval_entry_point,
-        &attr_path,
-        &actual_file_location,
-        &new_version, // version stays the same
-        &new_version,
-        Some(invalid_hash),
-        Some(&correct_hash),
+/// Rewrite the version of a package, invalidating its source hash
+///
+/// Multi-platform packages keep their source hashes, which are refreshed per platform instead.
+/// A `meta.changelog` with the old version written out is updated along with the version.
+/// Returns the file holding the version, see [`update_nix_file`].
+pub async fn rewrite_version(
+    eval_entry_point: &str,
+    attr_path: &str,
+    file_location: &str,
+    metadata: &PackageMetadata,
+    new_version: &str,
+) -> anyhow::Result<String> {
+    let is_multi_platform = !metadata.platform_sources.is_empty();
+    let actual_file_location = update_nix_file(
+        eval_entry_point,
+        attr_path,
+        file_location,
+        &metadata.version,
+        new_version,
+        if is_multi_platform {
+            None
+        } else {
+            metadata.output_hash.as_deref()
+        },
+        Some(FAKE_HASH),
     )
     .await?;
 
-    info!("Updated hash in {}", actual_file_location);
-
-    // Step 8: Build source again to verify
-    let (success, _stdout, stderr) =
-        build_nix_expr(&eval_entry_point, &attr_path, Some("src")).await?;
-
-    if !success {
-        anyhow::bail!("Source build failed after hash update:\n{}", stderr);
+    if metadata
+        .changelog
+        .as_deref()
+        .is_some_and(|changelog| changelog.contains(&metadata.version))
+    {
+        if let Err(e) =
+            update_changelog_in_file(file_location, &metadata.version, new_version).await
+        {
+            warn!("{}: Failed to update the changelog: {}", attr_path, e);
+        }
     }
+    Ok(actual_file_location)
+}
 
-    info!("Source build successful");
+/// Update the version written out in the changelog of a package, see [`update_changelog_version`]
+async fn update_changelog_in_file(
+    file_location: &str,
+    old_version: &str,
+    new_version: &str,
+) -> anyhow::Result<()> {
+    let content = tokio::fs::read_to_string(file_location).await?;
+    match update_changelog_version(&content, old_version, new_version)? {
+        Some(updated) => {
+            tokio::fs::write(file_location, updated).await?;
+            debug!("Updated the version of the changelog in {}", file_location);
+        },
+        None => debug!(
+            "No changelog with version {} written out in {}",
+            old_version, file_location
+        ),
+    }
+    Ok(())
+}
 
-    // For Rust packages, update cargoHash
-    if let Some(old_cargo_hash) = &metadata.cargo_hash {
-        info!("Detected Rust package, updating cargoHash");
+/// Update the nix expr generically
+///
+/// `metadata` is the already evaluated metadata of the package, if any. The time spent in each
+/// phase is added to `timings`, whose last phase is left for the caller to finish.
+pub async fn update_from_file_path(
+    eval_entry_point: String,
+    attr_path: String,
+    file_location: String,
+    metadata: Option<PackageMetadata>,
+    options: &UpdateOptions,
+    timings: &mut PhaseTimings,
+) -> anyhow::Result<UpdateOutcome> {
+    let UpdateOptions {
+        commit,
+        create_pr,
+        run_passthru_tests,
+        passthru_test_timeout,
+        ref build_options,
+        ref verify_systems,
+        ref verify_cross_systems,
+        fail_on_test_failure,
+        ref formatter,
+        split_commits,
+        ref config,
+        ..
+    } = *options;
 
-        // Set invalid cargo hash
-        let invalid_cargo_hash = "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
-        update_cargo_hash(&actual_file_location, old_cargo_hash, invalid_cargo_hash).await?;
+    info!(
+        "Starting generic update for {} at {}",
+        attr_path, file_location
+    );
 
-        info!("Set invalid cargoHash in {}", actual_file_location);
+    let ProposedUpdate {
+        metadata,
+        upstream_source,
+        best_release,
+        new_version,
+    } = match metadata {
+        Some(metadata) => {
+            find_update_with_metadata(&eval_entry_point, &attr_path, metadata, options, timings)
+                .await?
+        },
+        None => find_update(&eval_entry_point, &attr_path, options, timings).await?,
+    };
 
-        // Build full package to get correct cargo hash
-        let (success, _stdout, stderr) =
-            build_nix_expr(&eval_entry_point, &attr_path, None).await?;
+    let package_config = config.package(&attr_path);
+    // Slow packages can be given more time to build than the others
+    let package_build_options = BuildOptions {
+        timeout: options.build_timeout(&attr_path),
+        ..build_options.clone()
+    };
+    let build_options = &package_build_options;
+    let hooks = package_config.hooks;
+    let tree = entry_point_dir(&eval_entry_point);
+    let mut hook_context = HookContext::new(&attr_path, &metadata.version, &new_version);
+    run_hook(&hooks, HookStage::PreRewrite, &hook_context, tree).await?;
 
-        if success {
-            warn!("Build succeeded with invalid cargoHash - this shouldn't happen");
-            anyhow::bail!("Expected cargoHash mismatch error but build succeeded");
-        }
+    // Step 5: Update version in file with invalid hash
+    timings.enter(UpdatePhase::Rewrite);
+    let is_multi_platform = !metadata.platform_sources.is_empty();
+    let actual_file_location = rewrite_version(
+        &eval_entry_point,
+        &attr_path,
+        &file_location,
+        &metadata,
+        &new_version,
+    )
+    .await?;
 
-        let correct_cargo_hash = extract_hash_from_error(&stderr).ok_or_else(|| {
-            anyhow::anyhow!(
-                "Could not extract correct cargoHash from build error:\n{}",
-                stderr
-            )
-        })?;
+    // Other files of the package may repeat the version, e.g. module defaults or test fixtures
+    let occurrence_files = if package_config.update_version_occurrences {
+        update_version_occurrences(
+            &eval_entry_point,
+            &attr_path,
+            &[&file_location, &actual_file_location],
+            &metadata.version,
+            &new_version,
+        )
+        .await?
+    } else {
+        Vec::new()
+    };
 
-        info!("Extracted correct cargoHash: {}", correct_cargo_hash);
+    // URL publishing the digest the hash of the new source was taken from
+    let mut digest_origin: Option<String> = None;
 
-        // Update cargoHash with correct value
-        update_cargo_hash(
+    if is_multi_platform {
+        info!("Updated version in {}", actual_file_location);
+        refresh_platform_source_hashes(
+            &eval_entry_point,
+            &attr_path,
             &actual_file_location,
-            invalid_cargo_hash,
-            &correct_cargo_hash,
+            &metadata.platform_sources,
+            build_options,
         )
         .await?;
+    } else {
+        info!(
+            "Updated version and set invalid hash in {}",
+            actual_file_location
+        );
+
+        // Make sure the release asset exists before spending a build on it
+        let new_src_url = PackageQuery::new(&eval_entry_point, &attr_path)
+            .get_src_url()
+            .await;
+        if let Some(url) = interpolated_src_url(
+            metadata.src_url.as_deref(),
+            new_src_url.as_deref(),
+            &new_version,
+        ) {
+            check_src_url_exists(url).await?;
+        }
 
-        info!("Updated cargoHash in {}", actual_file_location);
+        // PyPI publishes the digest of every artifact, which spares the invalid hash roundtrip
+        let mut pypi_digest: Option<(String, String)> = None;
+        if let UpstreamSource::PyPI { pname } = &upstream_source {
+            pypi_digest = pypi_artifact_sha256(
+                &eval_entry_point,
+                &attr_path,
+                pname,
+                &new_version,
+                new_src_url.as_deref(),
+            )
+            .await
+            .map(|sha256| {
+                (
+                    format!("https://pypi.org/pypi/{}/{}/json", pname, new_version),
+                    sha256,
+                )
+            });
+        }
+
+        match pypi_digest.and_then(|(origin, sha256)| Some((origin, sha256_hex_to_sri(&sha256)?))) {
+            Some((origin, sri_hash)) => {
+                info!("Using hash from PyPI digest: {}", sri_hash);
+                digest_origin = Some(origin);
+                let _ = update_nix_file(
+                    &eval_entry_point,
+                    &attr_path,
+                    &actual_file_location,
+                    &new_version,
+                    &new_version,
+                    Some(FAKE_HASH),
+                    Some(&sri_hash),
+                )
+                .await?;
+            },
+            None => {
+                refresh_src_hash(
+                    &eval_entry_point,
+                    &attr_path,
+                    &actual_file_location,
+                    &new_version,
+                    build_options,
+                )
+                .await?;
+            },
+        }
     }
 
-    // For Go packages, update vendorHash
-    if let Some(old_vendor_hash) = &metadata.vendor_hash {
-        info!("Detected Go package, updating vendorHash");
+    // Step 8: Build source again to verify
+    let (success, stdout, stderr) =
+        build_nix_expr(&eval_entry_point, &attr_path, Some("src"), build_options).await?;
 
-        // Set invalid vendor hash
-        let invalid_vendor_hash = "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
-        update_vendor_hash(&actual_file_location, old_vendor_hash, invalid_vendor_hash).await?;
+    if !success {
+        anyhow::bail!("Source build failed after hash update:\n{}", stderr);
+    }
 
-        info!("Set invalid vendorHash in {}", actual_file_location);
+    info!("Source build successful");
 
-        // Build full package to get correct vendor hash
-        let (success, _stdout, stderr) =
-            build_nix_expr(&eval_entry_point, &attr_path, None).await?;
+    let mut version_files = vec![file_location.as_str(), actual_file_location.as_str()];
+    version_files.extend(occurrence_files.iter().map(String::as_str));
+    let mut steps = Vec::new();
+    record_commit_step(
+        &mut steps,
+        split_commits,
+        update_commit_message(&attr_path, &metadata.version, &new_version, &[]),
+        &version_files,
+    )
+    .await?;
 
-        if success {
-            warn!("Build succeeded with invalid vendorHash - this shouldn't happen");
-            anyhow::bail!("Expected vendorHash mismatch error but build succeeded");
+    // Check the fetched source against checksums and signatures published by upstream, unless
+    // disabled. Packages requiring provenance are checked regardless.
+    let mut source_verification = Vec::new();
+    let new_src_url = if !config.skip_source_verification || package_config.require_provenance {
+        PackageQuery::new(&eval_entry_point, &attr_path)
+            .get_src_url()
+            .await
+    } else {
+        None
+    };
+    let mut provenance = None;
+    let src_out_path = stdout.lines().last().map(str::trim);
+    if let (Some(url), Some(out_path)) = (
+        new_src_url
+            .as_deref()
+            .and_then(|u| u.split_whitespace().next()),
+        src_out_path,
+    ) {
+        source_verification = verify_source(Path::new(out_path), url).await?;
+
+        let pypi_project = match &upstream_source {
+            UpstreamSource::PyPI { pname } => Some(pname.as_str()),
+            _ => None,
+        };
+        if let Some(origin) = ProvenanceOrigin::from_source(url, pypi_project, &new_version) {
+            provenance = verify_provenance(Path::new(out_path), &origin).await?;
+        }
+    }
+    // The source is a fixed-output derivation of the digest, building it checked the digest
+    if let Some(origin) = digest_origin {
+        source_verification.push(VerificationCheck {
+            url: origin,
+            result: CheckResult::Passed,
+        });
+    }
+    if package_config.require_provenance {
+        match &provenance {
+            Some(ProvenanceCheck {
+                result: CheckResult::Passed,
+                ..
+            }) => {},
+            Some(ProvenanceCheck {
+                url,
+                result: CheckResult::Unverifiable(reason),
+                ..
+            }) => anyhow::bail!(
+                "{} requires provenance, but the attestation at {} could not be verified: {}",
+                attr_path,
+                url,
+                reason
+            ),
+            None if src_out_path.is_some_and(|path| Path::new(path).is_dir()) => anyhow::bail!(
+                "{} requires provenance, but the source of {} is unpacked and attestations cover \
+                 archives",
+                attr_path,
+                new_version
+            ),
+            None => anyhow::bail!(
+                "{} requires provenance, but none is published for the source of {}",
+                attr_path,
+                new_version
+            ),
         }
+    }
 
-        let correct_vendor_hash = extract_hash_from_error(&stderr).ok_or_else(|| {
-            anyhow::anyhow!(
-                "Could not extract correct vendorHash from build error:\n{}",
-                stderr
-            )
-        })?;
+    // Patches, and possibly dependency hashes, stay in the Nix file when the version is pinned
+    // in a sidecar file
+    let nix_file_location = if SidecarFormat::from_path(&actual_file_location).is_some() {
+        file_location.clone()
+    } else {
+        actual_file_location.clone()
+    };
 
-        info!("Extracted correct vendorHash: {}", correct_vendor_hash);
+    // Rust packages vendoring their Cargo.lock need the lockfile of the new source
+    let nix_content = tokio::fs::read_to_string(&nix_file_location).await?;
+    if let Some(lock_path) = find_cargo_lock_file(&nix_content) {
+        let changed_files = update_cargo_lock(
+            &eval_entry_point,
+            &attr_path,
+            &nix_file_location,
+            &lock_path,
+            src_out_path,
+            build_options,
+        )
+        .await
+        .context("Failed to update Cargo.lock")?;
+        if !changed_files.is_empty() {
+            let changed_files: Vec<&str> = changed_files.iter().map(String::as_str).collect();
+            record_commit_step(
+                &mut steps,
+                split_commits,
+                format!("{}: update Cargo.lock", attr_path),
+                &changed_files,
+            )
+            .await?;
+        }
+    }
 
-        // Update vendorHash with correct value
-        update_vendor_hash(
-            &actual_file_location,
-            invalid_vendor_hash,
-            &correct_vendor_hash,
+    // Refresh dependency FOD hashes (cargoHash, vendorHash, pnpmDeps, mixFodDeps, ...)
+    for dependency_hash in &metadata.dependency_hashes {
+        let label = &dependency_hash.attr.eval_attr;
+        info!("Detected {}, updating dependency hash", label);
+
+        let rewrite_attrs: Vec<&str> = dependency_hash
+            .attr
+            .rewrite_attrs
+            .iter()
+            .map(String::as_str)
+            .collect();
+        let hash_file_location = if tokio::fs::read_to_string(&actual_file_location)
+            .await?
+            .contains(&dependency_hash.hash)
+        {
+            &actual_file_location
+        } else {
+            &nix_file_location
+        };
+        refresh_dependency_hash(
+            &eval_entry_point,
+            &attr_path,
+            hash_file_location,
+            label,
+            &rewrite_attrs,
+            &dependency_hash.hash,
+            build_options,
+        )
+        .await?;
+        record_commit_step(
+            &mut steps,
+            split_commits,
+            format!("{}: update {}", attr_path, label),
+            &[hash_file_location],
         )
         .await?;
-
-        info!("Updated vendorHash in {}", actual_file_location);
     }
 
-    // Step 9: Build full package to verify with reversed patch recovery
+    // Step 9: Build full package to verify with reversed and failed patch recovery
+    timings.enter(UpdatePhase::Build);
+    let mut rebased_patches: Vec<(String, PatchRebase)> = Vec::new();
+    let mut refreshed_patches: Vec<String> = Vec::new();
     loop {
-        let (success, _stdout, stderr) =
-            build_nix_expr(&eval_entry_point, &attr_path, None).await?;
+        let (success, stdout, stderr) =
+            build_nix_expr(&eval_entry_point, &attr_path, None, build_options).await?;
 
         if success {
+            hook_context.out_paths = stdout
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect();
             // Build succeeded - check if patches array is now empty
-            let content = tokio::fs::read_to_string(&actual_file_location).await?;
+            let content = tokio::fs::read_to_string(&nix_file_location).await?;
             if is_patches_array_empty(&content) {
                 match remove_patches_attribute(&content) {
                     Ok(updated_content) => {
-                        tokio::fs::write(&actual_file_location, updated_content).await?;
+                        tokio::fs::write(&nix_file_location, updated_content).await?;
                         debug!("Removed empty patches attribute");
+                        record_commit_step(
+                            &mut steps,
+                            split_commits,
+                            format!("{}: remove empty patches", attr_path),
+                            &[&nix_file_location],
+                        )
+                        .await?;
                     },
                     Err(e) => {
                         debug!("Could not remove empty patches attribute: {}", e);
@@ -697,19 +2205,41 @@ pub async fn update_from_file_path(
             break;
         }
 
+        // Fetched patches changed upstream only need a new hash, once per patch
+        if let Some(patch_name) =
+            refresh_changed_patch_hash(&nix_file_location, &stderr, &refreshed_patches).await?
+        {
+            refreshed_patches.push(patch_name.clone());
+            record_commit_step(
+                &mut steps,
+                split_commits,
+                format!("{}: refresh hash of patch {}", attr_path, patch_name),
+                &[&nix_file_location],
+            )
+            .await?;
+            continue;
+        }
+
         // Build failed - check for reversed patch errors
         if let Some(patch_name) = detect_reversed_patch(&stderr) {
             debug!("Detected reversed patch: {}", patch_name);
 
             // Read the file
-            let content = tokio::fs::read_to_string(&actual_file_location).await?;
+            let content = tokio::fs::read_to_string(&nix_file_location).await?;
 
             // Remove the patch
             match remove_patch_from_array(&content, &patch_name) {
                 Ok(updated_content) => {
                     // Write the updated content back
-                    tokio::fs::write(&actual_file_location, updated_content).await?;
+                    tokio::fs::write(&nix_file_location, updated_content).await?;
                     debug!("Removed obsolete patch: {}", patch_name);
+                    record_commit_step(
+                        &mut steps,
+                        split_commits,
+                        format!("{}: remove obsolete patch {}", attr_path, patch_name),
+                        &[&nix_file_location],
+                    )
+                    .await?;
                     // Continue loop to retry the build
                 },
                 Err(e) => {
@@ -723,85 +2253,363 @@ pub async fn update_from_file_path(
                     );
                 },
             }
+        } else if let Some(patch_name) = detect_failed_patch(&stderr) {
+            // Rebase each patch once, a patch failing again needs a human
+            if rebased_patches
+                .iter()
+                .any(|(rebased, _)| *rebased == patch_name)
+            {
+                anyhow::bail!(
+                    "Package build failed after update. Patch {} still fails to apply after \
+                     rebasing it\n{}",
+                    patch_name,
+                    stderr
+                );
+            }
+            info!("Patch {} fails to apply, rebasing it", patch_name);
+            match rebase_failed_patch(
+                &eval_entry_point,
+                &attr_path,
+                &nix_file_location,
+                &patch_name,
+                src_out_path,
+                build_options,
+            )
+            .await
+            {
+                Ok((change, file, rebase)) => {
+                    info!("{}: {}", attr_path, change);
+                    record_commit_step(
+                        &mut steps,
+                        split_commits,
+                        format!("{}: {}", attr_path, change),
+                        &[&file],
+                    )
+                    .await?;
+                    rebased_patches.push((patch_name, rebase));
+                    // Continue loop to retry the build
+                },
+                Err(e) => {
+                    warn!("Failed to rebase patch {}: {}", patch_name, e);
+                    anyhow::bail!(
+                        "Package build failed after update. Patch {} doesn't apply and couldn't \
+                         be rebased: {}\n{}",
+                        patch_name,
+                        e,
+                        stderr
+                    );
+                },
+            }
         } else {
-            // No reversed patch detected - this is a real build failure
+            // No reversed or failed patch detected - this is a real build failure
             warn!("Full package build failed:\n{}", stderr);
             anyhow::bail!(
-                "Package build failed after update. You may need to manually fix build issues."
+                "Package build failed after update. You may need to manually fix build issues.\n{}",
+                stderr
+            );
+        }
+    }
+
+    // A placeholder the build doesn't use would otherwise be committed
+    for file in [&file_location, &actual_file_location, &nix_file_location] {
+        let placeholders = count_fake_hashes(&tokio::fs::read_to_string(file).await?);
+        if placeholders > 0 {
+            anyhow::bail!(
+                "{} still contains {} placeholder hashes after the update",
+                file,
+                placeholders
             );
         }
     }
 
+    run_hook(&hooks, HookStage::PostBuild, &hook_context, tree).await?;
+
     // Run passthru.tests if requested
-    let mut tests_passed = false;
-    info!("Checking for passthru.tests...");
+    let mut test_results = Vec::new();
     if run_passthru_tests {
-        // Check if tests exist using nix eval
-        let normalized_entry = normalize_entry_point(&eval_entry_point);
-
-        if has_passthru_tests(&normalized_entry, &attr_path).await? {
-            info!("Found {}.passthru.tests, building tests...", &attr_path);
-
-            // Build tests
-            let (success, _stdout, stderr) =
-                build_nix_expr(&eval_entry_point, &attr_path, Some("passthru.tests")).await?;
+        info!("Checking for passthru.tests...");
+        test_results = passthru_tests::run_passthru_tests(
+            &eval_entry_point,
+            &attr_path,
+            passthru_test_timeout,
+            build_options,
+        )
+        .await?;
 
-            if !success {
-                warn!("Tests failed:\n{}", stderr);
-                if fail_on_test_failure {
-                    anyhow::bail!("Package tests failed after update");
-                } else {
-                    warn!("Package tests failed after update, but continuing anyway");
-                }
-            } else {
+        if passthru_tests::all_passed(&test_results) {
+            if !test_results.is_empty() {
                 info!("✓ Tests passed");
-                tests_passed = true;
             }
         } else {
-            info!("No passthru.tests found for {}", attr_path);
+            let failed: Vec<String> = test_results
+                .iter()
+                .filter(|r| r.outcome != passthru_tests::TestOutcome::Passed)
+                .map(|r| format!("{} ({})", r.name, r.outcome))
+                .collect();
+            if fail_on_test_failure {
+                anyhow::bail!("Package tests failed after update: {}", failed.join(", "));
+            } else {
+                warn!(
+                    "Package tests failed after update, but continuing anyway: {}",
+                    failed.join(", ")
+                );
+            }
         }
     }
 
+    // Build for additional systems the package claims to support
+    let mut system_results = Vec::new();
+    if !verify_systems.is_empty() || !verify_cross_systems.is_empty() {
+        system_results = build_for_systems(
+            &eval_entry_point,
+            &attr_path,
+            verify_systems,
+            verify_cross_systems,
+            build_options,
+        )
+        .await?;
+    }
+    timings.finish();
+
+    // Format the rewritten Nix file so the update passes the repository's formatting checks
+    if let Some(formatter) = formatter {
+        if let Err(e) = format_files(formatter, &[&nix_file_location]).await {
+            warn!("Failed to format {}: {}", nix_file_location, e);
+        }
+    }
+
+    // Point out Python dependencies which likely need manual edits
+    let python_dependencies = match &upstream_source {
+        UpstreamSource::PyPI { pname } => {
+            python_dependency_delta(&eval_entry_point, &attr_path, pname, &new_version).await
+        },
+        _ => None,
+    };
+
+    // Mention whether nixpkgs is ahead, to decide between this update and syncing from nixpkgs
+    let nixpkgs_comparison = match &config.nixpkgs {
+        Some(nixpkgs) => {
+            let nixpkgs_version = nixpkgs::nixpkgs_version(nixpkgs, &attr_path).await;
+            if let Err(e) = &nixpkgs_version {
+                warn!("{}: Failed to evaluate in nixpkgs: {}", attr_path, e);
+            }
+            Some(nixpkgs::report_section(&nixpkgs_version, &new_version))
+        },
+        None => None,
+    };
+
+    // The markings of the new version are only known once the expression is rewritten
+    let new_markings = PackageQuery::new(&eval_entry_point, &attr_path)
+        .get_insecure_markings()
+        .await;
+    if new_markings.is_marked() {
+        warn!(
+            "{}: {} is marked insecure: {}",
+            attr_path,
+            new_version,
+            new_markings.known_vulnerabilities.join(", ")
+        );
+    }
+    let insecure_markings = metadata.insecure.report(&new_markings);
+
+    // Give reviewers a sense of the size of the update
+    let mut release_notes = upstream_source.release_notes_section(&best_release, &metadata.version);
+    if let Some(summary) = upstream_source
+        .compare_summary(&best_release, &metadata.version)
+        .await
+    {
+        release_notes = Some(match release_notes {
+            Some(section) => format!("{}\n\n{}", section, summary),
+            None => format!("## Release Notes\n\n{}", summary),
+        });
+    }
+
     info!(
         "✓ Successfully updated {} from {} to {}",
         attr_path, metadata.version, new_version
     );
 
+    let outcome = UpdateOutcome {
+        test_results,
+        system_results,
+        release_notes,
+        source_verification,
+        provenance,
+        commit_steps: steps,
+        python_dependencies,
+        nixpkgs_comparison,
+        nixpkgs_sync: None,
+        out_paths: hook_context.out_paths,
+        insecure_markings,
+        rebased_patches,
+    };
+
     // Handle commit and PR creation
     if create_pr {
-        // Get PR configuration - use CLI override or auto-detect from git
-        let pr_config = if let Some(remote_name) = upstream {
-            crate::git::get_pr_config_from_remote(&remote_name).await?
+        create_update_pr(
+            &attr_path,
+            &metadata.version,
+            &new_version,
+            None,
+            &update_trailers(&[(&attr_path, &metadata.version, &new_version)]),
+            &outcome,
+            &metadata,
+            options,
+        )
+        .await?;
+    } else if commit {
+        // Just create a commit without PR
+        let commit_message = update_commit_message(
+            &attr_path,
+            &metadata.version,
+            &new_version,
+            &outcome.test_results,
+        );
+        if outcome.commit_steps.is_empty() {
+            create_git_commit(&commit_message).await?;
         } else {
-            get_pr_config_from_git().await?
-        };
+            commit_steps(Path::new("."), &outcome.commit_steps).await?;
+        }
+    }
+
+    Ok(outcome)
+}
 
-        // Get GitHub token from environment
-        let github_token = std::env::var("GITHUB_TOKEN").context(
-            "GITHUB_TOKEN environment variable is required for PR creation. Set it with: export \
-             GITHUB_TOKEN=your_token_here",
-        )?;
+/// Commit, and optionally open a pull request for, the changes made by an update script
+///
+/// Uses the commits reported by scripts supporting the `commit` feature, otherwise compares the
+/// package version before and after the script ran.
+async fn commit_script_update(
+    eval_entry_point: &str,
+    attr_path: &str,
+    script_result: UpdateScriptResult,
+    options: &UpdateOptions,
+) -> anyhow::Result<()> {
+    let metadata = PackageMetadata::from_attr_path(eval_entry_point, attr_path).await?;
 
-        info!("Creating pull request for {}", attr_path);
+    let commits = if script_result.commits.is_empty() {
+        if metadata.version == script_result.old_version {
+            info!("Update script made no version change for {}", attr_path);
+            return Ok(());
+        }
+        vec![ScriptCommit {
+            attr_path: attr_path.to_string(),
+            old_version: script_result.old_version,
+            new_version: metadata.version.clone(),
+            files: Vec::new(),
+            commit_message: None,
+            commit_body: None,
+        }]
+    } else {
+        script_result.commits
+    };
 
-        // Create branch name
-        let sanitized_attr = attr_path.replace(['.', '/'], "-");
-        let branch_name = format!("update/{}/{}", sanitized_attr, new_version);
+    let commit_message = script_commit_message(&commits);
+    let changes: Vec<(&str, &str, &str)> = commits
+        .iter()
+        .map(|c| {
+            (
+                c.attr_path.as_str(),
+                c.old_version.as_str(),
+                c.new_version.as_str(),
+            )
+        })
+        .collect();
+    let trailers = update_trailers(&changes);
+    let (old_version, new_version) = (&commits[0].old_version, &commits[0].new_version);
+
+    if options.create_pr {
+        create_update_pr(
+            attr_path,
+            old_version,
+            new_version,
+            Some(&commit_message),
+            &trailers,
+            &UpdateOutcome::default(),
+            &metadata,
+            options,
+        )
+        .await
+    } else {
+        create_git_commit(&format!("{}\n\n{}", commit_message, trailers)).await
+    }
+}
 
-        // Create new branch
-        debug!("Creating branch '{}'", branch_name);
-        let output = Command::new("git")
-            .args(["checkout", "-b", &branch_name])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .await?;
+/// Commit message for the commits reported by an update script
+///
+/// Scripts updating several attributes at once are committed together, listing every change.
+fn script_commit_message(commits: &[ScriptCommit]) -> String {
+    match commits {
+        [commit] => commit.message(),
+        _ => {
+            let changes: Vec<String> = commits
+                .iter()
+                .map(|c| format!("- {}: {} -> {}", c.attr_path, c.old_version, c.new_version))
+                .collect();
+            format!(
+                "Update {} packages\n\n{}",
+                commits.len(),
+                changes.join("\n")
+            )
+        },
+    }
+}
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("Failed to create branch '{}': {}", branch_name, stderr);
-        }
+/// Create a branch with all changes committed, push it and open a pull request
+///
+/// `commit_message` overrides the default commit message, e.g. with the message reported by an
+/// update script. `trailers` are appended to the commit message, see [`update_trailers`].
+#[allow(clippy::too_many_arguments)]
+pub async fn create_update_pr(
+    attr_path: &str,
+    old_version: &str,
+    new_version: &str,
+    commit_message: Option<&str>,
+    trailers: &str,
+    outcome: &UpdateOutcome,
+    metadata: &PackageMetadata,
+    options: &UpdateOptions,
+) -> anyhow::Result<()> {
+    let package_config = options.config.package(attr_path);
+    let fork = package_config.fork(&options.fork);
+
+    // Get PR configuration - use CLI override or auto-detect from git
+    let pr_config = if let Some(remote_name) = options.upstream.as_deref() {
+        crate::git::get_pr_config_from_remote(remote_name).await?
+    } else {
+        get_pr_config_from_git().await?
+    };
+    let pr_config = package_config.pr_config(&pr_config);
+
+    // Get GitHub token from environment
+    let github_token = std::env::var("GITHUB_TOKEN").context(
+        "GITHUB_TOKEN environment variable is required for PR creation. Set it with: export \
+         GITHUB_TOKEN=your_token_here",
+    )?;
+
+    info!("Creating pull request for {}", attr_path);
+
+    // Create branch name
+    let sanitized_attr = attr_path.replace(['.', '/'], "-");
+    let branch_name = format!("update/{}/{}", sanitized_attr, new_version);
+
+    // Create new branch
+    debug!("Creating branch '{}'", branch_name);
+    let output = Command::new("git")
+        .args(["checkout", "-b", &branch_name])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Failed to create branch '{}': {}", branch_name, stderr);
+    }
 
+    if !outcome.commit_steps.is_empty() {
+        commit_steps(Path::new("."), &outcome.commit_steps).await?;
+    } else {
         // Stage all changes
         debug!("Staging changes");
         let output = Command::new("git")
@@ -817,23 +2625,27 @@ pub async fn update_from_file_path(
         }
 
         // Create commit with bot signature
-        let commit_message = if tests_passed {
-            format!(
-                "Update {} from {} to {}\n\nTests: passthru.tests passed\n\n🤖 Generated with \
-                 ekapkgs-update\n\nCo-Authored-By: ekapkgs-update <noreply@ekapkgs.org>",
-                attr_path, metadata.version, new_version
-            )
-        } else {
-            format!(
-                "Update {} from {} to {}\n\n🤖 Generated with ekapkgs-update\n\nCo-Authored-By: \
-                 ekapkgs-update <noreply@ekapkgs.org>",
-                attr_path, metadata.version, new_version
-            )
+        let tests_trailer = passthru_tests::tests_trailer(&outcome.test_results);
+        let commit_message = match (commit_message, tests_trailer) {
+            (Some(message), _) => message.to_string(),
+            (None, Some(tests_trailer)) => format!(
+                "Update {} from {} to {}\n\n{}",
+                attr_path, old_version, new_version, tests_trailer
+            ),
+            (None, None) => format!(
+                "Update {} from {} to {}",
+                attr_path, old_version, new_version
+            ),
         };
+        let commit_message = format!(
+            "{}\n\n🤖 Generated with ekapkgs-update\n\nCo-Authored-By: ekapkgs-update \
+             <noreply@ekapkgs.org>\n{}",
+            commit_message, trailers
+        );
 
         debug!("Creating commit");
-        let output = Command::new("git")
-            .args(["commit", "-m", &commit_message])
+        let output = git_commit_command()
+            .args(["-m", &commit_message])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .output()
@@ -843,66 +2655,92 @@ pub async fn update_from_file_path(
             let stderr = String::from_utf8_lossy(&output.stderr);
             anyhow::bail!("Failed to commit changes: {}", stderr);
         }
+    }
 
-        // Push to remote
-        debug!("Pushing branch to remote");
-        let push_target = format!("{}:{}", branch_name, branch_name);
-        let output = Command::new("git")
-            .args(["push", "-u", &fork, &push_target])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .await?;
+    // Push to remote
+    debug!("Pushing branch to remote");
+    let push_target = format!("{}:{}", branch_name, branch_name);
+    let output = Command::new("git")
+        .args(["push", "-u", fork, &push_target])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!(
-                "Failed to push branch '{}' to remote '{}': {}",
-                branch_name,
-                fork,
-                stderr
-            );
-        }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!(
+            "Failed to push branch '{}' to remote '{}': {}",
+            branch_name,
+            fork,
+            stderr
+        );
+    }
 
-        info!("Pushed branch '{}' to remote", branch_name);
+    info!("Pushed branch '{}' to remote", branch_name);
 
-        // Create pull request
-        let pr_title = format!("{}: {} -> {}", attr_path, metadata.version, new_version);
-        let mut pr_body = format!(
-            "## Update {}\n\nUpdates from version {} to {}.",
-            attr_path, metadata.version, new_version
-        );
+    // Create pull request
+    let pr_title = format!("{}: {} -> {}", attr_path, old_version, new_version);
+    let mut pr_body = format!(
+        "## Update {}\n\nUpdates from version {} to {}.",
+        attr_path, old_version, new_version
+    );
 
-        // Add optional metadata fields
-        if let Some(description) = metadata.description.as_ref() {
-            pr_body.push_str(&format!("\n\n**Description:** {}", description));
-        }
-        if let Some(homepage) = metadata.homepage.as_ref() {
-            pr_body.push_str(&format!("\n\n**Homepage:** {}", homepage));
-        }
-        if let Some(changelog) = metadata.changelog.as_ref() {
-            pr_body.push_str(&format!("\n\n**Changelog:** {}", changelog));
-        }
+    // Add optional metadata fields
+    if let Some(description) = metadata.description.as_ref() {
+        pr_body.push_str(&format!("\n\n**Description:** {}", description));
+    }
+    if let Some(homepage) = metadata.homepage.as_ref() {
+        pr_body.push_str(&format!("\n\n**Homepage:** {}", homepage));
+    }
+    if let Some(changelog) = metadata.changelog.as_ref() {
+        pr_body.push_str(&format!(
+            "\n\n**Changelog:** {}",
+            replace_version(changelog, old_version, new_version)
+        ));
+    }
 
-        pr_body.push_str("\n\n🤖 Generated with ekapkgs-update");
+    for section in outcome.report_sections() {
+        pr_body.push_str(&format!("\n\n{}", section));
+    }
 
-        debug!("Creating pull request");
-        let pr = github::create_pull_request(
-            &pr_config.owner,
-            &pr_config.repo,
-            &pr_title,
-            &pr_body,
-            &branch_name,
-            &pr_config.base_branch,
-            &github_token,
-        )
-        .await?;
+    if let Some(mentions) =
+        github::mention_maintainers(&metadata.maintainers, &options.maintainer_opt_out)
+    {
+        pr_body.push_str(&format!("\n\n{}", mentions));
+    }
 
-        info!("✓ Created pull request: {}", pr.html_url);
-        println!("Pull request created: {}", pr.html_url);
-    } else if commit {
-        // Just create a commit without PR
-        create_git_commit(&attr_path, &metadata.version, &new_version, tests_passed).await?;
+    pr_body.push_str("\n\n🤖 Generated with ekapkgs-update");
+
+    debug!("Creating pull request");
+    let pr = github::create_pull_request(
+        &pr_config.owner,
+        &pr_config.repo,
+        &pr_title,
+        &pr_body,
+        &crate::git::pr_head(fork, &branch_name, &pr_config).await,
+        &pr_config.base_branch,
+        &github_token,
+    )
+    .await?;
+
+    info!("✓ Created pull request: {}", pr.html_url);
+    println!("Pull request created: {}", pr.html_url);
+
+    let context = HookContext {
+        out_paths: outcome.out_paths.clone(),
+        pr_url: Some(pr.html_url),
+        ..HookContext::new(attr_path, old_version, new_version)
+    };
+    if let Err(e) = run_hook(
+        &package_config.hooks,
+        HookStage::PostPr,
+        &context,
+        Path::new("."),
+    )
+    .await
+    {
+        warn!("{}: {:#}", attr_path, e);
     }
 
     Ok(())
@@ -912,6 +2750,93 @@ pub async fn update_from_file_path(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_is_distinctive_version() {
+        assert!(is_distinctive_version("2.12"));
+        assert!(is_distinctive_version("1.2.3"));
+        assert!(is_distinctive_version("2024-05-01"));
+        assert!(!is_distinctive_version("24"));
+        assert!(!is_distinctive_version("2024"));
+        assert!(!is_distinctive_version("1.0"));
+    }
+
+    #[test]
+    fn test_version_occurrence_files() {
+        assert!(owns_directory(Path::new(
+            "pkgs/by-name/he/hello/package.nix"
+        )));
+        assert!(owns_directory(Path::new("pkgs/tools/foo/default.nix")));
+        assert!(!owns_directory(Path::new("pkgs/tools/misc/foo.nix")));
+        // Sidecar files only own directories defining a package
+        assert!(!owns_directory(Path::new(
+            "/nonexistent/pkgs/foo/version.json"
+        )));
+
+        assert!(keeps_old_version(Path::new("pkgs/foo/fix-build.patch")));
+        assert!(keeps_old_version(Path::new("pkgs/foo/Cargo.lock")));
+        assert!(keeps_old_version(Path::new("pkgs/foo/package-lock.json")));
+        assert!(!keeps_old_version(Path::new("pkgs/foo/module.nix")));
+        assert!(!keeps_old_version(Path::new("pkgs/foo/deps.json")));
+    }
+
+    #[test]
+    fn test_parse_attrs_file() {
+        let content =
+            "hello\n\n# Python packages\npython3Packages.requests  # pinned by foo\n  jq\n";
+        assert_eq!(
+            parse_attrs_file(content),
+            vec!["hello", "python3Packages.requests", "jq"]
+        );
+
+        let expr = package_versions_expr("default.nix", &["python3Packages.requests".to_string()]);
+        assert!(expr.contains(
+            r#""python3Packages.requests" = get (pkgs.python3Packages.requests.version or"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_dependency_hash_maven() {
+        let old_hash = "sha256-kLaZ2ZY7yyXbFqUwbLf/4b1bmOL8b7kNzQYUfZ8V1UE=";
+        let new_hash = "sha256-2QZ2kMbmDR2XGQhqbpJq0B7Y6kzPHJEv3nS7xTm2Yvc=";
+        let file_path = std::env::temp_dir().join(format!(
+            "ekapkgs-update-{}-maven-package.nix",
+            std::process::id()
+        ));
+        let content = format!(
+            r#"{{ lib, maven, fetchFromGitHub }}:
+
+maven.buildMavenPackage rec {{
+  pname = "jd-cli";
+  version = "1.2.1";
+
+  src = fetchFromGitHub {{
+    owner = "intoolswetrust";
+    repo = "jd-cli";
+    rev = "jd-cli-${{version}}";
+    hash = "sha256-rRttA5H0A0c44loBzbKH7Waoted3IsOgxGCD2VM0U/Q=";
+  }};
+
+  mvnHash = "{}";
+}}
+"#,
+            old_hash
+        );
+        tokio::fs::write(&file_path, &content).await.unwrap();
+
+        let maven = DependencyHashAttr::defaults()
+            .into_iter()
+            .find(|a| a.eval_attr == "fetchedMavenDeps.outputHash")
+            .unwrap();
+        let rewrite_attrs: Vec<&str> = maven.rewrite_attrs.iter().map(String::as_str).collect();
+        let file = file_path.to_str().unwrap();
+        let result = update_dependency_hash(file, &rewrite_attrs, old_hash, new_hash).await;
+        let updated = tokio::fs::read_to_string(&file_path).await.unwrap();
+        tokio::fs::remove_file(&file_path).await.unwrap();
+
+        result.unwrap();
+        assert_eq!(updated, content.replace(old_hash, new_hash));
+    }
+
     #[test]
     fn test_extract_hash_from_error() {
         let stderr = r#"
@@ -1016,4 +2941,61 @@ Reversed (or previously applied) patch detected!  Skipping patch.
         };
         assert_eq!(normalized4, "../other/default.nix");
     }
+
+    #[test]
+    fn test_interpolated_src_url() {
+        let old = "https://example.com/download/v1.0.0/foo-1.0.0.tar.gz";
+        let new = "https://example.com/download/v2.0.0/foo-2.0.0.tar.gz";
+        assert_eq!(
+            interpolated_src_url(Some(old), Some(new), "2.0.0"),
+            Some(new)
+        );
+
+        // First mirror of src.urls
+        let urls = "https://a.example.com/foo-2.0.0.tar.gz https://b.example.com/foo-2.0.0.tar.gz";
+        assert_eq!(
+            interpolated_src_url(Some(old), Some(urls), "2.0.0"),
+            Some("https://a.example.com/foo-2.0.0.tar.gz")
+        );
+    }
+
+    #[test]
+    fn test_interpolated_src_url_skipped() {
+        let url = "https://example.com/foo-1.0.0.tar.gz";
+        // Unchanged URL
+        assert_eq!(interpolated_src_url(Some(url), Some(url), "2.0.0"), None);
+        // Version not part of the URL
+        assert_eq!(
+            interpolated_src_url(
+                Some(url),
+                Some("https://example.com/latest.tar.gz"),
+                "2.0.0"
+            ),
+            None
+        );
+        // Not fetched over HTTP
+        assert_eq!(
+            interpolated_src_url(None, Some("git://example.com/foo-2.0.0"), "2.0.0"),
+            None
+        );
+        assert_eq!(interpolated_src_url(Some(url), None, "2.0.0"), None);
+    }
+
+    #[test]
+    fn test_script_commit_message() {
+        let commit = |attr: &str| ScriptCommit {
+            attr_path: attr.to_string(),
+            old_version: "1.0".to_string(),
+            new_version: "1.1".to_string(),
+            files: Vec::new(),
+            commit_message: None,
+            commit_body: None,
+        };
+
+        assert_eq!(script_commit_message(&[commit("foo")]), "foo: 1.0 -> 1.1");
+        assert_eq!(
+            script_commit_message(&[commit("foo"), commit("bar")]),
+            "Update 2 packages\n\n- foo: 1.0 -> 1.1\n- bar: 1.0 -> 1.1"
+        );
+    }
 }
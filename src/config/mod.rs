@@ -0,0 +1,388 @@
+//! Per-package configuration
+//!
+//! Settings which only apply to some packages are read from a TOML file, keyed by attribute
+//! path:
+//!
+//! ```toml
+//! [packages.gh]
+//! tag_prefix = "cli/v"
+//!
+//! [packages."python3Packages.component-a"]
+//! tag_regex = '^componentA-(.+)$'
+//!
+//! [packages.yt-dlp]
+//! version_scheme = "calver"
+//! min_release_age = 7
+//!
+//! [packages.nodejs]
+//! release_listing = "tags"
+//!
+//! [packages.foo]
+//! source_command = ["./scripts/foo-versions"]
+//! ```
+//!
+//! Top-level settings like `min_release_age = 3` apply to every package which doesn't override
+//! them. `worktree_dir = "/tmp/ekapkgs-update"` moves the worktrees of updates out of the cache
+//! directory. `nixpkgs = "<nixpkgs>"` or a path to a checkout mentions the nixpkgs version of
+//! packages in reports and PR bodies.
+//!
+//! `[rate_limits]` caps the requests per second sent to each host, e.g. `"api.github.com" = 10`,
+//! with `"*"` applying to every other host. `proxy = "http://proxy:3128"` and
+//! `ca_bundle = "/etc/ssl/corporate.pem"` configure the requests going through an egress proxy.
+//!
+//! `update_version_occurrences = true` in the settings of a package replaces its old version in
+//! every text file of its directory, not only where the version is defined. Only packages defined
+//! in a `default.nix` or `package.nix` own their directory, the others, packages at the root of
+//! the tree, short versions like `24` and versions found in more than 20 files are left alone, as
+//! are patches and lockfiles.
+//!
+//! Fetched sources are checked against the provenance attestations their upstream publishes,
+//! for GitHub release assets, npm packages and PyPI files. `require_provenance = true` in the
+//! settings of a package fails its updates unless the provenance of the new source is verified,
+//! which only GitHub artifact attestations checked by `gh` are. `skip_source_verification = true`
+//! stops probing upstreams for checksums, signatures and attestations, except for the packages
+//! requiring provenance.
+//!
+//! `build_timeout = 7200` in the settings of a package overrides `--build-timeout`, in seconds,
+//! for packages taking longer to build than the others. It also limits their update script.
+//!
+//! `[hooks]` and `[packages.<attr>.hooks]` declare commands run at the stages of updates, see
+//! [`crate::hooks`].
+//!
+//! `[packages.<attr>.pull_request]` opens the PRs of a package vendored from another repository
+//! there, overriding the `owner`, `repo` and `base_branch` of the upstream remote. `fork` names
+//! the git remote its branches are pushed to instead of `--fork`, a fork of that repository:
+//!
+//! ```toml
+//! [packages.foo.pull_request]
+//! owner = "foo-org"
+//! repo = "foo-overlay"
+//! base_branch = "release"
+//! fork = "foo-fork"
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Context;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::git::PrConfig;
+use crate::hooks::Hooks;
+use crate::vcs_sources::{ReleaseListing, TagFilter, VersionSchemeKind};
+
+/// Settings of a single package
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackageConfig {
+    /// Only consider tags starting with this prefix, e.g. `cli/v` in monorepos
+    pub tag_prefix: Option<String>,
+    /// Only consider tags matching this regex. The first capture group, if any, is the version
+    pub tag_regex: Option<String>,
+    /// How versions are compared: `auto` (default), `semver`, `calver`, `pep440`, `debian` or
+    /// `numeric`
+    #[serde(default)]
+    pub version_scheme: VersionSchemeKind,
+    /// Where versions of GitHub and GitLab sources are looked up: `releases` (default, falling
+    /// back to tags), `tags` or `merged`
+    #[serde(default)]
+    pub release_listing: ReleaseListing,
+    /// Days a release must have been published for before it is proposed
+    pub min_release_age: Option<u64>,
+    /// Command listing the versions of the package, see [`crate::plugin`]
+    pub source_command: Option<Vec<String>>,
+    /// Hooks of the package, replacing the hooks of every package
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Repository the PRs of the package are opened against
+    #[serde(default)]
+    pub pull_request: PullRequestTarget,
+    /// Also replace the old version in the other files of the package's directory, e.g. module
+    /// defaults, test fixtures or lock metadata
+    #[serde(default)]
+    pub update_version_occurrences: bool,
+    /// Fail updates whose fetched source has no verified provenance attestation
+    #[serde(default)]
+    pub require_provenance: bool,
+    /// Seconds a single build of the package may take, instead of `--build-timeout`
+    pub build_timeout: Option<u64>,
+    /// Version the package must be updated to, set when applying an update plan
+    #[serde(skip)]
+    pub pinned_version: Option<String>,
+}
+
+impl PackageConfig {
+    /// Filter selecting the tags of this package
+    pub fn tag_filter(&self) -> anyhow::Result<TagFilter> {
+        let regex = self
+            .tag_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid tag_regex")?;
+        Ok(TagFilter::new(self.tag_prefix.clone(), regex).pinned(self.pinned_version.clone()))
+    }
+
+    /// Minimum age of proposed releases, None if releases are proposed right away
+    pub fn min_release_age(&self) -> Option<chrono::Duration> {
+        self.min_release_age
+            .filter(|days| *days > 0)
+            .map(|days| chrono::Duration::days(days as i64))
+    }
+
+    /// Time a single build of the package may take, None to use the global timeout
+    pub fn build_timeout(&self) -> Option<std::time::Duration> {
+        self.build_timeout.map(std::time::Duration::from_secs)
+    }
+
+    /// PR configuration of the package, `upstream` with the overrides of its target
+    pub fn pr_config(&self, upstream: &PrConfig) -> PrConfig {
+        let target = &self.pull_request;
+        PrConfig {
+            remote: upstream.remote.clone(),
+            owner: target
+                .owner
+                .clone()
+                .unwrap_or_else(|| upstream.owner.clone()),
+            repo: target.repo.clone().unwrap_or_else(|| upstream.repo.clone()),
+            base_branch: target
+                .base_branch
+                .clone()
+                .unwrap_or_else(|| upstream.base_branch.clone()),
+        }
+    }
+
+    /// Git remote the branches of the package are pushed to, `fork` unless overridden
+    pub fn fork<'a>(&'a self, fork: &'a str) -> &'a str {
+        self.pull_request.fork.as_deref().unwrap_or(fork)
+    }
+}
+
+/// Repository to open the PRs of a package against, the upstream remote for unset fields
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PullRequestTarget {
+    /// Owner of the repository
+    pub owner: Option<String>,
+    /// Name of the repository
+    pub repo: Option<String>,
+    /// Branch the PRs are merged into
+    pub base_branch: Option<String>,
+    /// Git remote of a fork of the repository to push the branches to
+    pub fork: Option<String>,
+}
+
+/// Configuration of every package with non-default settings
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Default of [`PackageConfig::min_release_age`]
+    pub min_release_age: Option<u64>,
+    /// Directory to create worktrees in instead of the cache directory
+    pub worktree_dir: Option<String>,
+    /// nixpkgs checkout or channel to compare the versions of packages with
+    pub nixpkgs: Option<String>,
+    /// Requests per second allowed to each host, `"*"` for any other host
+    #[serde(default)]
+    pub rate_limits: HashMap<String, f64>,
+    /// Proxy of every HTTP(S) request, instead of the `HTTPS_PROXY` environment variable
+    pub proxy: Option<String>,
+    /// PEM file of certificates to trust in addition to the system ones
+    pub ca_bundle: Option<String>,
+    /// Hooks of every package
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Don't look for checksums, signatures and provenance attestations of fetched sources
+    #[serde(default)]
+    pub skip_source_verification: bool,
+    #[serde(default)]
+    packages: HashMap<String, PackageConfig>,
+}
+
+impl Config {
+    /// Parse the contents of a configuration file
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        for (attr_path, package) in &config.packages {
+            package
+                .tag_filter()
+                .with_context(|| format!("Invalid configuration of {}", attr_path))?;
+            if package
+                .source_command
+                .as_ref()
+                .is_some_and(|command| command.is_empty())
+            {
+                anyhow::bail!("Empty source_command of {}", attr_path);
+            }
+            package
+                .hooks
+                .validate()
+                .with_context(|| format!("Invalid configuration of {}", attr_path))?;
+        }
+        config.hooks.validate()?;
+        for (host, per_second) in &config.rate_limits {
+            if per_second.is_nan() || *per_second <= 0.0 {
+                anyhow::bail!("Invalid rate limit of {}: {}", host, per_second);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read a configuration file
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Settings of a package, the defaults if it isn't configured
+    pub fn package(&self, attr_path: &str) -> PackageConfig {
+        let mut package = self.packages.get(attr_path).cloned().unwrap_or_default();
+        package.min_release_age = package.min_release_age.or(self.min_release_age);
+        package.hooks = package.hooks.or(&self.hooks);
+        package
+    }
+
+    /// Only update `attr_path` to `version`
+    pub fn pin_version(&mut self, attr_path: &str, version: &str) {
+        self.packages
+            .entry(attr_path.to_string())
+            .or_default()
+            .pinned_version = Some(version.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            min_release_age = 3
+            worktree_dir = "/scratch/worktrees"
+            nixpkgs = "<nixpkgs>"
+            proxy = "http://proxy.example.org:3128"
+            skip_source_verification = true
+
+            [rate_limits]
+            "api.github.com" = 10
+            "*" = 2.5
+
+            [hooks]
+            post_pr = ["./ci/rebuild-images"]
+
+            [packages.gh]
+            tag_prefix = "cli/v"
+            release_listing = "merged"
+
+            [packages.foo]
+            source_command = ["./scripts/foo-versions", "--stable"]
+            update_version_occurrences = true
+            require_provenance = true
+            build_timeout = 7200
+
+            [packages.foo.hooks]
+            pre_rewrite = ["./ci/check-freeze"]
+
+            [packages.foo.pull_request]
+            repo = "foo-overlay"
+            base_branch = "release"
+            fork = "foo-fork"
+
+            [packages."python3Packages.component-a"]
+            tag_regex = '^componentA-(.+)$'
+            version_scheme = "calver"
+            min_release_age = 0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.package("gh").tag_prefix.as_deref(), Some("cli/v"));
+        assert_eq!(config.package("gh").release_listing, ReleaseListing::Merged);
+        assert!(
+            config
+                .package("python3Packages.component-a")
+                .tag_regex
+                .is_some()
+        );
+        assert_eq!(
+            config.package("python3Packages.component-a").version_scheme,
+            VersionSchemeKind::Calver
+        );
+        assert_eq!(
+            config.package("gh").min_release_age(),
+            Some(chrono::Duration::days(3))
+        );
+        assert_eq!(
+            config
+                .package("python3Packages.component-a")
+                .min_release_age(),
+            None
+        );
+        assert_eq!(config.package("hello").min_release_age, Some(3));
+        assert_eq!(config.worktree_dir.as_deref(), Some("/scratch/worktrees"));
+        assert!(config.skip_source_verification);
+        assert_eq!(config.nixpkgs.as_deref(), Some("<nixpkgs>"));
+        assert_eq!(config.rate_limits.get("*"), Some(&2.5));
+        assert_eq!(
+            config.proxy.as_deref(),
+            Some("http://proxy.example.org:3128")
+        );
+        assert_eq!(
+            config.package("foo").source_command,
+            Some(vec![
+                "./scripts/foo-versions".to_string(),
+                "--stable".to_string()
+            ])
+        );
+        assert_eq!(
+            config.package("foo").hooks.pre_rewrite,
+            Some(vec!["./ci/check-freeze".to_string()])
+        );
+        assert_eq!(
+            config.package("hello").hooks.post_pr,
+            Some(vec!["./ci/rebuild-images".to_string()])
+        );
+        assert_eq!(Config::default().package("hello"), PackageConfig::default());
+
+        assert!(config.package("foo").update_version_occurrences);
+        assert!(!config.package("gh").update_version_occurrences);
+        assert!(config.package("foo").require_provenance);
+        assert!(!config.package("gh").require_provenance);
+        assert_eq!(
+            config.package("foo").build_timeout(),
+            Some(std::time::Duration::from_secs(7200))
+        );
+        assert_eq!(config.package("gh").build_timeout(), None);
+
+        let upstream = PrConfig {
+            remote: "upstream".to_string(),
+            owner: "ekala-project".to_string(),
+            repo: "ekapkgs".to_string(),
+            base_branch: "master".to_string(),
+        };
+        let pr_config = config.package("foo").pr_config(&upstream);
+        assert_eq!(
+            (
+                pr_config.remote.as_str(),
+                pr_config.owner.as_str(),
+                pr_config.repo.as_str(),
+                pr_config.base_branch.as_str()
+            ),
+            ("upstream", "ekala-project", "foo-overlay", "release")
+        );
+        assert_eq!(config.package("gh").pr_config(&upstream).repo, "ekapkgs");
+        assert_eq!(config.package("foo").fork("origin"), "foo-fork");
+        assert_eq!(config.package("gh").fork("origin"), "origin");
+
+        assert!(Config::parse("[packages.foo]\ntag_regex = '('\n").is_err());
+        assert!(Config::parse("[packages.foo]\nunknown = 1\n").is_err());
+        assert!(Config::parse("[rate_limits]\n\"pypi.org\" = 0\n").is_err());
+        assert!(Config::parse("[packages.foo]\nsource_command = []\n").is_err());
+        assert!(Config::parse("[hooks]\npost_build = []\n").is_err());
+    }
+}
@@ -1,3 +1,10 @@
+//! Record of update attempts
+//!
+//! A SQLite database tracks, per package, the last update attempt, the version proposed and when
+//! to try again, with an exponential backoff after failures. The logs of failed builds, update
+//! scripts and passthru tests, the time spent in each phase of an update and the packages failing
+//! to evaluate are kept alongside, as are the version changes and PRs of each package.
+
 use std::path::Path;
 use std::str::FromStr;
 
@@ -7,31 +14,78 @@ use sqlx::Row;
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
 use tracing::{debug, info};
 
+use crate::nix::passthru_tests::PassthruTestResult;
+use crate::osv::SecurityStatus;
+use crate::timings::PhaseTimings;
+
 /// Represents a package update record in the database
 #[derive(Debug, Clone)]
 pub struct UpdateRecord {
-    pub _attr_path: String,
+    /// Attribute path of the package
+    pub attr_path: String,
+    /// When an update of the package was last attempted
     pub last_attempted: Option<DateTime<Utc>>,
+    /// Earliest time to attempt the next update, None to attempt it right away
     pub next_attempt: Option<DateTime<Utc>>,
-    pub _current_version: Option<String>,
+    /// Version of the package when it was last attempted
+    pub current_version: Option<String>,
+    /// Version the package was updated to, if the update was proposed
     pub proposed_version: Option<String>,
-    pub _latest_upstream_version: Option<String>,
+    /// Latest version found upstream
+    pub latest_upstream_version: Option<String>,
+    /// Strategy to attempt the next update with, see [`crate::retry::RetryStrategy`]
+    pub retry_strategy: Option<String>,
 }
 
 /// Represents a failed update log entry in the database
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct UpdateLog {
+    /// Derivation of the package the update was attempted on
     pub drv_path: String,
+    /// Attribute path of the package
     pub attr_path: String,
+    /// When the attempt was made, in RFC 3339
     pub timestamp: String,
+    /// Outcome of the attempt, e.g. `failed`
     pub status: String,
+    /// Output of the failed build or rewrite
     pub error_log: String,
+    /// Version the package was updated from
     pub old_version: Option<String>,
+    /// Version the package was updated to
     pub new_version: Option<String>,
+    /// What the attempt failed on, e.g. `compile-error`, if it could be determined
+    pub failure_kind: Option<String>,
+    /// Retry strategy the attempt was made with, e.g. `minor`
+    pub retry_strategy: Option<String>,
 }
 
 impl UpdateLog {
-    /// Parse the timestamp string as a DateTime<Utc>
+    /// Parse the timestamp string as a `DateTime<Utc>`
+    pub fn timestamp_as_datetime(&self) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now())
+    }
+}
+
+/// Represents the captured output of an update script run
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UpdateScriptLog {
+    /// Attribute path of the package
+    pub attr_path: String,
+    /// When the script was run, in RFC 3339
+    pub timestamp: String,
+    /// Outcome of the run, e.g. `success`
+    pub status: String,
+    /// Standard output of the script
+    pub stdout: String,
+    /// Standard error of the script
+    pub stderr: String,
+}
+
+impl UpdateScriptLog {
+    /// Parse the timestamp string as a `DateTime<Utc>`
     pub fn timestamp_as_datetime(&self) -> DateTime<Utc> {
         DateTime::parse_from_rfc3339(&self.timestamp)
             .map(|dt| dt.with_timezone(&Utc))
@@ -39,6 +93,97 @@ impl UpdateLog {
     }
 }
 
+/// Represents the result of a single passthru test of an update
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PassthruTestRecord {
+    /// Version of the package the test was run on
+    pub version: String,
+    /// When the test was run, in RFC 3339
+    pub timestamp: String,
+    /// Name of the test in `passthru.tests`
+    pub test_name: String,
+    /// Outcome of the test, e.g. `passed`
+    pub status: String,
+}
+
+impl PassthruTestRecord {
+    /// Parse the timestamp string as a `DateTime<Utc>`
+    pub fn timestamp_as_datetime(&self) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now())
+    }
+}
+
+/// Time spent in each phase of the latest update attempt of a package
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PackageTimingRecord {
+    /// Attribute path of the package
+    pub attr_path: String,
+    /// Milliseconds spent evaluating the metadata of the package
+    pub metadata_eval_ms: i64,
+    /// Milliseconds spent looking up the releases of the source
+    pub upstream_fetch_ms: i64,
+    /// Milliseconds spent rewriting the files of the package
+    pub rewrite_ms: i64,
+    /// Milliseconds spent building the update
+    pub build_ms: i64,
+}
+
+impl PackageTimingRecord {
+    /// Milliseconds spent on the whole update attempt
+    pub fn total_ms(&self) -> i64 {
+        self.metadata_eval_ms + self.upstream_fetch_ms + self.rewrite_ms + self.build_ms
+    }
+}
+
+/// Change in the history of a package
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UpdateEvent {
+    /// When the change happened, in RFC 3339
+    pub timestamp: String,
+    /// What changed: `updated` by an update, `version_changed` outside of updates, e.g. by hand,
+    /// or `pr_created`
+    pub kind: String,
+    /// Version of the package before the change
+    pub old_version: Option<String>,
+    /// Version of the package after the change
+    pub new_version: Option<String>,
+    /// URL of the PR created
+    pub pr_url: Option<String>,
+    /// Number of the PR created
+    pub pr_number: Option<i64>,
+}
+
+/// Evaluation error of a package, reported by nix-eval-jobs
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct EvalErrorRecord {
+    /// Attribute path of the package
+    pub attr_path: String,
+    /// Latest error message
+    pub error: String,
+    /// When the package was first seen failing to evaluate, in RFC 3339
+    pub first_seen: String,
+    /// When the package was last seen failing to evaluate, in RFC 3339
+    pub last_seen: String,
+}
+
+impl EvalErrorRecord {
+    /// Parse the first_seen string as a `DateTime<Utc>`
+    pub fn first_seen_as_datetime(&self) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&self.first_seen)
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now())
+    }
+
+    /// Parse the last_seen string as a `DateTime<Utc>`
+    pub fn last_seen_as_datetime(&self) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&self.last_seen)
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now())
+    }
+}
+
 /// Database connection wrapper for tracking package updates
 #[derive(Clone)]
 pub struct Database {
@@ -78,12 +223,17 @@ impl Database {
         Ok(Self { pool })
     }
 
+    /// Close the connections, checkpointing the write-ahead log
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
     /// Get an update record for a specific package
     pub async fn get_update_record(&self, attr_path: &str) -> Result<Option<UpdateRecord>> {
         let row = sqlx::query(
             r#"
             SELECT attr_path, last_attempted, next_attempt, current_version,
-                   proposed_version, latest_upstream_version
+                   proposed_version, latest_upstream_version, retry_strategy
             FROM updates
             WHERE attr_path = ?
             "#,
@@ -98,16 +248,17 @@ impl Database {
                 let next_attempt: Option<String> = row.try_get("next_attempt")?;
 
                 Ok(Some(UpdateRecord {
-                    _attr_path: row.try_get("attr_path")?,
+                    attr_path: row.try_get("attr_path")?,
                     last_attempted: last_attempted
                         .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
                         .map(|dt| dt.with_timezone(&Utc)),
                     next_attempt: next_attempt
                         .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
                         .map(|dt| dt.with_timezone(&Utc)),
-                    _current_version: row.try_get("current_version")?,
+                    current_version: row.try_get("current_version")?,
                     proposed_version: row.try_get("proposed_version")?,
-                    _latest_upstream_version: row.try_get("latest_upstream_version")?,
+                    latest_upstream_version: row.try_get("latest_upstream_version")?,
+                    retry_strategy: row.try_get("retry_strategy")?,
                 }))
             },
             None => Ok(None),
@@ -154,6 +305,8 @@ impl Database {
 
     /// Record that no update was available for a package
     /// Implements backoff: 2 days -> 4 days -> 6 days (max)
+    ///
+    /// Failed attempts stop counting as consecutive, the package no longer needs an update.
     pub async fn record_no_update(
         &self,
         attr_path: &str,
@@ -185,6 +338,25 @@ impl Database {
 
         let next_attempt = now + Duration::days(backoff_days);
 
+        // The package was changed since the last check, e.g. by hand or by a merged PR
+        if let Some(previous) = record.as_ref().and_then(|r| r.current_version.as_deref()) {
+            if previous != current_version {
+                sqlx::query(
+                    r#"
+                    INSERT INTO update_events (attr_path, timestamp, kind, old_version, new_version)
+                    VALUES (?, ?, 'version_changed', ?, ?)
+                    "#,
+                )
+                .bind(attr_path)
+                .bind(now.to_rfc3339())
+                .bind(previous)
+                .bind(current_version)
+                .execute(&self.pool)
+                .await
+                .context("Failed to record version change")?;
+            }
+        }
+
         debug!(
             "{}: No update available, setting next_attempt to {} ({} days)",
             attr_path,
@@ -201,7 +373,8 @@ impl Database {
                 last_attempted = excluded.last_attempted,
                 next_attempt = excluded.next_attempt,
                 current_version = excluded.current_version,
-                latest_upstream_version = excluded.latest_upstream_version
+                latest_upstream_version = excluded.latest_upstream_version,
+                consecutive_failures = 0
             "#,
         )
         .bind(attr_path)
@@ -243,7 +416,9 @@ impl Database {
                 next_attempt = excluded.next_attempt,
                 current_version = excluded.current_version,
                 proposed_version = NULL,
-                latest_upstream_version = excluded.latest_upstream_version
+                latest_upstream_version = excluded.latest_upstream_version,
+                consecutive_failures = 0,
+                retry_strategy = NULL
             "#,
         )
         .bind(attr_path)
@@ -255,6 +430,20 @@ impl Database {
         .await
         .context("Failed to record successful update")?;
 
+        sqlx::query(
+            r#"
+            INSERT INTO update_events (attr_path, timestamp, kind, old_version, new_version)
+            VALUES (?, ?, 'updated', ?, ?)
+            "#,
+        )
+        .bind(attr_path)
+        .bind(now.to_rfc3339())
+        .bind(old_version)
+        .bind(new_version)
+        .execute(&self.pool)
+        .await
+        .context("Failed to record update event")?;
+
         Ok(())
     }
 
@@ -281,11 +470,27 @@ impl Database {
         .await
         .context("Failed to record PR info")?;
 
+        // PRs are created after the update was recorded, with the version it proposes
+        sqlx::query(
+            r#"
+            INSERT INTO update_events (attr_path, timestamp, kind, new_version, pr_url, pr_number)
+            SELECT ?1, ?2, 'pr_created',
+                   (SELECT current_version FROM updates WHERE attr_path = ?1), ?3, ?4
+            "#,
+        )
+        .bind(attr_path)
+        .bind(Utc::now().to_rfc3339())
+        .bind(pr_url)
+        .bind(pr_number)
+        .execute(&self.pool)
+        .await
+        .context("Failed to record PR event")?;
+
         Ok(())
     }
 
     /// Record a proposed update (update was made but not yet merged)
-    pub async fn _record_proposed_update(
+    pub async fn record_proposed_update(
         &self,
         attr_path: &str,
         current_version: &str,
@@ -327,7 +532,7 @@ impl Database {
     }
 
     /// Get statistics about tracked packages
-    pub async fn _get_statistics(&self) -> Result<_DatabaseStatistics> {
+    pub async fn get_statistics(&self) -> Result<DatabaseStatistics> {
         let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM updates")
             .fetch_one(&self.pool)
             .await?;
@@ -342,22 +547,41 @@ impl Database {
                 .fetch_one(&self.pool)
                 .await?;
 
-        Ok(_DatabaseStatistics {
+        let failures_by_kind: Vec<(String, i64)> = sqlx::query_as(
+            r#"
+            SELECT COALESCE(failure_kind, 'unknown'), COUNT(*)
+            FROM update_logs
+            WHERE status = 'failed'
+            GROUP BY 1
+            ORDER BY 2 DESC
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(DatabaseStatistics {
             total_packages: total,
             packages_with_proposed_updates: with_proposed,
             packages_in_backoff: in_backoff,
+            failures_by_kind,
         })
     }
 
-    /// Record a failed update attempt with error log
+    /// Record a failed update attempt with error log, what it failed on and the retry strategy
+    /// it was made with
+    ///
+    /// Returns the number of consecutive failed attempts of the package, including this one.
+    #[allow(clippy::too_many_arguments)]
     pub async fn record_failed_update(
         &self,
         drv_path: &str,
         attr_path: &str,
         error_log: &str,
+        failure_kind: Option<&str>,
+        retry_strategy: Option<&str>,
         old_version: Option<&str>,
         new_version: Option<&str>,
-    ) -> Result<()> {
+    ) -> Result<i64> {
         let now = Utc::now();
 
         debug!(
@@ -368,11 +592,13 @@ impl Database {
         sqlx::query(
             r#"
             INSERT INTO update_logs (drv_path, attr_path, timestamp, status, error_log,
-                                    old_version, new_version)
-            VALUES (?, ?, ?, 'failed', ?, ?, ?)
+                                    failure_kind, retry_strategy, old_version, new_version)
+            VALUES (?, ?, ?, 'failed', ?, ?, ?, ?, ?)
             ON CONFLICT(drv_path) DO UPDATE SET
                 timestamp = excluded.timestamp,
                 error_log = excluded.error_log,
+                failure_kind = excluded.failure_kind,
+                retry_strategy = excluded.retry_strategy,
                 old_version = excluded.old_version,
                 new_version = excluded.new_version
             "#,
@@ -381,21 +607,459 @@ impl Database {
         .bind(attr_path)
         .bind(now.to_rfc3339())
         .bind(error_log)
+        .bind(failure_kind)
+        .bind(retry_strategy)
         .bind(old_version)
         .bind(new_version)
         .execute(&self.pool)
         .await
         .context("Failed to record failed update")?;
 
+        let failures: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO updates (attr_path, consecutive_failures)
+            VALUES (?, 1)
+            ON CONFLICT(attr_path) DO UPDATE SET
+                consecutive_failures = consecutive_failures + 1
+            RETURNING consecutive_failures
+            "#,
+        )
+        .bind(attr_path)
+        .fetch_one(&self.pool)
+        .await
+        .context("Failed to count consecutive failures")?;
+
+        Ok(failures)
+    }
+
+    /// Set the retry strategy to attempt the next update of a package with
+    ///
+    /// Without a strategy left to try, the package is given up on for 6 days, after which
+    /// updates start over with the default strategy.
+    pub async fn schedule_retry(
+        &self,
+        attr_path: &str,
+        retry_strategy: Option<&str>,
+    ) -> Result<()> {
+        let next_attempt = retry_strategy
+            .is_none()
+            .then(|| (Utc::now() + Duration::days(6)).to_rfc3339());
+
+        sqlx::query(
+            r#"
+            INSERT INTO updates (attr_path, next_attempt, retry_strategy)
+            VALUES (?, ?, ?)
+            ON CONFLICT(attr_path) DO UPDATE SET
+                next_attempt = COALESCE(excluded.next_attempt, next_attempt),
+                retry_strategy = excluded.retry_strategy
+            "#,
+        )
+        .bind(attr_path)
+        .bind(next_attempt)
+        .bind(retry_strategy)
+        .execute(&self.pool)
+        .await
+        .context("Failed to schedule retry")?;
+
+        Ok(())
+    }
+
+    /// Number of the issue tracking the failing updates of a package, if one was opened
+    pub async fn get_failure_issue(&self, attr_path: &str) -> Result<Option<i64>> {
+        let number: Option<Option<i64>> =
+            sqlx::query_scalar("SELECT failure_issue_number FROM updates WHERE attr_path = ?")
+                .bind(attr_path)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(number.flatten())
+    }
+
+    /// Record the issue opened for the failing updates of a package
+    pub async fn record_failure_issue(&self, attr_path: &str, issue_number: i64) -> Result<()> {
+        sqlx::query("UPDATE updates SET failure_issue_number = ? WHERE attr_path = ?")
+            .bind(issue_number)
+            .bind(attr_path)
+            .execute(&self.pool)
+            .await
+            .context("Failed to record failure issue")?;
+        Ok(())
+    }
+
+    /// Record the output of an update script run
+    pub async fn record_update_script_log(
+        &self,
+        attr_path: &str,
+        success: bool,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<()> {
+        let now = Utc::now();
+        let status = if success { "success" } else { "failed" };
+
+        debug!("{}: Recording update script output ({})", attr_path, status);
+
+        sqlx::query(
+            r#"
+            INSERT INTO update_script_logs (attr_path, timestamp, status, stdout, stderr)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(attr_path)
+        .bind(now.to_rfc3339())
+        .bind(status)
+        .bind(stdout)
+        .bind(stderr)
+        .execute(&self.pool)
+        .await
+        .context("Failed to record update script output")?;
+
         Ok(())
     }
 
+    /// Get the most recent update script output for an attr_path
+    pub async fn get_latest_update_script_log(
+        &self,
+        attr_path: &str,
+    ) -> Result<Option<UpdateScriptLog>> {
+        let log = sqlx::query_as::<_, UpdateScriptLog>(
+            r#"
+            SELECT attr_path, timestamp, status, stdout, stderr
+            FROM update_script_logs
+            WHERE attr_path = ?
+            ORDER BY timestamp DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(attr_path)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(log)
+    }
+
+    /// Get every update script run of a package, most recent first
+    pub async fn get_update_script_logs(&self, attr_path: &str) -> Result<Vec<UpdateScriptLog>> {
+        let logs = sqlx::query_as::<_, UpdateScriptLog>(
+            r#"
+            SELECT attr_path, timestamp, status, stdout, stderr
+            FROM update_script_logs
+            WHERE attr_path = ?
+            ORDER BY timestamp DESC
+            "#,
+        )
+        .bind(attr_path)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(logs)
+    }
+
+    /// Record the per-test results of building passthru.tests for an update
+    pub(crate) async fn record_passthru_test_results(
+        &self,
+        attr_path: &str,
+        version: &str,
+        results: &[PassthruTestResult],
+    ) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        debug!(
+            "{}: Recording {} passthru test results",
+            attr_path,
+            results.len()
+        );
+
+        for result in results {
+            sqlx::query(
+                r#"
+                INSERT INTO passthru_test_results (attr_path, version, timestamp, test_name, status)
+                VALUES (?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(attr_path)
+            .bind(version)
+            .bind(&now)
+            .bind(&result.name)
+            .bind(result.outcome.as_str())
+            .execute(&self.pool)
+            .await
+            .context("Failed to record passthru test result")?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the passthru test results of the most recent update of an attr_path
+    pub async fn get_latest_passthru_test_results(
+        &self,
+        attr_path: &str,
+    ) -> Result<Vec<PassthruTestRecord>> {
+        let records = sqlx::query_as::<_, PassthruTestRecord>(
+            r#"
+            SELECT version, timestamp, test_name, status
+            FROM passthru_test_results
+            WHERE attr_path = ? AND timestamp = (
+                SELECT MAX(timestamp) FROM passthru_test_results WHERE attr_path = ?
+            )
+            ORDER BY test_name
+            "#,
+        )
+        .bind(attr_path)
+        .bind(attr_path)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records)
+    }
+
+    /// Record the known vulnerabilities of a package's current version, split by whether the
+    /// latest version fixes them
+    pub(crate) async fn record_vulnerability_status(
+        &self,
+        attr_path: &str,
+        current_version: &str,
+        latest_version: &str,
+        status: &SecurityStatus,
+    ) -> Result<()> {
+        let ids = |vulnerabilities: &[crate::osv::Vulnerability]| {
+            vulnerabilities
+                .iter()
+                .map(|v| v.id.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        debug!(
+            "{}: Recording {} fixed and {} remaining vulnerabilities",
+            attr_path,
+            status.fixed.len(),
+            status.remaining.len()
+        );
+
+        sqlx::query(
+            r#"
+            INSERT INTO vulnerabilities (attr_path, checked_at, current_version, latest_version,
+                                         fixed_ids, remaining_ids)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(attr_path) DO UPDATE SET
+                checked_at = excluded.checked_at,
+                current_version = excluded.current_version,
+                latest_version = excluded.latest_version,
+                fixed_ids = excluded.fixed_ids,
+                remaining_ids = excluded.remaining_ids
+            "#,
+        )
+        .bind(attr_path)
+        .bind(Utc::now().to_rfc3339())
+        .bind(current_version)
+        .bind(latest_version)
+        .bind(ids(&status.fixed))
+        .bind(ids(&status.remaining))
+        .execute(&self.pool)
+        .await
+        .context("Failed to record vulnerability status")?;
+
+        Ok(())
+    }
+
+    /// Record the phase timings of the latest update attempt of a package
+    pub(crate) async fn record_package_timings(
+        &self,
+        attr_path: &str,
+        timings: &PhaseTimings,
+    ) -> Result<()> {
+        let millis = |duration: std::time::Duration| duration.as_millis() as i64;
+
+        sqlx::query(
+            r#"
+            INSERT INTO package_timings (attr_path, timestamp, metadata_eval_ms, upstream_fetch_ms,
+                                         rewrite_ms, build_ms)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(attr_path) DO UPDATE SET
+                timestamp = excluded.timestamp,
+                metadata_eval_ms = excluded.metadata_eval_ms,
+                upstream_fetch_ms = excluded.upstream_fetch_ms,
+                rewrite_ms = excluded.rewrite_ms,
+                build_ms = excluded.build_ms
+            "#,
+        )
+        .bind(attr_path)
+        .bind(Utc::now().to_rfc3339())
+        .bind(millis(timings.metadata_eval))
+        .bind(millis(timings.upstream_fetch))
+        .bind(millis(timings.rewrite))
+        .bind(millis(timings.build))
+        .execute(&self.pool)
+        .await
+        .context("Failed to record package timings")?;
+
+        Ok(())
+    }
+
+    /// Get the release data cached for an upstream source, if it was fetched within `max_age`
+    pub async fn get_cached_releases(
+        &self,
+        source: &str,
+        max_age: Duration,
+    ) -> Result<Option<String>> {
+        let row = sqlx::query("SELECT data FROM releases WHERE source = ? AND fetched_at >= ?")
+            .bind(source)
+            .bind((Utc::now() - max_age).to_rfc3339())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|row| row.get("data")))
+    }
+
+    /// Cache the release data fetched from an upstream source
+    pub async fn record_releases(&self, source: &str, data: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO releases (source, data, fetched_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(source) DO UPDATE SET
+                data = excluded.data,
+                fetched_at = excluded.fetched_at
+            "#,
+        )
+        .bind(source)
+        .bind(data)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.pool)
+        .await
+        .context("Failed to cache releases")?;
+
+        Ok(())
+    }
+
+    /// Get the packages which took the longest to update since a point in time, slowest first
+    pub async fn get_slowest_packages(
+        &self,
+        since: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<PackageTimingRecord>> {
+        let records = sqlx::query_as::<_, PackageTimingRecord>(
+            r#"
+            SELECT attr_path, metadata_eval_ms, upstream_fetch_ms, rewrite_ms, build_ms
+            FROM package_timings
+            WHERE timestamp >= ?
+            ORDER BY metadata_eval_ms + upstream_fetch_ms + rewrite_ms + build_ms DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(since.to_rfc3339())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records)
+    }
+
+    /// Record that a package failed to evaluate
+    ///
+    /// Returns whether the package newly fails: it evaluated in earlier runs, as it has an update
+    /// record, and wasn't failing already.
+    pub async fn record_eval_error(&self, attr_path: &str, error: &str) -> Result<bool> {
+        let newly_failing: bool = sqlx::query_scalar(
+            r#"
+            SELECT (SELECT COUNT(*) FROM eval_errors WHERE attr_path = ?1) = 0
+                AND (SELECT COUNT(*) FROM updates WHERE attr_path = ?1) > 0
+            "#,
+        )
+        .bind(attr_path)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let now = Utc::now().to_rfc3339();
+        sqlx::query(
+            r#"
+            INSERT INTO eval_errors (attr_path, error, first_seen, last_seen)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(attr_path) DO UPDATE SET
+                error = excluded.error,
+                last_seen = excluded.last_seen
+            "#,
+        )
+        .bind(attr_path)
+        .bind(error)
+        .bind(&now)
+        .bind(&now)
+        .execute(&self.pool)
+        .await
+        .context("Failed to record evaluation error")?;
+
+        Ok(newly_failing)
+    }
+
+    /// Forget the evaluation errors not seen since a point in time, of packages which evaluate
+    /// again
+    ///
+    /// Returns the number of packages forgotten.
+    pub async fn clear_eval_errors_before(&self, since: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM eval_errors WHERE last_seen < ?")
+            .bind(since.to_rfc3339())
+            .execute(&self.pool)
+            .await
+            .context("Failed to clear evaluation errors")?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Get the evaluation error of a package, if it fails to evaluate
+    pub async fn get_eval_error(&self, attr_path: &str) -> Result<Option<EvalErrorRecord>> {
+        let record = sqlx::query_as::<_, EvalErrorRecord>(
+            r#"
+            SELECT attr_path, error, first_seen, last_seen
+            FROM eval_errors
+            WHERE attr_path = ?
+            "#,
+        )
+        .bind(attr_path)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Get the evaluation errors of every package failing to evaluate, most recent first
+    pub async fn get_eval_errors(&self) -> Result<Vec<EvalErrorRecord>> {
+        let records = sqlx::query_as::<_, EvalErrorRecord>(
+            r#"
+            SELECT attr_path, error, first_seen, last_seen
+            FROM eval_errors
+            ORDER BY first_seen DESC, attr_path
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records)
+    }
+
+    /// Get the version changes and PRs of a package, oldest first
+    pub async fn get_update_events(&self, attr_path: &str) -> Result<Vec<UpdateEvent>> {
+        let events = sqlx::query_as::<_, UpdateEvent>(
+            r#"
+            SELECT timestamp, kind, old_version, new_version, pr_url, pr_number
+            FROM update_events
+            WHERE attr_path = ?
+            ORDER BY timestamp, id
+            "#,
+        )
+        .bind(attr_path)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(events)
+    }
+
     /// Get a log entry by drv_path (supports both full path and hash-name format)
     pub async fn get_log_by_drv(&self, drv_identifier: &str) -> Result<Option<UpdateLog>> {
         // Try exact match first
         let mut log = sqlx::query_as::<_, UpdateLog>(
             r#"
-            SELECT drv_path, attr_path, timestamp, status, error_log, old_version, new_version
+            SELECT drv_path, attr_path, timestamp, status, error_log, old_version, new_version,
+                   failure_kind, retry_strategy
             FROM update_logs
             WHERE drv_path = ?
             "#,
@@ -409,7 +1073,8 @@ impl Database {
         if log.is_none() && !drv_identifier.starts_with("/nix/store/") {
             log = sqlx::query_as::<_, UpdateLog>(
                 r#"
-                SELECT drv_path, attr_path, timestamp, status, error_log, old_version, new_version
+                SELECT drv_path, attr_path, timestamp, status, error_log, old_version, new_version,
+                       failure_kind, retry_strategy
                 FROM update_logs
                 WHERE drv_path LIKE ?
                 "#,
@@ -423,13 +1088,14 @@ impl Database {
     }
 
     /// Get the most recent failed log for an attr_path
-    pub async fn _get_latest_failed_log_by_attr(
+    pub async fn get_latest_failed_log_by_attr(
         &self,
         attr_path: &str,
     ) -> Result<Option<UpdateLog>> {
         let log = sqlx::query_as::<_, UpdateLog>(
             r#"
-            SELECT drv_path, attr_path, timestamp, status, error_log, old_version, new_version
+            SELECT drv_path, attr_path, timestamp, status, error_log, old_version, new_version,
+                   failure_kind, retry_strategy
             FROM update_logs
             WHERE attr_path = ?
             ORDER BY timestamp DESC
@@ -447,7 +1113,8 @@ impl Database {
     pub async fn get_all_failed_logs_by_attr(&self, attr_path: &str) -> Result<Vec<UpdateLog>> {
         let logs = sqlx::query_as::<_, UpdateLog>(
             r#"
-            SELECT drv_path, attr_path, timestamp, status, error_log, old_version, new_version
+            SELECT drv_path, attr_path, timestamp, status, error_log, old_version, new_version,
+                   failure_kind, retry_strategy
             FROM update_logs
             WHERE attr_path = ?
             ORDER BY timestamp DESC
@@ -461,9 +1128,15 @@ impl Database {
     }
 }
 
+/// Summary of the packages tracked by the database
 #[derive(Debug)]
-pub struct _DatabaseStatistics {
+pub struct DatabaseStatistics {
+    /// Number of packages an update was attempted on
     pub total_packages: i64,
+    /// Number of packages with a proposed update
     pub packages_with_proposed_updates: i64,
+    /// Number of packages waiting for their backoff to expire
     pub packages_in_backoff: i64,
+    /// Number of failed update attempts per failure kind, most common first
+    pub failures_by_kind: Vec<(String, i64)>,
 }
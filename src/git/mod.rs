@@ -1,24 +1,140 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::process::Stdio;
+use std::process::{Output, Stdio};
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use tokio::process::Command;
 use tracing::{debug, warn};
 
-use crate::github::parse_github_url;
+use crate::github::{GithubRepo, list_pull_requests_from_branch, parse_github_url};
 
-/// Create a git worktree for an isolated update
-pub async fn create_worktree(attr_path: &str) -> anyhow::Result<PathBuf> {
-    // Get XDG cache directory
+/// Signature format of signed commits, git's `gpg.format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SigningFormat {
+    Openpgp,
+    Ssh,
+    X509,
+}
+
+impl SigningFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            SigningFormat::Openpgp => "openpgp",
+            SigningFormat::Ssh => "ssh",
+            SigningFormat::X509 => "x509",
+        }
+    }
+}
+
+/// How generated commits are signed
+///
+/// Without a key or format, git's own `user.signingkey` and `gpg.format` settings are used.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommitSigning {
+    /// GPG key ID, or path of the SSH public key for SSH signing
+    pub key: Option<String>,
+    pub format: Option<SigningFormat>,
+}
+
+impl CommitSigning {
+    /// Arguments of a git subcommand creating commits, starting with the subcommand
+    fn args(&self, subcommand: &str, sign_flag: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(format) = self.format {
+            args.extend(["-c".to_string(), format!("gpg.format={}", format.as_str())]);
+        }
+        if let Some(key) = &self.key {
+            args.extend(["-c".to_string(), format!("user.signingkey={}", key)]);
+        }
+        args.extend([subcommand.to_string(), sign_flag.to_string()]);
+        args
+    }
+}
+
+static COMMIT_SIGNING: OnceLock<CommitSigning> = OnceLock::new();
+
+static WORKTREE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Create worktrees in `dir` instead of the cache directory, e.g. on a tmpfs or scratch disk
+pub fn set_worktree_dir(dir: PathBuf) {
+    if WORKTREE_DIR.set(dir).is_err() {
+        warn!("Worktree directory was already set");
+    }
+}
+
+/// Directory worktrees are created in, `worktrees` in the cache directory by default
+fn worktree_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = WORKTREE_DIR.get() {
+        return Ok(dir.clone());
+    }
     let cache_dir = directories::ProjectDirs::from("", "", "ekapkgs-update")
         .ok_or_else(|| anyhow::anyhow!("Failed to determine cache directory"))?
         .cache_dir()
         .to_path_buf();
+    Ok(cache_dir.join("worktrees"))
+}
 
+/// Sign every generated commit, must be called before committing
+pub fn set_commit_signing(signing: CommitSigning) {
+    if COMMIT_SIGNING.set(signing).is_err() {
+        warn!("Commit signing was already configured");
+    }
+}
+
+/// Create a `git commit` command, signing the commit if configured with [`set_commit_signing`]
+pub fn git_commit_command() -> Command {
+    signing_command("commit", "-S")
+}
+
+/// Trailers identifying the packages updated by a generated commit, one line per trailer
+///
+/// Each change is `(attr_path, old_version, new_version)`. Commits updating several packages
+/// repeat the trailers in the same order, so tooling can pair them up with
+/// `git interpret-trailers --parse`.
+pub fn update_trailers(changes: &[(&str, &str, &str)]) -> String {
+    changes
+        .iter()
+        .map(|(attr_path, old_version, new_version)| {
+            format!(
+                "Ekapkgs-Update-Attr: {}\nEkapkgs-Update-From: {}\nEkapkgs-Update-To: {}",
+                attr_path, old_version, new_version
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Create a `git rebase` command, signing the rebased commits if configured
+fn git_rebase_command() -> Command {
+    signing_command("rebase", "--gpg-sign")
+}
+
+fn signing_command(subcommand: &str, sign_flag: &str) -> Command {
+    let mut command = Command::new("git");
+    match COMMIT_SIGNING.get() {
+        Some(signing) => command.args(signing.args(subcommand, sign_flag)),
+        None => command.arg(subcommand),
+    };
+    command
+}
+
+/// Create a git worktree for an isolated update
+pub async fn create_worktree(attr_path: &str) -> anyhow::Result<PathBuf> {
+    create_worktree_at(attr_path, "HEAD").await
+}
+
+/// Path of the worktree [`create_worktree`] creates for an attr_path
+pub fn worktree_path(attr_path: &str) -> anyhow::Result<PathBuf> {
     // Create a safe worktree directory name from attr_path
     let worktree_name = attr_path.replace(['.', '/'], "-");
-    let worktree_path = cache_dir
-        .join("worktrees")
-        .join(format!("update-{}", worktree_name));
+    Ok(worktree_dir()?.join(format!("update-{}", worktree_name)))
+}
+
+/// Create a git worktree checking out `start_point`, e.g. a fetched branch or the commit of
+/// another update
+pub async fn create_worktree_at(attr_path: &str, start_point: &str) -> anyhow::Result<PathBuf> {
+    let worktree_path = worktree_path(attr_path)?;
 
     // Remove existing worktree if it exists
     if worktree_path.exists() {
@@ -37,7 +153,13 @@ pub async fn create_worktree(attr_path: &str) -> anyhow::Result<PathBuf> {
     // Create the worktree
     debug!("{}: Creating worktree at {:?}", attr_path, worktree_path);
     let output = Command::new("git")
-        .args(["worktree", "add", worktree_path.to_str().unwrap(), "HEAD"])
+        .args([
+            "worktree",
+            "add",
+            "--detach",
+            worktree_path.to_str().unwrap(),
+            start_point,
+        ])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .output()
@@ -52,6 +174,12 @@ pub async fn create_worktree(attr_path: &str) -> anyhow::Result<PathBuf> {
     Ok(worktree_path)
 }
 
+/// Commit checked out in a worktree
+pub async fn head_commit(worktree_path: &Path) -> anyhow::Result<String> {
+    let output = git_in(worktree_path, &["rev-parse", "HEAD"], "resolve HEAD").await?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
 /// Clean up a git worktree
 pub async fn cleanup_worktree(worktree_path: &Path) -> anyhow::Result<()> {
     if !worktree_path.exists() {
@@ -87,6 +215,121 @@ pub async fn cleanup_worktree(worktree_path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Age after which a worktree is assumed to be left behind by a crashed run
+const STALE_WORKTREE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Remove update worktrees left behind by crashed runs, returning how many were removed
+///
+/// Worktrees found in the worktree directory or registered with git are removed once older than
+/// [`STALE_WORKTREE_AGE`], which leaves those of concurrent runs alone. Registrations of worktrees
+/// which no longer exist are pruned, as they prevent creating a worktree at the same path.
+pub async fn cleanup_stale_worktrees() -> anyhow::Result<usize> {
+    let dir = worktree_dir()?;
+
+    let output = Command::new("git")
+        .args(["worktree", "list", "--porcelain"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Failed to list worktrees: {}", stderr);
+    }
+    let registered: Vec<PathBuf> = parse_worktree_list(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        .filter(|path| is_update_worktree(&dir, path))
+        .collect();
+    let mut candidates = registered.clone();
+
+    if let Ok(mut entries) = tokio::fs::read_dir(&dir).await {
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if is_update_worktree(&dir, &path) && !candidates.contains(&path) {
+                candidates.push(path);
+            }
+        }
+    }
+
+    let mut removed = 0;
+    let mut missing = 0;
+    for path in candidates {
+        let age = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.modified()?.elapsed().unwrap_or_default(),
+            Err(_) => {
+                missing += 1;
+                continue;
+            },
+        };
+        if age >= STALE_WORKTREE_AGE {
+            debug!("Removing stale worktree at {:?}", path);
+            if registered.contains(&path) {
+                cleanup_worktree(&path).await?;
+            } else {
+                tokio::fs::remove_dir_all(&path).await?;
+            }
+            removed += 1;
+        }
+    }
+
+    if missing > 0 || removed > 0 {
+        let output = Command::new("git")
+            .args(["worktree", "prune"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            warn!("Failed to prune worktrees: {}", stderr);
+        }
+    }
+
+    Ok(removed + missing)
+}
+
+/// Whether `path` is a worktree [`create_worktree`] creates in `dir`
+fn is_update_worktree(dir: &Path, path: &Path) -> bool {
+    path.parent() == Some(dir)
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("update-"))
+}
+
+/// Worktree paths from `git worktree list --porcelain` output
+fn parse_worktree_list(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("worktree "))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Turn a name into a component of a branch name git accepts, e.g. `github:owner/repo` into
+/// `github-owner-repo`
+///
+/// Characters git rejects in refs (`:`, `~`, `^`, `?`, `*`, `[`, `\`, spaces, ...) and slashes
+/// become `-`, and `..`, leading dots and a trailing `.lock` are removed.
+pub fn ref_component(name: &str) -> String {
+    let mut component: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    while component.contains("..") {
+        component = component.replace("..", ".");
+    }
+    let component = component.trim_start_matches('.');
+    let component = component.strip_suffix(".lock").unwrap_or(component);
+    component.trim_end_matches('.').to_string()
+}
+
 /// Create a git branch, commit changes, and push to remote
 /// Returns the branch name
 pub async fn create_and_push_branch(
@@ -94,21 +337,57 @@ pub async fn create_and_push_branch(
     attr_path: &str,
     old_version: &str,
     new_version: &str,
+    steps: &[CommitStep],
     remote_repo: &str,
 ) -> anyhow::Result<String> {
     // Create a safe branch name from attr_path and version
     let sanitized_attr = attr_path.replace(['.', '/'], "-");
     let branch_name = format!("update/{}/{}", sanitized_attr, new_version);
 
+    // Create commit message
+    let commit_message = format!(
+        "Update {} from {} to {}\n\n🤖 Generated with ekapkgs-update\n\nCo-Authored-By: \
+         ekapkgs-update <noreply@ekapkgs.org>\n{}",
+        attr_path,
+        old_version,
+        new_version,
+        update_trailers(&[(attr_path, old_version, new_version)])
+    );
+
+    commit_and_push_branch(
+        worktree_path,
+        attr_path,
+        &branch_name,
+        &commit_message,
+        steps,
+        remote_repo,
+    )
+    .await?;
+
+    Ok(branch_name)
+}
+
+/// Create `branch_name` in a worktree, commit every change with `commit_message` and push it
+///
+/// Non-empty `steps` are committed separately with [`commit_steps`] instead of using
+/// `commit_message`. `label` identifies the update in log messages.
+pub async fn commit_and_push_branch(
+    worktree_path: &Path,
+    label: &str,
+    branch_name: &str,
+    commit_message: &str,
+    steps: &[CommitStep],
+    remote_repo: &str,
+) -> anyhow::Result<()> {
     debug!(
         "{}: Creating branch '{}' in worktree {:?}",
-        attr_path, branch_name, worktree_path
+        label, branch_name, worktree_path
     );
 
     // Create new branch
     let output = Command::new("git")
         .current_dir(worktree_path)
-        .args(["checkout", "-b", &branch_name])
+        .args(["checkout", "-b", branch_name])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .output()
@@ -119,51 +398,24 @@ pub async fn create_and_push_branch(
         anyhow::bail!("Failed to create branch '{}': {}", branch_name, stderr);
     }
 
-    // Add all changes
-    let output = Command::new("git")
-        .current_dir(worktree_path)
-        .args(["add", "-A"])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .await?;
+    if steps.is_empty() {
+        // Add all changes
+        git_in(worktree_path, &["add", "-A"], "stage changes").await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to stage changes: {}", stderr);
+        // Commit changes
+        commit_in(worktree_path, &["-m", commit_message]).await?;
+    } else {
+        commit_steps(worktree_path, steps).await?;
     }
 
-    // Create commit message
-    let commit_message = format!(
-        "Update {} from {} to {}\n\n🤖 Generated with ekapkgs-update\n\nCo-Authored-By: \
-         ekapkgs-update <noreply@ekapkgs.org>",
-        attr_path, old_version, new_version
-    );
-
-    // Commit changes
-    let output = Command::new("git")
-        .current_dir(worktree_path)
-        .args(["commit", "-m", &commit_message])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .await?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to commit changes: {}", stderr);
-    }
-
-    debug!(
-        "{}: Committed changes to branch '{}'",
-        attr_path, branch_name
-    );
+    debug!("{}: Committed changes to branch '{}'", label, branch_name);
 
     // Push to remote
     let push_target = format!("{}:{}", branch_name, branch_name);
     let output = Command::new("git")
         .current_dir(worktree_path)
         .args(["push", "-u", remote_repo, &push_target])
+        .process_group(0)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .output()
@@ -181,15 +433,100 @@ pub async fn create_and_push_branch(
 
     debug!(
         "{}: Pushed branch '{}' to remote '{}'",
-        attr_path, branch_name, remote_repo
+        label, branch_name, remote_repo
     );
 
-    Ok(branch_name)
+    Ok(())
+}
+
+/// A logical change of an update, e.g. the version bump or a refreshed dependency hash
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitStep {
+    pub message: String,
+    /// Files touched by the change, with their content right after it was made
+    pub files: Vec<(PathBuf, String)>,
+}
+
+/// Commit each step of an update separately, for easier review and bisecting
+///
+/// The files are rewritten to their content after each step, then restored to their current
+/// content. Steps leaving the files unchanged are skipped, and changes made after the last step,
+/// e.g. by a formatter, are amended into its commit.
+pub async fn commit_steps(worktree_path: &Path, steps: &[CommitStep]) -> anyhow::Result<()> {
+    let Some(last_step) = steps.last() else {
+        anyhow::bail!("No changes to commit");
+    };
+
+    let mut final_contents: Vec<(&Path, String)> = Vec::new();
+    for (path, _) in steps.iter().flat_map(|step| &step.files) {
+        if final_contents.iter().all(|(p, _)| p != path) {
+            let content = tokio::fs::read_to_string(worktree_path.join(path)).await?;
+            final_contents.push((path, content));
+        }
+    }
+
+    let mut committed = false;
+    for step in steps {
+        for (path, content) in &step.files {
+            tokio::fs::write(worktree_path.join(path), content).await?;
+            let path = path.to_string_lossy();
+            git_in(worktree_path, &["add", "--", &path], "stage changes").await?;
+        }
+        if has_staged_changes(worktree_path).await? {
+            commit_in(worktree_path, &["-m", &step.message]).await?;
+            committed = true;
+        }
+    }
+
+    for (path, content) in &final_contents {
+        tokio::fs::write(worktree_path.join(path), content).await?;
+    }
+    git_in(worktree_path, &["add", "-A"], "stage changes").await?;
+    if has_staged_changes(worktree_path).await? {
+        if committed {
+            commit_in(worktree_path, &["--amend", "--no-edit"]).await?;
+        } else {
+            commit_in(worktree_path, &["-m", &last_step.message]).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the index of a worktree differs from HEAD
+async fn has_staged_changes(worktree_path: &Path) -> anyhow::Result<bool> {
+    let status = Command::new("git")
+        .current_dir(worktree_path)
+        .args(["diff", "--cached", "--quiet"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await?;
+    Ok(!status.success())
+}
+
+/// Run `git commit` in a worktree, signing the commit if configured
+async fn commit_in(worktree_path: &Path, args: &[&str]) -> anyhow::Result<()> {
+    let output = git_commit_command()
+        .current_dir(worktree_path)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Failed to commit changes: {}", stderr);
+    }
+    Ok(())
 }
 
 /// PR configuration for creating pull requests
 #[derive(Debug, Clone)]
 pub struct PrConfig {
+    /// Name of the git remote of the upstream repository
+    pub remote: String,
     pub owner: String,
     pub repo: String,
     pub base_branch: String,
@@ -212,6 +549,7 @@ pub async fn get_pr_config_from_remote(remote: &str) -> anyhow::Result<PrConfig>
     debug!("Base branch: {}", base_branch);
 
     Ok(PrConfig {
+        remote: remote.to_string(),
         owner: github_repo.owner,
         repo: github_repo.repo,
         base_branch,
@@ -234,8 +572,21 @@ pub async fn get_pr_config_from_git() -> anyhow::Result<PrConfig> {
     get_pr_config_from_remote(&remote).await
 }
 
+/// Head of a PR against `config` for `branch`, pushed to the `fork` remote
+///
+/// Branches of forks owned by someone else are qualified with the owner of the fork, as GitHub
+/// requires for PRs across repositories.
+pub async fn pr_head(fork: &str, branch: &str, config: &PrConfig) -> String {
+    match get_remote_github_repo(fork).await {
+        Ok(fork_repo) if fork_repo.owner != config.owner => {
+            format!("{}:{}", fork_repo.owner, branch)
+        },
+        _ => branch.to_string(),
+    }
+}
+
 /// Get the current git branch name
-async fn get_current_branch() -> anyhow::Result<String> {
+pub async fn get_current_branch() -> anyhow::Result<String> {
     let output = Command::new("git")
         .args(["rev-parse", "--abbrev-ref", "HEAD"])
         .stdout(Stdio::piped())
@@ -257,6 +608,12 @@ async fn get_current_branch() -> anyhow::Result<String> {
     Ok(branch)
 }
 
+/// Check out an existing branch
+pub async fn checkout_branch(branch: &str) -> anyhow::Result<()> {
+    git_in(Path::new("."), &["checkout", branch], "check out branch").await?;
+    Ok(())
+}
+
 /// Get the upstream remote name for a branch
 async fn get_upstream_remote(branch: &str) -> anyhow::Result<String> {
     let output = Command::new("git")
@@ -281,6 +638,441 @@ async fn get_upstream_remote(branch: &str) -> anyhow::Result<String> {
     Ok("origin".to_string())
 }
 
+/// Branches of a remote starting with `prefix`, e.g. `update/`
+pub async fn list_remote_branches(remote: &str, prefix: &str) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args(["ls-remote", "--heads", remote])
+        .arg(format!("refs/heads/{}*", prefix))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Failed to list branches of remote '{}': {}", remote, stderr);
+    }
+
+    Ok(parse_ls_remote_heads(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Branch names from `git ls-remote --heads` output
+fn parse_ls_remote_heads(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter_map(|reference| reference.strip_prefix("refs/heads/"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Delete a branch from a remote
+pub async fn delete_remote_branch(remote: &str, branch: &str) -> anyhow::Result<()> {
+    let output = Command::new("git")
+        .args(["push", remote, "--delete", branch])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!(
+            "Failed to delete branch '{}' from remote '{}': {}",
+            branch,
+            remote,
+            stderr
+        );
+    }
+
+    Ok(())
+}
+
+/// Delete the `update/` branches of the fork whose pull requests were all merged or closed
+///
+/// Branches without any pull request, e.g. from a run which failed to open one, are kept.
+/// Returns the number of deleted branches.
+pub async fn delete_closed_update_branches(
+    pr_config: &PrConfig,
+    fork: &str,
+    token: &str,
+) -> anyhow::Result<usize> {
+    let fork_repo = get_remote_github_repo(fork).await?;
+
+    let mut deleted = 0;
+    for branch in list_remote_branches(fork, "update/").await? {
+        let head = format!("{}:{}", fork_repo.owner, branch);
+        let pull_requests =
+            list_pull_requests_from_branch(&pr_config.owner, &pr_config.repo, &head, token).await?;
+        if pull_requests.is_empty() || pull_requests.iter().any(|pr| pr.state == "open") {
+            continue;
+        }
+
+        match delete_remote_branch(fork, &branch).await {
+            Ok(()) => {
+                debug!(
+                    "Deleted branch '{}' of closed PR #{}",
+                    branch, pull_requests[0].number
+                );
+                deleted += 1;
+            },
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Fetch the base branch of the upstream repository, returning its remote-tracking branch
+pub async fn fetch_base_branch(pr_config: &PrConfig) -> anyhow::Result<String> {
+    let tracking = format!(
+        "refs/remotes/{}/{}",
+        pr_config.remote, pr_config.base_branch
+    );
+    let output = Command::new("git")
+        .args(["fetch", "--quiet", &pr_config.remote])
+        .arg(format!(
+            "+refs/heads/{}:{}",
+            pr_config.base_branch, tracking
+        ))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!(
+            "Failed to fetch '{}' from remote '{}': {}",
+            pr_config.base_branch,
+            pr_config.remote,
+            stderr
+        );
+    }
+
+    Ok(tracking)
+}
+
+/// Contents of a file at a revision, None if the file doesn't exist there
+///
+/// `path` is absolute or relative to the current directory, and is looked up relative to the root
+/// of the repository, wherever the current directory is in the tree.
+pub async fn file_at_revision(revision: &str, path: &Path) -> anyhow::Result<Option<String>> {
+    let root = repository_root().await?;
+    let path = std::env::current_dir()?.join(path);
+    let relative = path
+        .strip_prefix(&root)
+        .map_err(|_| anyhow::anyhow!("{} is outside of {}", path.display(), root.display()))?;
+    let object = format!("{}:{}", revision, relative.display());
+    let exists = Command::new("git")
+        .args(["cat-file", "-e", &object])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await?;
+    if !exists.success() {
+        return Ok(None);
+    }
+
+    let output = Command::new("git")
+        .args(["show", &object])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Failed to read {}: {}", object, stderr);
+    }
+
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Files with uncommitted changes at `path`, a file or a directory, including untracked files
+///
+/// Returns an empty list if `path` isn't in a git repository.
+pub async fn uncommitted_changes(path: &Path) -> anyhow::Result<Vec<String>> {
+    let (dir, pathspec) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if path.is_file() => (parent, Path::new(name)),
+        _ => (path, Path::new(".")),
+    };
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["status", "--porcelain", "--"])
+        .arg(pathspec)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        debug!(
+            "Not checking {} for uncommitted changes: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Ok(Vec::new());
+    }
+
+    Ok(parse_porcelain_status(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Root of the repository of the current directory
+async fn repository_root() -> anyhow::Result<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--show-toplevel"])
+        .output()
+        .await?;
+    if !output.status.success() {
+        anyhow::bail!("Not in a git repository");
+    }
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim(),
+    ))
+}
+
+/// Discard the uncommitted changes of `paths`, as listed by [`uncommitted_changes`]
+///
+/// Paths are relative to the root of the repository. Tracked files are restored from HEAD and
+/// untracked files and directories removed.
+async fn discard_changes(paths: &[String]) -> anyhow::Result<()> {
+    let root = repository_root().await?;
+
+    for path in paths {
+        let tracked = Command::new("git")
+            .current_dir(&root)
+            .args(["ls-files", "--error-unmatch", "--", path])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await?
+            .success();
+        if tracked {
+            git_in(&root, &["checkout", "HEAD", "--", path], "restore files").await?;
+        } else {
+            let full_path = root.join(path);
+            if full_path.is_dir() {
+                tokio::fs::remove_dir_all(&full_path).await?;
+            } else {
+                tokio::fs::remove_file(&full_path).await?;
+            }
+        }
+        debug!("Discarded changes of {}", path);
+    }
+    Ok(())
+}
+
+/// State of a file with uncommitted changes, see [`ChangesSnapshot`]
+enum SnapshotEntry {
+    Content(Vec<u8>),
+    Deleted,
+    /// Untracked directories are listed as a whole, their files aren't tracked one by one
+    Directory,
+}
+
+/// Uncommitted changes of the repository of the current directory, taken by
+/// [`ChangesSnapshot::take`]
+///
+/// Restoring the snapshot drops whatever was written since, including to files which already had
+/// uncommitted changes.
+pub struct ChangesSnapshot {
+    root: PathBuf,
+    entries: HashMap<String, SnapshotEntry>,
+}
+
+impl ChangesSnapshot {
+    /// Record the content of the files with uncommitted changes
+    pub async fn take() -> anyhow::Result<Self> {
+        let root = repository_root().await?;
+        let mut entries = HashMap::new();
+        for path in uncommitted_changes(&root).await? {
+            let full_path = root.join(&path);
+            let entry = if full_path.is_dir() {
+                SnapshotEntry::Directory
+            } else if full_path.exists() {
+                SnapshotEntry::Content(tokio::fs::read(&full_path).await?)
+            } else {
+                SnapshotEntry::Deleted
+            };
+            entries.insert(path, entry);
+        }
+        Ok(Self { root, entries })
+    }
+
+    /// Put the uncommitted changes back as they were when the snapshot was taken
+    ///
+    /// Files changed since are restored from the snapshot, or from HEAD if they had no changes
+    /// then, and files created since are removed.
+    pub async fn restore(&self) -> anyhow::Result<()> {
+        let mut discarded = Vec::new();
+        for path in uncommitted_changes(&self.root).await? {
+            let full_path = self.root.join(&path);
+            match self.entries.get(&path) {
+                Some(SnapshotEntry::Content(content)) => {
+                    if tokio::fs::read(&full_path).await.ok().as_ref() != Some(content) {
+                        tokio::fs::write(&full_path, content).await?;
+                        debug!("Restored {}", path);
+                    }
+                },
+                Some(SnapshotEntry::Deleted) => {
+                    if full_path.is_file() {
+                        tokio::fs::remove_file(&full_path).await?;
+                        debug!("Removed {} again", path);
+                    }
+                },
+                Some(SnapshotEntry::Directory) => {},
+                None => discarded.push(path),
+            }
+        }
+        discard_changes(&discarded).await
+    }
+}
+
+/// Paths from `git status --porcelain` output
+fn parse_porcelain_status(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.get(3..))
+        .map(|path| match path.split_once(" -> ") {
+            // Renames list the old and new path
+            Some((_, new_path)) => new_path.to_string(),
+            None => path.to_string(),
+        })
+        .collect()
+}
+
+/// Result of rebasing a branch onto its base branch
+#[derive(Debug, Clone, PartialEq)]
+pub enum RebaseOutcome {
+    /// Rebased and force-pushed
+    Rebased,
+    /// The branch already contains the latest base branch
+    UpToDate,
+    /// The rebase stopped on a conflict and was aborted
+    Conflict(String),
+}
+
+/// Run git in a worktree, failing with `action` in the error message
+async fn git_in(worktree_path: &Path, args: &[&str], action: &str) -> anyhow::Result<Output> {
+    let output = Command::new("git")
+        .current_dir(worktree_path)
+        .args(args)
+        .process_group(0)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Failed to {}: {}", action, stderr);
+    }
+    Ok(output)
+}
+
+/// Unified diff of the uncommitted changes in a worktree
+pub async fn worktree_diff(worktree_path: &Path) -> anyhow::Result<String> {
+    let output = git_in(worktree_path, &["diff"], "diff worktree").await?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Rebase a branch of the fork onto the latest base branch and force-push it
+///
+/// The base branch is fetched from the upstream remote of `pr_config`. The push uses
+/// `--force-with-lease`, so commits pushed to the branch in the meantime aren't lost.
+pub async fn rebase_branch(
+    pr_config: &PrConfig,
+    fork: &str,
+    branch: &str,
+) -> anyhow::Result<RebaseOutcome> {
+    let output = Command::new("git")
+        .args(["fetch", fork, branch])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("Failed to fetch branch '{}': {}", branch, stderr);
+    }
+
+    let label = format!("rebase-{}", branch);
+    let worktree_path = create_worktree_at(&label, "FETCH_HEAD").await?;
+    let outcome = rebase_in_worktree(&worktree_path, pr_config, fork, branch).await;
+    cleanup_worktree(&worktree_path).await?;
+    outcome
+}
+
+async fn rebase_in_worktree(
+    worktree_path: &Path,
+    pr_config: &PrConfig,
+    fork: &str,
+    branch: &str,
+) -> anyhow::Result<RebaseOutcome> {
+    let fork_head = String::from_utf8_lossy(
+        &git_in(worktree_path, &["rev-parse", "HEAD"], "resolve branch head")
+            .await?
+            .stdout,
+    )
+    .trim()
+    .to_string();
+
+    git_in(
+        worktree_path,
+        &["fetch", &pr_config.remote, &pr_config.base_branch],
+        "fetch base branch",
+    )
+    .await?;
+    let is_up_to_date = Command::new("git")
+        .current_dir(worktree_path)
+        .args(["merge-base", "--is-ancestor", "FETCH_HEAD", "HEAD"])
+        .status()
+        .await?
+        .success();
+    if is_up_to_date {
+        return Ok(RebaseOutcome::UpToDate);
+    }
+
+    let output = git_rebase_command()
+        .current_dir(worktree_path)
+        .arg("FETCH_HEAD")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        git_in(worktree_path, &["rebase", "--abort"], "abort rebase")
+            .await
+            .ok();
+        return Ok(RebaseOutcome::Conflict(stderr));
+    }
+
+    let lease = format!("--force-with-lease={}:{}", branch, fork_head);
+    let refspec = format!("HEAD:refs/heads/{}", branch);
+    git_in(
+        worktree_path,
+        &["push", &lease, fork, &refspec],
+        &format!("push rebased branch '{}'", branch),
+    )
+    .await?;
+
+    Ok(RebaseOutcome::Rebased)
+}
+
+/// GitHub repository a remote points to
+pub async fn get_remote_github_repo(remote: &str) -> anyhow::Result<GithubRepo> {
+    let remote_url = get_remote_url(remote).await?;
+    parse_github_url(&remote_url)
+        .ok_or_else(|| anyhow::anyhow!("Remote URL is not a GitHub repository: {}", remote_url))
+}
+
 /// Get the URL for a git remote
 async fn get_remote_url(remote: &str) -> anyhow::Result<String> {
     let output = Command::new("git")
@@ -367,3 +1159,103 @@ async fn get_default_branch(remote: &str) -> anyhow::Result<String> {
 
     anyhow::bail!("Could not determine default branch for remote '{}'", remote)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ls_remote_heads() {
+        let output =
+            "0123abcd\trefs/heads/update/hello/2.12\n4567ef01\trefs/heads/update/group-kde/6.1\n";
+        assert_eq!(
+            parse_ls_remote_heads(output),
+            vec!["update/hello/2.12", "update/group-kde/6.1"]
+        );
+        assert!(parse_ls_remote_heads("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_porcelain_status() {
+        let output = " M pkgs/hello/package.nix\n?? pkgs/hello/fix.patch\nR  old.nix -> \
+                      pkgs/hello/new.nix\n";
+        assert_eq!(
+            parse_porcelain_status(output),
+            vec![
+                "pkgs/hello/package.nix",
+                "pkgs/hello/fix.patch",
+                "pkgs/hello/new.nix"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_commit_signing_args() {
+        assert_eq!(
+            CommitSigning::default().args("commit", "-S"),
+            vec!["commit", "-S"]
+        );
+
+        let signing = CommitSigning {
+            key: Some("/home/bot/.ssh/id_ed25519.pub".to_string()),
+            format: Some(SigningFormat::Ssh),
+        };
+        assert_eq!(
+            signing.args("commit", "-S"),
+            vec![
+                "-c",
+                "gpg.format=ssh",
+                "-c",
+                "user.signingkey=/home/bot/.ssh/id_ed25519.pub",
+                "commit",
+                "-S"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_update_trailers() {
+        assert_eq!(
+            update_trailers(&[("hello", "1.0", "1.1")]),
+            "Ekapkgs-Update-Attr: hello\nEkapkgs-Update-From: 1.0\nEkapkgs-Update-To: 1.1"
+        );
+        assert_eq!(
+            update_trailers(&[("foo", "1", "2"), ("bar", "3", "4")])
+                .lines()
+                .filter(|l| l.starts_with("Ekapkgs-Update-Attr: "))
+                .collect::<Vec<_>>(),
+            ["Ekapkgs-Update-Attr: foo", "Ekapkgs-Update-Attr: bar"]
+        );
+    }
+
+    #[test]
+    fn test_parse_worktree_list() {
+        let output = "worktree /src/ekapkgs\nHEAD 1234abcd\nbranch refs/heads/main\n\nworktree \
+                      /cache/worktrees/update-hello\nHEAD 1234abcd\ndetached\nprunable gitdir \
+                      file points to non-existent location\n";
+        let worktrees = parse_worktree_list(output);
+        assert_eq!(
+            worktrees,
+            vec![
+                PathBuf::from("/src/ekapkgs"),
+                PathBuf::from("/cache/worktrees/update-hello")
+            ]
+        );
+
+        let dir = Path::new("/cache/worktrees");
+        assert!(!is_update_worktree(dir, &worktrees[0]));
+        assert!(is_update_worktree(dir, &worktrees[1]));
+        assert!(!is_update_worktree(
+            dir,
+            Path::new("/cache/worktrees/other")
+        ));
+    }
+
+    #[test]
+    fn test_ref_component() {
+        assert_eq!(ref_component("github:owner/repo"), "github-owner-repo");
+        assert_eq!(ref_component("kde frameworks"), "kde-frameworks");
+        assert_eq!(ref_component("..hidden..name.lock"), "hidden.name");
+        assert_eq!(ref_component("6.1.0+build"), "6.1.0+build");
+    }
+}
@@ -1,15 +1,31 @@
 //! GitHub API integration and utilities
 
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
+use crate::http::{self, Throttled};
+
 /// GitHub release information from the API
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GithubRelease {
     pub tag_name: String,
     pub _name: Option<String>,
     pub prerelease: bool,
+    /// Release notes in markdown
+    pub body: Option<String>,
+    /// Publish time, None for drafts
+    #[serde(default)]
+    pub published_at: Option<String>,
+    /// Files attached to the release
+    #[serde(default)]
+    pub assets: Vec<GithubReleaseAsset>,
+}
+
+/// File attached to a GitHub release
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GithubReleaseAsset {
+    pub name: String,
 }
 
 /// Represents a GitHub repository with owner and name
@@ -20,7 +36,7 @@ pub struct GithubRepo {
 }
 
 /// GitHub tag information from the API
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GithubTag {
     pub name: String,
 }
@@ -32,6 +48,113 @@ pub struct GithubPullRequest {
     pub number: i64,
 }
 
+/// State of an existing pull request from the API
+#[derive(Debug, Deserialize)]
+pub struct GithubPullRequestState {
+    pub number: i64,
+    /// `open` or `closed`, merged PRs are closed
+    pub state: String,
+    /// When the PR was merged, None if it wasn't
+    pub merged_at: Option<String>,
+}
+
+impl GithubPullRequestState {
+    /// `open`, `merged` or `closed`
+    pub fn outcome(&self) -> &str {
+        if self.merged_at.is_some() {
+            "merged"
+        } else {
+            &self.state
+        }
+    }
+}
+
+/// Issue from the API
+#[derive(Debug, Deserialize)]
+pub struct GithubIssue {
+    pub html_url: String,
+    pub number: i64,
+    /// `open` or `closed`
+    pub state: String,
+}
+
+/// Artifact attestations of a file from the API
+#[derive(Debug, Deserialize)]
+pub struct GithubAttestations {
+    #[serde(default)]
+    pub attestations: Vec<serde_json::Value>,
+}
+
+/// Comparison of two refs from the API
+#[derive(Debug, Deserialize)]
+pub struct GithubComparison {
+    /// Commits between the base and the head
+    pub total_commits: u64,
+    /// Files changed between the base and the head, the API lists at most 300
+    #[serde(default)]
+    pub files: Vec<GithubComparisonFile>,
+}
+
+/// File changed between two refs
+#[derive(Debug, Deserialize)]
+pub struct GithubComparisonFile {
+    pub filename: String,
+}
+
+/// Top-level entries of a comparison listed in reports
+const MAX_CHANGED_ENTRIES: usize = 10;
+
+/// Files the compare API lists at most, the other files are left out
+const MAX_COMPARISON_FILES: usize = 300;
+
+impl GithubComparison {
+    /// Top-level directories and files changed, sorted, with a trailing `/` for directories
+    pub fn changed_entries(&self) -> Vec<String> {
+        let mut entries: Vec<String> = self
+            .files
+            .iter()
+            .map(|file| match file.filename.split_once('/') {
+                Some((dir, _)) => format!("{}/", dir),
+                None => file.filename.clone(),
+            })
+            .collect();
+        entries.sort();
+        entries.dedup();
+        entries
+    }
+
+    /// Line summarizing the size of the comparison, for PR bodies
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "**{} commit{}**",
+            self.total_commits,
+            if self.total_commits == 1 { "" } else { "s" }
+        );
+        let entries = self.changed_entries();
+        if !entries.is_empty() {
+            let listed: Vec<String> = entries
+                .iter()
+                .take(MAX_CHANGED_ENTRIES)
+                .map(|entry| format!("`{}`", entry))
+                .collect();
+            summary.push_str(&format!(", changed: {}", listed.join(", ")));
+            if entries.len() > MAX_CHANGED_ENTRIES {
+                summary.push_str(&format!(
+                    " and {} more",
+                    entries.len() - MAX_CHANGED_ENTRIES
+                ));
+            }
+            if self.files.len() >= MAX_COMPARISON_FILES {
+                summary.push_str(&format!(
+                    " (among the first {} changed files, GitHub lists no more)",
+                    MAX_COMPARISON_FILES
+                ));
+            }
+        }
+        summary
+    }
+}
+
 /// Parse GitHub URL to extract owner and repo
 ///
 /// Supports various GitHub URL formats:
@@ -63,6 +186,21 @@ pub fn parse_github_url(url: &str) -> Option<GithubRepo> {
     })
 }
 
+/// Parse the name of the release asset a GitHub URL downloads
+///
+/// Matches URLs like `https://github.com/owner/repo/releases/download/v1.0.0/tool-1.0.0-linux.tar.gz`
+///
+/// Returns the asset name if found
+pub fn parse_github_release_asset(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let (_, after) = path.split_once("github.com/")?;
+    let parts: Vec<&str> = after.split('/').collect();
+    match parts.as_slice() {
+        [_, _, "releases", "download", _, asset] if !asset.is_empty() => Some(asset.to_string()),
+        _ => None,
+    }
+}
+
 /// Fetch tags from GitHub API
 ///
 /// Retrieves all tags from a repository.
@@ -84,7 +222,7 @@ pub async fn fetch_github_tags(
 
     debug!("Fetching tags from {}", url);
 
-    let client = reqwest::Client::new();
+    let client = http::client();
     let mut request = client
         .get(&url)
         .header("User-Agent", "ekapkgs-update")
@@ -96,7 +234,7 @@ pub async fn fetch_github_tags(
         request = request.header("Authorization", format!("Bearer {}", token_str));
     }
 
-    let response = request.send().await?;
+    let response = request.send_throttled().await?;
 
     if !response.status().is_success() {
         anyhow::bail!(
@@ -130,7 +268,7 @@ pub async fn fetch_github_releases(
 
     debug!("Fetching all releases from {}", url);
 
-    let client = reqwest::Client::new();
+    let client = http::client();
     let mut request = client
         .get(&url)
         .header("User-Agent", "ekapkgs-update")
@@ -142,7 +280,7 @@ pub async fn fetch_github_releases(
         request = request.header("Authorization", format!("Bearer {}", token_str));
     }
 
-    let response = request.send().await?;
+    let response = request.send_throttled().await?;
 
     if !response.status().is_success() {
         anyhow::bail!(
@@ -155,6 +293,85 @@ pub async fn fetch_github_releases(
     Ok(releases)
 }
 
+/// Fetch the tags of the latest releases from a repository's `releases.atom` feed
+///
+/// The feed isn't part of the API, so it needs no token and doesn't count against the API rate
+/// limit. It only lists the latest releases, newest first, or tags if the repository publishes
+/// no releases.
+pub async fn fetch_github_release_feed(owner: &str, repo: &str) -> anyhow::Result<Vec<String>> {
+    let url = format!("https://github.com/{}/{}/releases.atom", owner, repo);
+
+    debug!("Fetching release feed from {}", url);
+
+    let client = http::client();
+    let response = client
+        .get(&url)
+        .header("User-Agent", "ekapkgs-update")
+        .send_throttled()
+        .await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "GitHub release feed request failed with status: {}",
+            response.status()
+        );
+    }
+
+    Ok(parse_release_feed(&response.text().await?))
+}
+
+/// Release tags linked by the entries of a `releases.atom` feed
+fn parse_release_feed(feed: &str) -> Vec<String> {
+    let Ok(link_regex) = Regex::new(r#"<link[^>]*href="[^"]*/releases/tag/([^"]+)""#) else {
+        return Vec::new();
+    };
+    link_regex
+        .captures_iter(feed)
+        .map(|caps| percent_decode(&caps[1]))
+        .collect()
+}
+
+/// Decode the percent-encoded bytes of a URL path segment, e.g. `cli%2Fv1.0` to `cli/v1.0`
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| segment.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Line mentioning maintainers in a PR body, skipping those who opted out
+///
+/// Handles are compared case-insensitively, as on GitHub. Returns None if nobody is left to ping.
+pub fn mention_maintainers(handles: &[String], opt_out: &[String]) -> Option<String> {
+    let mentions: Vec<String> = handles
+        .iter()
+        .filter(|handle| !opt_out.iter().any(|o| o.eq_ignore_ascii_case(handle)))
+        .map(|handle| format!("@{}", handle))
+        .collect();
+
+    if mentions.is_empty() {
+        None
+    } else {
+        Some(format!("cc {} for review", mentions.join(" ")))
+    }
+}
+
 /// Create a pull request on GitHub
 ///
 /// # Arguments
@@ -181,7 +398,7 @@ pub async fn create_pull_request(
 
     debug!("Creating PR at {}", url);
 
-    let client = reqwest::Client::new();
+    let client = http::client();
     let request_body = serde_json::json!({
         "title": title,
         "body": body,
@@ -196,7 +413,7 @@ pub async fn create_pull_request(
         .header("X-GitHub-Api-Version", "2022-11-28")
         .header("Authorization", format!("Bearer {}", token))
         .json(&request_body)
-        .send()
+        .send_throttled()
         .await?;
 
     if !response.status().is_success() {
@@ -215,6 +432,262 @@ pub async fn create_pull_request(
     Ok(pr)
 }
 
+/// List the pull requests of `owner/repo` opened from a branch, in any state
+///
+/// # Arguments
+/// * `head` - Branch as `fork-owner:branch-name`
+pub async fn list_pull_requests_from_branch(
+    owner: &str,
+    repo: &str,
+    head: &str,
+    token: &str,
+) -> anyhow::Result<Vec<GithubPullRequestState>> {
+    let url = format!("https://api.github.com/repos/{}/{}/pulls", owner, repo);
+
+    debug!("Listing PRs from {} at {}", head, url);
+
+    let client = http::client();
+    let response = client
+        .get(&url)
+        .query(&[("head", head), ("state", "all")])
+        .header("User-Agent", "ekapkgs-update")
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header("Authorization", format!("Bearer {}", token))
+        .send_throttled()
+        .await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "GitHub API request failed with status: {}",
+            response.status()
+        );
+    }
+
+    Ok(response.json().await?)
+}
+
+/// Open an issue on `owner/repo`
+pub async fn create_issue(
+    owner: &str,
+    repo: &str,
+    title: &str,
+    body: &str,
+    token: &str,
+) -> anyhow::Result<GithubIssue> {
+    let url = format!("https://api.github.com/repos/{}/{}/issues", owner, repo);
+
+    debug!("Creating issue at {}", url);
+
+    let client = http::client();
+    let response = client
+        .post(&url)
+        .header("User-Agent", "ekapkgs-update")
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header("Authorization", format!("Bearer {}", token))
+        .json(&serde_json::json!({ "title": title, "body": body }))
+        .send_throttled()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await?;
+        anyhow::bail!(
+            "GitHub issue creation failed with status {}: {}",
+            status,
+            error_text
+        );
+    }
+
+    Ok(response.json().await?)
+}
+
+/// Get the state of a pull request of `owner/repo` by number
+pub async fn get_pull_request(
+    owner: &str,
+    repo: &str,
+    number: i64,
+    token: &str,
+) -> anyhow::Result<GithubPullRequestState> {
+    let url = format!(
+        "https://api.github.com/repos/{}/{}/pulls/{}",
+        owner, repo, number
+    );
+
+    let client = http::client();
+    let response = client
+        .get(&url)
+        .header("User-Agent", "ekapkgs-update")
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header("Authorization", format!("Bearer {}", token))
+        .send_throttled()
+        .await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "GitHub API request failed with status: {}",
+            response.status()
+        );
+    }
+
+    Ok(response.json().await?)
+}
+
+/// Get an issue of `owner/repo` by number
+pub async fn get_issue(
+    owner: &str,
+    repo: &str,
+    number: i64,
+    token: &str,
+) -> anyhow::Result<GithubIssue> {
+    let url = format!(
+        "https://api.github.com/repos/{}/{}/issues/{}",
+        owner, repo, number
+    );
+
+    let client = http::client();
+    let response = client
+        .get(&url)
+        .header("User-Agent", "ekapkgs-update")
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header("Authorization", format!("Bearer {}", token))
+        .send_throttled()
+        .await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "GitHub API request failed with status: {}",
+            response.status()
+        );
+    }
+
+    Ok(response.json().await?)
+}
+
+/// Compare two refs of `owner/repo`, e.g. the tags of two releases
+///
+/// Only the first commit is requested, the changed files are listed along with it and
+/// `total_commits` counts every commit anyway.
+pub async fn compare_refs(
+    owner: &str,
+    repo: &str,
+    base: &str,
+    head: &str,
+    token: Option<&str>,
+) -> anyhow::Result<GithubComparison> {
+    let url = format!(
+        "https://api.github.com/repos/{}/{}/compare/{}...{}?per_page=1",
+        owner, repo, base, head
+    );
+
+    debug!("Comparing refs at {}", url);
+
+    let client = http::client();
+    let mut request = client
+        .get(&url)
+        .header("User-Agent", "ekapkgs-update")
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", "2022-11-28");
+
+    if let Some(token_str) = token {
+        request = request.header("Authorization", format!("Bearer {}", token_str));
+    }
+
+    let response = request.send_throttled().await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "GitHub compare API request failed with status: {}",
+            response.status()
+        );
+    }
+
+    Ok(response.json().await?)
+}
+
+/// Whether `owner/repo` has artifact attestations of the file with SHA-256 digest `sha256`
+pub async fn has_attestations(
+    owner: &str,
+    repo: &str,
+    sha256: &str,
+    token: Option<&str>,
+) -> anyhow::Result<bool> {
+    let url = format!(
+        "https://api.github.com/repos/{}/{}/attestations/sha256:{}",
+        owner, repo, sha256
+    );
+
+    debug!("Fetching attestations from {}", url);
+
+    let client = http::client();
+    let mut request = client
+        .get(&url)
+        .header("User-Agent", "ekapkgs-update")
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", "2022-11-28");
+
+    if let Some(token_str) = token {
+        request = request.header("Authorization", format!("Bearer {}", token_str));
+    }
+
+    let response = request.send_throttled().await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "GitHub attestations API request failed with status: {}",
+            response.status()
+        );
+    }
+
+    let attestations: GithubAttestations = response.json().await?;
+    Ok(!attestations.attestations.is_empty())
+}
+
+/// Comment on an issue or pull request of `owner/repo`
+pub async fn comment_on_issue(
+    owner: &str,
+    repo: &str,
+    number: i64,
+    body: &str,
+    token: &str,
+) -> anyhow::Result<()> {
+    let url = format!(
+        "https://api.github.com/repos/{}/{}/issues/{}/comments",
+        owner, repo, number
+    );
+
+    debug!("Commenting on issue #{} at {}", number, url);
+
+    let client = http::client();
+    let response = client
+        .post(&url)
+        .header("User-Agent", "ekapkgs-update")
+        .header("Accept", "application/vnd.github+json")
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header("Authorization", format!("Bearer {}", token))
+        .json(&serde_json::json!({ "body": body }))
+        .send_throttled()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await?;
+        anyhow::bail!(
+            "GitHub comment creation failed with status {}: {}",
+            status,
+            error_text
+        );
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -315,4 +788,110 @@ mod tests {
         assert_eq!(extract_version_from_tag("v2.0.0-beta"), "2.0.0-beta");
         assert_eq!(extract_version_from_tag("1.0.0-alpha.1"), "1.0.0-alpha.1");
     }
+
+    #[test]
+    fn test_mention_maintainers() {
+        let handles = vec!["alice".to_string(), "Bob".to_string()];
+        assert_eq!(
+            mention_maintainers(&handles, &[]).as_deref(),
+            Some("cc @alice @Bob for review")
+        );
+        assert_eq!(
+            mention_maintainers(&handles, &["bob".to_string()]).as_deref(),
+            Some("cc @alice for review")
+        );
+        assert_eq!(mention_maintainers(&[], &[]), None);
+    }
+
+    #[test]
+    fn test_parse_release_feed() {
+        let feed = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xml:lang="en-US">
+  <id>tag:github.com,2008:https://github.com/owner/repo/releases</id>
+  <link type="text/html" rel="alternate" href="https://github.com/owner/repo/releases"/>
+  <title>Release notes from repo</title>
+  <entry>
+    <id>tag:github.com,2008:Repository/1/cli/v2.3.0</id>
+    <updated>2025-01-02T10:00:00Z</updated>
+    <link rel="alternate" type="text/html" href="https://github.com/owner/repo/releases/tag/cli%2Fv2.3.0"/>
+    <title>cli/v2.3.0</title>
+  </entry>
+  <entry>
+    <id>tag:github.com,2008:Repository/1/v1.0.0</id>
+    <link rel="alternate" type="text/html" href="https://github.com/owner/repo/releases/tag/v1.0.0"/>
+  </entry>
+</feed>"#;
+        assert_eq!(parse_release_feed(feed), vec!["cli/v2.3.0", "v1.0.0"]);
+        assert!(parse_release_feed("<feed></feed>").is_empty());
+    }
+
+    #[test]
+    fn test_parse_github_release_asset() {
+        assert_eq!(
+            parse_github_release_asset(
+                "https://github.com/owner/repo/releases/download/v1.0.0/tool-1.0.0-x86_64.AppImage"
+            )
+            .as_deref(),
+            Some("tool-1.0.0-x86_64.AppImage")
+        );
+        assert_eq!(
+            parse_github_release_asset("https://github.com/owner/repo/archive/v1.0.0.tar.gz"),
+            None
+        );
+        assert_eq!(
+            parse_github_release_asset("https://github.com/owner/repo"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_comparison_summary() {
+        let comparison: GithubComparison = serde_json::from_str(
+            r#"{"total_commits": 42, "files": [
+                {"filename": "src/main.rs"},
+                {"filename": "docs/usage.md"},
+                {"filename": "src/lib/mod.rs"},
+                {"filename": "README.md"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            comparison.changed_entries(),
+            vec!["README.md", "docs/", "src/"]
+        );
+        assert_eq!(
+            comparison.summary(),
+            "**42 commits**, changed: `README.md`, `docs/`, `src/`"
+        );
+
+        let single = GithubComparison {
+            total_commits: 1,
+            files: Vec::new(),
+        };
+        assert_eq!(single.summary(), "**1 commit**");
+
+        let wide = GithubComparison {
+            total_commits: 12,
+            files: (0..12)
+                .map(|i| GithubComparisonFile {
+                    filename: format!("dir{:02}/file", i),
+                })
+                .collect(),
+        };
+        assert!(wide.summary().ends_with("`dir09/` and 2 more"));
+
+        let capped = GithubComparison {
+            total_commits: 500,
+            files: (0..MAX_COMPARISON_FILES)
+                .map(|i| GithubComparisonFile {
+                    filename: format!("src/file{}.rs", i),
+                })
+                .collect(),
+        };
+        assert_eq!(
+            capped.summary(),
+            "**500 commits**, changed: `src/` (among the first 300 changed files, GitHub lists no \
+             more)"
+        );
+    }
 }
@@ -1,16 +1,22 @@
 //! GitLab API integration and utilities
 
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
+use crate::http::{self, Throttled};
+
 /// GitLab release information from the API
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GitlabRelease {
     pub tag_name: String,
     pub _name: Option<String>,
     #[serde(default)]
     pub upcoming_release: bool,
+    /// Release notes in markdown
+    pub description: Option<String>,
+    #[serde(default)]
+    pub released_at: Option<String>,
 }
 
 /// Represents a GitLab project with owner/group and project name
@@ -21,7 +27,7 @@ pub struct GitlabProject {
 }
 
 /// GitLab tag information from the API
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GitlabTag {
     pub name: String,
 }
@@ -84,7 +90,7 @@ pub async fn fetch_gitlab_tags(
 
     debug!("Fetching tags from {}", url);
 
-    let client = reqwest::Client::new();
+    let client = http::client();
     let mut request = client.get(&url).header("User-Agent", "ekapkgs-update");
 
     // Add authorization header if token is provided
@@ -92,7 +98,7 @@ pub async fn fetch_gitlab_tags(
         request = request.header("PRIVATE-TOKEN", token_str);
     }
 
-    let response = request.send().await?;
+    let response = request.send_throttled().await?;
 
     if !response.status().is_success() {
         anyhow::bail!(
@@ -130,7 +136,7 @@ pub async fn fetch_gitlab_releases(
 
     debug!("Fetching all releases from {}", url);
 
-    let client = reqwest::Client::new();
+    let client = http::client();
     let mut request = client.get(&url).header("User-Agent", "ekapkgs-update");
 
     // Add authorization header if token is provided
@@ -138,7 +144,7 @@ pub async fn fetch_gitlab_releases(
         request = request.header("PRIVATE-TOKEN", token_str);
     }
 
-    let response = request.send().await?;
+    let response = request.send_throttled().await?;
 
     if !response.status().is_success() {
         anyhow::bail!(
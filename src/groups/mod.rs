@@ -0,0 +1,332 @@
+//! Groups of packages which are updated together
+//!
+//! Some packages must move in lockstep, e.g. the KDE frameworks or sphinx and its extensions.
+//! Updating them one by one produces PRs which can't be merged on their own, so `run` updates
+//! every member of a group in a single worktree, commit and PR instead.
+//!
+//! Groups are read from a TOML file:
+//!
+//! ```toml
+//! [[group]]
+//! name = "sphinx"
+//! members = ["python3Packages.sphinx", "python3Packages.sphinxcontrib-*"]
+//! ```
+//!
+//! A member ending in `*` matches every attribute path starting with the text before it. Groups
+//! whose members share their version, e.g. Qt modules, can set `lockstep = true` so no update is
+//! proposed until upstream has released the new version of every member.
+//!
+//! Packages built from the same GitHub or GitLab repository, e.g. monorepos producing several
+//! attributes, can also be grouped implicitly with [`derive_upstream_groups`].
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context;
+use futures::StreamExt;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::nix::nix_eval_jobs::NixEvalDrv;
+use crate::nix::{eval_nix_expr, import_entry_point};
+use crate::vcs_sources::UpstreamSource;
+
+/// A named set of packages updated together
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PackageGroup {
+    pub name: String,
+    /// Attribute paths, or attribute path prefixes ending in `*`
+    pub members: Vec<String>,
+    /// Only update once every member is released at the same version
+    #[serde(default)]
+    pub lockstep: bool,
+}
+
+impl PackageGroup {
+    /// Whether `attr_path` is a member of the group
+    pub fn contains(&self, attr_path: &str) -> bool {
+        self.members
+            .iter()
+            .any(|member| match member.strip_suffix('*') {
+                Some(prefix) => attr_path.starts_with(prefix),
+                None => attr_path == member,
+            })
+    }
+}
+
+/// Every configured package group
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct PackageGroups {
+    #[serde(default, rename = "group")]
+    groups: Vec<PackageGroup>,
+}
+
+impl PackageGroups {
+    /// Parse the contents of a groups file
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let groups: Self = toml::from_str(content)?;
+
+        let mut names = std::collections::HashSet::new();
+        for group in &groups.groups {
+            if !names.insert(group.name.as_str()) {
+                anyhow::bail!("Group '{}' is defined more than once", group.name);
+            }
+            if group.members.is_empty() {
+                anyhow::bail!("Group '{}' has no members", group.name);
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// Read a groups file
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read groups file {}", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("Failed to parse groups file {}", path.display()))
+    }
+
+    /// The group `attr_path` belongs to, the first matching one if there are several
+    pub fn group_of(&self, attr_path: &str) -> Option<&PackageGroup> {
+        self.groups.iter().find(|group| group.contains(attr_path))
+    }
+
+    /// Add groups, e.g. derived ones, after the configured groups
+    pub fn extend(&mut self, groups: impl IntoIterator<Item = PackageGroup>) {
+        self.groups.extend(groups);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+}
+
+/// Source of a package as needed to group it by upstream
+#[derive(Debug, Clone, PartialEq)]
+struct PackageSource {
+    attr: String,
+    /// Repository the source is fetched from, e.g. `github:owner/repo`
+    repository: String,
+    /// File defining the package, from `meta.position`
+    file: String,
+}
+
+/// Repository identifying an upstream source, None for sources not hosted in a repository
+fn repository_key(source: &UpstreamSource) -> Option<String> {
+    match source {
+        UpstreamSource::GitHub { owner, repo, .. } => Some(format!("github:{}/{}", owner, repo)),
+        UpstreamSource::GitLab { owner, project } => Some(format!("gitlab:{}/{}", owner, project)),
+        UpstreamSource::PyPI { .. }
+        | UpstreamSource::LibrariesIo { .. }
+        | UpstreamSource::Mirror { .. }
+        | UpstreamSource::Plugin { .. } => None,
+    }
+}
+
+/// Evaluate the repository and defining file of a package's source
+async fn package_source(eval_entry_point: &str, attr_path: &str) -> Option<PackageSource> {
+    #[derive(Deserialize)]
+    struct SourceInfo {
+        url: String,
+        position: String,
+    }
+
+    let nix_expr = format!(
+        "with {}; builtins.toJSON {{ url = builtins.toString ({}.src.url or {}.src.urls or \"\"); \
+         position = {}.meta.position or \"\"; }}",
+        import_entry_point(eval_entry_point),
+        attr_path,
+        attr_path,
+        attr_path
+    );
+    let json = eval_nix_expr(&nix_expr).await.ok()?;
+    let info: SourceInfo = serde_json::from_str(&json).ok()?;
+
+    let url = info.url.split_whitespace().next()?;
+    let repository = repository_key(&UpstreamSource::from_url(url)?)?;
+    let file = match info.position.rsplit_once(':') {
+        Some((file, _line)) => file.to_string(),
+        None => info.position,
+    };
+
+    Some(PackageSource {
+        attr: attr_path.to_string(),
+        repository,
+        file,
+    })
+}
+
+/// Group packages fetched from the same repository
+///
+/// Attributes defined in the same file as an earlier member, e.g. aliases or the same Python
+/// package in several interpreter package sets, are left out since updating the file once
+/// updates all of them. Only repositories with at least two remaining packages form a group.
+fn group_by_repository(sources: Vec<PackageSource>) -> Vec<PackageGroup> {
+    let mut by_repository: BTreeMap<String, Vec<PackageSource>> = BTreeMap::new();
+    for source in sources {
+        let members = by_repository.entry(source.repository.clone()).or_default();
+        if source.file.is_empty() || !members.iter().any(|m| m.file == source.file) {
+            members.push(source);
+        }
+    }
+
+    by_repository
+        .into_iter()
+        .filter(|(_, members)| members.len() > 1)
+        .map(|(repository, members)| PackageGroup {
+            name: repository,
+            members: members.into_iter().map(|m| m.attr).collect(),
+            lockstep: false,
+        })
+        .collect()
+}
+
+/// Derive implicit groups of packages fetched from the same GitHub or GitLab repository
+///
+/// Packages already in one of the `configured` groups are left out. Evaluates the source of
+/// every package, `concurrency` at a time.
+pub async fn derive_upstream_groups(
+    eval_entry_point: &str,
+    drvs: &[NixEvalDrv],
+    configured: &PackageGroups,
+    concurrency: usize,
+) -> Vec<PackageGroup> {
+    let mut sources: Vec<(usize, PackageSource)> = futures::stream::iter(drvs.iter().enumerate())
+        .filter(|(_, drv)| std::future::ready(configured.group_of(&drv.attr).is_none()))
+        .map(|(index, drv)| async move {
+            package_source(eval_entry_point, &drv.attr)
+                .await
+                .map(|source| (index, source))
+        })
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+
+    // Keep the evaluation order, so the first attribute defined in a file is kept
+    sources.sort_by_key(|(index, _)| *index);
+    let groups = group_by_repository(sources.into_iter().map(|(_, source)| source).collect());
+    for group in &groups {
+        debug!(
+            "Derived group {} from a shared upstream: {}",
+            group.name,
+            group.members.join(", ")
+        );
+    }
+    groups
+}
+
+/// Repositories packages are fetched from, e.g. `github:owner/repo`, as (attr, repository)
+///
+/// Evaluates the source of every package, `concurrency` at a time. Packages not fetched from a
+/// GitHub or GitLab repository are left out.
+pub async fn package_repositories(
+    eval_entry_point: &str,
+    drvs: &[NixEvalDrv],
+    concurrency: usize,
+) -> Vec<(String, String)> {
+    futures::stream::iter(drvs)
+        .map(|drv| package_source(eval_entry_point, &drv.attr))
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(std::future::ready)
+        .map(|source| (source.attr, source.repository))
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_groups() {
+        let groups = PackageGroups::parse(
+            r#"
+            [[group]]
+            name = "sphinx"
+            members = ["python3Packages.sphinx", "python3Packages.sphinxcontrib-*"]
+
+            [[group]]
+            name = "kde"
+            members = ["kdePackages.*"]
+            lockstep = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups
+                .group_of("python3Packages.sphinxcontrib-bibtex")
+                .map(|g| g.name.as_str()),
+            Some("sphinx")
+        );
+        assert_eq!(
+            groups
+                .group_of("python3Packages.sphinx")
+                .map(|g| g.name.as_str()),
+            Some("sphinx")
+        );
+        assert_eq!(groups.group_of("python3Packages.sphinx-rtd-theme"), None);
+        assert_eq!(
+            groups
+                .group_of("kdePackages.kcoreaddons")
+                .map(|g| g.name.as_str()),
+            Some("kde")
+        );
+        assert!(groups.group_of("kdePackages.kcoreaddons").unwrap().lockstep);
+        assert!(!groups.group_of("python3Packages.sphinx").unwrap().lockstep);
+
+        assert!(PackageGroups::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid_groups() {
+        let duplicate = r#"
+            [[group]]
+            name = "a"
+            members = ["foo"]
+
+            [[group]]
+            name = "a"
+            members = ["bar"]
+        "#;
+        assert!(PackageGroups::parse(duplicate).is_err());
+
+        let empty = "[[group]]\nname = \"a\"\nmembers = []\n";
+        assert!(PackageGroups::parse(empty).is_err());
+    }
+
+    #[test]
+    fn test_group_by_repository() {
+        let source = |attr: &str, repository: &str, file: &str| PackageSource {
+            attr: attr.to_string(),
+            repository: repository.to_string(),
+            file: file.to_string(),
+        };
+
+        let groups = group_by_repository(vec![
+            source("foo-cli", "github:foo/foo", "pkgs/foo-cli.nix"),
+            source("foo-server", "github:foo/foo", "pkgs/foo-server.nix"),
+            // Same package in another package set
+            source("python3Packages.bar", "github:bar/bar", "pkgs/bar.nix"),
+            source("python312Packages.bar", "github:bar/bar", "pkgs/bar.nix"),
+            source("baz", "gitlab:baz/baz", "pkgs/baz.nix"),
+        ]);
+
+        assert_eq!(
+            groups,
+            vec![PackageGroup {
+                name: "github:foo/foo".to_string(),
+                members: vec!["foo-cli".to_string(), "foo-server".to_string()],
+                lockstep: false,
+            }]
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Commands run at the stages of an update
+//!
+//! Hooks are declared in the configuration file, for every package or for a single one:
+//!
+//! ```toml
+//! [hooks]
+//! post_pr = ["./ci/trigger-image-rebuild"]
+//!
+//! [packages.hello.hooks]
+//! pre_rewrite = ["./ci/check-freeze", "hello"]
+//! ```
+//!
+//! A hook of a package replaces the hook of the same stage of every package. `pre_rewrite` runs
+//! before the files of the package are rewritten and `post_build` after the updated package
+//! built, either failing aborts the update. `post_pr` runs after the pull request of the update
+//! was created, its failure is only reported. Packages updated by their update script run
+//! `pre_rewrite` before the script, with an empty new version, and `post_build` once the script
+//! changed the version.
+//!
+//! Hooks run in the root of the tree being updated, with the update described by environment
+//! variables: `EKAPKGS_UPDATE_HOOK` (the stage), `EKAPKGS_UPDATE_ATTR_PATH`,
+//! `EKAPKGS_UPDATE_OLD_VERSION` and `EKAPKGS_UPDATE_NEW_VERSION`, plus
+//! `EKAPKGS_UPDATE_OUT_PATHS` (space separated) after the build and `EKAPKGS_UPDATE_PR_URL` after
+//! the pull request was created.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::{debug, info};
+
+/// Commands to run at each stage of an update, as a program followed by its arguments
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Run before the files of the package are rewritten
+    pub pre_rewrite: Option<Vec<String>>,
+    /// Run after the updated package built successfully
+    pub post_build: Option<Vec<String>>,
+    /// Run after the pull request of the update was created
+    pub post_pr: Option<Vec<String>>,
+}
+
+/// Stage of an update a hook runs at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    PreRewrite,
+    PostBuild,
+    PostPr,
+}
+
+impl HookStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookStage::PreRewrite => "pre_rewrite",
+            HookStage::PostBuild => "post_build",
+            HookStage::PostPr => "post_pr",
+        }
+    }
+}
+
+impl Hooks {
+    /// These hooks, falling back to `defaults` for the stages without one
+    pub fn or(self, defaults: &Hooks) -> Hooks {
+        Hooks {
+            pre_rewrite: self.pre_rewrite.or_else(|| defaults.pre_rewrite.clone()),
+            post_build: self.post_build.or_else(|| defaults.post_build.clone()),
+            post_pr: self.post_pr.or_else(|| defaults.post_pr.clone()),
+        }
+    }
+
+    /// Command of a stage, None if it has no hook
+    pub fn command(&self, stage: HookStage) -> Option<&[String]> {
+        match stage {
+            HookStage::PreRewrite => self.pre_rewrite.as_deref(),
+            HookStage::PostBuild => self.post_build.as_deref(),
+            HookStage::PostPr => self.post_pr.as_deref(),
+        }
+    }
+
+    /// Check that every declared hook has a program
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for stage in [
+            HookStage::PreRewrite,
+            HookStage::PostBuild,
+            HookStage::PostPr,
+        ] {
+            if self
+                .command(stage)
+                .is_some_and(|command| command.is_empty())
+            {
+                anyhow::bail!("Empty {} hook", stage.as_str());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Update described to hooks
+#[derive(Debug, Clone, Default)]
+pub struct HookContext {
+    pub attr_path: String,
+    pub old_version: String,
+    pub new_version: String,
+    /// Output paths of the updated package, once it's built
+    pub out_paths: Vec<String>,
+    /// URL of the pull request, once it's created
+    pub pr_url: Option<String>,
+}
+
+impl HookContext {
+    /// Context of updating `attr_path` from `old_version` to `new_version`
+    pub fn new(attr_path: &str, old_version: &str, new_version: &str) -> Self {
+        Self {
+            attr_path: attr_path.to_string(),
+            old_version: old_version.to_string(),
+            new_version: new_version.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Environment variables describing the update to a hook of `stage`
+    fn env(&self, stage: HookStage) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("EKAPKGS_UPDATE_HOOK", stage.as_str().to_string()),
+            ("EKAPKGS_UPDATE_ATTR_PATH", self.attr_path.clone()),
+            ("EKAPKGS_UPDATE_OLD_VERSION", self.old_version.clone()),
+            ("EKAPKGS_UPDATE_NEW_VERSION", self.new_version.clone()),
+        ];
+        if !self.out_paths.is_empty() {
+            env.push(("EKAPKGS_UPDATE_OUT_PATHS", self.out_paths.join(" ")));
+        }
+        if let Some(ref pr_url) = self.pr_url {
+            env.push(("EKAPKGS_UPDATE_PR_URL", pr_url.clone()));
+        }
+        env
+    }
+}
+
+/// Run the hook of `stage` in `dir`, if there is one
+///
+/// Fails if the hook exits unsuccessfully, with the end of its output.
+pub async fn run_hook(
+    hooks: &Hooks,
+    stage: HookStage,
+    context: &HookContext,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let Some((program, args)) = hooks.command(stage).and_then(|c| c.split_first()) else {
+        return Ok(());
+    };
+
+    info!(
+        "{}: Running {} hook {}",
+        context.attr_path,
+        stage.as_str(),
+        program
+    );
+    let output = Command::new(program)
+        .args(args)
+        .envs(context.env(stage))
+        .current_dir(dir)
+        .process_group(0)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {} hook {}", stage.as_str(), program))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stdout.lines().chain(stderr.lines()) {
+        debug!("[{}] {}", stage.as_str(), line);
+    }
+    if !output.status.success() {
+        let lines: Vec<&str> = stderr.lines().collect();
+        anyhow::bail!(
+            "{} hook {} failed ({}):\n{}",
+            stage.as_str(),
+            program,
+            output.status,
+            lines[lines.len().saturating_sub(20)..].join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hooks() {
+        let defaults = Hooks {
+            pre_rewrite: Some(vec!["./freeze".to_string()]),
+            post_pr: Some(vec!["./rebuild".to_string()]),
+            ..Hooks::default()
+        };
+        let hooks = Hooks {
+            post_pr: Some(vec!["./notify".to_string(), "hello".to_string()]),
+            ..Hooks::default()
+        }
+        .or(&defaults);
+        assert_eq!(
+            hooks.command(HookStage::PreRewrite),
+            Some(&["./freeze".to_string()][..])
+        );
+        assert_eq!(hooks.command(HookStage::PostBuild), None);
+        assert_eq!(
+            hooks.command(HookStage::PostPr),
+            Some(&["./notify".to_string(), "hello".to_string()][..])
+        );
+
+        let mut context = HookContext::new("hello", "2.12", "2.13");
+        assert_eq!(context.env(HookStage::PreRewrite).len(), 4);
+        context.out_paths = vec![
+            "/nix/store/a-hello".to_string(),
+            "/nix/store/b-hello-man".to_string(),
+        ];
+        context.pr_url = Some("https://github.com/o/r/pull/1".to_string());
+        let env = context.env(HookStage::PostPr);
+        assert!(env.contains(&("EKAPKGS_UPDATE_HOOK", "post_pr".to_string())));
+        assert!(env.contains(&(
+            "EKAPKGS_UPDATE_OUT_PATHS",
+            "/nix/store/a-hello /nix/store/b-hello-man".to_string()
+        )));
+        assert!(env.contains(&(
+            "EKAPKGS_UPDATE_PR_URL",
+            "https://github.com/o/r/pull/1".to_string()
+        )));
+
+        assert!(Hooks::default().validate().is_ok());
+        let empty = Hooks {
+            post_build: Some(Vec::new()),
+            ..Hooks::default()
+        };
+        assert!(empty.validate().is_err());
+    }
+}
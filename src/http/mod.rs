@@ -0,0 +1,321 @@
+//! Outbound HTTP requests
+//!
+//! Requests are sent with [`Throttled::send_throttled`], which spaces them per host according to
+//! the `[rate_limits]` of the configuration file, in requests per second:
+//!
+//! ```toml
+//! [rate_limits]
+//! "api.github.com" = 10
+//! "*" = 20
+//! ```
+//!
+//! `"*"` applies to every other host. Without a limit, requests are sent right away. This keeps
+//! runs with many concurrent updates from bursting past the limits of upstream APIs.
+//!
+//! Connection errors, timeouts and `429`/`5xx` responses of `GET` and `HEAD` requests are retried
+//! with exponential backoff and jitter, waiting as long as `Retry-After` asks for, so a transient
+//! failure doesn't push a package into the backoff of the database. Other requests, e.g. creating
+//! a pull request, are sent once, as a failed response doesn't mean they had no effect.
+//!
+//! Every request goes through the [`client`] shared by the whole run. It uses the proxy of the
+//! `HTTPS_PROXY`/`HTTP_PROXY`/`NO_PROXY` environment variables, or `proxy = "http://host:port"`
+//! of the configuration file, and trusts the certificates of `ca_bundle = "/path/to/ca.pem"` or
+//! `NIX_SSL_CERT_FILE` in addition to the system ones.
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use reqwest::StatusCode;
+use tracing::{debug, warn};
+
+/// Key of the rate limit applying to hosts without their own
+const ANY_HOST: &str = "*";
+
+/// Attempts of a request before giving up on transient failures
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry, doubled on each following one
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay before a retry, longer `Retry-After` are given up on
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Build the shared client with a proxy and additional trusted certificates
+///
+/// Must be called before sending any request, the client is built without them otherwise.
+pub fn configure_client(proxy: Option<&str>, ca_bundle: Option<&Path>) -> anyhow::Result<()> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        let proxy =
+            reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy {}", proxy))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(ca_bundle) = ca_bundle {
+        let pem = std::fs::read(ca_bundle)
+            .with_context(|| format!("Failed to read CA bundle {}", ca_bundle.display()))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid CA bundle {}", ca_bundle.display()))?;
+        debug!(
+            "Trusting {} certificates of {}",
+            certificates.len(),
+            ca_bundle.display()
+        );
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    let client = builder
+        .build()
+        .context("Failed to create the HTTP client")?;
+    if CLIENT.set(client).is_err() {
+        warn!("HTTP client was already created");
+    }
+    Ok(())
+}
+
+/// Client shared by every outbound request, reusing connections across requests
+pub fn client() -> &'static reqwest::Client {
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// Set the requests per second allowed to each host, must be called before sending any request
+pub fn set_rate_limits(limits: &HashMap<String, f64>) {
+    if RATE_LIMITER.set(RateLimiter::new(limits)).is_err() {
+        warn!("Rate limits were already set");
+    }
+}
+
+/// Spaces requests to each host by the interval of its rate limit
+struct RateLimiter {
+    intervals: HashMap<String, Duration>,
+    /// Earliest time the next request to each host may be sent
+    next_slots: Mutex<HashMap<String, Instant>>,
+}
+
+impl RateLimiter {
+    fn new(limits: &HashMap<String, f64>) -> Self {
+        let intervals = limits
+            .iter()
+            .filter(|(_, per_second)| **per_second > 0.0)
+            .map(|(host, per_second)| (host.clone(), Duration::from_secs_f64(1.0 / per_second)))
+            .collect();
+        Self {
+            intervals,
+            next_slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserve the next slot for a request to `host`, returning how long to wait for it
+    fn reserve(&self, host: &str, now: Instant) -> Duration {
+        let Some(interval) = self
+            .intervals
+            .get(host)
+            .or_else(|| self.intervals.get(ANY_HOST))
+            .copied()
+        else {
+            return Duration::ZERO;
+        };
+
+        let mut next_slots = self.next_slots.lock().unwrap();
+        let slot = next_slots
+            .get(host)
+            .copied()
+            .filter(|slot| *slot > now)
+            .unwrap_or(now);
+        next_slots.insert(host.to_string(), slot + interval);
+        slot - now
+    }
+}
+
+/// Whether a response is a transient failure worth retrying
+fn is_transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Whether requests of `method` may be sent again after a transient failure
+fn is_retryable(method: &reqwest::Method) -> bool {
+    matches!(*method, reqwest::Method::GET | reqwest::Method::HEAD)
+}
+
+/// Host and path of a URL, leaving out query parameters which may hold credentials
+fn display_url(url: &reqwest::Url) -> String {
+    format!("{}{}", url.host_str().unwrap_or_default(), url.path())
+}
+
+/// Delay requested by a `Retry-After` header in seconds, HTTP dates aren't supported
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Delay before retry number `attempt` (from 1), `jitter` being a random factor in `[0, 1)`
+///
+/// The exponential delay is spread over its upper half so concurrent updates failing together
+/// don't retry together. A `Retry-After` of the server takes precedence.
+fn backoff(attempt: u32, retry_after: Option<Duration>, jitter: f64) -> Duration {
+    if let Some(retry_after) = retry_after {
+        return retry_after;
+    }
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_BACKOFF);
+    delay.mul_f64(0.5 + jitter / 2.0)
+}
+
+/// Random factor in `[0, 1)`, from the random keys of the standard library
+fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Wait for the rate limit of the host of a request
+async fn throttle(request: &reqwest::Request) {
+    if let (Some(limiter), Some(host)) = (RATE_LIMITER.get(), request.url().host_str()) {
+        let wait = limiter.reserve(host, Instant::now());
+        if !wait.is_zero() {
+            debug!("Waiting {:?} for the rate limit of {}", wait, host);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Sending requests within the rate limit of their host
+pub trait Throttled {
+    /// Send the request once the rate limit of its host allows it, retrying transient failures of
+    /// `GET` and `HEAD` requests
+    fn send_throttled(self) -> impl Future<Output = reqwest::Result<reqwest::Response>> + Send;
+}
+
+impl Throttled for reqwest::RequestBuilder {
+    async fn send_throttled(self) -> reqwest::Result<reqwest::Response> {
+        let (client, request) = self.build_split();
+        let request = request?;
+
+        let attempts = if is_retryable(request.method()) {
+            MAX_ATTEMPTS
+        } else {
+            1
+        };
+        let mut attempt = 1;
+        loop {
+            // Streamed bodies can't be sent twice
+            let Some(retry) = request.try_clone().filter(|_| attempt < attempts) else {
+                throttle(&request).await;
+                return client.execute(request).await;
+            };
+            throttle(&retry).await;
+            let (failure, delay) = match client.execute(retry).await {
+                Ok(response) if is_transient(response.status()) => {
+                    let delay = backoff(attempt, retry_after(&response), jitter());
+                    if delay > MAX_BACKOFF {
+                        return Ok(response);
+                    }
+                    (response.status().to_string(), delay)
+                },
+                Ok(response) => return Ok(response),
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => (
+                    e.without_url().to_string(),
+                    backoff(attempt, None, jitter()),
+                ),
+                Err(e) => return Err(e),
+            };
+            warn!(
+                "{} {} failed ({}), retrying in {:?}",
+                request.method(),
+                display_url(request.url()),
+                failure,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_reserve() {
+        let limiter = RateLimiter::new(&HashMap::from([
+            ("api.github.com".to_string(), 2.0),
+            (ANY_HOST.to_string(), 10.0),
+        ]));
+        let now = Instant::now();
+
+        // Requests to a host are spaced by its interval
+        assert_eq!(limiter.reserve("api.github.com", now), Duration::ZERO);
+        assert_eq!(
+            limiter.reserve("api.github.com", now),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            limiter.reserve("api.github.com", now),
+            Duration::from_secs(1)
+        );
+
+        // Other hosts fall back to the default, each with its own slots
+        assert_eq!(limiter.reserve("pypi.org", now), Duration::ZERO);
+        assert_eq!(limiter.reserve("pypi.org", now), Duration::from_millis(100));
+        assert_eq!(limiter.reserve("gitlab.com", now), Duration::ZERO);
+
+        // Slots in the past don't delay requests
+        let later = now + Duration::from_secs(5);
+        assert_eq!(limiter.reserve("api.github.com", later), Duration::ZERO);
+
+        let unlimited = RateLimiter::new(&HashMap::new());
+        assert_eq!(unlimited.reserve("api.github.com", now), Duration::ZERO);
+        assert_eq!(unlimited.reserve("api.github.com", now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1, None, 0.0), Duration::from_millis(500));
+        assert_eq!(backoff(1, None, 0.5), Duration::from_millis(750));
+        assert_eq!(backoff(3, None, 0.0), Duration::from_secs(2));
+        assert_eq!(backoff(20, None, 0.0), MAX_BACKOFF / 2);
+        assert_eq!(
+            backoff(1, Some(Duration::from_secs(30)), 0.9),
+            Duration::from_secs(30)
+        );
+        assert!((0..100).map(|_| jitter()).all(|j| (0.0..1.0).contains(&j)));
+
+        assert!(is_transient(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient(StatusCode::BAD_GATEWAY));
+        assert!(!is_transient(StatusCode::NOT_FOUND));
+        assert!(!is_transient(StatusCode::FORBIDDEN));
+
+        assert!(is_retryable(&reqwest::Method::GET));
+        assert!(is_retryable(&reqwest::Method::HEAD));
+        assert!(!is_retryable(&reqwest::Method::POST));
+        assert!(!is_retryable(&reqwest::Method::PATCH));
+    }
+
+    #[test]
+    fn test_display_url() {
+        let url = reqwest::Url::parse("https://libraries.io/api/npm/foo?api_key=secret").unwrap();
+        assert_eq!(display_url(&url), "libraries.io/api/npm/foo");
+    }
+}
@@ -0,0 +1,76 @@
+//! Version discovery and Nix rewriting for ekapkgs
+//!
+//! The `ekapkgs-update` binary is a thin CLI over this library. Tools embedding the update
+//! machinery without shelling out may rely on these modules, which follow semver:
+//!
+//! - [`vcs_sources`]: looking up the releases of upstream sources and picking the next version
+//! - [`rewrite`]: rewriting versions, hashes and other attributes of Nix files
+//! - [`database`]: the record of update attempts, failures and backoff
+//! - [`package`]: evaluating the metadata of packages and their update directives
+//! - [`plugin`]: the protocol of commands looking up the versions of other upstreams
+//!
+//! The other modules implement the CLI and may change in any release.
+
+#[warn(missing_docs)]
+pub mod database;
+#[warn(missing_docs)]
+pub mod package;
+#[warn(missing_docs)]
+pub mod plugin;
+#[warn(missing_docs)]
+pub mod rewrite;
+#[warn(missing_docs)]
+pub mod vcs_sources;
+
+#[doc(hidden)]
+pub mod cargo;
+#[doc(hidden)]
+pub mod commands;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod git;
+#[doc(hidden)]
+pub mod github;
+#[doc(hidden)]
+pub mod gitlab;
+#[doc(hidden)]
+pub mod groups;
+#[doc(hidden)]
+pub mod hooks;
+#[doc(hidden)]
+pub mod http;
+#[doc(hidden)]
+pub mod libraries_io;
+#[doc(hidden)]
+pub mod load;
+#[doc(hidden)]
+pub mod mirrors;
+#[doc(hidden)]
+pub mod nix;
+#[doc(hidden)]
+pub mod nixpkgs;
+#[doc(hidden)]
+pub mod osv;
+#[doc(hidden)]
+pub mod patches;
+#[doc(hidden)]
+pub mod plan;
+#[doc(hidden)]
+pub mod progress;
+#[doc(hidden)]
+pub mod pypi;
+#[doc(hidden)]
+pub mod retry;
+#[doc(hidden)]
+pub mod summary;
+#[doc(hidden)]
+pub mod timings;
+#[doc(hidden)]
+pub mod update_script;
+#[doc(hidden)]
+pub mod verification;
+#[doc(hidden)]
+pub mod webhook;
+#[doc(hidden)]
+pub mod withdrawn;
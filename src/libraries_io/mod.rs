@@ -0,0 +1,178 @@
+//! libraries.io API integration
+//!
+//! libraries.io aggregates the versions of packages from many registries (crates.io, npm,
+//! RubyGems, Hackage, ...), which makes it a fallback for sources no other client understands.
+//! The API requires a key, read from `LIBRARIES_IO_API_KEY`; without one, sources are never
+//! looked up on libraries.io.
+
+use std::env;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::http::{self, Throttled};
+
+/// Environment variable holding the libraries.io API key
+const API_KEY_VAR: &str = "LIBRARIES_IO_API_KEY";
+
+/// Version of a package as returned by the API
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LibrariesIoVersion {
+    pub number: String,
+    #[serde(default)]
+    pub published_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LibrariesIoProject {
+    versions: Vec<LibrariesIoVersion>,
+}
+
+/// Whether an API key is configured
+pub fn is_configured() -> bool {
+    env::var(API_KEY_VAR).is_ok_and(|key| !key.is_empty())
+}
+
+/// Parse the platform and package name of a registry download URL
+///
+/// Matches URLs like:
+/// - `https://crates.io/api/v1/crates/serde/1.0.0/download` or `https://static.crates.io/crates/serde/serde-1.0.0.crate`
+/// - `https://registry.npmjs.org/@scope/name/-/name-1.0.0.tgz`
+/// - `https://rubygems.org/gems/rails-7.1.0.gem`
+/// - `mirror://hackage/aeson-2.2.0.0.tar.gz` or `https://hackage.haskell.org/package/aeson-2.2.0.0/aeson-2.2.0.0.tar.gz`
+///
+/// Returns the libraries.io platform, e.g. `cargo`, and the package name if found
+pub fn parse_registry_url(url: &str) -> Option<(String, String)> {
+    let url = url.split(['?', '#']).next()?;
+    let path_after = |prefixes: &[&str]| {
+        prefixes
+            .iter()
+            .find_map(|prefix| url.strip_prefix(prefix))
+            .map(|path| path.split('/').collect::<Vec<_>>())
+    };
+
+    if let Some(path) = path_after(&[
+        "https://crates.io/api/v1/crates/",
+        "https://static.crates.io/crates/",
+    ]) {
+        return Some(("cargo".to_string(), path.first()?.to_string()));
+    }
+
+    if let Some(path) = path_after(&["https://registry.npmjs.org/", "mirror://npm/"]) {
+        let name = match path.first()? {
+            scope if scope.starts_with('@') => format!("{}/{}", scope, path.get(1)?),
+            name => name.to_string(),
+        };
+        return Some(("npm".to_string(), name));
+    }
+
+    if let Some(path) = path_after(&[
+        "https://rubygems.org/gems/",
+        "https://rubygems.org/downloads/",
+    ]) {
+        let file = path.last()?.strip_suffix(".gem")?;
+        return Some(("rubygems".to_string(), strip_version(file)?.to_string()));
+    }
+
+    if let Some(path) = path_after(&["mirror://hackage/", "https://hackage.haskell.org/package/"]) {
+        return Some((
+            "hackage".to_string(),
+            strip_version(path.first()?)?.to_string(),
+        ));
+    }
+
+    None
+}
+
+/// Name of a `name-version` file name, the version starting after the last dash followed by a
+/// digit
+fn strip_version(file: &str) -> Option<&str> {
+    let (name, _) = file.rmatch_indices('-').find_map(|(i, _)| {
+        file[i + 1..]
+            .starts_with(|c: char| c.is_ascii_digit())
+            .then(|| file.split_at(i))
+    })?;
+    Some(name)
+}
+
+/// Fetch every version of a package known to libraries.io
+///
+/// # Arguments
+/// * `platform` - libraries.io platform, e.g. `cargo` or `npm`
+/// * `name` - Package name on the platform
+pub async fn fetch_libraries_io_versions(
+    platform: &str,
+    name: &str,
+) -> anyhow::Result<Vec<LibrariesIoVersion>> {
+    let api_key = env::var(API_KEY_VAR)
+        .with_context(|| format!("{} is required to query libraries.io", API_KEY_VAR))?;
+    let url = format!(
+        "https://libraries.io/api/{}/{}",
+        platform,
+        name.replace('/', "%2F")
+    );
+
+    debug!("Fetching versions from {}", url);
+
+    // The API only takes the key as a query parameter, errors drop the URL so it isn't logged
+    // or reported
+    let client = http::client();
+    let response = client
+        .get(&url)
+        .query(&[("api_key", api_key)])
+        .header("User-Agent", "ekapkgs-update")
+        .send_throttled()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .with_context(|| format!("Failed to query {}", url))?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "libraries.io API request failed with status: {}",
+            response.status()
+        );
+    }
+
+    let project: LibrariesIoProject = response
+        .json()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .with_context(|| format!("Invalid response from {}", url))?;
+    Ok(project.versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_registry_url() {
+        let parse = |url| parse_registry_url(url).map(|(p, n)| format!("{}:{}", p, n));
+        assert_eq!(
+            parse("https://crates.io/api/v1/crates/serde/1.0.0/download").as_deref(),
+            Some("cargo:serde")
+        );
+        assert_eq!(
+            parse("https://static.crates.io/crates/serde-json/serde-json-1.0.0.crate").as_deref(),
+            Some("cargo:serde-json")
+        );
+        assert_eq!(
+            parse("https://registry.npmjs.org/left-pad/-/left-pad-1.3.0.tgz").as_deref(),
+            Some("npm:left-pad")
+        );
+        assert_eq!(
+            parse("https://registry.npmjs.org/@babel/core/-/core-7.24.0.tgz").as_deref(),
+            Some("npm:@babel/core")
+        );
+        assert_eq!(
+            parse("https://rubygems.org/gems/net-ssh-7.2.0.gem").as_deref(),
+            Some("rubygems:net-ssh")
+        );
+        assert_eq!(
+            parse("mirror://hackage/aeson-2.2.0.0.tar.gz").as_deref(),
+            Some("hackage:aeson")
+        );
+        assert_eq!(parse("https://example.org/foo-1.0.tar.gz"), None);
+    }
+}
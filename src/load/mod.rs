@@ -0,0 +1,215 @@
+//! Adapting the number of concurrent builds to the load of the system
+//!
+//! Packages vendoring their dependencies (Rust, Go, ...) can take several gigabytes of memory to
+//! build, so a fixed number of concurrent builds which is fine most of the time can run out of
+//! memory when a few of them happen to build at once. The load average and available memory are
+//! sampled periodically, and build slots are held back while the system is overloaded.
+//!
+//! The one-minute load average lags behind the builds started or stopped, so it is compared with
+//! the five-minute one to tell whether the load is still rising, and slots are only given back
+//! once the system stayed calm for a minute. Otherwise concurrency would oscillate with the lag.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info};
+
+/// Interval at which the system load is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Available memory below which fewer builds are run
+const LOW_MEMORY_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Available memory above which more builds may be run
+const AMPLE_MEMORY_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Consecutive calm samples before another build is allowed, a minute at [`SAMPLE_INTERVAL`]
+const CALM_SAMPLES: u32 = 6;
+
+/// Load of the system at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadSample {
+    /// Load average over the last minute
+    pub load_average: f64,
+    /// Load average over the last five minutes
+    pub load_average_5min: f64,
+    /// Memory available to new processes, in bytes
+    pub available_memory: u64,
+}
+
+impl LoadSample {
+    /// Sample the load of the system, if it can be determined (Linux only)
+    pub async fn read() -> Option<Self> {
+        let loadavg = tokio::fs::read_to_string("/proc/loadavg").await.ok()?;
+        let meminfo = tokio::fs::read_to_string("/proc/meminfo").await.ok()?;
+        let (load_average, load_average_5min) = parse_load_averages(&loadavg)?;
+        Some(Self {
+            load_average,
+            load_average_5min,
+            available_memory: parse_available_memory(&meminfo)?,
+        })
+    }
+
+    /// Whether the load is still increasing
+    fn load_rising(&self) -> bool {
+        self.load_average >= self.load_average_5min
+    }
+}
+
+/// Parse the one and five-minute load averages from the contents of `/proc/loadavg`
+fn parse_load_averages(loadavg: &str) -> Option<(f64, f64)> {
+    let mut averages = loadavg.split_whitespace().map(|field| field.parse().ok());
+    Some((averages.next()??, averages.next()??))
+}
+
+/// Parse `MemAvailable` from the contents of `/proc/meminfo`, in bytes
+fn parse_available_memory(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let kilobytes = line
+            .strip_prefix("MemAvailable:")?
+            .trim()
+            .strip_suffix("kB")?;
+        kilobytes.trim().parse::<u64>().ok().map(|kb| kb * 1024)
+    })
+}
+
+/// Number of builds to allow, adjusted one step at a time to the samples of the system load
+struct Concurrency {
+    target: usize,
+    max: usize,
+    cpus: f64,
+    /// Consecutive samples with ample memory and idle CPUs
+    calm_samples: u32,
+}
+
+impl Concurrency {
+    fn new(max: usize, cpus: usize) -> Self {
+        Self {
+            target: max,
+            max,
+            cpus: cpus.max(1) as f64,
+            calm_samples: 0,
+        }
+    }
+
+    /// Adjust the number of builds to a new sample
+    ///
+    /// Builds are reduced while memory runs low, or while the load exceeds the CPUs by half and
+    /// keeps rising. They're increased again once memory was ample and the CPUs weren't saturated
+    /// for [`CALM_SAMPLES`] samples in a row.
+    fn adjust(&mut self, sample: LoadSample) -> usize {
+        let overloaded = sample.load_average > self.cpus * 1.5 && sample.load_rising();
+        let calm = sample.available_memory > AMPLE_MEMORY_BYTES
+            && sample.load_average < self.cpus
+            && sample.load_average_5min < self.cpus;
+
+        if sample.available_memory < LOW_MEMORY_BYTES || overloaded {
+            self.calm_samples = 0;
+            self.target = self.target.saturating_sub(1).max(1);
+        } else if calm {
+            self.calm_samples += 1;
+            if self.calm_samples >= CALM_SAMPLES && self.target < self.max {
+                self.calm_samples = 0;
+                self.target += 1;
+            }
+        } else {
+            self.calm_samples = 0;
+        }
+        self.target
+    }
+}
+
+/// Adapt the permits of `slots`, out of `max`, to the load of the system until the task is
+/// aborted
+///
+/// Permits are held back by acquiring them, so slots in use are only taken away once their build
+/// finishes. Nothing is adapted if the load of the system can't be determined.
+pub async fn adapt_concurrency(slots: Arc<Semaphore>, max: usize) {
+    let mut concurrency = Concurrency::new(max, num_cpus::get());
+    let mut held: Vec<OwnedSemaphorePermit> = Vec::new();
+    let mut target = max;
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let Some(sample) = LoadSample::read().await else {
+            debug!("System load unavailable, not adapting concurrency");
+            return;
+        };
+
+        let next = concurrency.adjust(sample);
+        if next != target {
+            info!(
+                "Adapting concurrent builds from {} to {} (load {:.2}, {} MiB available)",
+                target,
+                next,
+                sample.load_average,
+                sample.available_memory / (1024 * 1024)
+            );
+            target = next;
+        }
+
+        while held.len() > max - target {
+            held.pop();
+        }
+        while held.len() < max - target {
+            match slots.clone().try_acquire_owned() {
+                Ok(permit) => held.push(permit),
+                // Every slot is in use, retry once some build finished
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn test_parse_load() {
+        assert_eq!(
+            parse_load_averages("3.25 2.10 1.05 4/1234 56789\n"),
+            Some((3.25, 2.10))
+        );
+        assert_eq!(parse_load_averages("3.25\n"), None);
+        let meminfo = "MemTotal:       32768000 kB\nMemFree:         1024000 kB\nMemAvailable:    \
+                       8192000 kB\n";
+        assert_eq!(parse_available_memory(meminfo), Some(8192000 * 1024));
+        assert_eq!(parse_available_memory("MemTotal: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn test_adjust_concurrency() {
+        let sample = |load_average, load_average_5min, available_memory| LoadSample {
+            load_average,
+            load_average_5min,
+            available_memory,
+        };
+        let mut concurrency = Concurrency::new(4, 8);
+
+        // Low memory or overloaded CPUs
+        assert_eq!(concurrency.adjust(sample(1.0, 1.0, GIB)), 3);
+        assert_eq!(concurrency.adjust(sample(16.0, 12.0, 16 * GIB)), 2);
+        // Still overloaded, but the load is already going down
+        assert_eq!(concurrency.adjust(sample(14.0, 15.0, 16 * GIB)), 2);
+        assert_eq!(concurrency.adjust(sample(16.0, 16.0, GIB)), 1);
+        assert_eq!(concurrency.adjust(sample(16.0, 16.0, GIB)), 1);
+
+        // Recovered for a minute
+        for _ in 1..CALM_SAMPLES {
+            assert_eq!(concurrency.adjust(sample(2.0, 4.0, 16 * GIB)), 1);
+        }
+        assert_eq!(concurrency.adjust(sample(2.0, 4.0, 16 * GIB)), 2);
+
+        // A busy sample starts the minute over
+        for _ in 1..CALM_SAMPLES {
+            concurrency.adjust(sample(2.0, 4.0, 16 * GIB));
+        }
+        assert_eq!(concurrency.adjust(sample(10.0, 4.0, 3 * GIB)), 2);
+        assert_eq!(concurrency.adjust(sample(2.0, 4.0, 16 * GIB)), 2);
+    }
+}
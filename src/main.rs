@@ -1,17 +1,15 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use clap::{Parser, Subcommand};
+use ekapkgs_update::commands::run::RunArgs;
+use ekapkgs_update::nix::run_eval::EvalJobsOptions;
+use ekapkgs_update::nix::{BuildOptions, ImportArgs, NixOptions};
+use ekapkgs_update::{commands, config, git, http, nix, progress};
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::EnvFilter;
-
-mod commands;
-mod database;
-mod git;
-mod github;
-mod gitlab;
-mod nix;
-mod package;
-mod pypi;
-mod rewrite;
-mod vcs_sources;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
 
 #[derive(Parser)]
 #[command(name = "ekapkgs-update")]
@@ -19,6 +17,70 @@ mod vcs_sources;
 struct Args {
     #[command(subcommand)]
     command: Commands,
+    /// Nix store to build in, e.g. `ssh-ng://builder`. Passed to every nix invocation
+    #[arg(long, global = true)]
+    store: Option<String>,
+    /// Remote builders to use, e.g. `ssh://builder x86_64-linux`. Passed to every nix invocation
+    #[arg(long, global = true)]
+    builders: Option<String>,
+    /// Extra nix option as `name=value`, passed to every nix invocation. May be given multiple
+    /// times
+    #[arg(long = "option", global = true)]
+    nix_options: Vec<String>,
+    /// Argument of the entry point as `name=expr`, e.g. `system='"x86_64-linux"'`. May be given
+    /// multiple times
+    #[arg(long = "arg", global = true)]
+    import_args: Vec<String>,
+    /// String argument of the entry point as `name=value`, e.g. `system=x86_64-linux`. May be
+    /// given multiple times
+    #[arg(long = "argstr", global = true)]
+    import_argstrs: Vec<String>,
+    /// Attribute set of arguments of the entry point, e.g. `{ config.allowUnfree = true; }`
+    #[arg(long, global = true)]
+    eval_args: Option<String>,
+    /// TOML file with per-package settings, e.g. tag prefixes for monorepos
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Days a release must have been published for before it is proposed, so quick follow-up
+    /// fixes of broken releases are picked up instead. Overrides `min_release_age` of the
+    /// config file, not the per-package settings
+    #[arg(long, global = true)]
+    min_release_age: Option<u64>,
+    /// Sign generated commits, e.g. for branches requiring signed commits. Uses git's signing
+    /// settings unless --signing-key or --signing-format is given
+    #[arg(long, global = true)]
+    sign_commits: bool,
+    /// Key to sign commits with: a GPG key ID, or the path of an SSH public key. Implies
+    /// --sign-commits
+    #[arg(long, global = true)]
+    signing_key: Option<String>,
+    /// Signature format of signed commits. Implies --sign-commits
+    #[arg(long, global = true, value_enum)]
+    signing_format: Option<git::SigningFormat>,
+    /// Directory to create the worktrees of updates in, e.g. on a tmpfs or scratch disk.
+    /// Defaults to the cache directory
+    #[arg(long, global = true)]
+    worktree_dir: Option<String>,
+    /// File to write debug-level logs to, in addition to the console. Rotated daily by
+    /// appending the date to the file name
+    #[arg(long, global = true)]
+    log_file: Option<String>,
+    /// Number of nix-eval-jobs workers evaluating the tree
+    #[arg(long, global = true)]
+    eval_workers: Option<usize>,
+    /// Memory in MiB a nix-eval-jobs worker may use before it is replaced by a fresh one
+    #[arg(long, global = true)]
+    eval_max_memory_size: Option<usize>,
+    /// Directory to register GC roots of the evaluated derivations in
+    #[arg(long, global = true)]
+    eval_gc_roots_dir: Option<String>,
+    /// Extra argument passed to nix-eval-jobs. May be given multiple times
+    #[arg(long = "eval-jobs-arg", global = true, allow_hyphen_values = true)]
+    eval_jobs_args: Vec<String>,
+    /// Number of times nix-eval-jobs is restarted with half the workers after running out of
+    /// memory
+    #[arg(long, global = true, default_value = "2")]
+    eval_restarts: u32,
 }
 
 #[derive(Subcommand)]
@@ -40,23 +102,147 @@ enum Commands {
         /// Run passthru.tests if available before considering update successful
         #[arg(long)]
         run_passthru_tests: bool,
+        /// Seconds each passthru test may take to build before it is reported as timed out
+        #[arg(long, default_value = "3600")]
+        passthru_test_timeout: u64,
+        /// Seconds a single build or update script may take before it is killed and recorded as
+        /// failed
+        #[arg(long)]
+        build_timeout: Option<u64>,
+        /// Maximum number of concurrent build jobs of each nix-build, passed as `--max-jobs`
+        #[arg(long)]
+        max_build_jobs: Option<usize>,
+        /// Cores available to each build job, passed as `--cores`
+        #[arg(long)]
+        cores: Option<usize>,
+        /// Number of times to retry builds failing for transient reasons, e.g. network errors
+        #[arg(long, default_value = "2")]
+        build_retries: u32,
+        /// Additional systems to build updated packages for, e.g. `aarch64-linux`, if listed in
+        /// their meta.platforms. Requires remote builders or emulation
+        #[arg(long = "verify-system", value_delimiter = ',')]
+        verify_systems: Vec<String>,
+        /// Platforms to cross-compile updated packages for, e.g. `aarch64-unknown-linux-gnu`.
+        /// Requires the entry point to accept `crossSystem`
+        #[arg(long = "verify-cross-system", value_delimiter = ',')]
+        verify_cross_systems: Vec<String>,
         /// Check for updates without rewriting, building, committing, or creating PRs
         #[arg(long)]
         dry_run: bool,
-        /// Maximum number of concurrent package updates (default: CPU cores / 4)
+        /// Maximum number of packages rewritten and built concurrently (default: CPU cores / 4)
         #[arg(long)]
         concurrent_updates: Option<usize>,
+        /// Maximum number of packages checked for updates concurrently, which mostly waits on
+        /// evaluation and upstream APIs (default: CPU cores)
+        #[arg(long)]
+        concurrent_checks: Option<usize>,
+        /// Run fewer concurrent builds while memory runs low or the load average exceeds the CPU
+        /// cores, up to --concurrent-updates
+        #[arg(long)]
+        adaptive_concurrency: bool,
+        /// Check the releases.atom feed of GitHub packages before the API, skipping the API
+        /// requests when the feed lists no newer release. Saves most of the API rate limit on
+        /// large runs
+        #[arg(long)]
+        release_feeds: bool,
+        /// Seconds the releases fetched from upstream are cached in the database, shared by
+        /// packages with the same upstream and by consecutive runs. 0 disables the cache
+        #[arg(long, default_value = "3600")]
+        release_cache_ttl: u64,
         /// Skip packages with 'unstable' in their version
         #[arg(long)]
         skip_unstable: bool,
+        /// Ignore update scripts and use the generic update method for every package
+        #[arg(long, default_value = "false")]
+        ignore_update_script: bool,
+        /// Additional dependency hash attribute to refresh, e.g. `mixFodDeps.outputHash` or
+        /// `npmDeps.outputHash=npmDepsHash`. May be given multiple times
+        #[arg(long = "dependency-hash-attr")]
+        dependency_hash_attrs: Vec<String>,
+        /// Formatter to run on rewritten Nix files before committing, e.g. `nixfmt`,
+        /// `alejandra` or `treefmt`
+        #[arg(long)]
+        formatter: Option<String>,
+        /// GitHub handle of a maintainer not to ping in PRs. May be given multiple times
+        #[arg(long = "maintainer-opt-out", value_delimiter = ',')]
+        maintainer_opt_out: Vec<String>,
+        /// Verify direct reverse dependencies of updated packages and report them in the PR.
+        /// Requires evaluating the whole package set before updating
+        #[arg(long, value_enum)]
+        verify_reverse_deps: Option<commands::run::ReverseDepsMode>,
+        /// Query OSV.dev for known vulnerabilities fixed by updates and list them in PRs
+        #[arg(long)]
+        check_advisories: bool,
+        /// Only propose updates fixing known vulnerabilities, e.g. for stable branches.
+        /// Implies --check-advisories
+        #[arg(long)]
+        security_only: bool,
+        /// Update packages marked with meta.knownVulnerabilities or meta.insecure before the
+        /// others. Requires evaluating the whole package set before updating, and the tree to
+        /// permit insecure packages
+        #[arg(long)]
+        prioritize_insecure: bool,
+        /// TOML file defining groups of packages to update together in a single PR, e.g.
+        /// packages which must move in lockstep
+        #[arg(long)]
+        groups: Option<String>,
+        /// Update packages fetched from the same GitHub or GitLab repository together in a
+        /// single PR. Requires evaluating the source of every package before updating
+        #[arg(long)]
+        auto_group: bool,
+        /// Keep update branches of the fork whose PRs were merged or closed, instead of deleting
+        /// them at the start of the run
+        #[arg(long)]
+        keep_closed_branches: bool,
+        /// Write the updates found by a dry run to this JSON plan file, to review before
+        /// carrying them out with --apply
+        #[arg(long, requires = "dry_run")]
+        plan_out: Option<String>,
+        /// Only update the packages of a plan written by --plan-out, to the planned versions.
+        /// Planned packages are updated even in their backoff period
+        #[arg(long)]
+        apply: Option<String>,
+        /// Open an issue on the upstream repository once updating a package failed this many
+        /// times in a row, and comment later failures on it. Requires GITHUB_TOKEN
+        #[arg(long)]
+        failure_issue_threshold: Option<i64>,
+        /// Don't show the live progress display, which is only shown on terminals anyway
+        #[arg(long)]
+        no_progress: bool,
+        /// Instead of checking every package, listen on this address, e.g. `127.0.0.1:8080`, for
+        /// GitHub release webhooks or POSTs of `{"attr_path": ...}` and update the packages they
+        /// name. Requests must be signed with the secret in WEBHOOK_SECRET, which is required
+        /// unless listening on a loopback address
+        #[arg(long, conflicts_with_all = ["groups", "auto_group", "apply", "plan_out"])]
+        listen: Option<String>,
+        /// Commit each logical change of an update separately, e.g. the version bump, refreshed
+        /// dependency hashes and removed patches, instead of a single commit
+        #[arg(long)]
+        split_commits: bool,
+        /// Stop updating packages once this many were updated, e.g. to keep the review load of
+        /// scheduled runs predictable. The other packages are left to the next run
+        #[arg(long)]
+        max_updates: Option<usize>,
+        /// Stop updating packages once the run took this long, e.g. `45m` or `1h30m`. Updates in
+        /// progress are finished and the other packages are left to the next run
+        #[arg(long, value_parser = ekapkgs_update::timings::parse_duration)]
+        max_duration: Option<Duration>,
+        /// Write a summary of the run, with the PRs created and the errors of failed updates, to
+        /// this file: Markdown if it ends in `.md`, JSON otherwise. May be given multiple times
+        #[arg(long)]
+        summary_out: Vec<String>,
     },
-    /// Update a package in a Nix file
+    /// Update one or more packages in a Nix file
     Update {
         /// Nix file to update
         #[arg(short, long, default_value = "default.nix")]
         file: String,
-        /// Attribute path of the package to update
-        attr_path: String,
+        /// Attribute paths of the packages to update
+        #[arg(required_unless_present = "attrs_file")]
+        attr_paths: Vec<String>,
+        /// File listing attribute paths of packages to update, one per line. `#` starts a comment
+        #[arg(long)]
+        attrs_file: Option<String>,
         /// Version selection strategy: latest, major, minor, or patch
         #[arg(long, default_value = "latest")]
         semver: String,
@@ -80,6 +266,120 @@ enum Commands {
         /// Run passthru.tests if available before considering update successful
         #[arg(long)]
         run_passthru_tests: bool,
+        /// Seconds each passthru test may take to build before it is reported as timed out
+        #[arg(long, default_value = "3600")]
+        passthru_test_timeout: u64,
+        /// Seconds a single build or update script may take before it is killed and recorded as
+        /// failed
+        #[arg(long)]
+        build_timeout: Option<u64>,
+        /// Maximum number of concurrent build jobs of each nix-build, passed as `--max-jobs`
+        #[arg(long)]
+        max_build_jobs: Option<usize>,
+        /// Cores available to each build job, passed as `--cores`
+        #[arg(long)]
+        cores: Option<usize>,
+        /// Number of times to retry builds failing for transient reasons, e.g. network errors
+        #[arg(long, default_value = "2")]
+        build_retries: u32,
+        /// Additional systems to build updated packages for, e.g. `aarch64-linux`, if listed in
+        /// their meta.platforms. Requires remote builders or emulation
+        #[arg(long = "verify-system", value_delimiter = ',')]
+        verify_systems: Vec<String>,
+        /// Platforms to cross-compile updated packages for, e.g. `aarch64-unknown-linux-gnu`.
+        /// Requires the entry point to accept `crossSystem`
+        #[arg(long = "verify-cross-system", value_delimiter = ',')]
+        verify_cross_systems: Vec<String>,
+        /// Additional dependency hash attribute to refresh, e.g. `mixFodDeps.outputHash` or
+        /// `npmDeps.outputHash=npmDepsHash`. May be given multiple times
+        #[arg(long = "dependency-hash-attr")]
+        dependency_hash_attrs: Vec<String>,
+        /// Formatter to run on rewritten Nix files before committing, e.g. `nixfmt`,
+        /// `alejandra` or `treefmt`
+        #[arg(long)]
+        formatter: Option<String>,
+        /// GitHub handle of a maintainer not to ping in PRs. May be given multiple times
+        #[arg(long = "maintainer-opt-out", value_delimiter = ',')]
+        maintainer_opt_out: Vec<String>,
+        /// Rewrite the package even if its directory has uncommitted changes
+        #[arg(long)]
+        allow_dirty: bool,
+        /// Commit each logical change of an update separately, e.g. the version bump, refreshed
+        /// dependency hashes and removed patches, instead of a single commit
+        #[arg(long)]
+        split_commits: bool,
+        /// Commit the updates of all packages in a single commit instead of one commit per
+        /// package. Only used with --commit
+        #[arg(long, conflicts_with = "create_pr")]
+        single_commit: bool,
+    },
+    /// Print the rewrite an update of a package would make, without building or committing it
+    Diff {
+        /// Nix file to update
+        #[arg(short, long, default_value = "default.nix")]
+        file: String,
+        /// Attribute path of the package to update
+        attr_path: String,
+        /// Version selection strategy: latest, major, minor, or patch
+        #[arg(long, default_value = "latest")]
+        semver: String,
+    },
+    /// List packages with newer upstream releases without building anything, exiting with an
+    /// error if there are any
+    Outdated {
+        /// Nix file to evaluate
+        #[arg(short, long, default_value = "default.nix")]
+        file: String,
+        /// Version selection strategy: latest, major, minor, or patch
+        #[arg(long, default_value = "latest")]
+        semver: String,
+        /// Maximum number of packages checked concurrently (default: CPU cores)
+        #[arg(long)]
+        concurrent_checks: Option<usize>,
+        /// Skip packages with 'unstable' in their version
+        #[arg(long)]
+        skip_unstable: bool,
+    },
+    /// Copy the expression of a package, and the patches it refers to, from nixpkgs
+    SyncFromNixpkgs {
+        /// Nix file to evaluate
+        #[arg(short, long, default_value = "default.nix")]
+        file: String,
+        /// Attribute path of the package to sync
+        attr_path: String,
+        /// nixpkgs checkout or channel to sync from, e.g. `../nixpkgs` or `<nixpkgs>`. Defaults
+        /// to `nixpkgs` of the config file
+        #[arg(long)]
+        nixpkgs: Option<String>,
+        /// Create a git commit after syncing
+        #[arg(long)]
+        commit: bool,
+        /// Create a pull request after syncing (implies --commit)
+        #[arg(long)]
+        create_pr: bool,
+        /// Upstream git remote. Inferred if left unset. E.g. nixpkgs.
+        /// Only used with --create-pr.
+        #[arg(long)]
+        upstream: Option<String>,
+        /// Remote repository to push branches. E.g. my-fork
+        /// Only used with --create-pr.
+        #[arg(long, default_value = "origin")]
+        fork: String,
+        /// Sync the package even if its directory has uncommitted changes
+        #[arg(long)]
+        allow_dirty: bool,
+    },
+    /// Rebase the branches of open update PRs onto the latest base branch
+    RebasePrs {
+        /// Upstream git remote. Inferred if left unset. E.g. nixpkgs
+        #[arg(long)]
+        upstream: Option<String>,
+        /// Remote repository the update branches were pushed to. E.g. my-fork
+        #[arg(long, default_value = "origin")]
+        fork: String,
+        /// List the branches which would be rebased without rebasing them
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Prune maintainers from all .nix files in a directory
     PruneMaintainers {
@@ -89,11 +389,69 @@ enum Commands {
         #[arg(long, default_value = "false")]
         check: bool,
     },
-    /// Show update failure logs for a package
+    /// Set an attribute to a new value in all .nix files in a directory
+    RewriteAttr {
+        /// Directory to process
+        directory: String,
+        /// Attribute to rewrite, e.g. `homepage` or `meta.homepage`
+        #[arg(long)]
+        attr: String,
+        /// New value of the attribute
+        #[arg(long)]
+        value: String,
+        /// Only rewrite the attribute where it currently holds this value
+        #[arg(long)]
+        old_value: Option<String>,
+        /// Check mode: print the changes and fail if any would be made, without modifying files
+        #[arg(long, default_value = "false")]
+        check: bool,
+    },
+    /// Convert legacy `sha256` attributes to SRI `hash` attributes in all .nix files in a
+    /// directory
+    NormalizeHashes {
+        /// Directory to process
+        directory: String,
+        /// Check mode: print the changes and fail if any would be made, without modifying files
+        #[arg(long, default_value = "false")]
+        check: bool,
+    },
+    /// Replace placeholder hashes left by failed or interrupted updates with the correct hashes
+    FixFakeHashes {
+        /// Nix file to evaluate
+        #[arg(short, long, default_value = "default.nix")]
+        file: String,
+        /// Attribute paths of the packages to repair. By default, every file of the tree with
+        /// placeholder hashes is repaired as part of the package it defines
+        attr_paths: Vec<String>,
+        /// Check mode: report the files with placeholder hashes and fail if there are any,
+        /// without modifying files
+        #[arg(long, default_value = "false")]
+        check: bool,
+        /// Seconds a single build may take before it is killed
+        #[arg(long)]
+        build_timeout: Option<u64>,
+    },
+    /// Show update failure and evaluation error logs for a package
     Log {
         /// Drv path (e.g., /nix/store/...drv or hash-name.drv) or attr path (e.g.,
         /// python.pkgs.setuptools)
-        identifier: String,
+        #[arg(required_unless_present = "eval_errors")]
+        identifier: Option<String>,
+        /// List the packages failing to evaluate as of the latest run instead
+        #[arg(long, conflicts_with = "identifier")]
+        eval_errors: bool,
+        /// Path to SQLite database for tracking updates
+        #[arg(short, long, default_value = "~/.cache/ekapkgs-update/updates.db")]
+        database: String,
+    },
+    /// Show the update timeline of a package: version changes, failed updates and PRs, with
+    /// their state if GITHUB_TOKEN is set
+    History {
+        /// Attribute path of the package
+        attr_path: String,
+        /// Output format
+        #[arg(long, value_enum, default_value = "text")]
+        format: commands::history::HistoryFormat,
         /// Path to SQLite database for tracking updates
         #[arg(short, long, default_value = "~/.cache/ekapkgs-update/updates.db")]
         database: String,
@@ -102,19 +460,123 @@ enum Commands {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
+    let args = Args::parse();
+
+    // Logs are printed to stdout too, which only the JSON document should be printed to
+    let console_level = match args.command {
+        Commands::History {
+            format: commands::history::HistoryFormat::Json,
+            ..
+        } => LevelFilter::WARN,
+        _ => LevelFilter::INFO,
+    };
+    let console_layer = tracing_subscriber::fmt::layer()
+        .with_writer(progress::ConsoleWriter)
         .with_ansi(true)
         .with_level(true)
         .with_target(true)
         .with_timer(tracing_subscriber::fmt::time())
+        .with_filter(
+            EnvFilter::builder()
+                .with_default_directive(console_level.into())
+                .from_env_lossy(),
+        );
+
+    // Keeps flushing the log file until main returns
+    let mut _log_file_guard = None;
+    let file_layer = match &args.log_file {
+        Some(log_file) => {
+            let path = Path::new(log_file);
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("Invalid log file path: {}", log_file))?;
+            let directory = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let (writer, guard) = tracing_appender::non_blocking(tracing_appender::rolling::daily(
+                directory, file_name,
+            ));
+            _log_file_guard = Some(guard);
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(writer)
+                    .with_filter(EnvFilter::new("info,ekapkgs_update=debug")),
+            )
+        },
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(console_layer)
+        .with(file_layer)
         .init();
 
-    let args = Args::parse();
+    nix::set_nix_options(NixOptions {
+        store: args.store,
+        builders: args.builders,
+        options: args
+            .nix_options
+            .iter()
+            .map(|spec| NixOptions::parse_option(spec))
+            .collect::<anyhow::Result<_>>()?,
+    });
+    nix::set_import_args(ImportArgs {
+        args: args
+            .import_args
+            .iter()
+            .map(|spec| ImportArgs::parse_arg(spec))
+            .collect::<anyhow::Result<_>>()?,
+        argstrs: args
+            .import_argstrs
+            .iter()
+            .map(|spec| ImportArgs::parse_arg(spec))
+            .collect::<anyhow::Result<_>>()?,
+        eval_args: args.eval_args,
+    });
+
+    if args.sign_commits || args.signing_key.is_some() || args.signing_format.is_some() {
+        git::set_commit_signing(git::CommitSigning {
+            key: args
+                .signing_key
+                .map(|key| shellexpand::tilde(&key).to_string()),
+            format: args.signing_format,
+        });
+    }
+
+    let mut config = match args.config {
+        Some(path) => config::Config::load(Path::new(&shellexpand::tilde(&path).to_string()))?,
+        None => config::Config::default(),
+    };
+    if args.min_release_age.is_some() {
+        config.min_release_age = args.min_release_age;
+    }
+    if let Some(dir) = args.worktree_dir.or_else(|| config.worktree_dir.clone()) {
+        git::set_worktree_dir(shellexpand::tilde(&dir).to_string().into());
+    }
+    http::set_rate_limits(&config.rate_limits);
+    let ca_bundle = config
+        .ca_bundle
+        .clone()
+        .or_else(|| {
+            std::env::var("NIX_SSL_CERT_FILE")
+                .ok()
+                .filter(|path| Path::new(path).is_file())
+        })
+        .map(|path| PathBuf::from(shellexpand::tilde(&path).to_string()));
+    http::configure_client(config.proxy.as_deref(), ca_bundle.as_deref())?;
+
+    let eval_jobs_options = EvalJobsOptions {
+        workers: args.eval_workers,
+        max_memory_size: args.eval_max_memory_size,
+        gc_roots_dir: args
+            .eval_gc_roots_dir
+            .map(|dir| shellexpand::tilde(&dir).to_string()),
+        extra_args: args.eval_jobs_args,
+        restarts: args.eval_restarts,
+        meta: false,
+    };
 
     match args.command {
         Commands::Run {
@@ -123,25 +585,93 @@ async fn main() -> anyhow::Result<()> {
             upstream,
             fork,
             run_passthru_tests,
+            passthru_test_timeout,
+            build_timeout,
+            max_build_jobs,
+            cores,
+            build_retries,
+            verify_systems,
+            verify_cross_systems,
             dry_run,
             concurrent_updates,
+            concurrent_checks,
+            adaptive_concurrency,
+            release_feeds,
+            release_cache_ttl,
             skip_unstable,
+            ignore_update_script,
+            dependency_hash_attrs,
+            formatter,
+            maintainer_opt_out,
+            verify_reverse_deps,
+            check_advisories,
+            security_only,
+            prioritize_insecure,
+            groups,
+            auto_group,
+            keep_closed_branches,
+            split_commits,
+            plan_out,
+            apply,
+            failure_issue_threshold,
+            no_progress,
+            listen,
+            max_updates,
+            max_duration,
+            summary_out,
         } => {
-            commands::run::run(
+            commands::run::run(RunArgs {
                 file,
-                database,
+                database_path: database,
                 upstream,
                 fork,
                 run_passthru_tests,
+                passthru_test_timeout,
+                build_options: BuildOptions {
+                    timeout: build_timeout.map(Duration::from_secs),
+                    max_jobs: max_build_jobs,
+                    cores,
+                    retries: build_retries,
+                    ..Default::default()
+                },
+                verify_systems,
+                verify_cross_systems,
                 dry_run,
                 concurrent_updates,
+                concurrent_checks,
+                adaptive_concurrency,
+                release_feeds,
+                release_cache_ttl,
                 skip_unstable,
-            )
+                ignore_update_script,
+                dependency_hash_attrs,
+                formatter,
+                verify_reverse_deps,
+                check_advisories,
+                security_only,
+                prioritize_insecure,
+                maintainer_opt_out,
+                groups_file: groups,
+                auto_group,
+                keep_closed_branches,
+                split_commits,
+                plan_out,
+                apply,
+                failure_issue_threshold,
+                show_progress: !no_progress,
+                listen,
+                max_updates,
+                max_duration,
+                summary_out,
+                eval_jobs_options,
+                config,
+            })
             .await?
         },
         Commands::Update {
             file,
-            attr_path,
+            attr_paths,
+            attrs_file,
             semver,
             ignore_update_script,
             commit,
@@ -149,10 +679,24 @@ async fn main() -> anyhow::Result<()> {
             upstream,
             fork,
             run_passthru_tests,
+            passthru_test_timeout,
+            build_timeout,
+            max_build_jobs,
+            cores,
+            build_retries,
+            verify_systems,
+            verify_cross_systems,
+            dependency_hash_attrs,
+            formatter,
+            maintainer_opt_out,
+            allow_dirty,
+            split_commits,
+            single_commit,
         } => {
             commands::update::update(
                 file,
-                attr_path,
+                attr_paths,
+                attrs_file,
                 semver,
                 ignore_update_script,
                 commit,
@@ -160,16 +704,116 @@ async fn main() -> anyhow::Result<()> {
                 upstream,
                 fork,
                 run_passthru_tests,
+                passthru_test_timeout,
+                BuildOptions {
+                    timeout: build_timeout.map(Duration::from_secs),
+                    max_jobs: max_build_jobs,
+                    cores,
+                    retries: build_retries,
+                    ..Default::default()
+                },
+                verify_systems,
+                verify_cross_systems,
+                dependency_hash_attrs,
+                formatter,
+                maintainer_opt_out,
+                allow_dirty,
+                split_commits,
+                single_commit,
+                config,
             )
             .await?
         },
+        Commands::Diff {
+            file,
+            attr_path,
+            semver,
+        } => commands::diff::diff(file, attr_path, semver, config).await?,
+        Commands::Outdated {
+            file,
+            semver,
+            concurrent_checks,
+            skip_unstable,
+        } => {
+            commands::outdated::outdated(
+                file,
+                semver,
+                concurrent_checks,
+                skip_unstable,
+                eval_jobs_options,
+                config,
+            )
+            .await?
+        },
+        Commands::SyncFromNixpkgs {
+            file,
+            attr_path,
+            nixpkgs,
+            commit,
+            create_pr,
+            upstream,
+            fork,
+            allow_dirty,
+        } => {
+            commands::sync_from_nixpkgs::sync_from_nixpkgs(
+                file,
+                attr_path,
+                nixpkgs,
+                commit,
+                create_pr,
+                upstream,
+                fork,
+                allow_dirty,
+                config,
+            )
+            .await?
+        },
+        Commands::RebasePrs {
+            upstream,
+            fork,
+            dry_run,
+        } => commands::rebase_prs::rebase_prs(upstream, fork, dry_run).await?,
         Commands::PruneMaintainers { directory, check } => {
             commands::prune_maintainers::prune_maintainers(directory, check).await?
         },
+        Commands::RewriteAttr {
+            directory,
+            attr,
+            value,
+            old_value,
+            check,
+        } => commands::rewrite_attr::rewrite_attr(directory, attr, value, old_value, check).await?,
+        Commands::NormalizeHashes { directory, check } => {
+            commands::normalize_hashes::normalize_hashes(directory, check).await?
+        },
+        Commands::FixFakeHashes {
+            file,
+            attr_paths,
+            check,
+            build_timeout,
+        } => {
+            commands::fix_fake_hashes::fix_fake_hashes(
+                file,
+                attr_paths,
+                BuildOptions {
+                    timeout: build_timeout.map(Duration::from_secs),
+                    ..Default::default()
+                },
+                check,
+            )
+            .await?
+        },
         Commands::Log {
-            identifier,
+            identifier: Some(identifier),
             database,
+            ..
         } => commands::log::show_log(database, identifier).await?,
+        Commands::Log { database, .. } => commands::log::show_eval_errors(database).await?,
+        Commands::History {
+            attr_path,
+            format,
+            database,
+        } => commands::history::show_history(database, attr_path, format).await?,
     }
 
     Ok(())
@@ -0,0 +1,386 @@
+//! Release discovery for sources fetched from `mirror://` URLs
+//!
+//! nixpkgs fetches the releases of GNU, KDE, X.Org, Apache and CPAN packages from mirror networks
+//! rather than a code hosting platform. These publish no API, but their canonical download
+//! servers list the released files in directory indexes, and SourceForge lists the files of a
+//! project in an RSS feed, so newer versions are found by matching the listed file names against
+//! the current source's.
+//!
+//! Apache projects are also fetched from `dlcdn.apache.org`, `downloads.apache.org` or
+//! `archive.apache.org` directly, which are handled as the `apache` mirror. Only the latest
+//! releases of each project are kept on the download servers, older ones are listed by the
+//! archive.
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+use tracing::debug;
+
+use crate::http::{self, Throttled};
+
+/// Canonical download servers of the mirror networks listing their files in directory indexes
+const DIRECTORY_MIRRORS: &[(&str, &str)] = &[
+    ("gnu", "https://ftp.gnu.org/gnu/"),
+    ("kde", "https://download.kde.org/"),
+    ("xorg", "https://xorg.freedesktop.org/releases/"),
+    ("apache", "https://downloads.apache.org/"),
+    ("cpan", "https://cpan.metacpan.org/"),
+];
+
+/// Download servers of Apache projects, tried after `mirror://apache`
+const APACHE_SERVERS: &[&str] = &[
+    "https://dlcdn.apache.org/",
+    "https://downloads.apache.org/",
+    "https://archive.apache.org/dist/",
+    "https://www.apache.org/dist/",
+];
+
+/// Apache's mirror redirector, taking the path of the file as a path or `path` parameter
+const APACHE_CLOSER: &str = "https://www.apache.org/dyn/closer.lua";
+
+/// Archive of every Apache release, listing releases removed from the download servers
+const APACHE_ARCHIVE: &str = "https://archive.apache.org/dist/";
+
+/// Parse a `mirror://` URL of a supported mirror network
+///
+/// Matches URLs like:
+/// - `mirror://gnu/hello/hello-2.12.1.tar.gz`
+/// - `mirror://sourceforge/p7zip/p7zip_16.02_src_all.tar.bz2`
+/// - `https://dlcdn.apache.org/httpd/httpd-2.4.58.tar.bz2`, see [`parse_apache_url`]
+///
+/// Returns the mirror name and the path of the file on the mirror if found
+pub fn parse_mirror_url(url: &str) -> Option<(String, String)> {
+    if let Some(path) = parse_apache_url(url) {
+        return Some(("apache".to_string(), path));
+    }
+
+    let (mirror, path) = url.strip_prefix("mirror://")?.split_once('/')?;
+    let is_supported =
+        mirror == "sourceforge" || DIRECTORY_MIRRORS.iter().any(|(name, _)| *name == mirror);
+    if !is_supported || path.is_empty() {
+        return None;
+    }
+    Some((mirror.to_string(), path.to_string()))
+}
+
+/// Parse the path of a file on the Apache download servers
+///
+/// Matches URLs like:
+/// - `https://dlcdn.apache.org/httpd/httpd-2.4.58.tar.bz2`
+/// - `https://archive.apache.org/dist/maven/maven-3/3.9.6/binaries/apache-maven-3.9.6-bin.tar.gz`
+/// - `https://www.apache.org/dyn/closer.lua?path=/zookeeper/zookeeper-3.9.1/apache-zookeeper-3.9.1.tar.gz&action=download`
+fn parse_apache_url(url: &str) -> Option<String> {
+    let path = if let Some(query) = url.strip_prefix(APACHE_CLOSER) {
+        match query.strip_prefix('?') {
+            Some(query) => query
+                .split('&')
+                .find_map(|param| param.strip_prefix("path="))?
+                .trim_start_matches('/'),
+            None => query.strip_prefix('/')?.split(['?', '#']).next()?,
+        }
+    } else {
+        let path = APACHE_SERVERS
+            .iter()
+            .find_map(|server| url.strip_prefix(server))?;
+        path.split(['?', '#']).next()?
+    };
+    (!path.is_empty()).then(|| path.to_string())
+}
+
+/// Resolve a path on a mirror network to its canonical download URL
+pub fn resolve_mirror_url(mirror: &str, path: &str) -> Option<String> {
+    if mirror == "sourceforge" {
+        return Some(format!("https://downloads.sourceforge.net/{}", path));
+    }
+    DIRECTORY_MIRRORS
+        .iter()
+        .find(|(name, _)| *name == mirror)
+        .map(|(_, base)| format!("{}{}", base, path))
+}
+
+/// Listed entries a release of the package is published as
+#[derive(Debug, Clone, PartialEq)]
+struct ReleasePattern {
+    /// Page listing the releases
+    listing_url: String,
+    /// Page listing older releases, tried if `listing_url` doesn't exist anymore
+    archive_url: Option<String>,
+    /// Entry name before the version
+    prefix: String,
+    /// Entry name after the version, `/` for versioned directories
+    suffix: String,
+}
+
+impl ReleasePattern {
+    /// Version of a listed entry, None if it isn't a release of the package
+    fn version_of<'a>(&self, entry: &'a str) -> Option<&'a str> {
+        let version = entry
+            .strip_prefix(self.prefix.as_str())?
+            .strip_suffix(self.suffix.as_str())?;
+        let is_version =
+            version.starts_with(|c: char| c.is_ascii_digit()) && !version.contains(['/', ' ']);
+        is_version.then_some(version)
+    }
+}
+
+/// Find where the releases of a package are listed from the path of its current source
+///
+/// Releases are often published in a directory per version, e.g.
+/// `gcc/gcc-13.2.0/gcc-13.2.0.tar.xz`, so the releases are listed by the parent of the outermost
+/// path segment containing the version.
+fn release_pattern(mirror: &str, path: &str, current_version: &str) -> Option<ReleasePattern> {
+    let segments: Vec<&str> = path.split('/').collect();
+
+    if mirror == "sourceforge" {
+        // The feed lists every file of the project, whatever the directory
+        let project = segments.first()?;
+        let (prefix, suffix) = segments.last()?.rsplit_once(current_version)?;
+        return Some(ReleasePattern {
+            listing_url: format!("https://sourceforge.net/projects/{}/rss?path=/", project),
+            archive_url: None,
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+        });
+    }
+
+    let index = segments
+        .iter()
+        .position(|segment| segment.contains(current_version))?;
+    let (prefix, suffix) = segments[index].rsplit_once(current_version)?;
+    let suffix = if index + 1 < segments.len() {
+        format!("{}/", suffix)
+    } else {
+        suffix.to_string()
+    };
+    let directory: String = segments[..index]
+        .iter()
+        .map(|s| format!("{}/", s))
+        .collect();
+
+    let archive_url = (mirror == "apache").then(|| format!("{}{}", APACHE_ARCHIVE, directory));
+
+    Some(ReleasePattern {
+        listing_url: resolve_mirror_url(mirror, &directory)?,
+        archive_url,
+        prefix: prefix.to_string(),
+        suffix,
+    })
+}
+
+/// Name of the entry a link points to, directories keeping their trailing slash
+fn entry_name(link: &str) -> Option<String> {
+    let link = link.split(['?', '#']).next()?;
+    // SourceForge links files through their download page
+    let link = link.strip_suffix("/download").unwrap_or(link);
+    let name = link.trim_end_matches('/').rsplit('/').next()?;
+    if name.is_empty() {
+        return None;
+    }
+    if link.ends_with('/') {
+        Some(format!("{}/", name))
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Versions of the entries of a listing matching the release pattern, deduplicated
+fn listed_versions(listing: &str, pattern: &ReleasePattern) -> Vec<String> {
+    // Links of directory indexes and RSS feeds
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    let link = LINK.get_or_init(|| Regex::new(r#"(?:href="|<link>)([^"<]+)"#).unwrap());
+
+    let mut versions: Vec<String> = Vec::new();
+    for captures in link.captures_iter(listing) {
+        let Some(entry) = entry_name(&captures[1]) else {
+            continue;
+        };
+        if let Some(version) = pattern.version_of(&entry) {
+            if !versions.iter().any(|v| v == version) {
+                versions.push(version.to_string());
+            }
+        }
+    }
+    versions
+}
+
+/// Fetch the versions of a package released on a mirror network
+///
+/// # Arguments
+/// * `mirror` - Mirror network, e.g. `gnu` or `sourceforge`
+/// * `path` - Path of the current source on the mirror
+/// * `current_version` - Version of the current source, which must be part of its path
+pub async fn fetch_mirror_versions(
+    mirror: &str,
+    path: &str,
+    current_version: &str,
+) -> anyhow::Result<Vec<String>> {
+    let pattern = release_pattern(mirror, path, current_version).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Version {} is not part of the source path {}",
+            current_version,
+            path
+        )
+    })?;
+
+    let client = http::client();
+    let mut listing_url = pattern.listing_url.as_str();
+    loop {
+        debug!("Fetching releases from {}", listing_url);
+
+        let response = client
+            .get(listing_url)
+            .header("User-Agent", "ekapkgs-update")
+            .send_throttled()
+            .await?;
+
+        // The release line of the package was removed from the download servers
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            if let Some(archive_url) = pattern.archive_url.as_deref() {
+                if archive_url != listing_url {
+                    listing_url = archive_url;
+                    continue;
+                }
+            }
+        }
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to list releases at {}: {}",
+                listing_url,
+                response.status()
+            );
+        }
+
+        let listing = response.text().await?;
+        return Ok(listed_versions(&listing, &pattern));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mirror_url() {
+        assert_eq!(
+            parse_mirror_url("mirror://gnu/hello/hello-2.12.1.tar.gz"),
+            Some(("gnu".to_string(), "hello/hello-2.12.1.tar.gz".to_string()))
+        );
+        assert_eq!(
+            resolve_mirror_url("xorg", "individual/lib/libX11-1.8.7.tar.xz").as_deref(),
+            Some("https://xorg.freedesktop.org/releases/individual/lib/libX11-1.8.7.tar.xz")
+        );
+        assert_eq!(parse_mirror_url("mirror://unknown/foo-1.0.tar.gz"), None);
+        assert_eq!(parse_mirror_url("https://ftp.gnu.org/gnu/hello/"), None);
+    }
+
+    #[test]
+    fn test_release_pattern() {
+        let pattern = release_pattern("gnu", "hello/hello-2.12.1.tar.gz", "2.12.1").unwrap();
+        assert_eq!(pattern.listing_url, "https://ftp.gnu.org/gnu/hello/");
+        assert_eq!(pattern.version_of("hello-2.12.2.tar.gz"), Some("2.12.2"));
+        assert_eq!(pattern.version_of("hello-2.12.2.tar.gz.sig"), None);
+        assert_eq!(pattern.version_of("hello-latest.tar.gz"), None);
+
+        // Directory per version
+        let pattern = release_pattern("kde", "stable/plasma/6.0.0/kwin-6.0.0.tar.xz", "6.0.0");
+        assert_eq!(
+            pattern,
+            Some(ReleasePattern {
+                listing_url: "https://download.kde.org/stable/plasma/".to_string(),
+                archive_url: None,
+                prefix: String::new(),
+                suffix: "/".to_string(),
+            })
+        );
+
+        let pattern =
+            release_pattern("sourceforge", "p7zip/p7zip_16.02_src_all.tar.bz2", "16.02").unwrap();
+        assert_eq!(
+            pattern.listing_url,
+            "https://sourceforge.net/projects/p7zip/rss?path=/"
+        );
+        assert_eq!(
+            pattern.version_of("p7zip_17.01_src_all.tar.bz2"),
+            Some("17.01")
+        );
+
+        assert_eq!(release_pattern("gnu", "hello/hello.tar.gz", "2.12.1"), None);
+    }
+
+    #[test]
+    fn test_listed_versions() {
+        let pattern = release_pattern("gnu", "hello/hello-2.12.1.tar.gz", "2.12.1").unwrap();
+        let listing = r#"<a href="hello-2.12.tar.gz">hello-2.12.tar.gz</a>
+<a href="hello-2.12.tar.gz.sig">hello-2.12.tar.gz.sig</a>
+<a href="/gnu/hello/hello-2.12.2.tar.gz">hello-2.12.2.tar.gz</a>
+<a href="hello-2.12.2.tar.gz?download">hello-2.12.2.tar.gz</a>"#;
+        assert_eq!(listed_versions(listing, &pattern), vec!["2.12", "2.12.2"]);
+
+        let pattern = release_pattern("kde", "stable/plasma/6.0.0/kwin-6.0.0.tar.xz", "6.0.0");
+        let listing = r#"<a href="6.0.0/">6.0.0/</a> <a href="6.1.0/">6.1.0/</a>
+<a href="../">Parent Directory</a>"#;
+        assert_eq!(
+            listed_versions(listing, &pattern.unwrap()),
+            vec!["6.0.0", "6.1.0"]
+        );
+
+        let pattern =
+            release_pattern("sourceforge", "p7zip/p7zip_16.02_src_all.tar.bz2", "16.02").unwrap();
+        let feed = "<item><link>https://sourceforge.net/projects/p7zip/files/p7zip/16.02/\
+                    p7zip_16.02_src_all.tar.bz2/download</link></item>";
+        assert_eq!(listed_versions(feed, &pattern), vec!["16.02"]);
+    }
+
+    #[test]
+    fn test_parse_apache_url() {
+        assert_eq!(
+            parse_mirror_url("https://dlcdn.apache.org/httpd/httpd-2.4.58.tar.bz2"),
+            Some((
+                "apache".to_string(),
+                "httpd/httpd-2.4.58.tar.bz2".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_apache_url(
+                "https://archive.apache.org/dist/maven/maven-3/3.9.6/binaries/\
+                 apache-maven-3.9.6-bin.tar.gz"
+            )
+            .as_deref(),
+            Some("maven/maven-3/3.9.6/binaries/apache-maven-3.9.6-bin.tar.gz")
+        );
+        assert_eq!(
+            parse_apache_url(
+                "https://www.apache.org/dyn/closer.lua?path=/zookeeper/zookeeper-3.9.1/\
+                 apache-zookeeper-3.9.1.tar.gz&action=download"
+            )
+            .as_deref(),
+            Some("zookeeper/zookeeper-3.9.1/apache-zookeeper-3.9.1.tar.gz")
+        );
+        assert_eq!(
+            parse_apache_url(
+                "https://www.apache.org/dyn/closer.lua/httpd/httpd-2.4.58.tar.bz2?action=download"
+            )
+            .as_deref(),
+            Some("httpd/httpd-2.4.58.tar.bz2")
+        );
+        assert_eq!(parse_apache_url("https://dlcdn.apache.org/"), None);
+
+        // Versioned directories of a release line, with the archive as fallback
+        let pattern = release_pattern(
+            "apache",
+            "maven/maven-3/3.9.6/binaries/apache-maven-3.9.6-bin.tar.gz",
+            "3.9.6",
+        )
+        .unwrap();
+        assert_eq!(
+            pattern.listing_url,
+            "https://downloads.apache.org/maven/maven-3/"
+        );
+        assert_eq!(
+            pattern.archive_url.as_deref(),
+            Some("https://archive.apache.org/dist/maven/maven-3/")
+        );
+        assert_eq!(pattern.version_of("3.9.9/"), Some("3.9.9"));
+    }
+}
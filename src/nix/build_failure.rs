@@ -0,0 +1,209 @@
+//! Classification of nix-build failures
+//!
+//! Network hiccups, remote builder disconnects and network timeouts are worth retrying, whereas
+//! compile errors, hash mismatches and builds killed by the build timeout fail the same way
+//! every time.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Whether a build failure is expected to go away when retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Transient,
+    Deterministic,
+}
+
+/// Output which shows a build failed deterministically, checked before `TRANSIENT_PATTERNS`
+///
+/// E.g. a 404 is also reported as "unable to download".
+const DETERMINISTIC_PATTERNS: &[&str] = &[
+    "hash mismatch in fixed-output derivation",
+    "HTTP error 403",
+    "HTTP error 404",
+    "HTTP error 410",
+];
+
+/// Output caused by the network or builders rather than by the package
+const TRANSIENT_PATTERNS: &[&str] = &[
+    "unable to download",
+    "Could not resolve host",
+    "Couldn't resolve host",
+    "Temporary failure in name resolution",
+    "Connection reset by peer",
+    "Connection refused",
+    "Connection timed out",
+    "Operation timed out",
+    "Timeout was reached",
+    "HTTP error 429",
+    "HTTP error 500",
+    "HTTP error 502",
+    "HTTP error 503",
+    "HTTP error 504",
+    "unexpected end-of-file",
+    "Broken pipe",
+    "failed to start SSH connection",
+    "cannot connect to",
+    "lost connection",
+];
+
+/// Classify a failed build from its stderr
+pub fn classify_build_failure(stderr: &str) -> FailureKind {
+    if DETERMINISTIC_PATTERNS.iter().any(|p| stderr.contains(p)) {
+        return FailureKind::Deterministic;
+    }
+    if TRANSIENT_PATTERNS.iter().any(|p| stderr.contains(p)) {
+        return FailureKind::Transient;
+    }
+    FailureKind::Deterministic
+}
+
+/// What a failed update attempt failed on, as recorded in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateFailureKind {
+    /// The source didn't match its hash, even after updating it
+    HashMismatch,
+    /// The source URL of the new version doesn't exist
+    SrcNotFound,
+    /// The package failed to build
+    CompileError,
+    /// passthru.tests failed
+    TestFailure,
+    /// A build was killed for exceeding its timeout
+    Timeout,
+    /// The package failed to evaluate
+    EvalError,
+}
+
+impl UpdateFailureKind {
+    /// Classify a failed update attempt from its error message, including the build's stderr
+    ///
+    /// Returns `None` for failures not caused by evaluating or building the package, e.g.
+    /// failing to find a release or to push a branch.
+    pub fn classify(error: &str) -> Option<Self> {
+        if error.contains("Package tests failed") {
+            Some(Self::TestFailure)
+        } else if error.contains("timed out after") {
+            Some(Self::Timeout)
+        } else if ["HTTP error 404", "HTTP error 410", "Release asset missing"]
+            .iter()
+            .any(|p| error.contains(p))
+        {
+            Some(Self::SrcNotFound)
+        } else if error.contains("hash mismatch") {
+            Some(Self::HashMismatch)
+        } else if [
+            "evaluation failed",
+            "while evaluating",
+            "undefined variable",
+            "infinite recursion",
+            "does not provide attribute",
+        ]
+        .iter()
+        .any(|p| error.contains(p))
+        {
+            Some(Self::EvalError)
+        } else if ["build failed", "builder for", "Cannot build"]
+            .iter()
+            .any(|p| error.contains(p))
+        {
+            Some(Self::CompileError)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HashMismatch => "hash-mismatch",
+            Self::SrcNotFound => "src-404",
+            Self::CompileError => "compile-error",
+            Self::TestFailure => "test-failure",
+            Self::Timeout => "timeout",
+            Self::EvalError => "eval-error",
+        }
+    }
+}
+
+/// Error returned when a build is killed for exceeding its timeout
+#[derive(Debug)]
+pub struct BuildTimedOut {
+    pub attr: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for BuildTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Build of {} timed out after {}s",
+            self.attr,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for BuildTimedOut {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_build_failure() {
+        let transient = "error: unable to download \
+                         'https://github.com/foo/bar/archive/v1.tar.gz': Couldn't resolve host \
+                         name (6)";
+        assert_eq!(classify_build_failure(transient), FailureKind::Transient);
+
+        let not_found =
+            "error: unable to download 'https://example.com/foo.tar.gz': HTTP error 404";
+        assert_eq!(
+            classify_build_failure(not_found),
+            FailureKind::Deterministic
+        );
+
+        let compile_error = "src/main.c:12:5: error: implicit declaration of function 'foo'";
+        assert_eq!(
+            classify_build_failure(compile_error),
+            FailureKind::Deterministic
+        );
+    }
+
+    #[test]
+    fn test_classify_update_failure() {
+        let cases = [
+            (
+                "Package build failed after update. You may need to manually fix build \
+                 issues.\nerror: builder for '/nix/store/abc-foo-1.1.drv' failed with exit code 2",
+                Some(UpdateFailureKind::CompileError),
+            ),
+            (
+                "Source build failed after hash update:\nerror: unable to download \
+                 'https://example.com/foo-1.1.tar.gz': HTTP error 404",
+                Some(UpdateFailureKind::SrcNotFound),
+            ),
+            (
+                "Source build failed after hash update:\nerror: hash mismatch in fixed-output \
+                 derivation",
+                Some(UpdateFailureKind::HashMismatch),
+            ),
+            (
+                "nix-instantiate evaluation failed: error: undefined variable 'foo'",
+                Some(UpdateFailureKind::EvalError),
+            ),
+            (
+                "Package tests failed after update: simple (timed out)",
+                Some(UpdateFailureKind::TestFailure),
+            ),
+            (
+                "Build of foo timed out after 3600s",
+                Some(UpdateFailureKind::Timeout),
+            ),
+            ("Failed to push branch: permission denied", None),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(UpdateFailureKind::classify(error), expected, "{}", error);
+        }
+    }
+}
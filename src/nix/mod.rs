@@ -1,8 +1,181 @@
+pub mod build_failure;
 pub mod nix_eval_jobs;
+pub mod passthru_tests;
 pub mod run_eval;
+pub mod systems;
+
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use tokio::process::Command;
-use tracing::debug;
+use tracing::{debug, warn};
+
+use self::build_failure::{BuildTimedOut, FailureKind, classify_build_failure};
+use crate::rewrite::escape_string_literal;
+
+/// Store and builder settings passed to every nix invocation
+///
+/// Allows the heavy builds to run on a remote builder or store while the updater itself runs
+/// elsewhere.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NixOptions {
+    /// Store URL, e.g. `ssh-ng://builder`
+    pub store: Option<String>,
+    /// Remote builders specification, e.g. `ssh://builder x86_64-linux`
+    pub builders: Option<String>,
+    /// Additional `--option name value` pairs
+    pub options: Vec<(String, String)>,
+}
+
+static NIX_OPTIONS: OnceLock<NixOptions> = OnceLock::new();
+
+impl NixOptions {
+    /// Parse an `--option` value of the form `name=value`
+    pub fn parse_option(spec: &str) -> anyhow::Result<(String, String)> {
+        match spec.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                Ok((name.trim().to_string(), value.trim().to_string()))
+            },
+            _ => anyhow::bail!("Invalid nix option '{}', expected name=value", spec),
+        }
+    }
+
+    /// Command line arguments for nix commands
+    pub fn args(&self) -> Vec<String> {
+        let store = self.store.as_ref().map(|store| ("store", store));
+        let builders = self
+            .builders
+            .as_ref()
+            .map(|builders| ("builders", builders));
+
+        store
+            .into_iter()
+            .chain(builders)
+            .chain(
+                self.options
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value)),
+            )
+            .flat_map(|(name, value)| ["--option".to_string(), name.to_string(), value.clone()])
+            .collect()
+    }
+}
+
+/// Set the options applied to every nix invocation, must be called before running any nix command
+pub fn set_nix_options(options: NixOptions) {
+    if NIX_OPTIONS.set(options).is_err() {
+        warn!("Nix options were already set");
+    }
+}
+
+/// Arguments the entry point is imported with, for trees whose entry point is a function
+/// requiring e.g. `system`, `config` or `overlays`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportArgs {
+    /// `name`, Nix expression pairs, like `--arg` of nix-build
+    pub args: Vec<(String, String)>,
+    /// `name`, string pairs, like `--argstr` of nix-build
+    pub argstrs: Vec<(String, String)>,
+    /// Attribute set expression holding further arguments, e.g. `{ config.allowUnfree = true; }`
+    pub eval_args: Option<String>,
+}
+
+static IMPORT_ARGS: OnceLock<ImportArgs> = OnceLock::new();
+
+impl ImportArgs {
+    /// Parse an `--arg` or `--argstr` value of the form `name=value`
+    pub fn parse_arg(spec: &str) -> anyhow::Result<(String, String)> {
+        match spec.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                Ok((name.trim().to_string(), value.to_string()))
+            },
+            _ => anyhow::bail!("Invalid argument '{}', expected name=value", spec),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty() && self.argstrs.is_empty() && self.eval_args.is_none()
+    }
+
+    /// Nix attribute set of the arguments
+    pub fn attrset(&self) -> String {
+        let bindings: String = self
+            .args
+            .iter()
+            .map(|(name, expr)| format!("{} = ({}); ", name, expr))
+            .chain(
+                self.argstrs
+                    .iter()
+                    .map(|(name, value)| format!("{} = {}; ", name, nix_string(value))),
+            )
+            .collect();
+        let attrset = format!("{{ {}}}", bindings);
+        match &self.eval_args {
+            Some(eval_args) => format!("(({}) // {})", eval_args, attrset),
+            None => attrset,
+        }
+    }
+}
+
+/// Nix string literal of a value
+fn nix_string(value: &str) -> String {
+    format!("\"{}\"", escape_string_literal(value))
+}
+
+/// Set the arguments the entry point is imported with, must be called before evaluating anything
+pub fn set_import_args(args: ImportArgs) {
+    if IMPORT_ARGS.set(args).is_err() {
+        warn!("Import arguments were already set");
+    }
+}
+
+/// Nix expression importing an entry point with the configured [`ImportArgs`], e.g.
+/// `import ./default.nix { }`
+pub fn import_entry_point(entry_point: &str) -> String {
+    let args = IMPORT_ARGS
+        .get()
+        .map_or_else(|| "{ }".to_string(), ImportArgs::attrset);
+    format!("import {} {}", normalize_entry_point(entry_point), args)
+}
+
+/// Arguments of nix-build and nix-eval-jobs selecting an entry point
+///
+/// The file itself without import arguments, otherwise a function importing it, so arguments
+/// given on the command line, e.g. `--argstr system`, are still passed to the entry point.
+pub fn entry_point_args(entry_point: &str) -> Vec<String> {
+    match IMPORT_ARGS.get() {
+        Some(args) if !args.is_empty() => {
+            vec!["--expr".to_string(), entry_point_expr(entry_point, args)]
+        },
+        _ => vec![entry_point.to_string()],
+    }
+}
+
+/// Function importing an entry point with `args` and the arguments it is called with
+///
+/// Nix only auto-calls functions with formals, a plain `args:` lambda would be returned as is
+/// and never receive e.g. `--arg crossSystem`.
+fn entry_point_expr(entry_point: &str, args: &ImportArgs) -> String {
+    format!(
+        "{{ ... }}@args: import {} ({} // args)",
+        normalize_entry_point(entry_point),
+        args.attrset()
+    )
+}
+
+/// Create a command running a nix program with the configured [`NixOptions`]
+///
+/// The program runs in its own process group, so an interrupt of the run doesn't reach the
+/// builds it lets finish, and is killed when the command is dropped, as cancelled updates do.
+pub fn nix_command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    if let Some(options) = NIX_OPTIONS.get() {
+        command.args(options.args());
+    }
+    command.process_group(0).kill_on_drop(true);
+    command
+}
 
 /// Normalize a Nix entry point path by prepending `./` if needed
 ///
@@ -33,6 +206,21 @@ pub fn normalize_entry_point(entry_point: &str) -> String {
     }
 }
 
+/// Directory holding the Nix tree of an entry point
+///
+/// That is the entry point itself if it is a directory, otherwise its parent directory,
+/// falling back to the current directory.
+pub fn entry_point_dir(entry_point: &str) -> &Path {
+    let path = Path::new(entry_point);
+    if path.is_dir() {
+        path
+    } else {
+        path.parent()
+            .filter(|parent| parent.is_dir())
+            .unwrap_or(Path::new("."))
+    }
+}
+
 /// Evaluate a Nix expression and return the result as a string
 ///
 /// Executes `nix-instantiate --eval -E <expr> --raw` to evaluate arbitrary Nix expressions
@@ -62,7 +250,7 @@ pub fn normalize_entry_point(entry_point: &str) -> String {
 pub async fn eval_nix_expr(expr: impl AsRef<str>) -> anyhow::Result<String> {
     let expr = expr.as_ref();
 
-    let output = Command::new("nix-instantiate")
+    let output = nix_command("nix-instantiate")
         .arg("--eval")
         .arg("-E")
         .arg(expr)
@@ -88,11 +276,8 @@ pub async fn is_many_variants_package(
     eval_entry_point: &str,
     attr_path: &str,
 ) -> anyhow::Result<bool> {
-    let normalized_entry = normalize_entry_point(eval_entry_point);
-    let check_expr = format!(
-        "with import {} {{ }}; {} ? variants",
-        normalized_entry, attr_path
-    );
+    let package_set = import_entry_point(eval_entry_point);
+    let check_expr = format!("with {}; {} ? variants", package_set, attr_path);
 
     match eval_nix_expr(&check_expr).await {
         Ok(result) => {
@@ -146,10 +331,10 @@ pub async fn has_attr(
     attr_path: &str,
     attribute_name: &str,
 ) -> anyhow::Result<bool> {
-    let normalized_entry = normalize_entry_point(eval_entry_point);
+    let package_set = import_entry_point(eval_entry_point);
     let check_expr = format!(
-        "with import {} {{ }}; toString({} ? {})",
-        normalized_entry, attr_path, attribute_name
+        "with {}; toString({} ? {})",
+        package_set, attr_path, attribute_name
     );
 
     match eval_nix_expr(&check_expr).await {
@@ -176,10 +361,211 @@ pub async fn has_passthru_tests(eval_entry_point: &str, attr_path: &str) -> anyh
     has_attr(eval_entry_point, &passthru_attr, "tests").await
 }
 
+/// Resource limits applied to nix-build invocations
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildOptions {
+    /// Time a single build may take before it is killed
+    pub timeout: Option<Duration>,
+    /// Maximum number of concurrent build jobs, passed as `--max-jobs`
+    pub max_jobs: Option<usize>,
+    /// Cores available to each build job, passed as `--cores`
+    pub cores: Option<usize>,
+    /// System to build for instead of the current one, passed as `--argstr system`
+    pub system: Option<String>,
+    /// Platform to cross-compile for, e.g. `aarch64-unknown-linux-gnu`, passed as
+    /// `--arg crossSystem`
+    pub cross_system: Option<String>,
+    /// Number of times a build failing for transient reasons is retried
+    pub retries: u32,
+}
+
+impl BuildOptions {
+    /// Command line arguments for nix-build
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(max_jobs) = self.max_jobs {
+            args.extend(["--max-jobs".to_string(), max_jobs.to_string()]);
+        }
+        if let Some(cores) = self.cores {
+            args.extend(["--cores".to_string(), cores.to_string()]);
+        }
+        if let Some(system) = &self.system {
+            args.extend(["--argstr".to_string(), "system".to_string(), system.clone()]);
+        }
+        if let Some(cross_system) = &self.cross_system {
+            args.extend([
+                "--arg".to_string(),
+                "crossSystem".to_string(),
+                format!("{{ config = {}; }}", nix_string(cross_system)),
+            ]);
+        }
+        args
+    }
+}
+
+/// Delay before retrying a transient build failure, multiplied by the attempt number
+const BUILD_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Build Nix expression and return stdout/stderr
+///
+/// Builds exceeding `options.timeout` are killed and reported as an error right away, as a hung
+/// build would hang again. Builds failing for transient reasons, e.g. network errors, are retried
+/// up to `options.retries` times.
+pub async fn build_nix_expr(
+    eval_entry_point: &str,
+    attr_path: &str,
+    attr_suffix: Option<&str>,
+    options: &BuildOptions,
+) -> anyhow::Result<(bool, String, String)> {
+    let full_attr = if let Some(suffix) = attr_suffix {
+        format!("{}.{}", attr_path, suffix)
+    } else {
+        attr_path.to_string()
+    };
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let result = build_nix_attr(eval_entry_point, &full_attr, options).await;
+
+        let failure = match &result {
+            Ok((false, _stdout, stderr)) => Some(classify_build_failure(stderr)),
+            _ => None,
+        };
+        if failure != Some(FailureKind::Transient) || attempt > options.retries {
+            return result;
+        }
+
+        warn!(
+            "Build of {} failed for a transient reason, retrying (attempt {}/{})",
+            full_attr,
+            attempt + 1,
+            options.retries + 1
+        );
+        tokio::time::sleep(BUILD_RETRY_DELAY * attempt).await;
+    }
+}
+
+/// Run a single nix-build of an attribute
+async fn build_nix_attr(
+    eval_entry_point: &str,
+    full_attr: &str,
+    options: &BuildOptions,
+) -> anyhow::Result<(bool, String, String)> {
+    debug!("Building {}", full_attr);
+
+    let build = nix_command("nix-build")
+        .args(entry_point_args(eval_entry_point))
+        .arg("-A")
+        .arg(full_attr)
+        .args(options.args())
+        .output();
+
+    let output = match options.timeout {
+        Some(timeout) => {
+            tokio::time::timeout(timeout, build)
+                .await
+                .map_err(|_| BuildTimedOut {
+                    attr: full_attr.to_string(),
+                    timeout,
+                })??
+        },
+        None => build.await?,
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    Ok((output.status.success(), stdout, stderr))
+}
+
+/// Instantiate a package and list what would be built, without building anything
+///
+/// Returns whether evaluation succeeded along with stderr.
+pub async fn dry_run_build_nix_expr(
+    eval_entry_point: &str,
+    attr_path: &str,
+) -> anyhow::Result<(bool, String)> {
+    debug!("Dry-run building {}", attr_path);
+
+    let output = nix_command("nix-build")
+        .args(entry_point_args(eval_entry_point))
+        .arg("-A")
+        .arg(attr_path)
+        .arg("--dry-run")
+        .output()
+        .await?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    Ok((output.status.success(), stderr))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_nix_options_args() {
+        assert!(NixOptions::default().args().is_empty());
+
+        let options = NixOptions {
+            store: Some("ssh-ng://builder".to_string()),
+            builders: None,
+            options: vec![NixOptions::parse_option("sandbox = relaxed").unwrap()],
+        };
+        assert_eq!(
+            options.args(),
+            vec![
+                "--option",
+                "store",
+                "ssh-ng://builder",
+                "--option",
+                "sandbox",
+                "relaxed"
+            ]
+        );
+        assert!(NixOptions::parse_option("sandbox").is_err());
+    }
+
+    #[test]
+    fn test_build_options_args() {
+        assert!(BuildOptions::default().args().is_empty());
+
+        let options = BuildOptions {
+            timeout: Some(Duration::from_secs(60)),
+            max_jobs: Some(2),
+            cores: Some(8),
+            system: Some("aarch64-linux".to_string()),
+            retries: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            options.args(),
+            vec![
+                "--max-jobs",
+                "2",
+                "--cores",
+                "8",
+                "--argstr",
+                "system",
+                "aarch64-linux"
+            ]
+        );
+
+        let options = BuildOptions {
+            cross_system: Some("aarch64-unknown-linux-gnu".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.args(),
+            vec![
+                "--arg",
+                "crossSystem",
+                "{ config = \"aarch64-unknown-linux-gnu\"; }"
+            ]
+        );
+    }
+
     #[test]
     fn test_normalize_entry_point_simple() {
         assert_eq!(normalize_entry_point("default.nix"), "./default.nix");
@@ -206,4 +592,38 @@ mod tests {
             "/absolute/path/to/default.nix"
         );
     }
+
+    #[test]
+    fn test_import_args_attrset() {
+        assert_eq!(ImportArgs::default().attrset(), "{ }");
+
+        let args = ImportArgs {
+            args: vec![ImportArgs::parse_arg("overlays=[ (import ./overlay.nix) ]").unwrap()],
+            argstrs: vec![ImportArgs::parse_arg("system=x86_64-\"linux\"").unwrap()],
+            eval_args: None,
+        };
+        assert_eq!(
+            args.attrset(),
+            "{ overlays = ([ (import ./overlay.nix) ]); system = \"x86_64-\\\"linux\\\"\"; }"
+        );
+
+        let args = ImportArgs {
+            eval_args: Some("{ config.allowUnfree = true; }".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(args.attrset(), "(({ config.allowUnfree = true; }) // { })");
+        assert!(ImportArgs::parse_arg("=value").is_err());
+    }
+
+    #[test]
+    fn test_entry_point_expr() {
+        let args = ImportArgs {
+            argstrs: vec![("system".to_string(), "x86_64-linux".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(
+            entry_point_expr("default.nix", &args),
+            "{ ... }@args: import ./default.nix ({ system = \"x86_64-linux\"; } // args)"
+        );
+    }
 }
@@ -4,16 +4,42 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
 pub enum NixEvalItem {
     Error(NixEvalError),
     Drv(NixEvalDrv),
 }
 
+/// Meta attributes of a derivation, output with `--meta`
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NixMeta {
+    /// The first one, for packages with several homepages
+    #[serde(default, deserialize_with = "first_string")]
     pub homepage: Option<String>,
+    #[serde(default, deserialize_with = "first_string")]
     pub changelog: Option<String>,
     pub description: Option<String>,
+    #[serde(rename = "knownVulnerabilities", default)]
+    pub known_vulnerabilities: Vec<String>,
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+/// Deserialize a string or the first string of a list, as allowed for some meta attributes
+fn first_string<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+    Ok(match Option::<StringOrList>::deserialize(deserializer)? {
+        Some(StringOrList::String(string)) => Some(string),
+        Some(StringOrList::List(list)) => list.into_iter().next(),
+        None => None,
+    })
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -48,6 +74,60 @@ pub struct NixEvalDrv {
     pub meta: Option<NixMeta>,
 }
 
+impl NixEvalDrv {
+    /// Order of the attr paths of a derivation, the canonical one first
+    ///
+    /// Aliases and package sets often evaluate to the same derivation, e.g. `python3Packages.foo`
+    /// and `python312Packages.foo`. The shortest attr path, then the first alphabetically, is
+    /// the one kept.
+    pub fn canonical_key(&self) -> (usize, usize, &str) {
+        (self.attr_path.len(), self.attr.len(), &self.attr)
+    }
+
+    /// Whether the package is marked insecure or with known vulnerabilities, only known if
+    /// evaluated with `--meta`
+    pub fn is_marked_insecure(&self) -> bool {
+        self.meta
+            .as_ref()
+            .is_some_and(|meta| meta.insecure || !meta.known_vulnerabilities.is_empty())
+    }
+}
+
+/// Direct reverse dependencies of evaluated derivations, derived from their `inputDrvs`
+#[derive(Debug, Clone, Default)]
+pub struct ReverseDependencyIndex {
+    /// drv path -> attrs of the derivations depending on it
+    dependents: HashMap<String, Vec<String>>,
+}
+
+impl ReverseDependencyIndex {
+    pub fn new(drvs: &[NixEvalDrv]) -> Self {
+        let mut dependents: HashMap<String, Vec<String>> = HashMap::new();
+        for drv in drvs {
+            for input_drv in drv.input_drvs.iter().flat_map(|inputs| inputs.keys()) {
+                let attrs = dependents.entry(input_drv.clone()).or_default();
+                if !attrs.contains(&drv.attr) {
+                    attrs.push(drv.attr.clone());
+                }
+            }
+        }
+
+        for attrs in dependents.values_mut() {
+            attrs.sort();
+        }
+
+        Self { dependents }
+    }
+
+    /// Attrs of the evaluated derivations which directly depend on `drv_path`
+    pub fn dependents_of(&self, drv_path: &str) -> &[String] {
+        self.dependents
+            .get(drv_path)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NixEvalError {
     pub attr: String,
@@ -56,6 +136,16 @@ pub struct NixEvalError {
     pub error: String,
 }
 
+/// Message of a Nix evaluation error without its trace, e.g. `error: attribute 'foo' missing`
+pub fn eval_error_summary(error: &str) -> &str {
+    // The error itself comes last, after the trace
+    error
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -70,9 +160,94 @@ mod tests {
         serde_json::from_str::<NixEvalDrv>(eval_drv).expect("Failed to deserialize output");
     }
 
+    #[test]
+    fn test_meta_deserialization() {
+        let eval_drv = r#"{"attr":"libxyz","attrPath":["libxyz"],"drvPath":"/nix/store/abc-libxyz-1.0.drv","name":"libxyz-1.0","outputs":{"out":"/nix/store/def-libxyz-1.0"},"system":"x86_64-linux","meta":{"homepage":["https://xyz.org","https://github.com/xyz/xyz"],"description":"XYZ library","knownVulnerabilities":["CVE-2024-1234"],"insecure":true,"platforms":["x86_64-linux"]}}"#;
+        let drv =
+            serde_json::from_str::<NixEvalDrv>(eval_drv).expect("Failed to deserialize output");
+        let meta = drv.meta.as_ref().unwrap();
+        assert_eq!(meta.homepage.as_deref(), Some("https://xyz.org"));
+        assert_eq!(meta.changelog, None);
+        assert_eq!(meta.known_vulnerabilities, vec!["CVE-2024-1234"]);
+        assert!(drv.is_marked_insecure());
+    }
+
     #[test]
     fn test_error() {
         let err = r##"{"attr":"adoptopenjdk-openj9-bin-15","attrPath":["adoptopenjdk-openj9-bin-15"],"error":"error:\n       … from call site\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:217:7:\n          216|     lib.mapAttrs (\n          217|       n: alias: removeDistribute (removeRecurseForDerivations (checkInPkgs n alias))\n             |       ^\n          218|     ) aliases;\n\n       … while calling anonymous lambda\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:217:10:\n          216|     lib.mapAttrs (\n          217|       n: alias: removeDistribute (removeRecurseForDerivations (checkInPkgs n alias))\n             |          ^\n          218|     ) aliases;\n\n       … from call site\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:217:17:\n          216|     lib.mapAttrs (\n          217|       n: alias: removeDistribute (removeRecurseForDerivations (checkInPkgs n alias))\n             |                 ^\n          218|     ) aliases;\n\n       … while calling 'removeDistribute'\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:34:22:\n           33|   # sets from building on Hydra.\n           34|   removeDistribute = alias: if lib.isDerivation alias then lib.dontDistribute alias else alias;\n             |                      ^\n           35|\n\n       … while evaluating a branch condition\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:34:29:\n           33|   # sets from building on Hydra.\n           34|   removeDistribute = alias: if lib.isDerivation alias then lib.dontDistribute alias else alias;\n             |                             ^\n           35|\n\n       … from call site\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:34:32:\n           33|   # sets from building on Hydra.\n           34|   removeDistribute = alias: if lib.isDerivation alias then lib.dontDistribute alias else alias;\n             |                                ^\n           35|\n\n       … while calling 'isDerivation'\n         at /home/jon/projects/nixpkgs/lib/attrsets.nix:1251:18:\n         1250|   */\n         1251|   isDerivation = value: value.type or null == \"derivation\";\n             |                  ^\n         1252|\n\n       … from call site\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:217:35:\n          216|     lib.mapAttrs (\n          217|       n: alias: removeDistribute (removeRecurseForDerivations (checkInPkgs n alias))\n             |                                   ^\n          218|     ) aliases;\n\n       … while calling 'removeRecurseForDerivations'\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:26:5:\n           25|   removeRecurseForDerivations =\n           26|     alias:\n             |     ^\n           27|     if alias.recurseForDerivations or false then\n\n       … while evaluating a branch condition\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:27:5:\n           26|     alias:\n           27|     if alias.recurseForDerivations or false then\n             |     ^\n           28|       lib.removeAttrs alias [ \"recurseForDerivations\" ]\n\n       … from call site\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:217:64:\n          216|     lib.mapAttrs (\n          217|       n: alias: removeDistribute (removeRecurseForDerivations (checkInPkgs n alias))\n             |                                                                ^\n          218|     ) aliases;\n\n       … while calling 'checkInPkgs'\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:211:8:\n          210|   checkInPkgs =\n          211|     n: alias:\n             |        ^\n          212|     if builtins.hasAttr n super then throw \"Alias ${n} is still in all-packages.nix\" else alias;\n\n       … while calling the 'throw' builtin\n         at /home/jon/projects/nixpkgs/pkgs/top-level/aliases.nix:257:32:\n          256|   adoptopenjdk-openj9-bin-11 = throw \"adoptopenjdk has been removed as the upstream project is deprecated. Consider using `semeru-bin-11`.\"; # Added 2024-05-09\n          257|   adoptopenjdk-openj9-bin-15 = throw \"adoptopenjdk has been removed as the upstream project is deprecated. JDK 15 is also EOL. Consider using `semeru-bin-17`.\"; # Added 2024-05-09\n             |                                ^\n          258|   adoptopenjdk-openj9-bin-16 = throw \"adoptopenjdk has been removed as the upstream project is deprecated. JDK 16 is also EOL. Consider using `semeru-bin-17`.\"; # Added 2024-05-09\n\n       error: adoptopenjdk has been removed as the upstream project is deprecated. JDK 15 is also EOL. Consider using `semeru-bin-17`."}"##;
-        let _item = serde_json::from_str::<NixEvalItem>(err).expect("Failed to deserialize output");
+        let item = serde_json::from_str::<NixEvalItem>(err).expect("Failed to deserialize output");
+        let NixEvalItem::Error(error) = item else {
+            panic!("Expected an evaluation error");
+        };
+        assert_eq!(
+            eval_error_summary(&error.error),
+            "error: adoptopenjdk has been removed as the upstream project is deprecated. JDK 15 \
+             is also EOL. Consider using `semeru-bin-17`."
+        );
+    }
+
+    #[test]
+    fn test_reverse_dependency_index() {
+        let drv = |attr: &str, inputs: &[&str]| NixEvalDrv {
+            attr: attr.to_string(),
+            attr_path: vec![attr.to_string()],
+            drv_path: format!("/nix/store/{}.drv", attr),
+            input_drvs: Some(
+                inputs
+                    .iter()
+                    .map(|i| (format!("/nix/store/{}.drv", i), vec!["out".to_string()]))
+                    .collect(),
+            ),
+            name: attr.to_string(),
+            outputs: HashMap::new(),
+            system: "x86_64-linux".to_string(),
+            meta: None,
+        };
+
+        let drvs = vec![
+            drv("zlib", &[]),
+            drv("curl", &["zlib", "openssl"]),
+            drv("cmake", &["curl", "zlib"]),
+        ];
+        let index = ReverseDependencyIndex::new(&drvs);
+
+        assert_eq!(
+            index.dependents_of("/nix/store/zlib.drv"),
+            ["cmake", "curl"]
+        );
+        assert_eq!(index.dependents_of("/nix/store/curl.drv"), ["cmake"]);
+        assert!(index.dependents_of("/nix/store/cmake.drv").is_empty());
+    }
+
+    #[test]
+    fn test_canonical_key() {
+        let drv = |attr: &str| NixEvalDrv {
+            attr: attr.to_string(),
+            attr_path: attr.split('.').map(str::to_string).collect(),
+            drv_path: "/nix/store/foo.drv".to_string(),
+            input_drvs: None,
+            name: "foo".to_string(),
+            outputs: HashMap::new(),
+            system: "x86_64-linux".to_string(),
+            meta: None,
+        };
+
+        let mut drvs = [
+            drv("python312Packages.foo"),
+            drv("foo-unwrapped"),
+            drv("python3Packages.foo"),
+            drv("foo"),
+        ];
+        drvs.sort_by(|a, b| a.canonical_key().cmp(&b.canonical_key()));
+        let attrs: Vec<&str> = drvs.iter().map(|d| d.attr.as_str()).collect();
+        assert_eq!(
+            attrs,
+            [
+                "foo",
+                "foo-unwrapped",
+                "python3Packages.foo",
+                "python312Packages.foo"
+            ]
+        );
     }
 }
@@ -0,0 +1,262 @@
+//! Building a package's `passthru.tests` individually
+//!
+//! Each derivation in `passthru.tests` is built on its own, concurrently and with its own
+//! timeout, so a single slow or broken test doesn't hide the results of the others.
+
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::future::join_all;
+use tracing::{debug, info, warn};
+
+use crate::nix::{
+    BuildOptions, entry_point_args, eval_nix_expr, has_passthru_tests, import_entry_point,
+    nix_command,
+};
+
+/// Default time a single passthru test may take to build
+pub const DEFAULT_TEST_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Outcome of building a single passthru test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+impl TestOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TestOutcome::Passed => "passed",
+            TestOutcome::Failed => "failed",
+            TestOutcome::TimedOut => "timed_out",
+        }
+    }
+}
+
+impl fmt::Display for TestOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Result of building a single passthru test
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassthruTestResult {
+    /// Name of the test within `passthru.tests`
+    pub name: String,
+    pub outcome: TestOutcome,
+}
+
+/// List the derivations in `passthru.tests` of a package
+///
+/// Returns an empty list if the package has no tests. Attributes which aren't derivations are
+/// skipped.
+pub async fn list_passthru_tests(
+    eval_entry_point: &str,
+    attr_path: &str,
+) -> anyhow::Result<Vec<String>> {
+    if !has_passthru_tests(eval_entry_point, attr_path).await? {
+        return Ok(Vec::new());
+    }
+
+    let nix_expr = format!(
+        "with {}; let tests = {}.passthru.tests; in builtins.toJSON (builtins.filter (name: \
+         (builtins.tryEval (tests.${{name}}.type or null == \"derivation\")).value) \
+         (builtins.attrNames tests))",
+        import_entry_point(eval_entry_point),
+        attr_path
+    );
+
+    let json = eval_nix_expr(&nix_expr)
+        .await
+        .with_context(|| format!("Failed to list passthru.tests of {}", attr_path))?;
+    serde_json::from_str(&json).context("Failed to parse passthru.tests names")
+}
+
+/// Build a single passthru test, killing the build if it exceeds `timeout`
+async fn build_passthru_test(
+    eval_entry_point: &str,
+    attr_path: &str,
+    name: &str,
+    timeout: Duration,
+    build_options: &BuildOptions,
+) -> TestOutcome {
+    let test_attr = format!("{}.passthru.tests.{}", attr_path, quote_attr_name(name));
+    debug!("Building {}", test_attr);
+
+    let build = nix_command("nix-build")
+        .args(entry_point_args(eval_entry_point))
+        .arg("-A")
+        .arg(&test_attr)
+        .arg("--no-out-link")
+        .args(build_options.args())
+        .kill_on_drop(true)
+        .output();
+
+    match tokio::time::timeout(timeout, build).await {
+        Ok(Ok(output)) if output.status.success() => TestOutcome::Passed,
+        Ok(Ok(output)) => {
+            warn!(
+                "Test {} failed:\n{}",
+                test_attr,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            TestOutcome::Failed
+        },
+        Ok(Err(e)) => {
+            warn!("Failed to run nix-build for {}: {}", test_attr, e);
+            TestOutcome::Failed
+        },
+        Err(_) => {
+            warn!("Test {} timed out after {:?}", test_attr, timeout);
+            TestOutcome::TimedOut
+        },
+    }
+}
+
+/// Build every test in `passthru.tests` of a package concurrently
+///
+/// Returns one result per test, in the order of `list_passthru_tests`. Each test is limited by
+/// `timeout` rather than the timeout of `build_options`.
+pub async fn run_passthru_tests(
+    eval_entry_point: &str,
+    attr_path: &str,
+    timeout: Duration,
+    build_options: &BuildOptions,
+) -> anyhow::Result<Vec<PassthruTestResult>> {
+    let names = list_passthru_tests(eval_entry_point, attr_path).await?;
+    if names.is_empty() {
+        info!("No passthru.tests found for {}", attr_path);
+        return Ok(Vec::new());
+    }
+
+    info!(
+        "Building {} passthru.tests of {}: {}",
+        names.len(),
+        attr_path,
+        names.join(", ")
+    );
+
+    let outcomes = join_all(names.iter().map(|name| {
+        build_passthru_test(eval_entry_point, attr_path, name, timeout, build_options)
+    }))
+    .await;
+
+    Ok(names
+        .into_iter()
+        .zip(outcomes)
+        .map(|(name, outcome)| PassthruTestResult { name, outcome })
+        .collect())
+}
+
+/// Whether every test passed
+pub fn all_passed(results: &[PassthruTestResult]) -> bool {
+    results.iter().all(|r| r.outcome == TestOutcome::Passed)
+}
+
+/// Commit message trailer summarizing test results, None if no tests were run
+pub fn tests_trailer(results: &[PassthruTestResult]) -> Option<String> {
+    if results.is_empty() {
+        return None;
+    }
+
+    let passed = results
+        .iter()
+        .filter(|r| r.outcome == TestOutcome::Passed)
+        .count();
+    Some(format!(
+        "Tests: {}/{} passthru.tests passed",
+        passed,
+        results.len()
+    ))
+}
+
+/// Render test results as a markdown table for PR bodies
+pub fn format_test_report(results: &[PassthruTestResult]) -> String {
+    let passed = results
+        .iter()
+        .filter(|r| r.outcome == TestOutcome::Passed)
+        .count();
+
+    let mut report = format!(
+        "## passthru.tests\n\n{} of {} tests passed.\n\n| Test | Result |\n| --- | --- |",
+        passed,
+        results.len()
+    );
+    for result in results {
+        let outcome = match result.outcome {
+            TestOutcome::Passed => "✅ passed",
+            TestOutcome::Failed => "❌ failed",
+            TestOutcome::TimedOut => "⏱️ timed out",
+        };
+        report.push_str(&format!("\n| `{}` | {} |", result.name, outcome));
+    }
+    report
+}
+
+/// Quote an attribute name for use in an attribute path if needed
+fn quote_attr_name(name: &str) -> String {
+    let is_identifier = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''));
+
+    if is_identifier {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_test_report() {
+        let results = vec![
+            PassthruTestResult {
+                name: "version".to_string(),
+                outcome: TestOutcome::Passed,
+            },
+            PassthruTestResult {
+                name: "nixos".to_string(),
+                outcome: TestOutcome::TimedOut,
+            },
+        ];
+
+        let report = format_test_report(&results);
+        assert!(report.contains("1 of 2 tests passed."));
+        assert!(report.contains("| `version` | ✅ passed |"));
+        assert!(report.contains("| `nixos` | ⏱️ timed out |"));
+        assert!(!all_passed(&results));
+    }
+
+    #[test]
+    fn test_quote_attr_name() {
+        assert_eq!(quote_attr_name("version"), "version");
+        assert_eq!(quote_attr_name("cross-aarch64"), "cross-aarch64");
+        assert_eq!(quote_attr_name("3rdparty"), "\"3rdparty\"");
+        assert_eq!(quote_attr_name("with.dot"), "\"with.dot\"");
+    }
+
+    #[test]
+    fn test_tests_trailer() {
+        assert_eq!(tests_trailer(&[]), None);
+
+        let results = vec![PassthruTestResult {
+            name: "version".to_string(),
+            outcome: TestOutcome::Passed,
+        }];
+        assert_eq!(
+            tests_trailer(&results).as_deref(),
+            Some("Tests: 1/1 passthru.tests passed")
+        );
+    }
+}
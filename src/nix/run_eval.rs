@@ -1,12 +1,98 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 
 use futures::stream::Stream;
 use tokio::fs;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
 use tracing::{debug, warn};
 
 use super::nix_eval_jobs::NixEvalItem;
+use super::{entry_point_args, nix_command};
+
+/// Signal the kernel OOM killer kills processes with
+const SIGKILL: i32 = 9;
+
+/// Tuning of nix-eval-jobs, for trees too large to evaluate with its defaults
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvalJobsOptions {
+    /// Number of evaluation workers, passed as `--workers`
+    pub workers: Option<usize>,
+    /// Memory in MiB a worker may use before it is replaced, passed as `--max-memory-size`
+    pub max_memory_size: Option<usize>,
+    /// Directory to register GC roots of the evaluated derivations in, passed as
+    /// `--gc-roots-dir`
+    pub gc_roots_dir: Option<String>,
+    /// Additional arguments of nix-eval-jobs
+    pub extra_args: Vec<String>,
+    /// Number of times nix-eval-jobs is restarted after running out of memory
+    pub restarts: u32,
+    /// Output the meta attributes of derivations, passed as `--meta`
+    pub meta: bool,
+}
+
+impl EvalJobsOptions {
+    /// Command line arguments for nix-eval-jobs
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(workers) = self.workers {
+            args.extend(["--workers".to_string(), workers.to_string()]);
+        }
+        if let Some(max_memory_size) = self.max_memory_size {
+            args.extend(["--max-memory-size".to_string(), max_memory_size.to_string()]);
+        }
+        if let Some(gc_roots_dir) = &self.gc_roots_dir {
+            args.extend(["--gc-roots-dir".to_string(), gc_roots_dir.clone()]);
+        }
+        if self.meta {
+            args.push("--meta".to_string());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+/// Whether nix-eval-jobs, or one of its workers, was killed for lack of memory
+fn killed_by_oom(signal: Option<i32>, stderr: &str) -> bool {
+    const OOM_MARKERS: &[&str] = &[
+        "signal 9",
+        "Killed",
+        "out of memory",
+        "Out of memory",
+        "std::bad_alloc",
+    ];
+    signal == Some(SIGKILL) || OOM_MARKERS.iter().any(|marker| stderr.contains(marker))
+}
+
+/// Lines of the stderr of nix-eval-jobs included in the error of a failed evaluation
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Hint at how to fix a failed evaluation, from the stderr of nix-eval-jobs
+fn remediation_hint(stderr: &str) -> &'static str {
+    if stderr.contains("without a default value") || stderr.contains("without required argument") {
+        "The entry point requires arguments, pass them with --arg, --argstr or --eval-args"
+    } else if stderr.contains("getting status of") || stderr.contains("does not exist") {
+        "Check that the file to evaluate exists and is a Nix expression"
+    } else if killed_by_oom(None, stderr) {
+        "nix-eval-jobs ran out of memory, lower --eval-max-memory-size or --eval-workers, or raise \
+         --eval-restarts"
+    } else {
+        "Check that the tree evaluates, e.g. with nix-instantiate"
+    }
+}
+
+/// Error of a failed nix-eval-jobs run, with the end of its stderr and a hint at the fix
+fn eval_failure(status: std::process::ExitStatus, stderr: &str, log_path: &Path) -> anyhow::Error {
+    let lines: Vec<&str> = stderr.trim_end().lines().collect();
+    let tail = lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n");
+    anyhow::anyhow!(
+        "nix-eval-jobs failed ({}):\n{}\n{}. The full log is in {}",
+        status,
+        tail,
+        remediation_hint(stderr),
+        log_path.display()
+    )
+}
 
 /// Get the path to the nix-eval-jobs stderr log file in XDG cache directory
 async fn get_stderr_log_path() -> anyhow::Result<PathBuf> {
@@ -21,7 +107,14 @@ async fn get_stderr_log_path() -> anyhow::Result<PathBuf> {
     Ok(logs_dir.join("nix-eval-jobs.stderr.log"))
 }
 
-pub fn run_nix_eval_jobs(file_path: String) -> impl Stream<Item = anyhow::Result<NixEvalItem>> {
+/// Evaluate every package of a tree with nix-eval-jobs
+///
+/// If nix-eval-jobs runs out of memory, it's restarted up to `options.restarts` times with half
+/// the workers, and the attributes evaluated before are skipped.
+pub fn run_nix_eval_jobs(
+    file_path: String,
+    options: EvalJobsOptions,
+) -> impl Stream<Item = anyhow::Result<NixEvalItem>> {
     async_stream::stream! {
         // Set up stderr logging to XDG cache directory
         let log_path = match get_stderr_log_path().await {
@@ -32,61 +125,169 @@ pub fn run_nix_eval_jobs(file_path: String) -> impl Stream<Item = anyhow::Result
             }
         };
 
-        let stderr_file = match std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&log_path)
-        {
-            Ok(file) => file,
-            Err(e) => {
-                yield Err(anyhow::anyhow!("Failed to open stderr log file: {}", e));
-                return;
-            }
-        };
-
-        debug!("nix-eval-jobs stderr logging to: {:?}", log_path);
-
-        let mut cmd = match Command::new("nix-eval-jobs")
-            .arg("--show-input-drvs")
-            .arg(&file_path)
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::from(stderr_file))
-            .spawn()
-        {
-            Ok(cmd) => cmd,
-            Err(e) => {
-                yield Err(anyhow::anyhow!("Failed to spawn nix-eval-jobs: {}", e));
-                return;
-            }
-        };
+        let mut options = options;
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut restarts = 0;
+        loop {
+            // Only the stderr of this run tells why it failed
+            let log_start = fs::metadata(&log_path).await.map_or(0, |m| m.len());
+            let stderr_file = match std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_path)
+            {
+                Ok(file) => file,
+                Err(e) => {
+                    yield Err(anyhow::anyhow!("Failed to open stderr log file: {}", e));
+                    return;
+                }
+            };
 
-        // TODO: handle failure case more nicely
-        let stdout = cmd.stdout.take().unwrap();
-        // Create a stream, so that we can pass through values as they are produced
-        let stdout_reader = BufReader::new(stdout);
-        let mut stdout_lines = stdout_reader.lines();
+            debug!("nix-eval-jobs stderr logging to: {:?}", log_path);
 
-        while let Some(line) = stdout_lines.next_line().await.transpose() {
-            let line = match line {
-                Ok(line) => line,
+            let mut cmd = match nix_command("nix-eval-jobs")
+                .arg("--show-input-drvs")
+                .args(options.args())
+                .args(entry_point_args(&file_path))
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::from(stderr_file))
+                .spawn()
+            {
+                Ok(cmd) => cmd,
                 Err(e) => {
-                    yield Err(anyhow::anyhow!("Error reading line: {}", e));
-                    continue;
+                    yield Err(anyhow::anyhow!(
+                        "Failed to spawn nix-eval-jobs: {}. Is nix-eval-jobs installed and in PATH?",
+                        e
+                    ));
+                    return;
                 }
             };
 
-            match serde_json::from_str::<NixEvalItem>(&line) {
-                Ok(item) => {
-                    yield Ok(item);
-                },
+            // TODO: handle failure case more nicely
+            let stdout = cmd.stdout.take().unwrap();
+            // Create a stream, so that we can pass through values as they are produced
+            let stdout_reader = BufReader::new(stdout);
+            let mut stdout_lines = stdout_reader.lines();
+
+            while let Some(line) = stdout_lines.next_line().await.transpose() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        yield Err(anyhow::anyhow!("Error reading line: {}", e));
+                        continue;
+                    }
+                };
+
+                match serde_json::from_str::<NixEvalItem>(&line) {
+                    Ok(item) => {
+                        let attr = match &item {
+                            NixEvalItem::Drv(drv) => &drv.attr,
+                            NixEvalItem::Error(error) => &error.attr,
+                        };
+                        // Attributes evaluated before a restart
+                        if !seen.insert(attr.clone()) {
+                            continue;
+                        }
+                        yield Ok(item);
+                    },
+                    Err(e) => {
+                        warn!(
+                            "Encountered error when deserializing nix-eval-jobs output: {:?}",
+                            e
+                        );
+                        continue;
+                    }
+                };
+            }
+
+            let status = match cmd.wait().await {
+                Ok(status) => status,
                 Err(e) => {
-                    warn!(
-                        "Encountered error when deserializing nix-eval-jobs output: {:?}",
-                        e
-                    );
-                    continue;
+                    yield Err(anyhow::anyhow!("Failed to wait for nix-eval-jobs: {}", e));
+                    return;
                 }
             };
+            if status.success() {
+                return;
+            }
+
+            let stderr = match fs::read(&log_path).await {
+                Ok(log) => String::from_utf8_lossy(log.get(log_start as usize..).unwrap_or(&[]))
+                    .to_string(),
+                Err(_) => String::new(),
+            };
+            if restarts >= options.restarts || !killed_by_oom(status.signal(), &stderr) {
+                yield Err(eval_failure(status, &stderr, &log_path));
+                return;
+            }
+
+            restarts += 1;
+            let workers = options.workers.unwrap_or(1);
+            options.workers = Some((workers / 2).max(1));
+            warn!(
+                "nix-eval-jobs ran out of memory after evaluating {} attributes, restarting with \
+                 {} workers ({}/{})",
+                seen.len(),
+                options.workers.unwrap_or(1),
+                restarts,
+                options.restarts
+            );
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_jobs_options() {
+        assert!(EvalJobsOptions::default().args().is_empty());
+
+        let options = EvalJobsOptions {
+            workers: Some(4),
+            max_memory_size: Some(4096),
+            gc_roots_dir: Some("/tmp/gcroots".to_string()),
+            extra_args: vec!["--force-recurse".to_string()],
+            restarts: 2,
+            meta: true,
+        };
+        assert_eq!(
+            options.args(),
+            vec![
+                "--workers",
+                "4",
+                "--max-memory-size",
+                "4096",
+                "--gc-roots-dir",
+                "/tmp/gcroots",
+                "--meta",
+                "--force-recurse"
+            ]
+        );
+
+        assert!(killed_by_oom(Some(SIGKILL), ""));
+        assert!(killed_by_oom(
+            None,
+            "error: worker process 1234 exited with signal 9"
+        ));
+        assert!(!killed_by_oom(None, "error: attribute 'foo' missing"));
+    }
+
+    #[test]
+    fn test_remediation_hint() {
+        assert!(
+            remediation_hint(
+                "error: cannot evaluate a function that has an argument without a value \
+                 ('system')\nerror: cannot auto-call a function that has an argument without a \
+                 default value ('system')"
+            )
+            .contains("--argstr")
+        );
+        assert!(
+            remediation_hint("error: getting status of '/src/missing.nix': No such file")
+                .contains("file to evaluate")
+        );
+        assert!(remediation_hint("terminate called after std::bad_alloc").contains("memory"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Verifying an update on additional systems
+//!
+//! Packages are normally only built for the system the updater runs on. Building for other
+//! systems requires remote builders (see `--builders`) or emulation, e.g. binfmt with qemu.
+//! Cross-compiling for other platforms, e.g. `aarch64-unknown-linux-gnu`, only requires the
+//! entry point to accept `crossSystem` like nixpkgs does.
+
+use anyhow::Context;
+use futures::future::join_all;
+use tracing::{info, warn};
+
+use crate::nix::{BuildOptions, build_nix_expr, eval_nix_expr, import_entry_point};
+
+/// Outcome of building a package for one system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemOutcome {
+    Passed,
+    Failed,
+}
+
+/// Result of building a package for one system
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemBuildResult {
+    /// Nix system, or platform config of a cross build
+    pub system: String,
+    /// Whether the package was cross-compiled for the system
+    pub cross: bool,
+    pub outcome: SystemOutcome,
+}
+
+/// Filter `systems` down to those listed in the package's `meta.platforms`
+pub async fn supported_systems(
+    eval_entry_point: &str,
+    attr_path: &str,
+    systems: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let package_set = import_entry_point(eval_entry_point);
+    let systems_list = systems
+        .iter()
+        .map(|system| format!("\"{}\"", system))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let nix_expr = format!(
+        "with {}; let platforms = {}.meta.platforms or [ ]; in builtins.toJSON (builtins.filter \
+         (system: builtins.elem system platforms) [ {} ])",
+        package_set, attr_path, systems_list
+    );
+
+    let json = eval_nix_expr(&nix_expr)
+        .await
+        .with_context(|| format!("Failed to evaluate meta.platforms of {}", attr_path))?;
+    serde_json::from_str(&json).context("Failed to parse supported systems")
+}
+
+/// Build a package for each of `systems` it supports and cross-compile it for each of
+/// `cross_systems`, concurrently
+///
+/// Systems not listed in `meta.platforms` are skipped. Failures are reported rather than
+/// returned as errors, so one broken platform doesn't hide the results of the others.
+pub async fn build_for_systems(
+    eval_entry_point: &str,
+    attr_path: &str,
+    systems: &[String],
+    cross_systems: &[String],
+    build_options: &BuildOptions,
+) -> anyhow::Result<Vec<SystemBuildResult>> {
+    let systems = if systems.is_empty() {
+        Vec::new()
+    } else {
+        supported_systems(eval_entry_point, attr_path, systems).await?
+    };
+    if systems.is_empty() && cross_systems.is_empty() {
+        info!("{} supports none of the systems to verify", attr_path);
+        return Ok(Vec::new());
+    }
+
+    let targets: Vec<(String, bool)> = systems
+        .into_iter()
+        .map(|system| (system, false))
+        .chain(cross_systems.iter().map(|system| (system.clone(), true)))
+        .collect();
+    info!(
+        "Building {} for {}",
+        attr_path,
+        targets
+            .iter()
+            .map(|(system, cross)| describe_target(system, *cross))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let builds = targets.iter().map(|(system, cross)| async move {
+        let options = if *cross {
+            BuildOptions {
+                cross_system: Some(system.clone()),
+                ..build_options.clone()
+            }
+        } else {
+            BuildOptions {
+                system: Some(system.clone()),
+                ..build_options.clone()
+            }
+        };
+        let target = describe_target(system, *cross);
+        match build_nix_expr(eval_entry_point, attr_path, None, &options).await {
+            Ok((true, _stdout, _stderr)) => SystemOutcome::Passed,
+            Ok((false, _stdout, stderr)) => {
+                warn!("{}: Build for {} failed:\n{}", attr_path, target, stderr);
+                SystemOutcome::Failed
+            },
+            Err(e) => {
+                warn!("{}: Build for {} failed: {}", attr_path, target, e);
+                SystemOutcome::Failed
+            },
+        }
+    });
+    let outcomes = join_all(builds).await;
+
+    Ok(targets
+        .into_iter()
+        .zip(outcomes)
+        .map(|((system, cross), outcome)| SystemBuildResult {
+            system,
+            cross,
+            outcome,
+        })
+        .collect())
+}
+
+/// Name of a build target in logs and reports, e.g. `aarch64-unknown-linux-gnu (cross)`
+fn describe_target(system: &str, cross: bool) -> String {
+    if cross {
+        format!("{} (cross)", system)
+    } else {
+        system.to_string()
+    }
+}
+
+/// Render per-system build results as a markdown table for PR bodies
+pub fn format_system_report(results: &[SystemBuildResult]) -> String {
+    let mut report = String::from("## Systems\n\n| System | Result |\n| --- | --- |");
+    for result in results {
+        let outcome = match result.outcome {
+            SystemOutcome::Passed => "✅ built",
+            SystemOutcome::Failed => "❌ failed",
+        };
+        let system = format!("`{}`", result.system);
+        report.push_str(&format!(
+            "\n| {} | {} |",
+            describe_target(&system, result.cross),
+            outcome
+        ));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_system_report() {
+        let results = vec![
+            SystemBuildResult {
+                system: "x86_64-linux".to_string(),
+                cross: false,
+                outcome: SystemOutcome::Passed,
+            },
+            SystemBuildResult {
+                system: "aarch64-linux".to_string(),
+                cross: false,
+                outcome: SystemOutcome::Failed,
+            },
+            SystemBuildResult {
+                system: "riscv64-unknown-linux-gnu".to_string(),
+                cross: true,
+                outcome: SystemOutcome::Passed,
+            },
+        ];
+
+        let report = format_system_report(&results);
+        assert!(report.starts_with("## Systems"));
+        assert!(report.contains("| `x86_64-linux` | ✅ built |"));
+        assert!(report.contains("| `aarch64-linux` | ❌ failed |"));
+        assert!(report.contains("| `riscv64-unknown-linux-gnu` (cross) | ✅ built |"));
+    }
+}
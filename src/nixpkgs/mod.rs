@@ -0,0 +1,118 @@
+//! Comparing packages against nixpkgs
+//!
+//! Most packages of the tree are also packaged in nixpkgs. Mentioning the version nixpkgs has in
+//! reports and PR bodies helps deciding whether to update a package locally or sync it from
+//! nixpkgs. The nixpkgs checkout or channel is configured with `nixpkgs = "<nixpkgs>"` or a path
+//! in the configuration file.
+
+use std::path::PathBuf;
+
+use tracing::debug;
+
+use crate::nix::{eval_nix_expr, normalize_entry_point};
+use crate::vcs_sources::{SemverStrategy, is_version_acceptable};
+
+/// Nix expression importing a nixpkgs checkout, or a channel like `<nixpkgs>`
+fn nixpkgs_expr(nixpkgs: &str) -> String {
+    if nixpkgs.starts_with('<') {
+        nixpkgs.to_string()
+    } else {
+        normalize_entry_point(nixpkgs)
+    }
+}
+
+/// Version of a package in nixpkgs, None if nixpkgs doesn't have it
+///
+/// Fails if the package fails to evaluate in nixpkgs, e.g. because it is marked broken.
+pub async fn nixpkgs_version(nixpkgs: &str, attr_path: &str) -> anyhow::Result<Option<String>> {
+    let expr = format!(
+        "with import {} {{ }}; {}.version or \"\"",
+        nixpkgs_expr(nixpkgs),
+        attr_path
+    );
+    let version = eval_nix_expr(&expr).await?;
+    Ok(Some(version).filter(|version| !version.is_empty()))
+}
+
+/// File defining a package in nixpkgs, from its `meta.position`
+pub async fn package_file(nixpkgs: &str, attr_path: &str) -> anyhow::Result<PathBuf> {
+    let expr = format!(
+        "with import {} {{ }}; {}.meta.position",
+        nixpkgs_expr(nixpkgs),
+        attr_path
+    );
+    let position = eval_nix_expr(&expr).await?;
+    let (file, _line) = position
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("{} has no meta.position in nixpkgs", attr_path))?;
+    Ok(PathBuf::from(file))
+}
+
+/// Describe the nixpkgs version of a package relative to `version`, e.g. `nixpkgs has 1.2.0
+/// (newer)`
+pub fn describe(nixpkgs_version: &str, version: &str) -> String {
+    let relation = if nixpkgs_version == version {
+        "same"
+    } else if is_version_acceptable(version, nixpkgs_version, SemverStrategy::Latest)
+        .unwrap_or(false)
+    {
+        "newer"
+    } else {
+        "older"
+    };
+    format!("nixpkgs has {} ({})", nixpkgs_version, relation)
+}
+
+/// Describe the result of [`nixpkgs_version`] relative to `version`
+fn describe_result(nixpkgs_version: &anyhow::Result<Option<String>>, version: &str) -> String {
+    match nixpkgs_version {
+        Ok(Some(nixpkgs_version)) => describe(nixpkgs_version, version),
+        Ok(None) => "not in nixpkgs".to_string(),
+        Err(_) => "fails to evaluate in nixpkgs".to_string(),
+    }
+}
+
+/// Compare `version` of a package with the version nixpkgs has, for reports
+pub async fn compare(nixpkgs: &str, attr_path: &str, version: &str) -> String {
+    let nixpkgs_version = nixpkgs_version(nixpkgs, attr_path).await;
+    if let Err(e) = &nixpkgs_version {
+        debug!("{}: Failed to evaluate in nixpkgs: {}", attr_path, e);
+    }
+    describe_result(&nixpkgs_version, version)
+}
+
+/// Markdown section comparing an update to `new_version` with nixpkgs, given the result of
+/// [`nixpkgs_version`]
+pub fn report_section(
+    nixpkgs_version: &anyhow::Result<Option<String>>,
+    new_version: &str,
+) -> String {
+    let comparison = match nixpkgs_version {
+        Ok(Some(nixpkgs_version)) => describe(nixpkgs_version, new_version),
+        Ok(None) => "nixpkgs doesn't have this package".to_string(),
+        Err(_) => "This package fails to evaluate in nixpkgs".to_string(),
+    };
+    format!("## nixpkgs\n\n{}.", comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe("2.12.1", "2.12.1"), "nixpkgs has 2.12.1 (same)");
+        assert_eq!(describe("2.13.0", "2.12.1"), "nixpkgs has 2.13.0 (newer)");
+        assert_eq!(describe("2.10", "2.12.1"), "nixpkgs has 2.10 (older)");
+        assert_eq!(
+            report_section(&Ok(None), "2.12.1"),
+            "## nixpkgs\n\nnixpkgs doesn't have this package."
+        );
+        assert_eq!(
+            report_section(&Err(anyhow::anyhow!("broken")), "2.12.1"),
+            "## nixpkgs\n\nThis package fails to evaluate in nixpkgs."
+        );
+        assert_eq!(nixpkgs_expr("<nixpkgs>"), "<nixpkgs>");
+        assert_eq!(nixpkgs_expr("../nixpkgs"), "./../nixpkgs");
+    }
+}
@@ -0,0 +1,232 @@
+//! OSV.dev security advisory integration
+//!
+//! Queries the vulnerabilities affecting the current and proposed version of a package, so
+//! updates fixing known CVEs can be called out in PRs and prioritized.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::http::{self, Throttled};
+use crate::vcs_sources::UpstreamSource;
+
+const OSV_QUERY_URL: &str = "https://api.osv.dev/v1/query";
+
+/// A package as identified by OSV
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OsvPackage {
+    pub ecosystem: String,
+    pub name: String,
+}
+
+impl OsvPackage {
+    /// Map an upstream source to its OSV ecosystem and package name, None for sources OSV doesn't
+    /// track
+    pub fn from_upstream(source: &UpstreamSource) -> Option<Self> {
+        let package = match source {
+            UpstreamSource::PyPI { pname } => Self {
+                ecosystem: "PyPI".to_string(),
+                name: pname.clone(),
+            },
+            UpstreamSource::GitHub { owner, repo, .. } => Self {
+                ecosystem: "GIT".to_string(),
+                name: format!("https://github.com/{}/{}", owner, repo),
+            },
+            UpstreamSource::GitLab { owner, project } => Self {
+                ecosystem: "GIT".to_string(),
+                name: format!("https://gitlab.com/{}/{}", owner, project),
+            },
+            UpstreamSource::LibrariesIo { platform, name } => Self {
+                ecosystem: match platform.as_str() {
+                    "cargo" => "crates.io".to_string(),
+                    "rubygems" => "RubyGems".to_string(),
+                    "hackage" => "Hackage".to_string(),
+                    other => other.to_string(),
+                },
+                name: name.clone(),
+            },
+            UpstreamSource::Mirror { .. } | UpstreamSource::Plugin { .. } => return None,
+        };
+        Some(package)
+    }
+}
+
+/// A vulnerability reported by OSV
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Vulnerability {
+    /// OSV identifier, e.g. `GHSA-xxxx-xxxx-xxxx` or `PYSEC-2024-1`
+    pub id: String,
+    /// Other identifiers of the same vulnerability, e.g. CVE IDs
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub summary: Option<String>,
+}
+
+impl Vulnerability {
+    /// CVE identifier if known, otherwise the OSV identifier
+    pub fn display_id(&self) -> &str {
+        self.aliases
+            .iter()
+            .find(|alias| alias.starts_with("CVE-"))
+            .unwrap_or(&self.id)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OsvQuery<'a> {
+    package: &'a OsvPackage,
+    version: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OsvResponse {
+    #[serde(default)]
+    vulns: Vec<Vulnerability>,
+    next_page_token: Option<String>,
+}
+
+/// Query the vulnerabilities affecting a version of a package
+pub async fn query_vulnerabilities(
+    package: &OsvPackage,
+    version: &str,
+) -> anyhow::Result<Vec<Vulnerability>> {
+    debug!(
+        "Querying OSV for {} {} {}",
+        package.ecosystem, package.name, version
+    );
+
+    let client = http::client();
+    let mut vulnerabilities = Vec::new();
+    let mut page_token = None;
+
+    loop {
+        let query = OsvQuery {
+            package,
+            version,
+            page_token,
+        };
+        let response = client
+            .post(OSV_QUERY_URL)
+            .header("User-Agent", "ekapkgs-update")
+            .json(&query)
+            .send_throttled()
+            .await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("OSV API request failed with status: {}", response.status());
+        }
+
+        let response: OsvResponse = response.json().await?;
+        vulnerabilities.extend(response.vulns);
+
+        match response.next_page_token {
+            Some(token) if !token.is_empty() => page_token = Some(token),
+            _ => break,
+        }
+    }
+
+    Ok(vulnerabilities)
+}
+
+/// Vulnerabilities of the current version, split by whether the proposed version fixes them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SecurityStatus {
+    /// Vulnerabilities no longer affecting the proposed version
+    pub fixed: Vec<Vulnerability>,
+    /// Vulnerabilities still affecting the proposed version
+    pub remaining: Vec<Vulnerability>,
+}
+
+impl SecurityStatus {
+    /// Compare the vulnerabilities of the current and proposed version
+    pub fn new(current: Vec<Vulnerability>, proposed: &[Vulnerability]) -> Self {
+        let proposed_ids: HashSet<&str> = proposed.iter().map(|v| v.id.as_str()).collect();
+        let (remaining, fixed) = current
+            .into_iter()
+            .partition(|v| proposed_ids.contains(v.id.as_str()));
+        Self { fixed, remaining }
+    }
+
+    /// Query OSV for the security status of updating a package
+    pub async fn query(
+        source: &UpstreamSource,
+        current_version: &str,
+        new_version: &str,
+    ) -> anyhow::Result<Self> {
+        let Some(package) = OsvPackage::from_upstream(source) else {
+            return Ok(Self::default());
+        };
+        let current = query_vulnerabilities(&package, current_version).await?;
+        if current.is_empty() {
+            return Ok(Self::default());
+        }
+        let proposed = query_vulnerabilities(&package, new_version).await?;
+        Ok(Self::new(current, &proposed))
+    }
+
+    /// Markdown section listing the vulnerabilities fixed by an update, None if there are none
+    pub fn report(&self) -> Option<String> {
+        if self.fixed.is_empty() {
+            return None;
+        }
+
+        let mut report = String::from("## Security\n\nThis update fixes:\n");
+        for vulnerability in &self.fixed {
+            report.push_str(&format!(
+                "\n- [{}](https://osv.dev/vulnerability/{})",
+                vulnerability.display_id(),
+                vulnerability.id
+            ));
+            if let Some(summary) = &vulnerability.summary {
+                report.push_str(&format!(": {}", summary));
+            }
+        }
+        if !self.remaining.is_empty() {
+            let ids: Vec<&str> = self.remaining.iter().map(|v| v.display_id()).collect();
+            report.push_str(&format!("\n\nStill affected by: {}", ids.join(", ")));
+        }
+        Some(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vulnerability(id: &str, cve: Option<&str>) -> Vulnerability {
+        Vulnerability {
+            id: id.to_string(),
+            aliases: cve.into_iter().map(str::to_string).collect(),
+            summary: None,
+        }
+    }
+
+    #[test]
+    fn test_security_status() {
+        let current = vec![
+            vulnerability("GHSA-aaaa-bbbb-cccc", Some("CVE-2024-1234")),
+            vulnerability("PYSEC-2024-2", None),
+        ];
+        let proposed = vec![vulnerability("PYSEC-2024-2", None)];
+
+        let status = SecurityStatus::new(current, &proposed);
+        assert_eq!(status.fixed.len(), 1);
+        assert_eq!(status.fixed[0].display_id(), "CVE-2024-1234");
+
+        let report = status.report().unwrap();
+        assert!(
+            report.contains("[CVE-2024-1234](https://osv.dev/vulnerability/GHSA-aaaa-bbbb-cccc)")
+        );
+        assert!(report.contains("Still affected by: PYSEC-2024-2"));
+        assert_eq!(SecurityStatus::default().report(), None);
+    }
+
+    #[test]
+    fn test_osv_response_without_vulns() {
+        let response: OsvResponse = serde_json::from_str("{}").unwrap();
+        assert!(response.vulns.is_empty());
+    }
+}
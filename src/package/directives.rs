@@ -0,0 +1,276 @@
+//! Update policy written next to the expression of a package
+//!
+//! Comments of the file defining a package may adjust how it's updated:
+//!
+//! ```nix
+//! # ekapkgs-update: skip waiting on the 3.0 migration
+//! # ekapkgs-update: semver=patch
+//! # ekapkgs-update: source=github:foo/bar
+//! ```
+//!
+//! `skip` takes an optional reason. `semver` is one of `latest`, `major`, `minor` and `patch`,
+//! the stricter of it and the requested strategy is used. `source` is a URL or one of
+//! `github:owner/repo`, `gitlab:owner/project` and `pypi:pname`, looked up instead of the
+//! source URL of the package. nixpkgs' `# nixpkgs-update: no auto update` is honored as `skip`.
+//!
+//! Directives written in the attribute set of a derivation, i.e. one binding `pname`, `version`
+//! or `name`, apply to that derivation only, as do directives trailing a binding which defines
+//! one. Directives outside of any derivation apply to every package defined in the file.
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+use rnix::{SyntaxKind, SyntaxNode, SyntaxToken, TextRange, TextSize, ast};
+use rowan::ast::AstNode;
+
+use crate::vcs_sources::SemverStrategy;
+
+/// Directives found in the file of a package
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Directives {
+    /// Reason the package isn't updated automatically, empty if none was given
+    pub skip: Option<String>,
+    /// Strictest semver strategy to update the package with
+    pub semver: Option<SemverStrategy>,
+    /// URL of the upstream source to look releases up in
+    pub source: Option<String>,
+}
+
+impl Directives {
+    /// Parse the directives of the comments of a file which apply to the package defined at
+    /// `line`
+    ///
+    /// `line` is the 1-based line of the package's `meta.position`, only the directives of the
+    /// whole file apply if it's unknown. Malformed directives are returned as errors along with
+    /// the valid ones, wherever they're written, so a typo doesn't go unnoticed nor discard the
+    /// rest of the file.
+    pub fn parse(content: &str, line: Option<usize>) -> (Self, Vec<String>) {
+        static DIRECTIVE: OnceLock<Regex> = OnceLock::new();
+        let directive = DIRECTIVE.get_or_init(|| {
+            Regex::new(r"^#[ \t]*(ekapkgs|nixpkgs)-update:[ \t]*(.*?)[ \t]*$").unwrap()
+        });
+
+        let package_line = line.and_then(|line| line_range(content, line));
+        let root = rnix::Root::parse(content).syntax();
+        let mut directives = Self::default();
+        let mut errors = Vec::new();
+        let comments = root
+            .descendants_with_tokens()
+            .filter_map(|element| element.into_token())
+            .filter(|token| token.kind() == SyntaxKind::TOKEN_COMMENT);
+        for comment in comments {
+            let Some(caps) = directive.captures(comment.text()) else {
+                continue;
+            };
+            let applies = match scope(&comment) {
+                None => true,
+                Some(scope) => package_line
+                    .is_some_and(|line| scope.start() < line.end() && line.start() < scope.end()),
+            };
+            // Directives of other packages are still parsed to report their errors
+            let mut other_package = Self::default();
+            let target = if applies {
+                &mut directives
+            } else {
+                &mut other_package
+            };
+
+            let text = &caps[2];
+            if &caps[1] == "nixpkgs" {
+                if text == "no auto update" {
+                    target.skip.get_or_insert_with(String::new);
+                }
+                continue;
+            }
+
+            let (name, value) = match text.split_once(['=', ' ', '\t']) {
+                Some((name, value)) => (name, value.trim_start_matches([' ', '\t', '='])),
+                None => (text, ""),
+            };
+            match name {
+                "skip" => target.skip = Some(value.to_string()),
+                "semver" => match value.parse::<SemverStrategy>() {
+                    Ok(strategy) => target.semver = Some(strategy),
+                    Err(e) => errors.push(e.to_string()),
+                },
+                "source" => match source_url(value) {
+                    Some(url) => target.source = Some(url),
+                    None => errors.push(format!("Invalid source directive: '{}'", value)),
+                },
+                _ => errors.push(format!("Unknown directive: '{}'", text)),
+            }
+        }
+        (directives, errors)
+    }
+}
+
+/// Range of the derivation a directive comment applies to, None if it applies to the whole file
+///
+/// A comment trailing a binding applies to the derivation the binding defines, if any. Otherwise
+/// comments apply to the derivation they're written in.
+fn scope(comment: &SyntaxToken) -> Option<TextRange> {
+    if let Some(binding) = trailed_binding(comment) {
+        if let Some(derivation) = binding.syntax().descendants().find(defines_derivation) {
+            return Some(derivation.text_range());
+        }
+    }
+    comment
+        .parent_ancestors()
+        .find(defines_derivation)
+        .map(|derivation| derivation.text_range())
+}
+
+/// Binding ending on the line of a comment, before it
+fn trailed_binding(comment: &SyntaxToken) -> Option<ast::AttrpathValue> {
+    let mut token = comment.prev_token()?;
+    while matches!(
+        token.kind(),
+        SyntaxKind::TOKEN_WHITESPACE | SyntaxKind::TOKEN_COMMENT
+    ) {
+        if token.text().contains('\n') {
+            return None;
+        }
+        token = token.prev_token()?;
+    }
+    token
+        .parent_ancestors()
+        .filter_map(ast::AttrpathValue::cast)
+        .find(|binding| binding.syntax().text_range().end() == token.text_range().end())
+}
+
+/// Whether a node is the attribute set of a derivation, i.e. binds `pname`, `version` or `name`
+fn defines_derivation(node: &SyntaxNode) -> bool {
+    let Some(set) = ast::AttrSet::cast(node.clone()) else {
+        return false;
+    };
+    set.syntax()
+        .children()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter_map(|binding| binding.attrpath())
+        .any(|path| {
+            let mut attrs = path.attrs();
+            match (attrs.next(), attrs.next()) {
+                (Some(ast::Attr::Ident(ident)), None) => ident
+                    .ident_token()
+                    .is_some_and(|t| matches!(t.text(), "pname" | "version" | "name")),
+                _ => false,
+            }
+        })
+}
+
+/// Range of a 1-based line of `content`
+fn line_range(content: &str, line: usize) -> Option<TextRange> {
+    let start = match line {
+        0 => return None,
+        1 => 0,
+        _ => content.match_indices('\n').nth(line - 2)?.0 + 1,
+    };
+    let end = content[start..]
+        .find('\n')
+        .map_or(content.len(), |i| start + i);
+    Some(TextRange::new(
+        TextSize::try_from(start).ok()?,
+        TextSize::try_from(end).ok()?,
+    ))
+}
+
+/// URL of a `source` directive, e.g. `https://github.com/foo/bar` for `github:foo/bar`
+fn source_url(value: &str) -> Option<String> {
+    if value.contains("://") {
+        return Some(value.to_string());
+    }
+    let (platform, name) = value.split_once(':')?;
+    if name.is_empty() {
+        return None;
+    }
+    match platform {
+        "github" => Some(format!("https://github.com/{}", name)),
+        "gitlab" => Some(format!("https://gitlab.com/{}", name)),
+        "pypi" => Some(format!("https://pypi.org/project/{}/", name)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_directives() {
+        let content = r#"{ stdenv, fetchFromGitHub }:
+# ekapkgs-update: semver = patch
+# ekapkgs-update: source=github:foo/bar
+stdenv.mkDerivation {
+  pname = "hello"; # ekapkgs-update: skip waiting on the 3.0 migration
+}"#;
+        let (directives, errors) = Directives::parse(content, Some(5));
+        assert_eq!(
+            directives,
+            Directives {
+                skip: Some("waiting on the 3.0 migration".to_string()),
+                semver: Some(SemverStrategy::Patch),
+                source: Some("https://github.com/foo/bar".to_string()),
+            }
+        );
+        assert!(errors.is_empty());
+
+        let (directives, errors) = Directives::parse("# nixpkgs-update: no auto update\n", None);
+        assert_eq!(directives.skip.as_deref(), Some(""));
+        assert!(errors.is_empty());
+
+        let (directives, errors) = Directives::parse(
+            "# ekapkgs-update: semver=sideways\n# ekapkgs-update: source=pypi:requests\n# \
+             ekapkgs-update: frobnicate\n",
+            None,
+        );
+        assert_eq!(
+            directives.source.as_deref(),
+            Some("https://pypi.org/project/requests/")
+        );
+        assert_eq!(directives.semver, None);
+        assert_eq!(errors.len(), 2);
+
+        assert_eq!(Directives::parse("{ }", None).0, Directives::default());
+    }
+
+    #[test]
+    fn test_directive_scope() {
+        let content = r#"{ mkDerivation }:
+# ekapkgs-update: semver=minor
+{
+  foo = mkDerivation {
+    pname = "foo"; # ekapkgs-update: skip broken
+    version = "1.0";
+  };
+  bar = mkDerivation {
+    pname = "bar";
+    version = "2.0";
+  }; # ekapkgs-update: source=github:foo/bar
+  baz = mkDerivation {
+    # ekapkgs-update: semver=sideways
+    pname = "baz";
+  };
+}"#;
+
+        // foo, defined at its version
+        let (foo, errors) = Directives::parse(content, Some(6));
+        assert_eq!(foo.skip.as_deref(), Some("broken"));
+        assert_eq!(foo.semver, Some(SemverStrategy::Minor));
+        assert_eq!(foo.source, None);
+        assert_eq!(errors.len(), 1);
+
+        let (bar, _) = Directives::parse(content, Some(10));
+        assert_eq!(bar.skip, None);
+        assert_eq!(bar.semver, Some(SemverStrategy::Minor));
+        assert_eq!(bar.source.as_deref(), Some("https://github.com/foo/bar"));
+
+        let (unknown, _) = Directives::parse(content, None);
+        assert_eq!(
+            unknown,
+            Directives {
+                semver: Some(SemverStrategy::Minor),
+                ..Directives::default()
+            }
+        );
+    }
+}
@@ -1,99 +1,864 @@
+//! Evaluating the metadata of packages
+//!
+//! [`PackageQuery`] evaluates the attributes of a package needed to update it: its version, source
+//! and hashes, along with the [`directives`] written in the file defining it.
+
+pub mod directives;
+
+use std::collections::{BTreeMap, HashMap};
+
 use anyhow::Result;
-use tracing::debug;
+use serde::Deserialize;
+use tracing::{debug, warn};
 
-use crate::nix::eval_nix_expr;
+use self::directives::Directives;
+use crate::nix::{eval_nix_expr, import_entry_point};
+use crate::plugin::SourcePlugin;
 
-// Data structure for package metadata
+/// Metadata of a package, as evaluated by [`PackageMetadata::from_attr_path`]
 #[derive(Debug)]
 pub struct PackageMetadata {
+    /// Current version of the package
     pub version: String,
+    /// `meta.position` of the package, as `file:line`
+    pub position: Option<String>,
+    /// URL the source of the package is fetched from
     pub src_url: Option<String>,
+    /// Hash of the source of the package
     pub output_hash: Option<String>,
-    pub cargo_hash: Option<String>,
-    pub vendor_hash: Option<String>,
+    /// Dependency hashes to refresh along with the source hash
+    pub dependency_hashes: Vec<DependencyHash>,
+    /// Sources of multi-platform packages, one per system
+    pub platform_sources: Vec<PlatformSource>,
+    /// `pname` of the package, if it has one
     pub pname: Option<String>,
+    /// `meta.description` of the package
     pub description: Option<String>,
+    /// `meta.homepage` of the package
     pub homepage: Option<String>,
+    /// `meta.changelog` of the package
     pub changelog: Option<String>,
+    /// GitHub handles of the package's maintainers
+    pub maintainers: Vec<String>,
+    /// Version of the Python interpreter of Python packages, e.g. `3.12.4`
+    pub python_version: Option<String>,
+    /// Update policy written in the file defining the package
+    pub directives: Directives,
+    /// Command listing the versions of the package, from `passthru.updateInfo.command`
+    pub source_plugin: Option<SourcePlugin>,
+    /// Vulnerabilities the package is marked with
+    pub insecure: InsecureMarkings,
+}
+
+/// Vulnerabilities a package is marked with in its `meta`
+///
+/// Packages marked insecure fail to evaluate unless the tree permits insecure packages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct InsecureMarkings {
+    /// Entries of `meta.knownVulnerabilities`, usually CVE identifiers or a description
+    pub known_vulnerabilities: Vec<String>,
+    /// `meta.insecure`, also set by nixpkgs for packages with known vulnerabilities
+    pub insecure: bool,
+}
+
+impl InsecureMarkings {
+    /// Whether the package is marked insecure or with known vulnerabilities
+    pub fn is_marked(&self) -> bool {
+        self.insecure || !self.known_vulnerabilities.is_empty()
+    }
+
+    /// Markdown section comparing the markings of the current version with those of the new
+    /// version `new`, None if neither version is marked
+    pub fn report(&self, new: &InsecureMarkings) -> Option<String> {
+        let list = |markings: &InsecureMarkings| {
+            markings
+                .known_vulnerabilities
+                .iter()
+                .map(|vulnerability| format!("\n- {}", vulnerability))
+                .collect::<String>()
+        };
+        match (self.is_marked(), new.is_marked()) {
+            (false, false) => None,
+            (true, false) => Some(format!(
+                "## Known vulnerabilities\n\nThe current version is marked insecure, the new \
+                 version no longer is:\n{}",
+                list(self)
+            )),
+            (false, true) => Some(format!(
+                "## Known vulnerabilities\n\n⚠️ The new version is marked insecure:\n{}",
+                list(new)
+            )),
+            (true, true) if self == new => Some(format!(
+                "## Known vulnerabilities\n\nBoth versions are marked insecure with the same \
+                 `meta.knownVulnerabilities`. Drop the entries fixed by the update:\n{}",
+                list(self)
+            )),
+            (true, true) => Some(format!(
+                "## Known vulnerabilities\n\nThe current version is marked with:\n{}\n\nThe new \
+                 version is marked with:\n{}",
+                list(self),
+                list(new)
+            )),
+        }
+    }
+}
+
+/// A dependency fixed-output derivation hash which must be refreshed after a version bump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyHashAttr {
+    /// Attribute (relative to the package) which evaluates to the current hash.
+    /// E.g. "cargoHash" or "pnpmDeps.outputHash"
+    pub eval_attr: String,
+
+    /// Attribute names which may hold the hash literal in the Nix file
+    pub rewrite_attrs: Vec<String>,
+}
+
+impl DependencyHashAttr {
+    /// Dependency hash evaluated as `eval_attr` and rewritten through `rewrite_attrs`
+    pub fn new(eval_attr: &str, rewrite_attrs: &[&str]) -> Self {
+        Self {
+            eval_attr: eval_attr.to_string(),
+            rewrite_attrs: rewrite_attrs.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    /// Dependency hash attributes of the common language builders
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            // Rust
+            Self::new("cargoHash", &["cargoHash"]),
+            // Go
+            Self::new("vendorHash", &["vendorHash"]),
+            // PHP
+            Self::new("composerVendorHash", &["composerVendorHash", "vendorHash"]),
+            // JavaScript
+            Self::new("pnpmDeps.outputHash", &["hash", "sha256"]),
+            Self::new("yarnOfflineCache.outputHash", &["hash", "sha256"]),
+            Self::new("offlineCache.outputHash", &["hash", "sha256"]),
+            // Elixir
+            Self::new("mixFodDeps.outputHash", &["hash", "sha256"]),
+            // Ruby
+            Self::new("gemHash", &["gemHash"]),
+            // Dart: the pub cache FOD built from pubspecLock
+            Self::new("pubcache.outputHash", &["hash", "sha256"]),
+            // Java: buildMavenPackage exposes the mvnHash FOD as fetchedMavenDeps
+            Self::new("fetchedMavenDeps.outputHash", &["mvnHash"]),
+        ]
+    }
+
+    /// Parse a dependency hash attribute from the command line
+    ///
+    /// Accepts either `evalAttr` or `evalAttr=rewriteAttr,otherRewriteAttr`. Without explicit
+    /// rewrite attributes, FOD attributes (`foo.outputHash`) are rewritten via `hash`/`sha256`
+    /// and plain attributes (`fooHash`) via their own name.
+    ///
+    /// # Example
+    /// ```
+    /// use ekapkgs_update::package::DependencyHashAttr;
+    ///
+    /// let attr = DependencyHashAttr::parse("mixFodDeps.outputHash").unwrap();
+    /// assert_eq!(attr.rewrite_attrs, vec!["hash", "sha256"]);
+    /// ```
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let (eval_attr, rewrite_attrs) = match spec.split_once('=') {
+            Some((eval_attr, rewrite_attrs)) => (
+                eval_attr.trim(),
+                rewrite_attrs
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|a| !a.is_empty())
+                    .collect::<Vec<_>>(),
+            ),
+            None => (spec.trim(), Vec::new()),
+        };
+
+        if eval_attr.is_empty() {
+            anyhow::bail!("Invalid dependency hash attribute: '{}'", spec);
+        }
+
+        if !rewrite_attrs.is_empty() {
+            return Ok(Self::new(eval_attr, &rewrite_attrs));
+        }
+
+        match eval_attr.rsplit_once('.') {
+            Some((_, "outputHash")) => Ok(Self::new(eval_attr, &["hash", "sha256"])),
+            Some((_, last)) => Ok(Self::new(eval_attr, &[last])),
+            None => Ok(Self::new(eval_attr, &[eval_attr])),
+        }
+    }
 }
 
+/// A dependency hash found on a package along with its current value
+#[derive(Debug, Clone)]
+pub struct DependencyHash {
+    /// Attribute the hash was found in
+    pub attr: DependencyHashAttr,
+    /// Current value of the hash
+    pub hash: String,
+}
+
+/// Attributes which may hold an attrset of per-platform sources keyed by system
+const PLATFORM_SOURCES_ATTRS: &[&str] = &["passthru.sources", "passthru.srcs", "srcs"];
+
+/// A source fetched for one platform of a multi-platform package
+///
+/// E.g. `x86_64-linux` of `passthru.sources = { x86_64-linux = fetchurl { ... }; ... }`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlatformSource {
+    /// System the source is fetched for, e.g. `x86_64-linux`
+    pub system: String,
+    /// Attribute path relative to the package which builds this source
+    pub attr: String,
+    /// Current hash of the source
+    pub hash: String,
+}
+
+/// Parse the `<system> <hash>` lines produced by [`PackageQuery::get_platform_sources`]
+fn parse_platform_sources(sources_attr: &str, output: &str) -> Vec<PlatformSource> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (system, hash) = line.trim().split_once(' ')?;
+            let hash = hash.trim();
+            if system.is_empty() || hash.is_empty() {
+                return None;
+            }
+            Some(PlatformSource {
+                system: system.to_string(),
+                attr: format!("{}.{}", sources_attr, system),
+                hash: hash.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Sources of `sources_attr` from their hashes by system, skipping those without a hash
+fn platform_sources_of(
+    sources_attr: &str,
+    hashes: BTreeMap<String, String>,
+) -> Vec<PlatformSource> {
+    hashes
+        .into_iter()
+        .filter(|(_, hash)| !hash.is_empty())
+        .map(|(system, hash)| PlatformSource {
+            attr: format!("{}.{}", sources_attr, system),
+            system,
+            hash,
+        })
+        .collect()
+}
+
+/// Record a dependency hash, unless another attribute already exposes the same FOD
+fn add_dependency_hash(
+    dependency_hashes: &mut Vec<DependencyHash>,
+    attr: &DependencyHashAttr,
+    hash: String,
+) {
+    // Several attributes may expose the same FOD (e.g. vendorHash and composerVendorHash), only
+    // refresh it once
+    if hash.is_empty() || dependency_hashes.iter().any(|d| d.hash == hash) {
+        return;
+    }
+    dependency_hashes.push(DependencyHash {
+        attr: attr.clone(),
+        hash,
+    });
+}
+
+/// Evaluates the attributes of a package of the tree of an entry point
 pub struct PackageQuery {
-    eval_entry_point: String,
+    /// Expression importing the entry point
+    package_set: String,
     attr_path: String,
 }
 
 impl PackageQuery {
+    /// Query the package at `attr_path` of the tree of `eval_entry_point`
     pub fn new(eval_entry_point: &str, attr_path: &str) -> Self {
-        // Normalize the entry point to a valid Nix filepath
-        let eval_path = if eval_entry_point.starts_with('/') || eval_entry_point.starts_with('.') {
-            eval_entry_point.to_string()
-        } else {
-            format!("./{}", eval_entry_point)
-        };
-
         Self {
-            eval_entry_point: eval_path,
+            package_set: import_entry_point(eval_entry_point),
             attr_path: attr_path.to_string(),
         }
     }
 
+    /// Evaluate an attribute of the package, None if it fails to evaluate
     pub async fn get_attr(&self, attr: &str) -> Option<String> {
-        let expr = format!(
-            "with import {} {{ }}; {}.{}",
-            self.eval_entry_point, self.attr_path, attr
-        );
+        let expr = format!("with {}; {}.{}", self.package_set, self.attr_path, attr);
 
         eval_nix_expr(&expr).await.ok()
     }
 
+    /// Evaluate the version of the package, from `version` or else its `name`
     pub async fn get_version(&self) -> Result<String> {
         // Try to get version directly
         let expr = format!(
-            "with import {} {{ }}; {}.version or (builtins.parseDrvName {}.name).version",
-            self.eval_entry_point, self.attr_path, self.attr_path
+            "with {}; {}.version or (builtins.parseDrvName {}.name).version",
+            self.package_set, self.attr_path, self.attr_path
         );
 
         let res = eval_nix_expr(&expr).await?;
         Ok(res)
     }
 
+    /// Evaluate the URL, or URLs separated by spaces, of the source of the package
     pub async fn get_src_url(&self) -> Option<String> {
         // Try to get source URL
         let url_expr = format!(
-            "with import {} {{ }}; builtins.toString ({}.src.url or {}.src.urls)",
-            self.eval_entry_point, self.attr_path, self.attr_path
+            "with {}; builtins.toString ({}.src.url or {}.src.urls)",
+            self.package_set, self.attr_path, self.attr_path
         );
 
         eval_nix_expr(&url_expr).await.ok()
     }
+
+    /// Names of the Python packages a Python package depends on at runtime
+    ///
+    /// Taken from `dependencies`, or `propagatedBuildInputs` for older expressions, keeping only
+    /// Python modules.
+    pub async fn get_python_dependencies(&self) -> Result<Vec<String>> {
+        let expr = format!(
+            "with {}; builtins.concatStringsSep \"\\n\" (map (dep: dep.pname) (builtins.filter \
+             (dep: dep != null && dep ? pythonModule && dep ? pname) ({}.dependencies or \
+             {}.propagatedBuildInputs or [ ])))",
+            self.package_set, self.attr_path, self.attr_path
+        );
+
+        let output = eval_nix_expr(&expr).await?;
+        Ok(output.lines().map(str::to_string).collect())
+    }
+
+    /// GitHub handles of the maintainers listed in `meta.maintainers`
+    pub async fn get_maintainer_handles(&self) -> Vec<String> {
+        let expr = format!(
+            "with {}; builtins.concatStringsSep \"\\n\" (builtins.filter (handle: handle != null) \
+             (map (maintainer: maintainer.github or null) ({}.meta.maintainers or [ ])))",
+            self.package_set, self.attr_path
+        );
+
+        match eval_nix_expr(&expr).await {
+            Ok(output) => output.lines().map(str::to_string).collect(),
+            Err(e) => {
+                debug!("{}: Failed to evaluate maintainers: {}", self.attr_path, e);
+                Vec::new()
+            },
+        }
+    }
+
+    /// Directives of the file defining the package, from its `meta.position`
+    pub async fn get_directives(&self) -> Directives {
+        self.directives_at(self.get_attr("meta.position").await.as_deref())
+            .await
+    }
+
+    /// Directives of the file at `position`, the already evaluated `meta.position` of the package
+    async fn directives_at(&self, position: Option<&str>) -> Directives {
+        let Some(position) = position else {
+            return Directives::default();
+        };
+        let (file, line) = match position.rsplit_once(':') {
+            Some((file, line)) => (file, line.parse().ok()),
+            None => (position, None),
+        };
+        let Ok(content) = tokio::fs::read_to_string(file).await else {
+            return Directives::default();
+        };
+        let (directives, errors) = Directives::parse(&content, line);
+        for error in errors {
+            warn!("{}: {} in {}", self.attr_path, error, file);
+        }
+        directives
+    }
+
+    /// Command listing the versions of the package, from `passthru.updateInfo.command`
+    pub async fn get_source_plugin(&self) -> Option<SourcePlugin> {
+        let command_expr = format!(
+            "({}.passthru.updateInfo or {}.updateInfo).command",
+            self.attr_path, self.attr_path
+        );
+        let expr = format!(
+            "with {}; let cmd = {}; in builtins.toJSON (map toString (if builtins.isList cmd then \
+             cmd else [ cmd ]))",
+            self.package_set, command_expr
+        );
+        let json = eval_nix_expr(&expr).await.ok()?;
+        let command: Vec<String> = match serde_json::from_str(&json) {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("{}: Invalid updateInfo.command: {}", self.attr_path, e);
+                return None;
+            },
+        };
+        self.source_plugin(command)
+    }
+
+    /// Source plugin running `command`, the evaluated `updateInfo.command` of the package
+    fn source_plugin(&self, command: Vec<String>) -> Option<SourcePlugin> {
+        if command.first().is_none_or(|program| program.is_empty()) {
+            return None;
+        }
+
+        let command_expr = format!(
+            "({}.passthru.updateInfo or {}.updateInfo).command",
+            self.attr_path, self.attr_path
+        );
+        Some(SourcePlugin {
+            command,
+            derivations: Some(format!(
+                "with {}; let cmd = {}; in builtins.filter builtins.isAttrs (if builtins.isList \
+                 cmd then cmd else [ cmd ])",
+                self.package_set, command_expr
+            )),
+        })
+    }
+
+    /// Vulnerabilities the package is marked with in `meta.knownVulnerabilities` and
+    /// `meta.insecure`
+    ///
+    /// Evaluating `meta` doesn't check whether insecure packages are permitted, so this works for
+    /// packages which fail to evaluate because of their markings.
+    pub async fn get_insecure_markings(&self) -> InsecureMarkings {
+        let expr = format!(
+            "with {}; builtins.toJSON {{ known_vulnerabilities = {}.meta.knownVulnerabilities or \
+             [ ]; insecure = {}.meta.insecure or false; }}",
+            self.package_set, self.attr_path, self.attr_path
+        );
+
+        let markings = eval_nix_expr(&expr)
+            .await
+            .and_then(|json| Ok(serde_json::from_str(&json)?));
+        match markings {
+            Ok(markings) => markings,
+            Err(e) => {
+                debug!(
+                    "{}: Failed to evaluate knownVulnerabilities: {}",
+                    self.attr_path, e
+                );
+                InsecureMarkings::default()
+            },
+        }
+    }
+
+    /// Enumerate per-platform sources of packages fetching a different `src` per system
+    ///
+    /// Returns an empty list if the package doesn't define an attrset of sources.
+    pub async fn get_platform_sources(&self) -> Vec<PlatformSource> {
+        for sources_attr in PLATFORM_SOURCES_ATTRS {
+            // `srcs` may also be the plain list of sources used by mkDerivation, only consider
+            // attrsets which aren't a derivation themselves
+            let expr = format!(
+                "with {}; let sources = {}.{}; in if builtins.isAttrs sources && !(sources ? \
+                 outPath) then builtins.concatStringsSep \"\\n\" (map (system: system + \" \" + \
+                 (sources.${{system}}.outputHash or \"\")) (builtins.attrNames sources)) else \"\"",
+                self.package_set, self.attr_path, sources_attr
+            );
+
+            let Ok(output) = eval_nix_expr(&expr).await else {
+                continue;
+            };
+
+            let sources = parse_platform_sources(sources_attr, &output);
+            if !sources.is_empty() {
+                debug!(
+                    "{}: Found {} platform sources in {}",
+                    self.attr_path,
+                    sources.len(),
+                    sources_attr
+                );
+                return sources;
+            }
+        }
+
+        Vec::new()
+    }
+}
+
+/// Attributes of a package evaluated by [`PackageMetadata::from_attr_paths`], None if they failed
+/// to evaluate
+#[derive(Debug, Default, Deserialize)]
+struct EvaluatedAttrs {
+    version: Option<String>,
+    position: Option<String>,
+    src_url: Option<String>,
+    output_hash: Option<String>,
+    /// By `eval_attr` of the dependency hash attributes
+    #[serde(default)]
+    dependency_hashes: HashMap<String, Option<String>>,
+    /// Hashes by system, by attribute of [`PLATFORM_SOURCES_ATTRS`]
+    #[serde(default)]
+    platform_sources: HashMap<String, Option<BTreeMap<String, String>>>,
+    pname: Option<String>,
+    description: Option<String>,
+    homepage: Option<String>,
+    changelog: Option<String>,
+    maintainers: Option<Vec<String>>,
+    python_version: Option<String>,
+    update_command: Option<Vec<String>>,
+    known_vulnerabilities: Option<Vec<String>>,
+    insecure: Option<bool>,
+}
+
+/// Nix expression evaluating the attributes of several packages to JSON, see [`EvaluatedAttrs`]
+///
+/// Each attribute is evaluated on its own, so one which fails doesn't fail the others.
+fn metadata_expr(
+    eval_entry_point: &str,
+    attr_paths: &[String],
+    dependency_hash_attrs: &[DependencyHashAttr],
+) -> String {
+    let dependency_hashes: Vec<String> = dependency_hash_attrs
+        .iter()
+        .map(|attr| format!("{:?} = str (p.{} or null);", attr.eval_attr, attr.eval_attr))
+        .collect();
+    let platform_sources: Vec<String> = PLATFORM_SOURCES_ATTRS
+        .iter()
+        .map(|sources_attr| {
+            format!(
+                "{:?} = get (let sources = p.{} or null; in if builtins.isAttrs sources && \
+                 !(sources ? outPath) then builtins.mapAttrs (system: source: source.outputHash \
+                 or \"\") sources else null);",
+                sources_attr, sources_attr
+            )
+        })
+        .collect();
+    let packages: Vec<String> = attr_paths
+        .iter()
+        .map(|attr_path| {
+            format!(
+                "{:?} = let p = pkgs.{} or null; in {{ version = str (p.version or \
+                 (builtins.parseDrvName (p.name or \"\")).version); position = str \
+                 (p.meta.position or null); src_url = str (builtins.toString (p.src.url or \
+                 p.src.urls or null)); output_hash = str (p.src.outputHash or null); \
+                 dependency_hashes = {{ {} }}; platform_sources = {{ {} }}; pname = str (p.pname \
+                 or null); description = str (p.meta.description or null); homepage = str \
+                 (p.meta.homepage or null); changelog = str (p.meta.changelog or null); \
+                 maintainers = get (builtins.filter (handle: handle != null) (map (maintainer: \
+                 maintainer.github or null) (p.meta.maintainers or [ ]))); python_version = str \
+                 (p.pythonModule.version or null); update_command = get (let cmd = \
+                 (p.passthru.updateInfo or p.updateInfo or {{ }}).command or null; in if cmd == \
+                 null then null else map toString (if builtins.isList cmd then cmd else [ cmd \
+                 ])); known_vulnerabilities = get (p.meta.knownVulnerabilities or [ ]); insecure \
+                 = get (p.meta.insecure or false); }};",
+                attr_path,
+                attr_path,
+                dependency_hashes.join(" "),
+                platform_sources.join(" ")
+            )
+        })
+        .collect();
+    format!(
+        "let pkgs = {}; get = value: let result = builtins.tryEval (builtins.deepSeq value \
+         value); in if result.success then result.value else null; str = value: let result = get \
+         value; in if builtins.isString result && result != \"\" then result else null; in \
+         builtins.toJSON {{ {} }}",
+        import_entry_point(eval_entry_point),
+        packages.join(" ")
+    )
 }
 
 impl PackageMetadata {
     /// Extract package metadata from Nix evaluation
     pub async fn from_attr_path(eval_entry_point: &str, attr_path: &str) -> anyhow::Result<Self> {
+        Self::from_attr_path_with_hashes(
+            eval_entry_point,
+            attr_path,
+            &DependencyHashAttr::defaults(),
+        )
+        .await
+    }
+
+    /// Extract package metadata, looking up the given dependency hash attributes
+    pub async fn from_attr_path_with_hashes(
+        eval_entry_point: &str,
+        attr_path: &str,
+        dependency_hash_attrs: &[DependencyHashAttr],
+    ) -> anyhow::Result<Self> {
         debug!("Extracting metadata for {}", attr_path);
         let package = PackageQuery::new(eval_entry_point, attr_path);
 
         let version = package.get_version().await?;
         let src_url = package.get_src_url().await;
         let output_hash = package.get_attr("src.outputHash").await;
-        let cargo_hash = package.get_attr("cargoHash").await;
-        let vendor_hash = package.get_attr("vendorHash").await;
+
+        let mut dependency_hashes: Vec<DependencyHash> = Vec::new();
+        for attr in dependency_hash_attrs {
+            if let Some(hash) = package.get_attr(&attr.eval_attr).await {
+                add_dependency_hash(&mut dependency_hashes, attr, hash);
+            }
+        }
+        let platform_sources = package.get_platform_sources().await;
         let pname = package.get_attr("pname").await;
         let description = package.get_attr("meta.description").await;
         let homepage = package.get_attr("meta.homepage").await;
         let changelog = package.get_attr("meta.changelog").await;
+        let maintainers = package.get_maintainer_handles().await;
+        let python_version = package.get_attr("pythonModule.version").await;
+        let position = package.get_attr("meta.position").await;
+        let directives = package.directives_at(position.as_deref()).await;
+        let source_plugin = package.get_source_plugin().await;
+        let insecure = package.get_insecure_markings().await;
 
         Ok(PackageMetadata {
             version,
+            position,
             src_url,
             output_hash,
-            cargo_hash,
-            vendor_hash,
+            dependency_hashes,
+            platform_sources,
             pname,
             description,
             homepage,
             changelog,
+            maintainers,
+            python_version,
+            directives,
+            source_plugin,
+            insecure,
         })
     }
+
+    /// Extract the metadata of several packages with a single evaluation
+    ///
+    /// Packages which fail to evaluate are missing from the result, evaluating them one by one
+    /// with [`Self::from_attr_path_with_hashes`] reports why.
+    pub async fn from_attr_paths(
+        eval_entry_point: &str,
+        attr_paths: &[String],
+        dependency_hash_attrs: &[DependencyHashAttr],
+    ) -> HashMap<String, Self> {
+        let expr = metadata_expr(eval_entry_point, attr_paths, dependency_hash_attrs);
+        let evaluated: HashMap<String, EvaluatedAttrs> = match eval_nix_expr(&expr)
+            .await
+            .and_then(|json| Ok(serde_json::from_str(&json)?))
+        {
+            Ok(evaluated) => evaluated,
+            Err(e) => {
+                debug!("Failed to evaluate the packages together: {}", e);
+                return HashMap::new();
+            },
+        };
+
+        let mut packages = HashMap::new();
+        for (attr_path, mut attrs) in evaluated {
+            let Some(version) = attrs.version else {
+                continue;
+            };
+            let package = PackageQuery::new(eval_entry_point, &attr_path);
+
+            let mut dependency_hashes: Vec<DependencyHash> = Vec::new();
+            for attr in dependency_hash_attrs {
+                if let Some(Some(hash)) = attrs.dependency_hashes.remove(&attr.eval_attr) {
+                    add_dependency_hash(&mut dependency_hashes, attr, hash);
+                }
+            }
+            let platform_sources = PLATFORM_SOURCES_ATTRS
+                .iter()
+                .filter_map(|sources_attr| {
+                    let hashes = attrs.platform_sources.remove(*sources_attr).flatten()?;
+                    Some(platform_sources_of(sources_attr, hashes))
+                })
+                .find(|sources| !sources.is_empty())
+                .unwrap_or_default();
+            let directives = package.directives_at(attrs.position.as_deref()).await;
+            let source_plugin = attrs
+                .update_command
+                .and_then(|command| package.source_plugin(command));
+
+            let metadata = PackageMetadata {
+                version,
+                position: attrs.position,
+                src_url: attrs.src_url,
+                output_hash: attrs.output_hash,
+                dependency_hashes,
+                platform_sources,
+                pname: attrs.pname,
+                description: attrs.description,
+                homepage: attrs.homepage,
+                changelog: attrs.changelog,
+                maintainers: attrs.maintainers.unwrap_or_default(),
+                python_version: attrs.python_version,
+                directives,
+                source_plugin,
+                insecure: InsecureMarkings {
+                    known_vulnerabilities: attrs.known_vulnerabilities.unwrap_or_default(),
+                    insecure: attrs.insecure.unwrap_or_default(),
+                },
+            };
+            packages.insert(attr_path, metadata);
+        }
+        packages
+    }
+
+    /// File defining the package, from its `meta.position`
+    pub fn file(&self) -> Option<&str> {
+        self.position
+            .as_deref()
+            .and_then(|position| position.rsplit_once(':'))
+            .map(|(file, _line)| file)
+            .filter(|file| !file.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insecure_markings_report() {
+        let marked = InsecureMarkings {
+            known_vulnerabilities: vec!["CVE-2024-1234".to_string()],
+            insecure: true,
+        };
+        let unmarked = InsecureMarkings::default();
+        assert!(!unmarked.is_marked());
+        assert_eq!(unmarked.report(&unmarked), None);
+
+        let fixed = marked.report(&unmarked).unwrap();
+        assert!(fixed.contains("the new version no longer is"));
+        assert!(fixed.contains("\n- CVE-2024-1234"));
+        let still = marked.report(&marked).unwrap();
+        assert!(still.contains("Drop the entries fixed by the update"));
+        assert!(
+            unmarked
+                .report(&marked)
+                .unwrap()
+                .contains("The new version is marked insecure")
+        );
+
+        let markings: InsecureMarkings =
+            serde_json::from_str(r#"{"known_vulnerabilities": [], "insecure": true}"#).unwrap();
+        assert!(markings.is_marked());
+    }
+
+    #[test]
+    fn test_dependency_hash_attr_parse_plain() {
+        let attr = DependencyHashAttr::parse("cargoHash").unwrap();
+        assert_eq!(attr.eval_attr, "cargoHash");
+        assert_eq!(attr.rewrite_attrs, vec!["cargoHash"]);
+    }
+
+    #[test]
+    fn test_dependency_hash_attr_parse_output_hash() {
+        let attr = DependencyHashAttr::parse("mixFodDeps.outputHash").unwrap();
+        assert_eq!(attr.eval_attr, "mixFodDeps.outputHash");
+        assert_eq!(attr.rewrite_attrs, vec!["hash", "sha256"]);
+    }
+
+    #[test]
+    fn test_dependency_hash_attr_parse_nested() {
+        let attr = DependencyHashAttr::parse("passthru.npmDepsHash").unwrap();
+        assert_eq!(attr.rewrite_attrs, vec!["npmDepsHash"]);
+    }
+
+    #[test]
+    fn test_dependency_hash_attr_parse_explicit_rewrite_attrs() {
+        let attr = DependencyHashAttr::parse("goModules.outputHash=vendorHash, modHash").unwrap();
+        assert_eq!(attr.eval_attr, "goModules.outputHash");
+        assert_eq!(attr.rewrite_attrs, vec!["vendorHash", "modHash"]);
+    }
+
+    #[test]
+    fn test_dependency_hash_attr_parse_invalid() {
+        assert!(DependencyHashAttr::parse("").is_err());
+        assert!(DependencyHashAttr::parse("=hash").is_err());
+    }
+
+    #[test]
+    fn test_dependency_hash_attr_defaults_include_common_builders() {
+        let defaults = DependencyHashAttr::defaults();
+        for eval_attr in [
+            "cargoHash",
+            "vendorHash",
+            "mixFodDeps.outputHash",
+            "gemHash",
+        ] {
+            assert!(defaults.iter().any(|a| a.eval_attr == eval_attr));
+        }
+    }
+
+    #[test]
+    fn test_dependency_hash_attr_defaults_dart() {
+        let defaults = DependencyHashAttr::defaults();
+        let dart = defaults
+            .iter()
+            .find(|a| a.eval_attr == "pubcache.outputHash")
+            .expect("dart pub cache hash should be a default");
+        assert_eq!(dart.rewrite_attrs, vec!["hash", "sha256"]);
+    }
+
+    #[test]
+    fn test_dependency_hash_attr_defaults_maven() {
+        let defaults = DependencyHashAttr::defaults();
+        let maven = defaults
+            .iter()
+            .find(|a| a.eval_attr == "fetchedMavenDeps.outputHash")
+            .expect("maven dependency hash should be a default");
+        assert_eq!(maven.rewrite_attrs, vec!["mvnHash"]);
+    }
+
+    #[test]
+    fn test_parse_platform_sources() {
+        let output = "aarch64-darwin sha256-aaa=\nx86_64-linux sha256-bbb=\n";
+        let sources = parse_platform_sources("passthru.sources", output);
+        assert_eq!(
+            sources,
+            vec![
+                PlatformSource {
+                    system: "aarch64-darwin".to_string(),
+                    attr: "passthru.sources.aarch64-darwin".to_string(),
+                    hash: "sha256-aaa=".to_string(),
+                },
+                PlatformSource {
+                    system: "x86_64-linux".to_string(),
+                    attr: "passthru.sources.x86_64-linux".to_string(),
+                    hash: "sha256-bbb=".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_platform_sources_skips_missing_hashes() {
+        let output = "x86_64-linux \naarch64-linux sha256-ccc=";
+        let sources = parse_platform_sources("srcs", output);
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].system, "aarch64-linux");
+        assert_eq!(sources[0].attr, "srcs.aarch64-linux");
+    }
+
+    #[test]
+    fn test_parse_platform_sources_empty() {
+        assert!(parse_platform_sources("srcs", "").is_empty());
+    }
+
+    #[test]
+    fn test_metadata_expr() {
+        let expr = metadata_expr(
+            "default.nix",
+            &["python3Packages.requests".to_string()],
+            &[DependencyHashAttr::new("pnpmDeps.outputHash", &["hash"])],
+        );
+        assert!(expr.contains(
+            r#""python3Packages.requests" = let p = pkgs.python3Packages.requests or null; in"#
+        ));
+        assert!(expr.contains(r#"dependency_hashes = { "pnpmDeps.outputHash" = str (p.pnpmDeps.outputHash or null); }"#));
+        assert!(
+            expr.contains(r#""passthru.sources" = get (let sources = p.passthru.sources or null;"#)
+        );
+
+        let attrs: EvaluatedAttrs = serde_json::from_str(
+            r#"{"version": "2.32.3", "position": "/src/pkgs/requests/default.nix:12", "dependency_hashes": {"pnpmDeps.outputHash": null}, "platform_sources": {"srcs": {"aarch64-linux": "sha256-a", "x86_64-linux": ""}}, "maintainers": ["alice"], "update_command": null, "insecure": false}"#,
+        )
+        .unwrap();
+        assert_eq!(attrs.version.as_deref(), Some("2.32.3"));
+        assert_eq!(attrs.dependency_hashes["pnpmDeps.outputHash"], None);
+        assert_eq!(
+            platform_sources_of("srcs", attrs.platform_sources["srcs"].clone().unwrap()),
+            vec![PlatformSource {
+                system: "aarch64-linux".to_string(),
+                attr: "srcs.aarch64-linux".to_string(),
+                hash: "sha256-a".to_string(),
+            }]
+        );
+    }
 }
@@ -0,0 +1,366 @@
+//! Rebasing patches which no longer apply to a new source
+//!
+//! A patch failing to apply after an update often only needs its context refreshed. The patch is
+//! applied to a scratch copy of the new source with `git apply -3`, falling back to
+//! `patch --fuzz`, and regenerated from the result. Patches which still conflict are left for a
+//! human to fix.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Context;
+use regex::Regex;
+use tokio::process::Command;
+use tracing::{debug, info};
+
+/// Fuzz factor allowed when falling back to `patch`
+const PATCH_FUZZ: &str = "3";
+
+/// How a patch failing to apply to the new source was brought up to date
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchRebase {
+    /// Fetched patch whose hash was refreshed, as upstream changed it
+    RefreshedHash,
+    /// Local patch regenerated with a 3-way merge
+    ThreeWayMerge,
+    /// Local patch regenerated by applying it with fuzz, which may have misplaced hunks
+    Fuzz,
+}
+
+/// Markdown section listing the patches rebased by an update, for PR bodies
+pub fn format_rebase_report(patches: &[(String, PatchRebase)]) -> Option<String> {
+    if patches.is_empty() {
+        return None;
+    }
+
+    let mut report = String::from(
+        "## Rebased Patches\n\nThese patches no longer applied to the new version, please review \
+         them:\n",
+    );
+    for (patch_name, rebase) in patches {
+        let how = match rebase {
+            PatchRebase::RefreshedHash => "hash refreshed, upstream changed the patch",
+            PatchRebase::ThreeWayMerge => "rebased with a 3-way merge",
+            PatchRebase::Fuzz => "⚠️ applied with fuzz, check where its hunks landed",
+        };
+        report.push_str(&format!("\n- `{}`: {}", patch_name, how));
+    }
+    Some(report)
+}
+
+/// Detect a patch which failed to apply and extract its filename
+///
+/// Looks for rejected hunks or missing files in the output of the patch phase and extracts the
+/// patch name from the preceding "applying patch" line.
+pub fn detect_failed_patch(stderr: &str) -> Option<String> {
+    static APPLYING: OnceLock<Regex> = OnceLock::new();
+    let applying =
+        APPLYING.get_or_init(|| Regex::new(r"applying patch /nix/store/[^-]+-(.+)").unwrap());
+
+    let lines: Vec<&str> = stderr.lines().collect();
+    let failure = lines.iter().rposition(|line| {
+        line.contains("FAILED -- saving rejects")
+            || line.contains("can't find file to patch")
+            || line.contains("No file to patch")
+    })?;
+    lines[..failure].iter().rev().find_map(|line| {
+        applying
+            .captures(line)
+            .map(|caps| caps[1].trim().to_string())
+    })
+}
+
+/// Detect a fetched file whose content doesn't match its hash, e.g. a patch changed upstream
+///
+/// Returns the name of the fixed-output derivation and the hash reported by Nix.
+pub fn detect_hash_mismatch(stderr: &str) -> Option<(String, String)> {
+    static MISMATCH: OnceLock<Regex> = OnceLock::new();
+    let mismatch = MISMATCH.get_or_init(|| {
+        Regex::new(
+            r"hash mismatch in fixed-output derivation '/nix/store/[^-]+-([^']+)\.drv'[\s\S]*?got:\s+(sha256-[A-Za-z0-9+/=]+)",
+        )
+        .unwrap()
+    });
+    let caps = mismatch.captures(stderr)?;
+    Some((caps[1].to_string(), caps[2].to_string()))
+}
+
+/// Path of a patch referenced by a Nix file, e.g. `./patches/fix-build.patch` for
+/// `fix-build.patch`
+pub fn local_patch_path(nix_file: &Path, content: &str, patch_name: &str) -> Option<PathBuf> {
+    let pattern = format!(
+        r"(?:^|[\s\[(])(\.\.?/(?:[A-Za-z0-9._+\-]+/)*{})(?:[\s\])]|$)",
+        regex::escape(patch_name)
+    );
+    let reference = Regex::new(&pattern).ok()?.captures(content)?;
+    let dir = nix_file.parent().unwrap_or(Path::new("."));
+    Some(dir.join(&reference[1]))
+}
+
+/// Text preceding the first diff of a patch, e.g. the commit message of `git format-patch`
+fn patch_header(patch: &str) -> &str {
+    let mut offset = 0;
+    for line in patch.split_inclusive('\n') {
+        if line.starts_with("diff ") || line.starts_with("--- ") || line.starts_with("Index: ") {
+            break;
+        }
+        offset += line.len();
+    }
+    &patch[..offset]
+}
+
+/// Run a command in `dir`, returning whether it succeeded
+async fn run_in(dir: &Path, program: &str, args: &[&str]) -> anyhow::Result<bool> {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .process_group(0)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        debug!(
+            "{} {} failed: {}{}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(output.status.success())
+}
+
+/// Copy or unpack a source into `dir` and return the root of the source tree
+pub async fn unpack_source(src: &Path, dir: &Path) -> anyhow::Result<PathBuf> {
+    let unpacked = if src.is_dir() {
+        run_in(
+            dir,
+            "cp",
+            &["-r", "--no-preserve=mode", &src.to_string_lossy(), "source"],
+        )
+        .await?
+    } else {
+        tokio::fs::create_dir(dir.join("source")).await?;
+        run_in(dir, "tar", &["-xf", &src.to_string_lossy(), "-C", "source"]).await?
+    };
+    if !unpacked {
+        anyhow::bail!("Failed to unpack {}", src.display());
+    }
+
+    // Archives usually hold a single top-level directory, which the patch paths are relative to
+    let root = dir.join("source");
+    let mut entries = tokio::fs::read_dir(&root).await?;
+    let mut only_dir = None;
+    while let Some(entry) = entries.next_entry().await? {
+        if only_dir.is_some() || !entry.file_type().await?.is_dir() {
+            return Ok(root);
+        }
+        only_dir = Some(entry.path());
+    }
+    Ok(only_dir.unwrap_or(root))
+}
+
+/// Arguments of a git command in the scratch repository, independent of the user configuration
+fn git<'a>(args: &[&'a str]) -> Vec<&'a str> {
+    const CONFIG: &[&str] = &[
+        "-c",
+        "user.name=ekapkgs-update",
+        "-c",
+        "user.email=ekapkgs-update@localhost",
+        "-c",
+        "commit.gpgsign=false",
+    ];
+    [CONFIG, args].concat()
+}
+
+/// Apply `patch` to the source in `root`, returning the regenerated diff and how it applied
+async fn reapply(root: &Path, patch: &Path) -> anyhow::Result<(String, PatchRebase)> {
+    let patch = patch.to_string_lossy();
+
+    for args in [
+        git(&["init", "-q"]),
+        git(&["add", "-A", "-f"]),
+        git(&["commit", "-q", "--no-verify", "-m", "source"]),
+    ] {
+        if !run_in(root, "git", &args).await? {
+            anyhow::bail!("Failed to create a scratch repository for the source");
+        }
+    }
+
+    let rebase = if run_in(root, "git", &git(&["apply", "-3", &patch])).await? {
+        info!("Rebased patch with a 3-way merge");
+        PatchRebase::ThreeWayMerge
+    } else {
+        run_in(root, "git", &git(&["reset", "-q", "--hard"])).await?;
+        run_in(root, "git", &git(&["clean", "-q", "-f", "-d"])).await?;
+        let applied = run_in(
+            root,
+            "patch",
+            &[
+                "-p1",
+                "--batch",
+                "--forward",
+                "--no-backup-if-mismatch",
+                "--fuzz",
+                PATCH_FUZZ,
+                "-i",
+                &patch,
+            ],
+        )
+        .await?;
+        if !applied {
+            anyhow::bail!("Patch doesn't apply to the new source, even with fuzz");
+        }
+        info!("Rebased patch with fuzz");
+        PatchRebase::Fuzz
+    };
+
+    run_in(root, "git", &git(&["add", "-A", "-f"])).await?;
+    let output = Command::new("git")
+        .args(git(&["diff", "--cached", "--binary", "--no-color"]))
+        .current_dir(root)
+        .output()
+        .await
+        .context("Failed to run git diff")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok((String::from_utf8_lossy(&output.stdout).to_string(), rebase))
+}
+
+/// Regenerate a local patch file against the new source
+///
+/// `src` is the store path of the new source, either a directory or an archive. The patch is
+/// applied to the pristine source, so patches depending on earlier patches of the list can't be
+/// rebased. The header of the patch, e.g. its commit message, is kept. Returns how the patch
+/// applied.
+pub async fn rebase_patch(src: &Path, patch: &Path) -> anyhow::Result<PatchRebase> {
+    let original = tokio::fs::read_to_string(patch)
+        .await
+        .with_context(|| format!("Failed to read {}", patch.display()))?;
+    let patch = tokio::fs::canonicalize(patch).await?;
+
+    // Packages are updated concurrently, each rebase gets its own directory
+    static REBASES: AtomicUsize = AtomicUsize::new(0);
+    let scratch = std::env::temp_dir().join(format!(
+        "ekapkgs-update-{}-rebase-patch-{}",
+        std::process::id(),
+        REBASES.fetch_add(1, Ordering::Relaxed)
+    ));
+    if scratch.exists() {
+        tokio::fs::remove_dir_all(&scratch).await?;
+    }
+    tokio::fs::create_dir_all(&scratch).await?;
+
+    let diff = match unpack_source(src, &scratch).await {
+        Ok(root) => reapply(&root, &patch).await,
+        Err(e) => Err(e),
+    };
+    tokio::fs::remove_dir_all(&scratch).await.ok();
+    let (diff, rebase) = diff?;
+    if diff.trim().is_empty() {
+        anyhow::bail!("Patch changes nothing in the new source");
+    }
+
+    tokio::fs::write(&patch, format!("{}{}", patch_header(&original), diff))
+        .await
+        .with_context(|| format!("Failed to write {}", patch.display()))?;
+    Ok(rebase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_failed_patch() {
+        let stderr = r#"Running phase: patchPhase
+applying patch /nix/store/abc123-fix-build.patch
+patching file src/main.c
+applying patch /nix/store/xyz789-musl.patch
+patching file src/util.c
+Hunk #1 FAILED at 12.
+1 out of 1 hunk FAILED -- saving rejects to file src/util.c.rej
+error: builder for '/nix/store/def-hello-2.12.drv' failed with exit code 1"#;
+        assert_eq!(detect_failed_patch(stderr).as_deref(), Some("musl.patch"));
+
+        let stderr = "applying patch /nix/store/abc123-fix-build.patch\ncan't find file to patch \
+                      at input line 5";
+        assert_eq!(
+            detect_failed_patch(stderr).as_deref(),
+            Some("fix-build.patch")
+        );
+
+        let stderr = "applying patch /nix/store/abc123-fix-build.patch\nerror: build failed";
+        assert_eq!(detect_failed_patch(stderr), None);
+    }
+
+    #[test]
+    fn test_local_patch_path() {
+        let content = r#"{
+  patches = [
+    ./patches/fix-build.patch
+    ./musl.patch
+    (fetchpatch { url = "https://example.org/other-musl.patch"; })
+  ];
+}"#;
+        let nix_file = Path::new("pkgs/hello/default.nix");
+        assert_eq!(
+            local_patch_path(nix_file, content, "fix-build.patch"),
+            Some(PathBuf::from("pkgs/hello/./patches/fix-build.patch"))
+        );
+        assert_eq!(
+            local_patch_path(nix_file, content, "musl.patch"),
+            Some(PathBuf::from("pkgs/hello/./musl.patch"))
+        );
+        assert_eq!(local_patch_path(nix_file, content, "other.patch"), None);
+    }
+
+    #[test]
+    fn test_format_rebase_report() {
+        assert_eq!(format_rebase_report(&[]), None);
+        let report = format_rebase_report(&[
+            ("fix-build.patch".to_string(), PatchRebase::ThreeWayMerge),
+            ("CVE-2024-1234.patch".to_string(), PatchRebase::Fuzz),
+        ])
+        .unwrap();
+        assert!(report.starts_with("## Rebased Patches"));
+        assert!(report.contains("\n- `fix-build.patch`: rebased with a 3-way merge"));
+        assert!(report.contains("\n- `CVE-2024-1234.patch`: ⚠️ applied with fuzz"));
+    }
+
+    #[test]
+    fn test_patch_header() {
+        let patch = "From 1234 Mon Sep 17 00:00:00 2001\nSubject: Fix build\n\n---\n src/main.c | \
+                     2 +-\n\ndiff --git a/src/main.c b/src/main.c\n--- a/src/main.c\n";
+        assert_eq!(
+            patch_header(patch),
+            "From 1234 Mon Sep 17 00:00:00 2001\nSubject: Fix build\n\n---\n src/main.c | 2 +-\n\n"
+        );
+        assert_eq!(patch_header("--- a/x\n+++ b/x\n"), "");
+    }
+
+    #[test]
+    fn test_detect_hash_mismatch() {
+        let stderr = r#"building '/nix/store/abc-fix-musl.patch.drv'...
+error: hash mismatch in fixed-output derivation '/nix/store/abc-fix-musl.patch.drv':
+         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-2lWOG1FI6b6jBoXJ6xYm5DBN3hj8hdWyHTSpd9z5nxg=
+error: 1 dependencies of derivation '/nix/store/def-hello-2.12.drv' failed to build"#;
+        assert_eq!(
+            detect_hash_mismatch(stderr),
+            Some((
+                "fix-musl.patch".to_string(),
+                "sha256-2lWOG1FI6b6jBoXJ6xYm5DBN3hj8hdWyHTSpd9z5nxg=".to_string()
+            ))
+        );
+        assert_eq!(detect_hash_mismatch("error: build failed"), None);
+    }
+}
@@ -0,0 +1,76 @@
+//! Update plans, reviewed between discovering updates and carrying them out
+//!
+//! `run --dry-run --plan-out plan.json` writes the update found for every package, and
+//! `run --apply plan.json` later updates exactly these packages to exactly these versions, even
+//! if newer releases were published in the meantime.
+
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Update of a single package
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlannedUpdate {
+    pub attr_path: String,
+    pub current_version: String,
+    pub target_version: String,
+    /// Upstream the target version was found at, e.g. `GitHub repo: owner/repo`
+    pub source: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub updates: Vec<PlannedUpdate>,
+}
+
+impl Plan {
+    /// Read a plan file
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read plan {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse plan {}", path.display()))
+    }
+
+    /// Write the plan as JSON, sorted by attribute path so plans diff well
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut plan = self.clone();
+        plan.updates.sort_by(|a, b| a.attr_path.cmp(&b.attr_path));
+        let content = serde_json::to_string_pretty(&plan)?;
+        tokio::fs::write(path, content + "\n")
+            .await
+            .with_context(|| format!("Failed to write plan {}", path.display()))
+    }
+
+    /// Whether the plan updates `attr_path`
+    pub fn contains(&self, attr_path: &str) -> bool {
+        self.updates.iter().any(|u| u.attr_path == attr_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_roundtrip() {
+        let plan: Plan = serde_json::from_str(
+            r#"{"updates": [{
+                "attr_path": "hello",
+                "current_version": "2.12",
+                "target_version": "2.12.1",
+                "source": "GitHub repo: gnu/hello"
+            }]}"#,
+        )
+        .unwrap();
+
+        assert!(plan.contains("hello"));
+        assert!(!plan.contains("world"));
+        assert_eq!(
+            serde_json::from_str::<Plan>(&serde_json::to_string(&plan).unwrap()).unwrap(),
+            plan
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Version lookup by external commands
+//!
+//! Upstreams without built-in support can be looked up by a command, declared in the
+//! configuration file:
+//!
+//! ```toml
+//! [packages.foo]
+//! source_command = ["./scripts/foo-versions", "--stable"]
+//! ```
+//!
+//! or by the package itself as `passthru.updateInfo.command`, a program or a list of a program and
+//! its arguments like `updateScript`. The configuration takes precedence over the package.
+//!
+//! The command receives the package as a JSON object on stdin:
+//!
+//! ```json
+//! { "attr_path": "foo", "version": "1.2.0", "pname": "foo", "src_url": "https://...", "homepage": null }
+//! ```
+//!
+//! and prints the candidate versions as a JSON list on stdout, either plain versions or objects:
+//!
+//! ```json
+//! ["1.2.1", { "version": "1.3.0-rc.1", "prerelease": true, "published_at": "2024-05-01T00:00:00Z" }]
+//! ```
+//!
+//! The best version is then picked like for any other source, honoring the semver strategy, tag
+//! filters and minimum release age of the package. Commands run in the current directory, and
+//! are killed if they don't finish within [`PLUGIN_TIMEOUT`].
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::nix::nix_command;
+
+/// Time a command may take to list the versions of a package
+pub const PLUGIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Command looking up the versions of a package
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourcePlugin {
+    /// Program followed by its arguments
+    pub command: Vec<String>,
+    /// Expression of the derivations referenced by the command, built before running it
+    pub derivations: Option<String>,
+}
+
+/// Package described to a command on its stdin
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PluginInput {
+    /// Attribute path of the package
+    pub attr_path: String,
+    /// Current version of the package
+    pub version: String,
+    /// `pname` of the package
+    pub pname: Option<String>,
+    /// URL the source of the package is fetched from
+    pub src_url: Option<String>,
+    /// `meta.homepage` of the package
+    pub homepage: Option<String>,
+}
+
+/// Version printed by a command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PluginVersion {
+    /// Plain version, e.g. `"1.2.1"`
+    Version(String),
+    /// Version with the details of its release
+    Release {
+        /// Version of the release
+        version: String,
+        /// Whether the release is a prerelease
+        #[serde(default)]
+        prerelease: bool,
+        /// When the release was published, in RFC 3339
+        published_at: Option<String>,
+        /// Release notes
+        notes: Option<String>,
+    },
+}
+
+impl PluginVersion {
+    /// Version of the release
+    pub fn version(&self) -> &str {
+        match self {
+            PluginVersion::Version(version) | PluginVersion::Release { version, .. } => version,
+        }
+    }
+}
+
+/// Parse the JSON list of versions printed by a command
+pub fn parse_plugin_output(stdout: &str) -> anyhow::Result<Vec<PluginVersion>> {
+    let versions: Vec<PluginVersion> = serde_json::from_str(stdout.trim())
+        .context("Expected a JSON list of versions on stdout")?;
+    Ok(versions
+        .into_iter()
+        .filter(|v| !v.version().trim().is_empty())
+        .collect())
+}
+
+/// Build the derivations referenced by a command so it can be executed
+async fn realise_derivations(expr: &str) {
+    let output = nix_command("nix-build")
+        .args(["--no-out-link", "-E", expr])
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => {},
+        Ok(output) => warn!(
+            "Failed to build source command: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => warn!("Failed to build source command: {}", e),
+    }
+}
+
+/// Run the command of a plugin and return the versions it lists
+pub async fn fetch_plugin_versions(
+    plugin: &SourcePlugin,
+    input: &PluginInput,
+) -> anyhow::Result<Vec<PluginVersion>> {
+    let Some((program, args)) = plugin.command.split_first() else {
+        anyhow::bail!("Empty source command");
+    };
+    if let Some(ref derivations) = plugin.derivations {
+        realise_derivations(derivations).await;
+    }
+
+    debug!(
+        "{}: Running source command {}",
+        input.attr_path,
+        plugin.command.join(" ")
+    );
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to execute source command {}", program))?;
+
+    // Commands don't have to read the package, e.g. when it's passed as an argument
+    let mut stdin = child.stdin.take().context("Failed to open stdin")?;
+    if let Err(e) = stdin.write_all(&serde_json::to_vec(input)?).await {
+        debug!(
+            "{}: Source command didn't read stdin: {}",
+            input.attr_path, e
+        );
+    }
+    drop(stdin);
+
+    let output = tokio::time::timeout(PLUGIN_TIMEOUT, child.wait_with_output())
+        .await
+        .with_context(|| format!("Source command {} timed out", program))??;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stderr.lines() {
+        debug!("[{}] {}", program, line);
+    }
+    if !output.status.success() {
+        anyhow::bail!(
+            "Source command {} failed ({}): {}",
+            program,
+            output.status,
+            stderr.trim()
+        );
+    }
+
+    parse_plugin_output(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("Invalid output of source command {}", program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plugin_output() {
+        let versions = parse_plugin_output(
+            r#"["1.2.1", "", {"version": "1.3.0-rc.1", "prerelease": true, "published_at": "2024-05-01T00:00:00Z"}]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            versions,
+            vec![
+                PluginVersion::Version("1.2.1".to_string()),
+                PluginVersion::Release {
+                    version: "1.3.0-rc.1".to_string(),
+                    prerelease: true,
+                    published_at: Some("2024-05-01T00:00:00Z".to_string()),
+                    notes: None,
+                },
+            ]
+        );
+        assert_eq!(versions[1].version(), "1.3.0-rc.1");
+
+        assert!(parse_plugin_output("[]").unwrap().is_empty());
+        assert!(parse_plugin_output("1.2.1").is_err());
+        assert!(parse_plugin_output(r#"{"versions": ["1.2.1"]}"#).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Live progress display of `run`
+//!
+//! Shows how many packages were evaluated and processed, the running updated/failed counts and
+//! a spinner with the elapsed time of every package being updated. Log lines are printed above
+//! the bars through [`ConsoleWriter`]. Nothing is drawn if stderr isn't a terminal.
+
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use tracing_subscriber::fmt::MakeWriter;
+
+static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Interval at which spinners and elapsed times are redrawn
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Progress of a `run`, hidden entirely when disabled
+pub struct RunProgress {
+    multi: Option<MultiProgress>,
+    overview: ProgressBar,
+    evaluated: AtomicUsize,
+    started: AtomicUsize,
+    processed: AtomicUsize,
+    updated: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl RunProgress {
+    pub fn new(enabled: bool) -> Self {
+        let multi = enabled.then(|| MULTI_PROGRESS.get_or_init(MultiProgress::new).clone());
+        let overview = match &multi {
+            Some(multi) => {
+                let bar = multi.add(ProgressBar::new_spinner());
+                bar.set_style(
+                    ProgressStyle::with_template("{spinner} [{elapsed_precise}] {msg}")
+                        .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+                );
+                bar.enable_steady_tick(TICK_INTERVAL);
+                bar
+            },
+            None => ProgressBar::hidden(),
+        };
+
+        let progress = Self {
+            multi,
+            overview,
+            evaluated: AtomicUsize::new(0),
+            started: AtomicUsize::new(0),
+            processed: AtomicUsize::new(0),
+            updated: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        };
+        progress.redraw();
+        progress
+    }
+
+    /// Count a package evaluated by nix-eval-jobs
+    pub fn evaluated(&self) {
+        self.evaluated.fetch_add(1, Ordering::Relaxed);
+        self.redraw();
+    }
+
+    /// Show a package (or group) being updated, until the returned bar is dropped
+    pub fn start(&self, label: &str) -> TaskProgress {
+        self.started.fetch_add(1, Ordering::Relaxed);
+        self.redraw();
+
+        let bar = match &self.multi {
+            Some(multi) => {
+                let bar = multi.add(ProgressBar::new_spinner());
+                bar.set_style(
+                    ProgressStyle::with_template("  {spinner} {msg} ({elapsed})")
+                        .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+                );
+                bar.set_message(label.to_string());
+                bar.enable_steady_tick(TICK_INTERVAL);
+                bar
+            },
+            None => ProgressBar::hidden(),
+        };
+        TaskProgress {
+            bar,
+            multi: self.multi.clone(),
+        }
+    }
+
+    /// Count a finished package (or group)
+    pub fn finished(&self, updated: bool, failed: bool) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        if updated {
+            self.updated.fetch_add(1, Ordering::Relaxed);
+        }
+        if failed {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.redraw();
+    }
+
+    /// Remove the progress display, e.g. before printing the summary
+    pub fn finish(&self) {
+        self.overview.finish_and_clear();
+        if let Some(multi) = &self.multi {
+            multi.remove(&self.overview);
+        }
+    }
+
+    fn redraw(&self) {
+        self.overview.set_message(format_overview(
+            self.evaluated.load(Ordering::Relaxed),
+            self.processed.load(Ordering::Relaxed),
+            self.started.load(Ordering::Relaxed),
+            self.updated.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+        ));
+    }
+}
+
+/// Spinner of a package being updated, removed when dropped
+pub struct TaskProgress {
+    bar: ProgressBar,
+    multi: Option<MultiProgress>,
+}
+
+impl Drop for TaskProgress {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+        if let Some(multi) = &self.multi {
+            multi.remove(&self.bar);
+        }
+    }
+}
+
+fn format_overview(
+    evaluated: usize,
+    processed: usize,
+    started: usize,
+    updated: usize,
+    failed: usize,
+) -> String {
+    format!(
+        "{} evaluated, {}/{} processed, {} updated, {} failed",
+        evaluated, processed, started, updated, failed
+    )
+}
+
+/// Console log writer which prints above the progress bars instead of through them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match MULTI_PROGRESS.get() {
+            Some(multi) => multi.suspend(|| io::stdout().write(buf)),
+            None => io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for ConsoleWriter {
+    type Writer = ConsoleWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        *self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_overview() {
+        assert_eq!(
+            format_overview(120, 3, 5, 2, 1),
+            "120 evaluated, 3/5 processed, 2 updated, 1 failed"
+        );
+    }
+}
@@ -1,12 +1,17 @@
 //! PyPI (Python Package Index) API integration
 
+use std::cmp::Ordering;
 use std::collections::HashMap;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
+use crate::http::{self, Throttled};
+use crate::package::PackageMetadata;
+use crate::vcs_sources::{Pep440, VersionScheme};
+
 /// PyPI release information from the API
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PypiResponse {
     #[allow(dead_code)]
     pub info: PypiInfo,
@@ -14,16 +19,172 @@ pub struct PypiResponse {
 }
 
 /// Package metadata from PyPI
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PypiInfo {
     #[allow(dead_code)]
     pub version: String,
+    /// Requirements of the release, e.g. `requests>=2.0; extra == "socks"`
+    #[serde(default)]
+    pub requires_dist: Option<Vec<String>>,
 }
 
-/// Individual release artifact
+/// Metadata of a single release from the API
 #[derive(Debug, Deserialize)]
+struct PypiReleaseResponse {
+    info: PypiInfo,
+}
+
+/// Individual release artifact
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PypiArtifact {
     pub yanked: bool,
+    /// File name of the sdist or wheel, e.g. `requests-2.31.0.tar.gz`
+    #[serde(default)]
+    pub filename: String,
+    #[serde(default)]
+    pub digests: PypiDigests,
+    /// Kind of artifact, `sdist` or `bdist_wheel`
+    #[serde(default)]
+    pub packagetype: String,
+    /// Python versions the artifact supports, e.g. `>=3.8`
+    #[serde(default)]
+    pub requires_python: Option<String>,
+    #[serde(default)]
+    pub upload_time_iso_8601: Option<String>,
+}
+
+/// Digests of an artifact as published by PyPI
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PypiDigests {
+    /// SHA-256 of the artifact in hex
+    pub sha256: Option<String>,
+}
+
+impl PypiResponse {
+    /// SHA-256 digest in hex of the artifact named `filename` in release `version`
+    pub fn artifact_sha256(&self, version: &str, filename: &str) -> Option<&str> {
+        self.releases
+            .get(version)?
+            .iter()
+            .find(|artifact| artifact.filename == filename)?
+            .digests
+            .sha256
+            .as_deref()
+    }
+}
+
+/// What the package set needs from a PyPI release to build it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PythonRequirements {
+    /// Version of the interpreter the package is built with, e.g. `3.12.4`
+    pub python_version: Option<String>,
+    /// Whether the Nix expression fetches an sdist rather than a wheel
+    pub needs_sdist: bool,
+}
+
+impl PythonRequirements {
+    /// Requirements of the PyPI source of a package
+    pub fn from_metadata(metadata: &PackageMetadata) -> Self {
+        Self {
+            python_version: metadata.python_version.clone(),
+            needs_sdist: metadata
+                .src_url
+                .as_deref()
+                .is_some_and(|url| !url.ends_with(".whl")),
+        }
+    }
+
+    /// Whether a release can be built, i.e. has an artifact of the fetched kind supporting the
+    /// interpreter
+    pub(crate) fn is_buildable(&self, version: &str, artifacts: &[PypiArtifact]) -> bool {
+        let mut candidates = artifacts
+            .iter()
+            .filter(|artifact| !self.needs_sdist || artifact.packagetype == "sdist")
+            .peekable();
+        if candidates.peek().is_none() {
+            if self.needs_sdist {
+                debug!("Skipping PyPI release {}: no sdist published", version);
+                return false;
+            }
+            return true;
+        }
+
+        let Some(python_version) = &self.python_version else {
+            return true;
+        };
+        let supported = candidates.any(|artifact| match artifact.requires_python.as_deref() {
+            Some(specifiers) => python_satisfies(specifiers, python_version),
+            None => true,
+        });
+        if !supported {
+            debug!(
+                "Skipping PyPI release {}: requires_python excludes Python {}",
+                version, python_version
+            );
+        }
+        supported
+    }
+}
+
+/// Whether `python_version` satisfies a PEP 440 specifier set like `>=3.8,!=3.9.*,<4`
+///
+/// Specifiers which can't be parsed are treated as satisfied, so that unusual metadata doesn't
+/// hold back updates.
+fn python_satisfies(specifiers: &str, python_version: &str) -> bool {
+    specifiers
+        .split(',')
+        .map(str::trim)
+        .filter(|specifier| !specifier.is_empty())
+        .all(|specifier| specifier_matches(specifier, python_version).unwrap_or(true))
+}
+
+/// Whether `version` matches a single specifier, None if it can't be parsed
+fn specifier_matches(specifier: &str, version: &str) -> Option<bool> {
+    let operator_len = specifier
+        .find(|c: char| !matches!(c, '=' | '!' | '<' | '>' | '~'))
+        .unwrap_or(specifier.len());
+    let (operator, target) = specifier.split_at(operator_len);
+    let target = target.trim();
+
+    // `==3.*` and `!=3.9.*` compare a prefix of the release
+    if let Some(prefix) = target.strip_suffix(".*") {
+        let matches = has_release_prefix(&Pep440.release(version)?, &Pep440.release(prefix)?);
+        return match operator {
+            "==" => Some(matches),
+            "!=" => Some(!matches),
+            _ => None,
+        };
+    }
+
+    let ordering = Pep440.compare(version, target)?;
+    match operator {
+        "==" | "===" => Some(ordering == Ordering::Equal),
+        "!=" => Some(ordering != Ordering::Equal),
+        ">=" => Some(ordering != Ordering::Less),
+        "<=" => Some(ordering != Ordering::Greater),
+        ">" => Some(ordering == Ordering::Greater),
+        "<" => Some(ordering == Ordering::Less),
+        // `~=3.7` means `>=3.7,==3.*`
+        "~=" => {
+            let target_release = Pep440.release(target)?;
+            if target_release.len() < 2 {
+                return None;
+            }
+            let prefix = &target_release[..target_release.len() - 1];
+            Some(
+                ordering != Ordering::Less && has_release_prefix(&Pep440.release(version)?, prefix),
+            )
+        },
+        _ => None,
+    }
+}
+
+/// Whether a release starts with `prefix`, missing components counting as 0
+fn has_release_prefix(release: &[u64], prefix: &[u64]) -> bool {
+    prefix
+        .iter()
+        .enumerate()
+        .all(|(i, component)| release.get(i).copied().unwrap_or(0) == *component)
 }
 
 /// Fetch all releases from PyPI API
@@ -52,11 +213,11 @@ pub async fn fetch_pypi_releases(pname: &str) -> anyhow::Result<PypiResponse> {
 
     debug!("Fetching PyPI releases from {}", url);
 
-    let client = reqwest::Client::new();
+    let client = http::client();
     let response = client
         .get(&url)
         .header("User-Agent", "ekapkgs-update")
-        .send()
+        .send_throttled()
         .await?;
 
     if !response.status().is_success() {
@@ -67,6 +228,160 @@ pub async fn fetch_pypi_releases(pname: &str) -> anyhow::Result<PypiResponse> {
     Ok(pypi_response)
 }
 
+/// Fetch the requirements (`requires_dist`) of a release from PyPI
+pub async fn fetch_requires_dist(pname: &str, version: &str) -> anyhow::Result<Vec<String>> {
+    let url = format!("https://pypi.org/pypi/{}/{}/json", pname, version);
+
+    debug!("Fetching PyPI release metadata from {}", url);
+
+    let client = http::client();
+    let response = client
+        .get(&url)
+        .header("User-Agent", "ekapkgs-update")
+        .send_throttled()
+        .await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!("PyPI API request failed with status: {}", response.status());
+    }
+
+    let release: PypiReleaseResponse = response.json().await?;
+    Ok(release.info.requires_dist.unwrap_or_default())
+}
+
+/// Python dependencies which differ between a new release and the Nix expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct DependencyDelta {
+    /// Version of the release the requirements are from
+    pub version: String,
+    /// Requirements of the release missing from the Nix expression, as written by upstream
+    pub added: Vec<String>,
+    /// Dependencies of the Nix expression the release no longer requires
+    pub dropped: Vec<String>,
+}
+
+impl DependencyDelta {
+    /// Compare the requirements of a release against the dependencies of the Nix expression
+    ///
+    /// Requirements only needed for an extra are not expected in the Nix expression, but a
+    /// dependency is only considered dropped if no extra requires it either.
+    pub fn compute(version: &str, requires_dist: &[String], nix_dependencies: &[String]) -> Self {
+        let nix_names: Vec<String> = nix_dependencies
+            .iter()
+            .map(|name| normalize_name(name))
+            .collect();
+
+        let added = requires_dist
+            .iter()
+            .filter(|requirement| !requirement.contains("extra =="))
+            .filter(|requirement| {
+                requirement_name(requirement)
+                    .is_some_and(|name| !nix_names.contains(&normalize_name(name)))
+            })
+            .cloned()
+            .collect();
+
+        let required_names: Vec<String> = requires_dist
+            .iter()
+            .filter_map(|requirement| requirement_name(requirement))
+            .map(normalize_name)
+            .collect();
+        let dropped = nix_dependencies
+            .iter()
+            .filter(|name| !required_names.contains(&normalize_name(name)))
+            .cloned()
+            .collect();
+
+        Self {
+            version: version.to_string(),
+            added,
+            dropped,
+        }
+    }
+
+    /// Markdown checklist of the dependencies to review, for PR bodies
+    pub fn report(&self) -> Option<String> {
+        if self.added.is_empty() && self.dropped.is_empty() {
+            return None;
+        }
+
+        let mut report = String::from("## Python Dependencies\n");
+        if !self.added.is_empty() {
+            report.push_str(&format!(
+                "\nRequired by {} on PyPI but missing from the Nix expression:\n",
+                self.version
+            ));
+            for requirement in &self.added {
+                report.push_str(&format!("\n- [ ] `{}`", requirement));
+            }
+            report.push('\n');
+        }
+        if !self.dropped.is_empty() {
+            report.push_str(&format!(
+                "\nIn the Nix expression but no longer required by {}:\n",
+                self.version
+            ));
+            for name in &self.dropped {
+                report.push_str(&format!("\n- [ ] `{}`", name));
+            }
+            report.push('\n');
+        }
+        Some(report.trim_end().to_string())
+    }
+}
+
+/// Name of the distribution a requirement refers to, e.g. `requests` for `requests[socks]>=2.0`
+fn requirement_name(requirement: &str) -> Option<&str> {
+    let end = requirement
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+        .unwrap_or(requirement.len());
+    Some(&requirement[..end]).filter(|name| !name.is_empty())
+}
+
+/// Normalize a distribution name as per PEP 503, e.g. `Zope.Interface` to `zope-interface`
+fn normalize_name(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+    normalized
+}
+
+/// Convert a hex SHA-256 digest to an SRI hash (`sha256-<base64>`) as used in Nix files
+pub fn sha256_hex_to_sri(hex: &str) -> Option<String> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    if hex.len() != 64 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    let mut encoded = String::with_capacity(44);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    Some(format!("sha256-{}", encoded))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -76,6 +391,129 @@ mod tests {
         // This test just verifies that the structures are defined correctly
         // Actual API integration tests would require network access
         let _response: Option<PypiResponse> = None;
-        assert!(true);
+    }
+
+    #[test]
+    fn test_sha256_hex_to_sri() {
+        // sha256 of the empty string
+        assert_eq!(
+            sha256_hex_to_sri("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+                .as_deref(),
+            Some("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=")
+        );
+        assert_eq!(sha256_hex_to_sri("abc"), None);
+        assert_eq!(sha256_hex_to_sri(&"zz".repeat(32)), None);
+    }
+
+    #[test]
+    fn test_artifact_sha256() {
+        let response: PypiResponse = serde_json::from_str(
+            r#"{
+                "info": {"version": "1.0"},
+                "releases": {
+                    "1.0": [
+                        {"filename": "foo-1.0-py3-none-any.whl", "yanked": false,
+                         "digests": {"sha256": "aa"}},
+                        {"filename": "foo-1.0.tar.gz", "yanked": false,
+                         "digests": {"sha256": "bb"}}
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.artifact_sha256("1.0", "foo-1.0.tar.gz"),
+            Some("bb")
+        );
+        assert_eq!(response.artifact_sha256("1.0", "foo-1.0.zip"), None);
+        assert_eq!(response.artifact_sha256("2.0", "foo-2.0.tar.gz"), None);
+    }
+
+    #[test]
+    fn test_python_satisfies() {
+        assert!(python_satisfies(">=3.8", "3.12.4"));
+        assert!(!python_satisfies(">=3.13", "3.12.4"));
+        assert!(python_satisfies(">=3.7, <4", "3.12.4"));
+        assert!(!python_satisfies(">=2.7,!=3.0.*,!=3.12.*", "3.12.4"));
+        assert!(python_satisfies("==3.*", "3.12.4"));
+        assert!(python_satisfies("~=3.10", "3.12.4"));
+        assert!(!python_satisfies("~=3.10.1", "3.12.4"));
+        assert!(python_satisfies("", "3.12.4"));
+        // Unparseable specifiers don't exclude releases
+        assert!(python_satisfies(">=3.x", "3.12.4"));
+    }
+
+    #[test]
+    fn test_release_buildable() {
+        let artifact =
+            |filename: &str, packagetype: &str, requires_python: Option<&str>| PypiArtifact {
+                yanked: false,
+                filename: filename.to_string(),
+                digests: PypiDigests::default(),
+                packagetype: packagetype.to_string(),
+                requires_python: requires_python.map(str::to_string),
+                upload_time_iso_8601: None,
+            };
+        let wheel_only = [artifact("foo-2.0-py3-none-any.whl", "bdist_wheel", None)];
+        let new_python = [
+            artifact("foo-3.0.tar.gz", "sdist", Some(">=3.13")),
+            artifact("foo-3.0-py3-none-any.whl", "bdist_wheel", Some(">=3.13")),
+        ];
+
+        let sdist = PythonRequirements {
+            python_version: Some("3.12.4".to_string()),
+            needs_sdist: true,
+        };
+        assert!(!sdist.is_buildable("2.0", &wheel_only));
+        assert!(!sdist.is_buildable("3.0", &new_python));
+        assert!(sdist.is_buildable("3.0", &[artifact("foo-3.0.tar.gz", "sdist", Some(">=3.8"))]));
+
+        let wheel = PythonRequirements {
+            python_version: None,
+            needs_sdist: false,
+        };
+        assert!(wheel.is_buildable("2.0", &wheel_only));
+        assert!(wheel.is_buildable("3.0", &new_python));
+    }
+
+    #[test]
+    fn test_dependency_delta() {
+        let requires_dist = vec![
+            "charset-normalizer<4,>=2".to_string(),
+            "idna<4,>=2.5".to_string(),
+            "urllib3<3,>=1.21.1".to_string(),
+            "PySocks!=1.5.7,>=1.5.6; extra == \"socks\"".to_string(),
+            "typing_extensions; python_version < \"3.11\"".to_string(),
+        ];
+        let nix_dependencies = vec![
+            "charset-normalizer".to_string(),
+            "idna".to_string(),
+            "certifi".to_string(),
+            "pysocks".to_string(),
+            "typing-extensions".to_string(),
+        ];
+
+        let delta = DependencyDelta::compute("2.32.0", &requires_dist, &nix_dependencies);
+        assert_eq!(delta.added, vec!["urllib3<3,>=1.21.1"]);
+        assert_eq!(delta.dropped, vec!["certifi"]);
+        assert_eq!(
+            delta.report().unwrap(),
+            "## Python Dependencies\n\nRequired by 2.32.0 on PyPI but missing from the Nix \
+             expression:\n\n- [ ] `urllib3<3,>=1.21.1`\n\nIn the Nix expression but no longer \
+             required by 2.32.0:\n\n- [ ] `certifi`"
+        );
+
+        let unchanged =
+            DependencyDelta::compute("1.0", &requires_dist[..2], &nix_dependencies[..2]);
+        assert_eq!(unchanged.report(), None);
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("Zope.Interface"), "zope-interface");
+        assert_eq!(normalize_name("typing__extensions"), "typing-extensions");
+        assert_eq!(requirement_name("requests[socks]>=2.0"), Some("requests"));
+        assert_eq!(requirement_name(">=2.0"), None);
     }
 }
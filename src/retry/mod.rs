@@ -0,0 +1,127 @@
+//! Escalating retry strategies for failed updates
+//!
+//! An update which fails is retried on the next run with a more conservative approach: without
+//! passthru.tests if they were what failed, then updating to the latest release of the current
+//! major version, then of the current minor version. Once every strategy failed the package is
+//! left alone for a while before starting over.
+
+use std::fmt;
+
+use crate::nix::build_failure::UpdateFailureKind;
+use crate::vcs_sources::SemverStrategy;
+
+/// How an update attempt is carried out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetryStrategy {
+    /// Update to the latest release with the configured options
+    #[default]
+    Default,
+    /// Update to the latest release, without building passthru.tests
+    SkipTests,
+    /// Update to the latest release of the current major version
+    Minor,
+    /// Update to the latest release of the current minor version
+    Patch,
+}
+
+impl RetryStrategy {
+    /// Parse a strategy as stored in the database
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "default" => Some(Self::Default),
+            "skip-tests" => Some(Self::SkipTests),
+            "minor" => Some(Self::Minor),
+            "patch" => Some(Self::Patch),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::SkipTests => "skip-tests",
+            Self::Minor => "minor",
+            Self::Patch => "patch",
+        }
+    }
+
+    /// Version selection strategy of the attempt
+    pub fn semver_strategy(&self) -> SemverStrategy {
+        match self {
+            Self::Default | Self::SkipTests => SemverStrategy::Latest,
+            Self::Minor => SemverStrategy::Minor,
+            Self::Patch => SemverStrategy::Patch,
+        }
+    }
+
+    /// Whether the attempt runs passthru.tests, if they are enabled at all
+    pub fn runs_tests(&self) -> bool {
+        *self != Self::SkipTests
+    }
+
+    /// Strategy to retry with after an attempt with this strategy failed
+    ///
+    /// Tests are only skipped if they were what failed. Returns `None` once every strategy has
+    /// been tried.
+    pub fn next(&self, failure_kind: Option<UpdateFailureKind>) -> Option<Self> {
+        match self {
+            Self::Default if failure_kind == Some(UpdateFailureKind::TestFailure) => {
+                Some(Self::SkipTests)
+            },
+            Self::Default | Self::SkipTests => Some(Self::Minor),
+            Self::Minor => Some(Self::Patch),
+            Self::Patch => None,
+        }
+    }
+}
+
+impl fmt::Display for RetryStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_strategy_escalation() {
+        let mut strategy = Some(RetryStrategy::Default);
+        let mut tried = Vec::new();
+        while let Some(current) = strategy {
+            tried.push(current);
+            strategy = current.next(Some(UpdateFailureKind::CompileError));
+        }
+        assert_eq!(
+            tried,
+            vec![
+                RetryStrategy::Default,
+                RetryStrategy::Minor,
+                RetryStrategy::Patch
+            ]
+        );
+
+        assert_eq!(
+            RetryStrategy::Default.next(Some(UpdateFailureKind::TestFailure)),
+            Some(RetryStrategy::SkipTests)
+        );
+        assert_eq!(
+            RetryStrategy::SkipTests.next(Some(UpdateFailureKind::TestFailure)),
+            Some(RetryStrategy::Minor)
+        );
+    }
+
+    #[test]
+    fn test_retry_strategy_roundtrip() {
+        for strategy in [
+            RetryStrategy::Default,
+            RetryStrategy::SkipTests,
+            RetryStrategy::Minor,
+            RetryStrategy::Patch,
+        ] {
+            assert_eq!(RetryStrategy::parse(strategy.as_str()), Some(strategy));
+        }
+        assert_eq!(RetryStrategy::parse("unknown"), None);
+    }
+}
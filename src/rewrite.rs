@@ -1,12 +1,19 @@
 //! Nix file rewriting utilities using AST validation and text manipulation
 
 use regex::Regex;
+use rnix::{SyntaxKind, TextRange, ast};
+use rowan::ast::AstNode;
 
-/// Find and update an attribute value in a Nix file using regex with rnix validation
+/// Find and update an attribute value in a Nix file
+///
+/// The attribute is located through the rnix syntax tree, so bindings inside `let` blocks,
+/// `rec` sets and nested attribute sets are found regardless of surrounding whitespace. Only the
+/// contents of the string literal are replaced; comments and formatting are left untouched.
 ///
 /// # Arguments
 /// * `content` - The Nix file content as a string
-/// * `attr_name` - The attribute name to find (e.g., "version", "hash")
+/// * `attr_name` - The attribute name to find (e.g., "version", "hash"). A dotted name such as
+///   "src.hash" matches bindings whose attribute path ends with those components
 /// * `new_value` - The new value to set (without quotes)
 /// * `old_value` - Optional old value to match (for safety)
 ///
@@ -41,39 +48,713 @@ pub fn find_and_update_attr(
         ));
     }
 
-    // Build regex pattern to match: attr_name = "value";
-    // This handles various whitespace patterns
-    let pattern = if let Some(old) = old_value {
-        // Match specific old value
-        format!(
-            r#"(?m)(\s*{}\s*=\s*"){}("\s*;)"#,
-            regex::escape(attr_name),
-            regex::escape(old)
-        )
-    } else {
-        // Match any value
-        format!(
-            r#"(?m)(\s*{}\s*=\s*")([^"]*)("\s*;)"#,
-            regex::escape(attr_name)
-        )
+    let wanted: Vec<&str> = attr_name.split('.').collect();
+
+    // Collect the ranges of every matching string literal's contents
+    let ranges: Vec<TextRange> = parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter(|binding| attrpath_ends_with(binding, &wanted))
+        .filter_map(|binding| string_contents_range(&binding))
+        .filter(|range| match old_value {
+            Some(old) => &content[*range] == old,
+            None => true,
+        })
+        .collect();
+
+    if ranges.is_empty() {
+        anyhow::bail!("Attribute '{}' not found in Nix file", attr_name);
+    }
+
+    // Splice from the end of the file so earlier ranges stay valid
+    let escaped = escape_string_literal(new_value);
+    let mut result = content.to_string();
+    for range in ranges.iter().rev() {
+        result.replace_range(std::ops::Range::<usize>::from(*range), &escaped);
+    }
+
+    // Validate the result parses correctly
+    let result_parse = rnix::Root::parse(&result);
+    if !result_parse.errors().is_empty() {
+        anyhow::bail!("Replacement would create invalid Nix syntax");
+    }
+
+    Ok(result)
+}
+
+/// Find and update the version of a package in a Nix file
+///
+/// Handles `version = "1.2.3";` anywhere in the file (including `let` bindings, `rec` sets and
+/// `finalAttrs:` style derivations). When `version` is instead bound to another binding, as in
+/// `let pkgVersion = "1.2.3"; in { version = pkgVersion; }` or `version = "${pkgVersion}";`,
+/// the referenced binding is updated.
+pub fn find_and_update_version(
+    content: &str,
+    new_version: &str,
+    old_version: &str,
+) -> anyhow::Result<String> {
+    let err = match find_and_update_attr(content, "version", new_version, Some(old_version)) {
+        Ok(updated) => return Ok(updated),
+        Err(e) if e.to_string().contains("not found") => e,
+        Err(e) => return Err(e),
     };
 
+    for name in referenced_bindings(content, "version") {
+        if let Ok(updated) = find_and_update_attr(content, &name, new_version, Some(old_version)) {
+            return Ok(updated);
+        }
+    }
+
+    Err(err)
+}
+
+/// Update the version written out in the `changelog` of a package
+///
+/// Rewrites `old_version` in the literal text of `changelog` bindings, such as
+/// `changelog = "https://github.com/foo/bar/blob/v1.2.3/CHANGELOG.md";` in `meta`. Changelogs
+/// interpolating `${version}` follow the version by themselves and are left untouched. The old
+/// version only matches as a whole, so `1.2` doesn't match within `1.2.3`.
+///
+/// Returns None if no changelog contains the old version.
+pub fn update_changelog_version(
+    content: &str,
+    old_version: &str,
+    new_version: &str,
+) -> anyhow::Result<Option<String>> {
+    if old_version.is_empty() || old_version == new_version {
+        return Ok(None);
+    }
+    let parse = rnix::Root::parse(content);
+    if !parse.errors().is_empty() {
+        let errors: Vec<String> = parse.errors().iter().map(|e| e.to_string()).collect();
+        anyhow::bail!("Failed to parse Nix file: {}", errors.join(", "));
+    }
+
+    // Literal parts of changelogs containing the old version, with their rewritten text
+    let mut replacements: Vec<(TextRange, String)> = Vec::new();
+    for binding in parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter(|binding| attrpath_ends_with(binding, &["changelog"]))
+    {
+        let Some(ast::Expr::Str(s)) = binding.value() else {
+            continue;
+        };
+        for token in s
+            .syntax()
+            .children_with_tokens()
+            .filter_map(|element| element.into_token())
+            .filter(|token| token.kind() == SyntaxKind::TOKEN_STRING_CONTENT)
+        {
+            let replaced = replace_version(
+                token.text(),
+                old_version,
+                &escape_string_literal(new_version),
+            );
+            if replaced != token.text() {
+                replacements.push((token.text_range(), replaced));
+            }
+        }
+    }
+
+    if replacements.is_empty() {
+        return Ok(None);
+    }
+
+    let mut result = content.to_string();
+    for (range, replaced) in replacements.iter().rev() {
+        result.replace_range(std::ops::Range::<usize>::from(*range), replaced);
+    }
+
+    let result_parse = rnix::Root::parse(&result);
+    if !result_parse.errors().is_empty() {
+        anyhow::bail!("Replacement would create invalid Nix syntax");
+    }
+
+    Ok(Some(result))
+}
+
+/// Replace the occurrences of `old_version` in `text` which aren't part of a longer version
+///
+/// # Example
+/// ```
+/// use ekapkgs_update::rewrite::replace_version;
+///
+/// let url = "https://example.org/news-1.2.3.html#v1.2";
+/// assert_eq!(
+///     replace_version(url, "1.2", "1.4"),
+///     "https://example.org/news-1.2.3.html#v1.4"
+/// );
+/// ```
+pub fn replace_version(text: &str, old_version: &str, new_version: &str) -> String {
+    if old_version.is_empty() {
+        return text.to_string();
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, _) in text.match_indices(old_version) {
+        if start < last || !is_whole_version(text, start, old_version.len()) {
+            continue;
+        }
+        result.push_str(&text[last..start]);
+        result.push_str(new_version);
+        last = start + old_version.len();
+    }
+    result.push_str(&text[last..]);
+    result
+}
+
+/// Whether the version at `start` of `text` isn't part of a longer version, e.g. `1.2` of `1.2.3`
+fn is_whole_version(text: &str, start: usize, len: usize) -> bool {
+    let before = text[..start].chars().next_back();
+    let mut after = text[start + len..].chars();
+    let continues = match after.next() {
+        Some(c) if c.is_ascii_digit() => true,
+        Some('.') => after.next().is_some_and(|c| c.is_ascii_digit()),
+        _ => false,
+    };
+    !continues && !before.is_some_and(|c| c.is_ascii_digit() || c == '.')
+}
+
+/// Find and update an attribute passed to `callPackage` in a Nix file
+///
+/// Handles callers such as `foo = callPackage ./foo { version = "1.2"; };`, where the package's
+/// own file only receives the value as a function argument. Only calls whose path argument
+/// satisfies `is_target` (given the path as written) are considered, and exactly one argument
+/// must hold `old_value`.
+pub fn update_call_package_arg(
+    content: &str,
+    is_target: impl Fn(&str) -> bool,
+    attr_name: &str,
+    new_value: &str,
+    old_value: &str,
+) -> anyhow::Result<String> {
+    let parse = rnix::Root::parse(content);
+    if !parse.errors().is_empty() {
+        let errors: Vec<String> = parse.errors().iter().map(|e| e.to_string()).collect();
+        anyhow::bail!("Failed to parse Nix file: {}", errors.join(", "));
+    }
+
+    let is_call_package = |expr: ast::Expr| match expr {
+        ast::Expr::Ident(ident) => ident
+            .ident_token()
+            .is_some_and(|t| t.text() == "callPackage"),
+        ast::Expr::Select(select) => select
+            .attrpath()
+            .and_then(|p| p.attrs().last())
+            .is_some_and(|attr| attr.syntax().text() == "callPackage"),
+        _ => false,
+    };
+
+    let mut ranges: Vec<TextRange> = Vec::new();
+    for call in parse.syntax().descendants().filter_map(ast::Apply::cast) {
+        let Some(ast::Expr::Apply(inner)) = call.lambda() else {
+            continue;
+        };
+        let Some(ast::Expr::Path(path)) = inner.argument() else {
+            continue;
+        };
+        if !inner.lambda().is_some_and(is_call_package) || !is_target(&path.syntax().to_string()) {
+            continue;
+        }
+        let Some(ast::Expr::AttrSet(args)) = call.argument() else {
+            continue;
+        };
+        ranges.extend(
+            args.syntax()
+                .children()
+                .filter_map(ast::AttrpathValue::cast)
+                .filter(|binding| {
+                    binding.attrpath().is_some_and(|p| p.attrs().count() == 1)
+                        && attrpath_ends_with(binding, &[attr_name])
+                })
+                .filter_map(|binding| string_contents_range(&binding))
+                .filter(|range| &content[*range] == old_value),
+        );
+    }
+
+    let range = match ranges.as_slice() {
+        [range] => *range,
+        [] => anyhow::bail!(
+            "Attribute '{}' not found in callPackage arguments",
+            attr_name
+        ),
+        _ => anyhow::bail!(
+            "Attribute '{}' is passed to {} matching callPackage calls",
+            attr_name,
+            ranges.len()
+        ),
+    };
+
+    let mut result = content.to_string();
+    result.replace_range(
+        std::ops::Range::<usize>::from(range),
+        &escape_string_literal(new_value),
+    );
+
+    let result_parse = rnix::Root::parse(&result);
+    if !result_parse.errors().is_empty() {
+        anyhow::bail!("Replacement would create invalid Nix syntax");
+    }
+
+    Ok(result)
+}
+
+/// Collect the names of the bindings referenced by the value of `attr_name`
+///
+/// Recognises plain identifiers (`pkgVersion`), selections (`finalAttrs.passthru.baseVersion`
+/// yields `baseVersion`) and strings consisting of a single interpolation of either.
+fn referenced_bindings(content: &str, attr_name: &str) -> Vec<String> {
+    fn reference_name(expr: ast::Expr) -> Option<String> {
+        match expr {
+            ast::Expr::Ident(ident) => ident.ident_token().map(|t| t.text().to_string()),
+            ast::Expr::Select(select) => match select.attrpath()?.attrs().last()? {
+                ast::Attr::Ident(ident) => ident.ident_token().map(|t| t.text().to_string()),
+                _ => None,
+            },
+            ast::Expr::Str(s) => match s.parts().collect::<Vec<_>>().as_slice() {
+                [ast::InterpolPart::Interpolation(interpol)] => reference_name(interpol.expr()?),
+                _ => None,
+            },
+            ast::Expr::Paren(paren) => reference_name(paren.expr()?),
+            _ => None,
+        }
+    }
+
+    let parse = rnix::Root::parse(content);
+    let mut names = Vec::new();
+    for binding in parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter(|binding| attrpath_ends_with(binding, &[attr_name]))
+    {
+        if let Some(name) = binding.value().and_then(reference_name) {
+            if name != attr_name && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Check whether a binding's attribute path ends with the given components
+///
+/// Dynamic (`${...}`) components never match.
+fn attrpath_ends_with(binding: &ast::AttrpathValue, wanted: &[&str]) -> bool {
+    let Some(attrpath) = binding.attrpath() else {
+        return false;
+    };
+
+    let names: Vec<Option<String>> = attrpath
+        .attrs()
+        .map(|attr| match attr {
+            ast::Attr::Ident(ident) => ident.ident_token().map(|t| t.text().to_string()),
+            ast::Attr::Str(s) => match s.normalized_parts().as_slice() {
+                [ast::InterpolPart::Literal(name)] => Some(name.clone()),
+                _ => None,
+            },
+            ast::Attr::Dynamic(_) => None,
+        })
+        .collect();
+
+    names.len() >= wanted.len()
+        && names[names.len() - wanted.len()..]
+            .iter()
+            .zip(wanted)
+            .all(|(name, wanted)| name.as_deref() == Some(*wanted))
+}
+
+/// Get the range of the contents of a binding's value, if it is a double-quoted string
+///
+/// The range excludes the surrounding quotes. Indented strings (`''...''`) are skipped as
+/// they use different escaping rules.
+fn string_contents_range(binding: &ast::AttrpathValue) -> Option<TextRange> {
+    let ast::Expr::Str(s) = binding.value()? else {
+        return None;
+    };
+
+    let start = s.syntax().first_token()?;
+    let end = s.syntax().last_token()?;
+    if start.kind() != SyntaxKind::TOKEN_STRING_START
+        || start.text() != "\""
+        || end.kind() != SyntaxKind::TOKEN_STRING_END
+    {
+        return None;
+    }
+
+    Some(TextRange::new(
+        start.text_range().end(),
+        end.text_range().start(),
+    ))
+}
+
+/// Escape a value for use inside a double-quoted Nix string
+pub fn escape_string_literal(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+}
+
+/// Nixpkgs fetchers taking a `hash` as well as a `sha256` argument
+const SRI_FETCHERS: &[&str] = &[
+    "fetchurl",
+    "fetchzip",
+    "fetchgit",
+    "fetchsvn",
+    "fetchhg",
+    "fetchpatch",
+    "fetchpatch2",
+    "fetchFromGitHub",
+    "fetchFromGitLab",
+    "fetchFromGitea",
+    "fetchFromCodeberg",
+    "fetchFromBitbucket",
+    "fetchFromSourcehut",
+    "fetchFromSavannah",
+    "fetchFromRepoOrCz",
+    "fetchPypi",
+    "fetchCrate",
+    "fetchNuGet",
+    "fetchDebianPatch",
+];
+
+/// Whether `set` is the argument set of a call to a fetcher of [`SRI_FETCHERS`], e.g.
+/// `fetchurl { ... }` or `pkgs.fetchFromGitHub { ... }`
+///
+/// The builtin fetchers, e.g. `builtins.fetchTarball`, only take `sha256`.
+fn is_sri_fetcher_args(set: &ast::AttrSet) -> bool {
+    let Some(apply) = set.syntax().parent().and_then(ast::Apply::cast) else {
+        return false;
+    };
+    if apply
+        .argument()
+        .is_none_or(|argument| argument.syntax() != set.syntax())
+    {
+        return false;
+    }
+    let name = match apply.lambda() {
+        Some(ast::Expr::Ident(ident)) => ident.ident_token().map(|t| t.text().to_string()),
+        Some(ast::Expr::Select(select)) => {
+            let from_builtins = matches!(
+                select.expr(),
+                Some(ast::Expr::Ident(ident))
+                    if ident.ident_token().is_some_and(|t| t.text() == "builtins")
+            );
+            select
+                .attrpath()
+                .and_then(|p| p.attrs().last())
+                .filter(|_| !from_builtins)
+                .map(|attr| attr.syntax().text().to_string())
+        },
+        _ => None,
+    };
+    name.is_some_and(|name| SRI_FETCHERS.contains(&name.as_str()))
+}
+
+/// Convert legacy `sha256 = "..."` arguments of nixpkgs fetchers to SRI `hash = "sha256-..."`
+///
+/// Values in hex, Nix base32 or plain base64 are converted; values which are already SRI hashes
+/// only have the binding renamed. Only the arguments of the fetchers of [`SRI_FETCHERS`] are
+/// converted, other `sha256` bindings, e.g. of `let` or `builtins.fetchTarball`, may be
+/// inherited or referenced by name. Bindings are left untouched if the value can't be converted
+/// (e.g. it contains an interpolation), the argument set is recursive or already has a `hash`
+/// binding, or the file reads a `sha256` attribute, e.g. `src.sha256`.
+///
+/// # Returns
+/// The updated content and the number of converted bindings, or an error if the file has
+/// invalid Nix syntax or the conversion would create invalid syntax
+pub fn normalize_sha256_hashes(content: &str) -> anyhow::Result<(String, usize)> {
+    let parse = rnix::Root::parse(content);
+    if !parse.errors().is_empty() {
+        let errors: Vec<String> = parse.errors().iter().map(|e| e.to_string()).collect();
+        anyhow::bail!("Failed to parse Nix file: {}", errors.join(", "));
+    }
+
+    // Attributes read from another set, e.g. `src.sha256` or `inherit (src) sha256;`, may be
+    // the arguments of a fetcher
+    let reads_sha256_attr = parse
+        .syntax()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| token.kind() == SyntaxKind::TOKEN_IDENT && token.text() == "sha256")
+        .any(|token| {
+            let Some(parent) = token.parent().and_then(|ident| ident.parent()) else {
+                return false;
+            };
+            match parent.kind() {
+                SyntaxKind::NODE_ATTRPATH => parent
+                    .parent()
+                    .is_some_and(|p| p.kind() == SyntaxKind::NODE_SELECT),
+                SyntaxKind::NODE_INHERIT => {
+                    ast::Inherit::cast(parent).is_some_and(|inherit| inherit.from().is_some())
+                },
+                _ => false,
+            }
+        });
+    if reads_sha256_attr {
+        return Ok((content.to_string(), 0));
+    }
+
+    // (range of the attribute name, range of the string contents, SRI hash)
+    let mut edits: Vec<(TextRange, TextRange, String)> = Vec::new();
+    for binding in parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter(|binding| {
+            binding.attrpath().is_some_and(|p| p.attrs().count() == 1)
+                && attrpath_ends_with(binding, &["sha256"])
+        })
+        .filter(|binding| {
+            binding
+                .syntax()
+                .parent()
+                .and_then(ast::AttrSet::cast)
+                .is_some_and(|set| set.rec_token().is_none() && is_sri_fetcher_args(&set))
+        })
+    {
+        let Some(ast::Attr::Ident(name)) = binding.attrpath().and_then(|p| p.attrs().last()) else {
+            continue;
+        };
+        let Some(value_range) = string_contents_range(&binding) else {
+            continue;
+        };
+        let Some(sri) = sha256_to_sri(&content[value_range]) else {
+            continue;
+        };
+        let has_hash_sibling = binding.syntax().parent().is_some_and(|parent| {
+            parent
+                .children()
+                .filter_map(ast::AttrpathValue::cast)
+                .any(|sibling| {
+                    sibling.attrpath().is_some_and(|p| {
+                        p.attrs().count() == 1 && attrpath_ends_with(&sibling, &["hash"])
+                    })
+                })
+        });
+        if has_hash_sibling {
+            continue;
+        }
+        edits.push((name.syntax().text_range(), value_range, sri));
+    }
+
+    // Splice from the end of the file so earlier ranges stay valid
+    let mut result = content.to_string();
+    for (name_range, value_range, sri) in edits.iter().rev() {
+        result.replace_range(std::ops::Range::<usize>::from(*value_range), sri);
+        result.replace_range(std::ops::Range::<usize>::from(*name_range), "hash");
+    }
+
+    let result_parse = rnix::Root::parse(&result);
+    if !result_parse.errors().is_empty() {
+        anyhow::bail!("Conversion would create invalid Nix syntax");
+    }
+
+    Ok((result, edits.len()))
+}
+
+/// Convert a SHA-256 hash in hex, Nix base32, base64 or SRI form to an SRI hash
+fn sha256_to_sri(value: &str) -> Option<String> {
+    const NIX32_ALPHABET: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+    const BASE64_ALPHABET: &str =
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let is_base64 = |s: &str| {
+        s.len() == 44
+            && s.ends_with('=')
+            && s.trim_end_matches('=')
+                .chars()
+                .all(|c| BASE64_ALPHABET.contains(c))
+    };
+
+    match value.len() {
+        64 => crate::pypi::sha256_hex_to_sri(value),
+        52 => {
+            // Nix base32 encodes the digest back to front, five bits per character
+            let mut bytes = [0u8; 32];
+            for (k, c) in value.chars().enumerate() {
+                let digit = NIX32_ALPHABET.find(c)? as u16;
+                let bit = (value.len() - 1 - k) * 5;
+                let (i, j) = (bit / 8, bit % 8);
+                bytes[i] |= (digit << j) as u8;
+                let carry = digit >> (8 - j);
+                match bytes.get_mut(i + 1) {
+                    Some(next) => *next |= carry as u8,
+                    None if carry != 0 => return None,
+                    None => {},
+                }
+            }
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            crate::pypi::sha256_hex_to_sri(&hex)
+        },
+        44 if is_base64(value) => Some(format!("sha256-{}", value)),
+        51 if is_base64(value.strip_prefix("sha256-")?) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Format of a sidecar file holding version pins next to a Nix expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SidecarFormat {
+    /// `.json` files
+    Json,
+    /// `.toml` files
+    Toml,
+}
+
+impl SidecarFormat {
+    /// Determine the sidecar format from a file's extension
+    pub fn from_path(path: &str) -> Option<Self> {
+        match std::path::Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+        {
+            Some("json") => Some(SidecarFormat::Json),
+            Some("toml") => Some(SidecarFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// Find JSON/TOML sidecar files read by a Nix expression
+///
+/// Detects `builtins.fromJSON (builtins.readFile ./sources.json)`, `lib.importJSON ./x.json`
+/// and their TOML equivalents. Paths are returned as written, relative to the Nix file.
+pub fn find_sidecar_files(content: &str) -> Vec<String> {
+    let Ok(re) = Regex::new(
+        r"(?:fromJSON|importJSON|fromTOML|importTOML)[\s(]*(?:(?:builtins\.)?readFile[\s(]*)?(\.{1,2}/[\w./-]+\.(?:json|toml))",
+    ) else {
+        return Vec::new();
+    };
+
+    let mut paths: Vec<String> = Vec::new();
+    for caps in re.captures_iter(content) {
+        let path = caps[1].to_string();
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Find the plain text files the version of a package is read from
+///
+/// Detects `version = lib.fileContents ./version;` and `version = builtins.readFile ./VERSION;`,
+/// also when wrapped in e.g. `lib.trim` or `lib.removeSuffix "\n"`, or bound to another binding
+/// like `version = pkgVersion;`. JSON and TOML files are left to [`find_sidecar_files`]. Paths are
+/// returned as written, relative to the Nix file.
+pub fn find_version_files(content: &str) -> Vec<String> {
+    fn is_file_reader(expr: ast::Expr) -> bool {
+        let name = match expr {
+            ast::Expr::Ident(ident) => ident.ident_token().map(|t| t.text().to_string()),
+            ast::Expr::Select(select) => select
+                .attrpath()
+                .and_then(|p| p.attrs().last())
+                .map(|attr| attr.syntax().text().to_string()),
+            _ => None,
+        };
+        matches!(name.as_deref(), Some("fileContents" | "readFile"))
+    }
+
+    let parse = rnix::Root::parse(content);
+    let mut names = vec!["version".to_string()];
+    names.extend(referenced_bindings(content, "version"));
+
+    let mut paths: Vec<String> = Vec::new();
+    for binding in parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter(|binding| {
+            names
+                .iter()
+                .any(|name| attrpath_ends_with(binding, &[name]))
+        })
+    {
+        let Some(value) = binding.value() else {
+            continue;
+        };
+        for apply in value.syntax().descendants().filter_map(ast::Apply::cast) {
+            let (Some(lambda), Some(ast::Expr::Path(path))) = (apply.lambda(), apply.argument())
+            else {
+                continue;
+            };
+            let path = path.syntax().to_string();
+            if is_file_reader(lambda)
+                && SidecarFormat::from_path(&path).is_none()
+                && !paths.contains(&path)
+            {
+                paths.push(path);
+            }
+        }
+    }
+    paths
+}
+
+/// Replace the version held by a plain text version file, keeping surrounding whitespace
+///
+/// Returns None if the file doesn't hold exactly `old_version`.
+pub fn update_version_file(content: &str, old_version: &str, new_version: &str) -> Option<String> {
+    let trimmed = content.trim();
+    if trimmed != old_version {
+        return None;
+    }
+    let start = content.len() - content.trim_start().len();
+    Some(format!(
+        "{}{}{}",
+        &content[..start],
+        new_version,
+        &content[start + trimmed.len()..]
+    ))
+}
+
+/// Find and update a string value in a JSON or TOML sidecar file
+///
+/// Only the value itself is replaced, so key order and formatting are preserved. Every
+/// occurrence of `key` holding `old_value` (or any string, if `old_value` is `None`) is updated,
+/// at any nesting depth.
+pub fn update_sidecar_attr(
+    content: &str,
+    format: SidecarFormat,
+    key: &str,
+    new_value: &str,
+    old_value: Option<&str>,
+) -> anyhow::Result<String> {
+    let value_pattern = match old_value {
+        Some(old) => regex::escape(old),
+        None => r#"(?:[^"\\]|\\.)*"#.to_string(),
+    };
+    let pattern = match format {
+        SidecarFormat::Json => format!(r#"("{}"\s*:\s*"){}(")"#, regex::escape(key), value_pattern),
+        SidecarFormat::Toml => format!(
+            r#"(?m)(^\s*(?:{key}|"{key}")\s*=\s*"){}(")"#,
+            value_pattern,
+            key = regex::escape(key)
+        ),
+    };
     let re = Regex::new(&pattern)?;
 
-    // Check if the attribute exists
     if !re.is_match(content) {
-        anyhow::bail!("Attribute '{}' not found in Nix file", attr_name);
+        anyhow::bail!("Key '{}' not found in sidecar file", key);
     }
 
-    // Replace the attribute value
+    // JSON and TOML basic strings share the escapes needed here
+    let escaped = new_value.replace('\\', "\\\\").replace('"', "\\\"");
     let result = re.replace_all(content, |caps: &regex::Captures| {
-        format!("{}{}{}", &caps[1], new_value, &caps[caps.len() - 1])
+        format!("{}{}{}", &caps[1], escaped, &caps[2])
     });
 
     // Validate the result parses correctly
-    let result_parse = rnix::Root::parse(&result);
-    if !result_parse.errors().is_empty() {
-        anyhow::bail!("Replacement would create invalid Nix syntax");
+    let valid = match format {
+        SidecarFormat::Json => serde_json::from_str::<serde_json::Value>(&result).is_ok(),
+        SidecarFormat::Toml => toml::from_str::<toml::Table>(&result).is_ok(),
+    };
+    if !valid {
+        anyhow::bail!("Replacement would create an invalid sidecar file");
     }
 
     Ok(result.into_owned())
@@ -212,8 +893,10 @@ pub fn replace_maintainers_with_empty(content: &str) -> anyhow::Result<(String,
 /// - The patch is not found in the array
 /// - The removal would create invalid syntax
 ///
-/// This function uses regex-based removal since rnix doesn't provide easy
-/// whitespace-preserving AST manipulation for array elements.
+/// Entries are located through the rnix syntax tree: paths like `./patches/fix-build.patch`,
+/// `fetchpatch`/`fetchpatch2`/`fetchurl` calls mentioning the patch, and variables bound to
+/// either. Comments on the lines of the entry, and comment lines right above it, go with it. A
+/// variable binding the patch is removed as well once nothing else refers to it.
 pub fn remove_patch_from_array(content: &str, patch_name: &str) -> anyhow::Result<String> {
     // First, validate that the file parses correctly
     let parse = rnix::Root::parse(content);
@@ -224,61 +907,407 @@ pub fn remove_patch_from_array(content: &str, patch_name: &str) -> anyhow::Resul
             errors.join(", ")
         ));
     }
+    let root = parse.syntax();
+
+    let entry = root
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter(|binding| attrpath_ends_with(binding, &["patches"]))
+        .filter_map(|binding| binding.value())
+        .flat_map(|value| value.syntax().descendants().filter_map(ast::List::cast))
+        .flat_map(|list| list.items())
+        .find_map(|item| {
+            let variable = match &item {
+                ast::Expr::Ident(ident) => {
+                    let name = ident.ident_token()?.text().to_string();
+                    let binding = root
+                        .descendants()
+                        .filter_map(ast::AttrpathValue::cast)
+                        .find(|binding| {
+                            binding
+                                .attrpath()
+                                .is_some_and(|path| path.attrs().count() == 1)
+                                && attrpath_ends_with(binding, &[&name])
+                        })?;
+                    if !is_patch_expr(&binding.value()?, patch_name) {
+                        return None;
+                    }
+                    Some((name, binding))
+                },
+                _ if is_patch_expr(&item, patch_name) => None,
+                _ => return None,
+            };
+            Some((item.syntax().text_range(), variable))
+        });
+
+    let Some((range, variable)) = entry else {
+        anyhow::bail!("Patch '{}' not found in patches array", patch_name)
+    };
 
-    // Build regex pattern to match the patch entry in the array
-    // Handles various formats:
-    // - ./patch-name.patch
-    // - (fetchpatch { name = "patch-name.patch"; ... })
-    // We need to match the entire line including potential trailing comma and whitespace
+    let mut result = content.to_string();
+    result.replace_range(removal_range(content, range), "");
+
+    // Drop the variable binding the patch unless something else still refers to it
+    if let Some((name, binding)) = variable {
+        let still_used = rnix::Root::parse(&result)
+            .syntax()
+            .descendants()
+            .filter_map(ast::Ident::cast)
+            .filter(|ident| {
+                ident.ident_token().is_some_and(|t| t.text() == name)
+                    && !ident
+                        .syntax()
+                        .ancestors()
+                        .any(|node| node.kind() == SyntaxKind::NODE_ATTRPATH)
+            })
+            .count()
+            > 0;
+        let binding_range = binding.syntax().text_range();
+        if !still_used && binding_range.end() <= range.start() {
+            let mut without_binding = result.clone();
+            without_binding.replace_range(removal_range(&result, binding_range), "");
+            if rnix::Root::parse(&without_binding).errors().is_empty() {
+                result = without_binding;
+            }
+        }
+    }
 
-    // Pattern 1: Simple path reference like ./patch-name.patch
-    // Match the whole line with leading whitespace and optional trailing comma
-    let simple_pattern = format!(r#"(?m)^\s*\.\/{}(?:,)?\s*$\n?"#, regex::escape(patch_name));
+    // Validate the result parses correctly
+    let result_parse = rnix::Root::parse(&result);
+    if !result_parse.errors().is_empty() {
+        anyhow::bail!("Removal would create invalid Nix syntax");
+    }
+
+    Ok(result)
+}
 
-    let simple_regex = Regex::new(&simple_pattern)?;
+/// Whether an expression is a path to the patch or a fetcher call downloading it
+fn is_patch_expr(expr: &ast::Expr, patch_name: &str) -> bool {
+    if let ast::Expr::Path(path) = expr {
+        let path = path.syntax().text().to_string();
+        return path.ends_with(&format!("/{}", patch_name));
+    }
+
+    expr.syntax()
+        .descendants()
+        .filter_map(ast::Apply::cast)
+        .any(|apply| {
+            apply.lambda().is_some_and(|lambda| {
+                matches!(
+                    lambda.syntax().text().to_string().as_str(),
+                    "fetchpatch" | "fetchpatch2" | "fetchurl"
+                )
+            }) && apply
+                .argument()
+                .is_some_and(|arg| arg.syntax().text().to_string().contains(patch_name))
+        })
+}
 
-    if simple_regex.is_match(content) {
-        let result = simple_regex.replace(content, "");
+/// Range of text to remove along with an element, including its own lines when it is alone on
+/// them
+///
+/// Trailing comments and the comment lines right above the element are part of its lines.
+/// Elements sharing a line with others only take the whitespace following them.
+fn removal_range(content: &str, range: TextRange) -> std::ops::Range<usize> {
+    let (start, end) = (usize::from(range.start()), usize::from(range.end()));
+    let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = content[end..].find('\n').map_or(content.len(), |i| end + i);
+    let tail = content[end..line_end].trim();
+
+    if !content[line_start..start].trim().is_empty() || !(tail.is_empty() || tail.starts_with('#'))
+    {
+        let spaces = content[end..line_end].len() - content[end..line_end].trim_start().len();
+        return start..end + spaces;
+    }
 
-        // Validate the result parses correctly
-        let result_parse = rnix::Root::parse(&result);
-        if !result_parse.errors().is_empty() {
-            anyhow::bail!("Removal would create invalid Nix syntax");
+    let mut first_line = line_start;
+    while first_line > 0 {
+        let previous = content[..first_line - 1].rfind('\n').map_or(0, |i| i + 1);
+        if !content[previous..first_line].trim_start().starts_with('#') {
+            break;
         }
+        first_line = previous;
+    }
+    first_line..(line_end + 1).min(content.len())
+}
 
-        return Ok(result.into_owned());
+/// Check whether a binding sets `attr` of `cargoLock`, either as `cargoLock.<attr> = ...` or
+/// inside `cargoLock = { ... }`
+fn is_cargo_lock_binding(binding: &ast::AttrpathValue, attr: &str) -> bool {
+    if attrpath_ends_with(binding, &["cargoLock", attr]) {
+        return true;
     }
+    if !attrpath_ends_with(binding, &[attr])
+        || binding.attrpath().map_or(0, |path| path.attrs().count()) != 1
+    {
+        return false;
+    }
+    binding
+        .syntax()
+        .parent()
+        .and_then(ast::AttrSet::cast)
+        .and_then(|set| set.syntax().parent())
+        .and_then(ast::AttrpathValue::cast)
+        .is_some_and(|parent| attrpath_ends_with(&parent, &["cargoLock"]))
+}
 
-    // Pattern 2: fetchpatch or other complex expression
-    // Look for lines containing the patch name within a fetchpatch call or similar
-    // This is more complex - we need to find the entire expression
-    let fetch_pattern = format!(
-        r#"(?ms)^\s*\(fetchpatch\s+\{{[^}}]*{}[^}}]*\}}\)[\s,]*\n"#,
-        regex::escape(patch_name)
-    );
+/// Whitespace indenting the line of `offset`
+fn line_indent(content: &str, offset: usize) -> &str {
+    let line_start = content[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = &content[line_start..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
 
-    let fetch_regex = Regex::new(&fetch_pattern)?;
+/// Find the `Cargo.lock` vendored next to a Rust package, from `cargoLock.lockFile`
+///
+/// Only paths are returned, as written relative to the Nix file. Lockfiles read from the source,
+/// e.g. `"${src}/Cargo.lock"`, aren't vendored.
+pub fn find_cargo_lock_file(content: &str) -> Option<String> {
+    let parse = rnix::Root::parse(content);
+    parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter(|binding| is_cargo_lock_binding(binding, "lockFile"))
+        .find_map(|binding| match binding.value()? {
+            ast::Expr::Path(path) => Some(path.syntax().to_string()),
+            _ => None,
+        })
+}
 
-    if fetch_regex.is_match(content) {
-        let result = fetch_regex.replace(content, "");
+/// Hashes of the git dependencies listed in `cargoLock.outputHashes`, by `<name>-<version>`
+pub fn cargo_output_hashes(content: &str) -> Vec<(String, String)> {
+    let parse = rnix::Root::parse(content);
+    let Some(ast::Expr::AttrSet(set)) = parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .find(|binding| is_cargo_lock_binding(binding, "outputHashes"))
+        .and_then(|binding| binding.value())
+    else {
+        return Vec::new();
+    };
 
-        // Validate the result parses correctly
-        let result_parse = rnix::Root::parse(&result);
-        if !result_parse.errors().is_empty() {
-            anyhow::bail!("Removal would create invalid Nix syntax");
+    set.syntax()
+        .children()
+        .filter_map(ast::AttrpathValue::cast)
+        .filter_map(|binding| {
+            let key = match binding.attrpath()?.attrs().collect::<Vec<_>>().as_slice() {
+                [ast::Attr::Str(s)] => match s.normalized_parts().as_slice() {
+                    [ast::InterpolPart::Literal(name)] => name.clone(),
+                    _ => return None,
+                },
+                [ast::Attr::Ident(ident)] => ident.ident_token()?.text().to_string(),
+                _ => return None,
+            };
+            let hash = content[string_contents_range(&binding)?].to_string();
+            Some((key, hash))
+        })
+        .collect()
+}
+
+/// Replace the entries of `cargoLock.outputHashes`
+///
+/// The attribute set is rewritten with one entry per line, added after `cargoLock.lockFile` if
+/// the package has none yet, and removed if `hashes` is empty.
+///
+/// # Errors
+/// Returns an error if the file has invalid Nix syntax, the package has no `cargoLock.lockFile`
+/// to add the hashes next to, or the rewrite would create invalid syntax.
+pub fn set_cargo_output_hashes(
+    content: &str,
+    hashes: &[(String, String)],
+) -> anyhow::Result<String> {
+    let parse = rnix::Root::parse(content);
+    if !parse.errors().is_empty() {
+        let errors: Vec<String> = parse.errors().iter().map(|e| e.to_string()).collect();
+        anyhow::bail!("Failed to parse Nix file: {}", errors.join(", "));
+    }
+
+    let render = |indent: &str| {
+        let mut set = String::from("{\n");
+        for (key, hash) in hashes {
+            set.push_str(&format!(
+                "{}  \"{}\" = \"{}\";\n",
+                indent,
+                escape_string_literal(key),
+                escape_string_literal(hash)
+            ));
         }
+        set.push_str(&format!("{}}}", indent));
+        set
+    };
+
+    let bindings: Vec<ast::AttrpathValue> = parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::AttrpathValue::cast)
+        .collect();
+    let result = match bindings
+        .iter()
+        .find(|binding| is_cargo_lock_binding(binding, "outputHashes"))
+    {
+        Some(binding) if hashes.is_empty() => {
+            let removal = removal_range(content, binding.syntax().text_range());
+            format!("{}{}", &content[..removal.start], &content[removal.end..])
+        },
+        Some(binding) => {
+            let value = binding
+                .value()
+                .ok_or_else(|| anyhow::anyhow!("cargoLock.outputHashes has no value"))?;
+            let range = value.syntax().text_range();
+            let indent = line_indent(content, usize::from(binding.syntax().text_range().start()));
+            format!(
+                "{}{}{}",
+                &content[..usize::from(range.start())],
+                render(indent),
+                &content[usize::from(range.end())..]
+            )
+        },
+        None if hashes.is_empty() => return Ok(content.to_string()),
+        None => {
+            let lock_file = bindings
+                .iter()
+                .find(|binding| is_cargo_lock_binding(binding, "lockFile"))
+                .ok_or_else(|| anyhow::anyhow!("cargoLock.lockFile not found in Nix file"))?;
+            let attrpath = lock_file
+                .attrpath()
+                .map(|path| path.syntax().to_string())
+                .unwrap_or_default();
+            let name = format!(
+                "{}outputHashes",
+                attrpath.strip_suffix("lockFile").unwrap_or_default()
+            );
+            let range = lock_file.syntax().text_range();
+            let indent = line_indent(content, usize::from(range.start()));
+            let end = usize::from(range.end());
+            format!(
+                "{}\n{}{} = {};{}",
+                &content[..end],
+                indent,
+                name,
+                render(indent),
+                &content[end..]
+            )
+        },
+    };
 
-        return Ok(result.into_owned());
+    if !rnix::Root::parse(&result).errors().is_empty() {
+        anyhow::bail!("Updating cargoLock.outputHashes would create invalid Nix syntax");
     }
+    Ok(result)
+}
 
-    // If we didn't find the patch, return an error
-    anyhow::bail!("Patch '{}' not found in patches array", patch_name)
+/// Find the hash of a `fetchpatch`/`fetchpatch2` call fetching the given patch
+///
+/// The call is identified by its argument set mentioning the patch name, e.g. in its `name` or
+/// `url` attribute. Returns the contents of its `hash` or `sha256` attribute.
+pub fn fetched_patch_hash(content: &str, patch_name: &str) -> Option<String> {
+    let parse = rnix::Root::parse(content);
+    parse
+        .syntax()
+        .descendants()
+        .filter_map(ast::Apply::cast)
+        .filter(|apply| {
+            apply.lambda().is_some_and(|lambda| {
+                matches!(
+                    lambda.syntax().text().to_string().as_str(),
+                    "fetchpatch" | "fetchpatch2"
+                )
+            })
+        })
+        .filter_map(|apply| match apply.argument()? {
+            ast::Expr::AttrSet(set) => Some(set),
+            _ => None,
+        })
+        .filter(|set| set.syntax().text().to_string().contains(patch_name))
+        .find_map(|set| {
+            set.syntax()
+                .children()
+                .filter_map(ast::AttrpathValue::cast)
+                .filter(|binding| {
+                    attrpath_ends_with(binding, &["hash"])
+                        || attrpath_ends_with(binding, &["sha256"])
+                })
+                .find_map(|binding| string_contents_range(&binding))
+                .map(|range| content[range].to_string())
+        })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_find_version_files() {
+        let content = r#"{ lib, stdenv }:
+stdenv.mkDerivation {
+  pname = "foo";
+  version = lib.fileContents ./version;
+  src = ./.;
+}"#;
+        assert_eq!(find_version_files(content), vec!["./version"]);
+
+        let content = r#"{ lib, stdenv }:
+let
+  pkgVersion = lib.removeSuffix "\n" (builtins.readFile ../VERSION);
+  sources = builtins.fromJSON (builtins.readFile ./sources.json);
+in
+stdenv.mkDerivation {
+  pname = "foo";
+  version = pkgVersion;
+}"#;
+        assert_eq!(find_version_files(content), vec!["../VERSION"]);
+        assert!(find_version_files(r#"{ version = "1.0"; }"#).is_empty());
+
+        assert_eq!(
+            update_version_file("1.2.3\n", "1.2.3", "1.3.0").as_deref(),
+            Some("1.3.0\n")
+        );
+        assert_eq!(update_version_file("1.2.3-rc1\n", "1.2.3", "1.3.0"), None);
+    }
+
+    #[test]
+    fn test_update_changelog_version() {
+        let content = r#"{
+  version = "1.2.3";
+  meta = {
+    changelog = "https://github.com/foo/bar/blob/v1.2.3/CHANGELOG.md";
+    homepage = "https://github.com/foo/bar/tree/v1.2.3";
+  };
+  passthru.changelog = "https://github.com/${owner}/bar/releases/tag/1.2.3";
+}"#;
+        let updated = update_changelog_version(content, "1.2.3", "1.3.0")
+            .unwrap()
+            .unwrap();
+        assert!(
+            updated
+                .contains(r#"changelog = "https://github.com/foo/bar/blob/v1.3.0/CHANGELOG.md";"#)
+        );
+        assert!(
+            updated
+                .contains(r#"changelog = "https://github.com/${owner}/bar/releases/tag/1.3.0";"#)
+        );
+        // Only changelogs are rewritten, the version is left to find_and_update_version
+        assert!(updated.contains(r#"version = "1.2.3";"#));
+        assert!(updated.contains("tree/v1.2.3"));
+
+        let interpolated =
+            r#"{ meta.changelog = "https://github.com/foo/bar/blob/v${version}/NEWS"; }"#;
+        assert_eq!(
+            update_changelog_version(interpolated, "1.2", "1.3").unwrap(),
+            None
+        );
+
+        let longer = r#"{ meta.changelog = "https://example.org/news-1.2.3.html#v1.2"; }"#;
+        assert_eq!(
+            update_changelog_version(longer, "1.2", "1.4")
+                .unwrap()
+                .as_deref(),
+            Some(r#"{ meta.changelog = "https://example.org/news-1.2.3.html#v1.4"; }"#)
+        );
+    }
+
     #[test]
     fn test_find_and_update_attr_simple() {
         let content = r#"{
@@ -400,6 +1429,219 @@ mod tests {
         assert!(updated.contains(r#"version = "2.0.0+build.456";"#));
     }
 
+    #[test]
+    fn test_find_and_update_attr_let_binding() {
+        let content = r#"{ stdenv, fetchurl }:
+let
+  version = "1.0.0";
+in
+stdenv.mkDerivation {
+  pname = "mypackage";
+  inherit version;
+}"#;
+
+        let updated = find_and_update_attr(content, "version", "2.0.0", Some("1.0.0")).unwrap();
+        assert!(updated.contains(r#"  version = "2.0.0";"#));
+        assert!(updated.contains("inherit version;"));
+    }
+
+    #[test]
+    fn test_find_and_update_attr_rec_set_and_whitespace() {
+        let content = r#"rec {
+  version="1.0.0" ;
+  src = fetchurl {
+    url = "https://example.com/${version}.tar.gz";
+    hash =
+      # pinned
+      "sha256-old";
+  };
+}"#;
+
+        let updated = find_and_update_attr(content, "version", "2.0.0", Some("1.0.0")).unwrap();
+        assert!(updated.contains(r#"version="2.0.0" ;"#));
+        assert!(updated.contains("${version}.tar.gz"));
+
+        let updated =
+            find_and_update_attr(&updated, "hash", "sha256-new", Some("sha256-old")).unwrap();
+        assert!(updated.contains("      # pinned\n      \"sha256-new\";"));
+    }
+
+    #[test]
+    fn test_find_and_update_attr_dotted_path() {
+        let content = r#"{
+  src.hash = "sha256-old";
+  "src-hash" = "sha256-other";
+  hash = "sha256-old";
+}"#;
+
+        let updated = find_and_update_attr(content, "src.hash", "sha256-new", None).unwrap();
+        assert!(updated.contains(r#"src.hash = "sha256-new";"#));
+        assert!(updated.contains(r#"  hash = "sha256-old";"#));
+
+        let updated = find_and_update_attr(content, "src-hash", "sha256-new", None).unwrap();
+        assert!(updated.contains(r#""src-hash" = "sha256-new";"#));
+    }
+
+    #[test]
+    fn test_find_and_update_attr_skips_interpolated_and_suffixed_names() {
+        let content = r#"{
+  myversion = "1.0.0";
+  version = "${base}-1.0.0";
+}"#;
+
+        let result = find_and_update_attr(content, "version", "2.0.0", Some("1.0.0"));
+        assert!(result.unwrap_err().to_string().contains("not found"));
+    }
+
+    #[test]
+    fn test_find_and_update_attr_escapes_value() {
+        let content = r#"{ description = "old"; }"#;
+
+        let updated = find_and_update_attr(content, "description", r#"say "${hi}""#, None).unwrap();
+        assert_eq!(updated, r#"{ description = "say \"\${hi}\""; }"#);
+    }
+
+    #[test]
+    fn test_find_and_update_version_final_attrs() {
+        let content = r#"{ stdenv, fetchurl }:
+stdenv.mkDerivation (finalAttrs: {
+  pname = "mypackage";
+  version = "1.0.0";
+  src = fetchurl {
+    url = "https://example.com/mypackage-${finalAttrs.version}.tar.gz";
+  };
+})"#;
+
+        let updated = find_and_update_version(content, "2.0.0", "1.0.0").unwrap();
+        assert!(updated.contains(r#"version = "2.0.0";"#));
+        assert!(updated.contains("${finalAttrs.version}"));
+    }
+
+    #[test]
+    fn test_find_and_update_version_through_reference() {
+        let content = r#"let
+  pkgVersion = "1.0.0";
+in
+{
+  version = pkgVersion;
+}"#;
+
+        let updated = find_and_update_version(content, "2.0.0", "1.0.0").unwrap();
+        assert!(updated.contains(r#"pkgVersion = "2.0.0";"#));
+        assert!(updated.contains("version = pkgVersion;"));
+
+        let content = r#"rec {
+  baseVersion = "1.0.0";
+  version = "${baseVersion}";
+}"#;
+
+        let updated = find_and_update_version(content, "2.0.0", "1.0.0").unwrap();
+        assert!(updated.contains(r#"baseVersion = "2.0.0";"#));
+    }
+
+    #[test]
+    fn test_find_and_update_version_not_found() {
+        let content = r#"{
+  version = lib.versions.majorMinor other;
+}"#;
+
+        let result = find_and_update_version(content, "2.0.0", "1.0.0");
+        assert!(result.unwrap_err().to_string().contains("not found"));
+    }
+
+    #[test]
+    fn test_find_sidecar_files() {
+        let content = r#"{ lib, stdenv }:
+let
+  sources = builtins.fromJSON (builtins.readFile ./sources.json);
+  pins = lib.importTOML ../pins/versions.toml;
+  again = lib.importJSON ./sources.json;
+in
+stdenv.mkDerivation { inherit (sources) version; }"#;
+
+        assert_eq!(
+            find_sidecar_files(content),
+            vec!["./sources.json", "../pins/versions.toml"]
+        );
+        assert!(find_sidecar_files(r#"{ version = "1.0.0"; }"#).is_empty());
+    }
+
+    #[test]
+    fn test_sidecar_format_from_path() {
+        assert_eq!(
+            SidecarFormat::from_path("pkgs/foo/sources.json"),
+            Some(SidecarFormat::Json)
+        );
+        assert_eq!(
+            SidecarFormat::from_path("versions.toml"),
+            Some(SidecarFormat::Toml)
+        );
+        assert_eq!(SidecarFormat::from_path("default.nix"), None);
+    }
+
+    #[test]
+    fn test_update_sidecar_attr_json() {
+        let content = r#"{
+  "version": "1.0.0",
+  "src": {
+    "url": "https://example.com/foo-1.0.0.tar.gz",
+    "hash": "sha256-old"
+  }
+}"#;
+
+        let updated = update_sidecar_attr(
+            content,
+            SidecarFormat::Json,
+            "version",
+            "2.0.0",
+            Some("1.0.0"),
+        )
+        .unwrap();
+        assert!(updated.contains(r#""version": "2.0.0","#));
+        // Only the keyed value changes
+        assert!(updated.contains("foo-1.0.0.tar.gz"));
+
+        let updated =
+            update_sidecar_attr(&updated, SidecarFormat::Json, "hash", "sha256-new", None).unwrap();
+        assert!(updated.contains(r#""hash": "sha256-new""#));
+    }
+
+    #[test]
+    fn test_update_sidecar_attr_toml() {
+        let content = r#"[foo]
+version = "1.0.0"
+"hash" = "sha256-old"
+"#;
+
+        let updated = update_sidecar_attr(
+            content,
+            SidecarFormat::Toml,
+            "version",
+            "2.0.0",
+            Some("1.0.0"),
+        )
+        .unwrap();
+        assert!(updated.contains(r#"version = "2.0.0""#));
+
+        let updated =
+            update_sidecar_attr(&updated, SidecarFormat::Toml, "hash", "sha256-new", None).unwrap();
+        assert!(updated.contains(r#""hash" = "sha256-new""#));
+    }
+
+    #[test]
+    fn test_update_sidecar_attr_not_found() {
+        let content = r#"{ "version": "1.0.0" }"#;
+
+        let result = update_sidecar_attr(
+            content,
+            SidecarFormat::Json,
+            "version",
+            "2.0.0",
+            Some("9.9.9"),
+        );
+        assert!(result.unwrap_err().to_string().contains("not found"));
+    }
+
     #[test]
     fn test_remove_patch_from_array_simple() {
         let content = r#"{
@@ -885,4 +2127,250 @@ mod tests {
         // Check that indentation is preserved
         assert!(updated.contains("    maintainers = [ ];"));
     }
+
+    #[test]
+    fn test_sha256_to_sri() {
+        let sri = Some("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".to_string());
+        assert_eq!(
+            sha256_to_sri("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            sri
+        );
+        assert_eq!(
+            sha256_to_sri("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"),
+            sri
+        );
+        assert_eq!(
+            sha256_to_sri("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="),
+            sri
+        );
+        assert_eq!(sha256_to_sri(sri.as_deref().unwrap()), sri);
+        assert_eq!(sha256_to_sri("${hashes.x86_64-linux}"), None);
+        assert_eq!(sha256_to_sri(&"e".repeat(52)), None);
+    }
+
+    #[test]
+    fn test_normalize_sha256_hashes() {
+        let content = r#"{
+  src = fetchurl {
+    url = "https://example.org/foo.tar.gz";
+    sha256 = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+  };
+  vendor = fetchzip {
+    url = "https://example.org/vendor.tar.gz";
+    sha256 = "${hashes.vendor}";
+  };
+  cargoSha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+}"#;
+
+        let (updated, converted) = normalize_sha256_hashes(content).unwrap();
+        assert_eq!(converted, 1);
+        assert!(
+            updated.contains(r#"hash = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";"#)
+        );
+        assert!(updated.contains(r#"sha256 = "${hashes.vendor}";"#));
+        assert!(updated.contains("cargoSha256 = "));
+
+        // Bindings outside the arguments of fetchers are left alone
+        let inherited = r#"let sha256 = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"; in fetchurl { inherit sha256; }"#;
+        assert_eq!(normalize_sha256_hashes(inherited).unwrap().1, 0);
+        let builtin = r#"builtins.fetchTarball { url = "https://example.org/a.tar.gz"; sha256 = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"; }"#;
+        assert_eq!(normalize_sha256_hashes(builtin).unwrap().1, 0);
+        let recursive = r#"fetchurl rec { sha256 = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"; name = sha256; }"#;
+        assert_eq!(normalize_sha256_hashes(recursive).unwrap().1, 0);
+        let referenced = r#"{ src = pkgs.fetchurl { sha256 = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"; }; id = src.sha256; }"#;
+        assert_eq!(normalize_sha256_hashes(referenced).unwrap().1, 0);
+        let qualified = r#"pkgs.fetchFromGitHub { sha256 = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"; }"#;
+        assert_eq!(normalize_sha256_hashes(qualified).unwrap().1, 1);
+
+        let with_hash =
+            r#"{ sha256 = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"; hash = ""; }"#;
+        assert_eq!(normalize_sha256_hashes(with_hash).unwrap().1, 0);
+    }
+
+    #[test]
+    fn test_update_call_package_arg() {
+        let content = r#"{ callPackage, python3Packages }:
+{
+  foo = callPackage ./foo { version = "1.2"; };
+  foo_1_1 = callPackage ./foo { version = "1.1"; };
+  bar = python3Packages.callPackage ../bar/default.nix { version = "1.2"; };
+  baz = callPackage ./baz { inherit (foo) version; };
+}"#;
+
+        let updated =
+            update_call_package_arg(content, |p| p == "./foo", "version", "1.3", "1.2").unwrap();
+        assert!(updated.contains(r#"foo = callPackage ./foo { version = "1.3"; };"#));
+        assert!(updated.contains(r#"foo_1_1 = callPackage ./foo { version = "1.1"; };"#));
+        assert!(updated.contains(r#"{ version = "1.2"; };"#));
+
+        let updated = update_call_package_arg(
+            content,
+            |p| p.starts_with("../bar"),
+            "version",
+            "2.0",
+            "1.2",
+        )
+        .unwrap();
+        assert!(updated.contains(r#"../bar/default.nix { version = "2.0"; }"#));
+
+        assert!(update_call_package_arg(content, |_| true, "version", "1.3", "1.2").is_err());
+        assert!(
+            update_call_package_arg(content, |p| p == "./baz", "version", "1.3", "1.2").is_err()
+        );
+    }
+
+    #[test]
+    fn test_fetched_patch_hash() {
+        let content = r#"{
+  patches = [
+    ./local.patch
+    (fetchpatch {
+      name = "fix-musl.patch";
+      url = "https://github.com/owner/repo/commit/abc.patch";
+      hash = "sha256-musl";
+    })
+    (fetchpatch2 {
+      url = "https://example.org/patches/fix-gcc14.patch";
+      sha256 = "sha256-gcc";
+    })
+  ];
+}"#;
+        assert_eq!(
+            fetched_patch_hash(content, "fix-musl.patch").as_deref(),
+            Some("sha256-musl")
+        );
+        assert_eq!(
+            fetched_patch_hash(content, "fix-gcc14.patch").as_deref(),
+            Some("sha256-gcc")
+        );
+        assert_eq!(fetched_patch_hash(content, "local.patch"), None);
+    }
+
+    #[test]
+    fn test_remove_patch_from_array_fetchpatch2_with_comments() {
+        let content = r#"{
+  patches = [
+    ./keep.patch # still needed
+    # Fix the build with musl
+    # https://github.com/owner/repo/pull/12
+    (fetchpatch2 {
+      name = "fix-musl.patch";
+      url = "https://github.com/owner/repo/commit/abc.patch";
+      hash = "sha256-musl";
+    }) # merged upstream in 2.0
+    ./patches/other.patch
+  ];
+}"#;
+        let updated = remove_patch_from_array(content, "fix-musl.patch").unwrap();
+        assert_eq!(
+            updated,
+            r#"{
+  patches = [
+    ./keep.patch # still needed
+    ./patches/other.patch
+  ];
+}"#
+        );
+
+        let updated = remove_patch_from_array(content, "other.patch").unwrap();
+        assert!(!updated.contains("other.patch"));
+        assert!(updated.contains("fix-musl.patch"));
+    }
+
+    #[test]
+    fn test_remove_patch_from_array_same_line() {
+        let content = "{\n  patches = [ ./first.patch ./second.patch ];\n}";
+        assert_eq!(
+            remove_patch_from_array(content, "first.patch").unwrap(),
+            "{\n  patches = [ ./second.patch ];\n}"
+        );
+    }
+
+    #[test]
+    fn test_remove_patch_from_array_variable() {
+        let content = r#"let
+  musl-patch = fetchpatch {
+    url = "https://example.org/fix-musl.patch";
+    hash = "sha256-musl";
+  };
+in
+{
+  patches = [
+    ./first.patch
+    musl-patch
+  ];
+}"#;
+        let updated = remove_patch_from_array(content, "fix-musl.patch").unwrap();
+        assert!(!updated.contains("musl-patch"));
+        assert!(updated.contains("./first.patch"));
+
+        // The binding stays while something else refers to it
+        let shared = content.replace("in\n{", "in\n{\n  passthru.patch = musl-patch;");
+        let updated = remove_patch_from_array(&shared, "fix-musl.patch").unwrap();
+        assert!(updated.contains("musl-patch = fetchpatch"));
+        assert!(!updated.contains("    musl-patch\n"));
+    }
+
+    #[test]
+    fn test_cargo_output_hashes() {
+        let content = r#"rustPlatform.buildRustPackage {
+  pname = "foo";
+  cargoLock = {
+    lockFile = ./Cargo.lock;
+    outputHashes = {
+      "bar-0.1.0" = "sha256-bar";
+      "baz-0.2.0" = "sha256-baz";
+    };
+  };
+}"#;
+        assert_eq!(
+            find_cargo_lock_file(content).as_deref(),
+            Some("./Cargo.lock")
+        );
+        assert_eq!(
+            cargo_output_hashes(content),
+            vec![
+                ("bar-0.1.0".to_string(), "sha256-bar".to_string()),
+                ("baz-0.2.0".to_string(), "sha256-baz".to_string()),
+            ]
+        );
+
+        let hashes = vec![("qux-1.0.0".to_string(), "sha256-qux".to_string())];
+        let updated = set_cargo_output_hashes(content, &hashes).unwrap();
+        assert!(
+            updated.contains(
+                "    outputHashes = {\n      \"qux-1.0.0\" = \"sha256-qux\";\n    };\n  };"
+            )
+        );
+        assert_eq!(cargo_output_hashes(&updated), hashes);
+
+        let removed = set_cargo_output_hashes(content, &[]).unwrap();
+        assert_eq!(
+            removed,
+            "rustPlatform.buildRustPackage {\n  pname = \"foo\";\n  cargoLock = {\n    lockFile = \
+             ./Cargo.lock;\n  };\n}"
+        );
+
+        // Added next to the lockfile when the package has no git dependencies yet
+        let added = set_cargo_output_hashes(&removed, &hashes).unwrap();
+        assert_eq!(added, updated);
+
+        let dotted = "{\n  cargoLock.lockFile = ./Cargo.lock;\n}";
+        assert_eq!(
+            find_cargo_lock_file(dotted).as_deref(),
+            Some("./Cargo.lock")
+        );
+        assert_eq!(
+            set_cargo_output_hashes(dotted, &hashes).unwrap(),
+            "{\n  cargoLock.lockFile = ./Cargo.lock;\n  cargoLock.outputHashes = {\n    \
+             \"qux-1.0.0\" = \"sha256-qux\";\n  };\n}"
+        );
+
+        // Lockfiles of the source aren't vendored
+        assert_eq!(
+            find_cargo_lock_file("{ cargoLock.lockFile = \"${src}/Cargo.lock\"; }"),
+            None
+        );
+        assert!(set_cargo_output_hashes("{ cargoHash = \"\"; }", &hashes).is_err());
+    }
 }
@@ -0,0 +1,262 @@
+//! Summary of a run, written for CI artifacts and notifications
+//!
+//! `run --summary-out summary.json` writes the outcome of every package checked by the run, with
+//! the URLs of the PRs created and the end of the logs of failed updates. `--summary-out
+//! summary.md` writes the same summary as Markdown, e.g. for a job summary or a chat message. The
+//! option may be given once per format.
+
+use std::path::Path;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::timings::format_duration;
+
+/// Lines of the error of a failed update kept in the summary
+const EXCERPT_LINES: usize = 20;
+
+/// Outcome of checking a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageStatus {
+    Updated,
+    /// Found by a dry run
+    WouldUpdate,
+    UpToDate,
+    Skipped,
+    Failed,
+    /// Member of a lockstep group whose newest version isn't released for every member yet
+    Waiting,
+}
+
+/// Outcome of checking a single package
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PackageSummary {
+    pub attr_path: String,
+    pub status: PackageStatus,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    /// URL of the PR of the update, if one was created
+    pub pr_url: Option<String>,
+    /// Reason the package was skipped, or the end of the error of a failed update
+    pub detail: Option<String>,
+}
+
+impl PackageSummary {
+    pub fn new(attr_path: &str, status: PackageStatus) -> Self {
+        Self {
+            attr_path: attr_path.to_string(),
+            status,
+            old_version: None,
+            new_version: None,
+            pr_url: None,
+            detail: None,
+        }
+    }
+
+    /// Versions the package was, or would be, updated between
+    pub fn versions(mut self, old_version: &str, new_version: Option<&str>) -> Self {
+        self.old_version = Some(old_version.to_string());
+        self.new_version = new_version.map(str::to_string);
+        self
+    }
+}
+
+/// Summary of a whole run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunSummary {
+    #[serde(serialize_with = "rfc3339")]
+    pub started_at: DateTime<Utc>,
+    #[serde(serialize_with = "rfc3339")]
+    pub finished_at: DateTime<Utc>,
+    pub dry_run: bool,
+    /// Whether the run was interrupted before checking every package
+    pub interrupted: bool,
+    /// Number of packages failing to evaluate
+    pub evaluation_errors: usize,
+    /// Number of packages not checked as they're in their backoff period
+    pub backoff: usize,
+    /// Packages checked, sorted by attribute path
+    pub packages: Vec<PackageSummary>,
+}
+
+/// Serialize a point in time in RFC 3339, like the timestamps of the database
+fn rfc3339<S: serde::Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339())
+}
+
+/// End of the error of a failed update
+pub fn excerpt(error: &str) -> String {
+    let lines: Vec<&str> = error.trim_end().lines().collect();
+    lines[lines.len().saturating_sub(EXCERPT_LINES)..].join("\n")
+}
+
+impl RunSummary {
+    /// Number of packages with the given outcome
+    pub fn count(&self, status: PackageStatus) -> usize {
+        self.packages.iter().filter(|p| p.status == status).count()
+    }
+
+    fn with_status(&self, status: PackageStatus) -> impl Iterator<Item = &PackageSummary> {
+        self.packages.iter().filter(move |p| p.status == status)
+    }
+
+    /// Summary as Markdown, listing the updated, failed and skipped packages
+    pub fn to_markdown(&self) -> String {
+        let duration = (self.finished_at - self.started_at)
+            .to_std()
+            .unwrap_or_default();
+        let mut md = String::from("# ekapkgs-update run\n\n");
+        md.push_str(&format!(
+            "Started {}, took {}{}{}.\n\n",
+            self.started_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            format_duration(duration),
+            if self.dry_run { ", dry run" } else { "" },
+            if self.interrupted {
+                ", interrupted"
+            } else {
+                ""
+            }
+        ));
+
+        md.push_str("| Outcome | Packages |\n| --- | --- |\n");
+        for (label, count) in [
+            ("Updated", self.count(PackageStatus::Updated)),
+            ("Would update", self.count(PackageStatus::WouldUpdate)),
+            ("Failed", self.count(PackageStatus::Failed)),
+            ("Waiting", self.count(PackageStatus::Waiting)),
+            ("Skipped", self.count(PackageStatus::Skipped)),
+            ("Up to date", self.count(PackageStatus::UpToDate)),
+            ("Skipped (backoff)", self.backoff),
+            ("Evaluation errors", self.evaluation_errors),
+        ] {
+            if count > 0 {
+                md.push_str(&format!("| {} | {} |\n", label, count));
+            }
+        }
+
+        for (title, status) in [
+            ("Updated", PackageStatus::Updated),
+            ("Would update", PackageStatus::WouldUpdate),
+        ] {
+            if self.count(status) == 0 {
+                continue;
+            }
+            md.push_str(&format!(
+                "\n## {}\n\n| Package | From | To | PR |\n| --- | --- | --- | --- |\n",
+                title
+            ));
+            for package in self.with_status(status) {
+                md.push_str(&format!(
+                    "| `{}` | {} | {} | {} |\n",
+                    package.attr_path,
+                    package.old_version.as_deref().unwrap_or_default(),
+                    package.new_version.as_deref().unwrap_or_default(),
+                    package.pr_url.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+
+        if self.count(PackageStatus::Failed) > 0 {
+            md.push_str("\n## Failed\n");
+            for package in self.with_status(PackageStatus::Failed) {
+                md.push_str(&format!("\n### `{}`", package.attr_path));
+                match (&package.old_version, &package.new_version) {
+                    (Some(old), Some(new)) => md.push_str(&format!(" {} → {}", old, new)),
+                    (Some(old), None) => md.push_str(&format!(" {}", old)),
+                    _ => {},
+                }
+                md.push_str(&format!(
+                    "\n\n```\n{}\n```\n",
+                    package.detail.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+
+        for (title, status) in [
+            ("Waiting", PackageStatus::Waiting),
+            ("Skipped", PackageStatus::Skipped),
+        ] {
+            if self.count(status) == 0 {
+                continue;
+            }
+            md.push_str(&format!(
+                "\n<details>\n<summary>{} ({})</summary>\n\n",
+                title,
+                self.count(status)
+            ));
+            for package in self.with_status(status) {
+                md.push_str(&format!(
+                    "- `{}`: {}\n",
+                    package.attr_path,
+                    package.detail.as_deref().unwrap_or_default()
+                ));
+            }
+            md.push_str("\n</details>\n");
+        }
+        md
+    }
+
+    /// Write the summary, as Markdown for `.md` files and as JSON otherwise
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let content = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("md" | "markdown") => self.to_markdown(),
+            _ => serde_json::to_string_pretty(self)? + "\n",
+        };
+        tokio::fs::write(path, content)
+            .await
+            .with_context(|| format!("Failed to write summary {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_summary() {
+        let started_at = DateTime::parse_from_rfc3339("2024-05-01T02:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let summary = RunSummary {
+            started_at,
+            finished_at: started_at + chrono::Duration::seconds(260),
+            dry_run: false,
+            interrupted: false,
+            evaluation_errors: 1,
+            backoff: 0,
+            packages: vec![
+                PackageSummary {
+                    pr_url: Some("https://github.com/o/r/pull/1".to_string()),
+                    ..PackageSummary::new("hello", PackageStatus::Updated)
+                        .versions("2.12", Some("2.13"))
+                },
+                PackageSummary {
+                    detail: Some(excerpt("error: builder failed\n")),
+                    ..PackageSummary::new("jq", PackageStatus::Failed).versions("1.6", Some("1.7"))
+                },
+                PackageSummary::new("curl", PackageStatus::UpToDate).versions("8.9.0", None),
+            ],
+        };
+
+        assert_eq!(summary.count(PackageStatus::Updated), 1);
+        let md = summary.to_markdown();
+        assert!(md.contains("took 4m 20s"));
+        assert!(md.contains("| `hello` | 2.12 | 2.13 | https://github.com/o/r/pull/1 |"));
+        assert!(md.contains("### `jq` 1.6 → 1.7\n\n```\nerror: builder failed\n```"));
+        assert!(md.contains("| Up to date | 1 |"));
+        assert!(!md.contains("Skipped"));
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["started_at"], "2024-05-01T02:00:00+00:00");
+        assert_eq!(json["packages"][0]["status"], "updated");
+        assert_eq!(json["packages"][2]["status"], "up_to_date");
+
+        let long = (1..=30)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(excerpt(&long).lines().next(), Some("11"));
+    }
+}
@@ -0,0 +1,151 @@
+//! Time spent in each phase of updating a package
+//!
+//! Recorded per package in the database and summarized after a run, to find the packages worth
+//! batching API lookups for or building remotely.
+
+use std::time::{Duration, Instant};
+
+/// Phase of an update attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdatePhase {
+    /// Evaluating the package's metadata
+    MetadataEval,
+    /// Looking up upstream releases
+    UpstreamFetch,
+    /// Rewriting the Nix file and refreshing its hashes
+    Rewrite,
+    /// Building the package, its passthru.tests and additional systems
+    Build,
+}
+
+/// Durations of the phases of an update attempt
+///
+/// Phases are timed from [`PhaseTimings::enter`] until the next phase is entered or
+/// [`PhaseTimings::finish`] is called, so a phase cut short by an error is still accounted for.
+/// Phases entered repeatedly accumulate.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseTimings {
+    pub metadata_eval: Duration,
+    pub upstream_fetch: Duration,
+    pub rewrite: Duration,
+    pub build: Duration,
+    current: Option<(UpdatePhase, Instant)>,
+}
+
+impl PhaseTimings {
+    /// Start timing a phase, ending the current one
+    pub fn enter(&mut self, phase: UpdatePhase) {
+        self.finish();
+        self.current = Some((phase, Instant::now()));
+    }
+
+    /// End the current phase, if any
+    pub fn finish(&mut self) {
+        if let Some((phase, started)) = self.current.take() {
+            self.add(phase, started.elapsed());
+        }
+    }
+
+    fn add(&mut self, phase: UpdatePhase, duration: Duration) {
+        let total = match phase {
+            UpdatePhase::MetadataEval => &mut self.metadata_eval,
+            UpdatePhase::UpstreamFetch => &mut self.upstream_fetch,
+            UpdatePhase::Rewrite => &mut self.rewrite,
+            UpdatePhase::Build => &mut self.build,
+        };
+        *total += duration;
+    }
+
+    pub fn total(&self) -> Duration {
+        self.metadata_eval + self.upstream_fetch + self.rewrite + self.build
+    }
+}
+
+/// Format a duration for humans, e.g. `4m 20s` or `1.5s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h {}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+/// Parse a duration given by humans, e.g. `90` seconds, `45m` or `1h30m`
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "Invalid duration '{}', expected e.g. 90s, 45m or 1h30m",
+            text
+        )
+    };
+    if let Ok(secs) = text.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut secs = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        secs += value * unit;
+        number.clear();
+    }
+    if !number.is_empty() || text.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_timings() {
+        let mut timings = PhaseTimings::default();
+        timings.add(UpdatePhase::Build, Duration::from_secs(3));
+        timings.add(UpdatePhase::Build, Duration::from_secs(2));
+        timings.add(UpdatePhase::MetadataEval, Duration::from_secs(1));
+        assert_eq!(timings.build, Duration::from_secs(5));
+        assert_eq!(timings.total(), Duration::from_secs(6));
+
+        timings.enter(UpdatePhase::Rewrite);
+        timings.finish();
+        timings.finish();
+        assert!(timings.current.is_none());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1.5s");
+        assert_eq!(format_duration(Duration::from_secs(260)), "4m 20s");
+        assert_eq!(
+            format_duration(Duration::from_secs(3 * 3600 + 120)),
+            "3h 2m"
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("45m"), Ok(Duration::from_secs(45 * 60)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2h5s"), Ok(Duration::from_secs(7205)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("10d").is_err());
+        assert!(parse_duration("1h30").is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! Support for nixpkgs-style `passthru.updateScript`
+//!
+//! An update script may be a single executable, a list of `[ script arg1 arg2 ]` or an attrset
+//! `{ command = [ ... ]; supportedFeatures = [ "commit" ]; attrPath = "..."; }`. Scripts are run
+//! with the `UPDATE_NIX_*` environment variables set, and scripts supporting the `commit`
+//! feature print a JSON list describing the commits to create.
+
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::nix::{eval_nix_expr, import_entry_point, nix_command};
+use crate::package::PackageQuery;
+
+/// An update script as declared by a package
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateScript {
+    /// Program followed by its arguments
+    pub command: Vec<String>,
+    /// Features of the update protocol supported by the script, e.g. `commit`
+    #[serde(default)]
+    pub supported_features: Vec<String>,
+    /// Attribute path the script updates, if it differs from the package's
+    pub attr_path: Option<String>,
+}
+
+/// A commit described by the JSON output of an update script
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptCommit {
+    pub attr_path: String,
+    pub old_version: String,
+    pub new_version: String,
+    #[serde(default)]
+    pub files: Vec<String>,
+    pub commit_message: Option<String>,
+    pub commit_body: Option<String>,
+}
+
+impl ScriptCommit {
+    /// Commit message for this change, defaulting to `attr: old -> new`
+    pub fn message(&self) -> String {
+        let title = self.commit_message.clone().unwrap_or_else(|| {
+            format!(
+                "{}: {} -> {}",
+                self.attr_path, self.old_version, self.new_version
+            )
+        });
+
+        match self.commit_body.as_deref() {
+            Some(body) if !body.is_empty() => format!("{}\n\n{}", title, body),
+            _ => title,
+        }
+    }
+}
+
+/// Outcome of running an update script
+#[derive(Debug, Clone)]
+pub struct UpdateScriptResult {
+    /// Exit code of the script, None if it was killed by a signal
+    pub exit_code: Option<i32>,
+    /// Version of the package before the script ran
+    pub old_version: String,
+    /// Commits reported by the script, empty if it doesn't support the `commit` feature
+    pub commits: Vec<ScriptCommit>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Timeout the script was killed after, None if it exited
+    pub timed_out: Option<Duration>,
+}
+
+impl UpdateScriptResult {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    /// Turn a failed run into an error carrying the end of the script's output
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.success() {
+            return Ok(());
+        }
+        if let Some(timeout) = self.timed_out {
+            anyhow::bail!("Update script timed out after {}s", timeout.as_secs());
+        }
+
+        let lines: Vec<&str> = self.stderr.lines().collect();
+        let tail = lines[lines.len().saturating_sub(20)..].join("\n");
+        anyhow::bail!(
+            "Update script failed with exit code: {}\n{}",
+            self.exit_code.unwrap_or(-1),
+            tail
+        )
+    }
+}
+
+impl UpdateScript {
+    /// Evaluate the update script of a package
+    ///
+    /// Returns None if the package doesn't define `updateScript`.
+    pub async fn eval(eval_entry_point: &str, attr_path: &str) -> anyhow::Result<Option<Self>> {
+        let package_set = import_entry_point(eval_entry_point);
+        let nix_expr = format!(
+            "with {}; let s = {}.updateScript; isSpec = builtins.isAttrs s && !(s ? outPath); cmd \
+             = if isSpec then s.command else s; in builtins.toJSON {{ command = map toString (if \
+             builtins.isList cmd then cmd else [ cmd ]); supportedFeatures = if isSpec then \
+             s.supportedFeatures or [ ] else [ ]; attrPath = if isSpec then s.attrPath or null \
+             else null; }}",
+            package_set, attr_path
+        );
+
+        let json = match eval_nix_expr(&nix_expr).await {
+            Ok(json) => json,
+            Err(e) => {
+                debug!("No update script found for {}", attr_path);
+                debug!("nix-instantiate stderr: {}", e);
+                return Ok(None);
+            },
+        };
+
+        let script: UpdateScript =
+            serde_json::from_str(&json).context("Failed to parse update script")?;
+        if script
+            .command
+            .first()
+            .is_none_or(|program| program.is_empty())
+        {
+            debug!("Update script command is empty");
+            return Ok(None);
+        }
+
+        Ok(Some(script))
+    }
+
+    /// Whether the script prints a JSON list of commits on stdout
+    pub fn supports_commit(&self) -> bool {
+        self.supported_features.iter().any(|f| f == "commit")
+    }
+}
+
+/// Build the derivations referenced by an update script so its command can be executed
+async fn realise_update_script(eval_entry_point: &str, attr_path: &str) {
+    let package_set = import_entry_point(eval_entry_point);
+    let nix_expr = format!(
+        "with {}; let s = {}.updateScript; cmd = if builtins.isAttrs s && !(s ? outPath) then \
+         s.command else s; in builtins.filter builtins.isAttrs (if builtins.isList cmd then cmd \
+         else [ cmd ])",
+        package_set, attr_path
+    );
+
+    let output = nix_command("nix-build")
+        .args(["--no-out-link", "-E", &nix_expr])
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => {},
+        Ok(output) => warn!(
+            "Failed to build update script of {}: {}",
+            attr_path,
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => warn!("Failed to build update script of {}: {}", attr_path, e),
+    }
+}
+
+/// Check for and run the update script of a package if it exists
+///
+/// The script runs in `working_dir` (e.g. a per-package worktree) if given, with its stdout and
+/// stderr captured. A script exiting unsuccessfully is reported through
+/// [`UpdateScriptResult::check`] so its output can be recorded.
+///
+/// Returns Ok(None) if no update script exists, or Err if it could not be executed.
+pub async fn run_update_script(
+    eval_entry_point: &str,
+    attr_path: &str,
+    working_dir: Option<&Path>,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Option<UpdateScriptResult>> {
+    info!("Checking for update script for {}", attr_path);
+
+    let Some(script) = UpdateScript::eval(eval_entry_point, attr_path).await? else {
+        return Ok(None);
+    };
+    info!("Found update script: {}", script.command.join(" "));
+
+    realise_update_script(eval_entry_point, attr_path).await;
+
+    // Environment expected by nixpkgs-style update scripts
+    let package = PackageQuery::new(eval_entry_point, attr_path);
+    let old_version = package.get_version().await.unwrap_or_default();
+    let name = package.get_attr("name").await.unwrap_or_default();
+    let pname = package.get_attr("pname").await.unwrap_or_default();
+    let script_attr_path = script.attr_path.as_deref().unwrap_or(attr_path);
+
+    debug!("Executing update script...");
+    let mut command = Command::new(&script.command[0]);
+    if let Some(dir) = working_dir {
+        command.current_dir(dir);
+    }
+    command
+        .args(&script.command[1..])
+        .env("UPDATE_NIX_ATTR_PATH", script_attr_path)
+        .env("UPDATE_NIX_NAME", &name)
+        .env("UPDATE_NIX_PNAME", &pname)
+        .env("UPDATE_NIX_OLD_VERSION", &old_version)
+        .process_group(0)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let output = command.output();
+    let output = match timeout {
+        // The script is killed when its output is dropped
+        Some(timeout) => match tokio::time::timeout(timeout, output).await {
+            Ok(output) => output,
+            Err(_) => {
+                warn!(
+                    "Update script for {} timed out after {}s",
+                    attr_path,
+                    timeout.as_secs()
+                );
+                return Ok(Some(UpdateScriptResult {
+                    exit_code: None,
+                    old_version,
+                    commits: Vec::new(),
+                    stdout: String::new(),
+                    stderr: format!("Update script timed out after {}s", timeout.as_secs()),
+                    timed_out: Some(timeout),
+                }));
+            },
+        },
+        None => output.await,
+    }
+    .with_context(|| format!("Failed to execute update script {}", script.command[0]))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    for line in stdout.lines().chain(stderr.lines()) {
+        debug!("[updateScript] {}", line);
+    }
+
+    let commits = if output.status.success() && script.supports_commit() {
+        parse_commit_output(&stdout)
+    } else {
+        Vec::new()
+    };
+
+    if output.status.success() {
+        info!("Update script completed successfully for {}", attr_path);
+    }
+    Ok(Some(UpdateScriptResult {
+        exit_code: output.status.code(),
+        old_version,
+        commits,
+        stdout,
+        stderr,
+        timed_out: None,
+    }))
+}
+
+/// Parse the JSON list of commits printed by scripts supporting the `commit` feature
+///
+/// The list is expected at the end of stdout, possibly preceded by other output.
+pub fn parse_commit_output(stdout: &str) -> Vec<ScriptCommit> {
+    let trimmed = stdout.trim_end();
+    let candidates = trimmed
+        .match_indices('[')
+        .map(|(i, _)| i)
+        .filter(|&i| i == 0 || trimmed[..i].ends_with('\n'));
+
+    for start in candidates {
+        if let Ok(commits) = serde_json::from_str::<Vec<ScriptCommit>>(&trimmed[start..]) {
+            return commits;
+        }
+    }
+
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commit_output() {
+        let stdout = r#"fetching latest release
+[
+  {
+    "attrPath": "hello",
+    "oldVersion": "2.12.1",
+    "newVersion": "2.12.2",
+    "files": ["pkgs/by-name/he/hello/package.nix"]
+  }
+]
+"#;
+
+        let commits = parse_commit_output(stdout);
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].attr_path, "hello");
+        assert_eq!(commits[0].new_version, "2.12.2");
+        assert_eq!(commits[0].files, vec!["pkgs/by-name/he/hello/package.nix"]);
+        assert_eq!(commits[0].message(), "hello: 2.12.1 -> 2.12.2");
+    }
+
+    #[test]
+    fn test_parse_commit_output_custom_message() {
+        let stdout = r#"[{"attrPath": "foo", "oldVersion": "1", "newVersion": "2", "commitMessage": "foo: update to 2", "commitBody": "Changelog: https://example.com"}]"#;
+
+        let commits = parse_commit_output(stdout);
+        assert_eq!(
+            commits[0].message(),
+            "foo: update to 2\n\nChangelog: https://example.com"
+        );
+    }
+
+    #[test]
+    fn test_update_script_result_check() {
+        let result = |exit_code| UpdateScriptResult {
+            exit_code,
+            old_version: "1.0".to_string(),
+            commits: Vec::new(),
+            stdout: String::new(),
+            stderr: "fetching\nerror: rate limited".to_string(),
+            timed_out: None,
+        };
+
+        assert!(result(Some(0)).check().is_ok());
+        let err = result(Some(1)).check().unwrap_err().to_string();
+        assert!(err.contains("exit code: 1"));
+        assert!(err.contains("rate limited"));
+        assert!(result(None).check().is_err());
+        let timed_out = UpdateScriptResult {
+            timed_out: Some(Duration::from_secs(600)),
+            ..result(None)
+        };
+        assert!(
+            timed_out
+                .check()
+                .unwrap_err()
+                .to_string()
+                .contains("timed out after 600s")
+        );
+    }
+
+    #[test]
+    fn test_parse_commit_output_empty() {
+        assert!(parse_commit_output("").is_empty());
+        assert!(parse_commit_output("[INFO] nothing to do\n").is_empty());
+        assert!(parse_commit_output("[]").is_empty());
+    }
+}
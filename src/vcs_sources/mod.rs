@@ -1,22 +1,153 @@
 //! VCS source abstraction for GitHub, GitLab, and other code hosting platforms
 
-use std::env;
-
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::{env, fmt};
+
+use chrono::{DateTime, Duration, Utc};
 use regex::Regex;
-use semver::Version;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
-use crate::github::{fetch_github_releases, fetch_github_tags, parse_github_url};
+use crate::database::Database;
+use crate::github::{
+    GithubRelease, compare_refs, fetch_github_release_feed, fetch_github_releases,
+    fetch_github_tags, parse_github_release_asset, parse_github_url,
+};
 use crate::gitlab::{fetch_gitlab_releases, fetch_gitlab_tags, parse_gitlab_url};
+use crate::libraries_io::{self, fetch_libraries_io_versions, parse_registry_url};
+use crate::mirrors::{fetch_mirror_versions, parse_mirror_url};
+use crate::plugin::{PluginInput, PluginVersion, SourcePlugin, fetch_plugin_versions};
 use crate::pypi::fetch_pypi_releases;
 
+mod version;
+
+pub use version::{Pep440, Semver, VersionScheme, VersionSchemeKind};
+
+pub use crate::pypi::PythonRequirements;
+pub use crate::withdrawn::WithdrawnVersions;
+
 /// Release information from a VCS source
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Release {
+    /// Tag of the release, e.g. `v1.2.3`
     pub tag_name: String,
+    /// Whether upstream marked the release as a prerelease
     pub is_prerelease: bool,
+    /// Release notes, if the release was published with a description
+    pub notes: Option<String>,
+    /// When the release was published, None for plain tags
+    pub published_at: Option<DateTime<Utc>>,
+}
+
+/// Database caching the data fetched from upstream, and how long it stays fresh
+static RELEASE_CACHE: OnceLock<(Database, Duration)> = OnceLock::new();
+
+/// Cache the release data fetched from upstream in the database for `ttl`
+///
+/// Packages sharing an upstream, and consecutive runs, then reuse the data instead of querying
+/// the APIs again. Must be called before fetching any release.
+pub fn set_release_cache(db: Database, ttl: Duration) {
+    if RELEASE_CACHE.set((db, ttl)).is_err() {
+        warn!("Release cache was already set");
+    }
+}
+
+tokio::task_local! {
+    /// Time the cached releases must have been fetched after, if the check was requested
+    static FETCHED_AFTER: Option<DateTime<Utc>>;
+}
+
+/// Run `check` ignoring the releases cached before `requested_at`
+///
+/// Checks requested because a release was published, e.g. by a webhook, would otherwise not see
+/// it until the cached releases expire. Releases fetched by the check, or by the checks of other
+/// packages requested at the same time, are still reused.
+pub async fn with_releases_fetched_after<F: Future>(
+    requested_at: Option<DateTime<Utc>>,
+    check: F,
+) -> F::Output {
+    FETCHED_AFTER.scope(requested_at, check).await
+}
+
+/// Fetch the data of an upstream source, e.g. `github-releases:owner/repo`, through the release
+/// cache
+///
+/// Failed fetches aren't cached, and cache errors fall back to fetching.
+async fn cached<T>(
+    source: &str,
+    fetch: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let Some((db, ttl)) = RELEASE_CACHE.get() else {
+        return fetch.await;
+    };
+    let max_age = match FETCHED_AFTER.try_with(|requested_at| *requested_at) {
+        Ok(Some(requested_at)) => (Utc::now() - requested_at).min(*ttl),
+        _ => *ttl,
+    };
+
+    match db.get_cached_releases(source, max_age).await {
+        Ok(Some(data)) => match serde_json::from_str(&data) {
+            Ok(value) => {
+                debug!("Using cached releases of {}", source);
+                return Ok(value);
+            },
+            Err(e) => debug!("Ignoring unreadable cached releases of {}: {}", source, e),
+        },
+        Ok(None) => {},
+        Err(e) => warn!("Failed to read cached releases of {}: {}", source, e),
+    }
+
+    let value = fetch.await?;
+    match serde_json::to_string(&value) {
+        Ok(data) => {
+            if let Err(e) = db.record_releases(source, &data).await {
+                warn!("Failed to cache releases of {}: {}", source, e);
+            }
+        },
+        Err(e) => debug!("Failed to serialize releases of {}: {}", source, e),
+    }
+    Ok(value)
+}
+
+/// Parse an RFC 3339 timestamp as returned by the GitHub, GitLab and PyPI APIs
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Error returned when no release of an upstream can be proposed for the current version
+///
+/// Other errors of [`UpstreamSource::get_compatible_release`] are failures to look up the
+/// releases, which may not persist.
+#[derive(Debug)]
+pub struct NoCompatibleRelease {
+    /// Current version of the package
+    pub current_version: String,
+    /// Strategy the releases were filtered with
+    pub strategy: SemverStrategy,
+}
+
+impl fmt::Display for NoCompatibleRelease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "No compatible releases found for version {} with strategy {:?}",
+            self.current_version, self.strategy
+        )
+    }
 }
 
+impl std::error::Error for NoCompatibleRelease {}
+
 /// Semver update strategy
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SemverStrategy {
@@ -30,9 +161,11 @@ pub enum SemverStrategy {
     Patch,
 }
 
-impl SemverStrategy {
+impl FromStr for SemverStrategy {
+    type Err = anyhow::Error;
+
     /// Parse strategy from string
-    pub fn from_str(s: &str) -> anyhow::Result<Self> {
+    fn from_str(s: &str) -> anyhow::Result<Self> {
         match s.to_lowercase().as_str() {
             "latest" => Ok(SemverStrategy::Latest),
             "major" => Ok(SemverStrategy::Major),
@@ -46,12 +179,181 @@ impl SemverStrategy {
     }
 }
 
+impl SemverStrategy {
+    /// The strategy accepting the fewest versions of `self` and `other`
+    pub fn stricter(self, other: Self) -> Self {
+        let rank = |strategy: Self| match strategy {
+            SemverStrategy::Latest | SemverStrategy::Major => 0,
+            SemverStrategy::Minor => 1,
+            SemverStrategy::Patch => 2,
+        };
+        if rank(other) > rank(self) {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// Which lists of a code hosting platform the versions of a package are looked up in
+///
+/// Some projects only publish releases for major milestones but tag every patch, so their tags
+/// are preferred or merged with the releases.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseListing {
+    /// Releases, falling back to tags if the releases can't be listed
+    #[default]
+    Releases,
+    /// Tags only
+    Tags,
+    /// Releases and tags without a release
+    Merged,
+}
+
+/// Whether a GitHub release ships the asset the package is downloaded from
+///
+/// The asset of a release is expected to be named like the current one, with the current version
+/// replaced by the release's, e.g. `tool-1.1.0-x86_64.AppImage` for `tool-1.0.0-x86_64.AppImage`.
+/// Projects sometimes publish a release before its binaries are built, or stop building them for
+/// some platform. Assets spelling the version differently, e.g. `tool_1_0_0.zip`, can't be
+/// predicted and are assumed to be shipped.
+fn ships_asset(
+    release: &GithubRelease,
+    asset: Option<&str>,
+    current_version: &str,
+    tag_filter: &TagFilter,
+) -> bool {
+    let (Some(asset), Some(version)) = (asset, tag_filter.version_of(&release.tag_name)) else {
+        return true;
+    };
+    if !asset.contains(current_version) {
+        return true;
+    }
+    let expected = asset.replace(current_version, &version);
+    let ships = release.assets.iter().any(|a| a.name == expected);
+    if !ships {
+        debug!(
+            "Skipping release {} without asset {}",
+            release.tag_name, expected
+        );
+    }
+    ships
+}
+
+/// Releases followed by the tags which weren't published as a release
+fn merge_releases(mut releases: Vec<Release>, tags: Vec<Release>) -> Vec<Release> {
+    for tag in tags {
+        if !releases.iter().any(|r| r.tag_name == tag.tag_name) {
+            releases.push(tag);
+        }
+    }
+    releases
+}
+
+/// Selects the tags belonging to a package and extracts their version
+///
+/// Monorepos tag the releases of every component in the same repository, e.g. `cli/v2.3.0` and
+/// `server/v1.4.0`, so only tags starting with the package's prefix and matching its regex are
+/// considered. The default filter accepts every tag.
+#[derive(Debug, Clone, Default)]
+pub struct TagFilter {
+    prefix: Option<String>,
+    /// Applied after stripping the prefix. The first capture group, if any, is the version
+    regex: Option<Regex>,
+    /// Only accept this version, e.g. the version of an approved update plan
+    pinned_version: Option<String>,
+}
+
+impl TagFilter {
+    /// Filter keeping the tags starting with `prefix` and matching `regex`
+    pub fn new(prefix: Option<String>, regex: Option<Regex>) -> Self {
+        Self {
+            prefix,
+            regex,
+            pinned_version: None,
+        }
+    }
+
+    /// Only accept the tags of `version`, if set
+    pub fn pinned(mut self, version: Option<String>) -> Self {
+        self.pinned_version = version;
+        self
+    }
+
+    /// Version of a tag, None if the tag doesn't belong to the package
+    pub fn version_of<'a>(&self, tag: &'a str) -> Option<Cow<'a, str>> {
+        let tag = match &self.prefix {
+            Some(prefix) => tag.strip_prefix(prefix.as_str())?,
+            None => tag,
+        };
+        let version = match &self.regex {
+            Some(regex) => {
+                let captures = regex.captures(tag)?;
+                let version = captures.get(1).or_else(|| captures.get(0))?;
+                extract_version_from_tag(version.as_str())
+            },
+            None => extract_version_from_tag(tag),
+        };
+        match &self.pinned_version {
+            Some(pinned) if **pinned != *version => None,
+            _ => Some(version),
+        }
+    }
+
+    /// Version of a release selected by this filter
+    pub fn release_version(&self, release: &Release) -> String {
+        self.version_of(&release.tag_name)
+            .unwrap_or_else(|| extract_version_from_tag(&release.tag_name))
+            .into_owned()
+    }
+}
+
 /// Upstream VCS source (GitHub, GitLab, PyPI, etc.)
 #[derive(Debug)]
 pub enum UpstreamSource {
-    GitHub { owner: String, repo: String },
-    GitLab { owner: String, project: String },
-    PyPI { pname: String },
+    /// Repository on GitHub
+    GitHub {
+        /// User or organization owning the repository
+        owner: String,
+        /// Name of the repository
+        repo: String,
+        /// Release asset the source is downloaded from, e.g. `tool-1.0.0-x86_64.AppImage`
+        asset: Option<String>,
+    },
+    /// Project on GitLab
+    GitLab {
+        /// User or group owning the project
+        owner: String,
+        /// Name of the project
+        project: String,
+    },
+    /// Package on the Python Package Index
+    PyPI {
+        /// Name of the package on PyPI
+        pname: String,
+    },
+    /// Package of a registry looked up on libraries.io, e.g. `cargo` or `npm`
+    LibrariesIo {
+        /// Registry of the package, as named by libraries.io
+        platform: String,
+        /// Name of the package in the registry
+        name: String,
+    },
+    /// File on a mirror network, e.g. `mirror://gnu/hello/hello-2.12.1.tar.gz`
+    Mirror {
+        /// Name of the mirror network, e.g. `gnu`
+        mirror: String,
+        /// Path of the file on the mirrors
+        path: String,
+    },
+    /// Versions listed by an external command, see [`crate::plugin`]
+    Plugin {
+        /// Command listing the versions
+        plugin: SourcePlugin,
+        /// Package passed to the command
+        input: PluginInput,
+    },
 }
 
 /// Parse PyPI URL to extract package name
@@ -113,7 +415,8 @@ fn parse_pypi_url(url: &str) -> Option<String> {
 impl UpstreamSource {
     /// Parse a URL and return the appropriate UpstreamSource
     ///
-    /// Tries to parse the URL as GitHub first, then GitLab, then PyPI.
+    /// Tries to parse the URL as GitHub first, then GitLab, then PyPI, then as a mirror network,
+    /// then as another registry known to libraries.io if an API key is configured.
     ///
     /// # Arguments
     /// * `url` - Source URL to parse
@@ -125,14 +428,22 @@ impl UpstreamSource {
             Some(UpstreamSource::GitHub {
                 owner: github_repo.owner,
                 repo: github_repo.repo,
+                asset: parse_github_release_asset(url),
             })
         } else if let Some(gitlab_project) = parse_gitlab_url(url) {
             Some(UpstreamSource::GitLab {
                 owner: gitlab_project.owner,
                 project: gitlab_project.project,
             })
+        } else if let Some(pypi_pname) = parse_pypi_url(url) {
+            Some(UpstreamSource::PyPI { pname: pypi_pname })
+        } else if let Some((mirror, path)) = parse_mirror_url(url) {
+            Some(UpstreamSource::Mirror { mirror, path })
+        } else if libraries_io::is_configured() {
+            parse_registry_url(url)
+                .map(|(platform, name)| UpstreamSource::LibrariesIo { platform, name })
         } else {
-            parse_pypi_url(url).map(|pypi_pname| UpstreamSource::PyPI { pname: pypi_pname })
+            None
         }
     }
 
@@ -147,20 +458,40 @@ impl UpstreamSource {
     /// # Arguments
     /// * `current_version` - The current version to compare against
     /// * `strategy` - The semver update strategy to apply
+    /// * `tag_filter` - Selects the tags of the package, see [`TagFilter`]
+    /// * `scheme` - How versions of the package are compared
+    /// * `listing` - Whether releases or tags of GitHub and GitLab sources are looked up
+    /// * `python` - Interpreter and artifact kind PyPI releases must support to be built
+    /// * `withdrawn` - Versions withdrawn from the package's registry, PyPI yanks are added
+    /// * `min_age` - Releases published more recently are not proposed yet
     ///
     /// # Returns
     /// The best compatible release information
     ///
     /// # Errors
-    /// Returns an error if the API request fails or no compatible releases are found
+    /// Returns an error if the API request fails, or [`NoCompatibleRelease`] if no compatible
+    /// releases are found
+    #[allow(clippy::too_many_arguments)]
     pub async fn get_compatible_release(
         &self,
         current_version: &str,
         strategy: SemverStrategy,
+        tag_filter: &TagFilter,
+        scheme: VersionSchemeKind,
+        listing: ReleaseListing,
+        python: &PythonRequirements,
+        withdrawn: &WithdrawnVersions,
+        min_age: Option<Duration>,
     ) -> anyhow::Result<Release> {
-        match self {
-            UpstreamSource::GitHub { owner, repo } => {
+        let scheme = scheme.resolve(matches!(self, UpstreamSource::PyPI { .. }), current_version);
+        let mut withdrawn = withdrawn.clone();
+        let releases: Vec<Release> = match self {
+            UpstreamSource::GitHub { owner, repo, asset } => {
                 let token = env::var("GITHUB_TOKEN").ok();
+                // Only assets named after the version can be looked up in other releases
+                let asset = asset
+                    .as_deref()
+                    .filter(|asset| asset.contains(current_version));
 
                 if token.is_none() {
                     warn!(
@@ -169,35 +500,77 @@ impl UpstreamSource {
                     );
                 }
 
-                // Try to fetch all releases first
-                let all_releases = fetch_github_releases(owner, repo, token.as_deref()).await;
+                // Try to fetch all releases first, unless only tags are looked up
+                let all_releases = match listing {
+                    ReleaseListing::Tags => None,
+                    _ => cached(
+                        &format!("github-releases:{}/{}", owner, repo),
+                        fetch_github_releases(owner, repo, token.as_deref()),
+                    )
+                    .await
+                    .ok(),
+                };
 
-                let releases: Vec<Release> = match all_releases {
-                    Ok(gh_releases) => {
-                        // Convert GitHub releases to our Release struct
-                        gh_releases
-                            .into_iter()
-                            .map(|r| Release {
-                                tag_name: r.tag_name,
-                                is_prerelease: r.prerelease,
-                            })
-                            .collect()
-                    },
-                    Err(_) => {
+                // Convert GitHub releases to our Release struct, skipping releases which don't
+                // ship the asset the package is downloaded from
+                let releases = all_releases.map(|gh_releases| {
+                    let (shipping, missing): (Vec<_>, Vec<_>) = gh_releases
+                        .into_iter()
+                        .partition(|r| ships_asset(r, asset, current_version, tag_filter));
+                    let is_newer = |r: &GithubRelease| {
+                        tag_filter.version_of(&r.tag_name).is_some_and(|version| {
+                            scheme.compare(&version, current_version) == Some(Ordering::Greater)
+                        })
+                    };
+                    if !shipping.iter().any(is_newer) {
+                        if let Some(skipped) = missing.iter().find(|r| is_newer(r)) {
+                            warn!(
+                                "No release of {}/{} newer than {} ships {}, e.g. {}",
+                                owner,
+                                repo,
+                                current_version,
+                                asset.unwrap_or_default(),
+                                skipped.tag_name
+                            );
+                        }
+                    }
+                    shipping
+                        .into_iter()
+                        .map(|r| Release {
+                            tag_name: r.tag_name,
+                            is_prerelease: r.prerelease,
+                            notes: r.body,
+                            published_at: r.published_at.as_deref().and_then(parse_timestamp),
+                        })
+                        .collect()
+                });
+
+                match releases {
+                    Some(releases) if listing == ReleaseListing::Releases => releases,
+                    // Tags without a release have no assets
+                    Some(releases) if asset.is_some() => releases,
+                    releases => {
                         // Fallback to tags if releases endpoint fails
-                        debug!("No releases found, falling back to tags");
-                        let tags = fetch_github_tags(owner, repo, token.as_deref()).await?;
-                        tags.into_iter()
+                        if releases.is_none() && listing != ReleaseListing::Tags {
+                            debug!("No releases found, falling back to tags");
+                        }
+                        let tags = cached(
+                            &format!("github-tags:{}/{}", owner, repo),
+                            fetch_github_tags(owner, repo, token.as_deref()),
+                        )
+                        .await?;
+                        let tags = tags
+                            .into_iter()
                             .map(|t| Release {
                                 tag_name: t.name,
                                 is_prerelease: false,
+                                notes: None,
+                                published_at: None,
                             })
-                            .collect()
+                            .collect();
+                        merge_releases(releases.unwrap_or_default(), tags)
                     },
-                };
-
-                // Filter and find best match
-                find_best_release(&releases, current_version, strategy)
+                }
             },
             UpstreamSource::GitLab { owner, project } => {
                 let token = env::var("GITLAB_TOKEN").ok();
@@ -209,39 +582,59 @@ impl UpstreamSource {
                     );
                 }
 
-                // Try to fetch all releases first
-                let all_releases = fetch_gitlab_releases(owner, project, token.as_deref()).await;
+                // Try to fetch all releases first, unless only tags are looked up
+                let all_releases = match listing {
+                    ReleaseListing::Tags => None,
+                    _ => cached(
+                        &format!("gitlab-releases:{}/{}", owner, project),
+                        fetch_gitlab_releases(owner, project, token.as_deref()),
+                    )
+                    .await
+                    .ok(),
+                };
 
-                let releases: Vec<Release> = match all_releases {
-                    Ok(gl_releases) => {
-                        // Convert GitLab releases to our Release struct
-                        gl_releases
-                            .into_iter()
-                            .map(|r| Release {
-                                tag_name: r.tag_name,
-                                is_prerelease: r.upcoming_release,
-                            })
-                            .collect()
-                    },
-                    Err(_) => {
+                // Convert GitLab releases to our Release struct
+                let releases = all_releases.map(|gl_releases| {
+                    gl_releases
+                        .into_iter()
+                        .map(|r| Release {
+                            tag_name: r.tag_name,
+                            is_prerelease: r.upcoming_release,
+                            notes: r.description,
+                            published_at: r.released_at.as_deref().and_then(parse_timestamp),
+                        })
+                        .collect()
+                });
+
+                match releases {
+                    Some(releases) if listing == ReleaseListing::Releases => releases,
+                    releases => {
                         // Fallback to tags if releases endpoint fails
-                        debug!("No releases found, falling back to tags");
-                        let tags = fetch_gitlab_tags(owner, project, token.as_deref()).await?;
-                        tags.into_iter()
+                        if releases.is_none() && listing != ReleaseListing::Tags {
+                            debug!("No releases found, falling back to tags");
+                        }
+                        let tags = cached(
+                            &format!("gitlab-tags:{}/{}", owner, project),
+                            fetch_gitlab_tags(owner, project, token.as_deref()),
+                        )
+                        .await?;
+                        let tags = tags
+                            .into_iter()
                             .map(|t| Release {
                                 tag_name: t.name,
                                 is_prerelease: false,
+                                notes: None,
+                                published_at: None,
                             })
-                            .collect()
+                            .collect();
+                        merge_releases(releases.unwrap_or_default(), tags)
                     },
-                };
-
-                // Filter and find best match
-                find_best_release(&releases, current_version, strategy)
+                }
             },
             UpstreamSource::PyPI { pname } => {
                 // PyPI doesn't require authentication tokens
-                let pypi_response = fetch_pypi_releases(pname).await?;
+                let pypi_response =
+                    cached(&format!("pypi:{}", pname), fetch_pypi_releases(pname)).await?;
 
                 // Convert PyPI releases to our Release struct
                 // PyPI returns a HashMap where keys are version strings
@@ -250,16 +643,143 @@ impl UpstreamSource {
                 for (version, artifacts) in pypi_response.releases {
                     // Check if this version has been yanked (any artifact yanked means version is
                     // yanked)
-                    let is_yanked = artifacts.iter().any(|a| a.yanked);
+                    if artifacts.iter().any(|a| a.yanked) {
+                        withdrawn.insert(&version);
+                    }
+
+                    // Releases which can't be built in the package set
+                    if !python.is_buildable(&version, &artifacts) {
+                        continue;
+                    }
 
+                    // The first upload publishes the release
+                    let published_at = artifacts
+                        .iter()
+                        .filter_map(|a| a.upload_time_iso_8601.as_deref().and_then(parse_timestamp))
+                        .min();
                     releases.push(Release {
                         tag_name: version,
-                        is_prerelease: is_yanked, // Treat yanked releases as prereleases
+                        is_prerelease: false,
+                        notes: None,
+                        published_at,
                     });
                 }
 
-                // Filter and find best match
-                find_best_release(&releases, current_version, strategy)
+                releases
+            },
+            UpstreamSource::LibrariesIo { platform, name } => cached(
+                &format!("libraries.io:{}/{}", platform, name),
+                fetch_libraries_io_versions(platform, name),
+            )
+            .await?
+            .into_iter()
+            .map(|v| Release {
+                tag_name: v.number,
+                is_prerelease: false,
+                notes: None,
+                published_at: v.published_at.as_deref().and_then(parse_timestamp),
+            })
+            .collect(),
+            UpstreamSource::Mirror { mirror, path } => {
+                // The directory listed depends on the current version
+                cached(
+                    &format!("mirror:{}/{}@{}", mirror, path, current_version),
+                    fetch_mirror_versions(mirror, path, current_version),
+                )
+                .await?
+                .into_iter()
+                .map(|version| Release {
+                    tag_name: version,
+                    is_prerelease: false,
+                    notes: None,
+                    published_at: None,
+                })
+                .collect()
+            },
+            UpstreamSource::Plugin { plugin, input } => cached(
+                &format!("plugin:{}@{}", plugin.command.join(" "), input.attr_path),
+                fetch_plugin_versions(plugin, input),
+            )
+            .await?
+            .into_iter()
+            .map(|version| match version {
+                PluginVersion::Version(version) => Release {
+                    tag_name: version,
+                    is_prerelease: false,
+                    notes: None,
+                    published_at: None,
+                },
+                PluginVersion::Release {
+                    version,
+                    prerelease,
+                    published_at,
+                    notes,
+                } => Release {
+                    tag_name: version,
+                    is_prerelease: prerelease,
+                    notes,
+                    published_at: published_at.as_deref().and_then(parse_timestamp),
+                },
+            })
+            .collect(),
+        };
+
+        // Never propose a withdrawn version, the next best release is picked instead
+        let releases: Vec<Release> = releases
+            .into_iter()
+            .filter(|r| {
+                let version = tag_filter
+                    .version_of(&r.tag_name)
+                    .unwrap_or(Cow::Borrowed(&r.tag_name));
+                let is_withdrawn = withdrawn.contains(&version);
+                if is_withdrawn {
+                    debug!("Skipping withdrawn release {}", r.tag_name);
+                }
+                !is_withdrawn
+            })
+            .filter(|r| {
+                let is_too_recent = is_too_recent(r, min_age, Utc::now());
+                if is_too_recent {
+                    debug!(
+                        "Skipping release {} until it is older than the minimum age",
+                        r.tag_name
+                    );
+                }
+                !is_too_recent
+            })
+            .collect();
+
+        // Filter and find best match
+        find_best_release(&releases, current_version, strategy, tag_filter, scheme)
+    }
+
+    /// Whether a release newer than `current_version` may have been published
+    ///
+    /// Checks the `releases.atom` feed of GitHub repositories, which costs no API requests. Only
+    /// returns false if the feed lists releases of the package and none of them is newer, every
+    /// other source or failure is assumed to possibly have a newer release. The feed doesn't list
+    /// tags without a release, so packages updated from their tags are always assumed to.
+    pub async fn may_have_newer_release(
+        &self,
+        current_version: &str,
+        tag_filter: &TagFilter,
+        scheme: VersionSchemeKind,
+        listing: ReleaseListing,
+    ) -> bool {
+        if listing != ReleaseListing::Releases {
+            return true;
+        }
+        let UpstreamSource::GitHub { owner, repo, .. } = self else {
+            return true;
+        };
+        match fetch_github_release_feed(owner, repo).await {
+            Ok(tags) => {
+                let scheme = scheme.resolve(false, current_version);
+                feed_has_newer_release(&tags, current_version, tag_filter, scheme)
+            },
+            Err(e) => {
+                debug!("Failed to fetch release feed of {}/{}: {}", owner, repo, e);
+                true
             },
         }
     }
@@ -274,25 +794,189 @@ impl UpstreamSource {
     /// # Returns
     /// Clean version string
     pub fn get_version(release: &Release) -> String {
-        extract_version_from_tag(&release.tag_name).to_string()
+        extract_version_from_tag(&release.tag_name).into_owned()
+    }
+
+    /// URL comparing two tags on the code hosting platform, None for package registries
+    pub fn compare_url(&self, old_tag: &str, new_tag: &str) -> Option<String> {
+        match self {
+            UpstreamSource::GitHub { owner, repo, .. } => Some(format!(
+                "https://github.com/{}/{}/compare/{}...{}",
+                owner, repo, old_tag, new_tag
+            )),
+            UpstreamSource::GitLab { owner, project } => Some(format!(
+                "https://gitlab.com/{}/{}/-/compare/{}...{}",
+                owner, project, old_tag, new_tag
+            )),
+            UpstreamSource::PyPI { .. }
+            | UpstreamSource::LibrariesIo { .. }
+            | UpstreamSource::Mirror { .. }
+            | UpstreamSource::Plugin { .. } => None,
+        }
+    }
+
+    /// Tag of the current version, assuming it's named like the tag of `release`
+    pub fn previous_tag(release: &Release, current_version: &str) -> String {
+        let new_version = Self::get_version(release);
+        if release.tag_name.contains(&new_version) {
+            release.tag_name.replacen(&new_version, current_version, 1)
+        } else {
+            // Tags separating the components with underscores, e.g. `curl-8_5_0`
+            release.tag_name.replacen(
+                &new_version.replace('.', "_"),
+                &current_version.replace('.', "_"),
+                1,
+            )
+        }
+    }
+
+    /// Number of commits and top-level entries changed between the current version and
+    /// `release`, None for other sources than GitHub or if the comparison can't be fetched
+    pub async fn compare_summary(
+        &self,
+        release: &Release,
+        current_version: &str,
+    ) -> Option<String> {
+        let UpstreamSource::GitHub { owner, repo, .. } = self else {
+            return None;
+        };
+        let old_tag = Self::previous_tag(release, current_version);
+        let token = env::var("GITHUB_TOKEN").ok();
+        match compare_refs(owner, repo, &old_tag, &release.tag_name, token.as_deref()).await {
+            Ok(comparison) => Some(comparison.summary()),
+            Err(e) => {
+                debug!(
+                    "Failed to compare {} and {} of {}/{}: {}",
+                    old_tag, release.tag_name, owner, repo, e
+                );
+                None
+            },
+        }
+    }
+
+    /// Markdown section with the release notes of `release` and a link comparing it to the
+    /// current version, None if there is neither
+    pub fn release_notes_section(
+        &self,
+        release: &Release,
+        current_version: &str,
+    ) -> Option<String> {
+        let old_tag = Self::previous_tag(release, current_version);
+        let compare_url = self.compare_url(&old_tag, &release.tag_name);
+        let notes = release
+            .notes
+            .as_deref()
+            .map(str::trim)
+            .filter(|notes| !notes.is_empty());
+
+        if notes.is_none() && compare_url.is_none() {
+            return None;
+        }
+
+        let mut section = String::from("## Release Notes");
+        if let Some(notes) = notes {
+            section.push_str(&format!(
+                "\n\n<details>\n<summary>{}</summary>\n\n{}\n\n</details>",
+                release.tag_name,
+                truncate_release_notes(notes)
+            ));
+        }
+        if let Some(url) = compare_url {
+            section.push_str(&format!("\n\n**Compare:** {}", url));
+        }
+        Some(section)
     }
 
     /// Get a human-readable description of this source
     pub fn description(&self) -> String {
         match self {
-            UpstreamSource::GitHub { owner, repo } => format!("GitHub repo: {}/{}", owner, repo),
+            UpstreamSource::GitHub { owner, repo, .. } => {
+                format!("GitHub repo: {}/{}", owner, repo)
+            },
             UpstreamSource::GitLab { owner, project } => {
                 format!("GitLab project: {}/{}", owner, project)
             },
             UpstreamSource::PyPI { pname } => format!("PyPI package: {}", pname),
+            UpstreamSource::LibrariesIo { platform, name } => {
+                format!("{} package (libraries.io): {}", platform, name)
+            },
+            UpstreamSource::Mirror { mirror, path } => format!("{} mirror: {}", mirror, path),
+            UpstreamSource::Plugin { plugin, .. } => {
+                format!("Source command: {}", plugin.command.join(" "))
+            },
+        }
+    }
+}
+
+/// Maximum number of lines of release notes included in PR bodies
+const MAX_RELEASE_NOTES_LINES: usize = 40;
+
+/// Maximum number of characters of release notes included in PR bodies
+const MAX_RELEASE_NOTES_CHARS: usize = 4000;
+
+/// Trim release notes to an excerpt short enough for a PR body
+fn truncate_release_notes(notes: &str) -> String {
+    let mut excerpt = String::new();
+    let mut truncated = false;
+
+    for (i, line) in notes.lines().enumerate() {
+        if i >= MAX_RELEASE_NOTES_LINES
+            || excerpt.chars().count() + line.chars().count() > MAX_RELEASE_NOTES_CHARS
+        {
+            truncated = true;
+            break;
+        }
+        if i > 0 {
+            excerpt.push('\n');
         }
+        excerpt.push_str(line);
+    }
+
+    if truncated {
+        excerpt.push_str("\n\n*(truncated)*");
     }
+    excerpt
+}
+
+/// Whether a release was published less than `min_age` before `now`
+///
+/// Releases without a publish time, e.g. plain tags, are never considered too recent.
+fn is_too_recent(release: &Release, min_age: Option<Duration>, now: DateTime<Utc>) -> bool {
+    match (release.published_at, min_age) {
+        (Some(published_at), Some(min_age)) => now - published_at < min_age,
+        _ => false,
+    }
+}
+
+/// Whether the tags of a release feed include a newer release of the package, or none of its
+/// releases at all
+fn feed_has_newer_release(
+    tags: &[String],
+    current_version: &str,
+    tag_filter: &TagFilter,
+    scheme: &dyn VersionScheme,
+) -> bool {
+    let versions: Vec<Cow<str>> = tags
+        .iter()
+        .filter_map(|tag| tag_filter.version_of(tag))
+        .collect();
+    versions.is_empty()
+        || versions.iter().any(|version| {
+            is_version_acceptable_with_scheme(
+                current_version,
+                version,
+                SemverStrategy::Latest,
+                scheme,
+            )
+            .unwrap_or(true)
+        })
 }
 
 /// Find the best compatible release from a list based on semver strategy
 ///
 /// Filters releases by:
-/// 1. Excluding prereleases
+/// 1. Excluding prereleases, including versions the scheme considers prereleases such as PEP 440
+///    `rc` and `dev` releases, and tags rejected by the tag filter
 /// 2. Checking version compatibility with strategy
 /// 3. Returns the newest compatible version
 ///
@@ -300,55 +984,50 @@ impl UpstreamSource {
 /// * `releases` - List of releases to filter
 /// * `current_version` - Current version to compare against
 /// * `strategy` - Semver strategy to apply
+/// * `tag_filter` - Selects the tags of the package and extracts their version
+/// * `scheme` - How versions are compared
 ///
 /// # Returns
 /// The best matching release
 ///
 /// # Errors
-/// Returns an error if no compatible releases are found
+/// Returns [`NoCompatibleRelease`] if no compatible releases are found
 fn find_best_release(
     releases: &[Release],
     current_version: &str,
     strategy: SemverStrategy,
+    tag_filter: &TagFilter,
+    scheme: &dyn VersionScheme,
 ) -> anyhow::Result<Release> {
     // Filter out prereleases and find compatible versions
-    let mut compatible_releases: Vec<&Release> = releases
+    let mut compatible_releases: Vec<(&Release, Cow<str>)> = releases
         .iter()
         .filter(|r| !r.is_prerelease)
-        .filter(|r| {
-            let version = extract_version_from_tag(&r.tag_name);
-            is_version_acceptable(current_version, version, strategy).unwrap_or(false)
+        .filter_map(|r| Some((r, tag_filter.version_of(&r.tag_name)?)))
+        .filter(|(_, version)| !scheme.is_prerelease(version))
+        .filter(|(_, version)| {
+            is_version_acceptable_with_scheme(current_version, version, strategy, scheme)
+                .unwrap_or(false)
         })
         .collect();
 
     if compatible_releases.is_empty() {
-        anyhow::bail!(
-            "No compatible releases found for version {} with strategy {:?}",
-            current_version,
-            strategy
-        );
+        return Err(NoCompatibleRelease {
+            current_version: current_version.to_string(),
+            strategy,
+        }
+        .into());
     }
 
-    // Sort by version (newest first)
-    compatible_releases.sort_by(|a, b| {
-        let version_a = extract_version_from_tag(&a.tag_name);
-        let version_b = extract_version_from_tag(&b.tag_name);
-
-        // Try to parse as semver for proper sorting
-        match (
-            Version::parse(version_a.trim_start_matches('v')),
-            Version::parse(version_b.trim_start_matches('v')),
-        ) {
-            (Ok(va), Ok(vb)) => vb.cmp(&va), // Reverse order for newest first
-            _ => version_b.cmp(version_a),   // Fallback to string comparison
-        }
+    // Sort by version (newest first), falling back to string comparison
+    compatible_releases.sort_by(|(_, version_a), (_, version_b)| {
+        scheme
+            .compare(version_b, version_a)
+            .unwrap_or_else(|| version_b.cmp(version_a))
     });
 
     // Return the best (first after sorting) release
-    Ok(Release {
-        tag_name: compatible_releases[0].tag_name.clone(),
-        is_prerelease: compatible_releases[0].is_prerelease,
-    })
+    Ok(compatible_releases[0].0.clone())
 }
 
 /// Extract version from tag name by pruning leading non-numerical characters
@@ -358,6 +1037,8 @@ fn find_best_release(
 /// and truncates everything from '-unstable' onwards if present.
 /// This handles various tag naming conventions like "v1.0.0", "release-1.0.0", "version-2.3.4",
 /// etc., as well as unstable versions like "1.2.3-unstable-2024-01-01".
+/// Versions separating their components with underscores instead of dots, like "REL_1_2_3",
+/// "curl-8_5_0" or "OpenSSL_3_2_1", are converted to dotted versions.
 ///
 /// # Arguments
 /// * `tag` - The tag name to extract version from
@@ -379,22 +1060,48 @@ fn find_best_release(
 ///     extract_version_from_tag("v2.0.0-unstable-2024-01-01"),
 ///     "2.0.0"
 /// );
+/// assert_eq!(extract_version_from_tag("curl-8_5_0"), "8.5.0");
 /// ```
-pub fn extract_version_from_tag(tag: &str) -> &str {
+pub fn extract_version_from_tag(tag: &str) -> Cow<'_, str> {
     // Find the first digit in the tag
     let version = if let Some(pos) = tag.find(|c: char| c.is_ascii_digit()) {
         &tag[pos..]
     } else {
         // If no digit found, return the original tag
-        return tag;
+        return Cow::Borrowed(tag);
     };
 
     // Truncate '-unstable' suffix if present
-    if let Some(unstable_pos) = version.find("-unstable") {
+    let version = if let Some(unstable_pos) = version.find("-unstable") {
         &version[..unstable_pos]
     } else {
         version
+    };
+
+    underscores_to_dots(version)
+}
+
+/// Convert the underscores separating numeric components of a version without dots to dots
+///
+/// Only underscores between two digits are converted, so `1_2_3` becomes `1.2.3` while suffixes
+/// like `16_RC1` are kept.
+fn underscores_to_dots(version: &str) -> Cow<'_, str> {
+    if version.contains('.') || !version.contains('_') {
+        return Cow::Borrowed(version);
     }
+
+    let chars: Vec<char> = version.chars().collect();
+    let converted: String = chars
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| {
+            let between_digits = i > 0
+                && chars[i - 1].is_ascii_digit()
+                && chars.get(i + 1).is_some_and(|next| next.is_ascii_digit());
+            if c == '_' && between_digits { '.' } else { c }
+        })
+        .collect();
+    Cow::Owned(converted)
 }
 
 /// Normalize a version string to ensure it has at least 3 components for semver parsing
@@ -475,6 +1182,21 @@ pub fn is_version_acceptable(
     current: &str,
     new: &str,
     strategy: SemverStrategy,
+) -> anyhow::Result<bool> {
+    let scheme = VersionSchemeKind::Auto.resolve(false, current.trim_start_matches('v'));
+    is_version_acceptable_with_scheme(current, new, strategy, scheme)
+}
+
+/// Check if a new version is acceptable based on the semver strategy and version scheme
+///
+/// See [`is_version_acceptable`]. Versions are ordered by `scheme`, falling back to semver and
+/// then string comparison if they aren't valid in it. Minor updates must keep the first release
+/// component and patch updates the first two, e.g. the year and month of calendar versions.
+pub fn is_version_acceptable_with_scheme(
+    current: &str,
+    new: &str,
+    strategy: SemverStrategy,
+    scheme: &dyn VersionScheme,
 ) -> anyhow::Result<bool> {
     // Strip common prefixes like 'v' or 'version-'
     let clean_current = current
@@ -482,59 +1204,58 @@ pub fn is_version_acceptable(
         .trim_start_matches("version-");
     let clean_new = new.trim_start_matches('v').trim_start_matches("version-");
 
-    // Normalize versions to ensure they have 3 components for semver parsing
-    let normalized_current = normalize_version(clean_current);
-    let normalized_new = normalize_version(clean_new);
-
-    // Try semantic versioning first
-    if let (Ok(curr_ver), Ok(new_ver)) = (
-        Version::parse(&normalized_current),
-        Version::parse(&normalized_new),
-    ) {
+    for scheme in [scheme, &Semver as &dyn VersionScheme] {
+        let Some(ordering) = scheme.compare(clean_new, clean_current) else {
+            continue;
+        };
         // First check if new version is actually newer
-        if new_ver <= curr_ver {
+        if ordering != Ordering::Greater {
             return Ok(false);
         }
 
         // Apply strategy-specific constraints
-        match strategy {
-            SemverStrategy::Latest | SemverStrategy::Major => {
-                // Accept any newer version
-                Ok(true)
-            },
-            SemverStrategy::Minor => {
-                // Only accept if major version matches
-                Ok(new_ver.major == curr_ver.major)
-            },
-            SemverStrategy::Patch => {
-                // Only accept if major and minor versions match
-                Ok(new_ver.major == curr_ver.major && new_ver.minor == curr_ver.minor)
-            },
-        }
-    } else {
-        // For non-semver versions, only Latest/Major strategies work
-        debug!(
-            "Could not parse versions as semver (current: {}, new: {}), using string comparison \
-             (strategy: {:?})",
-            clean_current, clean_new, strategy
-        );
+        let (Some(current_release), Some(new_release)) =
+            (scheme.release(clean_current), scheme.release(clean_new))
+        else {
+            continue;
+        };
+        let same_prefix = |n: usize| {
+            (0..n).all(|i| {
+                current_release.get(i).copied().unwrap_or(0)
+                    == new_release.get(i).copied().unwrap_or(0)
+            })
+        };
+        return Ok(match strategy {
+            // Accept any newer version
+            SemverStrategy::Latest | SemverStrategy::Major => true,
+            // Only accept if major version matches
+            SemverStrategy::Minor => same_prefix(1),
+            // Only accept if major and minor versions match
+            SemverStrategy::Patch => same_prefix(2),
+        });
+    }
 
-        match strategy {
-            SemverStrategy::Latest | SemverStrategy::Major => Ok(clean_new > clean_current),
-            SemverStrategy::Minor | SemverStrategy::Patch => {
-                warn!(
-                    "Version '{}' is not valid semver, cannot apply {:?} strategy. Skipping \
-                     update.",
-                    clean_current, strategy
-                );
-                Ok(false)
-            },
-        }
+    // For versions not valid in any scheme, only Latest/Major strategies work
+    debug!(
+        "Could not parse versions (current: {}, new: {}), using string comparison (strategy: {:?})",
+        clean_current, clean_new, strategy
+    );
+
+    match strategy {
+        SemverStrategy::Latest | SemverStrategy::Major => Ok(clean_new > clean_current),
+        SemverStrategy::Minor | SemverStrategy::Patch => {
+            warn!(
+                "Version '{}' is not valid semver, cannot apply {:?} strategy. Skipping update.",
+                clean_current, strategy
+            );
+            Ok(false)
+        },
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::version::{Calver, Pep440};
     use super::*;
 
     #[test]
@@ -543,9 +1264,10 @@ mod tests {
         let source = UpstreamSource::from_url(url);
         assert!(source.is_some());
         match source.unwrap() {
-            UpstreamSource::GitHub { owner, repo } => {
+            UpstreamSource::GitHub { owner, repo, asset } => {
                 assert_eq!(owner, "owner");
                 assert_eq!(repo, "repo");
+                assert_eq!(asset, None);
             },
             _ => panic!("Expected GitHub source"),
         }
@@ -642,6 +1364,7 @@ mod tests {
         let source = UpstreamSource::GitHub {
             owner: "owner".to_string(),
             repo: "repo".to_string(),
+            asset: None,
         };
         assert_eq!(source.description(), "GitHub repo: owner/repo");
     }
@@ -660,6 +1383,8 @@ mod tests {
         let release = Release {
             tag_name: "v1.2.3".to_string(),
             is_prerelease: false,
+            notes: None,
+            published_at: None,
         };
         assert_eq!(UpstreamSource::get_version(&release), "1.2.3");
     }
@@ -696,6 +1421,15 @@ mod tests {
 
         // Test invalid
         assert!(SemverStrategy::from_str("invalid").is_err());
+
+        assert_eq!(
+            SemverStrategy::Latest.stricter(SemverStrategy::Minor),
+            SemverStrategy::Minor
+        );
+        assert_eq!(
+            SemverStrategy::Patch.stricter(SemverStrategy::Major),
+            SemverStrategy::Patch
+        );
     }
 
     // is_version_acceptable tests - Latest strategy
@@ -779,12 +1513,99 @@ mod tests {
     #[test]
     fn test_version_acceptable_non_semver() {
         // Latest/Major strategies should fall back to string comparison
-        assert!(is_version_acceptable("2024.01.01", "2024.12.01", SemverStrategy::Latest).unwrap());
-        assert!(is_version_acceptable("2024.01.01", "2024.12.01", SemverStrategy::Major).unwrap());
+        assert!(is_version_acceptable("1.2.3.4", "1.2.3.5", SemverStrategy::Latest).unwrap());
+        assert!(is_version_acceptable("1.2.3.4", "1.2.3.5", SemverStrategy::Major).unwrap());
 
         // Minor/Patch strategies should reject non-semver
-        assert!(!is_version_acceptable("2024.01.01", "2024.12.01", SemverStrategy::Minor).unwrap());
-        assert!(!is_version_acceptable("2024.01.01", "2024.12.01", SemverStrategy::Patch).unwrap());
+        assert!(!is_version_acceptable("1.2.3.4", "1.2.3.5", SemverStrategy::Minor).unwrap());
+        assert!(!is_version_acceptable("1.2.3.4", "1.2.3.5", SemverStrategy::Patch).unwrap());
+    }
+
+    #[test]
+    fn test_version_acceptable_calver() {
+        // Detected from the current version
+        assert!(is_version_acceptable("2024.01.01", "2024.12.01", SemverStrategy::Latest).unwrap());
+        assert!(is_version_acceptable("2024.05.1", "2024.05.10", SemverStrategy::Latest).unwrap());
+        assert!(!is_version_acceptable("2024.05.10", "2024.05.9", SemverStrategy::Latest).unwrap());
+
+        // Minor updates stay within the year, patch updates within the month
+        assert!(is_version_acceptable("2024.01.01", "2024.12.01", SemverStrategy::Minor).unwrap());
+        assert!(!is_version_acceptable("2024.12.01", "2025.01.01", SemverStrategy::Minor).unwrap());
+        assert!(is_version_acceptable("2024.05.1", "2024.05.2", SemverStrategy::Patch).unwrap());
+        assert!(!is_version_acceptable("2024.05.1", "2024.06.0", SemverStrategy::Patch).unwrap());
+
+        // Forced for versions which don't start with a year
+        assert!(
+            is_version_acceptable_with_scheme("24.05.1", "24.10", SemverStrategy::Minor, &Calver)
+                .unwrap()
+        );
+
+        let release = |tag: &str| Release {
+            tag_name: tag.to_string(),
+            is_prerelease: false,
+            notes: None,
+            published_at: None,
+        };
+        let releases = vec![
+            release("2024.09.1"),
+            release("2024.10.0"),
+            release("2024.9.10"),
+        ];
+        let best = find_best_release(
+            &releases,
+            "2024.05.1",
+            SemverStrategy::Latest,
+            &TagFilter::default(),
+            &Calver,
+        )
+        .unwrap();
+        assert_eq!(best.tag_name, "2024.10.0");
+    }
+
+    #[test]
+    fn test_version_acceptable_pep440() {
+        let pep440 = |current: &str, new: &str, strategy: SemverStrategy| {
+            is_version_acceptable_with_scheme(current, new, strategy, &Pep440).unwrap()
+        };
+        assert!(pep440("1.2", "1.2.post1", SemverStrategy::Patch));
+        assert!(pep440("1.2rc1", "1.2", SemverStrategy::Patch));
+        assert!(!pep440("1.2", "1.2rc1", SemverStrategy::Latest));
+        assert!(!pep440("1.2.post1", "1.2", SemverStrategy::Latest));
+        assert!(!pep440("1.2.post1", "1.3", SemverStrategy::Patch));
+        assert!(pep440("1.2.post1", "1.3", SemverStrategy::Minor));
+
+        let release = |tag: &str| Release {
+            tag_name: tag.to_string(),
+            is_prerelease: false,
+            notes: None,
+            published_at: None,
+        };
+        let releases = vec![
+            release("1.2.post1"),
+            release("1.2"),
+            release("1.2rc1"),
+            release("1.3rc1"),
+            release("1.3.dev0"),
+        ];
+        let best = find_best_release(
+            &releases,
+            "1.1",
+            SemverStrategy::Latest,
+            &TagFilter::default(),
+            &Pep440,
+        )
+        .unwrap();
+        assert_eq!(best.tag_name, "1.2.post1");
+
+        let none = find_best_release(
+            &releases,
+            "1.2.post1",
+            SemverStrategy::Latest,
+            &TagFilter::default(),
+            &Pep440,
+        )
+        .unwrap_err();
+        assert!(none.is::<NoCompatibleRelease>());
     }
 
     // Test edge case: version 0.x.y
@@ -856,4 +1677,230 @@ mod tests {
         assert!(is_version_acceptable("1.9.0", "1.25", SemverStrategy::Latest).unwrap());
         assert!(!is_version_acceptable("1.25.0", "1.9", SemverStrategy::Latest).unwrap());
     }
+
+    #[test]
+    fn test_release_notes_section() {
+        let source = UpstreamSource::GitHub {
+            owner: "owner".to_string(),
+            repo: "repo".to_string(),
+            asset: None,
+        };
+        let release = Release {
+            tag_name: "v1.3.0".to_string(),
+            is_prerelease: false,
+            notes: Some("## What's Changed\n* Fix crash".to_string()),
+            published_at: None,
+        };
+
+        let section = source.release_notes_section(&release, "1.2.3").unwrap();
+        assert!(section.contains("<summary>v1.3.0</summary>"));
+        assert!(section.contains("* Fix crash"));
+        assert!(section.contains("https://github.com/owner/repo/compare/v1.2.3...v1.3.0"));
+        assert_eq!(UpstreamSource::previous_tag(&release, "1.2.3"), "v1.2.3");
+
+        let pypi = UpstreamSource::PyPI {
+            pname: "requests".to_string(),
+        };
+        let release = Release {
+            notes: None,
+            ..release
+        };
+        assert_eq!(pypi.release_notes_section(&release, "1.2.3"), None);
+    }
+
+    #[test]
+    fn test_truncate_release_notes() {
+        let notes: Vec<String> = (0..100).map(|i| format!("* change {}", i)).collect();
+        let excerpt = truncate_release_notes(&notes.join("\n"));
+        assert_eq!(excerpt.lines().count(), MAX_RELEASE_NOTES_LINES + 2);
+        assert!(excerpt.ends_with("*(truncated)*"));
+    }
+
+    #[test]
+    fn test_tag_filter() {
+        let release = |tag: &str| Release {
+            tag_name: tag.to_string(),
+            is_prerelease: false,
+            notes: None,
+            published_at: None,
+        };
+        let releases = vec![
+            release("server/v3.0.0"),
+            release("cli/v2.3.0"),
+            release("cli/v2.2.0"),
+            release("lib2-1.4"),
+        ];
+
+        let prefix = TagFilter::new(Some("cli/v".to_string()), None);
+        let best = find_best_release(&releases, "2.2.0", SemverStrategy::Latest, &prefix, &Semver)
+            .unwrap();
+        assert_eq!(best.tag_name, "cli/v2.3.0");
+        assert_eq!(prefix.release_version(&best), "2.3.0");
+
+        let regex = TagFilter::new(None, Some(Regex::new(r"^lib2-(.+)$").unwrap()));
+        assert_eq!(regex.version_of("lib2-1.4").as_deref(), Some("1.4"));
+        assert_eq!(regex.version_of("cli/v2.3.0"), None);
+        let best =
+            find_best_release(&releases, "1.3", SemverStrategy::Latest, &regex, &Semver).unwrap();
+        assert_eq!(regex.release_version(&best), "1.4");
+
+        // Pinned filters only select the planned version, even if newer ones exist
+        let pinned = TagFilter::new(Some("cli/v".to_string()), None).pinned(Some("2.2.0".into()));
+        let best = find_best_release(&releases, "2.1.0", SemverStrategy::Latest, &pinned, &Semver)
+            .unwrap();
+        assert_eq!(best.tag_name, "cli/v2.2.0");
+
+        // Without a filter the highest tag of any component wins
+        let best = find_best_release(
+            &releases,
+            "2.2.0",
+            SemverStrategy::Latest,
+            &TagFilter::default(),
+            &Semver,
+        )
+        .unwrap();
+        assert_eq!(best.tag_name, "server/v3.0.0");
+    }
+
+    #[test]
+    fn test_min_release_age() {
+        let now = parse_timestamp("2024-06-10T12:00:00Z").unwrap();
+        let release = |published_at: Option<&str>| Release {
+            tag_name: "v1.0.0".to_string(),
+            is_prerelease: false,
+            notes: None,
+            published_at: published_at.and_then(parse_timestamp),
+        };
+        let three_days = Some(Duration::days(3));
+
+        assert!(is_too_recent(
+            &release(Some("2024-06-09T08:30:00+02:00")),
+            three_days,
+            now
+        ));
+        assert!(!is_too_recent(
+            &release(Some("2024-06-01T00:00:00Z")),
+            three_days,
+            now
+        ));
+        assert!(!is_too_recent(&release(None), three_days, now));
+        assert!(!is_too_recent(
+            &release(Some("2024-06-10T11:00:00Z")),
+            None,
+            now
+        ));
+    }
+
+    #[test]
+    fn test_feed_has_newer_release() {
+        let tags: Vec<String> = ["cli/v2.3.0", "server/v1.4.0", "cli/v2.2.0"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        let cli = TagFilter::new(Some("cli/v".to_string()), None);
+        assert!(!feed_has_newer_release(&tags, "2.3.0", &cli, &Semver));
+        assert!(feed_has_newer_release(&tags, "2.2.0", &cli, &Semver));
+
+        // The feed lists no release of the package, which says nothing about older releases
+        let docs = TagFilter::new(Some("docs/v".to_string()), None);
+        assert!(feed_has_newer_release(&tags, "1.0.0", &docs, &Semver));
+        assert!(feed_has_newer_release(
+            &[],
+            "1.0.0",
+            &TagFilter::default(),
+            &Semver
+        ));
+    }
+
+    #[test]
+    fn test_merge_releases() {
+        let release = |tag_name: &str, notes: Option<&str>| Release {
+            tag_name: tag_name.to_string(),
+            is_prerelease: false,
+            notes: notes.map(str::to_string),
+            published_at: None,
+        };
+        let merged = merge_releases(
+            vec![release("v2.0.0", Some("Major release"))],
+            vec![release("v2.0.1", None), release("v2.0.0", None)],
+        );
+        let tags: Vec<&str> = merged.iter().map(|r| r.tag_name.as_str()).collect();
+        assert_eq!(tags, vec!["v2.0.0", "v2.0.1"]);
+        assert_eq!(merged[0].notes.as_deref(), Some("Major release"));
+    }
+
+    #[test]
+    fn test_extract_version_from_underscore_tag() {
+        assert_eq!(extract_version_from_tag("REL_1_2_3"), "1.2.3");
+        assert_eq!(extract_version_from_tag("curl-8_5_0"), "8.5.0");
+        assert_eq!(extract_version_from_tag("OpenSSL_3_2_1"), "3.2.1");
+        assert_eq!(extract_version_from_tag("REL_16_RC1"), "16_RC1");
+        assert_eq!(extract_version_from_tag("v1.2.3_beta"), "1.2.3_beta");
+
+        let filter = TagFilter::default();
+        let curl = |tag: &str| Release {
+            tag_name: tag.to_string(),
+            is_prerelease: false,
+            notes: None,
+            published_at: None,
+        };
+        let releases = vec![curl("curl-8_10_0"), curl("curl-8_9_1"), curl("curl-8_5_0")];
+        let best = find_best_release(&releases, "8.5.0", SemverStrategy::Latest, &filter, &Semver)
+            .unwrap();
+        assert_eq!(best.tag_name, "curl-8_10_0");
+        assert_eq!(filter.release_version(&best), "8.10.0");
+
+        let source = UpstreamSource::GitHub {
+            owner: "curl".to_string(),
+            repo: "curl".to_string(),
+            asset: None,
+        };
+        let section = source.release_notes_section(&best, "8.5.0").unwrap();
+        assert!(section.contains("https://github.com/curl/curl/compare/curl-8_5_0...curl-8_10_0"));
+    }
+
+    #[test]
+    fn test_ships_asset() {
+        let release = |tag_name: &str, assets: &[&str]| GithubRelease {
+            tag_name: tag_name.to_string(),
+            _name: None,
+            prerelease: false,
+            body: None,
+            published_at: None,
+            assets: assets
+                .iter()
+                .map(|name| crate::github::GithubReleaseAsset {
+                    name: name.to_string(),
+                })
+                .collect(),
+        };
+        let filter = TagFilter::default();
+        let asset = Some("tool-1.0.0-x86_64.AppImage");
+
+        let built = release(
+            "v1.1.0",
+            &["tool-1.1.0-x86_64.AppImage", "tool-1.1.0.tar.gz"],
+        );
+        assert!(ships_asset(&built, asset, "1.0.0", &filter));
+        let pending = release("v1.2.0", &["tool-1.2.0.tar.gz"]);
+        assert!(!ships_asset(&pending, asset, "1.0.0", &filter));
+        // Sources not downloaded from a release asset
+        assert!(ships_asset(&pending, None, "1.0.0", &filter));
+        // Assets not named after the version verbatim are assumed to be shipped
+        assert!(ships_asset(
+            &pending,
+            Some("tool_1_0_0.zip"),
+            "1.0.0",
+            &filter
+        ));
+
+        let source = UpstreamSource::from_url(
+            "https://github.com/owner/tool/releases/download/v1.0.0/tool-1.0.0-x86_64.AppImage",
+        );
+        assert!(matches!(
+            source,
+            Some(UpstreamSource::GitHub { asset: Some(ref name), .. })
+                if name == "tool-1.0.0-x86_64.AppImage"
+        ));
+    }
 }
@@ -0,0 +1,444 @@
+//! Version comparison schemes
+//!
+//! Upstreams number their releases differently: semver, calendar versions, PEP 440 on PyPI,
+//! Debian-style versions with epochs and revisions, or plain numbers. Each scheme orders versions
+//! and exposes the numeric release components the minor and patch strategies are checked
+//! against.
+
+use std::cmp::Ordering;
+use std::sync::OnceLock;
+
+use regex::Regex;
+use semver::Version;
+use serde::Deserialize;
+
+use super::normalize_version;
+
+/// A way of ordering versions
+pub trait VersionScheme: Send + Sync {
+    /// Order two versions, None if either isn't valid in this scheme
+    fn compare(&self, a: &str, b: &str) -> Option<Ordering>;
+
+    /// Numeric release components, e.g. `[1, 2, 3]` for `1.2.3`
+    ///
+    /// Minor updates keep the first component, patch updates the first two.
+    fn release(&self, version: &str) -> Option<Vec<u64>>;
+
+    /// Whether a version is a prerelease which shouldn't be proposed as an update
+    fn is_prerelease(&self, _version: &str) -> bool {
+        false
+    }
+}
+
+/// Which scheme to compare the versions of a package with, as configured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionSchemeKind {
+    /// PEP 440 for PyPI sources, CalVer if the current version starts with a year, semver
+    /// otherwise
+    #[default]
+    Auto,
+    /// Semantic versioning, e.g. `1.2.3-rc.1`
+    Semver,
+    /// Calendar versioning, e.g. `2024.05.1`
+    Calver,
+    /// Python versions as specified by PEP 440, e.g. `1.2.3rc1`
+    Pep440,
+    /// Debian package versions, e.g. `1:1.2.3-4`
+    Debian,
+    /// Dot-separated numbers compared one by one
+    Numeric,
+}
+
+impl VersionSchemeKind {
+    /// The scheme to use, resolving `Auto` from the source type and current version
+    pub fn resolve(self, is_pypi: bool, current_version: &str) -> &'static dyn VersionScheme {
+        match self {
+            VersionSchemeKind::Auto if is_pypi => &Pep440,
+            VersionSchemeKind::Auto if looks_like_calver(current_version) => &Calver,
+            VersionSchemeKind::Auto | VersionSchemeKind::Semver => &Semver,
+            VersionSchemeKind::Calver => &Calver,
+            VersionSchemeKind::Pep440 => &Pep440,
+            VersionSchemeKind::Debian => &Debian,
+            VersionSchemeKind::Numeric => &Numeric,
+        }
+    }
+}
+
+/// Compare numeric components, missing components counting as 0
+fn compare_components(a: &[u64], b: &[u64]) -> Ordering {
+    let component = |v: &[u64], i: usize| v.get(i).copied().unwrap_or(0);
+    (0..a.len().max(b.len()))
+        .map(|i| component(a, i).cmp(&component(b, i)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Semantic versions, with missing minor and patch components treated as 0
+pub struct Semver;
+
+impl Semver {
+    fn parse(version: &str) -> Option<Version> {
+        Version::parse(&normalize_version(version)).ok()
+    }
+}
+
+impl VersionScheme for Semver {
+    fn compare(&self, a: &str, b: &str) -> Option<Ordering> {
+        Some(Self::parse(a)?.cmp(&Self::parse(b)?))
+    }
+
+    fn release(&self, version: &str) -> Option<Vec<u64>> {
+        let version = Self::parse(version)?;
+        Some(vec![version.major, version.minor, version.patch])
+    }
+}
+
+/// Calendar versions like `2024.05.1` or `2024-05-01`
+pub struct Calver;
+
+/// Numeric components of a calendar version, None if any component isn't numeric
+fn calver_components(version: &str) -> Option<Vec<u64>> {
+    version
+        .split(['.', '-', '_'])
+        .map(|component| component.parse().ok())
+        .collect()
+}
+
+/// Whether a version starts with a plausible year, e.g. `2024.05.1` or `2024-05-01`
+fn looks_like_calver(version: &str) -> bool {
+    calver_components(version.trim_start_matches('v'))
+        .is_some_and(|components| components.len() >= 2 && (1990..=2100).contains(&components[0]))
+}
+
+impl VersionScheme for Calver {
+    fn compare(&self, a: &str, b: &str) -> Option<Ordering> {
+        Some(compare_components(
+            &calver_components(a)?,
+            &calver_components(b)?,
+        ))
+    }
+
+    fn release(&self, version: &str) -> Option<Vec<u64>> {
+        calver_components(version)
+    }
+}
+
+/// Versions made of numbers with arbitrary separators, e.g. `1.2.3.4` or `20_1`
+pub struct Numeric;
+
+fn numeric_components(version: &str) -> Option<Vec<u64>> {
+    if !version.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|component| !component.is_empty())
+        .map(|component| component.parse().ok())
+        .collect()
+}
+
+impl VersionScheme for Numeric {
+    fn compare(&self, a: &str, b: &str) -> Option<Ordering> {
+        Some(compare_components(
+            &numeric_components(a)?,
+            &numeric_components(b)?,
+        ))
+    }
+
+    fn release(&self, version: &str) -> Option<Vec<u64>> {
+        numeric_components(version)
+    }
+}
+
+/// Python package versions as specified by PEP 440, e.g. `1.2rc1`, `1.2.post1` or `1!2.0.dev3`
+pub struct Pep440;
+
+/// Kind of a PEP 440 prerelease, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PreKind {
+    Alpha,
+    Beta,
+    ReleaseCandidate,
+}
+
+/// A parsed PEP 440 version
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pep440Version {
+    epoch: u64,
+    release: Vec<u64>,
+    pre: Option<(PreKind, u64)>,
+    post: Option<u64>,
+    dev: Option<u64>,
+}
+
+impl Pep440Version {
+    fn parse(version: &str) -> Option<Self> {
+        static PATTERN: OnceLock<Regex> = OnceLock::new();
+        let pattern = PATTERN.get_or_init(|| {
+            Regex::new(
+                r"(?ix)^v?
+                (?:(?P<epoch>\d+)!)?
+                (?P<release>\d+(?:\.\d+)*)
+                (?:[-_.]?(?P<pre_l>alpha|a|beta|b|preview|pre|rc|c)[-_.]?(?P<pre_n>\d+)?)?
+                (?:-(?P<post_n1>\d+)|[-_.]?(?P<post_l>post|rev|r)[-_.]?(?P<post_n2>\d+)?)?
+                (?:[-_.]?(?P<dev_l>dev)[-_.]?(?P<dev_n>\d+)?)?
+                (?:\+[a-z0-9]+(?:[-_.][a-z0-9]+)*)?
+                $",
+            )
+            .expect("valid PEP 440 regex")
+        });
+
+        let caps = pattern.captures(version.trim())?;
+        let number = |name: &str| -> Option<u64> { caps.name(name)?.as_str().parse().ok() };
+
+        let pre = caps.name("pre_l").map(|label| {
+            let kind = match label.as_str().to_lowercase().as_str() {
+                "a" | "alpha" => PreKind::Alpha,
+                "b" | "beta" => PreKind::Beta,
+                _ => PreKind::ReleaseCandidate,
+            };
+            (kind, number("pre_n").unwrap_or(0))
+        });
+        let post = if caps.name("post_n1").is_some() {
+            number("post_n1")
+        } else {
+            caps.name("post_l").map(|_| number("post_n2").unwrap_or(0))
+        };
+        let dev = caps.name("dev_l").map(|_| number("dev_n").unwrap_or(0));
+
+        Some(Self {
+            epoch: number("epoch").unwrap_or(0),
+            release: caps["release"]
+                .split('.')
+                .map(|c| c.parse().ok())
+                .collect::<Option<_>>()?,
+            pre,
+            post,
+            dev,
+        })
+    }
+
+    /// Sort key following PEP 440: dev releases of a version sort before its prereleases,
+    /// prereleases before the final release, and post-releases after it
+    #[allow(clippy::type_complexity)]
+    fn key(&self) -> (u64, Vec<u64>, (u8, u64), Option<u64>, (bool, u64)) {
+        let mut release = self.release.clone();
+        while release.len() > 1 && release.last() == Some(&0) {
+            release.pop();
+        }
+
+        let pre = match (self.pre, self.post, self.dev) {
+            (None, None, Some(_)) => (0, 0),
+            (Some((kind, n)), ..) => (kind as u8 + 1, n),
+            (None, ..) => (u8::MAX, 0),
+        };
+        let dev = (self.dev.is_none(), self.dev.unwrap_or(0));
+
+        (self.epoch, release, pre, self.post, dev)
+    }
+}
+
+impl VersionScheme for Pep440 {
+    fn compare(&self, a: &str, b: &str) -> Option<Ordering> {
+        let (a, b) = (Pep440Version::parse(a)?, Pep440Version::parse(b)?);
+        Some(a.key().cmp(&b.key()))
+    }
+
+    fn release(&self, version: &str) -> Option<Vec<u64>> {
+        Pep440Version::parse(version).map(|v| v.release)
+    }
+
+    /// Alpha, beta, release candidate and dev releases. Post-releases of a final release are
+    /// stable
+    fn is_prerelease(&self, version: &str) -> bool {
+        Pep440Version::parse(version).is_some_and(|v| v.pre.is_some() || v.dev.is_some())
+    }
+}
+
+/// Debian-style versions, `[epoch:]upstream[-revision]`, where `~` sorts before anything
+pub struct Debian;
+
+/// Split a Debian version into epoch, upstream version and revision
+fn debian_parts(version: &str) -> Option<(u64, &str, &str)> {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().ok()?, rest),
+        None => (0, version),
+    };
+    let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+    if !upstream.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((epoch, upstream, revision))
+}
+
+/// Compare two version fragments as dpkg does
+fn dpkg_compare(a: &str, b: &str) -> Ordering {
+    fn order(c: Option<u8>) -> i32 {
+        match c {
+            None => 0,
+            Some(b'~') => -1,
+            Some(c) if c.is_ascii_digit() => 0,
+            Some(c) if c.is_ascii_alphabetic() => c as i32,
+            Some(c) => c as i32 + 256,
+        }
+    }
+
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        // Non-digit prefix
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let (ac, bc) = (order(a.get(i).copied()), order(b.get(j).copied()));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        // Digit run, compared numerically
+        while i < a.len() && a[i] == b'0' {
+            i += 1;
+        }
+        while j < b.len() && b[j] == b'0' {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while i < a.len() && a[i].is_ascii_digit() && j < b.len() && b[j].is_ascii_digit() {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if i < a.len() && a[i].is_ascii_digit() {
+            return Ordering::Greater;
+        }
+        if j < b.len() && b[j].is_ascii_digit() {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+impl VersionScheme for Debian {
+    fn compare(&self, a: &str, b: &str) -> Option<Ordering> {
+        let (a_epoch, a_upstream, a_revision) = debian_parts(a)?;
+        let (b_epoch, b_upstream, b_revision) = debian_parts(b)?;
+        Some(
+            a_epoch
+                .cmp(&b_epoch)
+                .then_with(|| dpkg_compare(a_upstream, b_upstream))
+                .then_with(|| dpkg_compare(a_revision, b_revision)),
+        )
+    }
+
+    fn release(&self, version: &str) -> Option<Vec<u64>> {
+        let (_, upstream, _) = debian_parts(version)?;
+        Some(
+            upstream
+                .split('.')
+                .map_while(|component| {
+                    let digits: String = component
+                        .chars()
+                        .take_while(|c| c.is_ascii_digit())
+                        .collect();
+                    digits.parse().ok()
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assert that `versions` are in ascending order in `scheme`
+    fn assert_ascending(scheme: &dyn VersionScheme, versions: &[&str]) {
+        for pair in versions.windows(2) {
+            assert_eq!(
+                scheme.compare(pair[0], pair[1]),
+                Some(Ordering::Less),
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    #[test]
+    fn test_pep440_ordering() {
+        assert_ascending(
+            &Pep440,
+            &[
+                "1.0.dev0",
+                "1.0a1",
+                "1.0b2.dev1",
+                "1.0b2",
+                "1.0rc1",
+                "1.0",
+                "1.0.post1.dev0",
+                "1.0.post1",
+                "1.1",
+                "1!0.1",
+            ],
+        );
+        assert_eq!(Pep440.compare("1.0", "1.0.0"), Some(Ordering::Equal));
+        assert_eq!(Pep440.compare("1.0-1", "1.0.post1"), Some(Ordering::Equal));
+        assert_eq!(Pep440.compare("1.0RC1", "1.0rc1"), Some(Ordering::Equal));
+        assert_eq!(Pep440.compare("not-a-version", "1.0"), None);
+
+        assert!(Pep440.is_prerelease("2.0.0rc1"));
+        assert!(Pep440.is_prerelease("1.9.dev0"));
+        assert!(Pep440.is_prerelease("1.0.post1.dev0"));
+        assert!(!Pep440.is_prerelease("1.5.post2"));
+        assert!(!Pep440.is_prerelease("1.5"));
+    }
+
+    #[test]
+    fn test_debian_ordering() {
+        assert_ascending(
+            &Debian,
+            &[
+                "1.0~rc1", "1.0", "1.0-1", "1.0-2", "1.0a", "1.0.1", "1.10", "1:0.9",
+            ],
+        );
+        assert_eq!(Debian.release("1.2.3-4"), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_numeric_and_calver_ordering() {
+        assert_ascending(&Numeric, &["1.2.3.4", "1.2.3.5", "1.2.10", "2"]);
+        assert_ascending(&Calver, &["2024.05.1", "2024.5.2", "2024.10", "2025.01.01"]);
+        assert_ascending(&Semver, &["1.9", "1.25", "2.0.0-beta.1", "2.0.0"]);
+    }
+
+    #[test]
+    fn test_resolve_scheme() {
+        let is = |scheme: &dyn VersionScheme, version: &str| {
+            // Identify the scheme by a version only it accepts
+            scheme.compare(version, version).is_some()
+        };
+        assert!(is(
+            VersionSchemeKind::Auto.resolve(true, "1.0"),
+            "1.0.post1"
+        ));
+        assert!(is(
+            VersionSchemeKind::Auto.resolve(false, "2024.05.1"),
+            "2024.05.1"
+        ));
+        assert!(!is(
+            VersionSchemeKind::Auto.resolve(false, "1.0"),
+            "2024.05.1"
+        ));
+        assert!(is(
+            VersionSchemeKind::Debian.resolve(true, "1.0"),
+            "1:1.0-1"
+        ));
+    }
+}
@@ -0,0 +1,321 @@
+//! Verification of fetched sources against upstream checksums and signatures
+//!
+//! Many projects publish detached signatures (`.asc`, `.sig`) or checksum files (`.sha256`,
+//! `SHA256SUMS`) next to their release tarballs. Checking the fetched tarball against them
+//! protects against tampered tags and release assets. Provenance attestations, which tie an
+//! artifact to the repository and workflow it was built by, are checked in [`provenance`].
+
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::Context;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::http::{self, Throttled};
+
+mod provenance;
+
+pub use provenance::{
+    ProvenanceCheck, ProvenanceOrigin, format_provenance_report, verify_provenance,
+};
+
+/// Suffixes of detached signatures published next to a release asset
+const SIGNATURE_SUFFIXES: &[&str] = &[".asc", ".sig", ".sign"];
+
+/// Suffixes of checksum files published next to a release asset
+const CHECKSUM_SUFFIXES: &[&str] = &[".sha256", ".sha256sum"];
+
+/// Checksum files covering every asset of a release, published in the same directory
+const CHECKSUM_FILES: &[&str] = &["SHA256SUMS", "sha256sums.txt", "SHA256SUMS.txt"];
+
+/// Result of a single verification
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckResult {
+    Passed,
+    /// The file was published but couldn't be checked, e.g. because the signing key is unknown
+    Unverifiable(String),
+}
+
+/// A checksum or signature file checked against the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationCheck {
+    pub url: String,
+    pub result: CheckResult,
+}
+
+/// Verify a fetched source file against the checksums and signatures published next to it
+///
+/// Returns the checks which were performed, empty if upstream publishes neither. Fails if a
+/// checksum doesn't match or a signature is bad.
+pub async fn verify_source(
+    source_path: &Path,
+    source_url: &str,
+) -> anyhow::Result<Vec<VerificationCheck>> {
+    if !source_path.is_file() {
+        debug!(
+            "{} is not a file, skipping source verification",
+            source_path.display()
+        );
+        return Ok(Vec::new());
+    }
+    let Some((base_url, file_name)) = source_url.rsplit_once('/') else {
+        return Ok(Vec::new());
+    };
+
+    let client = http::client();
+    let mut checks = Vec::new();
+
+    let mut checksum_urls: Vec<String> = CHECKSUM_SUFFIXES
+        .iter()
+        .map(|suffix| format!("{}{}", source_url, suffix))
+        .collect();
+    checksum_urls.extend(
+        CHECKSUM_FILES
+            .iter()
+            .map(|name| format!("{}/{}", base_url, name)),
+    );
+
+    for url in checksum_urls {
+        let Some(content) = fetch_optional(client, &url).await else {
+            continue;
+        };
+        let Some(expected) = find_checksum(&content, file_name) else {
+            debug!("{} doesn't list {}", url, file_name);
+            continue;
+        };
+
+        let actual = sha256_hex(source_path).await?;
+        if !actual.eq_ignore_ascii_case(&expected) {
+            anyhow::bail!(
+                "Checksum mismatch for {}: {} lists {}, fetched source has {}",
+                file_name,
+                url,
+                expected,
+                actual
+            );
+        }
+        info!("✓ Source checksum matches {}", url);
+        checks.push(VerificationCheck {
+            url,
+            result: CheckResult::Passed,
+        });
+        break;
+    }
+
+    for suffix in SIGNATURE_SUFFIXES {
+        let url = format!("{}{}", source_url, suffix);
+        let Some(signature) = fetch_optional_bytes(client, &url).await else {
+            continue;
+        };
+
+        let result = verify_signature(source_path, &signature).await?;
+        match &result {
+            CheckResult::Passed => info!("✓ Source signature {} verified", url),
+            CheckResult::Unverifiable(reason) => {
+                warn!("Could not verify signature {}: {}", url, reason)
+            },
+        }
+        checks.push(VerificationCheck { url, result });
+        break;
+    }
+
+    Ok(checks)
+}
+
+/// Download a file, None if it doesn't exist or can't be fetched
+async fn fetch_optional_bytes(client: &reqwest::Client, url: &str) -> Option<Vec<u8>> {
+    let response = client
+        .get(url)
+        .header("User-Agent", "ekapkgs-update")
+        .send_throttled()
+        .await
+        .ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response.bytes().await.ok().map(|bytes| bytes.to_vec())
+}
+
+async fn fetch_optional(client: &reqwest::Client, url: &str) -> Option<String> {
+    let bytes = fetch_optional_bytes(client, url).await?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Find the checksum of `file_name` in a checksum file
+///
+/// Supports the `sha256sum` format (`<hash>  <file>`, optionally `*<file>`) and files containing
+/// just the hash.
+fn find_checksum(content: &str, file_name: &str) -> Option<String> {
+    let is_sha256 = |s: &str| s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit());
+
+    for line in content.lines() {
+        let mut parts = line.split_whitespace();
+        let (Some(hash), name) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        if !is_sha256(hash) {
+            continue;
+        }
+        match name {
+            Some(name) if name.trim_start_matches('*').rsplit('/').next() == Some(file_name) => {
+                return Some(hash.to_string());
+            },
+            None if content.lines().filter(|l| !l.trim().is_empty()).count() == 1 => {
+                return Some(hash.to_string());
+            },
+            _ => {},
+        }
+    }
+
+    None
+}
+
+/// SHA-256 of a file in hex
+async fn sha256_hex(path: &Path) -> anyhow::Result<String> {
+    file_hash_hex(path, "sha256").await
+}
+
+/// Hash of a file in hex, `algorithm` being a hash type of `nix-hash`
+async fn file_hash_hex(path: &Path, algorithm: &str) -> anyhow::Result<String> {
+    let output = Command::new("nix-hash")
+        .args(["--type", algorithm, "--flat", "--base16"])
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to run nix-hash")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "nix-hash failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Verify a detached signature with gpg, fetching unknown keys from the configured keyserver
+///
+/// Fails on a bad signature. A missing key or gpg binary is reported as unverifiable.
+async fn verify_signature(source_path: &Path, signature: &[u8]) -> anyhow::Result<CheckResult> {
+    let signature_path = std::env::temp_dir().join(format!(
+        "ekapkgs-update-{}-{}.sig",
+        std::process::id(),
+        source_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default()
+    ));
+    tokio::fs::write(&signature_path, signature).await?;
+
+    let output = Command::new("gpg")
+        .args([
+            "--batch",
+            "--status-fd",
+            "1",
+            "--auto-key-retrieve",
+            "--verify",
+        ])
+        .arg(&signature_path)
+        .arg(source_path)
+        .stdin(Stdio::null())
+        .output()
+        .await;
+    tokio::fs::remove_file(&signature_path).await.ok();
+
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => return Ok(CheckResult::Unverifiable(format!("gpg unavailable: {}", e))),
+    };
+    parse_gpg_status(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Interpret the `--status-fd` output of `gpg --verify`
+fn parse_gpg_status(status: &str) -> anyhow::Result<CheckResult> {
+    let has = |keyword: &str| {
+        status
+            .lines()
+            .any(|line| line.starts_with(&format!("[GNUPG:] {}", keyword)))
+    };
+
+    if has("BADSIG") {
+        anyhow::bail!("Bad signature on fetched source");
+    }
+    if has("GOODSIG") && has("VALIDSIG") {
+        return Ok(CheckResult::Passed);
+    }
+    if has("NO_PUBKEY") {
+        return Ok(CheckResult::Unverifiable(
+            "signing key not found".to_string(),
+        ));
+    }
+    Ok(CheckResult::Unverifiable(
+        "gpg could not check the signature".to_string(),
+    ))
+}
+
+/// Markdown section describing the verification of the source, None if nothing was checked
+pub fn format_verification_report(checks: &[VerificationCheck]) -> Option<String> {
+    if checks.is_empty() {
+        return None;
+    }
+
+    let mut report = String::from("## Source Verification\n");
+    for check in checks {
+        match &check.result {
+            CheckResult::Passed => {
+                report.push_str(&format!("\n- ✅ Verified against {}", check.url))
+            },
+            CheckResult::Unverifiable(reason) => report.push_str(&format!(
+                "\n- ⚠️ Could not verify against {}: {}",
+                check.url, reason
+            )),
+        }
+    }
+    Some(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn test_find_checksum() {
+        let sums = format!(
+            "{}  foo-1.0.tar.gz\n{} *foo-1.0.zip\n",
+            HASH,
+            HASH.replace('9', "0")
+        );
+        assert_eq!(
+            find_checksum(&sums, "foo-1.0.tar.gz").as_deref(),
+            Some(HASH)
+        );
+        assert_eq!(
+            find_checksum(&sums, "foo-1.0.zip"),
+            Some(HASH.replace('9', "0"))
+        );
+        assert_eq!(find_checksum(&sums, "bar-1.0.tar.gz"), None);
+
+        // A `.sha256` file may hold only the hash
+        assert_eq!(
+            find_checksum(&format!("{}\n", HASH), "foo").as_deref(),
+            Some(HASH)
+        );
+    }
+
+    #[test]
+    fn test_parse_gpg_status() {
+        let good = "[GNUPG:] NEWSIG\n[GNUPG:] GOODSIG ABCDEF Jane Doe\n[GNUPG:] VALIDSIG ABCDEF";
+        assert_eq!(parse_gpg_status(good).unwrap(), CheckResult::Passed);
+
+        let no_key = "[GNUPG:] ERRSIG ABCDEF 1 10 00 1700000000 9\n[GNUPG:] NO_PUBKEY ABCDEF";
+        assert!(matches!(
+            parse_gpg_status(no_key).unwrap(),
+            CheckResult::Unverifiable(_)
+        ));
+
+        assert!(parse_gpg_status("[GNUPG:] BADSIG ABCDEF Jane Doe").is_err());
+    }
+}
@@ -0,0 +1,591 @@
+//! Provenance attestations of fetched sources
+//!
+//! Release assets built by GitHub Actions may be covered by artifact attestations, npm packages
+//! published with `--provenance` carry SLSA provenance, and files uploaded to PyPI by Trusted
+//! Publishers carry attestations naming the repository they were built from.
+//!
+//! GitHub attestations are verified with `gh attestation verify`, which checks their Sigstore
+//! signature and that the asset was built in the repository. The attestations served by npm and
+//! PyPI are only checked to cover the digest of the fetched file, their signature isn't verified,
+//! so they're reported as present but unverified.
+//!
+//! Sources unpacked by `fetchzip` are directories, while attestations cover the archive they were
+//! fetched from, so their provenance can't be verified either.
+
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use super::{CheckResult, fetch_optional, file_hash_hex};
+use crate::github::{has_attestations, parse_github_release_asset, parse_github_url};
+use crate::http;
+
+/// Prefix of the predicate type of SLSA provenance
+const SLSA_PREDICATE_PREFIX: &str = "https://slsa.dev/provenance/";
+
+/// Reason attestations covering the source whose signature isn't verified are unverifiable
+const UNVERIFIED_SIGNATURE: &str = "attestation present (unverified), it covers the fetched \
+                                    source but its signature isn't checked";
+
+/// Reason the attestations of unpacked sources are unverifiable
+const UNPACKED_SOURCE: &str =
+    "attestation present (unverified), the source is unpacked but it covers the archive";
+
+/// Where the provenance of a fetched source may be published
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProvenanceOrigin {
+    /// Artifact attestations of a release asset of `owner/repo`
+    GitHub { owner: String, repo: String },
+    /// Provenance of a version of an npm package
+    Npm { package: String, version: String },
+    /// Attestations uploaded with a file of a PyPI release
+    PyPI {
+        project: String,
+        version: String,
+        filename: String,
+    },
+}
+
+impl ProvenanceOrigin {
+    /// Origin of the provenance of the source fetched from `source_url`, None if it can't have any
+    ///
+    /// `pypi_project` is the PyPI project the package is updated from, if any, as the URLs of
+    /// PyPI files don't always name it.
+    pub fn from_source(
+        source_url: &str,
+        pypi_project: Option<&str>,
+        version: &str,
+    ) -> Option<Self> {
+        if parse_github_release_asset(source_url).is_some() {
+            let repo = parse_github_url(source_url)?;
+            return Some(ProvenanceOrigin::GitHub {
+                owner: repo.owner,
+                repo: repo.repo,
+            });
+        }
+
+        if let Some(path) = source_url.strip_prefix("https://registry.npmjs.org/") {
+            let (package, _) = path.split_once("/-/")?;
+            return Some(ProvenanceOrigin::Npm {
+                package: package.to_string(),
+                version: version.to_string(),
+            });
+        }
+
+        let path = [
+            "mirror://pypi/",
+            "files.pythonhosted.org/packages/",
+            "pypi.io/packages/",
+        ]
+        .iter()
+        .find_map(|prefix| source_url.split_once(prefix).map(|(_, path)| path))?;
+        let segments: Vec<&str> = path.split('/').collect();
+        let filename = segments.last().filter(|name| !name.is_empty())?;
+        // `<first letter>/<project>/<file>`, possibly below `source/`
+        let project = pypi_project
+            .map(str::to_string)
+            .or_else(|| match segments.as_slice() {
+                ["source", _, project, _] | [_, project, _] => Some(project.to_string()),
+                _ => None,
+            })?;
+        Some(ProvenanceOrigin::PyPI {
+            project,
+            version: version.to_string(),
+            filename: filename.to_string(),
+        })
+    }
+}
+
+/// Provenance attestation checked against the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProvenanceCheck {
+    /// Where the attestation was fetched from
+    pub url: String,
+    /// Repository or publisher the attestation names as the origin of the source
+    pub publisher: Option<String>,
+    pub result: CheckResult,
+}
+
+/// In-toto statement signed by an attestation
+#[derive(Debug, Deserialize)]
+struct Statement {
+    #[serde(default)]
+    subject: Vec<Subject>,
+    #[serde(default)]
+    predicate: serde_json::Value,
+}
+
+/// Artifact covered by a statement
+#[derive(Debug, Deserialize)]
+struct Subject {
+    /// Hex digests by algorithm
+    #[serde(default)]
+    digest: HashMap<String, String>,
+}
+
+impl Statement {
+    /// Decode a base64 encoded statement
+    fn decode(encoded: &str) -> anyhow::Result<Self> {
+        let bytes = openssl::base64::decode_block(encoded.trim())
+            .context("Invalid base64 in attestation")?;
+        serde_json::from_slice(&bytes).context("Invalid in-toto statement in attestation")
+    }
+
+    /// Whether the statement covers an artifact with the `algorithm` digest `digest`
+    fn covers(&self, algorithm: &str, digest: &str) -> bool {
+        self.subject.iter().any(|subject| {
+            subject
+                .digest
+                .get(algorithm)
+                .is_some_and(|d| d.eq_ignore_ascii_case(digest))
+        })
+    }
+
+    /// Repository the artifact was built from according to SLSA v1 provenance
+    fn source_repository(&self) -> Option<String> {
+        self.predicate
+            .pointer("/buildDefinition/externalParameters/workflow/repository")
+            .and_then(|repository| repository.as_str())
+            .map(|repository| {
+                repository
+                    .trim_start_matches("https://github.com/")
+                    .to_string()
+            })
+    }
+}
+
+/// Attestations of an npm package version
+#[derive(Debug, Deserialize)]
+struct NpmAttestations {
+    #[serde(default)]
+    attestations: Vec<NpmAttestation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NpmAttestation {
+    predicate_type: String,
+    bundle: NpmBundle,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NpmBundle {
+    dsse_envelope: NpmEnvelope,
+}
+
+#[derive(Debug, Deserialize)]
+struct NpmEnvelope {
+    /// Base64 encoded statement
+    payload: String,
+}
+
+/// Provenance of a PyPI file from the integrity API
+#[derive(Debug, Deserialize)]
+struct PypiProvenance {
+    #[serde(default)]
+    attestation_bundles: Vec<PypiAttestationBundle>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PypiAttestationBundle {
+    publisher: PypiPublisher,
+    #[serde(default)]
+    attestations: Vec<PypiAttestation>,
+}
+
+/// Trusted Publisher which uploaded a file
+#[derive(Debug, Deserialize)]
+struct PypiPublisher {
+    /// e.g. `GitHub` or `GitLab`
+    kind: String,
+    repository: Option<String>,
+}
+
+impl PypiPublisher {
+    fn describe(&self) -> String {
+        match &self.repository {
+            Some(repository) => format!("{} {}", self.kind, repository),
+            None => self.kind.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PypiAttestation {
+    envelope: PypiEnvelope,
+}
+
+#[derive(Debug, Deserialize)]
+struct PypiEnvelope {
+    /// Base64 encoded statement
+    statement: String,
+}
+
+/// Check the provenance published for a fetched source, a file or a directory unpacked by
+/// `fetchzip`
+///
+/// Returns None if `origin` publishes no provenance for it. Attestations which can't be looked
+/// up or checked are unverifiable. Fails if the published provenance doesn't cover the fetched
+/// file, or `gh` rejects the attestation of a GitHub release asset.
+pub async fn verify_provenance(
+    source_path: &Path,
+    origin: &ProvenanceOrigin,
+) -> anyhow::Result<Option<ProvenanceCheck>> {
+    let source = if source_path.is_file() {
+        Some(source_path)
+    } else if source_path.is_dir() {
+        None
+    } else {
+        debug!(
+            "{} doesn't exist, skipping provenance verification",
+            source_path.display()
+        );
+        return Ok(None);
+    };
+
+    let check = match origin {
+        ProvenanceOrigin::GitHub { owner, repo } => match source {
+            Some(source) => verify_github_attestation(source, owner, repo).await?,
+            // Looking up attestations requires the digest of the archive
+            None => {
+                debug!(
+                    "{} is unpacked, skipping provenance verification",
+                    source_path.display()
+                );
+                None
+            },
+        },
+        ProvenanceOrigin::Npm { package, version } => {
+            verify_npm_provenance(source, package, version).await?
+        },
+        ProvenanceOrigin::PyPI {
+            project,
+            version,
+            filename,
+        } => verify_pypi_provenance(source, project, version, filename).await?,
+    };
+
+    match &check {
+        Some(ProvenanceCheck {
+            url,
+            result: CheckResult::Passed,
+            ..
+        }) => info!("✓ Source provenance verified against {}", url),
+        Some(ProvenanceCheck {
+            url,
+            result: CheckResult::Unverifiable(reason),
+            ..
+        }) => warn!("Could not verify provenance {}: {}", url, reason),
+        None => debug!("No provenance published for {}", source_path.display()),
+    }
+    Ok(check)
+}
+
+async fn verify_github_attestation(
+    source_path: &Path,
+    owner: &str,
+    repo: &str,
+) -> anyhow::Result<Option<ProvenanceCheck>> {
+    let digest = file_hash_hex(source_path, "sha256").await?;
+    let token = env::var("GITHUB_TOKEN").ok();
+    let url = format!(
+        "https://github.com/{}/{}/attestations (sha256:{})",
+        owner, repo, digest
+    );
+    match has_attestations(owner, repo, &digest, token.as_deref()).await {
+        Ok(true) => {},
+        Ok(false) => return Ok(None),
+        Err(e) => {
+            return Ok(Some(ProvenanceCheck {
+                url,
+                publisher: Some(format!("{}/{}", owner, repo)),
+                result: CheckResult::Unverifiable(format!(
+                    "failed to look up attestations: {:#}",
+                    e
+                )),
+            }));
+        },
+    }
+
+    let mut command = Command::new("gh");
+    command
+        .args(["attestation", "verify"])
+        .arg(source_path)
+        .args(["--repo", &format!("{}/{}", owner, repo)])
+        .stdin(Stdio::null());
+    if let (Some(token), Err(_)) = (&token, env::var("GH_TOKEN")) {
+        command.env("GH_TOKEN", token);
+    }
+
+    let result = match command.output().await {
+        Err(e) => CheckResult::Unverifiable(format!("gh unavailable: {}", e)),
+        // gh exits with 4 when it isn't authenticated
+        Ok(output) if output.status.code() == Some(4) => {
+            CheckResult::Unverifiable("gh is not authenticated".to_string())
+        },
+        Ok(output) if !output.status.success() => anyhow::bail!(
+            "Attestation of {} in {}/{} failed verification: {}",
+            source_path.display(),
+            owner,
+            repo,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Ok(_) => CheckResult::Passed,
+    };
+    Ok(Some(ProvenanceCheck {
+        url,
+        publisher: Some(format!("{}/{}", owner, repo)),
+        result,
+    }))
+}
+
+/// Check of attestations which were found but couldn't be read
+fn invalid_attestation(url: String, error: anyhow::Error) -> ProvenanceCheck {
+    ProvenanceCheck {
+        url,
+        publisher: None,
+        result: CheckResult::Unverifiable(format!("{:#}", error)),
+    }
+}
+
+/// `source_path` is None for unpacked sources
+async fn verify_npm_provenance(
+    source_path: Option<&Path>,
+    package: &str,
+    version: &str,
+) -> anyhow::Result<Option<ProvenanceCheck>> {
+    let url = format!(
+        "https://registry.npmjs.org/-/npm/v1/attestations/{}@{}",
+        package.replace('/', "%2f"),
+        version
+    );
+    let Some(content) = fetch_optional(http::client(), &url).await else {
+        return Ok(None);
+    };
+    let attestations: NpmAttestations = match serde_json::from_str(&content)
+        .with_context(|| format!("Invalid attestations at {}", url))
+    {
+        Ok(attestations) => attestations,
+        Err(e) => return Ok(Some(invalid_attestation(url, e))),
+    };
+    let Some(provenance) = attestations.attestations.iter().find(|attestation| {
+        attestation
+            .predicate_type
+            .starts_with(SLSA_PREDICATE_PREFIX)
+    }) else {
+        return Ok(None);
+    };
+
+    let statement = match Statement::decode(&provenance.bundle.dsse_envelope.payload) {
+        Ok(statement) => statement,
+        Err(e) => return Ok(Some(invalid_attestation(url, e))),
+    };
+    let reason = match source_path {
+        Some(source_path) => {
+            let digest = file_hash_hex(source_path, "sha512").await?;
+            if !statement.covers("sha512", &digest) {
+                anyhow::bail!(
+                    "Provenance of {}@{} at {} doesn't cover the fetched source",
+                    package,
+                    version,
+                    url
+                );
+            }
+            UNVERIFIED_SIGNATURE
+        },
+        None => UNPACKED_SOURCE,
+    };
+    Ok(Some(ProvenanceCheck {
+        publisher: statement.source_repository(),
+        url,
+        result: CheckResult::Unverifiable(reason.to_string()),
+    }))
+}
+
+/// `source_path` is None for unpacked sources
+async fn verify_pypi_provenance(
+    source_path: Option<&Path>,
+    project: &str,
+    version: &str,
+    filename: &str,
+) -> anyhow::Result<Option<ProvenanceCheck>> {
+    let url = format!(
+        "https://pypi.org/integrity/{}/{}/{}/provenance",
+        project, version, filename
+    );
+    let Some(content) = fetch_optional(http::client(), &url).await else {
+        return Ok(None);
+    };
+    let provenance: PypiProvenance = match serde_json::from_str(&content)
+        .with_context(|| format!("Invalid provenance at {}", url))
+    {
+        Ok(provenance) => provenance,
+        Err(e) => return Ok(Some(invalid_attestation(url, e))),
+    };
+    let Some(first) = provenance.attestation_bundles.first() else {
+        return Ok(None);
+    };
+    let Some(source_path) = source_path else {
+        return Ok(Some(ProvenanceCheck {
+            url,
+            publisher: Some(first.publisher.describe()),
+            result: CheckResult::Unverifiable(UNPACKED_SOURCE.to_string()),
+        }));
+    };
+
+    let digest = file_hash_hex(source_path, "sha256").await?;
+    for bundle in &provenance.attestation_bundles {
+        for attestation in &bundle.attestations {
+            let statement = match Statement::decode(&attestation.envelope.statement) {
+                Ok(statement) => statement,
+                Err(e) => return Ok(Some(invalid_attestation(url, e))),
+            };
+            if statement.covers("sha256", &digest) {
+                return Ok(Some(ProvenanceCheck {
+                    url,
+                    publisher: Some(bundle.publisher.describe()),
+                    result: CheckResult::Unverifiable(UNVERIFIED_SIGNATURE.to_string()),
+                }));
+            }
+        }
+    }
+    anyhow::bail!(
+        "Provenance of {} at {} doesn't cover the fetched source",
+        filename,
+        url
+    );
+}
+
+/// Markdown section describing the provenance of the source
+pub fn format_provenance_report(check: &ProvenanceCheck) -> String {
+    let publisher = check
+        .publisher
+        .as_ref()
+        .map(|publisher| format!(" from {}", publisher))
+        .unwrap_or_default();
+    match &check.result {
+        CheckResult::Passed => format!(
+            "## Provenance\n\n- ✅ Built{} according to {}",
+            publisher, check.url
+        ),
+        CheckResult::Unverifiable(reason) => format!(
+            "## Provenance\n\n- ⚠️ Could not verify the attestation{} at {}: {}",
+            publisher, check.url, reason
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provenance_origin() {
+        assert_eq!(
+            ProvenanceOrigin::from_source(
+                "https://github.com/owner/repo/releases/download/v1.0.0/tool-1.0.0.tar.gz",
+                None,
+                "1.0.0"
+            ),
+            Some(ProvenanceOrigin::GitHub {
+                owner: "owner".to_string(),
+                repo: "repo".to_string(),
+            })
+        );
+        // Archives generated by GitHub aren't built by workflows
+        assert_eq!(
+            ProvenanceOrigin::from_source(
+                "https://github.com/owner/repo/archive/v1.0.0.tar.gz",
+                None,
+                "1.0.0"
+            ),
+            None
+        );
+        assert_eq!(
+            ProvenanceOrigin::from_source(
+                "https://registry.npmjs.org/@scope/pkg/-/pkg-2.0.0.tgz",
+                None,
+                "2.0.0"
+            ),
+            Some(ProvenanceOrigin::Npm {
+                package: "@scope/pkg".to_string(),
+                version: "2.0.0".to_string(),
+            })
+        );
+        assert_eq!(
+            ProvenanceOrigin::from_source(
+                "mirror://pypi/r/requests/requests-2.32.0.tar.gz",
+                None,
+                "2.32.0"
+            ),
+            Some(ProvenanceOrigin::PyPI {
+                project: "requests".to_string(),
+                version: "2.32.0".to_string(),
+                filename: "requests-2.32.0.tar.gz".to_string(),
+            })
+        );
+        // Hashed paths don't name the project
+        let hashed = "https://files.pythonhosted.org/packages/ab/cd/0123/foo_bar-1.0.tar.gz";
+        assert_eq!(ProvenanceOrigin::from_source(hashed, None, "1.0"), None);
+        assert!(matches!(
+            ProvenanceOrigin::from_source(hashed, Some("foo-bar"), "1.0"),
+            Some(ProvenanceOrigin::PyPI { project, .. }) if project == "foo-bar"
+        ));
+        assert_eq!(
+            ProvenanceOrigin::from_source("https://example.org/foo-1.0.tar.gz", None, "1.0"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_statement() {
+        let statement = serde_json::json!({
+            "_type": "https://in-toto.io/Statement/v1",
+            "subject": [{"name": "pkg:npm/foo@1.0.0", "digest": {"sha512": "ABCD"}}],
+            "predicateType": "https://slsa.dev/provenance/v1",
+            "predicate": {"buildDefinition": {"externalParameters": {"workflow": {
+                "repository": "https://github.com/owner/foo", "path": ".github/workflows/release.yml"
+            }}}}
+        });
+        let encoded = openssl::base64::encode_block(statement.to_string().as_bytes());
+        let statement = Statement::decode(&encoded).unwrap();
+
+        assert!(statement.covers("sha512", "abcd"));
+        assert!(!statement.covers("sha256", "abcd"));
+        assert!(!statement.covers("sha512", "abce"));
+        assert_eq!(statement.source_repository().as_deref(), Some("owner/foo"));
+        assert!(Statement::decode("not base64!").is_err());
+
+        let check = ProvenanceCheck {
+            url: "https://github.com/owner/foo/attestations".to_string(),
+            publisher: Some("owner/foo".to_string()),
+            result: CheckResult::Passed,
+        };
+        assert_eq!(
+            format_provenance_report(&check),
+            "## Provenance\n\n- ✅ Built from owner/foo according to \
+             https://github.com/owner/foo/attestations"
+        );
+
+        let check = ProvenanceCheck {
+            url: "https://pypi.org/integrity/foo/1.0/foo-1.0.tar.gz/provenance".to_string(),
+            publisher: Some("GitHub owner/foo".to_string()),
+            result: CheckResult::Unverifiable(UNVERIFIED_SIGNATURE.to_string()),
+        };
+        assert_eq!(
+            format_provenance_report(&check),
+            format!(
+                "## Provenance\n\n- ⚠️ Could not verify the attestation from GitHub owner/foo at \
+                 https://pypi.org/integrity/foo/1.0/foo-1.0.tar.gz/provenance: {}",
+                UNVERIFIED_SIGNATURE
+            )
+        );
+    }
+}
@@ -0,0 +1,555 @@
+//! Webhook listener triggering updates of single packages
+//!
+//! `run --listen` evaluates the package set once, then waits for webhooks instead of checking
+//! every package. Two kinds of requests are accepted:
+//!
+//! - GitHub `release` webhooks, updating every package fetched from the repository of the published
+//!   release
+//! - Generic `POST`s of `{"attr_path": "hello"}`, updating the given package
+//!
+//! With a secret in `WEBHOOK_SECRET`, requests must carry the `X-Hub-Signature-256` HMAC GitHub
+//! sends for webhooks with a secret. Without one, the listener only binds to loopback addresses.
+//!
+//! Redeliveries of a GitHub webhook, recognized by their `X-GitHub-Delivery` id, are ignored, and
+//! a package is updated at most once per [`DEBOUNCE`], however many releases trigger it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures::Stream;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::groups::package_repositories;
+use crate::nix::nix_eval_jobs::{NixEvalDrv, NixEvalItem};
+
+/// Largest request body accepted, GitHub caps webhook payloads at 25 MB but releases are small
+const MAX_BODY_SIZE: usize = 5 * 1024 * 1024;
+
+/// Largest request head accepted
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Time a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time during which further requests to update a package are ignored
+pub const DEBOUNCE: Duration = Duration::from_secs(60);
+
+/// Number of delivery ids remembered to recognize redeliveries
+const MAX_DELIVERIES: usize = 1000;
+
+/// Deliveries and updates already handled
+#[derive(Default)]
+struct Handled {
+    deliveries: HashSet<String>,
+    /// Delivery ids from oldest to newest, to forget the oldest
+    delivery_order: VecDeque<String>,
+    /// When each package was last updated
+    updated: HashMap<String, Instant>,
+}
+
+impl Handled {
+    /// Record a delivery id, returning whether it was new
+    fn record_delivery(&mut self, id: &str) -> bool {
+        if !self.deliveries.insert(id.to_string()) {
+            return false;
+        }
+        self.delivery_order.push_back(id.to_string());
+        if self.delivery_order.len() > MAX_DELIVERIES {
+            if let Some(oldest) = self.delivery_order.pop_front() {
+                self.deliveries.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Keep the packages not updated within [`DEBOUNCE`] of `now`, recording their update
+    fn debounce(&mut self, drvs: Vec<NixEvalDrv>, now: Instant) -> Vec<NixEvalDrv> {
+        self.updated
+            .retain(|_, updated| now.duration_since(*updated) < DEBOUNCE);
+        drvs.into_iter()
+            .filter(|drv| {
+                if self.updated.contains_key(&drv.attr) {
+                    debug!("Update of {} already requested recently", drv.attr);
+                    return false;
+                }
+                self.updated.insert(drv.attr.clone(), now);
+                true
+            })
+            .collect()
+    }
+}
+
+/// Packages webhooks can trigger updates of
+pub struct WebhookTargets {
+    drvs: HashMap<String, NixEvalDrv>,
+    /// Attributes by lowercase repository, e.g. `github:owner/repo`
+    by_repository: HashMap<String, Vec<String>>,
+}
+
+impl WebhookTargets {
+    /// Index the evaluated packages by attribute and by the repository they're fetched from
+    pub async fn new(eval_entry_point: &str, drvs: Vec<NixEvalDrv>, concurrency: usize) -> Self {
+        let mut by_repository: HashMap<String, Vec<String>> = HashMap::new();
+        for (attr, repository) in package_repositories(eval_entry_point, &drvs, concurrency).await {
+            by_repository
+                .entry(repository.to_lowercase())
+                .or_default()
+                .push(attr);
+        }
+        Self {
+            drvs: drvs
+                .into_iter()
+                .map(|drv| (drv.attr.clone(), drv))
+                .collect(),
+            by_repository,
+        }
+    }
+
+    /// Packages to update for a request
+    fn resolve(&self, request: &WebhookRequest) -> Vec<NixEvalDrv> {
+        let attrs: Vec<&String> = match request {
+            WebhookRequest::Release { repository, .. } => self
+                .by_repository
+                .get(&repository.to_lowercase())
+                .map(|attrs| attrs.iter().collect())
+                .unwrap_or_default(),
+            WebhookRequest::Package { attr_path } => vec![attr_path],
+        };
+        attrs
+            .into_iter()
+            .filter_map(|attr| self.drvs.get(attr).cloned())
+            .collect()
+    }
+}
+
+/// Update requested by a webhook
+#[derive(Debug, Clone, PartialEq)]
+enum WebhookRequest {
+    /// A release was published in a repository, e.g. `github:owner/repo`
+    Release { repository: String, tag: String },
+    /// Update of a single package
+    Package { attr_path: String },
+}
+
+/// Parse the body of a webhook, given its `X-GitHub-Event` header
+///
+/// Returns None for events which don't call for an update, e.g. `ping` or edited releases.
+fn parse_request(event: Option<&str>, body: &[u8]) -> anyhow::Result<Option<WebhookRequest>> {
+    #[derive(Deserialize)]
+    struct ReleaseEvent {
+        action: String,
+        release: Release,
+        repository: Repository,
+    }
+
+    #[derive(Deserialize)]
+    struct Release {
+        tag_name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Repository {
+        full_name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct PackageRequest {
+        attr_path: String,
+    }
+
+    match event {
+        Some("release") => {
+            let event: ReleaseEvent =
+                serde_json::from_slice(body).context("Failed to parse release event")?;
+            if event.action != "published" {
+                return Ok(None);
+            }
+            Ok(Some(WebhookRequest::Release {
+                repository: format!("github:{}", event.repository.full_name),
+                tag: event.release.tag_name,
+            }))
+        },
+        Some(_) => Ok(None),
+        None => {
+            let request: PackageRequest =
+                serde_json::from_slice(body).context("Expected a JSON object with an attr_path")?;
+            Ok(Some(WebhookRequest::Package {
+                attr_path: request.attr_path,
+            }))
+        },
+    }
+}
+
+/// Whether `signature`, an `X-Hub-Signature-256` header, is the HMAC of `body` with `secret`
+fn verify_signature(secret: &str, body: &[u8], signature: Option<&str>) -> bool {
+    let Some(expected) = signature
+        .and_then(|s| s.strip_prefix("sha256="))
+        .and_then(decode_hex)
+    else {
+        return false;
+    };
+    let mac = PKey::hmac(secret.as_bytes()).and_then(|key| {
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(body)?;
+        signer.sign_to_vec()
+    });
+    match mac {
+        Ok(mac) => mac.len() == expected.len() && memcmp::eq(&mac, &expected),
+        Err(e) => {
+            warn!("Failed to compute webhook signature: {}", e);
+            false
+        },
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Request line and headers of an HTTP request
+#[derive(Debug, PartialEq)]
+struct RequestHead {
+    method: String,
+    /// Headers with lowercase names
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parse the request line and headers of an HTTP/1.x request
+fn parse_head(head: &str) -> anyhow::Result<RequestHead> {
+    let mut lines = head.lines();
+    let method = lines
+        .next()
+        .and_then(|line| line.split_whitespace().next())
+        .context("Missing request line")?
+        .to_string();
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .map(|line| {
+            let (name, value) = line.split_once(':').context("Malformed header")?;
+            Ok((name.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(RequestHead { method, headers })
+}
+
+/// Read an HTTP request, returning its head and body
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<(RequestHead, Vec<u8>)> {
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    let mut limited = (&mut reader).take(MAX_HEAD_SIZE as u64);
+    while !head.ends_with("\r\n\r\n") && !head.ends_with("\n\n") {
+        if limited.read_line(&mut head).await? == 0 {
+            anyhow::bail!("Incomplete or too large request head");
+        }
+    }
+    let head = parse_head(&head)?;
+
+    let length: usize = match head.header("content-length") {
+        Some(length) => length.parse().context("Invalid Content-Length")?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        anyhow::bail!("Request body too large");
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok((head, body))
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: \
+         close\r\n\r\n{}",
+        status,
+        message.len(),
+        message
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("Failed to respond to webhook: {}", e);
+    }
+}
+
+/// Handle a webhook request, returning the packages it triggers updates of
+async fn handle_connection(
+    stream: &mut TcpStream,
+    secret: Option<&str>,
+    targets: &WebhookTargets,
+    handled: &mut Handled,
+) -> Vec<NixEvalDrv> {
+    let (head, body) = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => {
+            respond(stream, "400 Bad Request", &format!("{:#}", e)).await;
+            return Vec::new();
+        },
+        Err(_) => {
+            respond(stream, "408 Request Timeout", "Timed out reading request").await;
+            return Vec::new();
+        },
+    };
+
+    if head.method != "POST" {
+        respond(stream, "405 Method Not Allowed", "Only POST is supported").await;
+        return Vec::new();
+    }
+    if let Some(secret) = secret {
+        if !verify_signature(secret, &body, head.header("x-hub-signature-256")) {
+            respond(stream, "401 Unauthorized", "Invalid signature").await;
+            return Vec::new();
+        }
+    }
+
+    let request = match parse_request(head.header("x-github-event"), &body) {
+        Ok(Some(request)) => request,
+        Ok(None) => {
+            respond(stream, "200 OK", "Ignored").await;
+            return Vec::new();
+        },
+        Err(e) => {
+            respond(stream, "400 Bad Request", &format!("{:#}", e)).await;
+            return Vec::new();
+        },
+    };
+
+    let drvs = targets.resolve(&request);
+    if drvs.is_empty() {
+        debug!("No package matches webhook {:?}", request);
+        respond(stream, "404 Not Found", "No package matches the request").await;
+        return Vec::new();
+    }
+
+    if let Some(delivery) = head.header("x-github-delivery") {
+        if !handled.record_delivery(delivery) {
+            debug!("Webhook delivery {} was already handled", delivery);
+            respond(stream, "200 OK", "Already handled").await;
+            return Vec::new();
+        }
+    }
+    let drvs = handled.debounce(drvs, Instant::now());
+    if drvs.is_empty() {
+        respond(stream, "200 OK", "Update already requested").await;
+        return Vec::new();
+    }
+
+    let attrs: Vec<&str> = drvs.iter().map(|drv| drv.attr.as_str()).collect();
+    match &request {
+        WebhookRequest::Release { repository, tag } => info!(
+            "Release {} of {} published, updating {}",
+            tag,
+            repository,
+            attrs.join(", ")
+        ),
+        WebhookRequest::Package { attr_path } => info!("Update of {} requested", attr_path),
+    }
+    respond(
+        stream,
+        "202 Accepted",
+        &format!("Updating {}", attrs.join(", ")),
+    )
+    .await;
+    drvs
+}
+
+/// Listen for webhooks on `addr`, yielding the packages to update as they are requested
+///
+/// Requests are handled one at a time, the stream only ends with an error accepting connections.
+/// Fails without a `secret` unless `addr` is a loopback address, an empty secret counting as none
+/// since anyone can sign with it.
+pub async fn listen(
+    addr: &str,
+    secret: Option<String>,
+    targets: WebhookTargets,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<NixEvalItem>>> {
+    let secret = secret.filter(|s| !s.is_empty());
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    if secret.is_none() {
+        let local = listener
+            .local_addr()
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        if !local.ip().is_loopback() {
+            anyhow::bail!(
+                "WEBHOOK_SECRET is required to listen for webhooks on {}, which isn't a loopback \
+                 address",
+                local
+            );
+        }
+        warn!("WEBHOOK_SECRET is not set, accepting unauthenticated webhooks from this host");
+    }
+    info!("Listening for webhooks on {}", addr);
+
+    Ok(async_stream::stream! {
+        let mut handled = Handled::default();
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    yield Err(anyhow::anyhow!("Failed to accept webhook connection: {}", e));
+                    return;
+                },
+            };
+            debug!("Webhook connection from {}", peer);
+            let drvs =
+                handle_connection(&mut stream, secret.as_deref(), &targets, &mut handled).await;
+            for drv in drvs {
+                yield Ok(NixEvalItem::Drv(drv));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let release = br#"{
+            "action": "published",
+            "release": {"tag_name": "v1.2.0", "draft": false},
+            "repository": {"full_name": "owner/repo", "private": false}
+        }"#;
+        assert_eq!(
+            parse_request(Some("release"), release).unwrap(),
+            Some(WebhookRequest::Release {
+                repository: "github:owner/repo".to_string(),
+                tag: "v1.2.0".to_string(),
+            })
+        );
+
+        let edited = br#"{
+            "action": "edited",
+            "release": {"tag_name": "v1.2.0"},
+            "repository": {"full_name": "owner/repo"}
+        }"#;
+        assert_eq!(parse_request(Some("release"), edited).unwrap(), None);
+        assert_eq!(parse_request(Some("ping"), b"{}").unwrap(), None);
+
+        assert_eq!(
+            parse_request(None, br#"{"attr_path": "python3Packages.requests"}"#).unwrap(),
+            Some(WebhookRequest::Package {
+                attr_path: "python3Packages.requests".to_string(),
+            })
+        );
+        assert!(parse_request(None, b"hello").is_err());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        // Example from GitHub's documentation on validating webhook deliveries
+        let secret = "It's a Secret to Everybody";
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify_signature(secret, b"Hello, World!", Some(signature)));
+        assert!(!verify_signature(secret, b"Hello, World", Some(signature)));
+        assert!(!verify_signature(
+            "other",
+            b"Hello, World!",
+            Some(signature)
+        ));
+        assert!(!verify_signature(
+            secret,
+            b"Hello, World!",
+            Some("sha256=zz")
+        ));
+        assert!(!verify_signature(secret, b"Hello, World!", None));
+    }
+
+    #[test]
+    fn test_handled() {
+        let drv = |attr: &str| NixEvalDrv {
+            attr: attr.to_string(),
+            attr_path: vec![attr.to_string()],
+            drv_path: format!("/nix/store/{}.drv", attr),
+            input_drvs: None,
+            name: attr.to_string(),
+            outputs: HashMap::new(),
+            system: "x86_64-linux".to_string(),
+            meta: None,
+        };
+        let attrs = |drvs: Vec<NixEvalDrv>| -> Vec<String> {
+            drvs.into_iter().map(|drv| drv.attr).collect()
+        };
+        let mut handled = Handled::default();
+
+        assert!(handled.record_delivery("a"));
+        assert!(!handled.record_delivery("a"));
+        for i in 0..MAX_DELIVERIES {
+            assert!(handled.record_delivery(&i.to_string()));
+        }
+        // The oldest deliveries are forgotten
+        assert!(handled.record_delivery("a"));
+
+        let now = Instant::now();
+        assert_eq!(
+            attrs(handled.debounce(vec![drv("hello"), drv("curl")], now)),
+            ["hello", "curl"]
+        );
+        assert_eq!(
+            attrs(handled.debounce(vec![drv("hello"), drv("zlib")], now + DEBOUNCE / 2)),
+            ["zlib"]
+        );
+        assert_eq!(
+            attrs(handled.debounce(vec![drv("hello")], now + DEBOUNCE)),
+            ["hello"]
+        );
+    }
+
+    #[test]
+    fn test_parse_head() {
+        let head = parse_head(
+            "POST /hook HTTP/1.1\r\nHost: localhost\r\nContent-Length: 12\r\nX-GitHub-Event: \
+             release\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.header("content-length"), Some("12"));
+        assert_eq!(head.header("x-github-event"), Some("release"));
+        assert_eq!(head.header("authorization"), None);
+
+        assert!(parse_head("").is_err());
+        assert!(parse_head("POST / HTTP/1.1\r\nnot a header\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_listen_empty_secret() {
+        let targets = || WebhookTargets {
+            drvs: HashMap::new(),
+            by_repository: HashMap::new(),
+        };
+        assert!(
+            listen("0.0.0.0:0", Some(String::new()), targets())
+                .await
+                .is_err()
+        );
+        assert!(listen("0.0.0.0:0", None, targets()).await.is_err());
+        assert!(
+            listen("127.0.0.1:0", Some(String::new()), targets())
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,472 @@
+//! Versions withdrawn by upstream from their package registry
+//!
+//! Upstreams pull broken releases after publishing them: crates.io versions are yanked, Go
+//! modules retract versions in their `go.mod` and npm versions are deprecated. Their tags usually
+//! stay in the repository, so they are looked up in the registry of the package's ecosystem and
+//! never proposed as updates.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::cargo;
+use crate::commands::run::get_file_location;
+use crate::http::{self, Throttled};
+use crate::nix::{eval_nix_expr, import_entry_point};
+use crate::package::PackageMetadata;
+use crate::rewrite::find_cargo_lock_file;
+use crate::vcs_sources::{Semver, UpstreamSource, VersionScheme};
+
+/// Versions which must not be proposed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WithdrawnVersions {
+    versions: HashSet<String>,
+    /// Inclusive version ranges, e.g. from Go's `retract [v1.0.0, v1.0.5]`
+    ranges: Vec<(String, String)>,
+}
+
+/// Strip the `v` prefix of Go and some npm versions, so they compare with release versions
+fn clean(version: &str) -> &str {
+    version.trim().trim_start_matches('v')
+}
+
+impl WithdrawnVersions {
+    /// Withdraw a single version
+    pub fn insert(&mut self, version: &str) {
+        self.versions.insert(clean(version).to_string());
+    }
+
+    /// Withdraw the versions from `low` to `high`, inclusive
+    pub fn insert_range(&mut self, low: &str, high: &str) {
+        self.ranges
+            .push((clean(low).to_string(), clean(high).to_string()));
+    }
+
+    /// Whether `version` was withdrawn
+    pub fn contains(&self, version: &str) -> bool {
+        let version = clean(version);
+        self.versions.contains(version)
+            || self.ranges.iter().any(|(low, high)| {
+                Semver.compare(version, low).is_some_and(|o| o.is_ge())
+                    && Semver.compare(version, high).is_some_and(|o| o.is_le())
+            })
+    }
+}
+
+/// Package registry of an ecosystem which can withdraw versions
+#[derive(Debug, Clone, PartialEq)]
+pub enum Registry {
+    CratesIo { name: String },
+    GoProxy { module: String },
+    Npm { name: String },
+}
+
+impl Registry {
+    /// Detect the registry of a package from the builder it uses
+    ///
+    /// Rust and npm packages are looked up by their name in the registry, see `registry_name`.
+    /// The path of Go modules is derived from their GitHub or GitLab repository, with the `/vN`
+    /// suffix of major versions 2 and up.
+    pub async fn detect(
+        eval_entry_point: &str,
+        attr_path: &str,
+        upstream: &UpstreamSource,
+        metadata: &PackageMetadata,
+    ) -> Option<Self> {
+        let nix_expr = format!(
+            "with {}; let pkg = {}; in if pkg ? goModules then \"go\" else if pkg ? cargoDeps \
+             then \"cargo\" else if pkg ? npmDeps then \"npm\" else \"\"",
+            import_entry_point(eval_entry_point),
+            attr_path
+        );
+        let builder = eval_nix_expr(&nix_expr).await.ok()?;
+
+        match builder.as_str() {
+            "cargo" => Some(Registry::CratesIo {
+                name: registry_name(eval_entry_point, attr_path, &builder, metadata).await?,
+            }),
+            "npm" => Some(Registry::Npm {
+                name: registry_name(eval_entry_point, attr_path, &builder, metadata).await?,
+            }),
+            "go" => {
+                let repository = match upstream {
+                    UpstreamSource::GitHub { owner, repo, .. } => {
+                        format!("github.com/{}/{}", owner, repo)
+                    },
+                    UpstreamSource::GitLab { owner, project } => {
+                        format!("gitlab.com/{}/{}", owner, project)
+                    },
+                    UpstreamSource::PyPI { .. }
+                    | UpstreamSource::LibrariesIo { .. }
+                    | UpstreamSource::Mirror { .. }
+                    | UpstreamSource::Plugin { .. } => {
+                        return None;
+                    },
+                };
+                Some(Registry::GoProxy {
+                    module: go_module_path(&repository, &metadata.version),
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// Query the versions withdrawn from the registry
+    pub async fn withdrawn_versions(&self) -> anyhow::Result<WithdrawnVersions> {
+        let client = http::client();
+        let get = |url: String| {
+            let client = client.clone();
+            async move {
+                debug!("Querying withdrawn versions from {}", url);
+                let response = client
+                    .get(&url)
+                    .header("User-Agent", "ekapkgs-update")
+                    .send_throttled()
+                    .await?;
+                if !response.status().is_success() {
+                    anyhow::bail!("{} returned status {}", url, response.status());
+                }
+                Ok(response.text().await?)
+            }
+        };
+
+        match self {
+            Registry::CratesIo { name } => {
+                let body =
+                    get(format!("https://crates.io/api/v1/crates/{}/versions", name)).await?;
+                parse_crates_io_versions(&body)
+            },
+            Registry::Npm { name } => {
+                let body = get(format!("https://registry.npmjs.org/{}", name)).await?;
+                parse_npm_packument(&body)
+            },
+            Registry::GoProxy { module } => {
+                #[derive(Deserialize)]
+                struct GoLatest {
+                    #[serde(rename = "Version")]
+                    version: String,
+                }
+
+                // Retractions are declared in the go.mod of the latest version
+                let base = format!("https://proxy.golang.org/{}/@", escape_go_module(module));
+                let latest: GoLatest =
+                    serde_json::from_str(&get(format!("{}latest", base)).await?)?;
+                let go_mod = get(format!("{}v/{}.mod", base, latest.version)).await?;
+                Ok(parse_go_retractions(&go_mod))
+            },
+        }
+    }
+}
+
+/// Versions withdrawn from the registry of a package, empty if it has none or the lookup fails
+pub async fn query_withdrawn_versions(
+    eval_entry_point: &str,
+    attr_path: &str,
+    upstream: &UpstreamSource,
+    metadata: &PackageMetadata,
+) -> WithdrawnVersions {
+    let Some(registry) = Registry::detect(eval_entry_point, attr_path, upstream, metadata).await
+    else {
+        return WithdrawnVersions::default();
+    };
+    match registry.withdrawn_versions().await {
+        Ok(withdrawn) => withdrawn,
+        Err(e) => {
+            debug!(
+                "{}: Failed to query withdrawn versions from {:?}: {:#}",
+                attr_path, registry, e
+            );
+            WithdrawnVersions::default()
+        },
+    }
+}
+
+/// Name of a Rust or npm package in its registry
+///
+/// Packages fetched from the registry are named by their src URL. Others are named by the lockfile
+/// vendored next to them: the root package of their `cargoLock.lockFile`, or the `name` of their
+/// `package-lock.json`. Packages whose name can't be derived aren't looked up, as their `pname`
+/// often differs from the name upstream publishes under.
+async fn registry_name(
+    eval_entry_point: &str,
+    attr_path: &str,
+    builder: &str,
+    metadata: &PackageMetadata,
+) -> Option<String> {
+    if let Some(name) = metadata.src_url.as_deref().and_then(|url| match builder {
+        "cargo" => crates_io_name(url),
+        _ => npm_name(url),
+    }) {
+        return Some(name);
+    }
+
+    let file = get_file_location(eval_entry_point, attr_path).await.ok()?;
+    let dir = Path::new(&file).parent()?;
+    let name = if builder == "cargo" {
+        let content = tokio::fs::read_to_string(&file).await.ok()?;
+        let lock_file = find_cargo_lock_file(&content)?;
+        let lock = tokio::fs::read_to_string(dir.join(lock_file)).await.ok()?;
+        match cargo::root_packages(&lock).ok()?.as_slice() {
+            [name] => Some(name.clone()),
+            _ => None,
+        }
+    } else {
+        let lock = tokio::fs::read_to_string(dir.join("package-lock.json"))
+            .await
+            .ok()?;
+        npm_lock_name(&lock)
+    };
+    if name.is_none() {
+        debug!(
+            "{}: Can't tell the name of the package in its registry",
+            attr_path
+        );
+    }
+    name
+}
+
+/// Crate name of a crates.io download URL, e.g. `https://crates.io/api/v1/crates/<name>/...`
+fn crates_io_name(url: &str) -> Option<String> {
+    let path = url
+        .strip_prefix("https://crates.io/api/v1/crates/")
+        .or_else(|| url.strip_prefix("https://static.crates.io/crates/"))?;
+    let name = path.split('/').next()?;
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Package name of an npm registry tarball URL, e.g.
+/// `https://registry.npmjs.org/@scope/name/-/name-1.0.0.tgz`
+fn npm_name(url: &str) -> Option<String> {
+    let (name, _) = url
+        .strip_prefix("https://registry.npmjs.org/")?
+        .split_once("/-/")?;
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// `name` of a `package-lock.json`
+fn npm_lock_name(lock: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct PackageLock {
+        name: Option<String>,
+    }
+
+    let lock: PackageLock = serde_json::from_str(lock).ok()?;
+    lock.name.filter(|name| !name.is_empty())
+}
+
+/// Module path of a Go module, which includes the major version from v2 on
+fn go_module_path(repository: &str, current_version: &str) -> String {
+    let major = Semver
+        .release(clean(current_version))
+        .and_then(|release| release.first().copied())
+        .unwrap_or(0);
+    if major >= 2 {
+        format!("{}/v{}", repository, major)
+    } else {
+        repository.to_string()
+    }
+}
+
+/// Escape a module path for the Go module proxy, which encodes capitals as `!` and lowercase
+fn escape_go_module(module: &str) -> String {
+    module
+        .chars()
+        .map(|c| {
+            if c.is_ascii_uppercase() {
+                format!("!{}", c.to_ascii_lowercase())
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Parse the `retract` directives of a go.mod file
+fn parse_go_retractions(go_mod: &str) -> WithdrawnVersions {
+    let mut withdrawn = WithdrawnVersions::default();
+    let mut in_block = false;
+
+    for line in go_mod.lines() {
+        let line = line.split("//").next().unwrap_or_default().trim();
+        let retraction = if in_block {
+            if line == ")" {
+                in_block = false;
+                continue;
+            }
+            line
+        } else if let Some(rest) = line.strip_prefix("retract") {
+            let rest = rest.trim();
+            if rest == "(" {
+                in_block = true;
+                continue;
+            }
+            rest
+        } else {
+            continue;
+        };
+
+        if let Some(range) = retraction
+            .strip_prefix('[')
+            .and_then(|r| r.strip_suffix(']'))
+        {
+            if let Some((low, high)) = range.split_once(',') {
+                withdrawn.insert_range(low, high);
+            }
+        } else if !retraction.is_empty() {
+            withdrawn.insert(retraction);
+        }
+    }
+
+    withdrawn
+}
+
+/// Parse the yanked versions from a crates.io versions response
+fn parse_crates_io_versions(body: &str) -> anyhow::Result<WithdrawnVersions> {
+    #[derive(Deserialize)]
+    struct CrateVersion {
+        num: String,
+        yanked: bool,
+    }
+    #[derive(Deserialize)]
+    struct CrateVersions {
+        versions: Vec<CrateVersion>,
+    }
+
+    let response: CrateVersions = serde_json::from_str(body)?;
+    let mut withdrawn = WithdrawnVersions::default();
+    for version in response.versions.iter().filter(|v| v.yanked) {
+        withdrawn.insert(&version.num);
+    }
+    Ok(withdrawn)
+}
+
+/// Parse the deprecated versions from an npm registry package document
+fn parse_npm_packument(body: &str) -> anyhow::Result<WithdrawnVersions> {
+    #[derive(Deserialize)]
+    struct NpmVersion {
+        #[serde(default)]
+        deprecated: Option<serde_json::Value>,
+    }
+    #[derive(Deserialize)]
+    struct Packument {
+        #[serde(default)]
+        versions: HashMap<String, NpmVersion>,
+    }
+
+    let packument: Packument = serde_json::from_str(body)?;
+    let mut withdrawn = WithdrawnVersions::default();
+    for (version, info) in &packument.versions {
+        // `deprecated` holds the deprecation message, an empty string or false un-deprecates
+        let deprecated = match &info.deprecated {
+            Some(serde_json::Value::String(message)) => !message.is_empty(),
+            Some(serde_json::Value::Bool(deprecated)) => *deprecated,
+            _ => false,
+        };
+        if deprecated {
+            withdrawn.insert(version);
+        }
+    }
+    Ok(withdrawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_go_retractions() {
+        let withdrawn = parse_go_retractions(
+            r#"
+module example.com/foo
+
+go 1.21
+
+retract v1.0.1 // Published accidentally
+retract [v1.2.0, v1.2.3]
+retract (
+    v1.3.0 // Broken build
+    [v1.4.0, v1.4.2]
+)
+
+require example.com/bar v1.0.0
+"#,
+        );
+
+        assert!(withdrawn.contains("1.0.1"));
+        assert!(withdrawn.contains("v1.2.2"));
+        assert!(withdrawn.contains("1.3.0"));
+        assert!(withdrawn.contains("1.4.2"));
+        assert!(!withdrawn.contains("1.2.4"));
+        assert!(!withdrawn.contains("1.0.0"));
+    }
+
+    #[test]
+    fn test_parse_registry_responses() {
+        let crates = parse_crates_io_versions(
+            r#"{"versions": [{"num": "1.0.1", "yanked": true}, {"num": "1.0.0", "yanked": false}]}"#,
+        )
+        .unwrap();
+        assert!(crates.contains("1.0.1"));
+        assert!(!crates.contains("1.0.0"));
+
+        let npm = parse_npm_packument(
+            r#"{"versions": {
+                "2.0.0": {"deprecated": "Use 2.0.1, this release breaks the CLI"},
+                "2.0.1": {},
+                "1.9.0": {"deprecated": ""}
+            }}"#,
+        )
+        .unwrap();
+        assert!(npm.contains("2.0.0"));
+        assert!(!npm.contains("2.0.1"));
+        assert!(!npm.contains("1.9.0"));
+    }
+
+    #[test]
+    fn test_registry_names() {
+        assert_eq!(
+            crates_io_name("https://crates.io/api/v1/crates/fd-find/10.2.0/download").as_deref(),
+            Some("fd-find")
+        );
+        assert_eq!(
+            crates_io_name("https://static.crates.io/crates/serde/serde-1.0.200.crate").as_deref(),
+            Some("serde")
+        );
+        assert_eq!(
+            crates_io_name("https://github.com/sharkdp/fd/archive/v10.2.0.tar.gz"),
+            None
+        );
+
+        assert_eq!(
+            npm_name("https://registry.npmjs.org/@angular/cli/-/cli-18.0.0.tgz").as_deref(),
+            Some("@angular/cli")
+        );
+        assert_eq!(
+            npm_name("https://registry.npmjs.org/prettier/-/prettier-3.3.0.tgz").as_deref(),
+            Some("prettier")
+        );
+        assert_eq!(
+            npm_lock_name(r#"{"name": "@vue/cli", "lockfileVersion": 3}"#).as_deref(),
+            Some("@vue/cli")
+        );
+        assert_eq!(npm_lock_name(r#"{"lockfileVersion": 3}"#), None);
+    }
+
+    #[test]
+    fn test_go_module_path() {
+        assert_eq!(
+            go_module_path("github.com/foo/bar", "1.4.0"),
+            "github.com/foo/bar"
+        );
+        assert_eq!(
+            go_module_path("github.com/foo/bar", "3.1.0"),
+            "github.com/foo/bar/v3"
+        );
+        assert_eq!(
+            escape_go_module("github.com/BurntSushi/toml"),
+            "github.com/!burnt!sushi/toml"
+        );
+    }
+}